@@ -0,0 +1,60 @@
+use anyhow::Result;
+use clap::Subcommand;
+use nvmetcfg::helpers::assert_valid_nqn;
+use nvmetcfg::state::{Aliases, ALIAS_FILE};
+
+#[derive(Subcommand)]
+pub enum CliAliasCommands {
+    /// List all Subsystem aliases.
+    List,
+    /// Set the alias of a Subsystem, replacing its previous one.
+    Set {
+        /// Alias to refer to the Subsystem by, as @alias.
+        alias: String,
+        /// NVMe Qualified Name of the Subsystem.
+        sub: String,
+    },
+    /// Remove a Subsystem alias.
+    Remove {
+        /// Alias to remove.
+        alias: String,
+    },
+}
+
+impl CliAliasCommands {
+    pub(super) fn parse(command: Self) -> Result<()> {
+        match command {
+            Self::List => {
+                for (nqn, alias) in Aliases::load(ALIAS_FILE)?.iter() {
+                    println!("@{alias}\t{nqn}");
+                }
+            }
+            Self::Set { alias, sub } => {
+                let mut aliases = Aliases::load(ALIAS_FILE)?;
+                aliases.set(strip_at(&alias), &sub)?;
+                aliases.save(ALIAS_FILE)?;
+            }
+            Self::Remove { alias } => {
+                let mut aliases = Aliases::load(ALIAS_FILE)?;
+                aliases.remove(strip_at(&alias))?;
+                aliases.save(ALIAS_FILE)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolve a Subsystem given on the command line as NQN or @alias, and check the NQN.
+pub fn resolve_sub(sub: String) -> Result<String> {
+    let sub = if sub.starts_with('@') {
+        Aliases::load(ALIAS_FILE)?.resolve(&sub)?
+    } else {
+        sub
+    };
+    assert_valid_nqn(&sub)?;
+    Ok(sub)
+}
+
+fn strip_at(alias: &str) -> &str {
+    alias.strip_prefix('@').unwrap_or(alias)
+}
@@ -0,0 +1,87 @@
+use anyhow::Result;
+use nvmetcfg::kernel::ApplyReport;
+use nvmetcfg::state::{AuditLog, AuditRecord, StateDelta};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+static AUDIT_LOG: OnceLock<AuditLog> = OnceLock::new();
+
+/// Record applied changes in the audit log at `path`, if given.
+pub fn init(path: Option<PathBuf>) {
+    if let Some(path) = path {
+        let _ = AUDIT_LOG.set(AuditLog::new(path));
+    }
+}
+
+/// The command line we were run with, without the program name.
+fn command() -> String {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    shlex::try_join(args.iter().map(String::as_str)).unwrap_or_else(|_| args.join(" "))
+}
+
+/// Apply `deltas` using `apply`, recording them in the audit log if there is one.
+pub fn apply<F>(deltas: Vec<StateDelta>, apply: F) -> Result<()>
+where
+    F: FnOnce(Vec<StateDelta>) -> Result<()>,
+{
+    match AUDIT_LOG.get() {
+        Some(log) => log.apply(&command(), deltas, apply),
+        None => apply(deltas),
+    }
+}
+
+/// Record the outcome of `KernelConfig::apply_state` in the audit log, if there is one.
+///
+/// A failure is recorded without changes, as which were made isn't known then. Dry runs change
+/// nothing, so neither their failures nor their reports are recorded.
+pub fn record_report(result: &Result<ApplyReport>, dry_run: bool) -> Result<()> {
+    let Some(log) = AUDIT_LOG.get() else {
+        return Ok(());
+    };
+    let record = match result {
+        Ok(report) => {
+            let deltas = if report.applied {
+                report.deltas.clone()
+            } else {
+                Vec::new()
+            };
+            let mut actions: Vec<String> = report
+                .keyed_hosts
+                .iter()
+                .map(|host| format!("set keys of host {host}"))
+                .collect();
+            if report.applied {
+                actions.extend(
+                    report
+                        .host_removals
+                        .iter()
+                        .map(|host| format!("remove host {host}")),
+                );
+            }
+            if deltas.is_empty() && actions.is_empty() {
+                return Ok(());
+            }
+            AuditRecord::new(&command(), deltas, None).with_actions(actions)
+        }
+        Err(_) if dry_run => return Ok(()),
+        Err(err) => AuditRecord::new(&command(), Vec::new(), Some(format!("{err:#}"))),
+    };
+    log.append(&record)?;
+    Ok(())
+}
+
+/// Record `result` of doing `actions` outside of the state in the audit log, if there is one,
+/// and pass it on.
+pub fn record<T, E: Into<anyhow::Error>>(
+    actions: Vec<String>,
+    result: std::result::Result<T, E>,
+) -> Result<T> {
+    let result = result.map_err(Into::into);
+    if let Some(log) = AUDIT_LOG.get() {
+        if !actions.is_empty() {
+            let error = result.as_ref().err().map(|err| format!("{err:#}"));
+            log.append(&AuditRecord::new(&command(), Vec::new(), error).with_actions(actions))?;
+        }
+    }
+    result
+}
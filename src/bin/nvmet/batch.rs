@@ -0,0 +1,95 @@
+use crate::{namespace, port, subsystem, Cli, CliCommands};
+use anyhow::{Context, Result};
+use clap::Parser;
+use nvmetcfg::errors::Error;
+use nvmetcfg::kernel::KernelConfig;
+use nvmetcfg::state::{State, StateDelta};
+use std::io::Read;
+use tracing::info;
+
+/// A command from the batch, along with where it came from.
+struct BatchCommand {
+    line: usize,
+    text: String,
+    command: CliCommands,
+}
+
+/// Parse all commands up front, so a typo in the last line doesn't leave half a configuration.
+fn parse_commands(input: &str) -> Result<Vec<BatchCommand>> {
+    let mut commands = Vec::new();
+    for (idx, text) in input.lines().enumerate() {
+        let line = idx + 1;
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+        let mut words =
+            shlex::split(text).ok_or_else(|| Error::InvalidBatchLine(text.to_string()))?;
+        // Allow copying commands verbatim, including the binary name.
+        if words.first().is_some_and(|word| word == "nvmet") {
+            words.remove(0);
+        }
+        let cli = Cli::try_parse_from(std::iter::once("nvmet".to_string()).chain(words))
+            .with_context(|| format!("Failed to parse line {line}: {text}"))?;
+        commands.push(BatchCommand {
+            line,
+            text: text.to_string(),
+            command: cli.command,
+        });
+    }
+    Ok(commands)
+}
+
+fn command_deltas(command: CliCommands, state: &State) -> Result<Option<Vec<StateDelta>>> {
+    match command {
+        CliCommands::Port { port_command } => port::CliPortCommands::deltas(port_command, state),
+        CliCommands::Subsystem { subsystem_command } => {
+            subsystem::CliSubsystemCommands::deltas(subsystem_command, state)
+        }
+        CliCommands::Namespace { namespace_command } => {
+            namespace::CliNamespaceCommands::deltas(namespace_command, state)
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn run(verify: bool) -> Result<()> {
+    let mut input = String::new();
+    std::io::stdin()
+        .read_to_string(&mut input)
+        .context("Failed to read commands from stdin")?;
+    let commands = parse_commands(&input)?;
+
+    // Each command sees the changes of the ones before it.
+    let current = KernelConfig::gather_state().context("Failed to gather state for batch")?;
+    let mut intended = current.clone();
+    let mut deltas = Vec::new();
+    for BatchCommand {
+        line,
+        text,
+        command,
+    } in commands
+    {
+        let command_deltas = command_deltas(command, &intended)
+            .and_then(|command_deltas| {
+                let command_deltas =
+                    command_deltas.ok_or_else(|| Error::UnsupportedInBatch(text.clone()))?;
+                intended.apply_deltas(&command_deltas)?;
+                Ok(command_deltas)
+            })
+            .with_context(|| format!("Invalid command on line {line}: {text}"))?;
+        deltas.extend(command_deltas);
+    }
+    intended
+        .validate(false)
+        .context("Batch would result in an invalid state")?;
+
+    if deltas.is_empty() {
+        info!("No changes made: Batch contains no state changes.");
+        return Ok(());
+    }
+    let delta_len = deltas.len();
+    crate::apply_delta(&current, deltas, verify).context("Failed to apply batch")?;
+    info!("Sucessfully applied batch: {delta_len} state changes.");
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use crate::output::{print_json, print_ndjson, OutputFormat};
+use anyhow::Result;
+use nvmetcfg::kernel::{Feature, KernelConfig};
+
+pub fn show(output: OutputFormat) -> Result<()> {
+    let caps = KernelConfig::capabilities()?;
+    match output {
+        OutputFormat::Json => print_json(&caps)?,
+        OutputFormat::Ndjson => print_ndjson([&caps])?,
+        OutputFormat::Text | OutputFormat::Csv => {
+            println!(
+                "Kernel: {}",
+                caps.kernel_version.as_deref().unwrap_or("unknown")
+            );
+            for feature in Feature::ALL {
+                let supported = if caps.supports(feature) { "yes" } else { "no" };
+                println!("\t{:<20}{supported}", feature.name());
+            }
+        }
+    }
+    Ok(())
+}
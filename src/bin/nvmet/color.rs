@@ -0,0 +1,111 @@
+// Colors for text output on terminals, following NO_COLOR.
+// Without the color feature, everything is printed plain.
+
+use nvmetcfg::state::StateDelta;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+static STDOUT: OnceLock<bool> = OnceLock::new();
+static STDERR: OnceLock<bool> = OnceLock::new();
+
+/// What a piece of text is, which decides its color.
+#[derive(Clone, Copy)]
+pub enum Color {
+    /// Names of the objects shown, like `Port 1:`.
+    Header,
+    Added,
+    Removed,
+    Changed,
+    Error,
+    Warning,
+}
+
+/// Whether to color output going to a terminal if `is_terminal`, given the value of the
+/// `NO_COLOR` environment variable.
+///
+/// Following <https://no-color.org>, setting `NO_COLOR` to anything but the empty string
+/// disables colors.
+fn use_color(is_terminal: bool, no_color: Option<&OsStr>) -> bool {
+    is_terminal && no_color.map_or(true, OsStr::is_empty)
+}
+
+/// Decide whether stdout and stderr get colors, each depending on whether it's a terminal.
+pub fn init() {
+    let no_color = std::env::var_os("NO_COLOR");
+    let _ = STDOUT.set(use_color(
+        std::io::stdout().is_terminal(),
+        no_color.as_deref(),
+    ));
+    let _ = STDERR.set(use_color(
+        std::io::stderr().is_terminal(),
+        no_color.as_deref(),
+    ));
+}
+
+impl Color {
+    /// `text` in this color, for printing to stdout.
+    pub fn paint(self, text: impl Display) -> String {
+        self.paint_if(&STDOUT, text)
+    }
+
+    /// `text` in this color, for printing to stderr.
+    pub fn paint_stderr(self, text: impl Display) -> String {
+        self.paint_if(&STDERR, text)
+    }
+
+    #[cfg_attr(not(feature = "color"), allow(clippy::unused_self, unused_variables))]
+    fn paint_if(self, enabled: &OnceLock<bool>, text: impl Display) -> String {
+        #[cfg(feature = "color")]
+        if enabled.get().copied().unwrap_or_default() {
+            let style = self.style();
+            return format!("{}{text}{}", style.render(), style.render_reset());
+        }
+        text.to_string()
+    }
+
+    #[cfg(feature = "color")]
+    fn style(self) -> anstyle::Style {
+        use anstyle::{AnsiColor, Style};
+        match self {
+            Self::Header => Style::new().bold(),
+            Self::Added => AnsiColor::Green.on_default(),
+            Self::Removed | Self::Error => AnsiColor::Red.on_default(),
+            Self::Changed | Self::Warning => AnsiColor::Yellow.on_default(),
+        }
+    }
+}
+
+/// A change as shown in diffs: additions green, removals red, and updates yellow.
+pub fn delta(change: &StateDelta) -> String {
+    delta_color(change).paint(change)
+}
+
+/// A change like `delta`, for logging it to stderr.
+pub fn delta_stderr(change: &StateDelta) -> String {
+    delta_color(change).paint_stderr(change)
+}
+
+const fn delta_color(change: &StateDelta) -> Color {
+    match change {
+        StateDelta::AddPort(..) | StateDelta::AddSubsystem(..) => Color::Added,
+        StateDelta::RemovePort(_) | StateDelta::RemoveSubsystem(_) => Color::Removed,
+        StateDelta::UpdatePort(..) | StateDelta::UpdateSubsystem(..) => Color::Changed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_use_color() {
+        assert!(use_color(true, None));
+        assert!(use_color(true, Some(OsStr::new(""))));
+        assert!(!use_color(true, Some(OsStr::new("1"))));
+        // Pipes and files never get colors.
+        assert!(!use_color(false, None));
+        assert!(!use_color(false, Some(OsStr::new("1"))));
+    }
+}
@@ -0,0 +1,27 @@
+use anyhow::Result;
+use nvmetcfg::kernel::KernelConfig;
+use nvmetcfg::state::PortType;
+use tracing::warn;
+
+pub fn show(discovery_conf: bool, host_traddr: Option<&str>) -> Result<()> {
+    let state = KernelConfig::gather_state()?;
+    for (id, port) in &state.ports {
+        if let PortType::Tcp(addr) | PortType::Rdma(addr) = port.port_type {
+            if addr.ip().is_unspecified() {
+                warn!(
+                    "Port {id} listens on all addresses, replace {} with one the initiators can reach.",
+                    addr.ip()
+                );
+            }
+        }
+    }
+    let lines = if discovery_conf {
+        state.discovery_conf(host_traddr)
+    } else {
+        state.connect_commands(host_traddr)
+    };
+    for line in lines {
+        println!("{line}");
+    }
+    Ok(())
+}
@@ -0,0 +1,26 @@
+use crate::port::{listening_status, tcp_listening};
+use anyhow::Result;
+use nvmetcfg::errors::Error;
+use nvmetcfg::kernel::KernelConfig;
+use tracing::info;
+
+/// Look for configuration the kernel accepts, but which doesn't work.
+pub fn check() -> Result<()> {
+    let state = KernelConfig::gather_state()?;
+    let mut listening = tcp_listening(&state)?;
+    // The kernel only listens once a Subsystem is added to the Port.
+    listening.retain(|id, _| !state.ports[id].subsystems.is_empty());
+    for (id, listening) in &listening {
+        println!(
+            "Port {id} ({}): {}",
+            state.ports[id].port_type,
+            listening_status(*listening)
+        );
+    }
+    let not_listening = listening.values().filter(|listening| !**listening).count();
+    if not_listening > 0 {
+        return Err(Error::NotListening(not_listening).into());
+    }
+    info!("No problems found.");
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use crate::output::OutputFormat;
+use anyhow::Result;
+use nvmetcfg::kernel::{Event, KernelConfig};
+use serde::Serialize;
+use std::io::Write;
+use std::time::SystemTime;
+
+#[derive(Serialize)]
+struct TimedEvent {
+    time: String,
+    #[serde(flatten)]
+    event: Event,
+}
+
+pub fn watch(output: OutputFormat) -> Result<()> {
+    let mut watcher = KernelConfig::watch_events()?;
+    let mut stdout = std::io::stdout().lock();
+    loop {
+        for event in watcher.wait()? {
+            let time = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+            match output {
+                OutputFormat::Text | OutputFormat::Csv => writeln!(stdout, "{time} {event}")?,
+                OutputFormat::Json | OutputFormat::Ndjson => writeln!(
+                    stdout,
+                    "{}",
+                    serde_json::to_string(&TimedEvent { time, event })?
+                )?,
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use crate::color::Color;
+use crate::output::{print_ndjson, OutputFormat};
+use crate::state::ConfigFile;
+use anyhow::Result;
+use clap::{Args, Subcommand, ValueEnum};
+use nvmetcfg::helpers::{
+    assert_valid_nqn, generate_dhchap_key, is_readable_by_others, CsvWriter, DhchapHmac,
+    SecretSource,
+};
+use nvmetcfg::kernel::{HostAuth, KernelConfig};
+use serde::Serialize;
+use std::path::PathBuf;
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+/// Ways of passing a DH-HMAC-CHAP key.
+///
+/// Passing the key directly leaks it into the shell history and /proc/<pid>/cmdline,
+/// so the other options should be preferred.
+/// Commands requiring a key mark the group as required.
+#[derive(Args)]
+#[group(multiple = false)]
+pub struct KeyArgs {
+    /// The key itself. Insecure, prefer the other options.
+    #[arg(long)]
+    key: Option<String>,
+
+    /// Read the key from a file.
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+
+    /// Read the key from standard input.
+    #[arg(long)]
+    key_stdin: bool,
+
+    /// Read the key from an environment variable.
+    #[arg(long, value_name = "VAR")]
+    key_env: Option<String>,
+}
+
+impl KeyArgs {
+    pub fn source(self) -> SecretSource {
+        if let Some(key) = self.key {
+            warn!(
+                "Passing keys as arguments exposes them to other users, use --key-file, --key-stdin or --key-env instead."
+            );
+            SecretSource::Value(Zeroizing::new(key))
+        } else if let Some(path) = self.key_file {
+            if is_readable_by_others(&path).unwrap_or(false) {
+                warn!(
+                    "Key file {} is readable by other users! Restrict it using chmod 600.",
+                    path.display()
+                );
+            }
+            SecretSource::File(path)
+        } else if let Some(var) = self.key_env {
+            SecretSource::Env(var)
+        } else {
+            SecretSource::Stdin
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum CliHmac {
+    /// Use the secret as is.
+    None,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl From<CliHmac> for DhchapHmac {
+    fn from(hmac: CliHmac) -> Self {
+        match hmac {
+            CliHmac::None => Self::None,
+            CliHmac::Sha256 => Self::Sha256,
+            CliHmac::Sha384 => Self::Sha384,
+            CliHmac::Sha512 => Self::Sha512,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum CliHostCommands {
+    /// List all Hosts/Initiators, including those not allowed on any Subsystem.
+    ///
+    /// As CSV, whether each has DH-HMAC-CHAP keys set is included.
+    List,
+    /// Show which DH-HMAC-CHAP keys a Host/Initiator has, without showing the keys.
+    Show {
+        /// NVMe Qualified Name of the Host/Initiator.
+        host: String,
+    },
+    /// Create a Host/Initiator without allowing it on any Subsystem, optionally with a key.
+    ///
+    /// Allowing it on Subsystems later keeps its keys.
+    Add {
+        /// NVMe Qualified Name of the Host/Initiator.
+        host: String,
+
+        #[command(flatten)]
+        key: Option<KeyArgs>,
+    },
+    /// Remove all Hosts/Initiators not allowed on any Subsystem.
+    Prune {
+        /// Keep Hosts/Initiators which have a DH-HMAC-CHAP key set.
+        #[arg(long)]
+        keep_keys: bool,
+
+        /// Show the Hosts/Initiators that would be removed without removing them.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Generate a random DH-HMAC-CHAP key and print it.
+    GenKey {
+        /// Hash used to transform the secret, which also determines its length.
+        #[arg(long, value_enum, default_value_t = CliHmac::Sha256)]
+        hmac: CliHmac,
+    },
+    /// Set the DH-HMAC-CHAP key a Host/Initiator authenticates itself with.
+    #[command(mut_group("KeyArgs", |group| group.required(true)))]
+    SetKey {
+        /// NVMe Qualified Name of the Host/Initiator.
+        host: String,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+    /// Set the DH-HMAC-CHAP key used to authenticate the controller to a Host/Initiator.
+    #[command(mut_group("KeyArgs", |group| group.required(true)))]
+    SetCtrlKey {
+        /// NVMe Qualified Name of the Host/Initiator.
+        host: String,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+    /// Set the DH-HMAC-CHAP keys listed under `hosts` in a state file.
+    ///
+    /// Missing Hosts/Initiators are created without allowing them on any Subsystem.
+    /// Nothing is changed unless all keys are valid.
+    ImportKeys {
+        /// State file, or any YAML file with just a `hosts` section.
+        file: PathBuf,
+    },
+}
+
+/// A Host as listed, with which keys it has.
+#[derive(Serialize)]
+struct HostRecord {
+    nqn: String,
+    #[serde(flatten)]
+    auth: HostAuth,
+}
+
+impl CliHostCommands {
+    /// Whether the command changes the configuration in the kernel, which needs privileges.
+    pub(super) const fn mutates(&self) -> bool {
+        match self {
+            Self::List | Self::Show { .. } | Self::GenKey { .. } => false,
+            Self::Prune { dry_run, .. } => !*dry_run,
+            Self::Add { .. }
+            | Self::SetKey { .. }
+            | Self::SetCtrlKey { .. }
+            | Self::ImportKeys { .. } => true,
+        }
+    }
+
+    pub(super) fn parse(command: Self, output: OutputFormat) -> Result<()> {
+        match command {
+            Self::List => {
+                let hosts = KernelConfig::list_hosts()?;
+                if output == OutputFormat::Ndjson {
+                    for nqn in hosts {
+                        let auth = KernelConfig::host_auth(&nqn)?;
+                        print_ndjson([HostRecord { nqn, auth }])?;
+                    }
+                } else if output == OutputFormat::Csv {
+                    let mut csv = CsvWriter::new(&["nqn", "host_key", "ctrl_key"]);
+                    for host in hosts {
+                        let auth = KernelConfig::host_auth(&host)?;
+                        csv.row(&[host, auth.host_key.to_string(), auth.ctrl_key.to_string()]);
+                    }
+                    print!("{}", csv.finish());
+                } else {
+                    for host in hosts {
+                        println!("{host}");
+                    }
+                }
+            }
+            Self::Show { host } => {
+                assert_valid_nqn(&host)?;
+                let auth = KernelConfig::host_auth(&host)?;
+                let set = |set: bool| if set { "set" } else { "not set" };
+                println!("{}", Color::Header.paint(format!("Host: {host}")));
+                println!("\tHost Key: {}", set(auth.host_key));
+                println!("\tController Key: {}", set(auth.ctrl_key));
+                let mode = match (auth.host_key, auth.ctrl_key) {
+                    (true, true) => "mutual",
+                    (true, false) => "one-way",
+                    (false, true) => "none (controller key requires a host key)",
+                    (false, false) => "none",
+                };
+                println!("\tAuthentication: {mode}");
+            }
+            Self::Add { host, key } => {
+                assert_valid_nqn(&host)?;
+                let key = key.map(|key| key.source().read()).transpose()?;
+                let result = KernelConfig::add_host(&host, key.as_deref().map(|key| key.as_str()));
+                crate::audit::record(vec![format!("add host {host}")], result)?;
+            }
+            Self::Prune { keep_keys, dry_run } => {
+                let result = KernelConfig::prune_hosts(keep_keys, dry_run);
+                let actions = match &result {
+                    _ if dry_run => Vec::new(),
+                    Ok(report) => report
+                        .removed
+                        .iter()
+                        .map(|host| format!("remove host {host}"))
+                        .collect(),
+                    Err(_) => vec!["prune unused hosts".to_string()],
+                };
+                let report = crate::audit::record(actions, result)?;
+                for host in &report.kept {
+                    info!("Kept {host}: has keys");
+                }
+                if report.removed.is_empty() {
+                    info!("No changes made: No unused hosts to prune.");
+                } else {
+                    if dry_run {
+                        println!(
+                            "Unused hosts that would be pruned: {}",
+                            report.removed.len()
+                        );
+                    } else {
+                        info!("Sucessfully pruned unused hosts: {}", report.removed.len());
+                    }
+                    for host in &report.removed {
+                        println!("\t{host}");
+                    }
+                }
+            }
+            Self::GenKey { hmac } => {
+                let key = generate_dhchap_key(hmac.into())?;
+                println!("{}", key.as_str());
+            }
+            Self::SetKey { host, key } => {
+                assert_valid_nqn(&host)?;
+                let key = key.source().read()?;
+                let result = KernelConfig::set_host_dhchap_key(&host, &key);
+                crate::audit::record(vec![format!("set dhchap_key of host {host}")], result)?;
+            }
+            Self::SetCtrlKey { host, key } => {
+                assert_valid_nqn(&host)?;
+                let key = key.source().read()?;
+                let result = KernelConfig::set_host_dhchap_ctrl_key(&host, &key);
+                crate::audit::record(vec![format!("set dhchap_ctrl_key of host {host}")], result)?;
+            }
+            Self::ImportKeys { file } => {
+                let config = ConfigFile::load(file)?;
+                config.validate_sources(false)?;
+                let result = KernelConfig::set_host_keys(&config.state.hosts);
+                let actions = match &result {
+                    Ok(changed) => changed
+                        .iter()
+                        .map(|host| format!("set keys of host {host}"))
+                        .collect(),
+                    Err(_) => vec!["import keys of hosts".to_string()],
+                };
+                let changed = crate::audit::record(actions, result)?;
+                if changed.is_empty() {
+                    info!("No changes made: All hosts have their keys already.");
+                } else {
+                    info!("Sucessfully set keys of hosts: {}", changed.len());
+                    for host in &changed {
+                        println!("\t{host}");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
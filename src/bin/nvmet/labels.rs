@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use nvmetcfg::state::{parse_label, LabelStore, Labels, State, StateDelta, LABEL_FILE};
+use std::collections::BTreeMap;
+use tracing::info;
+
+/// Labels to set and remove on a Subsystem or Port.
+#[derive(Args)]
+pub struct LabelArgs {
+    /// Labels to set, as key=value.
+    #[arg(value_parser = parse_label_arg)]
+    labels: Vec<(String, String)>,
+
+    /// Label key to remove. Can be given multiple times.
+    #[arg(long, value_name = "KEY")]
+    remove: Vec<String>,
+}
+
+/// Only list objects with all of these labels.
+#[derive(Args)]
+pub struct LabelFilter {
+    /// Only list those with this label, given as key=value. Can be given multiple times.
+    #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_label_arg)]
+    labels: Vec<(String, String)>,
+}
+
+impl LabelFilter {
+    pub fn selector(&self) -> Labels {
+        self.labels.iter().cloned().collect()
+    }
+}
+
+fn parse_label_arg(label: &str) -> Result<(String, String)> {
+    Ok(parse_label(label)?)
+}
+
+/// Set and remove labels in the label store, with `select` picking the object's labels.
+pub fn update<K: Ord>(
+    args: LabelArgs,
+    id: K,
+    select: impl FnOnce(&mut LabelStore) -> &mut BTreeMap<K, Labels>,
+) -> Result<()> {
+    let mut store = LabelStore::load(LABEL_FILE)?;
+    LabelStore::update(
+        select(&mut store),
+        id,
+        args.labels.into_iter().collect(),
+        &args.remove,
+    )?;
+    Ok(store.save(LABEL_FILE)?)
+}
+
+/// Load the label store into `state`, for showing or filtering by labels.
+pub fn apply_to_state(state: &mut State) -> Result<()> {
+    LabelStore::load(LABEL_FILE)?.apply_to_state(state);
+    Ok(())
+}
+
+/// Drop the labels of the Subsystems and Ports removed by `deltas`.
+pub fn forget_removed(deltas: &[StateDelta]) -> Result<()> {
+    if !deltas.iter().any(|d| {
+        matches!(
+            d,
+            StateDelta::RemoveSubsystem(_) | StateDelta::RemovePort(_)
+        )
+    }) {
+        return Ok(());
+    }
+    let mut store = LabelStore::load(LABEL_FILE)?;
+    if store.forget_removed(deltas) {
+        store
+            .save(LABEL_FILE)
+            .context("Failed to remove labels of removed objects")?;
+        info!("Removed labels of removed objects.");
+    }
+    Ok(())
+}
+
+/// Labels as a single line of space separated key=value pairs.
+pub fn format(labels: &Labels) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,58 @@
+use crate::color::Color;
+use clap::ValueEnum;
+use std::fmt;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+
+/// How to print status messages and warnings.
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Plain messages, with warnings prefixed.
+    #[default]
+    Text,
+    /// One JSON object per line, with level, target, message and fields.
+    Json,
+}
+
+// Prints messages the way they were printed before there was logging.
+struct PlainFormat;
+
+impl<S, N> FormatEvent<S, N> for PlainFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        match *event.metadata().level() {
+            Level::ERROR => write!(writer, "{} ", Color::Error.paint_stderr("Error:"))?,
+            Level::WARN => write!(writer, "{} ", Color::Warning.paint_stderr("Warning:"))?,
+            _ => {}
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// Log to stderr in the given format. Configfs writes are only logged if `verbose` is set.
+pub fn init(format: LogFormat, verbose: bool) {
+    let level = if verbose { Level::DEBUG } else { Level::INFO };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.event_format(PlainFormat).init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .init(),
+    }
+}
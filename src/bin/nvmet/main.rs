@@ -1,10 +1,41 @@
+mod alias;
+mod audit;
+mod batch;
+mod capabilities;
+mod color;
+mod connect_info;
+mod doctor;
+mod events;
+mod host;
+mod labels;
+mod log;
 mod namespace;
+mod output;
 mod port;
+mod prompt;
+mod raw;
 mod state;
+mod stats;
 mod subsystem;
+#[cfg(test)]
+mod test_dir;
+mod verify;
+mod wizard;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use color::Color;
+use log::LogFormat;
+use nvmetcfg::errors::{Error, ErrorKind};
+use nvmetcfg::helpers::has_nvmet_privileges;
+use nvmetcfg::kernel::KernelConfig;
+use nvmetcfg::state::{State, StateDelta};
+use output::{CliOutputFormat, OutputFormat};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, info};
 
 #[derive(Parser)]
 #[command(name = "nvmet")]
@@ -14,6 +45,39 @@ use clap::{Parser, Subcommand};
 struct Cli {
     #[command(subcommand)]
     command: CliCommands,
+
+    /// Output format of results and errors.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    output: CliOutputFormat,
+
+    /// Print JSON indented over multiple lines. This is the default if stdout is a terminal.
+    #[arg(long, global = true, conflicts_with = "json_compact")]
+    json_pretty: bool,
+
+    /// Print JSON on a single line. This is the default if stdout is not a terminal.
+    #[arg(long, global = true)]
+    json_compact: bool,
+
+    /// Don't check that objects are unchanged since the changes to them were computed.
+    #[arg(long, global = true)]
+    no_verify_preconditions: bool,
+
+    /// Skip changes already in effect, rather than writing the same values again.
+    /// If nothing is left to change, nothing is applied.
+    #[arg(long, global = true)]
+    no_op_if_unchanged: bool,
+
+    /// Format of status messages and warnings, which are written to stderr.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    log_format: LogFormat,
+
+    /// Also log each change and configfs write.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Append a record of all applied changes to this file, one JSON object per line.
+    #[arg(long, global = true, env = "NVMET_AUDIT_LOG", value_name = "PATH")]
+    audit_log: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -33,6 +97,80 @@ enum CliCommands {
         #[command(subcommand)]
         namespace_command: namespace::CliNamespaceCommands,
     },
+    /// NVMe-oF Target Subsystem Alias Commands
+    Alias {
+        #[command(subcommand)]
+        alias_command: alias::CliAliasCommands,
+    },
+    /// NVMe-oF Target Host Commands
+    Host {
+        #[command(subcommand)]
+        host_command: host::CliHostCommands,
+    },
+    /// Show which optional NVMe-oF Target features the running kernel supports.
+    ///
+    /// Existing Ports, Subsystems, Namespaces and Hosts are inspected. If there are none,
+    /// temporary ones are created and removed again, without ever being linked to a Port.
+    Capabilities,
+    /// Print how initiators can connect to the Subsystems on the Ports, for nvme-cli.
+    ///
+    /// Loop Ports are left out, as they can only be used locally.
+    ConnectInfo {
+        /// Print lines for the initiator's /etc/nvme/discovery.conf instead, one per address.
+        #[arg(long)]
+        discovery_conf: bool,
+
+        /// Initiator side address to connect from, added as --host-traddr.
+        #[arg(long)]
+        host_traddr: Option<String>,
+    },
+    /// Show the exported capacity and how many Namespaces, Ports and Hosts there are.
+    ///
+    /// Namespaces whose device can't be found are counted, but left out of the capacity.
+    Stats {
+        /// Show the capacity each allowed Host can reach instead, through the Subsystems on
+        /// Ports it is allowed on and those allowing any Host.
+        #[arg(long)]
+        by_host: bool,
+    },
+    /// Look for configuration the kernel accepts, but which doesn't work.
+    ///
+    /// TCP Ports whose address is not assigned to any local interface are accepted,
+    /// but nothing listens on them, so initiators time out.
+    Doctor,
+    /// Check that the configuration in the kernel is consistent.
+    ///
+    /// By default, the configuration is gathered and checked like a state file would be.
+    /// With --deep, the configfs tree itself is checked, listing every problem found, like
+    /// links to subsystems or hosts which don't exist, hosts no subsystem allows, enabled
+    /// namespaces whose device is gone and invalid NQNs.
+    /// Exits with an error if there are errors, warnings alone are fine.
+    Verify {
+        /// Check the configfs tree itself, rather than the configuration gathered from it.
+        #[arg(long)]
+        deep: bool,
+        /// Fix the problems which can be fixed without losing anything in use, like removing
+        /// links which don't resolve and hosts no subsystem allows.
+        #[arg(long, requires = "deep")]
+        repair: bool,
+    },
+    /// Print changes to the configuration as they happen, whoever makes them.
+    ///
+    /// Only changes made through configfs are seen, like those of nvmetcli or a shell.
+    /// Unloading and loading the nvmet module is reported as well.
+    Events,
+    /// Read or write attributes nvmetcfg doesn't support yet, like those of newer kernels.
+    ///
+    /// Values are passed through as they are, only the object and attribute name are checked.
+    Raw {
+        #[command(subcommand)]
+        raw_command: raw::CliRawCommands,
+    },
+    /// Apply Port, Subsystem and Namespace commands read from stdin all at once.
+    ///
+    /// Commands are given one per line, like on the command line, with # starting a comment.
+    /// All of them are checked before anything is changed.
+    Batch,
     /// NVMe-oF Target Subsystem State Management Commands
     State {
         #[command(subcommand)]
@@ -40,17 +178,253 @@ enum CliCommands {
     },
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// Categories of errors, which are also the exit codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCategory {
+    /// I/O errors and anything else unexpected.
+    Internal = 1,
+    /// The Port, Subsystem, Namespace, Host or alias does not exist.
+    NotFound = 2,
+    /// Invalid arguments or files.
+    InvalidInput = 3,
+    /// The object already exists, or the system state differs from what was expected.
+    Conflict = 4,
+    /// The kernel lacks nvmet or the requested feature.
+    Unsupported = 5,
+    /// Not running with the privileges needed, usually as root.
+    PermissionDenied = 6,
+}
+
+impl ErrorCategory {
+    /// Categorize an error by the first error of ours in its chain, see `Error::kind`.
+    fn of(err: &anyhow::Error) -> Self {
+        Error::find(err).map_or(Self::Internal, |err| err.kind().into())
+    }
+}
+
+impl From<ErrorKind> for ErrorCategory {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Internal => Self::Internal,
+            ErrorKind::NotFound => Self::NotFound,
+            ErrorKind::InvalidInput => Self::InvalidInput,
+            ErrorKind::Conflict => Self::Conflict,
+            ErrorKind::UnsupportedKernel => Self::Unsupported,
+            ErrorKind::PermissionDenied => Self::PermissionDenied,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorOutput {
+    error: ErrorReport,
+}
+
+#[derive(Serialize)]
+struct ErrorReport {
+    code: u8,
+    category: ErrorCategory,
+    message: String,
+    causes: Vec<String>,
+}
+
+/// Exit code of a query like `subsystem exists` answered with no.
+///
+/// None of the `ErrorCategory` codes, so scripts can tell it apart from failing to answer.
+const ABSENT_EXIT_CODE: u8 = 10;
 
-    match cli.command {
-        CliCommands::Port { port_command } => port::CliPortCommands::parse(port_command),
+/// A query like `subsystem exists` answered with no.
+///
+/// Exits with `ABSENT_EXIT_CODE`, without printing an error, so scripts can use it as condition.
+#[derive(Debug)]
+struct Absent;
+
+impl std::fmt::Display for Absent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Absent")
+    }
+}
+
+impl std::error::Error for Absent {}
+
+/// Succeed if `what` exists, or fail with `Absent`. The answer is only logged with `--verbose`.
+fn exists(exists: bool, what: &str) -> Result<()> {
+    if exists {
+        debug!("{what} exists.");
+        Ok(())
+    } else {
+        debug!("{what} does not exist.");
+        Err(Absent.into())
+    }
+}
+
+/// Print the error with one line per cause, or as JSON object, and return the exit code.
+fn report_error(err: &anyhow::Error, output: OutputFormat) -> ExitCode {
+    let category = ErrorCategory::of(err);
+    let code = category as u8;
+    let mut chain = err.chain().map(ToString::to_string);
+    let message = chain.next().unwrap_or_default();
+    let causes: Vec<String> = chain.collect();
+    match output {
+        OutputFormat::Text | OutputFormat::Csv => {
+            eprintln!("{} {message}", Color::Error.paint_stderr("Error:"));
+            for cause in &causes {
+                eprintln!("  Caused by: {cause}");
+            }
+        }
+        OutputFormat::Json | OutputFormat::Ndjson => {
+            let report = ErrorReport {
+                code,
+                category,
+                message,
+                causes,
+            };
+            match serde_json::to_string(&ErrorOutput { error: report }) {
+                Ok(json) => eprintln!("{json}"),
+                Err(_) => eprintln!("Error: {err:#}"),
+            }
+        }
+    }
+    ExitCode::from(code)
+}
+
+static NO_OP_IF_UNCHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Apply changes computed against `state`, checking their preconditions if `verify` is set.
+///
+/// Labels of removed Subsystems and Ports, and the stashes of removed offline Ports, are
+/// dropped afterwards, and the device specs of changed Namespaces recorded.
+///
+/// With `--no-op-if-unchanged`, changes already in effect in `state` are left out.
+fn apply_delta(state: &State, changes: Vec<StateDelta>, verify: bool) -> Result<()> {
+    let changes = if NO_OP_IF_UNCHANGED.load(Ordering::Relaxed) {
+        let pending = state.get_pending_deltas(&changes);
+        if pending.is_empty() {
+            info!("No changes.");
+            return Ok(());
+        }
+        pending
+    } else {
+        changes
+    };
+    let removals: Vec<StateDelta> = changes
+        .iter()
+        .filter(|d| {
+            matches!(
+                d,
+                StateDelta::RemoveSubsystem(_) | StateDelta::RemovePort(_)
+            )
+        })
+        .cloned()
+        .collect();
+    let subsystem_changes: Vec<StateDelta> = changes
+        .iter()
+        .filter(|d| {
+            matches!(
+                d,
+                StateDelta::AddSubsystem(..)
+                    | StateDelta::UpdateSubsystem(..)
+                    | StateDelta::RemoveSubsystem(_)
+            )
+        })
+        .cloned()
+        .collect();
+    audit::apply(changes, |changes| {
+        if verify {
+            KernelConfig::apply_delta_checked(state, changes)?;
+        } else {
+            KernelConfig::apply_delta(changes)?;
+        }
+        Ok(())
+    })?;
+    labels::forget_removed(&removals)?;
+    namespace::record_device_specs(&subsystem_changes)?;
+    port::forget_offline(&removals)
+}
+
+impl CliCommands {
+    /// Whether the command changes the configuration in the kernel, which needs privileges.
+    ///
+    /// Checking capabilities creates and removes temporary objects, and batches always change
+    /// something.
+    const fn mutates(&self) -> bool {
+        match self {
+            Self::Port { port_command } => port_command.mutates(),
+            Self::Subsystem { subsystem_command } => subsystem_command.mutates(),
+            Self::Namespace { namespace_command } => namespace_command.mutates(),
+            Self::Host { host_command } => host_command.mutates(),
+            Self::Raw { raw_command } => raw_command.mutates(),
+            Self::State { state_command } => state_command.mutates(),
+            Self::Verify { repair, .. } => *repair,
+            Self::Capabilities | Self::Batch => true,
+            Self::Alias { .. }
+            | Self::ConnectInfo { .. }
+            | Self::Stats { .. }
+            | Self::Doctor
+            | Self::Events => false,
+        }
+    }
+}
+
+fn run(command: CliCommands, output: CliOutputFormat, verify: bool) -> Result<()> {
+    // Fail before changing anything, rather than halfway through.
+    if command.mutates() && !has_nvmet_privileges() {
+        return Err(Error::PermissionDenied("/sys/kernel/config/nvmet".to_string()).into());
+    }
+    // Only state files are YAML, everything else is printed as JSON for it.
+    let (output, state_output) = (OutputFormat::from(output), output);
+    match command {
+        CliCommands::Port { port_command } => {
+            port::CliPortCommands::parse(port_command, output, verify)
+        }
         CliCommands::Subsystem { subsystem_command } => {
-            subsystem::CliSubsystemCommands::parse(subsystem_command)
+            subsystem::CliSubsystemCommands::parse(subsystem_command, output, verify)
         }
         CliCommands::Namespace { namespace_command } => {
-            namespace::CliNamespaceCommands::parse(namespace_command)
+            namespace::CliNamespaceCommands::parse(namespace_command, output, verify)
+        }
+        CliCommands::Alias { alias_command } => alias::CliAliasCommands::parse(alias_command),
+        CliCommands::Host { host_command } => host::CliHostCommands::parse(host_command, output),
+        CliCommands::Capabilities => capabilities::show(output),
+        CliCommands::Stats { by_host } => stats::show(by_host, output),
+        CliCommands::Doctor => doctor::check(),
+        CliCommands::Verify { deep, repair } => verify::check(deep, repair, output),
+        CliCommands::Events => events::watch(output),
+        CliCommands::ConnectInfo {
+            discovery_conf,
+            host_traddr,
+        } => connect_info::show(discovery_conf, host_traddr.as_deref()),
+        CliCommands::Raw { raw_command } => raw::CliRawCommands::parse(raw_command, output),
+        CliCommands::Batch => batch::run(verify),
+        CliCommands::State { state_command } => {
+            state::CliStateCommands::parse(state_command, state_output, verify)
         }
-        CliCommands::State { state_command } => state::CliStateCommands::parse(state_command),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(err) => {
+            // Usage errors count as invalid input, rather than clap's exit code 2.
+            let _ = err.print();
+            return if err.use_stderr() {
+                ExitCode::from(ErrorCategory::InvalidInput as u8)
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+
+    log::init(cli.log_format, cli.verbose);
+    color::init();
+    output::init_json_style(cli.json_pretty, cli.json_compact);
+    audit::init(cli.audit_log);
+    NO_OP_IF_UNCHANGED.store(cli.no_op_if_unchanged, Ordering::Relaxed);
+    match run(cli.command, cli.output, !cli.no_verify_preconditions) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) if err.is::<Absent>() => ExitCode::from(ABSENT_EXIT_CODE),
+        Err(err) => report_error(&err, cli.output.into()),
     }
 }
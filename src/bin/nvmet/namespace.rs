@@ -1,34 +1,64 @@
-use anyhow::Result;
-use clap::Subcommand;
+use crate::alias::resolve_sub;
+use crate::color::Color;
+use crate::output::{print_json, print_ndjson, OutputFormat};
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
 use nvmetcfg::errors::Error;
-use nvmetcfg::helpers::assert_valid_nqn;
+use nvmetcfg::helpers::{
+    identify_nvme_namespace, match_device_identity, probe_block_device, probe_device_identities,
+    BlockDeviceInfo, CsvWriter, DeviceIdentity, DeviceSpec, IdentityMatch,
+};
 use nvmetcfg::kernel::KernelConfig;
-use nvmetcfg::state::{Namespace, StateDelta, SubsystemDelta};
+use nvmetcfg::state::{
+    DeviceSpecs, Namespace, State, StateDelta, Subsystem, SubsystemDelta, DEVICE_SPEC_FILE,
+};
 
-use std::path::PathBuf;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 use uuid::Uuid;
 
 #[derive(Subcommand)]
 pub enum CliNamespaceCommands {
     /// Show detailed information about the Namespaces of a Subsystem.
+    ///
+    /// The backing devices are probed for their filesystem, label and device-mapper name,
+    /// without writing to them.
     Show {
-        /// NVMe Qualified Name of the Subsystem.
+        /// NVMe Qualified Name or @alias of the Subsystem.
         sub: String,
+
+        /// Don't probe the backing devices.
+        #[arg(long)]
+        no_probe: bool,
     },
-    /// List Namespaces of a Subsystem.
+    /// Exit with 0 if the Namespace exists, or 10 if not, without printing anything.
+    ///
+    /// Failing to find out, like when configfs can't be read, exits with an error code from 1 to 6.
+    ///
+    /// A Namespace of a Subsystem that doesn't exist doesn't exist either.
+    Exists {
+        /// NVMe Qualified Name or @alias of the Subsystem.
+        sub: String,
+
+        /// Namespace ID.
+        nsid: u32,
+    },
+    /// List Namespaces of a Subsystem, with their devices if printed as CSV.
     List {
-        /// NVMe Qualified Name of the Subsystem.
+        /// NVMe Qualified Name or @alias of the Subsystem.
         sub: String,
     },
     /// Add a Namespace to an existing Subsystem.
     Add {
-        /// NVMe Qualified Name of the Subsystem.
+        /// NVMe Qualified Name or @alias of the Subsystem.
         sub: String,
 
         /// Namespace ID of the new namespace.
         nsid: u32,
 
-        /// Path to the block device.
+        /// Path to the block device, or the filesystem on it as UUID=<uuid> or LABEL=<label>.
         path: PathBuf,
 
         /// Do not enable it after creation.
@@ -42,16 +72,22 @@ pub enum CliNamespaceCommands {
         /// Optionally set the NGUID.
         #[arg(long)]
         nguid: Option<Uuid>,
+
+        /// Use the UUID and NGUID the device reports, if it is an NVMe namespace itself.
+        ///
+        /// Initiators then see the same identity as when the drive was attached directly.
+        #[arg(long, conflicts_with_all = ["uuid", "nguid"])]
+        inherit_identity: bool,
     },
     /// Update an existing Namespace of a Subsystem.
     Update {
-        /// NVMe Qualified Name of the Subsystem.
+        /// NVMe Qualified Name or @alias of the Subsystem.
         sub: String,
 
         /// Namespace ID of the new namespace.
         nsid: u32,
 
-        /// Path to the block device.
+        /// Path to the block device, or the filesystem on it as UUID=<uuid> or LABEL=<label>.
         path: PathBuf,
 
         /// Do not enable it after creation.
@@ -66,52 +102,335 @@ pub enum CliNamespaceCommands {
         #[arg(long)]
         nguid: Option<Uuid>,
     },
+    /// Enable a Namespace, or all Namespaces, of a Subsystem.
+    Enable {
+        /// NVMe Qualified Name or @alias of the Subsystem.
+        sub: String,
+
+        /// Namespace ID of the namespace to be enabled.
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        nsid: Option<u32>,
+
+        #[command(flatten)]
+        all: CliAllNamespaces,
+    },
+    /// Disable a Namespace, or all Namespaces, of a Subsystem.
+    Disable {
+        /// NVMe Qualified Name or @alias of the Subsystem.
+        sub: String,
+
+        /// Namespace ID of the namespace to be disabled.
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        nsid: Option<u32>,
+
+        #[command(flatten)]
+        all: CliAllNamespaces,
+    },
+    /// Check that the device of a Namespace has the Namespace's UUID.
+    ///
+    /// The UUID is compared to the filesystem, partition table and partition UUIDs and the
+    /// WWID of the device, to catch a different disk having taken its place after renumbering.
+    Verify {
+        /// NVMe Qualified Name or @alias of the Subsystem.
+        sub: String,
+
+        /// Namespace ID of the namespace to be verified.
+        nsid: u32,
+    },
     /// Remove a Namespace from a Subsystem.
     Remove {
-        /// NVMe Qualified Name of the Subsystem.
+        /// NVMe Qualified Name or @alias of the Subsystem.
         sub: String,
 
         /// Namespace ID of the namespace to be removed.
         nsid: u32,
     },
+    /// Give the Namespaces of a Subsystem sequential Namespace IDs, keeping their order.
+    ///
+    /// Each Namespace is removed and added back under its new ID with the same device, UUID
+    /// and NGUID. Connected initiators lose it for a moment and see it under the new ID.
+    Renumber {
+        /// NVMe Qualified Name or @alias of the Subsystem.
+        sub: String,
+
+        /// Namespace ID of the first Namespace.
+        #[arg(long, default_value_t = 1)]
+        start: u32,
+
+        /// Only print the new Namespace IDs and the changes.
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Confirm changing the Namespace IDs initiators see.
+        #[arg(long, short)]
+        yes: bool,
+    },
 }
 
-impl CliNamespaceCommands {
-    pub(super) fn parse(command: Self) -> Result<()> {
-        match command {
-            Self::Show { sub } => {
-                assert_valid_nqn(&sub)?;
-                let state = KernelConfig::gather_state()?;
-                if let Some(subsystem) = state.subsystems.get(&sub) {
-                    println!("Number of Namespaces: {}", subsystem.namespaces.len());
-                    for (nsid, ns) in &subsystem.namespaces {
-                        println!("Namespace {nsid}:");
-                        println!("\tEnabled: {}", ns.enabled);
-                        println!("\tDevice Path: {}", ns.device_path.display());
-                        println!(
-                            "\tDevice UUID: {}",
-                            ns.device_uuid.expect("device_uuid should always be set")
-                        );
-                        println!(
-                            "\tDevice NGUID: {}",
-                            ns.device_nguid.expect("device_nguid should always be set")
-                        );
-                    }
-                } else {
-                    return Err(Error::NoSuchSubsystem(sub).into());
-                }
+#[derive(Args)]
+pub struct CliAllNamespaces {
+    /// Apply to all Namespaces of the Subsystem.
+    #[arg(long)]
+    all: bool,
+
+    /// Only consider Namespaces which are currently enabled.
+    #[arg(long, requires = "all", conflicts_with = "only_disabled")]
+    only_enabled: bool,
+
+    /// Only consider Namespaces which are currently disabled.
+    #[arg(long, requires = "all")]
+    only_disabled: bool,
+}
+
+impl CliAllNamespaces {
+    fn only(&self) -> Option<bool> {
+        if self.only_enabled {
+            Some(true)
+        } else if self.only_disabled {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+// Returns how many namespaces were selected, along with the changes.
+fn enable_deltas(
+    state: &State,
+    sub: String,
+    nsid: Option<u32>,
+    all: &CliAllNamespaces,
+    enabled: bool,
+) -> Result<(usize, Vec<StateDelta>)> {
+    let sub = resolve_sub(sub)?;
+    let Some(subsystem) = state.subsystems.get(&sub) else {
+        return Err(Error::NoSuchSubsystem(sub).into());
+    };
+
+    let (selected, deltas) = if let Some(nsid) = nsid {
+        let ns = subsystem
+            .namespaces
+            .get(&nsid)
+            .ok_or_else(|| Error::NoSuchNamespace(nsid, sub.clone()))?;
+        if ns.enabled == enabled {
+            (1, Vec::new())
+        } else {
+            (1, vec![SubsystemDelta::SetNamespaceEnabled(nsid, enabled)])
+        }
+    } else {
+        let only = all.only();
+        let selected = subsystem
+            .namespaces
+            .values()
+            .filter(|ns| only.map_or(true, |only| ns.enabled == only))
+            .count();
+        (selected, subsystem.get_enable_deltas(enabled, only))
+    };
+
+    if deltas.is_empty() {
+        Ok((selected, Vec::new()))
+    } else {
+        Ok((selected, vec![StateDelta::UpdateSubsystem(sub, deltas)]))
+    }
+}
+
+fn set_enabled(
+    sub: String,
+    nsid: Option<u32>,
+    all: &CliAllNamespaces,
+    enabled: bool,
+    verify: bool,
+) -> Result<()> {
+    let state = match nsid {
+        Some(nsid) => namespace_state(resolve_sub(sub.clone())?, nsid)?,
+        None => KernelConfig::gather_state()?,
+    };
+    let (selected, deltas) = enable_deltas(&state, sub, nsid, all, enabled)?;
+    let changed = match deltas.first() {
+        Some(StateDelta::UpdateSubsystem(_, sub_deltas)) => sub_deltas.len(),
+        _ => 0,
+    };
+    if !deltas.is_empty() {
+        crate::apply_delta(&state, deltas, verify)?;
+    }
+    if all.all {
+        let state = if enabled { "enabled" } else { "disabled" };
+        info!(
+            "Namespaces {state}: {changed}, already {state}: {}",
+            selected - changed
+        );
+    }
+    Ok(())
+}
+
+/// Renumber the Namespaces of `sub`, see `CliNamespaceCommands::Renumber`.
+fn renumber(sub: String, start: u32, dry_run: bool, yes: bool, verify: bool) -> Result<()> {
+    let sub = resolve_sub(sub)?;
+    let state = KernelConfig::gather_state()?;
+    let renumbering = state.plan_namespace_renumbering(&sub, start)?;
+    if renumbering.is_noop() {
+        info!("Namespaces of subsystem {sub} are already numbered from {start}.");
+        return Ok(());
+    }
+
+    println!("Old NSID -> New NSID");
+    for (old, new) in &renumbering.mapping {
+        println!("{old:>8} -> {new}");
+    }
+    warn!("Renumbering changes the NSIDs initiators see! Each namespace is removed and added back, so connected initiators lose it for a moment and find it under its new NSID.");
+    if dry_run {
+        for change in &renumbering.deltas {
+            println!("\t{}", crate::color::delta(change));
+        }
+        return Ok(());
+    }
+    if !yes {
+        return Err(Error::UnconfirmedRenumbering(sub).into());
+    }
+
+    crate::apply_delta(&state, renumbering.deltas, verify)?;
+    let moved = renumbering
+        .mapping
+        .iter()
+        .filter(|(old, new)| old != new)
+        .count();
+    info!("Renumbered {moved} namespaces of subsystem {sub}.");
+    Ok(())
+}
+
+/// Read only the Namespace `nsid`, as the state of a Subsystem having just that one.
+fn namespace_state(sub: String, nsid: u32) -> Result<State> {
+    let Some(ns) = KernelConfig::get_namespace(&sub, nsid)? else {
+        return Err(Error::NoSuchNamespace(nsid, sub).into());
+    };
+    let subsystem = Subsystem {
+        namespaces: BTreeMap::from([(nsid, ns)]),
+        ..Default::default()
+    };
+    let mut state = State::default();
+    state.subsystems.insert(sub, subsystem);
+    Ok(state)
+}
+
+/// The outcome of `namespace verify`, as printed with `--output json`.
+#[derive(Serialize)]
+struct IdentityReport<'a> {
+    nsid: u32,
+    device_path: &'a Path,
+    device_uuid: Option<Uuid>,
+    /// Either `match`, `mismatch` or `unknown`.
+    result: &'static str,
+    identities: &'a [DeviceIdentity],
+}
+
+fn verify_identity(sub: String, nsid: u32, output: OutputFormat) -> Result<()> {
+    let sub = resolve_sub(sub)?;
+    let Some(ns) = KernelConfig::get_namespace(&sub, nsid)? else {
+        return Err(Error::NoSuchNamespace(nsid, sub).into());
+    };
+    let expected = ns
+        .device_uuid
+        .ok_or_else(|| missing_attr(&sub, nsid, "device_uuid"))?;
+    let identities = probe_device_identities(&ns.device_path);
+    let result = match_device_identity(expected, &identities);
+
+    let report = IdentityReport {
+        nsid,
+        device_path: &ns.device_path,
+        device_uuid: ns.device_uuid,
+        result: match result {
+            IdentityMatch::Match(_) => "match",
+            IdentityMatch::Mismatch => "mismatch",
+            IdentityMatch::Unknown => "unknown",
+        },
+        identities: &identities,
+    };
+    if output == OutputFormat::Json {
+        print_json(&report)?;
+    }
+    let device = ns.device_path.display();
+    match result {
+        IdentityMatch::Match(identity) => {
+            if output != OutputFormat::Json {
+                println!(
+                    "Device {device} matches UUID {expected} ({} UUID).",
+                    identity.source
+                );
             }
-            Self::List { sub } => {
-                assert_valid_nqn(&sub)?;
-                let state = KernelConfig::gather_state()?;
-                if let Some(subsystem) = state.subsystems.get(&sub) {
-                    for nsid in subsystem.namespaces.keys() {
-                        println!("{nsid}");
-                    }
-                } else {
-                    return Err(Error::NoSuchSubsystem(sub).into());
-                }
+            Ok(())
+        }
+        IdentityMatch::Mismatch => {
+            for identity in &identities {
+                warn!(
+                    "Device {device} has {} UUID {}",
+                    identity.source, identity.uuid
+                );
+            }
+            Err(Error::DeviceIdentityMismatch(nsid, sub, expected).into())
+        }
+        IdentityMatch::Unknown => {
+            warn!("Could not determine the identity of device {device}, nothing to compare.");
+            Ok(())
+        }
+    }
+}
+
+/// Take the UUID and NGUID of the namespace from its device, which is an NVMe namespace.
+fn inherit_nvme_identity(ns: &mut Namespace) -> Result<()> {
+    let identity = identify_nvme_namespace(&ns.device_path)?;
+    if let Some(eui64) = identity.eui64 {
+        warn!(
+            "{} also has the EUI-64 {eui64:016x}, which nvmet can't pass on.",
+            ns.device_path.display()
+        );
+    }
+    ns.device_uuid = identity.uuid;
+    ns.device_nguid = identity.nguid;
+    Ok(())
+}
+
+/// The path of `device` and how it was given, finding it first if given as UUID=<uuid> or
+/// LABEL=<label>.
+fn resolve_device(device: PathBuf) -> Result<(PathBuf, Option<DeviceSpec>)> {
+    match device.to_str().map(str::parse::<DeviceSpec>) {
+        Some(Ok(spec)) => Ok((spec.resolve()?, Some(spec))),
+        _ => Ok((device, None)),
+    }
+}
+
+/// Record the device specs of the Namespaces changed by `deltas`, see `DeviceSpecs::record`.
+pub fn record_device_specs(deltas: &[StateDelta]) -> Result<()> {
+    if deltas.is_empty() {
+        return Ok(());
+    }
+    let mut specs = DeviceSpecs::load(DEVICE_SPEC_FILE)?;
+    if specs.record(deltas) {
+        specs
+            .save(DEVICE_SPEC_FILE)
+            .context("Failed to save device specs of namespaces")?;
+    }
+    Ok(())
+}
+
+impl CliNamespaceCommands {
+    /// Whether the command changes the configuration in the kernel, which needs privileges.
+    pub(super) const fn mutates(&self) -> bool {
+        match self {
+            Self::Show { .. } | Self::Exists { .. } | Self::List { .. } | Self::Verify { .. } => {
+                false
             }
+            Self::Add { .. }
+            | Self::Update { .. }
+            | Self::Enable { .. }
+            | Self::Disable { .. }
+            | Self::Remove { .. } => true,
+            Self::Renumber { dry_run, .. } => !*dry_run,
+        }
+    }
+
+    /// The changes a command makes to `state`, or `None` if it doesn't change anything.
+    pub(super) fn deltas(command: Self, state: &State) -> Result<Option<Vec<StateDelta>>> {
+        let deltas = match command {
             Self::Add {
                 sub,
                 nsid,
@@ -119,18 +438,24 @@ impl CliNamespaceCommands {
                 disabled,
                 uuid,
                 nguid,
+                inherit_identity,
             } => {
-                assert_valid_nqn(&sub)?;
-                let new_ns = Namespace {
+                let sub = resolve_sub(sub)?;
+                let (device_path, device_spec) = resolve_device(path)?;
+                let mut new_ns = Namespace {
                     enabled: !disabled,
-                    device_path: path,
+                    device_path,
+                    device_spec,
                     device_uuid: uuid,
                     device_nguid: nguid,
                 };
-                KernelConfig::apply_delta(vec![StateDelta::UpdateSubsystem(
+                if inherit_identity {
+                    inherit_nvme_identity(&mut new_ns)?;
+                }
+                vec![StateDelta::UpdateSubsystem(
                     sub,
                     vec![SubsystemDelta::AddNamespace(nsid, new_ns)],
-                )])?;
+                )]
             }
             Self::Update {
                 sub,
@@ -140,26 +465,187 @@ impl CliNamespaceCommands {
                 uuid,
                 nguid,
             } => {
-                assert_valid_nqn(&sub)?;
+                let sub = resolve_sub(sub)?;
+                let (device_path, device_spec) = resolve_device(path)?;
                 let new_ns = Namespace {
                     enabled: !disabled,
-                    device_path: path,
+                    device_path,
+                    device_spec,
                     device_uuid: uuid,
                     device_nguid: nguid,
                 };
-                KernelConfig::apply_delta(vec![StateDelta::UpdateSubsystem(
+                vec![StateDelta::UpdateSubsystem(
                     sub,
                     vec![SubsystemDelta::UpdateNamespace(nsid, new_ns)],
-                )])?;
+                )]
             }
+            Self::Enable { sub, nsid, all } => enable_deltas(state, sub, nsid, &all, true)?.1,
+            Self::Disable { sub, nsid, all } => enable_deltas(state, sub, nsid, &all, false)?.1,
             Self::Remove { sub, nsid } => {
-                assert_valid_nqn(&sub)?;
-                KernelConfig::apply_delta(vec![StateDelta::UpdateSubsystem(
+                let sub = resolve_sub(sub)?;
+                vec![StateDelta::UpdateSubsystem(
                     sub,
                     vec![SubsystemDelta::RemoveNamespace(nsid)],
-                )])?;
+                )]
+            }
+            Self::Renumber { dry_run: true, .. } => return Ok(None),
+            Self::Renumber {
+                sub, start, yes, ..
+            } => {
+                let sub = resolve_sub(sub)?;
+                if !yes {
+                    return Err(Error::UnconfirmedRenumbering(sub).into());
+                }
+                state.plan_namespace_renumbering(&sub, start)?.deltas
+            }
+            Self::Show { .. } | Self::Exists { .. } | Self::List { .. } | Self::Verify { .. } => {
+                return Ok(None)
+            }
+        };
+        Ok(Some(deltas))
+    }
+
+    pub(super) fn parse(command: Self, output: OutputFormat, verify: bool) -> Result<()> {
+        match command {
+            Self::Exists { sub, nsid } => {
+                let sub = resolve_sub(sub)?;
+                let exists = match KernelConfig::get_namespace(&sub, nsid) {
+                    Ok(ns) => ns.is_some(),
+                    Err(Error::NoSuchSubsystem(_)) => false,
+                    Err(err) => return Err(err.into()),
+                };
+                crate::exists(exists, &format!("Namespace {nsid} of subsystem {sub}"))?;
+            }
+            Self::Show { sub, no_probe } => {
+                let sub = resolve_sub(sub)?;
+                let Some(subsystem) = KernelConfig::get_subsystem(&sub)? else {
+                    return Err(Error::NoSuchSubsystem(sub).into());
+                };
+                // Devices are probed one by one, so NDJSON lines come out as they're done.
+                let namespaces =
+                    subsystem
+                        .namespaces
+                        .iter()
+                        .map(|(&nsid, namespace)| NamespaceInfo {
+                            nsid,
+                            namespace,
+                            device: (!no_probe).then(|| probe_block_device(&namespace.device_path)),
+                        });
+                match output {
+                    OutputFormat::Ndjson => print_ndjson(namespaces)?,
+                    OutputFormat::Json => {
+                        print_json(&namespaces.collect::<Vec<_>>())?;
+                    }
+                    OutputFormat::Text | OutputFormat::Csv => {
+                        show_namespaces(&sub, &namespaces.collect::<Vec<_>>())?;
+                    }
+                }
+            }
+            Self::List { sub } => {
+                let sub = resolve_sub(sub)?;
+                let Some(subsystem) = KernelConfig::get_subsystem(&sub)? else {
+                    return Err(Error::NoSuchSubsystem(sub).into());
+                };
+                if output == OutputFormat::Ndjson {
+                    print_ndjson(subsystem.namespaces.iter().map(|(&nsid, namespace)| {
+                        NamespaceInfo {
+                            nsid,
+                            namespace,
+                            device: None,
+                        }
+                    }))?;
+                } else if output == OutputFormat::Csv {
+                    let mut csv = CsvWriter::new(&[
+                        "nsid",
+                        "enabled",
+                        "device_path",
+                        "device_uuid",
+                        "device_nguid",
+                    ]);
+                    let uuid = |uuid: Option<Uuid>| uuid.map(|u| u.to_string()).unwrap_or_default();
+                    for (nsid, ns) in &subsystem.namespaces {
+                        csv.row(&[
+                            nsid.to_string(),
+                            ns.enabled.to_string(),
+                            ns.device_path.display().to_string(),
+                            uuid(ns.device_uuid),
+                            uuid(ns.device_nguid),
+                        ]);
+                    }
+                    print!("{}", csv.finish());
+                } else {
+                    for nsid in subsystem.namespaces.keys() {
+                        println!("{nsid}");
+                    }
+                }
+            }
+            Self::Verify { sub, nsid } => verify_identity(sub, nsid, output)?,
+            Self::Enable { sub, nsid, all } => set_enabled(sub, nsid, &all, true, verify)?,
+            Self::Disable { sub, nsid, all } => set_enabled(sub, nsid, &all, false, verify)?,
+            Self::Renumber {
+                sub,
+                start,
+                dry_run,
+                yes,
+            } => renumber(sub, start, dry_run, yes, verify)?,
+            command => {
+                let state = KernelConfig::gather_state()?;
+                if let Some(deltas) = Self::deltas(command, &state)? {
+                    crate::apply_delta(&state, deltas, verify)?;
+                }
             }
         }
         Ok(())
     }
 }
+
+/// A Namespace as shown, with what was found on its backing device.
+#[derive(Serialize)]
+struct NamespaceInfo<'a> {
+    nsid: u32,
+    #[serde(flatten)]
+    namespace: &'a Namespace,
+    #[serde(flatten)]
+    device: Option<BlockDeviceInfo>,
+}
+
+// The kernel always has the UUID and NGUID of namespaces, unless the tree is broken.
+fn missing_attr(sub: &str, nsid: u32, attr: &str) -> Error {
+    Error::NoSuchAttribute(format!("namespace:{sub}/{nsid}"), attr.to_string())
+}
+
+fn show_namespaces(sub: &str, namespaces: &[NamespaceInfo]) -> Result<()> {
+    println!("Number of Namespaces: {}", namespaces.len());
+    for NamespaceInfo {
+        nsid,
+        namespace: ns,
+        device,
+    } in namespaces
+    {
+        println!("{}", Color::Header.paint(format!("Namespace {nsid}:")));
+        println!("\tEnabled: {}", ns.enabled);
+        println!("\tDevice Path: {}", ns.device_path.display());
+        let uuid = ns
+            .device_uuid
+            .ok_or_else(|| missing_attr(sub, *nsid, "device_uuid"))?;
+        let nguid = ns
+            .device_nguid
+            .ok_or_else(|| missing_attr(sub, *nsid, "device_nguid"))?;
+        println!("\tDevice UUID: {uuid}");
+        println!("\tDevice NGUID: {nguid}");
+        let Some(device) = device else {
+            continue;
+        };
+        if let Some(dm_name) = &device.dm_name {
+            println!("\tDevice Mapper Name: {dm_name}");
+        }
+        println!(
+            "\tFilesystem: {}",
+            device.fs_type.as_deref().unwrap_or("unknown")
+        );
+        if let Some(fs_label) = &device.fs_label {
+            println!("\tFilesystem Label: {fs_label}");
+        }
+    }
+    Ok(())
+}
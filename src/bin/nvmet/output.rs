@@ -0,0 +1,143 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use nvmetcfg::helpers::write_ndjson;
+use serde::Serialize;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+static JSON_STYLE: OnceLock<JsonStyle> = OnceLock::new();
+
+/// How to print the results of a command, as given by `--output`.
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CliOutputFormat {
+    /// Human readable text.
+    #[default]
+    Text,
+    /// JSON, for scripts.
+    Json,
+    /// JSON with one object per line, printed as soon as it's known. For large listings.
+    Ndjson,
+    /// CSV, for spreadsheets. Commands without tabular results print text instead.
+    Csv,
+    /// YAML, like state files. Commands other than `state show` print JSON, which is valid YAML.
+    Yaml,
+}
+
+/// How commands without YAML output of their own print their results, see `CliOutputFormat`.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl From<CliOutputFormat> for OutputFormat {
+    fn from(output: CliOutputFormat) -> Self {
+        match output {
+            CliOutputFormat::Text => Self::Text,
+            CliOutputFormat::Json | CliOutputFormat::Yaml => Self::Json,
+            CliOutputFormat::Ndjson => Self::Ndjson,
+            CliOutputFormat::Csv => Self::Csv,
+        }
+    }
+}
+
+/// How JSON output is laid out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JsonStyle {
+    /// Indented over multiple lines, for humans.
+    #[default]
+    Pretty,
+    /// On a single line without any extra whitespace, for machines.
+    Compact,
+}
+
+impl JsonStyle {
+    /// Serialize `value` as JSON in this style.
+    pub fn to_string<T: Serialize + ?Sized>(self, value: &T) -> Result<String> {
+        Ok(match self {
+            Self::Pretty => serde_json::to_string_pretty(value)?,
+            Self::Compact => serde_json::to_string(value)?,
+        })
+    }
+}
+
+/// Several values in a single CSV field, separated by spaces.
+pub fn csv_list<T: ToString>(values: impl IntoIterator<Item = T>) -> String {
+    values
+        .into_iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Set how `print_json` lays out JSON: as asked for, or pretty only if stdout is a terminal.
+pub fn init_json_style(pretty: bool, compact: bool) {
+    let style = if compact || !(pretty || std::io::stdout().is_terminal()) {
+        JsonStyle::Compact
+    } else {
+        JsonStyle::Pretty
+    };
+    let _ = JSON_STYLE.set(style);
+}
+
+/// Print each of `values` as JSON object on a line of its own.
+pub fn print_ndjson<T: Serialize>(values: impl IntoIterator<Item = T>) -> Result<()> {
+    Ok(write_ndjson(&mut std::io::stdout().lock(), values)?)
+}
+
+/// Print `value` as JSON, in the style chosen by `init_json_style`.
+pub fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<()> {
+    let style = JSON_STYLE.get().copied().unwrap_or_default();
+    println!("{}", style.to_string(value)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_json_style() -> Result<()> {
+        let value = BTreeMap::from([
+            ("nqn", serde_json::json!("nqn.2023-11.sh.tty:test")),
+            ("namespaces", serde_json::json!([1, 2])),
+            ("model", serde_json::json!("Linux Target")),
+        ]);
+        let pretty = JsonStyle::Pretty.to_string(&value)?;
+        let compact = JsonStyle::Compact.to_string(&value)?;
+        assert_ne!(pretty, compact);
+        assert!(pretty.contains('\n') && !compact.contains('\n'));
+
+        // Both only differ in whitespace outside of strings.
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&pretty)?,
+            serde_json::from_str::<serde_json::Value>(&compact)?
+        );
+        let strip = |json: &str| -> String {
+            let mut in_string = false;
+            let mut escaped = false;
+            json.chars()
+                .filter(|&c| {
+                    if in_string {
+                        match c {
+                            _ if escaped => escaped = false,
+                            '\\' => escaped = true,
+                            '"' => in_string = false,
+                            _ => {}
+                        }
+                        true
+                    } else {
+                        in_string = c == '"';
+                        !c.is_whitespace()
+                    }
+                })
+                .collect()
+        };
+        assert_eq!(strip(&pretty), compact);
+        Ok(())
+    }
+}
@@ -1,77 +1,185 @@
-use anyhow::Result;
-use clap::{Subcommand, ValueEnum};
+use crate::alias::resolve_sub;
+use crate::color::Color;
+use crate::labels::{self, LabelArgs, LabelFilter};
+use crate::output::{csv_list, print_json, print_ndjson, OutputFormat};
+use crate::prompt::confirm;
+use anyhow::{bail, Context, Result};
+use clap::{Args, Subcommand, ValueEnum};
 use nvmetcfg::errors::Error;
-use nvmetcfg::helpers::assert_valid_nqn;
-use nvmetcfg::kernel::KernelConfig;
-use nvmetcfg::state::{Port, PortDelta, PortType, StateDelta};
-use std::collections::BTreeSet;
+use nvmetcfg::helpers::{is_listening, listening_tcp_sockets, local_fc_hosts, CsvWriter};
+use nvmetcfg::kernel::{loop_port_controllers, KernelConfig};
+use nvmetcfg::state::{
+    labels_match, resolve_socket_addr, AddrFamily, LabelStore, OfflinePorts, Port, PortDelta,
+    PortInterface, PortMigration, PortType, State, StateDelta, LABEL_FILE, OFFLINE_FILE,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
 #[derive(Subcommand)]
 pub enum CliPortCommands {
     /// Show detailed Port information.
-    Show,
-    /// List only the Port names.
-    List,
+    Show {
+        /// Port ID, instead of showing all Ports.
+        pid: Option<u16>,
+
+        /// Check whether the kernel is listening on the address of each TCP Port.
+        #[arg(long)]
+        check: bool,
+    },
+    /// Exit with 0 if the Port exists, or 10 if not, without printing anything.
+    ///
+    /// Failing to find out, like when configfs can't be read, exits with an error code from 1 to 6.
+    Exists {
+        /// Port ID.
+        pid: u16,
+    },
+    /// Wait until the Port exists, like when another process is creating it.
+    Wait {
+        /// Port ID.
+        pid: u16,
+
+        /// Seconds to wait before giving up.
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        timeout: u64,
+    },
+    /// List only the Port names, or with their addresses as CSV.
+    List {
+        #[command(flatten)]
+        filter: LabelFilter,
+    },
     /// Create a new Port.
     Add {
         /// Port ID to use.
         pid: u16,
 
-        /// Type of Port.
-        port_type: CliPortType,
+        /// Type of Port: loop, tcp, rdma or fc.
+        /// Or the whole address as URI instead, like tcp://192.0.2.1:4420 or rdma://[fdff::1].
+        #[arg(
+            verbatim_doc_comment,
+            value_name = "PORT_TYPE|URI",
+            value_parser = parse_port_spec,
+            requires_if("tcp", "addr"),
+            requires_if("rdma", "addr"),
+            requires_if("fc", "address")
+        )]
+        port_type: CliPortSpec,
 
         /// Port Address to use.
         ///
-        /// For Tcp and Rdma port types, this should be an IP address and Port:
+        /// For Tcp and Rdma port types, this should be an IP address or host name and Port:
         /// IPv4: 1.2.3.4:4420
         /// IPv6: [::1]:4420
+        /// Host name: storage.example:4420
         ///
         /// For Fibre Channel transport, this should be the WWNN/WWPN in the following format:
         /// Long:  nn-0x1000000044001123:pn-0x2000000055001123
         /// Short: nn-1000000044001123:pn-2000000055001123
-        #[arg(
-            verbatim_doc_comment,
-            required_if_eq("port_type", "tcp"),
-            required_if_eq("port_type", "rdma"),
-            required_if_eq("port_type", "fc")
-        )]
+        #[arg(verbatim_doc_comment, group = "addr")]
         address: Option<String>,
+
+        /// Use the current global address of a network interface instead, like eth1:4420.
+        #[arg(long, value_name = "IFACE:PORT", group = "addr")]
+        interface: Option<String>,
+
+        /// Address family to use if the interface has global addresses of both.
+        #[arg(long, value_enum, requires = "interface", conflicts_with = "address")]
+        prefer: Option<CliAddrFamily>,
+
+        /// Address family to listen on, picking the address of a host name resolving to both.
+        /// Without it, the first address resolved is used.
+        #[arg(long, value_enum, requires = "address")]
+        adrfam: Option<CliAddrFamily>,
+
+        #[command(flatten)]
+        wwn_check: WwnCheckArgs,
     },
     /// Update an existing Port.
     Update {
         /// Port ID to use.
         pid: u16,
 
-        /// Type of Port.
-        port_type: CliPortType,
+        /// Type of Port: loop, tcp, rdma or fc.
+        /// Or the whole address as URI instead, like tcp://192.0.2.1:4420 or rdma://[fdff::1].
+        #[arg(
+            verbatim_doc_comment,
+            value_name = "PORT_TYPE|URI",
+            value_parser = parse_port_spec,
+            requires_if("tcp", "addr"),
+            requires_if("rdma", "addr"),
+            requires_if("fc", "address")
+        )]
+        port_type: CliPortSpec,
 
         /// Port Address to use.
         ///
-        /// For Tcp and Rdma port types, this should be an IP address and Port:
+        /// For Tcp and Rdma port types, this should be an IP address or host name and Port:
         /// IPv4: 1.2.3.4:4420
         /// IPv6: [::1]:4420
+        /// Host name: storage.example:4420
         ///
         /// For Fibre Channel transport, this should be the WWNN/WWPN in the following format:
         /// Long:  nn-0x1000000044001123:pn-0x2000000055001123
         /// Short: nn-1000000044001123:pn-2000000055001123
-        #[arg(
-            verbatim_doc_comment,
-            required_if_eq("port_type", "tcp"),
-            required_if_eq("port_type", "rdma"),
-            required_if_eq("port_type", "fc")
-        )]
+        #[arg(verbatim_doc_comment, group = "addr")]
         address: Option<String>,
+
+        /// Use the current global address of a network interface instead, like eth1:4420.
+        #[arg(long, value_name = "IFACE:PORT", group = "addr")]
+        interface: Option<String>,
+
+        /// Address family to use if the interface has global addresses of both.
+        #[arg(long, value_enum, requires = "interface", conflicts_with = "address")]
+        prefer: Option<CliAddrFamily>,
+
+        /// Address family to listen on, picking the address of a host name resolving to both.
+        /// Without it, the first address resolved is used.
+        #[arg(long, value_enum, requires = "address")]
+        adrfam: Option<CliAddrFamily>,
+
+        #[command(flatten)]
+        wwn_check: WwnCheckArgs,
     },
-    /// Remove a Port.
+    /// Remove a Port, or all Ports.
     Remove {
         /// Port ID to remove.
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        pid: Option<u16>,
+
+        /// Remove all Ports.
+        #[arg(long)]
+        all: bool,
+
+        /// Only remove Ports of this type.
+        #[arg(long, value_name = "TYPE", requires = "all")]
+        r#type: Option<CliPortType>,
+
+        /// Do not ask for confirmation.
+        #[arg(long, short)]
+        yes: bool,
+
+        /// Continue removing the other Ports if removing one fails.
+        #[arg(long, requires = "all")]
+        keep_going: bool,
+    },
+    /// Set or remove labels of a Port.
+    ///
+    /// Labels are only stored by nvmetcfg, in /var/lib/nvmetcfg/labels.yaml, and in state files.
+    Label {
+        /// Port ID.
         pid: u16,
+
+        #[command(flatten)]
+        labels: LabelArgs,
     },
     /// List the subsystems provided by a Port.
     ListSubsystems {
         /// Port ID.
         pid: u16,
     },
+    /// Print a table of which Ports provide which Subsystems, for all Ports.
+    Subsystems,
     /// Add a Subsystem to a Port.
     AddSubsystem {
         /// Port ID.
@@ -86,6 +194,96 @@ pub enum CliPortCommands {
         /// NVMe Qualified Name of the Subsystem to remove.
         sub: String,
     },
+    /// Remove all Subsystems from a Port, keeping the Port itself.
+    ClearSubsystems {
+        /// Port ID.
+        pid: u16,
+    },
+    /// Replace all Subsystems provided by a Port.
+    SetSubsystems {
+        /// Port ID.
+        pid: u16,
+        /// Comma-separated NVMe Qualified Names or @aliases of the Subsystems the Port should provide.
+        #[arg(value_delimiter = ',')]
+        subs: Vec<String>,
+    },
+    /// Stop providing any Subsystems on a Port, remembering them for bringing it online again.
+    ///
+    /// The Subsystems are kept in /var/lib/nvmetcfg/offline-ports.yaml.
+    Offline {
+        /// Port ID.
+        pid: u16,
+    },
+    /// Provide the Subsystems a Port had before it was taken offline again.
+    Online {
+        /// Port ID.
+        pid: u16,
+    },
+    /// Move a Port to a new address without disconnecting its initiators.
+    ///
+    /// A new Port with the new address is added, providing the same Subsystems, so initiators
+    /// can connect through it. The old Port is only removed after waiting for that. If anything
+    /// fails before, the new Port is removed again.
+    Migrate {
+        /// Port ID to move.
+        pid: u16,
+
+        /// Type of the new Port: loop, tcp, rdma or fc.
+        /// Or the whole address as URI instead, like tcp://192.0.2.1:4420 or rdma://[fdff::1].
+        #[arg(
+            verbatim_doc_comment,
+            value_name = "PORT_TYPE|URI",
+            value_parser = parse_port_spec,
+            requires_if("tcp", "addr"),
+            requires_if("rdma", "addr"),
+            requires_if("fc", "address")
+        )]
+        port_type: CliPortSpec,
+
+        /// New Port Address, in the same format as for `port add`.
+        #[arg(group = "addr")]
+        address: Option<String>,
+
+        /// Use the current global address of a network interface instead, like eth1:4420.
+        #[arg(long, value_name = "IFACE:PORT", group = "addr")]
+        interface: Option<String>,
+
+        /// Address family to use if the interface has global addresses of both.
+        #[arg(long, value_enum, requires = "interface", conflicts_with = "address")]
+        prefer: Option<CliAddrFamily>,
+
+        /// Address family to listen on, picking the address of a host name resolving to both.
+        /// Without it, the first address resolved is used.
+        #[arg(long, value_enum, requires = "address")]
+        adrfam: Option<CliAddrFamily>,
+
+        /// Port ID of the new Port, instead of the lowest unused one.
+        #[arg(long, value_name = "PID")]
+        new_pid: Option<u16>,
+
+        /// Seconds to wait for initiators to connect through the new Port.
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        delay: u64,
+
+        /// Wait until no controllers are connected through the old Port instead.
+        ///
+        /// Needs the nvmet debugfs of Linux 6.10 or newer, otherwise --delay is waited.
+        #[arg(long)]
+        wait_for_drain: bool,
+
+        /// Seconds to wait for the old Port to drain before giving up.
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value_t = 300,
+            requires = "wait_for_drain"
+        )]
+        drain_timeout: u64,
+
+        /// Only print the changes, without making them.
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -100,66 +298,633 @@ pub enum CliPortType {
     Fc,
 }
 
-impl CliPortCommands {
-    pub(super) fn parse(command: Self) -> Result<()> {
-        match command {
-            Self::List => {
-                let state = KernelConfig::gather_state()?;
-                for (id, _) in state.ports {
-                    println!("{id}");
-                }
+#[derive(Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum CliAddrFamily {
+    #[default]
+    Ipv4,
+    Ipv6,
+}
+
+impl From<CliAddrFamily> for AddrFamily {
+    fn from(family: CliAddrFamily) -> Self {
+        match family {
+            CliAddrFamily::Ipv4 => Self::Ipv4,
+            CliAddrFamily::Ipv6 => Self::Ipv6,
+        }
+    }
+}
+
+/// How to check Fibre Channel addresses against the local HBAs, to catch typos early.
+#[derive(Args)]
+pub struct WwnCheckArgs {
+    /// Fail if no local Fibre Channel HBA has the WWNN and WWPN, instead of only warning.
+    #[arg(long, conflicts_with = "skip_wwn_check")]
+    require_local_wwn: bool,
+
+    /// Don't look for a local Fibre Channel HBA with the WWNN and WWPN at all,
+    /// like for NPIV or HBAs which aren't installed yet.
+    #[arg(long)]
+    skip_wwn_check: bool,
+}
+
+impl WwnCheckArgs {
+    /// Check that a local HBA has the address of `port_type`, if it is a Fibre Channel one.
+    fn check(&self, port_type: &PortType) -> Result<()> {
+        let PortType::FibreChannel(addr) = port_type else {
+            return Ok(());
+        };
+        if self.skip_wwn_check {
+            return Ok(());
+        }
+        let local = local_fc_hosts()?
+            .iter()
+            .any(|host| host.node_name == addr.wwnn && host.port_name == addr.wwpn);
+        if !local {
+            let err = Error::NoLocalWwn(addr.to_traddr());
+            if self.require_local_wwn {
+                return Err(err.into());
             }
-            Self::Show => {
-                let state = KernelConfig::gather_state()?;
-                println!("Configured ports: {}", state.ports.len());
-                for (id, port) in state.ports {
-                    println!("Port {id}:");
-                    println!("\tType: {:?}", port.port_type);
-                    println!("\tSubsystems: {}", port.subsystems.len());
-                    for sub in port.subsystems {
-                        println!("\t\t{sub}");
+            warn!("{err}");
+        }
+        Ok(())
+    }
+}
+
+/// A type of Port, or a whole address given as URI.
+#[derive(Copy, Clone)]
+pub enum CliPortSpec {
+    Type(CliPortType),
+    Uri(PortType),
+}
+
+fn parse_port_spec(spec: &str) -> Result<CliPortSpec> {
+    if spec.contains("://") {
+        return Ok(CliPortSpec::Uri(spec.parse()?));
+    }
+    // Case-sensitive, as clap only requires an address for the lowercase names.
+    match CliPortType::from_str(spec, false) {
+        Ok(port_type) => Ok(CliPortSpec::Type(port_type)),
+        Err(_) => bail!("expected loop, tcp, rdma, fc or an address URI like tcp://192.0.2.1:4420"),
+    }
+}
+
+impl CliPortSpec {
+    fn with_address(
+        self,
+        address: Option<String>,
+        interface: Option<String>,
+        prefer: Option<CliAddrFamily>,
+        adrfam: Option<CliAddrFamily>,
+    ) -> Result<PortType> {
+        match self {
+            Self::Type(port_type) => port_type.with_address(address, interface, prefer, adrfam),
+            Self::Uri(port_type) if address.is_none() && interface.is_none() => Ok(port_type),
+            Self::Uri(port_type) => {
+                bail!("Port address {port_type} given as URI, cannot take another address")
+            }
+        }
+    }
+}
+
+/// Gather the Port `pid`, reading nothing else, or all of them, for showing.
+fn gather_shown(pid: Option<u16>) -> Result<State> {
+    let Some(pid) = pid else {
+        return Ok(KernelConfig::gather_state()?);
+    };
+    let Some(port) = KernelConfig::get_port(pid)? else {
+        return Err(Error::NoSuchPort(pid).into());
+    };
+    let mut state = State::default();
+    state.ports.insert(pid, port);
+    Ok(state)
+}
+
+/// Whether the kernel is listening on the address of each TCP Port in `state`.
+pub fn tcp_listening(state: &State) -> Result<BTreeMap<u16, bool>> {
+    let sockets = listening_tcp_sockets()?;
+    Ok(state
+        .ports
+        .iter()
+        .filter_map(|(id, port)| match port.port_type {
+            PortType::Tcp(addr) => Some((*id, is_listening(addr, &sockets))),
+            _ => None,
+        })
+        .collect())
+}
+
+pub const fn listening_status(listening: bool) -> &'static str {
+    if listening {
+        "LISTENING"
+    } else {
+        "NOT LISTENING"
+    }
+}
+
+/// Resolve `iface:port` to the current global address of the interface.
+fn resolve_interface(
+    port_type: CliPortType,
+    spec: &str,
+    prefer: CliAddrFamily,
+) -> Result<PortType> {
+    let (name, port) = spec
+        .rsplit_once(':')
+        .and_then(|(name, port)| Some((name, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| Error::InvalidInterfacePort(spec.to_string()))?;
+    let interface = PortInterface::new(name, prefer.into());
+    let unresolved = interface.unresolved(port_type.trtype(), port)?;
+    Ok(interface.resolve(unresolved)?)
+}
+
+impl CliPortType {
+    fn with_address(
+        self,
+        address: Option<String>,
+        interface: Option<String>,
+        prefer: Option<CliAddrFamily>,
+        adrfam: Option<CliAddrFamily>,
+    ) -> Result<PortType> {
+        if let Some(spec) = interface {
+            return resolve_interface(self, &spec, prefer.unwrap_or_default());
+        }
+        let family = adrfam.map(AddrFamily::from);
+        if family.is_some() && !matches!(self, Self::Tcp | Self::Rdma) {
+            bail!("--adrfam only applies to tcp and rdma Ports");
+        }
+        let address =
+            || address.ok_or_else(|| anyhow::anyhow!("{} Ports need an address", self.trtype()));
+        Ok(match self {
+            Self::Loop => PortType::Loop,
+            Self::Tcp => PortType::Tcp(resolve_socket_addr(&address()?, family)?),
+            Self::Rdma => PortType::Rdma(resolve_socket_addr(&address()?, family)?),
+            Self::Fc => PortType::FibreChannel(address()?.parse()?),
+        })
+    }
+
+    const fn trtype(self) -> &'static str {
+        match self {
+            Self::Loop => "loop",
+            Self::Tcp => "tcp",
+            Self::Rdma => "rdma",
+            Self::Fc => "fc",
+        }
+    }
+}
+
+fn remove_all(
+    port_type: Option<CliPortType>,
+    yes: bool,
+    keep_going: bool,
+    verify: bool,
+) -> Result<()> {
+    let state = KernelConfig::gather_state()?;
+    let pids: Vec<u16> = state
+        .ports
+        .iter()
+        .filter(|(_, port)| port_type.map_or(true, |pt| port.port_type.trtype() == pt.trtype()))
+        .map(|(id, _)| *id)
+        .collect();
+    if pids.is_empty() {
+        info!("No ports to remove.");
+        return Ok(());
+    }
+
+    println!("Ports to be removed: {}", pids.len());
+    for pid in &pids {
+        println!("\tPort {pid}: {:?}", state.ports[pid].port_type);
+    }
+    if !yes && !confirm("Remove these ports?")? {
+        info!("Aborted, no ports removed.");
+        return Ok(());
+    }
+
+    if !keep_going {
+        let deltas = pids.into_iter().map(StateDelta::RemovePort).collect();
+        crate::apply_delta(&state, deltas, verify)?;
+        return Ok(());
+    }
+
+    let mut failures = Vec::new();
+    for pid in &pids {
+        if let Err(err) = crate::apply_delta(&state, vec![StateDelta::RemovePort(*pid)], verify) {
+            failures.push((*pid, err));
+        }
+    }
+    info!("Removed ports: {}", pids.len() - failures.len());
+    if failures.is_empty() {
+        return Ok(());
+    }
+    for (pid, err) in &failures {
+        warn!("Failed to remove port {pid}: {err:#}");
+    }
+    Err(Error::PartialFailure(failures.len(), pids.len()).into())
+}
+
+/// Take the Port `pid` offline, stashing its Subsystems before removing them from it.
+fn offline(pid: u16, verify: bool) -> Result<()> {
+    let state = KernelConfig::gather_state()?;
+    let mut offline = OfflinePorts::load(OFFLINE_FILE)?;
+    let before = offline.clone();
+    let deltas = offline.take_offline(&state, pid)?;
+    // Stash them first, so they are never lost.
+    offline.save(OFFLINE_FILE)?;
+    if let Err(err) = crate::apply_delta(&state, deltas, verify) {
+        before
+            .save(OFFLINE_FILE)
+            .context("Failed to forget subsystems of port left online")?;
+        return Err(err);
+    }
+    info!(
+        "Port {pid} is offline, {} subsystems stashed.",
+        offline.ports[&pid].len()
+    );
+    Ok(())
+}
+
+/// Bring the Port `pid` online, adding its stashed Subsystems back.
+fn online(pid: u16, verify: bool) -> Result<()> {
+    let state = KernelConfig::gather_state()?;
+    let mut offline = OfflinePorts::load(OFFLINE_FILE)?;
+    let deltas = offline.bring_online(&state, pid)?;
+    crate::apply_delta(&state, deltas, verify)?;
+    offline.save(OFFLINE_FILE)?;
+    info!("Port {pid} is online.");
+    Ok(())
+}
+
+/// How `migrate` waits for initiators to move to the new Port.
+struct MigrationWait {
+    delay: Duration,
+    drain_timeout: Option<Duration>,
+}
+
+impl MigrationWait {
+    /// Wait for the initiators of Port `pid` to move.
+    fn wait(&self, pid: u16) -> Result<()> {
+        let Some(timeout) = self.drain_timeout else {
+            info!(
+                "Waiting {}s for initiators to connect through the new port.",
+                self.delay.as_secs()
+            );
+            std::thread::sleep(self.delay);
+            return Ok(());
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            let connected = match KernelConfig::list_controllers(None) {
+                Ok(controllers) => controllers.iter().filter(|c| c.port == Some(pid)).count(),
+                Err(err @ Error::NoDebugfs(_)) => {
+                    warn!("{err}, cannot wait for port {pid} to drain.");
+                    return Self {
+                        drain_timeout: None,
+                        ..*self
                     }
+                    .wait(pid);
                 }
+                Err(err) => return Err(err.into()),
+            };
+            if connected == 0 {
+                info!("No controllers left on port {pid}.");
+                return Ok(());
             }
+            if Instant::now() >= deadline {
+                return Err(Error::PortNotDrained(pid, connected).into());
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+impl fmt::Display for MigrationWait {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.drain_timeout {
+            Some(timeout) => write!(
+                f,
+                "Wait for the old port to drain, up to {}s",
+                timeout.as_secs()
+            ),
+            None => write!(f, "Wait {}s", self.delay.as_secs()),
+        }
+    }
+}
+
+/// Move the Port `pid` to the address `port_type` on a new Port, see `CliPortCommands::Migrate`.
+fn migrate(
+    pid: u16,
+    port_type: PortType,
+    new_pid: Option<u16>,
+    wait: &MigrationWait,
+    dry_run: bool,
+    verify: bool,
+) -> Result<()> {
+    let mut state = KernelConfig::gather_state()?;
+    labels::apply_to_state(&mut state)?;
+    if OfflinePorts::load(OFFLINE_FILE)?.is_offline(pid) {
+        return Err(Error::PortOffline(pid).into());
+    }
+    let migration = state.plan_port_migration(pid, port_type, new_pid)?;
+    let new = migration.new;
+    if dry_run {
+        println!("Changes required to move port {pid} to port {new}:");
+        for change in &migration.attach {
+            println!("\t{}", crate::color::delta(change));
+        }
+        println!("\t{wait}");
+        for change in &migration.detach {
+            println!("\t{}", crate::color::delta(change));
+        }
+        return Ok(());
+    }
+
+    crate::apply_delta(&state, migration.attach.clone(), verify)
+        .with_context(|| format!("Failed to add port {new}"))
+        .or_else(|err| roll_back(&migration, &state, err))?;
+    let mut attached = state;
+    attached.apply_deltas(&migration.attach)?;
+    info!("Added port {new} with address {port_type}.");
+    let labels = &attached.ports[&new].labels;
+    if !labels.is_empty() {
+        let mut store = LabelStore::load(LABEL_FILE)?;
+        store.ports.insert(new, labels.clone());
+        store
+            .save(LABEL_FILE)
+            .context("Failed to copy labels to the new port")?;
+    }
+
+    wait.wait(pid)
+        .or_else(|err| roll_back(&migration, &attached, err))?;
+
+    if let Err(err) = crate::apply_delta(&attached, migration.detach.clone(), verify) {
+        let err = err.context(format!("Failed to remove port {pid}"));
+        // Only if the old port still provides everything, as initiators may use the new one.
+        let intact = KernelConfig::get_port(pid)?
+            .is_some_and(|port| port.subsystems == attached.ports[&pid].subsystems);
+        if intact {
+            return roll_back(&migration, &attached, err);
+        }
+        warn!("Port {pid} lost subsystems, keeping port {new}.");
+        return Err(err);
+    }
+    info!("Moved port {pid} to port {new}.");
+    Ok(())
+}
+
+/// Remove the new Port of a failed migration again, returning the error it failed with.
+fn roll_back(migration: &PortMigration, base: &State, err: anyhow::Error) -> Result<()> {
+    let new = migration.new;
+    if KernelConfig::get_port(new)?.is_some() {
+        warn!("Removing port {new} again.");
+        if let Err(rollback_err) = crate::apply_delta(base, migration.rollback.clone(), false) {
+            warn!("Failed to remove port {new}: {rollback_err:#}");
+        }
+    }
+    Err(err)
+}
+
+/// Forget the stashed Subsystems of offline Ports removed by `deltas`.
+pub fn forget_offline(deltas: &[StateDelta]) -> Result<()> {
+    if !deltas
+        .iter()
+        .any(|d| matches!(d, StateDelta::RemovePort(_)))
+    {
+        return Ok(());
+    }
+    let mut offline = OfflinePorts::load(OFFLINE_FILE)?;
+    if offline.forget_removed(deltas) {
+        offline
+            .save(OFFLINE_FILE)
+            .context("Failed to forget removed offline ports")?;
+    }
+    Ok(())
+}
+
+impl CliPortCommands {
+    /// Whether the command changes the configuration in the kernel, which needs privileges.
+    pub(super) const fn mutates(&self) -> bool {
+        match self {
+            Self::Show { .. }
+            | Self::Exists { .. }
+            | Self::Wait { .. }
+            | Self::List { .. }
+            | Self::Label { .. }
+            | Self::ListSubsystems { .. }
+            | Self::Subsystems => false,
+            Self::Migrate { dry_run, .. } => !*dry_run,
+            Self::Add { .. }
+            | Self::Update { .. }
+            | Self::Remove { .. }
+            | Self::AddSubsystem { .. }
+            | Self::RemoveSubsystem { .. }
+            | Self::ClearSubsystems { .. }
+            | Self::SetSubsystems { .. }
+            | Self::Offline { .. }
+            | Self::Online { .. } => true,
+        }
+    }
+
+    /// The changes a command makes to `state`, or `None` if it doesn't change anything.
+    pub(super) fn deltas(command: Self, state: &State) -> Result<Option<Vec<StateDelta>>> {
+        let deltas = match command {
             Self::Add {
                 pid,
                 port_type,
                 address,
+                interface,
+                prefer,
+                adrfam,
+                wwn_check,
             } => {
-                let pt = match port_type {
-                    CliPortType::Loop => PortType::Loop,
-                    CliPortType::Tcp => PortType::Tcp(address.unwrap().parse()?),
-                    CliPortType::Rdma => PortType::Rdma(address.unwrap().parse()?),
-                    CliPortType::Fc => PortType::FibreChannel(address.unwrap().parse()?),
-                };
-
-                let state_delta = vec![StateDelta::AddPort(pid, Port::new(pt, BTreeSet::new()))];
-                KernelConfig::apply_delta(state_delta)?;
+                let pt = port_type.with_address(address, interface, prefer, adrfam)?;
+                wwn_check.check(&pt)?;
+                vec![StateDelta::AddPort(pid, Port::new(pt, BTreeSet::new()))]
             }
             Self::Update {
                 pid,
                 port_type,
                 address,
+                interface,
+                prefer,
+                adrfam,
+                wwn_check,
             } => {
-                let pt = match port_type {
-                    CliPortType::Loop => PortType::Loop,
-                    CliPortType::Tcp => PortType::Tcp(address.unwrap().parse()?),
-                    CliPortType::Rdma => PortType::Rdma(address.unwrap().parse()?),
-                    CliPortType::Fc => PortType::FibreChannel(address.unwrap().parse()?),
-                };
-
-                let state_delta = vec![StateDelta::UpdatePort(
+                let pt = port_type.with_address(address, interface, prefer, adrfam)?;
+                wwn_check.check(&pt)?;
+                vec![StateDelta::UpdatePort(
                     pid,
                     vec![PortDelta::UpdatePortType(pt)],
-                )];
-                KernelConfig::apply_delta(state_delta)?;
+                )]
             }
-            Self::Remove { pid } => {
-                KernelConfig::apply_delta(vec![StateDelta::RemovePort(pid)])?;
+            Self::Remove {
+                pid: Some(pid),
+                all: false,
+                ..
+            } => vec![StateDelta::RemovePort(pid)],
+            Self::AddSubsystem { pid, sub } => {
+                let sub = resolve_sub(sub)?;
+                vec![StateDelta::UpdatePort(
+                    pid,
+                    vec![PortDelta::AddSubsystem(sub)],
+                )]
+            }
+            Self::RemoveSubsystem { pid, sub } => {
+                let sub = resolve_sub(sub)?;
+                vec![StateDelta::UpdatePort(
+                    pid,
+                    vec![PortDelta::RemoveSubsystem(sub)],
+                )]
+            }
+            Self::ClearSubsystems { pid } => {
+                state.get_port_subsystem_deltas(pid, &BTreeSet::new())?
+            }
+            Self::SetSubsystems { pid, subs } => {
+                let subs = subs
+                    .into_iter()
+                    .map(resolve_sub)
+                    .collect::<Result<BTreeSet<_>>>()?;
+                state.get_port_subsystem_deltas(pid, &subs)?
+            }
+            Self::Show { .. }
+            | Self::Exists { .. }
+            | Self::Wait { .. }
+            | Self::List { .. }
+            | Self::Label { .. }
+            | Self::ListSubsystems { .. }
+            | Self::Subsystems
+            | Self::Offline { .. }
+            | Self::Online { .. }
+            | Self::Migrate { .. }
+            | Self::Remove { .. } => return Ok(None),
+        };
+        Ok(Some(deltas))
+    }
+
+    pub(super) fn parse(command: Self, output: OutputFormat, verify: bool) -> Result<()> {
+        match command {
+            Self::List { filter } => {
+                let mut state = KernelConfig::gather_state()?;
+                labels::apply_to_state(&mut state)?;
+                let selector = filter.selector();
+                state
+                    .ports
+                    .retain(|_, port| labels_match(&port.labels, &selector));
+                if output == OutputFormat::Ndjson {
+                    print_ndjson(state.port_records())?;
+                } else if output == OutputFormat::Csv {
+                    let mut csv = CsvWriter::new(&[
+                        "id",
+                        "trtype",
+                        "traddr",
+                        "trsvcid",
+                        "subsystems",
+                        "labels",
+                    ]);
+                    for (id, port) in &state.ports {
+                        let (traddr, trsvcid) = match port.port_type {
+                            PortType::Loop => (String::new(), String::new()),
+                            PortType::Tcp(addr) | PortType::Rdma(addr) => {
+                                (addr.ip().to_string(), addr.port().to_string())
+                            }
+                            PortType::FibreChannel(addr) => (addr.to_traddr(), String::new()),
+                        };
+                        csv.row(&[
+                            id.to_string(),
+                            port.port_type.trtype().to_string(),
+                            traddr,
+                            trsvcid,
+                            csv_list(&port.subsystems),
+                            labels::format(&port.labels),
+                        ]);
+                    }
+                    print!("{}", csv.finish());
+                } else {
+                    for (id, _) in state.ports {
+                        println!("{id}");
+                    }
+                }
+            }
+            Self::Wait { pid, timeout } => {
+                KernelConfig::wait_for_port(pid, Duration::from_secs(timeout))?;
+                debug!("Port {pid} exists.");
+            }
+            Self::Exists { pid } => {
+                crate::exists(
+                    KernelConfig::get_port(pid)?.is_some(),
+                    &format!("Port {pid}"),
+                )?;
+            }
+            Self::Show { pid, .. } if output == OutputFormat::Ndjson => {
+                let mut state = gather_shown(pid)?;
+                labels::apply_to_state(&mut state)?;
+                print_ndjson(state.port_records())?;
+            }
+            Self::Show { pid, check } => {
+                let mut state = gather_shown(pid)?;
+                labels::apply_to_state(&mut state)?;
+                let listening = if check {
+                    tcp_listening(&state)?
+                } else {
+                    BTreeMap::new()
+                };
+                let has_loop = state.ports.values().any(|p| p.port_type == PortType::Loop);
+                let loop_controllers = if has_loop {
+                    loop_port_controllers(&state, &KernelConfig::loop_controllers()?)
+                } else {
+                    BTreeMap::new()
+                };
+                let offline = OfflinePorts::load(OFFLINE_FILE)?;
+                if pid.is_none() {
+                    println!("Configured ports: {}", state.ports.len());
+                }
+                for (id, port) in state.ports {
+                    let stashed = offline.ports.get(&id);
+                    if stashed.is_some() {
+                        println!(
+                            "{} {}",
+                            Color::Header.paint(format!("Port {id}:")),
+                            Color::Warning.paint("[OFFLINE]")
+                        );
+                    } else {
+                        println!("{}", Color::Header.paint(format!("Port {id}:")));
+                    }
+                    println!("\tType: {:?}", port.port_type);
+                    println!("\tAddress: {}", port.port_type);
+                    if let Some(listening) = listening.get(&id) {
+                        println!("\tStatus: {}", listening_status(*listening));
+                    }
+                    if !port.labels.is_empty() {
+                        println!("\tLabels: {}", labels::format(&port.labels));
+                    }
+                    println!("\tSubsystems: {}", port.subsystems.len());
+                    for sub in port.subsystems {
+                        println!("\t\t{sub}");
+                    }
+                    if let Some(stashed) = stashed {
+                        println!("\tStashed Subsystems: {}", stashed.len());
+                        for sub in stashed {
+                            println!("\t\t{sub}");
+                        }
+                    }
+                    let controllers = loop_controllers.get(&id).filter(|c| !c.is_empty());
+                    if let Some(controllers) = controllers {
+                        println!("\tLoop Controllers: {}", controllers.len());
+                        for ctrl in controllers {
+                            let state = ctrl.state.as_deref().unwrap_or("unknown");
+                            print!("\t\t/dev/{}: {} ({state})", ctrl.name, ctrl.subsystem);
+                            for ns in &ctrl.namespaces {
+                                print!(" /dev/{ns}");
+                            }
+                            println!();
+                        }
+                    }
+                }
+            }
+            Self::Label { pid, labels } => {
+                if KernelConfig::get_port(pid)?.is_none() {
+                    return Err(Error::NoSuchPort(pid))?;
+                }
+                labels::update(labels, pid, |store| &mut store.ports)?;
             }
             Self::ListSubsystems { pid } => {
-                let state = KernelConfig::gather_state()?;
-                if let Some(port) = state.ports.get(&pid) {
+                if let Some(port) = KernelConfig::get_port(pid)? {
                     for sub in &port.subsystems {
                         println!("{sub}");
                     }
@@ -167,19 +932,61 @@ impl CliPortCommands {
                     return Err(Error::NoSuchPort(pid))?;
                 }
             }
-            Self::AddSubsystem { pid, sub } => {
-                assert_valid_nqn(&sub)?;
-                KernelConfig::apply_delta(vec![StateDelta::UpdatePort(
-                    pid,
-                    vec![PortDelta::AddSubsystem(sub)],
-                )])?;
+            Self::Subsystems => {
+                let state = KernelConfig::gather_state()?;
+                match output {
+                    OutputFormat::Json => {
+                        print_json(&state.port_subsystem_records().collect::<Vec<_>>())?;
+                    }
+                    OutputFormat::Ndjson => print_ndjson(state.port_subsystem_records())?,
+                    OutputFormat::Csv => {
+                        let mut csv = CsvWriter::new(&["port", "address", "nqn"]);
+                        for record in state.port_subsystem_records() {
+                            csv.row(&[
+                                record.port.to_string(),
+                                record.address,
+                                record.nqn.to_string(),
+                            ]);
+                        }
+                        print!("{}", csv.finish());
+                    }
+                    OutputFormat::Text => print!("{}", state.port_subsystem_table()),
+                }
             }
-            Self::RemoveSubsystem { pid, sub } => {
-                assert_valid_nqn(&sub)?;
-                KernelConfig::apply_delta(vec![StateDelta::UpdatePort(
-                    pid,
-                    vec![PortDelta::RemoveSubsystem(sub)],
-                )])?;
+            Self::Remove {
+                all: true,
+                r#type,
+                yes,
+                keep_going,
+                ..
+            } => remove_all(r#type, yes, keep_going, verify)?,
+            Self::Offline { pid } => offline(pid, verify)?,
+            Self::Online { pid } => online(pid, verify)?,
+            Self::Migrate {
+                pid,
+                port_type,
+                address,
+                interface,
+                prefer,
+                adrfam,
+                new_pid,
+                delay,
+                wait_for_drain,
+                drain_timeout,
+                dry_run,
+            } => {
+                let pt = port_type.with_address(address, interface, prefer, adrfam)?;
+                let wait = MigrationWait {
+                    delay: Duration::from_secs(delay),
+                    drain_timeout: wait_for_drain.then(|| Duration::from_secs(drain_timeout)),
+                };
+                migrate(pid, pt, new_pid, &wait, dry_run, verify)?;
+            }
+            command => {
+                let state = KernelConfig::gather_state()?;
+                if let Some(deltas) = Self::deltas(command, &state)? {
+                    crate::apply_delta(&state, deltas, verify)?;
+                }
             }
         }
         Ok(())
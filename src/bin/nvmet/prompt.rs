@@ -0,0 +1,11 @@
+use anyhow::Result;
+use std::io::{BufRead, Write};
+
+/// Ask the user a yes/no question on the terminal, defaulting to no.
+pub fn confirm(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
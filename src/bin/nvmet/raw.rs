@@ -0,0 +1,81 @@
+use crate::output::{print_json, print_ndjson, OutputFormat};
+use anyhow::Result;
+use clap::Subcommand;
+use nvmetcfg::kernel::{KernelConfig, ObjectRef};
+use serde::Serialize;
+use tracing::info;
+
+#[derive(Subcommand)]
+pub enum CliRawCommands {
+    /// Print the value of an attribute as the kernel has it.
+    Get {
+        /// Object the attribute belongs to.
+        /// One of subsystem:<nqn>, port:<id>, namespace:<nqn>/<nsid> or host:<nqn>.
+        object: ObjectRef,
+        /// Name of the attribute, like attr_qid_max.
+        attr: String,
+    },
+    /// Write the value of an attribute without checking it, and print what the kernel reads back.
+    Set {
+        /// Object the attribute belongs to.
+        /// One of subsystem:<nqn>, port:<id>, namespace:<nqn>/<nsid> or host:<nqn>.
+        object: ObjectRef,
+        /// Name of the attribute, like attr_qid_max.
+        attr: String,
+        /// Value to write.
+        value: String,
+    },
+}
+
+#[derive(Serialize)]
+struct RawAttr<'a> {
+    object: String,
+    attr: &'a str,
+    value: String,
+}
+
+fn print(output: OutputFormat, object: &ObjectRef, attr: &str, value: String) -> Result<()> {
+    let record = RawAttr {
+        object: object.to_string(),
+        attr,
+        value,
+    };
+    match output {
+        OutputFormat::Json => print_json(&record)?,
+        OutputFormat::Ndjson => print_ndjson([&record])?,
+        OutputFormat::Text | OutputFormat::Csv => println!("{}", record.value),
+    }
+    Ok(())
+}
+
+impl CliRawCommands {
+    /// Whether the command changes the configuration in the kernel, which needs privileges.
+    pub(super) const fn mutates(&self) -> bool {
+        matches!(self, Self::Set { .. })
+    }
+
+    pub(super) fn parse(command: Self, output: OutputFormat) -> Result<()> {
+        match command {
+            Self::Get { object, attr } => {
+                let value = KernelConfig::get_raw_attr(&object, &attr)?;
+                print(output, &object, &attr, value)
+            }
+            Self::Set {
+                object,
+                attr,
+                value,
+            } => {
+                // Keys must not end up in the audit log.
+                let action = if attr.ends_with("_key") {
+                    format!("set {attr} of {object}")
+                } else {
+                    format!("set {attr} of {object} to {value:?}")
+                };
+                let result = KernelConfig::set_raw_attr(&object, &attr, &value);
+                let value = crate::audit::record(vec![action], result)?;
+                info!("Set {attr} of {object}, which reads back as {value:?}.");
+                print(output, &object, &attr, value)
+            }
+        }
+    }
+}
@@ -1,82 +1,1051 @@
+use crate::output::{print_json, print_ndjson, CliOutputFormat};
 use anyhow::{Context, Result};
-use clap::Subcommand;
-use nvmetcfg::{errors::Error, kernel::KernelConfig, state::State};
+use clap::{Args, Subcommand};
+use nvmetcfg::{
+    errors::Error,
+    helpers::{
+        contains_key_material, decompress_reader, fetch_url, is_readable_by_others, is_url,
+        verify_sha256, write_file_atomic_with, CompressWriter, Compression, NotifyTarget,
+        STATE_FILE_MODE,
+    },
+    kernel::{ApplyOptions, KernelConfig},
+    state::{
+        Aliases, DeviceSpecs, DriftNotifier, DriftReport, IgnoreFields, LabelStore, OfflinePorts,
+        RedactFields, State, StateDelta, Subsystem, ALIAS_FILE, DEVICE_SPEC_FILE, LABEL_FILE,
+        OFFLINE_FILE,
+    },
+};
 use serde::{Deserialize, Serialize};
-use std::{fs::File, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Cursor, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
 
 #[derive(Subcommand)]
 pub enum CliStateCommands {
+    /// Show the whole NVMe-oF Target configuration: Ports, Subsystems with their Namespaces,
+    /// and the Hosts allowed on them.
+    ///
+    /// With --output json or yaml, it is printed like `state save -` writes it.
+    /// Given a state file, that is shown instead, with inactive Subsystems and Ports marked.
+    Show {
+        /// State file or https:// URL to show, or - for standard input.
+        file: Option<String>,
+
+        /// Refuse the state file unless it has this SHA-256 digest, in hex.
+        #[arg(long, value_name = "DIGEST", requires = "file")]
+        sha256: Option<String>,
+
+        /// Allow fetching the state file from an http:// URL, without TLS.
+        #[arg(long, requires = "file")]
+        insecure: bool,
+
+        /// Profile of the state file to show, instead of the file's default.
+        #[arg(long, requires = "file")]
+        profile: Option<String>,
+    },
     /// Save the NVMe-oF Target configuration to file.
     Save {
-        /// File to save the state to.
+        /// File to save the state to, or - for standard output.
+        /// Files ending in .gz or .zst are compressed accordingly.
+        #[arg(required_unless_present = "into")]
+        file: Option<PathBuf>,
+
+        /// Compress the state file regardless of its name, using gzip unless given.
+        #[arg(long, value_name = "FORMAT", num_args = 0..=1, require_equals = true, default_missing_value = "gzip", conflicts_with = "into")]
+        compress: Option<Compression>,
+
+        /// Save the state as this profile into an existing state file, keeping its other profiles.
+        /// The file is created if it doesn't exist yet.
+        #[arg(long, value_name = "FILE", requires = "profile", conflicts_with_all = ["file", "redact"])]
+        into: Option<PathBuf>,
+
+        /// Name of the profile to save the state as.
+        #[arg(long, requires = "into", conflicts_with = "file")]
+        profile: Option<String>,
+
+        /// Refuse to save anything if a Subsystem can't be read.
+        /// By default, the readable part of the configuration is saved.
+        #[arg(long)]
+        strict: bool,
+
+        /// Permissions of the state file, in octal.
+        #[arg(long, value_parser = parse_mode, default_value = "600")]
+        mode: u32,
+
+        /// Comma-separated values to replace with placeholders, for sharing the file.
+        /// Possible values: serials, hosts, keys.
+        #[arg(long, default_value = "")]
+        redact: RedactFields,
+    },
+    /// Copy a state file, replacing values with placeholders for sharing it.
+    ///
+    /// Equal values get equal placeholders, so the copy still shows which are shared.
+    /// Restoring the copy is refused unless forced.
+    Redact {
+        /// File from which to load the state.
         file: PathBuf,
+
+        /// File to save the redacted state to.
+        redacted: PathBuf,
+
+        /// Comma-separated values to replace with placeholders.
+        /// Possible values: serials, hosts, keys.
+        #[arg(long, default_value = "serials,hosts,keys")]
+        fields: RedactFields,
     },
     /// Restore the NVMe-oF Target configuration from previously saved configuration.
     Restore {
-        /// File from which to load the state.
-        file: PathBuf,
+        #[command(flatten)]
+        source: StateSource,
+
+        /// Profile to restore, instead of the file's default.
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Gather the state again after applying and check that it matches.
+        #[arg(long)]
+        verify: bool,
+
+        /// Only add and update configuration, never remove anything missing from the file.
+        #[arg(long)]
+        merge: bool,
+
+        /// Show the changes that would be made without applying them.
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Require subsystem NQNs to follow the formats from the NVMe specification.
+        #[arg(long)]
+        strict: bool,
+
+        /// How often to retry applying the state after a transient failure, like an I/O error.
+        #[arg(long, default_value_t = 0)]
+        retries: u32,
+
+        /// Seconds to wait between retries.
+        #[arg(long, value_name = "SECONDS", default_value_t = 1)]
+        retry_delay: u64,
+
+        /// Comma-separated fields to ignore when comparing.
+        /// Possible values: uuid, nguid, serial, model, enabled.
+        #[arg(long, default_value = "")]
+        ignore: IgnoreFields,
+
+        /// Restore the state even if values in the file were redacted.
+        #[arg(long)]
+        force: bool,
+
+        /// Allow changing the UUID or NGUID of a namespace whose device stays the same.
+        /// Initiators see such a namespace as a new disk.
+        #[arg(long)]
+        allow_identity_change: bool,
+
+        /// Leave out settings the kernel does not support, like attributes added by newer
+        /// kernels, and configure the rest.
+        #[arg(long)]
+        skip_unsupported: bool,
+
+        /// Bring offline Ports online with the Subsystems the file has for them.
+        /// Otherwise, those are stashed for `port online` instead.
+        #[arg(long)]
+        online_ports: bool,
     },
     /// Remove all configuration of the NVMe-oF Target.
-    Clear,
+    Clear {
+        /// Only remove the ports, keeping the subsystems.
+        #[arg(long, conflicts_with = "only_subsystems")]
+        only_ports: bool,
+
+        /// Only remove the subsystems, keeping the ports without any subsystems.
+        #[arg(long)]
+        only_subsystems: bool,
+
+        /// Keep hosts which are no longer allowed on any subsystem, including their keys.
+        #[arg(long)]
+        keep_hosts: bool,
+    },
+    /// Show the changes restoring a saved configuration would make.
+    Diff {
+        #[command(flatten)]
+        source: StateSource,
+
+        /// Profile to compare with, instead of the file's default.
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Comma-separated fields to ignore when comparing.
+        /// Possible values: uuid, nguid, serial, model, enabled.
+        #[arg(long, default_value = "")]
+        ignore: IgnoreFields,
+    },
+    /// Check that the NVMe-oF Target configuration matches a saved configuration.
+    Verify {
+        #[command(flatten)]
+        source: StateSource,
+
+        /// Profile to compare with, instead of the file's default.
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Comma-separated fields to ignore when comparing.
+        /// Possible values: uuid, nguid, serial, model, enabled.
+        #[arg(long, default_value = "")]
+        ignore: IgnoreFields,
+
+        #[command(flatten)]
+        notify: NotifyArgs,
+    },
+    /// Keep checking that the NVMe-oF Target configuration matches a saved configuration,
+    /// reporting drift from it.
+    ///
+    /// Checks whenever the configuration in the kernel changes, and every --interval seconds.
+    Watch {
+        #[command(flatten)]
+        source: StateSource,
+
+        /// Profile to compare with, instead of the file's default.
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Comma-separated fields to ignore when comparing.
+        /// Possible values: uuid, nguid, serial, model, enabled.
+        #[arg(long, default_value = "")]
+        ignore: IgnoreFields,
+
+        /// Seconds between checks while nothing changes.
+        #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+
+        /// Send at most one notification per this many seconds.
+        #[arg(long, default_value_t = 300)]
+        notify_interval: u64,
+
+        #[command(flatten)]
+        notify: NotifyArgs,
+    },
+    /// Check a state file for values the kernel would reject, without touching the system.
+    ///
+    /// Each profile is checked on its own.
+    Validate {
+        #[command(flatten)]
+        source: StateSource,
+
+        /// Only check this profile.
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Require subsystem NQNs to follow the formats from the NVMe specification.
+        #[arg(long)]
+        strict: bool,
+    },
+}
+
+/// Where to load a state file from.
+#[derive(Args)]
+pub struct StateSource {
+    /// File or https:// URL from which to load the state, or - for standard input.
+    /// Compressed files are recognized by their content.
+    file: String,
+
+    /// Refuse the state file unless it has this SHA-256 digest, in hex.
+    #[arg(long, value_name = "DIGEST")]
+    sha256: Option<String>,
+
+    /// Allow fetching the state file from an http:// URL, without TLS.
+    #[arg(long)]
+    insecure: bool,
+}
+
+/// Where to report drift from the state file to.
+#[derive(Args)]
+pub struct NotifyArgs {
+    /// Run this command with sh -c on drift, passing a JSON report on stdin.
+    #[arg(long, value_name = "CMD")]
+    notify_command: Option<String>,
+
+    /// POST a JSON report to this https:// URL on drift.
+    #[arg(long, value_name = "URL")]
+    notify_webhook: Option<String>,
+
+    /// Allow an http:// URL for --notify-webhook, without TLS.
+    #[arg(long, requires = "notify_webhook")]
+    notify_insecure: bool,
+}
+
+impl NotifyArgs {
+    /// A notifier for the given targets, sending at most one report per `interval`.
+    fn notifier(self, interval: Duration) -> DriftNotifier {
+        let mut targets = Vec::new();
+        if let Some(command) = self.notify_command {
+            targets.push(NotifyTarget::Command(command));
+        }
+        if let Some(url) = self.notify_webhook {
+            targets.push(NotifyTarget::Webhook {
+                url,
+                insecure: self.notify_insecure,
+            });
+        }
+        DriftNotifier::new(targets, interval)
+    }
+}
+
+/// Pause after changes in the kernel before comparing, so a series of changes is taken in at once.
+const WATCH_SETTLE: Duration = Duration::from_secs(1);
+
+/// Compare the configuration in the kernel with `desired` whenever it changes, and every
+/// `interval`, see `CliStateCommands::Watch`. Failing to notify only gets logged.
+fn watch(
+    desired: &State,
+    source: &str,
+    ignore: &IgnoreFields,
+    interval: Duration,
+    mut notifier: DriftNotifier,
+) -> Result<()> {
+    let mut watcher = KernelConfig::watch_events()?;
+    let mut last: Option<Vec<StateDelta>> = None;
+    loop {
+        match KernelConfig::gather_state() {
+            Ok(current) => {
+                let delta = current.get_deltas_ignoring(desired, ignore);
+                if delta.is_empty() {
+                    if last.is_some_and(|last| !last.is_empty()) {
+                        info!("System state matches saved state again.");
+                    }
+                    notifier.clear();
+                } else {
+                    if last.as_ref() != Some(&delta) {
+                        warn!(
+                            "System state drifted from saved state, changes required to restore it: {}",
+                            delta.len()
+                        );
+                        for change in &delta {
+                            warn!("\t{}", crate::color::delta_stderr(change));
+                        }
+                    }
+                    let report = DriftReport::new(source, delta.clone());
+                    if let Err(err) = notifier.notify(&report, Instant::now()) {
+                        warn!("{err:#}");
+                    }
+                }
+                last = Some(delta);
+            }
+            Err(err) => warn!("Failed to gather state for comparing: {err:#}"),
+        }
+
+        if !watcher.wait_timeout(interval)?.is_empty() {
+            while !watcher.wait_timeout(WATCH_SETTLE)?.is_empty() {}
+        }
+    }
+}
+
+impl StateSource {
+    /// Load the state file, fetching it first if given as URL, or reading stdin for `-`.
+    pub fn load(&self) -> Result<ConfigFile> {
+        let sha256 = self.sha256.as_deref();
+        if is_url(&self.file) {
+            let data = fetch_url(&self.file, self.insecure)?;
+            ConfigFile::load_single(data.as_slice(), &self.file, sha256)
+        } else if self.file == "-" {
+            ConfigFile::load_single(std::io::stdin().lock(), "-", sha256)
+        } else {
+            ConfigFile::load_checked(PathBuf::from(&self.file), sha256)
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ConfigFile {
     // TODO: Make this proper?
     #[serde(default)]
     pub version: u32,
+    #[serde(default, skip_serializing_if = "ConfigMeta::is_empty")]
+    pub meta: ConfigMeta,
+    /// State files merged in before this one, relative to its directory.
+    /// Emptied when loading, as their content has been merged in by then.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
     #[serde(flatten)]
     pub state: State,
+    /// Profile used when none is selected. Without one, the state outside of `profiles` is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
+    /// Alternative states, selected by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, State>,
+    /// Each file making up this one as read, included ones first, for validating them.
+    #[serde(skip)]
+    pub sources: Vec<(PathBuf, ConfigFile)>,
+}
+
+/// What nvmetcfg records about a state file itself.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigMeta {
+    /// Categories of values replaced with placeholders, see `RedactFields`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redacted: Vec<String>,
+}
+
+impl ConfigMeta {
+    fn is_empty(&self) -> bool {
+        self.redacted.is_empty()
+    }
+}
+
+/// Print the whole configuration, or that of a state file, see `CliStateCommands::Show`.
+fn show(output: CliOutputFormat, source: Option<StateSource>, profile: Option<&str>) -> Result<()> {
+    let (state, warnings) = match source {
+        Some(source) => (source.load()?.into_profile(profile)?, Vec::new()),
+        None => {
+            let (mut state, warnings) = KernelConfig::gather_state_partial()?;
+            Aliases::load(ALIAS_FILE)?.apply_to_state(&mut state);
+            LabelStore::load(LABEL_FILE)?.apply_to_state(&mut state);
+            DeviceSpecs::load(DEVICE_SPEC_FILE)?.apply_to_state(&mut state);
+            (state, warnings)
+        }
+    };
+    let config = ConfigFile {
+        state,
+        ..Default::default()
+    };
+    match output {
+        CliOutputFormat::Text | CliOutputFormat::Csv => print!("{}", config.state.tree()),
+        CliOutputFormat::Json => print_json(&config)?,
+        CliOutputFormat::Ndjson => print_ndjson([&config])?,
+        CliOutputFormat::Yaml => print!("{}", serde_yaml::to_string(&config)?),
+    }
+    for warning in warnings {
+        warn!("{warning}");
+    }
+    Ok(())
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(mode, 8).context("Mode must be an octal number")?;
+    if mode > 0o777 {
+        anyhow::bail!("Mode must be at most 777");
+    }
+    Ok(mode)
+}
+
+impl ConfigFile {
+    /// Load a state file, merging in the files it includes.
+    pub fn load(file: PathBuf) -> Result<Self> {
+        Self::load_checked(file, None)
+    }
+
+    /// Load a state file like `load`, refusing it unless it has the SHA-256 digest `sha256`.
+    ///
+    /// Only the file itself is checked, not the files it includes.
+    pub fn load_checked(file: PathBuf, sha256: Option<&str>) -> Result<Self> {
+        Self::load_included(file, sha256, &mut Vec::new())
+    }
+
+    /// Load a state file which can't include others, as it's not a local file.
+    fn load_single<R: BufRead>(reader: R, name: &str, sha256: Option<&str>) -> Result<Self> {
+        let mut config = Self::read(reader, name, sha256)?;
+        if !config.include.is_empty() {
+            anyhow::bail!(
+                "State file {name} includes other files, which is only supported for local files"
+            );
+        }
+        config.sources = vec![(PathBuf::from(name), config.clone())];
+        Ok(config)
+    }
+
+    /// Parse a state file read from `name`, decompressing it if needed.
+    fn read<R: BufRead>(mut reader: R, name: &str, sha256: Option<&str>) -> Result<Self> {
+        let reader = if let Some(digest) = sha256 {
+            // Nothing is parsed before checking the digest, which takes reading it all.
+            let mut data = Vec::new();
+            reader
+                .read_to_end(&mut data)
+                .with_context(|| format!("Failed to read state file {name}"))?;
+            verify_sha256(&data, digest).with_context(|| format!("Refusing state file {name}"))?;
+            decompress_reader(Cursor::new(data))
+        } else {
+            decompress_reader(reader)
+        }
+        .with_context(|| format!("Failed to read state file {name}"))?;
+        let config: Self = serde_yaml::from_reader(reader)
+            .with_context(|| format!("Failed to read from state file {name}"))?;
+        if config.version != 0 {
+            return Err(Error::UnsupportedConfigVersion(config.version))
+                .with_context(|| format!("Invalid state file {name}"));
+        }
+        Ok(config)
+    }
+
+    /// Load a state file included by the files in `stack`, the outermost first.
+    ///
+    /// Included files are merged like `state restore --merge` would apply them one after
+    /// another, followed by the including file: objects in several are combined, with the
+    /// settings of later files taking precedence.
+    fn load_included(
+        file: PathBuf,
+        sha256: Option<&str>,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<Self> {
+        let reader = File::open(&file)
+            .with_context(|| format!("Failed to open state file {} for reading", file.display()))?;
+        let mut config = Self::read(BufReader::new(reader), &file.display().to_string(), sha256)?;
+        if is_readable_by_others(&file)? && contains_key_material(&serde_yaml::to_string(&config)?)
+        {
+            warn!(
+                "State file {} contains DH-HMAC-CHAP keys and is readable by other users! Restrict it using chmod 600.",
+                file.display()
+            );
+        }
+        if config.include.is_empty() {
+            config.sources = vec![(file, config.clone())];
+            return Ok(config);
+        }
+
+        let canonical = file
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve state file {}", file.display()))?;
+        if let Some(start) = stack.iter().position(|f| *f == canonical) {
+            let cycle: Vec<String> = stack[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|f| f.display().to_string())
+                .collect();
+            return Err(Error::IncludeCycle(cycle.join(" -> ")).into());
+        }
+        stack.push(canonical);
+        let dir = file.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mut merged = Self::default();
+        for include in std::mem::take(&mut config.include) {
+            let included = Self::load_included(dir.join(include), None, stack)
+                .with_context(|| format!("Failed to include into {}", file.display()))?;
+            merged.merge(included);
+        }
+        stack.pop();
+
+        let own = config.clone();
+        merged.merge(config);
+        merged.sources.push((file, own));
+        Ok(merged)
+    }
+
+    /// Overlay `other` on top of this file, see `State::merge`.
+    fn merge(&mut self, other: Self) {
+        self.state.merge(&other.state);
+        for (name, state) in &other.profiles {
+            self.profiles.entry(name.clone()).or_default().merge(state);
+        }
+        if other.default_profile.is_some() {
+            self.default_profile = other.default_profile;
+        }
+        let mut redacted: Vec<String> = self
+            .meta
+            .redacted
+            .iter()
+            .chain(&other.meta.redacted)
+            .cloned()
+            .collect();
+        redacted.sort();
+        redacted.dedup();
+        self.meta.redacted = redacted;
+        self.sources.extend(other.sources);
+    }
+
+    /// Take the state of profile `name`, or of the default profile if none is given.
+    pub fn into_profile(mut self, name: Option<&str>) -> Result<State> {
+        let Some(name) = name.or(self.default_profile.as_deref()) else {
+            return Ok(self.state);
+        };
+        self.profiles
+            .remove(name)
+            .ok_or_else(|| Error::NoSuchProfile(name.to_string()).into())
+    }
+
+    /// Validate every file making up this one, and then all of them merged.
+    ///
+    /// Files are validated on their own, but may refer to subsystems and profiles of the others.
+    pub fn validate_sources(&self, strict: bool) -> Result<()> {
+        for (file, source) in &self.sources {
+            source
+                .with_references_from(self)
+                .validate(strict)
+                .with_context(|| format!("Invalid state file {}", file.display()))?;
+        }
+        if self.sources.len() > 1 {
+            self.validate(strict)
+                .context("Invalid state after merging included files")?;
+        }
+        Ok(())
+    }
+
+    /// This file, with the subsystems and default profile it refers to taken from `merged`.
+    fn with_references_from(&self, merged: &Self) -> Self {
+        let adopt = |state: &mut State, merged: Option<&State>| {
+            let Some(merged) = merged else { return };
+            let missing: Vec<String> = state
+                .ports
+                .values()
+                .flat_map(|port| &port.subsystems)
+                .filter(|nqn| !state.subsystems.contains_key(*nqn))
+                .filter(|nqn| merged.subsystems.contains_key(*nqn))
+                .cloned()
+                .collect();
+            for nqn in missing {
+                state.subsystems.insert(nqn, Subsystem::default());
+            }
+        };
+        let mut file = self.clone();
+        adopt(&mut file.state, Some(&merged.state));
+        for (name, state) in &mut file.profiles {
+            adopt(state, merged.profiles.get(name));
+        }
+        if let Some(name) = &file.default_profile {
+            if !file.profiles.contains_key(name) && merged.profiles.contains_key(name) {
+                file.default_profile = None;
+            }
+        }
+        file
+    }
+
+    /// Validate the state outside of `profiles` and every profile, each on its own.
+    pub fn validate(&self, strict: bool) -> Result<()> {
+        if let Some(name) = &self.default_profile {
+            if !self.profiles.contains_key(name) {
+                return Err(Error::NoSuchProfile(name.clone())).context("Invalid default profile");
+            }
+        }
+        self.state.validate(strict)?;
+        for (name, state) in &self.profiles {
+            state
+                .validate(strict)
+                .with_context(|| format!("Invalid profile {name}"))?;
+        }
+        Ok(())
+    }
+
+    /// Replace the selected values with placeholders and record that in the meta block.
+    pub fn redact(&mut self, fields: &RedactFields) -> Result<()> {
+        if fields.is_empty() {
+            return Ok(());
+        }
+        self.state.redact(fields);
+        for state in self.profiles.values_mut() {
+            state.redact(fields);
+        }
+        let recorded: RedactFields = self.meta.redacted.join(",").parse()?;
+        self.meta.redacted = recorded.union(fields).names();
+        Ok(())
+    }
+
+    /// Save the state file to `file`, or to stdout for `-`.
+    ///
+    /// Unless `compression` is given, it's compressed according to the extension of `file`.
+    pub fn save(&self, file: &Path, mode: u32, compression: Option<Compression>) -> Result<()> {
+        if self.meta.redacted.iter().any(|field| field == "keys")
+            && contains_key_material(&serde_yaml::to_string(self)?)
+        {
+            anyhow::bail!("Refusing to write DH-HMAC-CHAP keys to a file with redacted keys");
+        }
+        let compression = compression.unwrap_or_else(|| Compression::from_path(file));
+        if file == Path::new("-") {
+            return Ok(write_yaml(self, std::io::stdout().lock(), compression)?);
+        }
+        Ok(write_file_atomic_with(file, mode, |f| {
+            write_yaml(self, BufWriter::new(f), compression)
+        })?)
+    }
+
+    /// Set profile `name` to `state` in the state file `file`, creating it if needed.
+    ///
+    /// The rest of the file is kept as it is, after checking that it's a valid state file. Only
+    /// the record of redacted values is dropped, as the new profile has real ones.
+    pub fn save_profile(file: &Path, name: &str, state: &State, mode: u32) -> Result<()> {
+        let mut doc: serde_yaml::Mapping = if file.exists() {
+            Self::load(file.to_path_buf())?;
+            let reader = File::open(file).context("Failed to open state file for reading")?;
+            let reader = decompress_reader(BufReader::new(reader))?;
+            serde_yaml::from_reader(reader).context("Failed to read from state file")?
+        } else {
+            serde_yaml::Mapping::new()
+        };
+        if let Some(meta) = doc
+            .get_mut("meta")
+            .and_then(serde_yaml::Value::as_mapping_mut)
+        {
+            meta.remove("redacted");
+            if meta.is_empty() {
+                doc.remove("meta");
+            }
+        }
+        let profiles = doc
+            .entry("profiles".into())
+            .or_insert_with(|| serde_yaml::Mapping::new().into());
+        let Some(profiles) = profiles.as_mapping_mut() else {
+            anyhow::bail!("Failed to read from state file: profiles is not a mapping");
+        };
+        profiles.insert(
+            name.into(),
+            serde_yaml::to_value(state).context("Failed to serialize state")?,
+        );
+        let compression = Compression::from_path(file);
+        Ok(write_file_atomic_with(file, mode, |f| {
+            write_yaml(&doc, BufWriter::new(f), compression)
+        })?)
+    }
+}
+
+/// Serialize `value` as YAML into `writer`, compressing it on the way.
+fn write_yaml<T: Serialize, W: Write>(
+    value: &T,
+    writer: W,
+    compression: Compression,
+) -> nvmetcfg::errors::Result<()> {
+    let mut writer = CompressWriter::new(writer, compression)?;
+    serde_yaml::to_writer(&mut writer, value)?;
+    writer.finish()?.flush()?;
+    Ok(())
 }
 
 impl CliStateCommands {
-    pub(super) fn parse(command: Self) -> Result<()> {
+    /// Whether the command changes the configuration in the kernel, which needs privileges.
+    pub(super) const fn mutates(&self) -> bool {
+        match self {
+            Self::Show { .. }
+            | Self::Save { .. }
+            | Self::Redact { .. }
+            | Self::Diff { .. }
+            | Self::Verify { .. }
+            | Self::Watch { .. }
+            | Self::Validate { .. } => false,
+            Self::Restore { dry_run, .. } => !*dry_run,
+            Self::Clear { .. } => true,
+        }
+    }
+
+    pub(super) fn parse(
+        command: Self,
+        output: CliOutputFormat,
+        verify_preconditions: bool,
+    ) -> Result<()> {
         match command {
-            CliStateCommands::Save { file } => {
-                let f = File::create(file).context("Failed to open state file for writing")?;
-                let state =
-                    KernelConfig::gather_state().context("Failed to gather state for writing")?;
-                let config = ConfigFile { version: 0, state };
-                serde_yaml::to_writer(f, &config)
-                    .context("Failed to write current state to file")?;
-                println!("Sucessfully written current state to file.");
+            CliStateCommands::Show {
+                file,
+                sha256,
+                insecure,
+                profile,
+            } => {
+                let source = file.map(|file| StateSource {
+                    file,
+                    sha256,
+                    insecure,
+                });
+                show(output, source, profile.as_deref())
+            }
+            CliStateCommands::Save {
+                file,
+                compress,
+                into,
+                profile,
+                strict,
+                mode,
+                redact,
+            } => {
+                let (mut state, warnings) = KernelConfig::gather_state_partial()
+                    .context("Failed to gather state for writing")?;
+                for warning in &warnings {
+                    warn!("{warning}");
+                }
+                if strict && !warnings.is_empty() {
+                    return Err(Error::UnreadableSubsystems(warnings.len()).into());
+                }
+                Aliases::load(ALIAS_FILE)?.apply_to_state(&mut state);
+                LabelStore::load(LABEL_FILE)?.apply_to_state(&mut state);
+                DeviceSpecs::load(DEVICE_SPEC_FILE)?.apply_to_state(&mut state);
+                if let (Some(into), Some(profile)) = (into, profile) {
+                    ConfigFile::save_profile(&into, &profile, &state, mode)
+                        .context("Failed to write current state to file")?;
+                } else {
+                    let mut config = ConfigFile {
+                        state,
+                        ..Default::default()
+                    };
+                    config.redact(&redact)?;
+                    let Some(file) = file else {
+                        anyhow::bail!("No file to save the state to given");
+                    };
+                    config
+                        .save(&file, mode, compress)
+                        .context("Failed to write current state to file")?;
+                }
+                if warnings.is_empty() {
+                    info!("Sucessfully written current state to file.");
+                } else {
+                    warn!(
+                        "Written current state to file without {} unreadable subsystems.",
+                        warnings.len()
+                    );
+                }
                 Ok(())
             }
-            CliStateCommands::Restore { file } => {
-                let f = File::open(file).context("Failed to open state file for reading")?;
-                let config: ConfigFile =
-                    serde_yaml::from_reader(f).context("Failed to read from state file")?;
-                if config.version != 0 {
-                    return Err(Error::UnsupportedConfigVersion(config.version).into());
-                }
-                let desired = config.state;
-                let current =
-                    KernelConfig::gather_state().context("Failed to gather state for writing")?;
-                let delta = current.get_deltas(&desired);
-                let delta_len = delta.len();
+            CliStateCommands::Restore {
+                source,
+                profile,
+                verify,
+                merge,
+                dry_run,
+                strict,
+                retries,
+                retry_delay,
+                ignore,
+                force,
+                allow_identity_change,
+                skip_unsupported,
+                online_ports,
+            } => {
+                let config = source.load()?;
+                if !config.meta.redacted.is_empty() && !force {
+                    return Err(Error::RedactedState(config.meta.redacted.join(", ")).into());
+                }
+                let mut desired = config.into_profile(profile.as_deref())?;
+                // Inactive objects are left alone, including their aliases, labels and stashes.
+                desired.remove_inactive();
+                let mut offline = OfflinePorts::load(OFFLINE_FILE)?;
+                let offline_before = offline.clone();
+                if online_ports {
+                    offline
+                        .ports
+                        .retain(|id, _| !desired.ports.contains_key(id));
+                } else {
+                    for id in offline.hold_back(&mut desired) {
+                        warn!("Port {id} is offline, stashing its subsystems instead of adding them. Use --online-ports to bring it online.");
+                    }
+                }
+                let opts = ApplyOptions {
+                    merge,
+                    dry_run,
+                    strict,
+                    retries,
+                    retry_delay: Duration::from_secs(retry_delay),
+                    ignore,
+                    verify,
+                    skip_preconditions: !verify_preconditions,
+                    allow_identity_change,
+                    skip_unsupported,
+                    ..Default::default()
+                };
+                let result = KernelConfig::apply_state(&desired, opts)
+                    .context("Failed to apply state delta between current and saved state");
+                crate::audit::record_report(&result, dry_run)?;
+                let report = result?;
+                for skipped in &report.skipped {
+                    warn!("Skipped {skipped}, which the kernel does not support.");
+                }
+                for host in &report.keyed_hosts {
+                    info!("Set keys of host {host}.");
+                }
+                let delta_len = report.deltas.len();
                 if delta_len == 0 {
-                    println!(
-                        "No changes made: System state has no changes compared to saved state."
+                    info!("No changes made: System state has no changes compared to saved state.");
+                } else if dry_run {
+                    println!("State changes required to restore saved state: {delta_len}");
+                    for change in &report.deltas {
+                        println!("\t{}", crate::color::delta(change));
+                    }
+                    for host in &report.host_removals {
+                        println!("\tWould remove unused host {host}");
+                    }
+                } else {
+                    info!("Sucessfully applied saved state: {delta_len} state changes.");
+                }
+                if !dry_run {
+                    let mut aliases = Aliases::load(ALIAS_FILE)?;
+                    let before = aliases.clone();
+                    aliases.extend_from_state(&desired)?;
+                    if aliases != before {
+                        aliases
+                            .save(ALIAS_FILE)
+                            .context("Failed to save aliases from state file")?;
+                    }
+                    let mut labels = LabelStore::load(LABEL_FILE)?;
+                    let before = labels.clone();
+                    labels.forget_removed(&report.deltas);
+                    labels.extend_from_state(&desired);
+                    if labels != before {
+                        labels
+                            .save(LABEL_FILE)
+                            .context("Failed to save labels from state file")?;
+                    }
+                    let mut specs = DeviceSpecs::load(DEVICE_SPEC_FILE)?;
+                    let before = specs.clone();
+                    specs.record(&report.deltas);
+                    specs.extend_from_state(&desired);
+                    if specs != before {
+                        specs
+                            .save(DEVICE_SPEC_FILE)
+                            .context("Failed to save device specs from state file")?;
+                    }
+                    offline.forget_removed(&report.deltas);
+                    if offline != offline_before {
+                        offline
+                            .save(OFFLINE_FILE)
+                            .context("Failed to save subsystems of offline ports")?;
+                    }
+                }
+                if verify && !dry_run {
+                    if !report.residual.is_empty() {
+                        println!("State changes not reflected by the system after applying:");
+                        for change in &report.residual {
+                            println!("\t{}", crate::color::delta(change));
+                        }
+                        return Err(Error::StateMismatch(report.residual.len()).into());
+                    }
+                    info!("Verified system state matches saved state.");
+                }
+                Ok(())
+            }
+            CliStateCommands::Redact {
+                file,
+                redacted,
+                fields,
+            } => {
+                let mut config = ConfigFile::load(file)?;
+                config.redact(&fields)?;
+                config
+                    .save(&redacted, STATE_FILE_MODE, None)
+                    .context("Failed to write redacted state to file")?;
+                info!(
+                    "Sucessfully written redacted state to file: {}.",
+                    config.meta.redacted.join(", ")
+                );
+                Ok(())
+            }
+            CliStateCommands::Clear {
+                only_ports,
+                only_subsystems,
+                keep_hosts,
+            } => {
+                let mut target =
+                    KernelConfig::gather_state().context("Failed to gather state for clearing")?;
+                let mut cleared = Vec::new();
+                if !only_subsystems {
+                    target.clear_ports();
+                    cleared.push("ports");
+                }
+                if !only_ports {
+                    target.clear_subsystems();
+                    cleared.push("subsystems");
+                    if !keep_hosts {
+                        cleared.push("unused hosts");
+                    }
+                }
+                let opts = ApplyOptions {
+                    keep_hosts,
+                    skip_preconditions: !verify_preconditions,
+                    ..Default::default()
+                };
+                let result = KernelConfig::apply_state(&target, opts)
+                    .context("Failed to apply state delta between current and cleared state");
+                crate::audit::record_report(&result, false)?;
+                let report = result?;
+                crate::labels::forget_removed(&report.deltas)?;
+                crate::namespace::record_device_specs(&report.deltas)?;
+                crate::port::forget_offline(&report.deltas)?;
+                let delta_len = report.deltas.len();
+                let cleared = cleared.join(", ");
+                if delta_len == 0 {
+                    info!(
+                        "No changes made: System state has no configuration to clear ({cleared})."
                     );
                 } else {
-                    KernelConfig::apply_delta(delta)
-                        .context("Failed to apply state delta between current and saved state")?;
-                    println!("Sucessfully applied saved state: {delta_len} state changes.");
+                    info!("Sucessfully cleared {cleared}: {delta_len} state changes.");
                 }
                 Ok(())
             }
-            CliStateCommands::Clear => {
+            CliStateCommands::Diff {
+                source,
+                profile,
+                ignore,
+            } => {
+                let mut desired = source.load()?.into_profile(profile.as_deref())?;
+                desired.resolve()?;
                 let current =
-                    KernelConfig::gather_state().context("Failed to gather state for writing")?;
-                let delta = current.get_deltas(&State::default());
-                let delta_len = delta.len();
-                if delta_len == 0 {
-                    println!("No changes made: System state has no configuration.");
+                    KernelConfig::gather_state().context("Failed to gather state for comparing")?;
+                let delta = current.get_deltas_ignoring(&desired, &ignore);
+                if delta.is_empty() {
+                    println!("No differences: System state matches saved state.");
+                } else {
+                    println!(
+                        "State changes required to restore saved state: {}",
+                        delta.len()
+                    );
+                    for change in &delta {
+                        println!("\t{}", crate::color::delta(change));
+                    }
+                }
+                Ok(())
+            }
+            CliStateCommands::Verify {
+                source,
+                profile,
+                ignore,
+                notify,
+            } => {
+                let mut desired = source.load()?.into_profile(profile.as_deref())?;
+                desired.resolve()?;
+                let current =
+                    KernelConfig::gather_state().context("Failed to gather state for comparing")?;
+                let delta = current.get_deltas_ignoring(&desired, &ignore);
+                if delta.is_empty() {
+                    println!("System state matches saved state.");
+                    Ok(())
+                } else {
+                    let count = delta.len();
+                    let report = DriftReport::new(&source.file, delta);
+                    if let Err(err) = notify
+                        .notifier(Duration::ZERO)
+                        .notify(&report, Instant::now())
+                    {
+                        warn!("{err:#}");
+                    }
+                    Err(Error::StateMismatch(count).into())
+                }
+            }
+            CliStateCommands::Watch {
+                source,
+                profile,
+                ignore,
+                interval,
+                notify_interval,
+                notify,
+            } => {
+                let mut desired = source.load()?.into_profile(profile.as_deref())?;
+                desired.resolve()?;
+                watch(
+                    &desired,
+                    &source.file,
+                    &ignore,
+                    Duration::from_secs(interval),
+                    notify.notifier(Duration::from_secs(notify_interval)),
+                )
+            }
+            CliStateCommands::Validate {
+                source,
+                profile,
+                strict,
+            } => {
+                let config = source.load()?;
+                if let Some(profile) = profile {
+                    config
+                        .into_profile(Some(&profile))?
+                        .validate(strict)
+                        .with_context(|| format!("Invalid profile {profile}"))?;
                 } else {
-                    KernelConfig::apply_delta(delta)
-                        .context("Failed to apply state delta between current and saved state")?;
-                    println!("Sucessfully cleared configuration: {delta_len} state changes.");
+                    config.validate_sources(strict)?;
                 }
+                println!("State file is valid.");
                 Ok(())
             }
         }
@@ -0,0 +1,123 @@
+use crate::color::Color;
+use crate::output::{print_json, print_ndjson, OutputFormat};
+use anyhow::Result;
+use nvmetcfg::helpers::{format_size, CsvWriter, DeviceCache};
+use nvmetcfg::kernel::KernelConfig;
+use nvmetcfg::state::{HostCapacity, HostReach, StateStats};
+
+pub fn show(by_host: bool, output: OutputFormat) -> Result<()> {
+    let state = KernelConfig::gather_state()?;
+    let devices = DeviceCache::default();
+    if by_host {
+        show_by_host(&state.capacity_by_host(|path| devices.size(path)), output)
+    } else {
+        show_stats(&state.stats(|path| devices.size(path)), output)
+    }
+}
+
+fn show_stats(stats: &StateStats, output: OutputFormat) -> Result<()> {
+    match output {
+        OutputFormat::Json => print_json(stats)?,
+        OutputFormat::Ndjson => print_ndjson([stats])?,
+        OutputFormat::Csv => {
+            let mut csv = CsvWriter::new(&["nqn", "enabled", "disabled", "capacity", "missing"]);
+            for (nqn, summary) in &stats.subsystems {
+                csv.row(&[
+                    nqn.clone(),
+                    summary.enabled.to_string(),
+                    summary.disabled.to_string(),
+                    summary.capacity.to_string(),
+                    summary.missing.len().to_string(),
+                ]);
+            }
+            print!("{}", csv.finish());
+        }
+        OutputFormat::Text => {
+            println!("Capacity: {}", format_size(stats.capacity));
+            println!(
+                "Namespaces: {}, enabled: {}, disabled: {}",
+                stats.enabled_namespaces + stats.disabled_namespaces,
+                stats.enabled_namespaces,
+                stats.disabled_namespaces
+            );
+            if stats.missing_namespaces > 0 {
+                println!(
+                    "Namespaces with missing devices, not counted in the capacity: {}",
+                    stats.missing_namespaces
+                );
+            }
+            println!("{}", Color::Header.paint("Subsystems:"));
+            for (nqn, summary) in &stats.subsystems {
+                print!(
+                    "\t{nqn}: {} namespaces, {}",
+                    summary.enabled + summary.disabled,
+                    format_size(summary.capacity)
+                );
+                if !summary.missing.is_empty() {
+                    print!(" ({} with missing devices)", summary.missing.len());
+                }
+                println!();
+            }
+            println!("{}", Color::Header.paint("Ports:"));
+            for (trtype, count) in &stats.ports {
+                println!("\t{trtype}: {count}");
+            }
+            println!("Allowed Hosts: {}", stats.hosts);
+            println!("Subsystems allowing any Host: {}", stats.open_subsystems);
+        }
+    }
+    Ok(())
+}
+
+fn show_by_host(capacity: &HostCapacity, output: OutputFormat) -> Result<()> {
+    let hosts = capacity
+        .hosts
+        .iter()
+        .map(|(host, reach)| (host.as_str(), reach))
+        .chain([("*", &capacity.any_host)]);
+    match output {
+        OutputFormat::Json => print_json(capacity)?,
+        OutputFormat::Ndjson => print_ndjson([capacity])?,
+        OutputFormat::Csv => {
+            let mut csv =
+                CsvWriter::new(&["host", "subsystems", "namespaces", "capacity", "missing"]);
+            for (host, reach) in hosts {
+                csv.row(&[
+                    host.to_string(),
+                    reach.subsystems.len().to_string(),
+                    reach.namespaces.to_string(),
+                    reach.capacity.to_string(),
+                    reach.missing_namespaces.to_string(),
+                ]);
+            }
+            print!("{}", csv.finish());
+        }
+        OutputFormat::Text => {
+            for (host, reach) in hosts {
+                let name = if host == "*" {
+                    "Any Host:".to_string()
+                } else {
+                    format!("Host: {host}")
+                };
+                println!("{}", Color::Header.paint(name));
+                show_reach(reach);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn show_reach(reach: &HostReach) {
+    println!("\tCapacity: {}", format_size(reach.capacity));
+    println!("\tEnabled Namespaces: {}", reach.namespaces);
+    if reach.missing_namespaces > 0 {
+        println!(
+            "\tNamespaces with missing devices, not counted in the capacity: {}",
+            reach.missing_namespaces
+        );
+    }
+    println!("\tSubsystems: {}", reach.subsystems.len());
+    for nqn in &reach.subsystems {
+        println!("\t\t{nqn}");
+    }
+}
@@ -1,17 +1,52 @@
+use crate::alias::resolve_sub;
+use crate::color::Color;
+use crate::labels::{self, LabelArgs, LabelFilter};
+use crate::output::{csv_list, print_json, print_ndjson, OutputFormat};
+use crate::prompt::confirm;
+use crate::wizard::SubsystemWizard;
 use anyhow::Result;
-use clap::Subcommand;
+use clap::{Args, Subcommand};
 use nvmetcfg::errors::Error;
-use nvmetcfg::helpers::{assert_compliant_nqn, assert_valid_nqn};
-use nvmetcfg::kernel::KernelConfig;
-use nvmetcfg::state::{StateDelta, Subsystem, SubsystemDelta};
+use nvmetcfg::helpers::{
+    assert_compliant_nqn, assert_valid_nqn, fold_to_ascii, format_size, truncate_to_len, CsvWriter,
+    DeviceCache, MODEL_MAX_LEN, SERIAL_MAX_LEN,
+};
+use nvmetcfg::kernel::{Controller, GatherWarning, KernelConfig, SubsystemInventory};
+use nvmetcfg::state::{labels_match, State, StateDelta, Subsystem, SubsystemDelta};
+use serde::Serialize;
 use std::collections::{BTreeMap, BTreeSet};
+use std::io::IsTerminal;
+use std::time::Duration;
+use tracing::{debug, info, warn};
 
 #[derive(Subcommand)]
 pub enum CliSubsystemCommands {
     /// Show detailed Subsystem information.
-    Show,
-    /// List only the Subsystem names.
-    List,
+    Show {
+        /// NVMe Qualified Name or @alias of the Subsystem, instead of showing all of them.
+        sub: Option<String>,
+    },
+    /// Exit with 0 if the Subsystem exists, or 10 if not, without printing anything.
+    ///
+    /// Failing to find out, like when configfs can't be read, exits with an error code from 1 to 6.
+    Exists {
+        /// NVMe Qualified Name or @alias of the Subsystem.
+        sub: String,
+    },
+    /// Wait until the Subsystem exists, like when another process is creating it.
+    Wait {
+        /// NVMe Qualified Name or @alias of the Subsystem.
+        sub: String,
+
+        /// Seconds to wait before giving up.
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        timeout: u64,
+    },
+    /// List only the Subsystem names, or with their attributes as CSV.
+    List {
+        #[command(flatten)]
+        filter: LabelFilter,
+    },
     /// Create a new Subsystem.
     Add {
         /// NVMe Qualified Name of the Subsystem.
@@ -31,10 +66,13 @@ pub enum CliSubsystemCommands {
         /// Set the serial.
         #[arg(long)]
         serial: Option<String>,
+
+        #[command(flatten)]
+        fixup: FixupArgs,
     },
     /// Update an existing Subsystem.
     Update {
-        /// NVMe Qualified Name of the Subsystem.
+        /// NVMe Qualified Name or @alias of the Subsystem.
         /// This should follow the supported formats in the NVMe specification.
         ///
         /// Examples:
@@ -51,81 +89,307 @@ pub enum CliSubsystemCommands {
         /// Set the serial.
         #[arg(long)]
         serial: Option<String>,
+
+        #[command(flatten)]
+        fixup: FixupArgs,
     },
+    /// Create a Subsystem with its Namespaces, allowed Hosts and Port, asking for each step.
+    ///
+    /// Needs a terminal. The changes are shown and only made once confirmed.
+    Wizard,
     /// Remove an existing Subsystem.
     Remove {
-        /// NVMe Qualified Name of the Subsystem.
+        /// NVMe Qualified Name or @alias of the Subsystem.
         sub: String,
     },
+    /// Set or remove labels of a Subsystem.
+    ///
+    /// Labels are only stored by nvmetcfg, in /var/lib/nvmetcfg/labels.yaml, and in state files.
+    Label {
+        /// NVMe Qualified Name or @alias of the Subsystem.
+        sub: String,
+
+        #[command(flatten)]
+        labels: LabelArgs,
+    },
+    /// Show the identifying attributes of all Subsystems, like model, serial and firmware.
+    Inventory,
+    /// Show the controllers of connected Hosts, from the nvmet debugfs of Linux 6.10 and newer.
+    Connections {
+        /// NVMe Qualified Name or @alias of the Subsystem, instead of showing all of them.
+        sub: Option<String>,
+    },
     /// List the Hosts allowed to use a Subsystem.
     ListHosts {
-        /// NVMe Qualified Name of the Subsystem.
+        /// NVMe Qualified Name or @alias of the Subsystem.
+        sub: String,
+    },
+    /// List the IDs of the Ports providing a Subsystem.
+    Ports {
+        /// NVMe Qualified Name or @alias of the Subsystem.
         sub: String,
     },
     /// Add a Host/Initiator to the whitelist of a Subsystem.
     AddHost {
-        /// NVMe Qualified Name of the Subsystem.
+        /// NVMe Qualified Name or @alias of the Subsystem.
         sub: String,
         /// NVMe Qualified Name of the Host/Initiator.
         host: String,
     },
     /// Remove a Host/Initiator from the whitelist of a Subsystem.
     RemoveHost {
-        /// NVMe Qualified Name of the Subsystem.
+        /// NVMe Qualified Name or @alias of the Subsystem.
         sub: String,
         /// NVMe Qualified Name of the Host/Initiator.
         host: String,
     },
+    /// Give a Subsystem the same allowed Hosts as another one.
+    CopyHosts {
+        /// NVMe Qualified Name or @alias of the Subsystem to copy the Hosts from.
+        src: String,
+        /// NVMe Qualified Name or @alias of the Subsystem to copy the Hosts to.
+        dst: String,
+        /// Replace the allowed Hosts of the destination. This is the default.
+        #[arg(long, conflicts_with = "merge")]
+        replace: bool,
+        /// Keep the allowed Hosts of the destination, only adding those of the source.
+        #[arg(long)]
+        merge: bool,
+        /// Copy from a Subsystem allowing any Host, letting any Host use the destination too.
+        #[arg(long)]
+        allow_any_host: bool,
+    },
 }
 
-impl CliSubsystemCommands {
-    pub(super) fn parse(command: Self) -> Result<()> {
-        match command {
-            Self::Show => {
-                let state = KernelConfig::gather_state()?;
-                println!("Configured subsystems: {}", state.subsystems.len());
-                for (nqn, sub) in state.subsystems {
-                    println!("Subsystem: {nqn}");
-                    // TODO: this is not exactly true. :(
-                    // We don't represent attr_allow_any_host in our abstraction.
-                    // Perhaps we should make allowed_hosts Option<...>?
-                    // That'd require some rework for sure..
-                    println!("\tAllow Any Host: {}", sub.allowed_hosts.is_empty());
-                    if !sub.allowed_hosts.is_empty() {
-                        println!("\tNumber of allowed Hosts: {}", sub.allowed_hosts.len());
-                        println!("\tAllowed Hosts:");
-                        for host in sub.allowed_hosts {
-                            println!("\t\t{host}");
-                        }
-                    }
-                    println!("\tNumber of Namespaces: {}", sub.namespaces.len());
-                    print!("\tNamespaces:");
-                    for (nsid, _ns) in sub.namespaces {
-                        print!(" {nsid}");
-                    }
-                    println!();
+/// Ask for a new Subsystem on the terminal, see `CliSubsystemCommands::Wizard`.
+fn wizard(verify: bool) -> Result<()> {
+    if !(std::io::stdin().is_terminal() && std::io::stdout().is_terminal()) {
+        return Err(Error::NoTerminal("subsystem wizard").into());
+    }
+    let state = KernelConfig::gather_state()?;
+    let deltas = SubsystemWizard::new(&state, std::io::stdin().lock(), std::io::stdout()).run()?;
+    println!("{}", Color::Header.paint("Changes:"));
+    for change in &deltas {
+        println!("\t{}", crate::color::delta(change));
+    }
+    if !confirm("Apply these changes?")? {
+        info!("Nothing changed.");
+        return Ok(());
+    }
+    crate::apply_delta(&state, deltas, verify)
+}
+
+/// Gather the Subsystem `sub`, reading nothing else, or all of them, for showing.
+fn gather_shown(sub: Option<String>) -> Result<(State, Vec<GatherWarning>)> {
+    let Some(sub) = sub else {
+        return Ok(KernelConfig::gather_state_partial()?);
+    };
+    let nqn = resolve_sub(sub)?;
+    let Some(subsystem) = KernelConfig::get_subsystem(&nqn)? else {
+        return Err(Error::NoSuchSubsystem(nqn).into());
+    };
+    let mut state = State::default();
+    state.subsystems.insert(nqn, subsystem);
+    Ok((state, Vec::new()))
+}
+
+/// A controller, with the address of its port.
+#[derive(Serialize)]
+struct Connection {
+    #[serde(flatten)]
+    controller: Controller,
+    port_address: Option<String>,
+}
+
+/// Print the controllers of connected Hosts, of the Subsystem `sub` or all of them.
+fn show_connections(sub: Option<String>, output: OutputFormat) -> Result<()> {
+    let nqn = sub.map(resolve_sub).transpose()?;
+    let controllers = KernelConfig::list_controllers(nqn.as_deref())?;
+    let mut addresses: BTreeMap<u16, Option<String>> = BTreeMap::new();
+    let mut connections = Vec::with_capacity(controllers.len());
+    for controller in controllers {
+        let port_address = match controller.port {
+            Some(pid) => match addresses.get(&pid) {
+                Some(address) => address.clone(),
+                None => {
+                    let address = KernelConfig::get_port(pid)?.map(|p| p.port_type.to_string());
+                    addresses.insert(pid, address.clone());
+                    address
                 }
+            },
+            None => None,
+        };
+        connections.push(Connection {
+            controller,
+            port_address,
+        });
+    }
+
+    match output {
+        OutputFormat::Json => print_json(&connections)?,
+        OutputFormat::Ndjson => print_ndjson(&connections)?,
+        OutputFormat::Csv => {
+            let mut csv = CsvWriter::new(&[
+                "subsystem",
+                "controller",
+                "host_nqn",
+                "port",
+                "port_address",
+                "host_traddr",
+                "state",
+                "queues",
+            ]);
+            for conn in &connections {
+                let ctrl = &conn.controller;
+                csv.row(&[
+                    ctrl.subsystem.as_str(),
+                    &ctrl.id.to_string(),
+                    ctrl.host_nqn.as_deref().unwrap_or_default(),
+                    &ctrl.port.map(|p| p.to_string()).unwrap_or_default(),
+                    conn.port_address.as_deref().unwrap_or_default(),
+                    ctrl.host_traddr.as_deref().unwrap_or_default(),
+                    ctrl.state.as_deref().unwrap_or_default(),
+                    &ctrl.queues.map(|q| q.to_string()).unwrap_or_default(),
+                ]);
             }
-            Self::List => {
-                let state = KernelConfig::gather_state()?;
-                for (nqn, _) in state.subsystems {
-                    println!("{nqn}");
+            print!("{}", csv.finish());
+        }
+        OutputFormat::Text => {
+            if connections.is_empty() {
+                println!("No connected controllers.");
+            }
+            let unknown = || "unknown".to_string();
+            let mut subsystem = None;
+            for conn in connections {
+                let ctrl = conn.controller;
+                if subsystem.as_ref() != Some(&ctrl.subsystem) {
+                    println!(
+                        "{}",
+                        Color::Header.paint(format!("Subsystem: {}", ctrl.subsystem))
+                    );
+                }
+                println!("\tController: {}", ctrl.id);
+                println!("\t\tHost NQN: {}", ctrl.host_nqn.unwrap_or_else(unknown));
+                match (ctrl.port, conn.port_address) {
+                    (Some(pid), Some(address)) => println!("\t\tPort: {pid} ({address})"),
+                    (Some(pid), None) => println!("\t\tPort: {pid}"),
+                    (None, _) => println!("\t\tPort: unknown"),
                 }
+                if let Some(traddr) = ctrl.host_traddr {
+                    println!("\t\tHost Address: {traddr}");
+                }
+                println!("\t\tState: {}", ctrl.state.unwrap_or_else(unknown));
+                println!(
+                    "\t\tQueues: {}",
+                    ctrl.queues.map_or_else(unknown, |q| q.to_string())
+                );
+                subsystem = Some(ctrl.subsystem);
             }
-            Self::Add { sub, model, serial } => {
+        }
+    }
+    Ok(())
+}
+
+/// Ways to turn an invalid model or serial into a valid one, instead of failing.
+#[derive(Args)]
+pub struct FixupArgs {
+    /// Shorten a too long model or serial to the maximum length.
+    #[arg(long)]
+    truncate: bool,
+
+    /// Replace non-ASCII characters in the model or serial by ASCII lookalikes, or drop them.
+    #[arg(long)]
+    ascii_fold: bool,
+}
+
+impl FixupArgs {
+    /// Fix up the model and serial as requested, warning about every change.
+    fn apply(
+        &self,
+        model: Option<String>,
+        serial: Option<String>,
+    ) -> (Option<String>, Option<String>) {
+        (
+            self.fix("model", model, MODEL_MAX_LEN),
+            self.fix("serial", serial, SERIAL_MAX_LEN),
+        )
+    }
+
+    fn fix(&self, what: &str, value: Option<String>, max_len: usize) -> Option<String> {
+        let mut value = value?;
+        if self.ascii_fold {
+            if let Some(folded) = fold_to_ascii(&value) {
+                warn!("Folding {what} {value:?} to ASCII: {folded:?}");
+                value = folded;
+            }
+        }
+        if self.truncate {
+            if let Some(short) = truncate_to_len(&value, max_len) {
+                warn!("Truncating {what} {value:?} to {max_len} characters: {short:?}");
+                value = short.to_string();
+            }
+        }
+        Some(value)
+    }
+}
+
+impl CliSubsystemCommands {
+    /// Whether the command changes the configuration in the kernel, which needs privileges.
+    pub(super) const fn mutates(&self) -> bool {
+        match self {
+            Self::Show { .. }
+            | Self::Exists { .. }
+            | Self::Wait { .. }
+            | Self::List { .. }
+            | Self::Label { .. }
+            | Self::Inventory
+            | Self::Connections { .. }
+            | Self::ListHosts { .. }
+            | Self::Ports { .. } => false,
+            Self::Add { .. }
+            | Self::Update { .. }
+            | Self::Remove { .. }
+            | Self::AddHost { .. }
+            | Self::RemoveHost { .. }
+            | Self::CopyHosts { .. }
+            | Self::Wizard => true,
+        }
+    }
+
+    /// The changes a command makes to the state, or `None` if it doesn't change anything.
+    pub(super) fn deltas(command: Self, state: &State) -> Result<Option<Vec<StateDelta>>> {
+        let deltas = match command {
+            Self::Add {
+                sub,
+                model,
+                serial,
+                fixup,
+            } => {
                 assert_compliant_nqn(&sub)?;
-                KernelConfig::apply_delta(vec![StateDelta::AddSubsystem(
+                let (model, serial) = fixup.apply(model, serial);
+                vec![StateDelta::AddSubsystem(
                     sub,
                     Subsystem {
+                        active: None,
+                        alias: None,
+                        labels: BTreeMap::new(),
                         model,
                         serial,
+                        qid_max: None,
                         allowed_hosts: BTreeSet::new(),
                         namespaces: BTreeMap::new(),
                     },
-                )])?;
+                )]
             }
-            Self::Update { sub, model, serial } => {
+            Self::Update {
+                sub,
+                model,
+                serial,
+                fixup,
+            } => {
+                let sub = resolve_sub(sub)?;
                 assert_compliant_nqn(&sub)?;
+                let (model, serial) = fixup.apply(model, serial);
                 let mut sub_delta = Vec::with_capacity(1);
 
                 if let Some(model) = model {
@@ -138,40 +402,263 @@ impl CliSubsystemCommands {
 
                 if sub_delta.is_empty() {
                     return Err(Error::UpdateNoChanges.into());
-                } else {
-                    KernelConfig::apply_delta(vec![StateDelta::UpdateSubsystem(sub, sub_delta)])?
                 }
+                vec![StateDelta::UpdateSubsystem(sub, sub_delta)]
             }
             Self::Remove { sub } => {
-                assert_valid_nqn(&sub)?;
-                KernelConfig::apply_delta(vec![StateDelta::RemoveSubsystem(sub)])?;
-            }
-            Self::ListHosts { sub } => {
-                assert_valid_nqn(&sub)?;
-                let state = KernelConfig::gather_state()?;
-                if let Some(subsystem) = state.subsystems.get(&sub) {
-                    for host in &subsystem.allowed_hosts {
-                        println!("{host}");
-                    }
-                } else {
-                    return Err(Error::NoSuchSubsystem(sub).into());
-                }
+                let sub = resolve_sub(sub)?;
+                vec![StateDelta::RemoveSubsystem(sub)]
             }
             Self::AddHost { sub, host } => {
-                assert_valid_nqn(&sub)?;
+                let sub = resolve_sub(sub)?;
                 assert_valid_nqn(&host)?;
-                KernelConfig::apply_delta(vec![StateDelta::UpdateSubsystem(
+                vec![StateDelta::UpdateSubsystem(
                     sub,
                     vec![SubsystemDelta::AddHost(host)],
-                )])?;
+                )]
             }
             Self::RemoveHost { sub, host } => {
-                assert_valid_nqn(&sub)?;
+                let sub = resolve_sub(sub)?;
                 assert_valid_nqn(&host)?;
-                KernelConfig::apply_delta(vec![StateDelta::UpdateSubsystem(
+                vec![StateDelta::UpdateSubsystem(
                     sub,
                     vec![SubsystemDelta::RemoveHost(host)],
-                )])?;
+                )]
+            }
+            Self::CopyHosts {
+                src,
+                dst,
+                replace: _,
+                merge,
+                allow_any_host,
+            } => {
+                let src = resolve_sub(src)?;
+                let dst = resolve_sub(dst)?;
+                let Some(source) = state.subsystems.get(&src) else {
+                    return Err(Error::NoSuchSubsystem(src).into());
+                };
+                let Some(target) = state.subsystems.get(&dst) else {
+                    return Err(Error::NoSuchSubsystem(dst).into());
+                };
+                if source.allowed_hosts.is_empty() && !allow_any_host {
+                    return Err(Error::CopyAllowAnyHost(src).into());
+                }
+                let changes = target.get_host_copy_deltas(source, merge);
+                vec![StateDelta::UpdateSubsystem(dst, changes)]
+            }
+            Self::Show { .. }
+            | Self::Exists { .. }
+            | Self::Wait { .. }
+            | Self::List { .. }
+            | Self::Label { .. }
+            | Self::Inventory
+            | Self::Connections { .. }
+            | Self::ListHosts { .. }
+            | Self::Ports { .. }
+            | Self::Wizard => return Ok(None),
+        };
+        Ok(Some(deltas))
+    }
+
+    pub(super) fn parse(command: Self, output: OutputFormat, verify: bool) -> Result<()> {
+        match command {
+            Self::Wait { sub, timeout } => {
+                let sub = resolve_sub(sub)?;
+                KernelConfig::wait_for_subsystem(&sub, Duration::from_secs(timeout))?;
+                debug!("Subsystem {sub} exists.");
+            }
+            Self::Exists { sub } => {
+                let sub = resolve_sub(sub)?;
+                let exists = KernelConfig::get_subsystem(&sub)?.is_some();
+                crate::exists(exists, &format!("Subsystem {sub}"))?;
+            }
+            Self::Show { sub } if output == OutputFormat::Ndjson => {
+                let (mut state, warnings) = gather_shown(sub)?;
+                labels::apply_to_state(&mut state)?;
+                print_ndjson(state.subsystem_records())?;
+                for warning in warnings {
+                    warn!("{warning}");
+                }
+            }
+            Self::Show { sub } => {
+                let all = sub.is_none();
+                let (mut state, warnings) = gather_shown(sub)?;
+                labels::apply_to_state(&mut state)?;
+                if all {
+                    println!(
+                        "Configured subsystems: {}",
+                        state.subsystems.len() + warnings.len()
+                    );
+                }
+                // Subsystems sharing devices only look at them once.
+                let devices = DeviceCache::default();
+                for (nqn, sub) in state.subsystems {
+                    let summary = sub.namespace_summary(|path| devices.size(path));
+                    println!("{}", Color::Header.paint(format!("Subsystem: {nqn}")));
+                    if !sub.labels.is_empty() {
+                        println!("\tLabels: {}", labels::format(&sub.labels));
+                    }
+                    // TODO: this is not exactly true. :(
+                    // We don't represent attr_allow_any_host in our abstraction.
+                    // Perhaps we should make allowed_hosts Option<...>?
+                    // That'd require some rework for sure..
+                    println!("\tAllow Any Host: {}", sub.allowed_hosts.is_empty());
+                    if !sub.allowed_hosts.is_empty() {
+                        println!("\tNumber of allowed Hosts: {}", sub.allowed_hosts.len());
+                        println!("\tAllowed Hosts:");
+                        for host in sub.allowed_hosts {
+                            println!("\t\t{host}");
+                        }
+                    }
+                    println!("\tNumber of Namespaces: {}", sub.namespaces.len());
+                    println!(
+                        "\tEnabled Namespaces: {}, disabled: {}",
+                        summary.enabled, summary.disabled
+                    );
+                    print!("\tCapacity: {}", format_size(summary.capacity));
+                    if !summary.missing.is_empty() {
+                        let missing: Vec<_> =
+                            summary.missing.iter().map(ToString::to_string).collect();
+                        print!(
+                            " (without the missing devices of namespaces {})",
+                            missing.join(", ")
+                        );
+                    }
+                    println!();
+                    print!("\tNamespaces:");
+                    for (nsid, _ns) in sub.namespaces {
+                        print!(" {nsid}");
+                    }
+                    println!();
+                }
+                for warning in warnings {
+                    println!(
+                        "{} {}",
+                        Color::Header.paint(format!("Subsystem: {}", warning.nqn)),
+                        Color::Error.paint("[ERROR]")
+                    );
+                    println!("\tError: {}", warning.error.full_message());
+                }
+            }
+            Self::List { filter } => {
+                let (mut state, warnings) = KernelConfig::gather_state_partial()?;
+                labels::apply_to_state(&mut state)?;
+                let selector = filter.selector();
+                state
+                    .subsystems
+                    .retain(|_, sub| labels_match(&sub.labels, &selector));
+                if output == OutputFormat::Ndjson {
+                    print_ndjson(state.subsystem_records())?;
+                } else if output == OutputFormat::Csv {
+                    let mut csv = CsvWriter::new(&[
+                        "nqn",
+                        "model",
+                        "serial",
+                        "allowed_hosts",
+                        "namespaces",
+                        "labels",
+                    ]);
+                    for (nqn, sub) in &state.subsystems {
+                        csv.row(&[
+                            nqn.as_str(),
+                            sub.model.as_deref().unwrap_or_default(),
+                            sub.serial.as_deref().unwrap_or_default(),
+                            &csv_list(&sub.allowed_hosts),
+                            &csv_list(sub.namespaces.keys()),
+                            &labels::format(&sub.labels),
+                        ]);
+                    }
+                    print!("{}", csv.finish());
+                } else {
+                    for (nqn, _) in state.subsystems {
+                        println!("{nqn}");
+                    }
+                }
+                for warning in warnings {
+                    if !matches!(output, OutputFormat::Csv | OutputFormat::Ndjson) {
+                        println!("{} {}", warning.nqn, Color::Error.paint("[ERROR]"));
+                    }
+                    warn!("{warning}");
+                }
+            }
+            Self::Inventory => {
+                let inventory = KernelConfig::subsystem_inventory()?;
+                match output {
+                    OutputFormat::Csv => print!("{}", SubsystemInventory::to_csv(&inventory)),
+                    OutputFormat::Json => {
+                        print_json(&inventory)?;
+                    }
+                    OutputFormat::Ndjson => print_ndjson(&inventory)?,
+                    OutputFormat::Text => {
+                        let unknown = || "unknown".to_string();
+                        for sub in inventory {
+                            println!("{}", Color::Header.paint(format!("Subsystem: {}", sub.nqn)));
+                            println!("\tModel: {}", sub.model);
+                            println!("\tSerial: {}", sub.serial);
+                            println!("\tFirmware: {}", sub.firmware.unwrap_or_else(unknown));
+                            println!("\tIEEE OUI: {}", sub.ieee_oui.unwrap_or_else(unknown));
+                        }
+                    }
+                }
+            }
+            Self::Connections { sub } => show_connections(sub, output)?,
+            Self::Label { sub, labels } => {
+                let sub = resolve_sub(sub)?;
+                if KernelConfig::get_subsystem(&sub)?.is_none() {
+                    return Err(Error::NoSuchSubsystem(sub).into());
+                }
+                labels::update(labels, sub, |store| &mut store.subsystems)?;
+            }
+            Self::ListHosts { sub } => {
+                let sub = resolve_sub(sub)?;
+                if let Some(subsystem) = KernelConfig::get_subsystem(&sub)? {
+                    for host in &subsystem.allowed_hosts {
+                        println!("{host}");
+                    }
+                } else {
+                    return Err(Error::NoSuchSubsystem(sub).into());
+                }
+            }
+            Self::Ports { sub } => {
+                let sub = resolve_sub(sub)?;
+                let state = KernelConfig::gather_state()?;
+                for id in state.subsystem_ports(&sub)? {
+                    println!("{id}");
+                }
+            }
+            Self::Wizard => wizard(verify)?,
+            command @ Self::CopyHosts { .. } => {
+                let state = KernelConfig::gather_state()?;
+                let deltas = Self::deltas(command, &state)?.unwrap_or_default();
+                let changes: Vec<SubsystemDelta> = deltas
+                    .iter()
+                    .flat_map(|delta| match delta {
+                        StateDelta::UpdateSubsystem(_, changes) => changes.clone(),
+                        _ => Vec::new(),
+                    })
+                    .collect();
+                if changes.is_empty() {
+                    info!("No changes.");
+                    return Ok(());
+                }
+                crate::apply_delta(&state, deltas, verify)?;
+                for change in changes {
+                    match change {
+                        SubsystemDelta::AddHost(host) => {
+                            println!("{} {host}", Color::Added.paint("Added Host:"));
+                        }
+                        SubsystemDelta::RemoveHost(host) => {
+                            println!("{} {host}", Color::Removed.paint("Removed Host:"));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            command => {
+                let state = KernelConfig::gather_state()?;
+                if let Some(deltas) = Self::deltas(command, &state)? {
+                    crate::apply_delta(&state, deltas, verify)?;
+                }
             }
         }
         Ok(())
@@ -0,0 +1,32 @@
+// A directory of its own for tests working with files, like `helpers::TestDir` of the library,
+// whose test helpers aren't built for the tests of the binary.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// An empty directory below the temporary directory, removed with everything in it when dropped.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Create a new one named after `name`, which has to be unique among the tests.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("nvmet-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
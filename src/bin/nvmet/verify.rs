@@ -0,0 +1,83 @@
+use crate::color::Color;
+use crate::output::{print_json, print_ndjson, OutputFormat};
+use anyhow::Result;
+use nvmetcfg::errors::Error;
+use nvmetcfg::helpers::CsvWriter;
+use nvmetcfg::kernel::{Finding, KernelConfig, Severity};
+use tracing::info;
+
+pub fn check(deep: bool, repair: bool, output: OutputFormat) -> Result<()> {
+    if !deep {
+        KernelConfig::gather_state()?.validate(false)?;
+        info!("No problems found.");
+        return Ok(());
+    }
+    let mut findings = KernelConfig::check_tree()?;
+    if repair {
+        KernelConfig::repair_tree(&mut findings)?;
+    }
+    show_findings(&findings, output)?;
+    let errors = findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Error && !finding.repaired)
+        .count();
+    if errors > 0 {
+        return Err(Error::BrokenTree(errors).into());
+    }
+    if findings.is_empty() {
+        info!("No problems found.");
+    }
+    Ok(())
+}
+
+fn show_findings(findings: &[Finding], output: OutputFormat) -> Result<()> {
+    match output {
+        OutputFormat::Json => print_json(findings)?,
+        OutputFormat::Ndjson => print_ndjson(findings)?,
+        OutputFormat::Csv => {
+            let mut csv = CsvWriter::new(&[
+                "severity",
+                "object",
+                "problem",
+                "fix",
+                "repairable",
+                "repaired",
+            ]);
+            for finding in findings {
+                csv.row(&[
+                    severity_name(finding.severity).to_string(),
+                    finding.object.to_string(),
+                    finding.problem.clone(),
+                    finding.fix.clone(),
+                    finding.repair.is_some().to_string(),
+                    finding.repaired.to_string(),
+                ]);
+            }
+            print!("{}", csv.finish());
+        }
+        OutputFormat::Text => {
+            for finding in findings {
+                let severity = match finding.severity {
+                    Severity::Error => Color::Error.paint("Error:"),
+                    Severity::Warning => Color::Warning.paint("Warning:"),
+                };
+                println!("{severity} {} {}", finding.object, finding.problem);
+                if finding.repaired {
+                    println!("\tRepaired: {}", finding.fix);
+                } else if finding.repair.is_some() {
+                    println!("\tFix: {} (--repair does this)", finding.fix);
+                } else {
+                    println!("\tFix: {}", finding.fix);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+const fn severity_name(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Warning => "warning",
+        Severity::Error => "error",
+    }
+}
@@ -0,0 +1,396 @@
+// Asking for a new subsystem step by step, for `nvmet subsystem wizard`.
+// The questions go to any writer and the answers come from any reader, so the flow can be
+// driven by a script as well as by a terminal. Answers are checked by the same functions as the
+// non-interactive commands, and asked again if they don't pass.
+
+use nvmetcfg::errors::{Context, Error, Result};
+use nvmetcfg::helpers::{
+    assert_compliant_nqn, assert_valid_model, assert_valid_nqn, assert_valid_nsid,
+    assert_valid_serial, DeviceSpec,
+};
+use nvmetcfg::state::{Namespace, Port, PortDelta, PortType, State, StateDelta, Subsystem};
+use std::collections::BTreeSet;
+use std::io::{BufRead, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use uuid::Builder;
+
+/// Asks for a new subsystem with its namespaces, allowed hosts and port, see `run`.
+pub struct SubsystemWizard<'a, R, W> {
+    state: &'a State,
+    input: R,
+    output: W,
+    dev: PathBuf,
+}
+
+impl<'a, R: BufRead, W: Write> SubsystemWizard<'a, R, W> {
+    /// Ask on `output` and read the answers from `input`, next to the existing `state`.
+    pub fn new(state: &'a State, input: R, output: W) -> Self {
+        Self {
+            state,
+            input,
+            output,
+            dev: PathBuf::from("/dev"),
+        }
+    }
+
+    /// Complete device names in `dev` instead of /dev.
+    #[cfg(test)]
+    fn dev_dir(mut self, dev: impl Into<PathBuf>) -> Self {
+        self.dev = dev.into();
+        self
+    }
+
+    /// Ask for everything, returning the changes creating the subsystem.
+    ///
+    /// Invalid answers are explained and asked again. The end of the input fails, as does a
+    /// subsystem which doesn't fit into the existing state.
+    pub fn run(mut self) -> Result<Vec<StateDelta>> {
+        let generated = generate_nqn()?;
+        let state = self.state;
+        let nqn = self.ask_until("Subsystem NQN", Some(&generated), |nqn| {
+            assert_compliant_nqn(nqn)?;
+            if state.subsystems.contains_key(nqn) {
+                return Err(Error::ExistingSubsystem(nqn.to_string()));
+            }
+            Ok(nqn.to_string())
+        })?;
+
+        let model = self.ask_until("Model, empty for the kernel default", None, |model| {
+            optional(model, assert_valid_model)
+        })?;
+        let serial = self.ask_until("Serial, empty for a random one", None, |serial| {
+            optional(serial, assert_valid_serial)
+        })?;
+        let mut subsystem = Subsystem {
+            model,
+            serial,
+            ..Default::default()
+        };
+
+        while let Some(device_path) = self.ask_device(subsystem.namespaces.is_empty())? {
+            let next = (1..=u32::MAX)
+                .find(|nsid| !subsystem.namespaces.contains_key(nsid))
+                .unwrap_or(u32::MAX);
+            let used = &subsystem.namespaces;
+            let nsid = self.ask_until("Namespace ID", Some(&next.to_string()), |nsid| {
+                let nsid = nsid.parse()?;
+                assert_valid_nsid(nsid)?;
+                if used.contains_key(&nsid) {
+                    return Err(Error::ExistingNamespace(nsid, nqn.clone()));
+                }
+                Ok(nsid)
+            })?;
+            let namespace = Namespace::builder(device_path).build()?;
+            subsystem.namespaces.insert(nsid, namespace);
+        }
+
+        while let Some(host) = self.ask_until(
+            "Allowed host NQN, empty when done or to allow any host",
+            None,
+            |host| optional(host, assert_valid_nqn),
+        )? {
+            subsystem.allowed_hosts.insert(host);
+        }
+
+        let port = self.ask_port()?;
+        let mut deltas = vec![StateDelta::AddSubsystem(nqn.clone(), subsystem)];
+        match port {
+            Some((id, None)) => {
+                deltas.push(StateDelta::UpdatePort(
+                    id,
+                    vec![PortDelta::AddSubsystem(nqn)],
+                ));
+            }
+            Some((id, Some(port_type))) => {
+                deltas.push(StateDelta::AddPort(
+                    id,
+                    Port::new(port_type, BTreeSet::from([nqn])),
+                ));
+            }
+            None => writeln!(
+                self.output,
+                "Without a port, no host can connect to the subsystem yet."
+            )?,
+        }
+
+        let mut desired = self.state.clone();
+        desired.apply_deltas(&deltas)?;
+        desired.validate(false)?;
+        Ok(deltas)
+    }
+
+    /// Ask for the device of another namespace, or `None` when done.
+    fn ask_device(&mut self, first: bool) -> Result<Option<PathBuf>> {
+        let dev = self.dev.clone();
+        self.ask_until(
+            "Namespace device, as path, UUID=<uuid> or LABEL=<label>, empty when done",
+            None,
+            |device| {
+                if device.is_empty() {
+                    if first {
+                        return Err(Error::InvalidDevice(
+                            "none given, the subsystem needs a namespace".to_string(),
+                        ));
+                    }
+                    return Ok(None);
+                }
+                if let Ok(spec) = device.parse::<DeviceSpec>() {
+                    return spec.resolve().map(Some);
+                }
+                complete_device(&dev, device).map(Some)
+            },
+        )
+    }
+
+    /// Ask for the port to provide the subsystem on, with the address if it is a new one.
+    fn ask_port(&mut self) -> Result<Option<(u16, Option<PortType>)>> {
+        if !self.state.ports.is_empty() {
+            writeln!(self.output, "Existing ports:")?;
+            for (id, port) in &self.state.ports {
+                writeln!(self.output, "\t{id}: {}", port.port_type)?;
+            }
+        }
+        let default = self.state.ports.keys().next().map(ToString::to_string);
+        let id = self.ask_until(
+            "Port ID to provide the subsystem on, or none",
+            default.as_deref(),
+            |id| match id {
+                "" | "none" => Ok(None),
+                id => Ok(Some(id.parse::<u16>()?)),
+            },
+        )?;
+        let Some(id) = id else {
+            return Ok(None);
+        };
+        if self.state.ports.contains_key(&id) {
+            return Ok(Some((id, None)));
+        }
+        let port_type = self.ask_until(
+            &format!("Address of the new port {id}, like tcp://192.0.2.1:4420 or loop://"),
+            None,
+            str::parse::<PortType>,
+        )?;
+        Ok(Some((id, Some(port_type))))
+    }
+
+    /// Ask `question` until `parse` accepts the answer, or the default if there is none.
+    fn ask_until<T>(
+        &mut self,
+        question: &str,
+        default: Option<&str>,
+        mut parse: impl FnMut(&str) -> Result<T>,
+    ) -> Result<T> {
+        loop {
+            match default {
+                Some(default) => write!(self.output, "{question} [{default}]: ")?,
+                None => write!(self.output, "{question}: ")?,
+            }
+            self.output.flush()?;
+            let mut answer = String::new();
+            if self.input.read_line(&mut answer)? == 0 {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof))
+                    .context("Input ended before the subsystem was complete");
+            }
+            let answer = match answer.trim() {
+                "" => default.unwrap_or_default(),
+                answer => answer,
+            };
+            match parse(answer) {
+                Ok(value) => return Ok(value),
+                Err(err) => writeln!(self.output, "{err}")?,
+            }
+        }
+    }
+}
+
+/// `None` for an empty answer, otherwise the answer if `check` accepts it.
+fn optional(answer: &str, check: fn(&str) -> Result<()>) -> Result<Option<String>> {
+    if answer.is_empty() {
+        return Ok(None);
+    }
+    check(answer)?;
+    Ok(Some(answer.to_string()))
+}
+
+/// A UUID based NQN, which is unique without having to choose a name.
+fn generate_nqn() -> Result<String> {
+    let mut random = [0u8; 16];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut random))
+        .context("Failed to read random UUID")?;
+    let uuid = Builder::from_random_bytes(random).into_uuid();
+    Ok(format!("nqn.2014-08.org.nvmexpress:uuid:{uuid}"))
+}
+
+/// Complete `device` to a path in `dev`, if it is the start of the name of only one.
+///
+/// Names relative to `dev` are completed as well, like `loop` to /dev/loop0 if that is the only
+/// loop device. Fails listing the candidates if there are several.
+fn complete_device(dev: &Path, device: &str) -> Result<PathBuf> {
+    let path = dev.join(device);
+    if path.exists() {
+        return Ok(path);
+    }
+    let (dir, prefix) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(prefix)) if !device.ends_with('/') => {
+            (dir, prefix.to_string_lossy().into_owned())
+        }
+        _ => (path.as_path(), String::new()),
+    };
+    let mut candidates: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(std::result::Result::ok)
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+                .map(|entry| entry.path())
+                .collect()
+        })
+        .unwrap_or_default();
+    candidates.sort();
+    match candidates.as_slice() {
+        [only] => Ok(only.clone()),
+        [] => Err(Error::InvalidDevice(path.display().to_string())),
+        several => Err(Error::InvalidDevice(format!(
+            "{} could be any of {}",
+            path.display(),
+            several
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use std::fs;
+
+    const SUB: &str = "nqn.2023-11.sh.tty:wizard";
+    const HOST: &str = "nqn.2023-11.sh.tty:client";
+
+    fn dev_dir(name: &str, devices: &[&str]) -> TestDir {
+        let dev = TestDir::new(&format!("wizard-{name}"));
+        for device in devices {
+            fs::write(dev.join(device), "").unwrap();
+        }
+        dev
+    }
+
+    fn run(state: &State, dev: &Path, script: &str) -> (Result<Vec<StateDelta>>, String) {
+        let mut output = Vec::new();
+        let script = format!("{script}\n");
+        let result = SubsystemWizard::new(state, script.as_bytes(), &mut output)
+            .dev_dir(dev)
+            .run();
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_wizard() {
+        let dev = dev_dir("full", &["loop0", "nvme0n1", "nvme1n1"]);
+        let mut state = State::default();
+        state
+            .ports
+            .insert(3, Port::new(PortType::Loop, BTreeSet::new()));
+        let script = [
+            "not-an-nqn",
+            SUB,
+            "Model",
+            "",
+            "",     // A namespace is needed.
+            "nvme", // Ambiguous.
+            "loop", // Completed to loop0.
+            "0",    // Invalid namespace ID.
+            "",     // The next free one, 1.
+            "nvme1n1",
+            "1", // Taken.
+            "7",
+            "",
+            HOST,
+            "",
+            "", // The existing port 3.
+        ]
+        .join("\n");
+        let (deltas, output) = run(&state, &dev, &script);
+        let deltas = deltas.unwrap();
+
+        let mut sub = Subsystem::builder()
+            .model("Model")
+            .host(HOST)
+            .build()
+            .unwrap();
+        sub.namespaces
+            .insert(1, Namespace::builder(dev.join("loop0")).build().unwrap());
+        sub.namespaces
+            .insert(7, Namespace::builder(dev.join("nvme1n1")).build().unwrap());
+        assert_eq!(
+            deltas,
+            vec![
+                StateDelta::AddSubsystem(SUB.to_string(), sub),
+                StateDelta::UpdatePort(3, vec![PortDelta::AddSubsystem(SUB.to_string())]),
+            ]
+        );
+        assert!(output.contains("Subsystem NQN [nqn.2014-08.org.nvmexpress:uuid:"));
+        assert!(output.contains("NVMe Qualified Name does not start with 'nqn.': not-an-nqn"));
+        assert!(output.contains("nvme0n1, "));
+        assert!(output.contains("Namespace ID [2]: "));
+        assert!(output.contains("\t3: loop://"));
+        assert!(output.contains("Port ID to provide the subsystem on, or none [3]: "));
+    }
+
+    #[test]
+    fn test_wizard_new_port() {
+        let dev = dev_dir("port", &["loop0"]);
+        let state = State::default();
+        let script = [
+            "",
+            "",
+            "",
+            "loop0",
+            "",
+            "",
+            "",
+            "2",
+            "tcp://nope",
+            "loop://",
+        ]
+        .join("\n");
+        let (deltas, output) = run(&state, &dev, &script);
+        let deltas = deltas.unwrap();
+
+        let StateDelta::AddSubsystem(nqn, sub) = &deltas[0] else {
+            panic!("{deltas:?}");
+        };
+        assert!(assert_compliant_nqn(nqn).is_ok());
+        assert!(sub.allowed_hosts.is_empty() && sub.model.is_none());
+        assert_eq!(
+            deltas[1],
+            StateDelta::AddPort(2, Port::new(PortType::Loop, BTreeSet::from([nqn.clone()])))
+        );
+        assert!(output.contains("Invalid port address: tcp://nope"));
+
+        // Without a port, there only is the subsystem.
+        let script = [SUB, "", "", "loop0", "", "", "", "none"].join("\n");
+        let (deltas, output) = run(&state, &dev, &script);
+        assert_eq!(deltas.unwrap().len(), 1);
+        assert!(output.contains("no host can connect"));
+    }
+
+    #[test]
+    fn test_wizard_conflicts() {
+        let dev = dev_dir("conflicts", &["loop0"]);
+        let mut state = State::default();
+        state
+            .subsystems
+            .insert(SUB.to_string(), Subsystem::default());
+
+        // Existing subsystems are asked again, running out of answers fails.
+        let (result, output) = run(&state, &dev, SUB);
+        assert!(output.contains("already exists"));
+        let err = result.unwrap_err();
+        assert!(matches!(err.root(), Error::Io(err) if err.kind() == ErrorKind::UnexpectedEof));
+    }
+}
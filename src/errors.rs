@@ -1,4 +1,27 @@
-pub use anyhow::Result;
+//! Errors of this crate.
+//!
+//! Functions return an `Error`, wrapped in `Error::Context` layers describing what was being
+//! done. `Error::root` gets at the error beneath them, which can be matched directly:
+//!
+//! ```
+//! use nvmetcfg::errors::Error;
+//! use nvmetcfg::state::{State, StateDelta};
+//!
+//! let err = State::default()
+//!     .apply_deltas(&[StateDelta::RemoveSubsystem("nqn.2023-11.sh.tty:gone".to_string())])
+//!     .unwrap_err();
+//! assert!(matches!(err.root(), Error::NoSuchSubsystem(_)));
+//! ```
+//!
+//! Once converted into an `anyhow::Error`, `Error::find` or `ErrorExt::nvmet_error` do the same,
+//! with the `anyhow` feature enabled.
+//!
+//! To tell errors apart without matching every variant, `Error::kind` sorts them into the broad
+//! categories of `ErrorKind`, which `Error::is_not_found` and friends test for.
+
+use std::fmt::Display;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -8,6 +31,8 @@ pub enum Error {
     InvalidNumber(#[from] std::num::ParseIntError),
     #[error("/sys/kernel/config/nvmet does not exist. Are the nvmet modules loaded?")]
     NoNvmetSysfs,
+    #[error("Permission denied for {0}. Configuring nvmet needs root or CAP_SYS_ADMIN, try sudo")]
+    PermissionDenied(String),
     #[error("NVMe Qualified Name is not ASCII-only: {0}")]
     NQNNotAscii(String),
     #[error("NVMe Qualified Name is shorter than 13 bytes: {0}")]
@@ -26,14 +51,22 @@ pub enum Error {
     NQNInvalidIdentifier(String),
     #[error("Unsupported addr_trtype: {0}")]
     UnsupportedTrType(String),
+    #[error("Unsupported addr_adrfam: {0}")]
+    UnsupportedAdrFam(String),
     #[error("Failed to parse IP address")]
     InvalidIPAddr(#[from] std::net::AddrParseError),
+    #[error("Invalid port number (trsvcid): {0:?} - must be 1-65535")]
+    InvalidServiceId(String),
     #[error("Invalid FibreChannel addr_traddr: expected format nn-0x1000000044001123:pn-0x2000000055001123 or nn-1000000044001123:pn-2000000055001123: {0}")]
     InvalidFCAddr(String),
     #[error("Invalid Fibre Channel WWNN: {0}")]
     InvalidFCWWNN(String),
     #[error("Invalid Fibre Channel WWPN: {0}")]
     InvalidFCWWPN(String),
+    #[error(
+        "No local Fibre Channel HBA has the address {0}, skip this check with --skip-wwn-check"
+    )]
+    NoLocalWwn(String),
     #[error("No port with ID {0}")]
     NoSuchPort(u16),
     #[error("No subsystem with NQN {0}")]
@@ -50,6 +83,12 @@ pub enum Error {
     NoSuchHost(String),
     #[error("Invalid Device: {0}")]
     InvalidDevice(String),
+    #[error("Invalid device {0}, expected a path, UUID=<uuid> or LABEL=<label>")]
+    InvalidDeviceSpec(String),
+    #[error("No block device has a filesystem with {0}")]
+    NoSuchDevice(String),
+    #[error("Several block devices have a filesystem with {0}: {1}")]
+    AmbiguousDevice(String, String),
     #[error("Invalid namespace ID {0} - must not be 0 or NVME_NSID_ALL (4294967295)")]
     InvalidNamespaceID(u32),
     #[error("No namespace {0} in Subsystem {1}")]
@@ -62,4 +101,696 @@ pub enum Error {
     UpdateNoChanges,
     #[error("Unsupported config version: {0}")]
     UnsupportedConfigVersion(u32),
+    #[error("Unknown field to ignore: {0} (expected uuid, nguid, serial, model or enabled)")]
+    InvalidIgnoreField(String),
+    #[error("System state differs from saved state: {0} state changes")]
+    StateMismatch(usize),
+    #[error("Port {0} reports {1} as '{3}' after writing '{2}'")]
+    PortAttributeMismatch(u16, String, String, String),
+    #[error("Secret is empty")]
+    EmptySecret,
+    #[error("Environment variable {0} containing the secret is not set")]
+    MissingSecretEnv(String),
+    #[error("Secret is longer than {0} bytes")]
+    SecretTooLong(usize),
+    #[error("Secret is not valid UTF-8")]
+    InvalidSecretEncoding,
+    #[error("Invalid DH-HMAC-CHAP key: {0}")]
+    InvalidDhchapKey(String),
+    #[error("Port {0} uses transport {1}, cannot only update its address to a {2} one")]
+    PortTransportChanged(u16, String, String),
+    #[error("Ports {1} and {2} both use address {0}")]
+    DuplicatePortAddress(String, u16, u16),
+    #[error("Port {0} is offline")]
+    PortOffline(u16),
+    #[error("Port {0} is not offline")]
+    PortNotOffline(u16),
+    #[error("Port {0} still has {1} connected controllers")]
+    PortNotDrained(u16, usize),
+    #[error("Cannot bring port {0} online, its subsystems no longer exist: {1}")]
+    OfflineSubsystemsGone(u16, String),
+    #[error("{0} of {1} operations failed")]
+    PartialFailure(usize, usize),
+    #[error("Invalid subsystem alias: {0} (ASCII letters, digits, '.', '_' and '-' only and 1-64 bytes)")]
+    InvalidAlias(String),
+    #[error("Alias {0} is used by both {1} and {2}")]
+    DuplicateAlias(String, String, String),
+    #[error("No subsystem with alias {0}")]
+    UnknownAlias(String),
+    #[error("No profile named {0} in state file")]
+    NoSuchProfile(String),
+    #[error("Alias {0} is ambiguous, it refers to: {1}")]
+    AmbiguousAlias(String, String),
+    #[error("Port {0} cannot be created - it already exists")]
+    ExistingPort(u16),
+    #[error("Command not supported in batch mode: {0}")]
+    UnsupportedInBatch(String),
+    #[error("Unbalanced quotes: {0}")]
+    InvalidBatchLine(String),
+    #[error("{0} subsystems could not be gathered")]
+    UnreadableSubsystems(usize),
+    #[error("Host {0} cannot be created - it already exists")]
+    ExistingHost(String),
+    #[error("State changed since the changes were computed: {0}")]
+    StateChanged(String),
+    #[error("Device of namespace {0} in Subsystem {1} does not have the expected UUID {2}")]
+    DeviceIdentityMismatch(u32, String, uuid::Uuid),
+    #[error("Unknown field to redact: {0} (expected serials, hosts or keys)")]
+    InvalidRedactField(String),
+    #[error("State file has redacted {0}, restoring it anyway needs --force")]
+    RedactedState(String),
+    #[error("{0} needs a terminal to ask on")]
+    NoTerminal(&'static str),
+    #[error("Timed out waiting for {0} after {}", humantime::format_duration(*.1))]
+    Timeout(String, std::time::Duration),
+    #[error("Subsystem {0} allows any host, copying that needs --allow-any-host")]
+    CopyAllowAnyHost(String),
+    #[error("Invalid label key: {0} (ASCII letters, digits, '.', '_', '-' and '/' only, starting with a letter or digit and 1-63 bytes)")]
+    InvalidLabelKey(String),
+    #[error("Invalid label value: {0} (printable characters only and at most 255 bytes)")]
+    InvalidLabelValue(String),
+    #[error("Invalid label: {0} (expected key=value)")]
+    InvalidLabel(String),
+    #[error("No network interface {0}")]
+    NoSuchInterface(String),
+    #[error("Network interface {0} has no global IP address")]
+    NoInterfaceAddress(String),
+    #[error("Failed to resolve host name {0}")]
+    UnresolvableHost(String),
+    #[error("{0} has no {1} address")]
+    AddrFamilyMismatch(String, String),
+    #[error("Ports using transport {0} cannot take their address from a network interface")]
+    UnsupportedInterfaceTransport(String),
+    #[error("Invalid interface and port: {0} (expected format like eth1:4420)")]
+    InvalidInterfacePort(String),
+    #[error("{0} given before any {1}")]
+    BuilderOrder(&'static str, &'static str),
+    #[error("State files include each other: {0}")]
+    IncludeCycle(String),
+    #[error("Changing the UUID or NGUID of namespaces on the same device makes initiators see new disks, allow it explicitly: {0}")]
+    NamespaceIdentityChange(String),
+    #[error("Invalid maximum number of I/O queues {0} - must be 1-128")]
+    InvalidQidMax(u32),
+    #[error("Kernel does not support {0}, skip those with --skip-unsupported")]
+    UnsupportedAttributes(String),
+    #[error("{0} TCP ports are not listening, is their address assigned to a local interface?")]
+    NotListening(usize),
+    #[error("{0} errors found in the configfs tree")]
+    BrokenTree(usize),
+    #[error("{0} is not a whole NVMe namespace, so it has no NVMe identity")]
+    NotNvmeNamespace(String),
+    #[error("NVMe namespace {0} reports neither a UUID nor an NGUID")]
+    NoNvmeIdentity(String),
+    #[error("NVMe command to {0} failed with status {1:#x}")]
+    NvmeCommandFailed(String, i32),
+    #[error("Invalid NVMe namespace identification descriptor of type {0} and length {1}")]
+    InvalidNvmeDescriptor(u8, u8),
+    #[error("Renumbering the namespaces of Subsystem {0} changes the NSIDs initiators see, confirm it with --yes")]
+    UnconfirmedRenumbering(String),
+    #[error("Notification command {0} failed: {1}")]
+    NotifyCommandFailed(String, String),
+    #[error("Invalid port address: {0} (expected URI like tcp://192.0.2.1:4420, rdma://[fdff::1]:4420, fc://nn-0x1000000044001123:pn-0x2000000055001123 or loop://)")]
+    InvalidPortUri(String),
+    #[error("Refusing to fetch {0}, only https:// URLs are allowed, or http:// with --insecure")]
+    InsecureUrl(String),
+    #[error("Failed to fetch {0}: HTTP status {1} {2}")]
+    HttpStatus(String, u16, String),
+    #[error("HTTP request failed: {0}")]
+    Http(String),
+    #[error("Response from {0} is larger than {1} bytes")]
+    ResponseTooLarge(String, u64),
+    #[error("Invalid SHA-256 digest: {0} (expected 64 hexadecimal digits)")]
+    InvalidDigest(String),
+    #[error("SHA-256 digest mismatch: expected {0}, got {1}")]
+    DigestMismatch(String, String),
+    #[error("Invalid compression: {0} (expected gzip, zstd or none)")]
+    InvalidCompression(String),
+    #[error("Support for {0} compression was not built in")]
+    UnsupportedCompression(&'static str),
+    #[error("Invalid object: {0} (expected subsystem:<nqn>, port:<id>, namespace:<nqn>/<nsid> or host:<nqn>)")]
+    InvalidObjectRef(String),
+    #[error("Invalid attribute name: {0} (expected lowercase letters, digits and underscores)")]
+    InvalidAttributeName(String),
+    #[error("{0} has no attribute {1}")]
+    NoSuchAttribute(String, String),
+    #[error("nvmet debugfs support not available: {0} does not exist. It needs Linux 6.10 or newer and debugfs mounted")]
+    NoDebugfs(String),
+    #[error("Failed to parse YAML")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("Failed to parse JSON")]
+    Json(#[from] serde_json::Error),
+    /// Describes what was being done when `source` happened, see `Context`.
+    #[error("{context}")]
+    Context {
+        context: String,
+        #[source]
+        source: Box<Error>,
+    },
+}
+
+/// Broad categories of errors, to handle them without matching every `Error` variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// I/O errors and anything else unexpected.
+    Internal,
+    /// The port, subsystem, namespace, host or whatever else was referred to does not exist.
+    NotFound,
+    /// Invalid arguments, names or files.
+    InvalidInput,
+    /// The object already exists, or the system state differs from what was expected.
+    Conflict,
+    /// The kernel lacks nvmet or the requested feature.
+    UnsupportedKernel,
+    /// Lacking the privileges needed, usually those of root.
+    PermissionDenied,
+}
+
+impl Error {
+    /// The category of the error beneath all `Error::Context` layers.
+    ///
+    /// The nvmet command exits with a code given by this.
+    #[must_use]
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Context { source, .. } => source.kind(),
+            Self::Io(_)
+            | Self::PartialFailure(..)
+            | Self::UnreadableSubsystems(_)
+            | Self::HttpStatus(..)
+            | Self::Http(_)
+            | Self::NvmeCommandFailed(..)
+            | Self::InvalidNvmeDescriptor(..)
+            | Self::NotifyCommandFailed(..) => ErrorKind::Internal,
+            Self::NoLocalWwn(_)
+            | Self::NoSuchPort(_)
+            | Self::NoSuchSubsystem(_)
+            | Self::NoSuchHost(_)
+            | Self::NoSuchDevice(_)
+            | Self::NoSuchNamespace(..)
+            | Self::OfflineSubsystemsGone(..)
+            | Self::UnknownAlias(_)
+            | Self::NoSuchProfile(_)
+            | Self::Timeout(..)
+            | Self::NoSuchInterface(_)
+            | Self::NoInterfaceAddress(_)
+            | Self::UnresolvableHost(_)
+            | Self::NoNvmeIdentity(_)
+            | Self::NoSuchAttribute(..) => ErrorKind::NotFound,
+            Self::InvalidNumber(_)
+            | Self::NQNNotAscii(_)
+            | Self::NQNTooShort(_)
+            | Self::NQNTooLong(_)
+            | Self::NQNMissingNQN(_)
+            | Self::NQNUuidInvalid(_)
+            | Self::NQNInvalidDate(_)
+            | Self::NQNInvalidDomain(_)
+            | Self::NQNInvalidIdentifier(_)
+            | Self::UnsupportedTrType(_)
+            | Self::UnsupportedAdrFam(_)
+            | Self::InvalidIPAddr(_)
+            | Self::InvalidServiceId(_)
+            | Self::NotNvmeNamespace(_)
+            | Self::InvalidFCAddr(_)
+            | Self::InvalidFCWWNN(_)
+            | Self::InvalidFCWWPN(_)
+            | Self::CantCreateDiscovery
+            | Self::InvalidModel(_)
+            | Self::InvalidSerial(_)
+            | Self::InvalidDevice(_)
+            | Self::InvalidDeviceSpec(_)
+            | Self::InvalidNamespaceID(_)
+            | Self::InvalidUuid(_)
+            | Self::UpdateNoChanges
+            | Self::UnsupportedConfigVersion(_)
+            | Self::InvalidIgnoreField(_)
+            | Self::EmptySecret
+            | Self::MissingSecretEnv(_)
+            | Self::SecretTooLong(_)
+            | Self::InvalidSecretEncoding
+            | Self::InvalidDhchapKey(_)
+            | Self::PortTransportChanged(..)
+            | Self::DuplicatePortAddress(..)
+            | Self::InvalidAlias(_)
+            | Self::AmbiguousAlias(..)
+            | Self::UnsupportedInBatch(_)
+            | Self::InvalidBatchLine(_)
+            | Self::InvalidRedactField(_)
+            | Self::RedactedState(_)
+            | Self::UnconfirmedRenumbering(_)
+            | Self::NoTerminal(_)
+            | Self::CopyAllowAnyHost(_)
+            | Self::InvalidLabelKey(_)
+            | Self::InvalidLabelValue(_)
+            | Self::InvalidLabel(_)
+            | Self::AddrFamilyMismatch(..)
+            | Self::UnsupportedInterfaceTransport(_)
+            | Self::InvalidInterfacePort(_)
+            | Self::BuilderOrder(..)
+            | Self::IncludeCycle(_)
+            | Self::InvalidQidMax(_)
+            | Self::InvalidPortUri(_)
+            | Self::InsecureUrl(_)
+            | Self::ResponseTooLarge(..)
+            | Self::InvalidDigest(_)
+            | Self::InvalidCompression(_)
+            | Self::InvalidObjectRef(_)
+            | Self::InvalidAttributeName(_)
+            | Self::Yaml(_)
+            | Self::Json(_) => ErrorKind::InvalidInput,
+            Self::ExistingSubsystem(_)
+            | Self::AmbiguousDevice(..)
+            | Self::ExistingNamespace(..)
+            | Self::StateMismatch(_)
+            | Self::PortOffline(_)
+            | Self::PortNotOffline(_)
+            | Self::PortNotDrained(..)
+            | Self::DuplicateAlias(..)
+            | Self::ExistingPort(_)
+            | Self::ExistingHost(_)
+            | Self::StateChanged(_)
+            | Self::DeviceIdentityMismatch(..)
+            | Self::NamespaceIdentityChange(_)
+            | Self::NotListening(_)
+            | Self::BrokenTree(_)
+            | Self::DigestMismatch(..) => ErrorKind::Conflict,
+            Self::NoNvmetSysfs
+            | Self::PortAttributeMismatch(..)
+            | Self::UnsupportedAttributes(_)
+            | Self::UnsupportedCompression(_)
+            | Self::NoDebugfs(_) => ErrorKind::UnsupportedKernel,
+            Self::PermissionDenied(_) => ErrorKind::PermissionDenied,
+        }
+    }
+
+    /// Whether something referred to does not exist, see `ErrorKind::NotFound`.
+    #[must_use]
+    pub fn is_not_found(&self) -> bool {
+        self.kind() == ErrorKind::NotFound
+    }
+
+    /// Whether something already exists or has changed, see `ErrorKind::Conflict`.
+    #[must_use]
+    pub fn is_conflict(&self) -> bool {
+        self.kind() == ErrorKind::Conflict
+    }
+
+    /// Whether what was asked for is invalid, see `ErrorKind::InvalidInput`.
+    #[must_use]
+    pub fn is_invalid_input(&self) -> bool {
+        self.kind() == ErrorKind::InvalidInput
+    }
+
+    /// Whether the kernel lacks what was asked for, see `ErrorKind::UnsupportedKernel`.
+    #[must_use]
+    pub fn is_unsupported_kernel(&self) -> bool {
+        self.kind() == ErrorKind::UnsupportedKernel
+    }
+
+    /// Whether trying again may succeed, as the error came from a passing failure or the
+    /// system changing underneath, rather than from what was asked for.
+    ///
+    /// `KernelConfig::apply_state` only retries such errors.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        match self.root() {
+            // The kernel rejecting a value or access stays that way.
+            Self::Io(err) => !matches!(
+                err.kind(),
+                std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::InvalidInput
+            ),
+            Self::StateChanged(_)
+            | Self::UnreadableSubsystems(_)
+            | Self::PartialFailure(..)
+            | Self::Timeout(..)
+            | Self::Http(_) => true,
+            Self::HttpStatus(_, status, _) => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// The error beneath all `Error::Context` layers, which is the most specific one.
+    #[must_use]
+    pub fn root(&self) -> &Self {
+        match self {
+            Self::Context { source, .. } => source.root(),
+            other => other,
+        }
+    }
+
+    /// The message of this error followed by those of its causes, like `{:#}` of `anyhow::Error`.
+    #[must_use]
+    pub fn full_message(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        message
+    }
+
+    /// The first `Error` in the chain of `err`, with its context skipped, see `Error::root`.
+    #[cfg(feature = "anyhow")]
+    #[must_use]
+    pub fn find(err: &anyhow::Error) -> Option<&Self> {
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<Self>())
+            .map(Self::root)
+    }
+}
+
+/// Adding context to errors, like `anyhow::Context`, while keeping them an `Error`.
+pub trait Context<T> {
+    /// Wrap the error in an `Error::Context` describing what was being done.
+    fn context<C: Display>(self, context: C) -> Result<T>;
+
+    /// Like `context`, but only building the description if there is an error.
+    fn with_context<C: Display, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context<C: Display>(self, context: C) -> Result<T> {
+        self.map_err(|err| Error::Context {
+            context: context.to_string(),
+            source: Box::new(err.into()),
+        })
+    }
+
+    fn with_context<C: Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|err| Error::Context {
+            context: f().to_string(),
+            source: Box::new(err.into()),
+        })
+    }
+}
+
+/// Inspecting the `Error` wrapped in the errors returned by this crate.
+pub trait ErrorExt {
+    /// See `Error::find`.
+    fn nvmet_error(&self) -> Option<&Error>;
+
+    /// Whether the wrapped `Error` matches `pred`.
+    fn is_nvmet_error(&self, pred: impl FnOnce(&Error) -> bool) -> bool {
+        self.nvmet_error().is_some_and(pred)
+    }
+}
+
+#[cfg(feature = "anyhow")]
+impl ErrorExt for anyhow::Error {
+    fn nvmet_error(&self) -> Option<&Error> {
+        Error::find(self)
+    }
+}
+
+impl ErrorExt for Error {
+    fn nvmet_error(&self) -> Option<&Error> {
+        Some(self.root())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{State, StateDelta, Subsystem, SubsystemDelta};
+    use std::collections::HashSet;
+    use std::mem::discriminant;
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    const SUB: &str = "nqn.2023-11.sh.tty:sub";
+
+    fn update_missing(state: &mut State) -> Result<()> {
+        state
+            .apply_deltas(&[StateDelta::UpdateSubsystem(
+                SUB.to_string(),
+                vec![SubsystemDelta::UpdateModel("Model".to_string())],
+            )])
+            .context("Failed to update")
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let mut state = State::default();
+        let err = update_missing(&mut state).unwrap_err();
+        match err.root() {
+            Error::NoSuchSubsystem(nqn) => assert_eq!(nqn, SUB),
+            other => panic!("unexpected error: {other:?}"),
+        }
+        assert!(matches!(err, Error::Context { .. }));
+        assert_eq!(err.to_string(), "Failed to update");
+        assert_eq!(
+            err.full_message(),
+            format!("Failed to update: No subsystem with NQN {SUB}")
+        );
+
+        state
+            .subsystems
+            .insert(SUB.to_string(), Subsystem::default());
+        let err = state
+            .apply_deltas(&[StateDelta::AddSubsystem(
+                SUB.to_string(),
+                Subsystem::default(),
+            )])
+            .unwrap_err();
+        assert!(matches!(err, Error::ExistingSubsystem(_)));
+        assert!(err.is_nvmet_error(|e| matches!(e, Error::ExistingSubsystem(_))));
+
+        // Errors of other crates are taken in as they are.
+        let err = "x".parse::<u32>().context("Failed to parse").unwrap_err();
+        assert!(matches!(err.root(), Error::InvalidNumber(_)));
+    }
+
+    #[test]
+    #[cfg(feature = "anyhow")]
+    fn test_find_error() {
+        let err = anyhow::Error::from(update_missing(&mut State::default()).unwrap_err())
+            .context("Failed to run command");
+        match err.nvmet_error() {
+            Some(Error::NoSuchSubsystem(nqn)) => assert_eq!(nqn, SUB),
+            other => panic!("unexpected error: {other:?}"),
+        }
+        assert!(err.is_nvmet_error(|e| matches!(e, Error::NoSuchSubsystem(_))));
+        assert!(!err.is_nvmet_error(|e| matches!(e, Error::NoSuchPort(_))));
+        assert_eq!(
+            format!("{err:#}"),
+            format!("Failed to run command: Failed to update: No subsystem with NQN {SUB}")
+        );
+
+        // Errors from elsewhere have no `Error` to find.
+        assert!(anyhow::anyhow!("other").nvmet_error().is_none());
+    }
+
+    fn s() -> String {
+        "x".to_string()
+    }
+
+    // `Error::kind` has no catch-all, so new variants get a kind there, and belong here too.
+    fn all_errors() -> Vec<(Error, ErrorKind)> {
+        vec![
+            (Error::Io(std::io::Error::other("x")), ErrorKind::Internal),
+            (
+                Error::InvalidNumber("x".parse::<u32>().unwrap_err()),
+                ErrorKind::InvalidInput,
+            ),
+            (Error::NoNvmetSysfs, ErrorKind::UnsupportedKernel),
+            (Error::PermissionDenied(s()), ErrorKind::PermissionDenied),
+            (Error::NQNNotAscii(s()), ErrorKind::InvalidInput),
+            (Error::NQNTooShort(s()), ErrorKind::InvalidInput),
+            (Error::NQNTooLong(s()), ErrorKind::InvalidInput),
+            (Error::NQNMissingNQN(s()), ErrorKind::InvalidInput),
+            (Error::NQNUuidInvalid(s()), ErrorKind::InvalidInput),
+            (Error::NQNInvalidDate(s()), ErrorKind::InvalidInput),
+            (Error::NQNInvalidDomain(s()), ErrorKind::InvalidInput),
+            (Error::NQNInvalidIdentifier(s()), ErrorKind::InvalidInput),
+            (Error::UnsupportedTrType(s()), ErrorKind::InvalidInput),
+            (Error::UnsupportedAdrFam(s()), ErrorKind::InvalidInput),
+            (
+                Error::InvalidIPAddr("x".parse::<IpAddr>().unwrap_err()),
+                ErrorKind::InvalidInput,
+            ),
+            (Error::InvalidServiceId(s()), ErrorKind::InvalidInput),
+            (Error::InvalidFCAddr(s()), ErrorKind::InvalidInput),
+            (Error::InvalidFCWWNN(s()), ErrorKind::InvalidInput),
+            (Error::InvalidFCWWPN(s()), ErrorKind::InvalidInput),
+            (Error::NoLocalWwn(s()), ErrorKind::NotFound),
+            (Error::NoSuchPort(1), ErrorKind::NotFound),
+            (Error::NoSuchSubsystem(s()), ErrorKind::NotFound),
+            (Error::ExistingSubsystem(s()), ErrorKind::Conflict),
+            (Error::CantCreateDiscovery, ErrorKind::InvalidInput),
+            (Error::InvalidModel(s()), ErrorKind::InvalidInput),
+            (Error::InvalidSerial(s()), ErrorKind::InvalidInput),
+            (Error::NoSuchHost(s()), ErrorKind::NotFound),
+            (Error::InvalidDevice(s()), ErrorKind::InvalidInput),
+            (Error::InvalidDeviceSpec(s()), ErrorKind::InvalidInput),
+            (Error::NoSuchDevice(s()), ErrorKind::NotFound),
+            (Error::AmbiguousDevice(s(), s()), ErrorKind::Conflict),
+            (Error::InvalidNamespaceID(1), ErrorKind::InvalidInput),
+            (Error::NoSuchNamespace(1, s()), ErrorKind::NotFound),
+            (Error::ExistingNamespace(1, s()), ErrorKind::Conflict),
+            (
+                Error::InvalidUuid(Uuid::parse_str("x").unwrap_err()),
+                ErrorKind::InvalidInput,
+            ),
+            (Error::UpdateNoChanges, ErrorKind::InvalidInput),
+            (Error::UnsupportedConfigVersion(1), ErrorKind::InvalidInput),
+            (Error::InvalidIgnoreField(s()), ErrorKind::InvalidInput),
+            (Error::StateMismatch(1), ErrorKind::Conflict),
+            (
+                Error::PortAttributeMismatch(1, s(), s(), s()),
+                ErrorKind::UnsupportedKernel,
+            ),
+            (Error::EmptySecret, ErrorKind::InvalidInput),
+            (Error::MissingSecretEnv(s()), ErrorKind::InvalidInput),
+            (Error::SecretTooLong(1), ErrorKind::InvalidInput),
+            (Error::InvalidSecretEncoding, ErrorKind::InvalidInput),
+            (Error::InvalidDhchapKey(s()), ErrorKind::InvalidInput),
+            (
+                Error::PortTransportChanged(1, s(), s()),
+                ErrorKind::InvalidInput,
+            ),
+            (
+                Error::DuplicatePortAddress(s(), 1, 1),
+                ErrorKind::InvalidInput,
+            ),
+            (Error::PortOffline(1), ErrorKind::Conflict),
+            (Error::PortNotOffline(1), ErrorKind::Conflict),
+            (Error::PortNotDrained(1, 1), ErrorKind::Conflict),
+            (Error::OfflineSubsystemsGone(1, s()), ErrorKind::NotFound),
+            (Error::PartialFailure(1, 1), ErrorKind::Internal),
+            (Error::InvalidAlias(s()), ErrorKind::InvalidInput),
+            (Error::DuplicateAlias(s(), s(), s()), ErrorKind::Conflict),
+            (Error::UnknownAlias(s()), ErrorKind::NotFound),
+            (Error::NoSuchProfile(s()), ErrorKind::NotFound),
+            (Error::AmbiguousAlias(s(), s()), ErrorKind::InvalidInput),
+            (Error::ExistingPort(1), ErrorKind::Conflict),
+            (Error::UnsupportedInBatch(s()), ErrorKind::InvalidInput),
+            (Error::InvalidBatchLine(s()), ErrorKind::InvalidInput),
+            (Error::UnreadableSubsystems(1), ErrorKind::Internal),
+            (Error::ExistingHost(s()), ErrorKind::Conflict),
+            (Error::StateChanged(s()), ErrorKind::Conflict),
+            (
+                Error::DeviceIdentityMismatch(1, s(), Uuid::nil()),
+                ErrorKind::Conflict,
+            ),
+            (Error::InvalidRedactField(s()), ErrorKind::InvalidInput),
+            (Error::RedactedState(s()), ErrorKind::InvalidInput),
+            (Error::NoTerminal("x"), ErrorKind::InvalidInput),
+            (
+                Error::Timeout(s(), Duration::from_secs(1)),
+                ErrorKind::NotFound,
+            ),
+            (Error::CopyAllowAnyHost(s()), ErrorKind::InvalidInput),
+            (Error::InvalidLabelKey(s()), ErrorKind::InvalidInput),
+            (Error::InvalidLabelValue(s()), ErrorKind::InvalidInput),
+            (Error::InvalidLabel(s()), ErrorKind::InvalidInput),
+            (Error::NoSuchInterface(s()), ErrorKind::NotFound),
+            (Error::NoInterfaceAddress(s()), ErrorKind::NotFound),
+            (Error::UnresolvableHost(s()), ErrorKind::NotFound),
+            (Error::AddrFamilyMismatch(s(), s()), ErrorKind::InvalidInput),
+            (
+                Error::UnsupportedInterfaceTransport(s()),
+                ErrorKind::InvalidInput,
+            ),
+            (Error::InvalidInterfacePort(s()), ErrorKind::InvalidInput),
+            (Error::BuilderOrder("x", "x"), ErrorKind::InvalidInput),
+            (Error::IncludeCycle(s()), ErrorKind::InvalidInput),
+            (Error::NamespaceIdentityChange(s()), ErrorKind::Conflict),
+            (Error::InvalidQidMax(1), ErrorKind::InvalidInput),
+            (
+                Error::UnsupportedAttributes(s()),
+                ErrorKind::UnsupportedKernel,
+            ),
+            (Error::NotListening(1), ErrorKind::Conflict),
+            (Error::BrokenTree(1), ErrorKind::Conflict),
+            (Error::NotNvmeNamespace(s()), ErrorKind::InvalidInput),
+            (Error::NoNvmeIdentity(s()), ErrorKind::NotFound),
+            (Error::NvmeCommandFailed(s(), 0x2), ErrorKind::Internal),
+            (Error::InvalidNvmeDescriptor(3, 8), ErrorKind::Internal),
+            (Error::UnconfirmedRenumbering(s()), ErrorKind::InvalidInput),
+            (Error::NotifyCommandFailed(s(), s()), ErrorKind::Internal),
+            (Error::InvalidPortUri(s()), ErrorKind::InvalidInput),
+            (Error::InsecureUrl(s()), ErrorKind::InvalidInput),
+            (Error::HttpStatus(s(), 1, s()), ErrorKind::Internal),
+            (Error::Http(s()), ErrorKind::Internal),
+            (Error::ResponseTooLarge(s(), 1), ErrorKind::InvalidInput),
+            (Error::InvalidDigest(s()), ErrorKind::InvalidInput),
+            (Error::DigestMismatch(s(), s()), ErrorKind::Conflict),
+            (Error::InvalidCompression(s()), ErrorKind::InvalidInput),
+            (
+                Error::UnsupportedCompression("x"),
+                ErrorKind::UnsupportedKernel,
+            ),
+            (Error::InvalidObjectRef(s()), ErrorKind::InvalidInput),
+            (Error::InvalidAttributeName(s()), ErrorKind::InvalidInput),
+            (Error::NoSuchAttribute(s(), s()), ErrorKind::NotFound),
+            (Error::NoDebugfs(s()), ErrorKind::UnsupportedKernel),
+            (
+                Error::Yaml(serde_yaml::from_str::<u32>("x").unwrap_err()),
+                ErrorKind::InvalidInput,
+            ),
+            (
+                Error::Json(serde_json::from_str::<u32>("x").unwrap_err()),
+                ErrorKind::InvalidInput,
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let errors = all_errors();
+        let variants: HashSet<_> = errors.iter().map(|(err, _)| discriminant(err)).collect();
+        assert_eq!(variants.len(), errors.len());
+
+        for (err, kind) in errors {
+            assert_eq!(err.kind(), kind, "{err:?}");
+            assert_eq!(err.is_not_found(), kind == ErrorKind::NotFound, "{err:?}");
+            assert_eq!(err.is_conflict(), kind == ErrorKind::Conflict, "{err:?}");
+            assert_eq!(
+                err.is_invalid_input(),
+                kind == ErrorKind::InvalidInput,
+                "{err:?}"
+            );
+            assert_eq!(
+                err.is_unsupported_kernel(),
+                kind == ErrorKind::UnsupportedKernel,
+                "{err:?}"
+            );
+            // Context doesn't change what went wrong.
+            let transient = err.is_transient();
+            let err = Err::<(), _>(err).context("Failed").unwrap_err();
+            assert_eq!(err.kind(), kind);
+            assert_eq!(err.is_transient(), transient);
+        }
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        let transient: Vec<String> = all_errors()
+            .into_iter()
+            .map(|(err, _)| err)
+            .filter(Error::is_transient)
+            .map(|err| format!("{err:?}").split('(').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            transient,
+            [
+                "Io",
+                "PartialFailure",
+                "UnreadableSubsystems",
+                "StateChanged",
+                "Timeout",
+                "Http"
+            ]
+        );
+        // Only server errors and rate limiting might pass.
+        assert!(Error::HttpStatus(s(), 503, s()).is_transient());
+        assert!(Error::HttpStatus(s(), 429, s()).is_transient());
+        assert!(!Error::HttpStatus(s(), 404, s()).is_transient());
+        let io = |errno| Error::Io(std::io::Error::from_raw_os_error(errno));
+        assert!(io(libc::EBUSY).is_transient());
+        assert!(!io(libc::EACCES).is_transient());
+        assert!(!io(libc::EINVAL).is_transient());
+    }
 }
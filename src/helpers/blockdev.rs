@@ -0,0 +1,549 @@
+use crate::errors::{Context, Error, Result};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// What is known about the contents of a namespace's backing device.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockDeviceInfo {
+    /// Filesystem (or other signature) type, like `ext4` or `LVM2_member`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fs_type: Option<String>,
+    /// Filesystem label.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fs_label: Option<String>,
+    /// Name of the device-mapper device, like `vg0-data`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dm_name: Option<String>,
+}
+
+/// Find out what is on the block device or file at `path`, without ever writing to it.
+///
+/// This is best effort: anything that can't be read, like a missing device, is left unset.
+/// Block devices are looked up in the udev database first, as udev has already probed them.
+#[must_use]
+pub fn probe_block_device<P: AsRef<Path>>(path: P) -> BlockDeviceInfo {
+    probe_block_device_in(
+        Path::new("/sys"),
+        Path::new("/run/udev/data"),
+        path.as_ref(),
+    )
+}
+
+fn probe_block_device_in(sys: &Path, udev_data: &Path, path: &Path) -> BlockDeviceInfo {
+    let mut info = BlockDeviceInfo::default();
+    let Ok(meta) = std::fs::metadata(path) else {
+        return info;
+    };
+    if meta.file_type().is_block_device() {
+        let (major, minor) = dev_major_minor(meta.rdev());
+        info = device_info(sys, udev_data, major, minor);
+    }
+    if info.fs_type.is_none() {
+        if let Some(sb) = File::open(path).ok().and_then(|f| probe_superblock(&f)) {
+            info.fs_type = Some(sb.fs_type.to_string());
+            info.fs_label = sb.label;
+        }
+    }
+    info
+}
+
+/// The size in bytes of the block device or file at `path`, if it exists.
+#[must_use]
+pub fn device_size<P: AsRef<Path>>(path: P) -> Option<u64> {
+    device_size_in(Path::new("/sys"), path.as_ref())
+}
+
+fn device_size_in(sys: &Path, path: &Path) -> Option<u64> {
+    let meta = std::fs::metadata(path).ok()?;
+    if !meta.file_type().is_block_device() {
+        return meta.is_file().then_some(meta.len());
+    }
+    block_device_size_in(sys, meta.rdev())
+}
+
+fn block_device_size_in(sys: &Path, rdev: u64) -> Option<u64> {
+    let (major, minor) = dev_major_minor(rdev);
+    // Always in 512 byte sectors, whatever the logical block size.
+    let sectors = std::fs::read_to_string(sys.join(format!("dev/block/{major}:{minor}/size")))
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    sectors.checked_mul(512)
+}
+
+/// A block device used by namespaces, as found by `resolve_block_device`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedDevice {
+    /// The path of the device with all symlinks resolved, as configfs gets it.
+    pub canonical: PathBuf,
+    /// The device number, as in `st_rdev`.
+    pub rdev: u64,
+    /// The size in bytes, if sysfs knows it.
+    pub size: Option<u64>,
+}
+
+/// Ensure `path` is a block device, like namespaces need, and find its canonical path and size.
+pub fn resolve_block_device<P: AsRef<Path>>(path: P) -> Result<ResolvedDevice> {
+    resolve_block_device_in(Path::new("/sys"), path.as_ref())
+}
+
+fn resolve_block_device_in(sys: &Path, path: &Path) -> Result<ResolvedDevice> {
+    // TODO: is it possible to mount a file instead? there is a mysterious "buffered_io" file..
+    let meta = std::fs::metadata(path)
+        .with_context(|| format!("Failed to get metadata for device {}", path.display()))?;
+    if !meta.file_type().is_block_device() {
+        return Err(Error::InvalidDevice(path.display().to_string()));
+    }
+    Ok(ResolvedDevice {
+        canonical: path.canonicalize()?,
+        rdev: meta.rdev(),
+        size: block_device_size_in(sys, meta.rdev()),
+    })
+}
+
+/// Block devices already resolved, see `resolve_block_device`.
+///
+/// Namespaces sharing a device, like the paths of a multipath device, only resolve it once,
+/// across checking, applying and adding up capacities. Devices may be replaced at any time, so
+/// a cache should only live as long as a single apply or listing. Failures are not cached.
+#[derive(Debug, Default)]
+pub struct DeviceCache {
+    resolved: RefCell<HashMap<PathBuf, ResolvedDevice>>,
+}
+
+impl DeviceCache {
+    /// Resolve the block device at `path`, unless it already was.
+    pub fn resolve(&self, path: &Path) -> Result<ResolvedDevice> {
+        self.resolve_with(path, |path| resolve_block_device(path))
+    }
+
+    /// Resolve the block device at `path` using `resolve`, unless it already was.
+    pub fn resolve_with<F>(&self, path: &Path, resolve: F) -> Result<ResolvedDevice>
+    where
+        F: FnOnce(&Path) -> Result<ResolvedDevice>,
+    {
+        if let Some(device) = self.resolved.borrow().get(path) {
+            return Ok(device.clone());
+        }
+        let device = resolve(path)?;
+        self.resolved
+            .borrow_mut()
+            .insert(path.to_path_buf(), device.clone());
+        Ok(device)
+    }
+
+    /// The size in bytes of the block device or file at `path`, like `device_size`.
+    pub fn size(&self, path: &Path) -> Option<u64> {
+        match self.resolve(path) {
+            Ok(device) => device.size,
+            // Regular files have a size too.
+            Err(_) => device_size(path),
+        }
+    }
+}
+
+/// Format a size in bytes for humans, like `1.5 GiB`.
+#[must_use]
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Split a device number like the kernel's `MAJOR()` and `MINOR()` do for userspace.
+pub(super) const fn dev_major_minor(rdev: u64) -> (u64, u64) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major, minor)
+}
+
+/// Look up the device-mapper name in sysfs and the filesystem in the udev database.
+fn device_info(sys: &Path, udev_data: &Path, major: u64, minor: u64) -> BlockDeviceInfo {
+    let dm_name = std::fs::read_to_string(sys.join(format!("dev/block/{major}:{minor}/dm/name")))
+        .ok()
+        .map(|name| name.trim_end().to_string())
+        .filter(|name| !name.is_empty());
+    let mut properties = std::fs::read_to_string(udev_data.join(format!("b{major}:{minor}")))
+        .map(|data| parse_udev_properties(&data))
+        .unwrap_or_default();
+    BlockDeviceInfo {
+        fs_type: properties.remove("ID_FS_TYPE"),
+        fs_label: properties.remove("ID_FS_LABEL"),
+        dm_name,
+    }
+}
+
+/// A UUID identifying what is on a device, and where it was found.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceIdentity {
+    /// Where the UUID comes from, like `filesystem` or `wwid`.
+    pub source: &'static str,
+    pub uuid: Uuid,
+}
+
+/// How a device's identities compare to the UUID it is expected to have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentityMatch {
+    /// One of the identities is the expected UUID.
+    Match(DeviceIdentity),
+    /// The device has identities, but none of them is the expected UUID.
+    Mismatch,
+    /// Nothing identifying was found on the device.
+    Unknown,
+}
+
+/// Compare the identities of a device to the UUID it is expected to have.
+#[must_use]
+pub fn match_device_identity(expected: Uuid, identities: &[DeviceIdentity]) -> IdentityMatch {
+    if identities.is_empty() {
+        return IdentityMatch::Unknown;
+    }
+    identities
+        .iter()
+        .find(|identity| identity.uuid == expected)
+        .map_or(IdentityMatch::Mismatch, |identity| {
+            IdentityMatch::Match(identity.clone())
+        })
+}
+
+/// Find the UUIDs identifying the block device at `path`, without ever writing to it.
+///
+/// These are the filesystem, partition table and partition UUIDs known to udev, and the
+/// WWID reported by the kernel if it is UUID sized. Anything that can't be read is skipped.
+#[must_use]
+pub fn probe_device_identities<P: AsRef<Path>>(path: P) -> Vec<DeviceIdentity> {
+    let Ok(meta) = std::fs::metadata(path) else {
+        return Vec::new();
+    };
+    if !meta.file_type().is_block_device() {
+        return Vec::new();
+    }
+    let (major, minor) = dev_major_minor(meta.rdev());
+    device_identities(Path::new("/sys"), Path::new("/run/udev/data"), major, minor)
+}
+
+fn device_identities(sys: &Path, udev_data: &Path, major: u64, minor: u64) -> Vec<DeviceIdentity> {
+    let mut identities = Vec::new();
+    let properties = std::fs::read_to_string(udev_data.join(format!("b{major}:{minor}")))
+        .map(|data| parse_udev_properties(&data))
+        .unwrap_or_default();
+    for (key, source) in [
+        ("ID_FS_UUID", "filesystem"),
+        ("ID_PART_TABLE_UUID", "partition table"),
+        ("ID_PART_ENTRY_UUID", "partition"),
+    ] {
+        if let Some(uuid) = properties.get(key).and_then(|uuid| uuid.parse().ok()) {
+            identities.push(DeviceIdentity { source, uuid });
+        }
+    }
+    // NVMe namespaces have the WWID directly, SCSI disks on their device.
+    let dev = sys.join(format!("dev/block/{major}:{minor}"));
+    if let Some(uuid) = [dev.join("wwid"), dev.join("device/wwid")]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .and_then(|wwid| parse_wwid(&wwid))
+    {
+        identities.push(DeviceIdentity {
+            source: "wwid",
+            uuid,
+        });
+    }
+    identities
+}
+
+/// Parse a WWID like `uuid.<uuid>`, `eui.<hex>` or `naa.<hex>` into a UUID, if it is 128 bits.
+fn parse_wwid(wwid: &str) -> Option<Uuid> {
+    let (_, id) = wwid.trim().split_once('.')?;
+    let hex: String = id.chars().filter(|c| *c != '-').collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    u128::from_str_radix(&hex, 16).ok().map(Uuid::from_u128)
+}
+
+/// Parse the `E:KEY=value` property lines of a udev database entry, skipping empty values.
+pub(super) fn parse_udev_properties(data: &str) -> BTreeMap<String, String> {
+    data.lines()
+        .filter_map(|line| line.strip_prefix("E:")?.split_once('='))
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Read `len` bytes at `offset`, or `None` if the device is too small or unreadable.
+fn read_at(file: &File, offset: u64, len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    file.read_exact_at(&mut buf, offset).ok()?;
+    Some(buf)
+}
+
+/// A label stored as NUL padded bytes.
+fn label(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let label = String::from_utf8_lossy(&bytes[..end]).trim().to_string();
+    (!label.is_empty()).then_some(label)
+}
+
+/// A filesystem recognized by its superblock, see `probe_superblock`.
+pub(super) struct Superblock {
+    pub(super) fs_type: &'static str,
+    pub(super) label: Option<String>,
+    pub(super) uuid: Option<Uuid>,
+}
+
+impl Superblock {
+    fn new(fs_type: &'static str, label: &[u8], uuid: &[u8]) -> Self {
+        Self {
+            fs_type,
+            label: self::label(label),
+            uuid: Uuid::from_slice(uuid).ok().filter(|uuid| !uuid.is_nil()),
+        }
+    }
+}
+
+/// Recognize the most common filesystems by their superblock, for devices udev doesn't know.
+pub(super) fn probe_superblock(file: &File) -> Option<Superblock> {
+    // ext2/3/4: the superblock starts at 1024.
+    if let Some(sb) = read_at(file, 1024, 136) {
+        if sb[56..58] == [0x53, 0xef] {
+            let compat = u32::from_le_bytes(sb[92..96].try_into().ok()?);
+            let incompat = u32::from_le_bytes(sb[96..100].try_into().ok()?);
+            // Extents, 64bit or flex_bg are only supported by ext4, a journal by ext3.
+            let fs_type = if incompat & 0x2c0 != 0 {
+                "ext4"
+            } else if compat & 0x4 != 0 {
+                "ext3"
+            } else {
+                "ext2"
+            };
+            return Some(Superblock::new(fs_type, &sb[120..136], &sb[104..120]));
+        }
+    }
+    if let Some(sb) = read_at(file, 0, 120) {
+        if &sb[0..4] == b"XFSB" {
+            return Some(Superblock::new("xfs", &sb[108..120], &sb[32..48]));
+        }
+    }
+    // btrfs: the superblock starts at 64 KiB.
+    if let Some(sb) = read_at(file, 0x10000, 0x22b) {
+        if &sb[0x40..0x48] == b"_BHRfS_M" {
+            return Some(Superblock::new("btrfs", &sb[0x12b..0x22b], &sb[0x20..0x30]));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+
+    /// An image with just enough of an ext4 superblock to be recognized.
+    fn ext4_image(label: &str, uuid: Uuid) -> Vec<u8> {
+        let mut image = vec![0u8; 4096];
+        image[1024 + 56..1024 + 58].copy_from_slice(&[0x53, 0xef]);
+        image[1024 + 96] = 0x40;
+        image[1024 + 104..1024 + 120].copy_from_slice(uuid.as_bytes());
+        image[1024 + 120..1024 + 120 + label.len()].copy_from_slice(label.as_bytes());
+        image
+    }
+
+    #[test]
+    fn test_dev_major_minor() {
+        assert_eq!(dev_major_minor(0x0700), (7, 0));
+        assert_eq!(dev_major_minor(0x0fd01), (253, 1));
+        // Large minors of device-mapper and NVMe devices.
+        assert_eq!(dev_major_minor(0x100_fd10), (253, 0x1010));
+    }
+
+    #[test]
+    fn test_device_size() {
+        let dir = TestDir::new("blockdev-size");
+        let path = dir.join("disk.img");
+        std::fs::write(&path, [0u8; 4096]).unwrap();
+        assert_eq!(device_size(&path), Some(4096));
+        assert_eq!(device_size(&dir), None);
+        assert_eq!(device_size(dir.join("missing")), None);
+    }
+
+    #[test]
+    fn test_device_cache() {
+        let dir = TestDir::new("blockdev-cache");
+        let path = dir.join("disk.img");
+        std::fs::write(&path, [0u8; 4096]).unwrap();
+        // Regular files are no block devices, but still have a size.
+        let devices = DeviceCache::default();
+        let err = devices.resolve(&path).unwrap_err();
+        assert!(matches!(err.root(), Error::InvalidDevice(_)), "{err:?}");
+        assert_eq!(devices.size(&path), Some(4096));
+        assert_eq!(devices.size(&dir.join("missing")), None);
+
+        let calls = std::cell::Cell::new(0);
+        let resolve = |path: &Path| {
+            calls.set(calls.get() + 1);
+            if calls.get() == 1 {
+                return Err(Error::InvalidDevice(path.display().to_string()));
+            }
+            Ok(ResolvedDevice {
+                canonical: "/dev/dm-0".into(),
+                rdev: 0xfd00,
+                size: Some(1 << 30),
+            })
+        };
+        let mpath = Path::new("/dev/mapper/mpatha");
+        // Failures are not cached, devices are.
+        assert!(devices.resolve_with(mpath, resolve).is_err());
+        let device = devices.resolve_with(mpath, resolve).unwrap();
+        assert_eq!(devices.resolve_with(mpath, resolve).unwrap(), device);
+        assert_eq!(devices.size(mpath), Some(1 << 30));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(10 << 30), "10.0 GiB");
+        assert_eq!(format_size(u64::MAX), "16.0 EiB");
+    }
+
+    #[test]
+    fn test_device_info() {
+        let dir = TestDir::new("blockdev-info");
+        let sys = dir.join("sys");
+        let udev_data = dir.join("udev");
+        std::fs::create_dir_all(sys.join("dev/block/253:1/dm")).unwrap();
+        std::fs::create_dir_all(&udev_data).unwrap();
+        std::fs::write(sys.join("dev/block/253:1/dm/name"), "vg0-data\n").unwrap();
+        std::fs::write(
+            udev_data.join("b253:1"),
+            "S:mapper/vg0-data\nE:ID_FS_TYPE=xfs\nE:ID_FS_LABEL=data\nE:ID_FS_UUID=\nG:systemd\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            device_info(&sys, &udev_data, 253, 1),
+            BlockDeviceInfo {
+                fs_type: Some("xfs".to_string()),
+                fs_label: Some("data".to_string()),
+                dm_name: Some("vg0-data".to_string()),
+            }
+        );
+        // Devices unknown to both are tolerated.
+        assert_eq!(
+            device_info(&sys, &udev_data, 7, 0),
+            BlockDeviceInfo::default()
+        );
+    }
+
+    #[test]
+    fn test_probe_superblock() {
+        let dir = TestDir::new("blockdev-superblock");
+        let path = dir.join("ext4.img");
+        std::fs::write(&path, ext4_image("backup", Uuid::from_u128(42))).unwrap();
+
+        let info = probe_block_device(&path);
+        assert_eq!(info.fs_type.as_deref(), Some("ext4"));
+        assert_eq!(info.fs_label.as_deref(), Some("backup"));
+        assert_eq!(info.dm_name, None);
+        let sb = probe_superblock(&File::open(&path).unwrap()).unwrap();
+        assert_eq!(sb.uuid, Some(Uuid::from_u128(42)));
+
+        // Unknown contents, short files and missing paths are left unset.
+        std::fs::write(&path, [0u8; 4096]).unwrap();
+        assert_eq!(probe_block_device(&path), BlockDeviceInfo::default());
+        std::fs::write(&path, b"short").unwrap();
+        assert_eq!(probe_block_device(&path), BlockDeviceInfo::default());
+        assert_eq!(
+            probe_block_device(dir.join("missing")),
+            BlockDeviceInfo::default()
+        );
+    }
+
+    #[test]
+    fn test_device_identities() {
+        let dir = TestDir::new("blockdev-identities");
+        let sys = dir.join("sys");
+        let udev_data = dir.join("udev");
+        std::fs::create_dir_all(sys.join("dev/block/259:0")).unwrap();
+        std::fs::create_dir_all(sys.join("dev/block/8:0/device")).unwrap();
+        std::fs::create_dir_all(&udev_data).unwrap();
+        std::fs::write(
+            udev_data.join("b259:0"),
+            "E:ID_FS_UUID=6f1c3e2a-51d4-4d5a-9c0e-0b8f1d2c3a4b\nE:ID_PART_TABLE_UUID=\n",
+        )
+        .unwrap();
+        std::fs::write(
+            sys.join("dev/block/259:0/wwid"),
+            "uuid.0f6a1b2c-3d4e-5f60-7182-93a4b5c6d7e8\n",
+        )
+        .unwrap();
+        std::fs::write(
+            sys.join("dev/block/8:0/device/wwid"),
+            "naa.600a098038303053453f463045727a4b\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            device_identities(&sys, &udev_data, 259, 0),
+            vec![
+                DeviceIdentity {
+                    source: "filesystem",
+                    uuid: "6f1c3e2a-51d4-4d5a-9c0e-0b8f1d2c3a4b".parse().unwrap(),
+                },
+                DeviceIdentity {
+                    source: "wwid",
+                    uuid: "0f6a1b2c-3d4e-5f60-7182-93a4b5c6d7e8".parse().unwrap(),
+                },
+            ]
+        );
+        assert_eq!(
+            device_identities(&sys, &udev_data, 8, 0),
+            vec![DeviceIdentity {
+                source: "wwid",
+                uuid: "600a0980-3830-3053-453f-463045727a4b".parse().unwrap(),
+            }]
+        );
+        assert!(device_identities(&sys, &udev_data, 7, 0).is_empty());
+        // WWIDs which are not 128 bits, like the 64-bit EUI of older disks, are no UUIDs.
+        assert_eq!(parse_wwid("eui.0025388b91b0e2a1"), None);
+        assert_eq!(parse_wwid("garbage"), None);
+    }
+
+    #[test]
+    fn test_match_device_identity() {
+        let expected = Uuid::from_u128(42);
+        let fs = DeviceIdentity {
+            source: "filesystem",
+            uuid: Uuid::from_u128(1),
+        };
+        let wwid = DeviceIdentity {
+            source: "wwid",
+            uuid: expected,
+        };
+        assert_eq!(match_device_identity(expected, &[]), IdentityMatch::Unknown);
+        assert_eq!(
+            match_device_identity(expected, std::slice::from_ref(&fs)),
+            IdentityMatch::Mismatch
+        );
+        assert_eq!(
+            match_device_identity(expected, &[fs, wwid.clone()]),
+            IdentityMatch::Match(wwid)
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use crate::errors::{Error, Result};
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder};
+use std::io::{BufRead, Cursor, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression of state files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The compression implied by the extension of `path`, `.gz` or `.zst`.
+    #[must_use]
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Self::Gzip,
+            Some("zst") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+
+    /// The compression of data starting with `magic`.
+    #[must_use]
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(GZIP_MAGIC) {
+            Self::Gzip
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "zstd" | "zst" => Ok(Self::Zstd),
+            _ => Err(Error::InvalidCompression(s.to_string())),
+        }
+    }
+}
+
+/// Wrap `reader` to decompress it, if its first bytes show it's compressed.
+///
+/// The data is decompressed as it is read, not all at once.
+pub fn decompress_reader<'a, R: BufRead + 'a>(mut reader: R) -> Result<Box<dyn Read + 'a>> {
+    // Both formats are recognizable by a few bytes, which may come in several reads, like from
+    // a pipe. They are read ahead and put back in front.
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    while magic.len() < ZSTD_MAGIC.len() {
+        let buf = match reader.fill_buf() {
+            Ok([]) => break,
+            Ok(buf) => buf,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let len = buf.len().min(ZSTD_MAGIC.len() - magic.len());
+        magic.extend_from_slice(&buf[..len]);
+        reader.consume(len);
+    }
+    let compression = Compression::detect(&magic);
+    let reader = Cursor::new(magic).chain(reader);
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => return Err(Error::UnsupportedCompression("zstd")),
+    })
+}
+
+/// A writer compressing what is written to it as it goes.
+///
+/// `finish` has to be called after writing everything, to write out the end of the data.
+pub enum CompressWriter<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressWriter<W> {
+    pub fn new(writer: W, compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::None(writer),
+            Compression::Gzip => Self::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Self::Zstd(zstd::Encoder::new(writer, 0)?),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => return Err(Error::UnsupportedCompression("zstd")),
+        })
+    }
+
+    /// Write out the end of the compressed data, returning the inner writer.
+    pub fn finish(self) -> Result<W> {
+        Ok(match self {
+            Self::None(writer) => writer,
+            Self::Gzip(encoder) => encoder.finish()?,
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => encoder.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::None(writer) => writer.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::None(writer) => writer.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = "subsystems: {}\nports: {}\n";
+
+    fn roundtrip(compression: Compression) -> Result<Vec<u8>> {
+        let mut writer = CompressWriter::new(Vec::new(), compression)?;
+        writer.write_all(YAML.as_bytes())?;
+        let compressed = writer.finish()?;
+        assert_eq!(Compression::detect(&compressed), compression);
+
+        let mut decompressed = String::new();
+        decompress_reader(compressed.as_slice())?.read_to_string(&mut decompressed)?;
+        assert_eq!(decompressed, YAML);
+
+        // Even if the magic comes a byte at a time.
+        let mut decompressed = String::new();
+        decompress_reader(std::io::BufReader::with_capacity(1, compressed.as_slice()))?
+            .read_to_string(&mut decompressed)?;
+        assert_eq!(decompressed, YAML);
+        Ok(compressed)
+    }
+
+    #[test]
+    fn test_gzip() -> Result<()> {
+        assert_eq!(roundtrip(Compression::None)?, YAML.as_bytes());
+        let mut short = String::new();
+        decompress_reader(b"a:".as_slice())?.read_to_string(&mut short)?;
+        assert_eq!(short, "a:");
+        roundtrip(Compression::Gzip)?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_zstd() -> Result<()> {
+        roundtrip(Compression::Zstd)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_names() -> Result<()> {
+        assert_eq!(Compression::from_path("state.yaml.gz"), Compression::Gzip);
+        assert_eq!(Compression::from_path("state.yaml.zst"), Compression::Zstd);
+        assert_eq!(Compression::from_path("state.yaml"), Compression::None);
+        assert_eq!("zstd".parse::<Compression>()?, Compression::Zstd);
+        assert!(matches!(
+            "lz4".parse::<Compression>(),
+            Err(Error::InvalidCompression(_))
+        ));
+        Ok(())
+    }
+}
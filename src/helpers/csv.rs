@@ -0,0 +1,69 @@
+use std::borrow::Cow;
+
+/// Builds CSV text as described in RFC 4180, starting with a header row.
+pub struct CsvWriter {
+    csv: String,
+}
+
+impl CsvWriter {
+    #[must_use]
+    pub fn new(header: &[&str]) -> Self {
+        let mut writer = Self { csv: String::new() };
+        writer.row(header);
+        writer
+    }
+
+    /// Append a row, quoting fields where needed.
+    pub fn row<S: AsRef<str>>(&mut self, fields: &[S]) {
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                self.csv.push(',');
+            }
+            self.csv.push_str(&csv_field(field.as_ref()));
+        }
+        self.csv.push('\n');
+    }
+
+    /// The CSV text, ending with a newline.
+    #[must_use]
+    pub fn finish(self) -> String {
+        self.csv
+    }
+}
+
+/// Quote a CSV field if it contains separators, quotes or line breaks.
+#[must_use]
+pub fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("plain value"), "plain value");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+    }
+
+    #[test]
+    fn test_csv_writer() {
+        let mut csv = CsvWriter::new(&["id", "name"]);
+        csv.row(&["1", "Dumb-O-Tron 2000, \"Pro\""]);
+        csv.row(&[String::from("2"), String::new()]);
+        assert_eq!(
+            csv.finish(),
+            "id,name\n1,\"Dumb-O-Tron 2000, \"\"Pro\"\"\"\n2,\n"
+        );
+        assert_eq!(CsvWriter::new(&["id"]).finish(), "id\n");
+    }
+}
@@ -0,0 +1,340 @@
+use super::blockdev::{parse_udev_properties, probe_superblock};
+use crate::errors::{Error, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A block device given by the filesystem on it, which stays the same when devices are renamed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeviceSpec {
+    /// `UUID=<uuid>`, the UUID of the filesystem.
+    Uuid(String),
+    /// `LABEL=<label>`, the label of the filesystem.
+    Label(String),
+}
+
+impl DeviceSpec {
+    /// Find the block device, like /dev/sdb1.
+    ///
+    /// The links in /dev/disk and the udev database are looked at first. Only if neither has it,
+    /// the superblocks of all block devices are read instead. Fails with `Error::NoSuchDevice`
+    /// if no device has the filesystem, or `Error::AmbiguousDevice` if several have.
+    pub fn resolve(&self) -> Result<PathBuf> {
+        self.resolve_in(
+            Path::new("/dev"),
+            Path::new("/sys"),
+            Path::new("/run/udev/data"),
+        )
+    }
+
+    fn resolve_in(&self, dev: &Path, sys: &Path, udev_data: &Path) -> Result<PathBuf> {
+        let devices = block_devices(sys);
+        let mut found = BTreeSet::new();
+        if let Ok(path) = dev.join("disk").join(self.link()).canonicalize() {
+            found.insert(path);
+        }
+        // The link is that of whichever device udev saw last, others may have the same.
+        for (name, major_minor) in &devices {
+            let properties = std::fs::read_to_string(udev_data.join(format!("b{major_minor}")))
+                .map(|data| parse_udev_properties(&data))
+                .unwrap_or_default();
+            let label = properties
+                .get("ID_FS_LABEL_ENC")
+                .map(|label| unescape_udev(label))
+                .or_else(|| properties.get("ID_FS_LABEL").cloned());
+            if self.matches(
+                properties.get("ID_FS_UUID").map(String::as_str),
+                label.as_deref(),
+            ) {
+                found.insert(device_node(dev, name));
+            }
+        }
+        if found.is_empty() {
+            tracing::debug!("No device with {self} known to udev, reading superblocks");
+            for (name, _) in &devices {
+                let path = device_node(dev, name);
+                let Some(sb) = File::open(&path).ok().and_then(|f| probe_superblock(&f)) else {
+                    continue;
+                };
+                let uuid = sb.uuid.map(|uuid| uuid.hyphenated().to_string());
+                if self.matches(uuid.as_deref(), sb.label.as_deref()) {
+                    found.insert(path);
+                }
+            }
+        }
+
+        let mut found = found.into_iter();
+        match (found.next(), found.next()) {
+            (None, _) => Err(Error::NoSuchDevice(self.to_string())),
+            (Some(path), None) => Ok(path),
+            (Some(first), Some(second)) => {
+                let paths: Vec<String> = [first, second]
+                    .into_iter()
+                    .chain(found)
+                    .map(|path| path.display().to_string())
+                    .collect();
+                Err(Error::AmbiguousDevice(self.to_string(), paths.join(", ")))
+            }
+        }
+    }
+
+    /// The path of its link below /dev/disk.
+    fn link(&self) -> String {
+        match self {
+            Self::Uuid(uuid) => format!("by-uuid/{}", escape_udev(uuid)),
+            Self::Label(label) => format!("by-label/{}", escape_udev(label)),
+        }
+    }
+
+    fn matches(&self, uuid: Option<&str>, label: Option<&str>) -> bool {
+        match self {
+            // blkid prints some UUIDs, like those of FAT, in upper case.
+            Self::Uuid(expected) => uuid.is_some_and(|uuid| uuid.eq_ignore_ascii_case(expected)),
+            Self::Label(expected) => label == Some(expected.as_str()),
+        }
+    }
+}
+
+impl FromStr for DeviceSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let spec = if let Some(uuid) = s.strip_prefix("UUID=") {
+            Self::Uuid(uuid.to_string())
+        } else if let Some(label) = s.strip_prefix("LABEL=") {
+            Self::Label(label.to_string())
+        } else {
+            return Err(Error::InvalidDeviceSpec(s.to_string()));
+        };
+        match &spec {
+            Self::Uuid(value) | Self::Label(value) if value.is_empty() => {
+                Err(Error::InvalidDeviceSpec(s.to_string()))
+            }
+            _ => Ok(spec),
+        }
+    }
+}
+
+impl fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Uuid(uuid) => write!(f, "UUID={uuid}"),
+            Self::Label(label) => write!(f, "LABEL={label}"),
+        }
+    }
+}
+
+impl Serialize for DeviceSpec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The kernel names of all block devices, with their `major:minor`.
+fn block_devices(sys: &Path) -> Vec<(String, String)> {
+    let Ok(entries) = std::fs::read_dir(sys.join("class/block")) else {
+        return Vec::new();
+    };
+    let mut devices: Vec<(String, String)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let dev = std::fs::read_to_string(entry.path().join("dev")).ok()?;
+            Some((name, dev.trim().to_string()))
+        })
+        .collect();
+    devices.sort();
+    devices
+}
+
+fn device_node(dev: &Path, name: &str) -> PathBuf {
+    let path = dev.join(name);
+    path.canonicalize().unwrap_or(path)
+}
+
+/// Escape a value for a link name like udev does, as `\x2f` for `/`.
+fn escape_udev(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() {
+            escaped.push(c);
+        } else {
+            escaped.push_str(&format!("\\x{:02x}", c as u32));
+        }
+    }
+    escaped
+}
+
+/// Undo the escaping of udev, as in `ID_FS_LABEL_ENC`.
+fn unescape_udev(value: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let decoded = (b == b'\\')
+            .then(|| tail.strip_prefix(b"x"))
+            .flatten()
+            .and_then(|hex| hex.get(..2))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        if let Some(decoded) = decoded {
+            bytes.push(decoded);
+            rest = &tail[3..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use uuid::Uuid;
+
+    const UUID: &str = "6f1c3e2a-51d4-4d5a-9c0e-0b8f1d2c3a4b";
+
+    struct Fixture {
+        dir: TestDir,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = TestDir::new(name);
+            for sub in [
+                "dev/disk/by-uuid",
+                "dev/disk/by-label",
+                "sys/class/block",
+                "udev",
+            ] {
+                std::fs::create_dir_all(dir.join(sub)).unwrap();
+            }
+            Self { dir }
+        }
+
+        /// Add the block device `name`, with its udev database entry if given.
+        fn device(&self, name: &str, minor: u32, udev: Option<&str>, contents: &[u8]) {
+            let class = self.dir.join("sys/class/block").join(name);
+            std::fs::create_dir_all(&class).unwrap();
+            std::fs::write(class.join("dev"), format!("7:{minor}\n")).unwrap();
+            std::fs::write(self.dir.join("dev").join(name), contents).unwrap();
+            if let Some(udev) = udev {
+                std::fs::write(self.dir.join(format!("udev/b7:{minor}")), udev).unwrap();
+            }
+        }
+
+        fn link(&self, link: &str, name: &str) {
+            std::os::unix::fs::symlink(
+                format!("../../{name}"),
+                self.dir.join("dev/disk").join(link),
+            )
+            .unwrap();
+        }
+
+        fn resolve(&self, spec: &str) -> Result<PathBuf> {
+            spec.parse::<DeviceSpec>()?.resolve_in(
+                &self.dir.join("dev"),
+                &self.dir.join("sys"),
+                &self.dir.join("udev"),
+            )
+        }
+
+        fn node(&self, name: &str) -> PathBuf {
+            self.dir.join("dev").join(name).canonicalize().unwrap()
+        }
+    }
+
+    /// An image with just enough of an ext4 superblock to be recognized.
+    fn ext4_image(label: &str, uuid: Uuid) -> Vec<u8> {
+        let mut image = vec![0u8; 4096];
+        image[1024 + 56..1024 + 58].copy_from_slice(&[0x53, 0xef]);
+        image[1024 + 96] = 0x40;
+        image[1024 + 104..1024 + 120].copy_from_slice(uuid.as_bytes());
+        image[1024 + 120..1024 + 120 + label.len()].copy_from_slice(label.as_bytes());
+        image
+    }
+
+    #[test]
+    fn test_parse_device_spec() {
+        assert_eq!(
+            "UUID=abcd-1234".parse::<DeviceSpec>().unwrap(),
+            DeviceSpec::Uuid("abcd-1234".to_string())
+        );
+        let label: DeviceSpec = "LABEL=tenant a".parse().unwrap();
+        assert_eq!(label, DeviceSpec::Label("tenant a".to_string()));
+        assert_eq!(label.to_string(), "LABEL=tenant a");
+        assert_eq!(label.link(), "by-label/tenant\\x20a");
+        for invalid in ["/dev/sda", "LABEL=", "uuid=abcd", "PARTUUID=abcd"] {
+            assert!(matches!(
+                invalid.parse::<DeviceSpec>(),
+                Err(Error::InvalidDeviceSpec(_))
+            ));
+        }
+        assert_eq!(unescape_udev("tenant\\x20a\\x2fb\\x"), "tenant a/b\\x");
+    }
+
+    #[test]
+    fn test_resolve_udev() -> Result<()> {
+        let fixture = Fixture::new("devspec-udev");
+        fixture.device(
+            "loop0",
+            0,
+            Some(&format!(
+                "E:ID_FS_UUID={UUID}\nE:ID_FS_LABEL=tenant_a\nE:ID_FS_LABEL_ENC=tenant\\x20a\n"
+            )),
+            b"",
+        );
+        fixture.device("loop1", 1, Some("E:ID_FS_LABEL=other\n"), b"");
+        fixture.link(&format!("by-uuid/{UUID}"), "loop0");
+        fixture.link("by-label/tenant\\x20a", "loop0");
+
+        assert_eq!(
+            fixture.resolve(&format!("UUID={UUID}"))?,
+            fixture.node("loop0")
+        );
+        let upper = format!("UUID={}", UUID.to_uppercase());
+        assert_eq!(fixture.resolve(&upper)?, fixture.node("loop0"));
+        assert_eq!(fixture.resolve("LABEL=tenant a")?, fixture.node("loop0"));
+        assert_eq!(fixture.resolve("LABEL=other")?, fixture.node("loop1"));
+
+        let err = fixture.resolve("LABEL=missing").unwrap_err();
+        assert!(matches!(err, Error::NoSuchDevice(ref spec) if spec == "LABEL=missing"));
+
+        // Two devices with the same label, like a cloned disk, only get one link.
+        fixture.device("loop2", 2, Some("E:ID_FS_LABEL=other\n"), b"");
+        let err = fixture.resolve("LABEL=other").unwrap_err();
+        assert!(matches!(err, Error::AmbiguousDevice(..)));
+        assert!(err.to_string().contains("loop1") && err.to_string().contains("loop2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_superblock() -> Result<()> {
+        let fixture = Fixture::new("devspec-superblock");
+        let uuid: Uuid = UUID.parse().unwrap();
+        fixture.device("loop0", 0, None, &ext4_image("tenant-a", uuid));
+        fixture.device("loop1", 1, None, &[0u8; 4096]);
+
+        assert_eq!(fixture.resolve("LABEL=tenant-a")?, fixture.node("loop0"));
+        assert_eq!(
+            fixture.resolve(&format!("UUID={UUID}"))?,
+            fixture.node("loop0")
+        );
+        assert!(matches!(
+            fixture.resolve("LABEL=tenant-b"),
+            Err(Error::NoSuchDevice(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use crate::errors::{Context, Result};
+use base64::Engine;
+use std::io::Read;
+use zeroize::Zeroizing;
+
+/// Hash used to transform a DH-HMAC-CHAP secret, which also determines its length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhchapHmac {
+    /// Use the secret as is.
+    None,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl DhchapHmac {
+    /// The hmac field of the key.
+    #[must_use]
+    pub const fn id(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Sha256 => 1,
+            Self::Sha384 => 2,
+            Self::Sha512 => 3,
+        }
+    }
+
+    /// Length of the secret in bytes.
+    #[must_use]
+    pub const fn secret_len(&self) -> usize {
+        match self {
+            Self::None | Self::Sha256 => 32,
+            Self::Sha384 => 48,
+            Self::Sha512 => 64,
+        }
+    }
+}
+
+/// Generate a random DH-HMAC-CHAP key in the `DHHC-1:<hmac>:<base64>:` format.
+///
+/// The payload is the secret followed by its CRC32 in little endian, like nvme-cli's
+/// gen-dhchap-key produces it.
+pub fn generate_dhchap_key(hmac: DhchapHmac) -> Result<Zeroizing<String>> {
+    let len = hmac.secret_len();
+    let mut payload = Zeroizing::new(vec![0u8; len + 4]);
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut payload[..len]))
+        .context("Failed to read random secret")?;
+    let crc = crc32fast::hash(&payload[..len]);
+    payload[len..].copy_from_slice(&crc.to_le_bytes());
+
+    let mut key = Zeroizing::new(format!("DHHC-1:{:02}:", hmac.id()));
+    base64::engine::general_purpose::STANDARD.encode_string(&payload[..], &mut key);
+    key.push(':');
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::assert_valid_dhchap_key;
+
+    #[test]
+    fn test_generate_dhchap_key() -> Result<()> {
+        for hmac in [
+            DhchapHmac::None,
+            DhchapHmac::Sha256,
+            DhchapHmac::Sha384,
+            DhchapHmac::Sha512,
+        ] {
+            let key = generate_dhchap_key(hmac)?;
+            assert!(key.starts_with(&format!("DHHC-1:0{}:", hmac.id())));
+            assert_valid_dhchap_key(&key)?;
+        }
+        // Secrets are random.
+        assert_ne!(
+            generate_dhchap_key(DhchapHmac::Sha256)?,
+            generate_dhchap_key(DhchapHmac::Sha256)?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+use crate::errors::{Error, Result};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// The SHA-256 digest of `data`, as lowercase hex.
+#[must_use]
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Check that `data` has the SHA-256 digest `expected`, given as hex in either case.
+pub fn verify_sha256(data: &[u8], expected: &str) -> Result<()> {
+    if expected.len() != 64 || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::InvalidDigest(expected.to_string()));
+    }
+    let actual = sha256_hex(data);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(Error::DigestMismatch(expected.to_lowercase(), actual));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn test_verify_sha256() {
+        assert_eq!(sha256_hex(b"abc"), ABC);
+        assert!(verify_sha256(b"abc", ABC).is_ok());
+        assert!(verify_sha256(b"abc", &ABC.to_uppercase()).is_ok());
+        assert!(matches!(
+            verify_sha256(b"abd", ABC),
+            Err(Error::DigestMismatch(..))
+        ));
+        assert!(matches!(
+            verify_sha256(b"abc", &ABC[1..]),
+            Err(Error::InvalidDigest(_))
+        ));
+        assert!(matches!(
+            verify_sha256(b"abc", &ABC.replace('a', "g")),
+            Err(Error::InvalidDigest(_))
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+use crate::errors::{Context, Result};
+use std::io::ErrorKind;
+use std::path::Path;
+
+static FC_HOST_CLASS: &str = "/sys/class/fc_host";
+
+/// A local Fibre Channel HBA port, see `local_fc_hosts`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FcHost {
+    /// Name of the SCSI host, like `host3`.
+    pub name: String,
+    /// World Wide Node Name.
+    pub node_name: u64,
+    /// World Wide Port Name.
+    pub port_name: u64,
+}
+
+/// The local Fibre Channel HBA ports, from /sys/class/fc_host, ordered by name.
+///
+/// Without any Fibre Channel driver loaded, there are none.
+pub fn local_fc_hosts() -> Result<Vec<FcHost>> {
+    local_fc_hosts_in(Path::new(FC_HOST_CLASS))
+}
+
+/// Like `local_fc_hosts`, reading the fc_host class directory `class`.
+///
+/// Hosts without valid node_name and port_name attributes are skipped.
+pub fn local_fc_hosts_in(class: &Path) -> Result<Vec<FcHost>> {
+    let entries = match std::fs::read_dir(class) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("Failed to list {}", class.display())),
+    };
+    let mut hosts = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to list {}", class.display()))?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let dir = entry.path();
+        let (Some(node_name), Some(port_name)) =
+            (read_wwn(&dir, "node_name"), read_wwn(&dir, "port_name"))
+        else {
+            continue;
+        };
+        hosts.push(FcHost {
+            name,
+            node_name,
+            port_name,
+        });
+    }
+    hosts.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(hosts)
+}
+
+// Names are written like 0x2000000055001123.
+fn read_wwn(dir: &Path, attr: &str) -> Option<u64> {
+    let value = std::fs::read_to_string(dir.join(attr)).ok()?;
+    let value = value.trim();
+    let hex = value.strip_prefix("0x").unwrap_or(value);
+    u64::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use std::fs;
+
+    fn fc_host(class: &Path, name: &str, node_name: &str, port_name: &str) {
+        let dir = class.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("node_name"), format!("{node_name}\n")).unwrap();
+        fs::write(dir.join("port_name"), format!("{port_name}\n")).unwrap();
+    }
+
+    #[test]
+    fn test_local_fc_hosts() -> Result<()> {
+        let dir = TestDir::new("fc-host");
+        let class = dir.join("fc_host");
+        assert!(local_fc_hosts_in(&class)?.is_empty());
+
+        fc_host(&class, "host3", "0x1000000044001123", "0x2000000055001123");
+        fc_host(&class, "host1", "0x1000000044001124", "0x2000000055001124");
+        fc_host(&class, "host2", "unknown", "0x2000000055001125");
+        fs::create_dir_all(class.join("host4")).unwrap();
+
+        assert_eq!(
+            local_fc_hosts_in(&class)?,
+            vec![
+                FcHost {
+                    name: "host1".to_string(),
+                    node_name: 0x1000_0000_4400_1124,
+                    port_name: 0x2000_0000_5500_1124,
+                },
+                FcHost {
+                    name: "host3".to_string(),
+                    node_name: 0x1000_0000_4400_1123,
+                    port_name: 0x2000_0000_5500_1123,
+                },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use crate::errors::{Context, Error, Result};
+use std::io::Read;
+use std::time::Duration;
+
+/// Larger responses are refused, no state file comes close.
+pub const MAX_FETCH_SIZE: u64 = 16 * 1024 * 1024;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether `source` is an http:// or https:// URL, rather than a path.
+#[must_use]
+pub fn is_url(source: &str) -> bool {
+    let lower = source.to_ascii_lowercase();
+    lower.starts_with("https://") || lower.starts_with("http://")
+}
+
+/// Fetch the body of `url` using GET.
+///
+/// Only https:// URLs are allowed, unless `insecure` allows http:// as well.
+/// Responses with a status other than success are errors.
+pub fn fetch_url(url: &str, insecure: bool) -> Result<Vec<u8>> {
+    check_scheme(url, insecure)?;
+    let response = send(url, ureq::get(url).timeout(FETCH_TIMEOUT).call())?;
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_FETCH_SIZE + 1)
+        .read_to_end(&mut data)
+        .with_context(|| format!("Failed to read response from {url}"))?;
+    if data.len() as u64 > MAX_FETCH_SIZE {
+        return Err(Error::ResponseTooLarge(url.to_string(), MAX_FETCH_SIZE));
+    }
+    Ok(data)
+}
+
+/// Send `body` to `url` as JSON using POST, ignoring the response.
+///
+/// Like `fetch_url`, only https:// URLs are allowed unless `insecure`, and responses with a
+/// status other than success are errors.
+pub fn post_json(url: &str, body: &[u8], insecure: bool) -> Result<()> {
+    check_scheme(url, insecure)?;
+    let request = ureq::post(url)
+        .timeout(FETCH_TIMEOUT)
+        .set("Content-Type", "application/json");
+    send(url, request.send_bytes(body))?;
+    Ok(())
+}
+
+fn check_scheme(url: &str, insecure: bool) -> Result<()> {
+    let lower = url.to_ascii_lowercase();
+    if lower.starts_with("https://") || insecure && lower.starts_with("http://") {
+        Ok(())
+    } else {
+        Err(Error::InsecureUrl(url.to_string()))
+    }
+}
+
+fn send(
+    url: &str,
+    result: std::result::Result<ureq::Response, ureq::Error>,
+) -> Result<ureq::Response> {
+    match result {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(status, response)) => Err(Error::HttpStatus(
+            url.to_string(),
+            status,
+            response.status_text().to_string(),
+        )),
+        Err(err) => Err(Error::Http(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    // Answer a single request with `response`, returning the URL to request.
+    fn serve_once(response: &'static str) -> String {
+        serve_once_capture(response).0
+    }
+
+    // Read a request up to the end of its body, which may come in a write of its own.
+    fn read_request(stream: &mut impl Read) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while let Ok(len @ 1..) = stream.read(&mut buf) {
+            request.extend_from_slice(&buf[..len]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse().ok())
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    break;
+                }
+            }
+        }
+        String::from_utf8_lossy(&request).into_owned()
+    }
+
+    // Like `serve_once`, also passing on the request as received.
+    fn serve_once_capture(response: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request(&mut stream);
+            stream.write_all(response.as_bytes()).unwrap();
+            let _ = tx.send(request);
+        });
+        (format!("http://{addr}/state.yaml"), rx)
+    }
+
+    #[test]
+    fn test_fetch_url() -> Result<()> {
+        let url = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Length: 12\r\nConnection: close\r\n\r\nsubsystems:\n",
+        );
+        assert_eq!(fetch_url(&url, true)?, b"subsystems:\n");
+
+        let url =
+            serve_once("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        let err = fetch_url(&url, true).unwrap_err();
+        assert!(matches!(err, Error::HttpStatus(_, 404, _)));
+        assert!(err.to_string().contains("404 Not Found"));
+
+        // Just over the limit is too much.
+        let body = "#".repeat(MAX_FETCH_SIZE as usize + 1);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        let url = serve_once(Box::leak(response.into_boxed_str()));
+        let err = fetch_url(&url, true).unwrap_err();
+        assert!(matches!(err, Error::ResponseTooLarge(_, MAX_FETCH_SIZE)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_insecure() {
+        // Refused before connecting anywhere.
+        let err = fetch_url("http://192.0.2.1/state.yaml", false).unwrap_err();
+        assert!(matches!(err, Error::InsecureUrl(_)));
+        let err = fetch_url("ftp://192.0.2.1/state.yaml", true).unwrap_err();
+        assert!(matches!(err, Error::InsecureUrl(_)));
+
+        assert!(is_url("HTTPS://example.com/state.yaml"));
+        assert!(!is_url("/etc/nvmetcfg/state.yaml"));
+    }
+
+    #[test]
+    fn test_post_json() -> Result<()> {
+        let (url, request) = serve_once_capture(
+            "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        post_json(&url, br#"{"drift":true}"#, true)?;
+        let request = request.recv().unwrap();
+        assert!(request.starts_with("POST /state.yaml "), "{request}");
+        assert!(
+            request.contains("Content-Type: application/json"),
+            "{request}"
+        );
+        assert!(request.ends_with(r#"{"drift":true}"#), "{request}");
+
+        let url = serve_once(
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        let err = post_json(&url, b"{}", true).unwrap_err();
+        assert!(matches!(err, Error::HttpStatus(_, 500, _)));
+        let err = post_json("http://192.0.2.1/hook", b"{}", false).unwrap_err();
+        assert!(matches!(err, Error::InsecureUrl(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use crate::errors::{Context, Result};
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+/// Default permissions of written state files, which may contain keys.
+pub const STATE_FILE_MODE: u32 = 0o600;
+
+/// Atomically replace the file at `path` with `contents`, giving it the permissions `mode`.
+///
+/// The contents are written to a temporary file in the same directory, synced and then renamed
+/// over `path`, so readers see either the old or the new file, never a partial one.
+pub fn write_file_atomic<P: AsRef<Path>>(path: P, mode: u32, contents: &[u8]) -> Result<()> {
+    write_file_atomic_with(path, mode, |file| Ok(file.write_all(contents)?))
+}
+
+/// Atomically replace the file at `path` like `write_file_atomic`, with the contents written
+/// by `write` instead of all at once.
+pub fn write_file_atomic_with<P, F>(path: P, mode: u32, write: F) -> Result<()>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut File) -> Result<()>,
+{
+    let path = path.as_ref();
+    let name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Not a file path: {}", path.display()),
+        )
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let tmp = dir.join(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let replace = || -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&tmp)
+            .with_context(|| format!("Failed to create {}", tmp.display()))?;
+        // The umask may have taken away bits from the mode.
+        file.set_permissions(Permissions::from_mode(mode))?;
+        write(&mut file)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    };
+    replace().inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })
+}
+
+/// Whether users other than the owner can read the file at `path`.
+pub fn is_readable_by_others<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    Ok(mode & 0o044 != 0)
+}
+
+/// Whether `text` contains DH-HMAC-CHAP keys.
+#[must_use]
+pub fn contains_key_material(text: &str) -> bool {
+    text.contains("DHHC-1:")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+
+    #[test]
+    fn test_write_file_atomic_mode() -> Result<()> {
+        let dir = TestDir::new("atomic-mode");
+        let path = dir.join("state.yaml");
+        write_file_atomic(&path, STATE_FILE_MODE, b"secret")?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!is_readable_by_others(&path)?);
+
+        write_file_atomic(&path, 0o644, b"public")?;
+        assert!(is_readable_by_others(&path)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_file_atomic_replace() -> Result<()> {
+        let dir = TestDir::new("atomic-replace");
+        let path = dir.join("state.yaml");
+        std::fs::write(&path, "old contents, which are longer")?;
+        std::fs::set_permissions(&path, Permissions::from_mode(0o666))?;
+        // Keep the old file open, like a concurrent reader would.
+        let old = File::open(&path)?;
+
+        write_file_atomic(&path, STATE_FILE_MODE, b"new")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "new");
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o600
+        );
+        // The reader still sees the complete old file.
+        assert_eq!(
+            std::io::read_to_string(old)?,
+            "old contents, which are longer"
+        );
+        // No temporary files are left behind.
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_contains_key_material() {
+        assert!(contains_key_material(
+            "dhchap_key: DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:"
+        ));
+        assert!(!contains_key_material("subsystems: {}"));
+    }
+}
@@ -10,10 +10,9 @@ pub fn read_str<P: AsRef<Path>>(path: P) -> Result<String> {
     Ok(contents.trim().to_string())
 }
 
-pub fn write_str<P: AsRef<Path>, D: std::fmt::Display>(path: P, data: D) -> Result<()> {
+pub fn write_str<P: AsRef<Path>>(path: P, data: &str) -> Result<()> {
     let mut file = File::create(path)?;
     // Unfortunately, we need to write in a single write call.
-    let value = format!("{data}");
-    file.write_all(value.as_bytes())?;
+    file.write_all(data.as_bytes())?;
     Ok(())
 }
@@ -0,0 +1,20 @@
+use crate::errors::Result;
+use serde::Serialize;
+use std::io::Write;
+
+/// Write each of `values` as compact JSON on a line of its own, flushing after every line.
+///
+/// Unlike a JSON array, each line can be consumed as soon as it's written.
+pub fn write_ndjson<W, T, I>(writer: &mut W, values: I) -> Result<()>
+where
+    W: Write,
+    T: Serialize,
+    I: IntoIterator<Item = T>,
+{
+    for value in values {
+        serde_json::to_writer(&mut *writer, &value)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+    Ok(())
+}
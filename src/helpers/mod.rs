@@ -1,7 +1,45 @@
+mod blockdev;
+mod compress;
+mod csv;
+mod devspec;
+mod dhchap;
+mod digest;
+mod fchost;
+#[cfg(feature = "http")]
+mod fetch;
+mod file;
 mod hash_differences;
 mod io;
+mod json;
+mod netif;
+mod notify;
+mod nvme;
+mod privileges;
+mod secret;
+mod sockets;
+#[cfg(test)]
+mod test_dir;
 mod validation;
 
+pub use blockdev::*;
+pub use compress::*;
+pub use csv::*;
+pub use devspec::*;
+pub use dhchap::*;
+pub use digest::*;
+pub use fchost::*;
+#[cfg(feature = "http")]
+pub use fetch::*;
+pub use file::*;
 pub use hash_differences::*;
 pub(crate) use io::*;
+pub use json::*;
+pub use netif::*;
+pub use notify::*;
+pub use nvme::*;
+pub use privileges::*;
+pub use secret::*;
+pub use sockets::*;
+#[cfg(test)]
+pub use test_dir::*;
 pub use validation::*;
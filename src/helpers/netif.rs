@@ -0,0 +1,85 @@
+use crate::errors::{Context, Error, Result};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+/// The addresses currently assigned to the network interface `name`.
+///
+/// Fails with `Error::NoSuchInterface` if there is no such interface.
+pub fn interface_addresses(name: &str) -> Result<Vec<IpAddr>> {
+    if name.is_empty() || name.contains('/') || !Path::new("/sys/class/net").join(name).exists() {
+        return Err(Error::NoSuchInterface(name.to_string()));
+    }
+    let addrs = if_addrs::get_if_addrs()
+        .context("Failed to list network interface addresses")?
+        .into_iter()
+        .filter(|iface| iface.name == name)
+        .map(|iface| iface.ip())
+        .collect();
+    Ok(addrs)
+}
+
+/// The addresses the host name `host` resolves to, with `port`, in the order the resolver
+/// prefers them.
+pub fn resolve_host(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let addrs = (host, port).to_socket_addrs().map_err(|err| {
+        tracing::debug!("Resolving {host} failed: {err}");
+        Error::UnresolvableHost(host.to_string())
+    })?;
+    Ok(addrs.collect())
+}
+
+/// Whether `addr` is reachable from other hosts, so neither loopback nor link-local.
+#[must_use]
+pub fn is_global_address(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => !(v4.is_unspecified() || v4.is_loopback() || v4.is_link_local()),
+        IpAddr::V6(v6) => {
+            !(v6.is_unspecified() || v6.is_loopback() || (v6.segments()[0] & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Pick the first global address out of `addrs`, of the preferred family if there is one.
+#[must_use]
+pub fn select_global_address(addrs: &[IpAddr], prefer_ipv6: bool) -> Option<IpAddr> {
+    let global = || addrs.iter().filter(|addr| is_global_address(addr));
+    global()
+        .find(|addr| addr.is_ipv6() == prefer_ipv6)
+        .or_else(|| global().next())
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_global_address() {
+        let addrs: Vec<IpAddr> = [
+            "127.0.0.1",
+            "fe80::1",
+            "169.254.0.1",
+            "192.0.2.1",
+            "2001:db8::1",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        assert_eq!(
+            select_global_address(&addrs, false),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            select_global_address(&addrs, true),
+            Some("2001:db8::1".parse().unwrap())
+        );
+
+        // Falls back to the other family.
+        assert_eq!(
+            select_global_address(&addrs[..4], true),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(select_global_address(&addrs[..3], false), None);
+        assert_eq!(select_global_address(&[], true), None);
+    }
+}
@@ -0,0 +1,173 @@
+// Handing notifications, like drift reports, to a command or a webhook.
+
+#[cfg(feature = "http")]
+use super::fetch::post_json;
+use crate::errors::{Context, Error, Result};
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Where to deliver a notification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyTarget {
+    /// A command run by `sh -c`, getting the payload on stdin.
+    Command(String),
+    /// A URL the payload is POSTed to as JSON. Only https:// unless `insecure`.
+    #[cfg(feature = "http")]
+    Webhook { url: String, insecure: bool },
+}
+
+impl NotifyTarget {
+    /// Deliver `payload` once.
+    pub fn deliver(&self, payload: &[u8]) -> Result<()> {
+        match self {
+            Self::Command(command) => run_command(command, payload),
+            #[cfg(feature = "http")]
+            Self::Webhook { url, insecure } => post_json(url, payload, *insecure),
+        }
+    }
+
+    /// Deliver `payload`, trying up to `attempts` times before returning the last error.
+    ///
+    /// The wait between attempts starts at `delay` and doubles after each.
+    pub fn deliver_with_retry(&self, payload: &[u8], attempts: u32, delay: Duration) -> Result<()> {
+        let mut delay = delay;
+        for _ in 1..attempts {
+            if self.deliver(payload).is_ok() {
+                return Ok(());
+            }
+            std::thread::sleep(delay);
+            delay = delay.saturating_mul(2);
+        }
+        self.deliver(payload)
+    }
+}
+
+impl fmt::Display for NotifyTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Command(command) => write!(f, "command {command}"),
+            #[cfg(feature = "http")]
+            Self::Webhook { url, .. } => write!(f, "webhook {url}"),
+        }
+    }
+}
+
+fn run_command(command: &str, payload: &[u8]) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run notification command {command}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // Commands are free to not read the payload.
+        match stdin.write_all(payload) {
+            Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err)
+                    .with_context(|| format!("Failed to pass notification to command {command}"));
+            }
+            _ => {}
+        }
+    }
+    let status = child
+        .wait()
+        .with_context(|| format!("Failed to wait for notification command {command}"))?;
+    if !status.success() {
+        return Err(Error::NotifyCommandFailed(
+            command.to_string(),
+            status.to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Lets through at most one notification per interval, so flapping doesn't flood anyone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl RateLimit {
+    #[must_use]
+    pub const fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+        }
+    }
+
+    /// Whether a notification may be sent at `now`, counting it as sent if so.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if self
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) < self.interval)
+        {
+            return false;
+        }
+        self.last = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+
+    #[test]
+    fn test_deliver_command() -> Result<()> {
+        let dir = TestDir::new("notify-payload");
+        let out = dir.join("payload");
+        let target = NotifyTarget::Command(format!("cat > {}", out.display()));
+        target.deliver(br#"{"changes":[]}"#)?;
+        assert_eq!(std::fs::read_to_string(&out)?, r#"{"changes":[]}"#);
+
+        // Not reading the payload is fine, failing is not.
+        NotifyTarget::Command("true".to_string()).deliver(&[b'x'; 1 << 20])?;
+        let err = NotifyTarget::Command("exit 3".to_string())
+            .deliver(b"{}")
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::NotifyCommandFailed(ref command, ref status)
+                if command == "exit 3" && status.contains('3')),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_deliver_with_retry() -> Result<()> {
+        // Fails until it has been run three times.
+        let dir = TestDir::new("notify-attempts");
+        let count = dir.join("attempts");
+        let command = format!("echo >> {0}; test $(wc -l < {0}) -ge 3", count.display());
+        let target = NotifyTarget::Command(command);
+        let err = target
+            .deliver_with_retry(b"{}", 2, Duration::ZERO)
+            .unwrap_err();
+        assert!(matches!(err, Error::NotifyCommandFailed(..)));
+        target.deliver_with_retry(b"{}", 2, Duration::ZERO)?;
+        let attempts = std::fs::read_to_string(&count)?.lines().count();
+        assert_eq!(attempts, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limit = RateLimit::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(limit.allow(start));
+        assert!(!limit.allow(start + Duration::from_secs(59)));
+        assert!(limit.allow(start + Duration::from_secs(60)));
+        assert!(!limit.allow(start + Duration::from_secs(61)));
+
+        let mut unlimited = RateLimit::new(Duration::ZERO);
+        assert!(unlimited.allow(start));
+        assert!(unlimited.allow(start));
+    }
+}
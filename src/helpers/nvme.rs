@@ -0,0 +1,330 @@
+// Reading the identity of NVMe namespaces from the drive itself.
+//
+// Admin commands are passed through the namespace's block device with NVME_IOCTL_ADMIN_CMD.
+// Only Identify is ever sent, which changes nothing on the drive.
+
+use super::blockdev::dev_major_minor;
+use crate::errors::{Context, Error, Result};
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use uuid::Uuid;
+
+// How ioctl numbers are encoded, see asm-generic/ioctl.h. A few architectures use fewer bits
+// for the size and more for the direction, with other values for it.
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+)))]
+mod ioc {
+    pub const SIZEBITS: u32 = 14;
+    pub const NONE: u32 = 0;
+    pub const WRITE: u32 = 1;
+    pub const READ: u32 = 2;
+}
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+))]
+mod ioc {
+    pub const SIZEBITS: u32 = 13;
+    pub const NONE: u32 = 1;
+    pub const WRITE: u32 = 4;
+    pub const READ: u32 = 2;
+}
+
+/// The ioctl number _IOC(dir, ty, nr, size) would give.
+const fn ioc(dir: u32, ty: u8, nr: u8, size: usize) -> u32 {
+    (dir << (16 + ioc::SIZEBITS)) | ((size as u32) << 16) | ((ty as u32) << 8) | nr as u32
+}
+
+// _IO('N', 0x40) and _IOWR('N', 0x41, struct nvme_passthru_cmd) from linux/nvme_ioctl.h.
+const NVME_IOCTL_ID: u32 = ioc(ioc::NONE, b'N', 0x40, 0);
+const NVME_IOCTL_ADMIN_CMD: u32 = ioc(
+    ioc::READ | ioc::WRITE,
+    b'N',
+    0x41,
+    std::mem::size_of::<NvmePassthruCmd>(),
+);
+
+const NVME_ADMIN_IDENTIFY: u8 = 0x06;
+// Controller or Namespace Structure of the Namespace Identification Descriptor list.
+const NVME_ID_CNS_NS_DESC_LIST: u32 = 0x03;
+const NVME_IDENTIFY_DATA_SIZE: usize = 4096;
+
+// Namespace Identifier Types of the descriptors.
+const NIDT_EUI64: u8 = 0x1;
+const NIDT_NGUID: u8 = 0x2;
+const NIDT_UUID: u8 = 0x3;
+
+/// struct nvme_passthru_cmd of linux/nvme_ioctl.h.
+#[repr(C)]
+#[derive(Default)]
+struct NvmePassthruCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+/// The identifiers an NVMe namespace reports, see `identify_nvme_namespace`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NvmeIdentity {
+    /// IEEE Extended Unique Identifier. nvmet has no attribute for it.
+    pub eui64: Option<u64>,
+    /// Namespace Globally Unique Identifier.
+    pub nguid: Option<Uuid>,
+    pub uuid: Option<Uuid>,
+}
+
+/// Parse a Namespace Identification Descriptor list, as returned by Identify with CNS 03h.
+///
+/// The list ends at the first descriptor of type 0 or the end of the data. Descriptors of
+/// other types, like the Command Set Identifier, are skipped.
+pub fn parse_ns_descriptors(data: &[u8]) -> Result<NvmeIdentity> {
+    let mut identity = NvmeIdentity::default();
+    let mut rest = data;
+    while let [nidt, nidl, _, _, tail @ ..] = rest {
+        if *nidt == 0 {
+            break;
+        }
+        let len = usize::from(*nidl);
+        let nid = tail
+            .get(..len)
+            .ok_or(Error::InvalidNvmeDescriptor(*nidt, *nidl))?;
+        match (*nidt, len) {
+            (NIDT_EUI64, 8) => {
+                identity.eui64 = Some(u64::from_be_bytes(nid.try_into().unwrap()));
+            }
+            (NIDT_NGUID, 16) => identity.nguid = Some(Uuid::from_slice(nid).unwrap()),
+            (NIDT_UUID, 16) => identity.uuid = Some(Uuid::from_slice(nid).unwrap()),
+            (NIDT_EUI64 | NIDT_NGUID | NIDT_UUID, _) => {
+                return Err(Error::InvalidNvmeDescriptor(*nidt, *nidl));
+            }
+            _ => {}
+        }
+        rest = &tail[len..];
+    }
+    Ok(identity)
+}
+
+/// Ask the NVMe namespace at `path`, like `/dev/nvme0n1`, for its identifiers.
+///
+/// Fails with `Error::NotNvmeNamespace` for anything but the block device of a whole NVMe
+/// namespace, as partitions would report the identity of the namespace they are on.
+pub fn identify_nvme_namespace<P: AsRef<Path>>(path: P) -> Result<NvmeIdentity> {
+    identify_nvme_namespace_in(Path::new("/sys"), path.as_ref())
+}
+
+fn identify_nvme_namespace_in(sys: &Path, path: &Path) -> Result<NvmeIdentity> {
+    let not_nvme = || Error::NotNvmeNamespace(path.display().to_string());
+    let meta = std::fs::metadata(path)
+        .with_context(|| format!("Failed to get metadata for device {}", path.display()))?;
+    if !meta.file_type().is_block_device() {
+        return Err(not_nvme());
+    }
+    let (major, minor) = dev_major_minor(meta.rdev());
+    if sys
+        .join(format!("dev/block/{major}:{minor}/partition"))
+        .exists()
+    {
+        return Err(not_nvme());
+    }
+    let device = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+    // Anything but NVMe namespaces doesn't know the ioctl.
+    // SAFETY: NVME_IOCTL_ID takes no argument.
+    let nsid = unsafe { libc::ioctl(device.as_raw_fd(), NVME_IOCTL_ID as libc::Ioctl) };
+    let Ok(nsid) = u32::try_from(nsid) else {
+        return Err(not_nvme());
+    };
+
+    let mut data = vec![0u8; NVME_IDENTIFY_DATA_SIZE];
+    let mut cmd = NvmePassthruCmd {
+        opcode: NVME_ADMIN_IDENTIFY,
+        nsid,
+        addr: data.as_mut_ptr() as u64,
+        data_len: NVME_IDENTIFY_DATA_SIZE as u32,
+        cdw10: NVME_ID_CNS_NS_DESC_LIST,
+        ..Default::default()
+    };
+    // SAFETY: cmd is a struct nvme_passthru_cmd, with addr pointing to data_len bytes.
+    let status = unsafe {
+        libc::ioctl(
+            device.as_raw_fd(),
+            NVME_IOCTL_ADMIN_CMD as libc::Ioctl,
+            &mut cmd as *mut NvmePassthruCmd,
+        )
+    };
+    match status {
+        0 => {}
+        status if status < 0 => {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to identify NVMe namespace {}", path.display()))
+        }
+        // Positive values are the status of the NVMe command.
+        status => return Err(Error::NvmeCommandFailed(path.display().to_string(), status)),
+    }
+
+    identity_from_descriptors(path, &data)
+}
+
+// Only the UUID and NGUID can be passed on by nvmet, so without either there is nothing.
+fn identity_from_descriptors(path: &Path, data: &[u8]) -> Result<NvmeIdentity> {
+    let identity = parse_ns_descriptors(data)
+        .with_context(|| format!("Failed to identify NVMe namespace {}", path.display()))?;
+    if identity.uuid.is_none() && identity.nguid.is_none() {
+        return Err(Error::NoNvmeIdentity(path.display().to_string()));
+    }
+    Ok(identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "8f2c3a41-6b2e-4d8a-9c1f-0e7d5b4a3c21";
+    const NGUID: &str = "0025385b-71b0-4a3c-8ee0-00000000beef";
+
+    fn descriptor(nidt: u8, nid: &[u8]) -> Vec<u8> {
+        let mut desc = vec![nidt, nid.len() as u8, 0, 0];
+        desc.extend_from_slice(nid);
+        desc
+    }
+
+    // As drives return it, padded with zeroes to the full size.
+    fn payload(descriptors: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = descriptors.concat();
+        data.resize(NVME_IDENTIFY_DATA_SIZE, 0);
+        data
+    }
+
+    #[test]
+    fn test_parse_ns_descriptors() -> Result<()> {
+        let uuid: Uuid = UUID.parse()?;
+        let nguid: Uuid = NGUID.parse()?;
+        let data = payload(&[
+            descriptor(NIDT_EUI64, &0x0025_385b_71b0_4a3c_u64.to_be_bytes()),
+            descriptor(NIDT_NGUID, nguid.as_bytes()),
+            descriptor(NIDT_UUID, uuid.as_bytes()),
+            // Command Set Identifier, NVM.
+            descriptor(0x4, &[0]),
+        ]);
+        assert_eq!(
+            parse_ns_descriptors(&data)?,
+            NvmeIdentity {
+                eui64: Some(0x0025_385b_71b0_4a3c),
+                nguid: Some(nguid),
+                uuid: Some(uuid),
+            }
+        );
+
+        // Many drives only have an NGUID.
+        let data = payload(&[descriptor(NIDT_NGUID, nguid.as_bytes())]);
+        let identity = parse_ns_descriptors(&data)?;
+        assert_eq!(identity.nguid, Some(nguid));
+        assert_eq!(identity.uuid, None);
+
+        assert_eq!(
+            parse_ns_descriptors(&payload(&[]))?,
+            NvmeIdentity::default()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ns_descriptors_invalid() {
+        let nguid = [0xab; 16];
+        // Wrong length for the type.
+        let data = payload(&[descriptor(NIDT_UUID, &nguid[..8])]);
+        assert!(matches!(
+            parse_ns_descriptors(&data),
+            Err(Error::InvalidNvmeDescriptor(NIDT_UUID, 8))
+        ));
+        // Cut off by the end of the data.
+        let data = descriptor(NIDT_NGUID, &nguid);
+        assert!(matches!(
+            parse_ns_descriptors(&data[..10]),
+            Err(Error::InvalidNvmeDescriptor(NIDT_NGUID, 16))
+        ));
+        // A truncated header ends the list like a zero type.
+        assert_eq!(
+            parse_ns_descriptors(&[NIDT_NGUID, 16]).unwrap(),
+            NvmeIdentity::default()
+        );
+    }
+
+    #[test]
+    fn test_identity_from_descriptors() -> Result<()> {
+        let path = Path::new("/dev/nvme0n1");
+        let eui64 = descriptor(
+            NIDT_EUI64,
+            &[0x00, 0x25, 0x38, 0x5b, 0x71, 0xb0, 0x4a, 0x3c],
+        );
+        for data in [payload(&[]), payload(&[eui64])] {
+            let err = identity_from_descriptors(path, &data).unwrap_err();
+            assert!(
+                matches!(err, Error::NoNvmeIdentity(ref dev) if dev == "/dev/nvme0n1"),
+                "{err:?}"
+            );
+        }
+        let uuid: Uuid = UUID.parse()?;
+        let data = payload(&[descriptor(NIDT_UUID, uuid.as_bytes())]);
+        assert_eq!(identity_from_descriptors(path, &data)?.uuid, Some(uuid));
+        Ok(())
+    }
+
+    #[test]
+    fn test_identify_not_nvme() {
+        // Neither a block device nor one that exists.
+        for path in ["/dev/null", "/nonexistent/nvme0n1"] {
+            let err = identify_nvme_namespace(path).unwrap_err();
+            assert_eq!(
+                matches!(err, Error::NotNvmeNamespace(_)),
+                path == "/dev/null",
+                "{err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_passthru_cmd_layout() {
+        // The size is part of the ioctl number.
+        assert_eq!(std::mem::size_of::<NvmePassthruCmd>(), 72);
+        assert_eq!(
+            (NVME_IOCTL_ADMIN_CMD >> 16) & ((1 << ioc::SIZEBITS) - 1),
+            72
+        );
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn test_ioctl_numbers() {
+        // As linux/nvme_ioctl.h gives them there.
+        assert_eq!(NVME_IOCTL_ID, 0x4e40);
+        assert_eq!(NVME_IOCTL_ADMIN_CMD, 0xc048_4e41);
+    }
+}
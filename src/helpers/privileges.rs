@@ -0,0 +1,75 @@
+use std::os::unix::fs::MetadataExt;
+
+static PROC_SELF_STATUS: &str = "/proc/self/status";
+
+// Bit of CAP_SYS_ADMIN in the capability sets, see capabilities(7).
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// The effective user ID of this process, which owns its /proc/self.
+///
+/// `None` if /proc is not mounted.
+#[must_use]
+pub fn effective_uid() -> Option<u32> {
+    std::fs::metadata("/proc/self").ok().map(|meta| meta.uid())
+}
+
+/// Whether the effective capabilities in a /proc/self/status include CAP_SYS_ADMIN.
+///
+/// `None` if they are missing or can't be parsed.
+#[must_use]
+pub fn has_cap_sys_admin(status: &str) -> Option<bool> {
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    let caps = u64::from_str_radix(caps.trim(), 16).ok()?;
+    Some(caps & (1 << CAP_SYS_ADMIN) != 0)
+}
+
+/// Whether this process may configure nvmet, having CAP_SYS_ADMIN.
+///
+/// Root counts only if its capabilities can't be told, as it may have dropped them, like in a
+/// container. If neither can be told, like without /proc, this assumes it may and leaves refusing
+/// to the kernel.
+#[must_use]
+pub fn has_nvmet_privileges() -> bool {
+    std::fs::read_to_string(PROC_SELF_STATUS)
+        .ok()
+        .and_then(|status| has_cap_sys_admin(&status))
+        .unwrap_or_else(|| effective_uid().map_or(true, |uid| uid == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Abbreviated, the other lines don't matter.
+    const STATUS: &str = "Name:\tnvmet
+Uid:\t1000\t1000\t1000\t1000
+CapInh:\t0000000000000000
+CapPrm:\t0000000000000000
+CapEff:\t0000000000000000
+CapBnd:\t000001ffffffffff
+";
+
+    #[test]
+    fn test_has_cap_sys_admin() {
+        assert_eq!(has_cap_sys_admin(STATUS), Some(false));
+        let root = STATUS.replace("CapEff:\t0000000000000000", "CapEff:\t000001ffffffffff");
+        assert_eq!(has_cap_sys_admin(&root), Some(true));
+        let sys_admin = STATUS.replace("CapEff:\t0000000000000000", "CapEff:\t0000000000200000");
+        assert_eq!(has_cap_sys_admin(&sys_admin), Some(true));
+        let other = STATUS.replace("CapEff:\t0000000000000000", "CapEff:\t0000000000100000");
+        assert_eq!(has_cap_sys_admin(&other), Some(false));
+        assert_eq!(has_cap_sys_admin("Name:\tnvmet\n"), None);
+        assert_eq!(has_cap_sys_admin("CapEff:\tgarbage\n"), None);
+    }
+
+    #[test]
+    fn test_has_nvmet_privileges() {
+        // Whoever runs the tests, even root, has them exactly with CAP_SYS_ADMIN.
+        let status = std::fs::read_to_string(PROC_SELF_STATUS).unwrap();
+        if let Some(cap) = has_cap_sys_admin(&status) {
+            assert_eq!(has_nvmet_privileges(), cap);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use crate::errors::{Context, Error, Result};
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Longest secret read from a file, stdin or the environment, in bytes.
+///
+/// DH-HMAC-CHAP keys are at most a few hundred bytes, this leaves plenty of room.
+pub const MAX_SECRET_LEN: usize = 4096;
+
+/// Where to read a secret, such as a DH-HMAC-CHAP key, from.
+#[derive(Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    /// The secret itself, as given on the command line.
+    Value(Zeroizing<String>),
+    /// A file containing the secret.
+    File(PathBuf),
+    /// Standard input.
+    Stdin,
+    /// An environment variable containing the secret.
+    Env(String),
+}
+
+// Written by hand, so the secret never ends up in logs.
+impl fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Value(_) => f.write_str("Value(<redacted>)"),
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Stdin => f.write_str("Stdin"),
+            Self::Env(var) => f.debug_tuple("Env").field(var).finish(),
+        }
+    }
+}
+
+impl SecretSource {
+    /// Read the secret, trimming a single trailing newline and rejecting empty secrets.
+    ///
+    /// The returned buffer is zeroed when dropped.
+    pub fn read(self) -> Result<Zeroizing<String>> {
+        match self {
+            Self::Value(value) => check_secret(value),
+            Self::File(path) => {
+                let file = std::fs::File::open(&path)
+                    .with_context(|| format!("Failed to open secret file {}", path.display()))?;
+                read_secret(file)
+                    .with_context(|| format!("Failed to read secret file {}", path.display()))
+            }
+            Self::Stdin => {
+                read_secret(std::io::stdin().lock()).context("Failed to read secret from stdin")
+            }
+            Self::Env(var) => {
+                let value = std::env::var_os(&var).ok_or(Error::MissingSecretEnv(var))?;
+                let value = Zeroizing::new(value.into_encoded_bytes());
+                read_secret(value.as_slice())
+            }
+        }
+    }
+}
+
+/// Read a secret from a reader, trimming a single trailing newline and rejecting empty secrets.
+///
+/// The secret is read into a buffer of [`MAX_SECRET_LEN`] bytes allocated up front, so no copies
+/// of it are left behind by growing it. Longer secrets are rejected.
+pub fn read_secret<R: Read>(mut reader: R) -> Result<Zeroizing<String>> {
+    let mut buf = Zeroizing::new(vec![0u8; MAX_SECRET_LEN]);
+    let mut len = 0;
+    loop {
+        if len == buf.len() {
+            // Full, anything more is too much.
+            let mut more = Zeroizing::new([0u8; 1]);
+            match reader.read(more.as_mut()) {
+                Ok(0) => break,
+                Ok(_) => return Err(Error::SecretTooLong(MAX_SECRET_LEN)),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    buf.truncate(len);
+    // Checked in place, the bytes are moved into the string without copying them.
+    match String::from_utf8(std::mem::take(&mut *buf)) {
+        Ok(secret) => check_secret(Zeroizing::new(secret)),
+        Err(err) => {
+            err.into_bytes().zeroize();
+            Err(Error::InvalidSecretEncoding)
+        }
+    }
+}
+
+fn check_secret(mut secret: Zeroizing<String>) -> Result<Zeroizing<String>> {
+    if secret.ends_with('\n') {
+        secret.pop();
+        if secret.ends_with('\r') {
+            secret.pop();
+        }
+    }
+    if secret.trim().is_empty() {
+        return Err(Error::EmptySecret);
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_secret_trims_newline() {
+        let key = "DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:";
+        for input in [key.to_string(), format!("{key}\n"), format!("{key}\r\n")] {
+            assert_eq!(read_secret(input.as_bytes()).unwrap().as_str(), key);
+        }
+        // Only a single line ending is stripped.
+        assert_eq!(read_secret("key\n\n".as_bytes()).unwrap().as_str(), "key\n");
+    }
+
+    #[test]
+    fn test_read_secret_rejects_empty() {
+        for input in ["", "\n", "\r\n", "  \n"] {
+            let err = read_secret(input.as_bytes()).unwrap_err();
+            assert!(matches!(err.root(), Error::EmptySecret));
+        }
+        assert!(SecretSource::Value(Zeroizing::new(String::new()))
+            .read()
+            .is_err());
+        assert!(SecretSource::Env("NVMETCFG_TEST_UNSET_SECRET".to_string())
+            .read()
+            .is_err());
+    }
+
+    #[test]
+    fn test_read_secret_limits() {
+        let key = "k".repeat(MAX_SECRET_LEN);
+        assert_eq!(read_secret(key.as_bytes()).unwrap().as_str(), key);
+        let err = read_secret(format!("{key}\n").as_bytes()).unwrap_err();
+        assert!(matches!(err.root(), Error::SecretTooLong(MAX_SECRET_LEN)));
+        let err = read_secret(&b"key\xff\n"[..]).unwrap_err();
+        assert!(matches!(err.root(), Error::InvalidSecretEncoding));
+    }
+
+    #[test]
+    fn test_debug_redacts_value() {
+        let key = "DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:";
+        let source = SecretSource::Value(Zeroizing::new(key.to_string()));
+        assert_eq!(format!("{source:?}"), "Value(<redacted>)");
+        assert_eq!(source.read().unwrap().as_str(), key);
+    }
+}
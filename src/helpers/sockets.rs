@@ -0,0 +1,155 @@
+use crate::errors::{Context, Result};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+static PROC_NET_TCP: &str = "/proc/net/tcp";
+static PROC_NET_TCP6: &str = "/proc/net/tcp6";
+
+// Socket state of listening sockets in /proc/net/tcp, TCP_LISTEN.
+const TCP_LISTEN: &str = "0A";
+
+/// The local addresses of all listening TCP sockets, from /proc/net/tcp and /proc/net/tcp6.
+///
+/// This includes the sockets the kernel itself listens on for NVMe over TCP ports.
+pub fn listening_tcp_sockets() -> Result<Vec<SocketAddr>> {
+    let mut sockets = Vec::new();
+    for (path, ipv6) in [(PROC_NET_TCP, false), (PROC_NET_TCP6, true)] {
+        match std::fs::read_to_string(path) {
+            Ok(table) => sockets.extend(parse_listening_sockets(&table, ipv6)),
+            // Without IPv6, there is no tcp6 table.
+            Err(err) if err.kind() == ErrorKind::NotFound && ipv6 => {}
+            Err(err) => return Err(err).with_context(|| format!("Failed to read {path}")),
+        }
+    }
+    Ok(sockets)
+}
+
+/// The local addresses of the listening sockets in a /proc/net/tcp or tcp6 table.
+///
+/// Lines which can't be parsed are skipped.
+#[must_use]
+pub fn parse_listening_sockets(table: &str, ipv6: bool) -> Vec<SocketAddr> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let local = fields.next()?;
+            let state = fields.nth(1)?;
+            if state != TCP_LISTEN {
+                return None;
+            }
+            let (addr, port) = local.split_once(':')?;
+            let ip = if ipv6 {
+                parse_ipv6(addr)?
+            } else {
+                parse_ipv4(addr)?
+            };
+            Some(SocketAddr::new(ip, u16::from_str_radix(port, 16).ok()?))
+        })
+        .collect()
+}
+
+// Addresses are printed as 32 bit words in host byte order.
+fn parse_ipv4(hex: &str) -> Option<IpAddr> {
+    if hex.len() != 8 {
+        return None;
+    }
+    let word = u32::from_str_radix(hex, 16).ok()?;
+    Some(IpAddr::V4(Ipv4Addr::from(word.to_ne_bytes())))
+}
+
+fn parse_ipv6(hex: &str) -> Option<IpAddr> {
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut octets = [0u8; 16];
+    for (i, chunk) in octets.chunks_mut(4).enumerate() {
+        let word = u32::from_str_radix(&hex[i * 8..i * 8 + 8], 16).ok()?;
+        chunk.copy_from_slice(&word.to_ne_bytes());
+    }
+    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+}
+
+/// Whether one of the `listening` sockets accepts connections to `addr`.
+///
+/// Sockets listening on the unspecified address accept connections to any address of their
+/// family. IPv6 ones are taken to accept IPv4 as well, as being restricted to IPv6 doesn't show.
+#[must_use]
+pub fn is_listening(addr: SocketAddr, listening: &[SocketAddr]) -> bool {
+    let ip = addr.ip().to_canonical();
+    listening.iter().any(|socket| {
+        socket.port() == addr.port()
+            && match socket.ip().to_canonical() {
+                sock_ip if sock_ip == ip => true,
+                IpAddr::V4(v4) => v4.is_unspecified() && ip.is_ipv4(),
+                IpAddr::V6(v6) => v6.is_unspecified(),
+            }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Abbreviated, the remaining columns don't matter.
+    const TCP: &str = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:0CEA 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1000
+   1: 010200C0:1144 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1001
+   2: 00000000:1145 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1002
+   3: 020200C0:1144 030200C0:C350 01 00000000:00000000 00:00000000 00000000     0        0 1003
+   4: garbage
+";
+    const TCP6: &str = "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0000FFFD000000000000000001000000:1144 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 2000
+   1: 00000000000000000000000000000000:1146 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 2001
+";
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn test_parse_listening_sockets() {
+        assert_eq!(
+            parse_listening_sockets(TCP, false),
+            vec![
+                addr("127.0.0.1:3306"),
+                addr("192.0.2.1:4420"),
+                addr("0.0.0.0:4421")
+            ]
+        );
+        assert_eq!(
+            parse_listening_sockets(TCP6, true),
+            vec![addr("[fdff::1]:4420"), addr("[::]:4422")]
+        );
+    }
+
+    #[test]
+    fn test_is_listening() {
+        let listening = [
+            addr("192.0.2.1:4420"),
+            addr("0.0.0.0:4421"),
+            addr("[fdff::1]:4420"),
+            addr("[::]:4422"),
+        ];
+        assert!(is_listening(addr("192.0.2.1:4420"), &listening));
+        assert!(!is_listening(addr("192.0.2.2:4420"), &listening));
+        assert!(!is_listening(addr("192.0.2.1:4423"), &listening));
+        assert!(is_listening(addr("[fdff::1]:4420"), &listening));
+        assert!(!is_listening(addr("[fdff::2]:4420"), &listening));
+
+        // Wildcard listeners accept any address, and configured wildcards need one.
+        assert!(is_listening(addr("192.0.2.7:4421"), &listening));
+        assert!(is_listening(addr("0.0.0.0:4421"), &listening));
+        assert!(!is_listening(addr("0.0.0.0:4420"), &listening));
+        assert!(!is_listening(addr("[fdff::1]:4421"), &listening));
+        assert!(is_listening(addr("[fdff::7]:4422"), &listening));
+        assert!(is_listening(addr("[::]:4422"), &listening));
+        assert!(is_listening(addr("192.0.2.7:4422"), &listening));
+
+        // IPv4-mapped addresses are treated as IPv4.
+        assert!(is_listening(addr("[::ffff:192.0.2.1]:4420"), &listening));
+    }
+}
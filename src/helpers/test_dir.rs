@@ -0,0 +1,44 @@
+// Directories of their own for tests working with files, removed again once they're done.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty directory below the temporary directory, removed with everything in it when dropped.
+///
+/// Dereferences to its path, so files in it are `dir.join("state.yaml")`.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Create a new one named after `name`, unique even among tests running at the same time.
+    pub fn new(name: &str) -> Self {
+        let id = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("nvmetcfg-{name}-{}-{id}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,4 +1,6 @@
 use crate::errors::{Error, Result};
+use base64::Engine;
+use std::net::SocketAddr;
 use uuid::Uuid;
 
 #[must_use]
@@ -13,9 +15,9 @@ pub fn is_ascii_only(data: &str) -> bool {
 
 pub fn assert_valid_nqn(nqn: &str) -> Result<()> {
     if !is_ascii_only(nqn) {
-        Err(Error::NQNNotAscii(nqn.to_string()).into())
+        Err(Error::NQNNotAscii(nqn.to_string()))
     } else if nqn.len() > 223 {
-        Err(Error::NQNTooLong(nqn.to_string()).into())
+        Err(Error::NQNTooLong(nqn.to_string()))
     } else {
         Ok(())
     }
@@ -24,18 +26,18 @@ pub fn assert_valid_nqn(nqn: &str) -> Result<()> {
 pub fn assert_compliant_nqn(nqn: &str) -> Result<()> {
     assert_valid_nqn(nqn)?;
     if !nqn.starts_with("nqn.") {
-        Err(Error::NQNMissingNQN(nqn.to_string()).into())
+        Err(Error::NQNMissingNQN(nqn.to_string()))
     } else if nqn.len() < 15 {
-        Err(Error::NQNTooShort(nqn.to_string()).into())
+        Err(Error::NQNTooShort(nqn.to_string()))
     } else if let Some(uuid) = nqn.strip_prefix("nqn.2014-08.org.nvmexpress:uuid:") {
         // NQN is a UUID. So we should ensure it's valid.
         if Uuid::try_parse(uuid).is_err() {
-            Err(Error::NQNUuidInvalid(uuid.to_string()).into())
+            Err(Error::NQNUuidInvalid(uuid.to_string()))
         } else {
             Ok(())
         }
     } else if nqn == "nqn.2014-08.org.nvmexpress.discovery" {
-        Err(Error::CantCreateDiscovery.into())
+        Err(Error::CantCreateDiscovery)
     } else {
         // TODO: check if nqn has nqn.yyyy-mm, some reverse domain and a colon.
         // we can't make many other assumptions.
@@ -44,31 +46,150 @@ pub fn assert_compliant_nqn(nqn: &str) -> Result<()> {
             (nqn_bytes[3] == b'.') && (nqn_bytes[8] == b'-') && (nqn_bytes[11] == b'.');
         let valid_date = nqn[4..8].parse::<i16>().is_ok() && nqn[9..10].parse::<i16>().is_ok();
         if !has_dots_and_dash || !valid_date {
-            Err(Error::NQNInvalidDate(nqn.to_string()).into())
+            Err(Error::NQNInvalidDate(nqn.to_string()))
         } else {
             if let Some((domain, identifier)) = nqn[12..].split_once(":") {
                 if domain == "org.nvmexpress" {
-                    return Err(Error::NQNInvalidDomain(nqn.to_string()).into());
+                    return Err(Error::NQNInvalidDomain(nqn.to_string()));
                 }
                 if !domain.is_empty() && !identifier.is_empty() {
                     return Ok(());
                 }
             }
-            Err(Error::NQNInvalidIdentifier(nqn.to_string()).into())
+            Err(Error::NQNInvalidIdentifier(nqn.to_string()))
         }
     }
 }
 
+/// Maximum length of a subsystem model.
+pub const MODEL_MAX_LEN: usize = 40;
+/// Maximum length of a subsystem serial.
+pub const SERIAL_MAX_LEN: usize = 20;
+/// Highest number of I/O queues a subsystem can be limited to (NVMET_NR_QUEUES).
+pub const QID_MAX_LIMIT: u32 = 128;
+
 pub fn assert_valid_model(model: &str) -> Result<()> {
-    if !is_ascii_only(model) || model.is_empty() || (model.len() > 40) {
-        Err(Error::InvalidModel(model.to_string()).into())
+    if !is_ascii_only(model) || model.is_empty() || (model.len() > MODEL_MAX_LEN) {
+        Err(Error::InvalidModel(model.to_string()))
     } else {
         Ok(())
     }
 }
 pub fn assert_valid_serial(serial: &str) -> Result<()> {
-    if !is_ascii_only(serial) || serial.is_empty() || (serial.len() > 20) {
-        Err(Error::InvalidSerial(serial.to_string()).into())
+    if !is_ascii_only(serial) || serial.is_empty() || (serial.len() > SERIAL_MAX_LEN) {
+        Err(Error::InvalidSerial(serial.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn assert_valid_qid_max(qid_max: u32) -> Result<()> {
+    if qid_max == 0 || qid_max > QID_MAX_LIMIT {
+        Err(Error::InvalidQidMax(qid_max))
+    } else {
+        Ok(())
+    }
+}
+
+// ASCII replacements for common non-ASCII characters in device descriptions.
+fn transliterate(c: char) -> Option<&'static str> {
+    let ascii = match c {
+        'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' => "A",
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' => "C",
+        'ç' => "c",
+        'È' | 'É' | 'Ê' | 'Ë' => "E",
+        'è' | 'é' | 'ê' | 'ë' => "e",
+        'Ì' | 'Í' | 'Î' | 'Ï' => "I",
+        'ì' | 'í' | 'î' | 'ï' => "i",
+        'Ñ' => "N",
+        'ñ' => "n",
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ø' => "O",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => "o",
+        'Ù' | 'Ú' | 'Û' | 'Ü' => "U",
+        'ù' | 'ú' | 'û' | 'ü' => "u",
+        'Ý' => "Y",
+        'ý' | 'ÿ' => "y",
+        'ß' => "ss",
+        '‐' | '‑' | '‒' | '–' | '—' => "-",
+        '‘' | '’' | '´' => "'",
+        '“' | '”' => "\"",
+        '®' => "(R)",
+        '©' => "(C)",
+        '™' => "(TM)",
+        '\u{a0}' => " ",
+        _ => return None,
+    };
+    Some(ascii)
+}
+
+/// Replace non-ASCII characters by ASCII lookalikes, dropping those without one.
+///
+/// Returns `None` if `value` is ASCII already.
+#[must_use]
+pub fn fold_to_ascii(value: &str) -> Option<String> {
+    if value.is_ascii() {
+        return None;
+    }
+    let mut folded = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_ascii() {
+            folded.push(c);
+        } else if let Some(ascii) = transliterate(c) {
+            folded.push_str(ascii);
+        }
+    }
+    Some(folded)
+}
+
+/// Shorten `value` to at most `max_len` bytes, or return `None` if it already fits.
+#[must_use]
+pub fn truncate_to_len(value: &str, max_len: usize) -> Option<&str> {
+    if value.len() <= max_len {
+        return None;
+    }
+    let end = (0..=max_len)
+        .rev()
+        .find(|end| value.is_char_boundary(*end))
+        .unwrap_or(0);
+    Some(&value[..end])
+}
+
+/// Check a subsystem alias, as given without the leading `@`.
+pub fn assert_valid_alias(alias: &str) -> Result<()> {
+    if alias.is_empty()
+        || alias.len() > 64
+        || !alias
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        Err(Error::InvalidAlias(alias.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check a label key: ASCII letters, digits, '.', '_', '-' and '/', starting with a letter or
+/// digit and at most 63 bytes long.
+pub fn assert_valid_label_key(key: &str) -> Result<()> {
+    if !key.starts_with(|c: char| c.is_ascii_alphanumeric())
+        || key.len() > 63
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'))
+    {
+        Err(Error::InvalidLabelKey(key.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check a label value: printable characters only and at most 255 bytes long.
+pub fn assert_valid_label_value(value: &str) -> Result<()> {
+    if value.len() > 255 || value.chars().any(char::is_control) {
+        Err(Error::InvalidLabelValue(value.to_string()))
     } else {
         Ok(())
     }
@@ -76,12 +197,81 @@ pub fn assert_valid_serial(serial: &str) -> Result<()> {
 
 pub fn assert_valid_nsid(nsid: u32) -> Result<()> {
     if nsid == 0 || nsid == 0xffff_ffff {
-        Err(Error::InvalidNamespaceID(nsid).into())
+        Err(Error::InvalidNamespaceID(nsid))
     } else {
         Ok(())
     }
 }
 
+/// Check a DH-HMAC-CHAP key in the `DHHC-1:<hmac>:<base64>:` format.
+///
+/// The hmac field indicates the hash used to transform the secret: 00 for none,
+/// 01 for SHA-256, 02 for SHA-384 and 03 for SHA-512. The payload is the secret
+/// followed by its CRC32 in little endian.
+pub fn assert_valid_dhchap_key(key: &str) -> Result<()> {
+    let invalid = |reason: String| Err(Error::InvalidDhchapKey(reason));
+
+    let Some(rest) = key.strip_prefix("DHHC-1:") else {
+        return invalid("does not start with 'DHHC-1:'".to_string());
+    };
+    let Some((hmac, payload)) = rest.split_once(':') else {
+        return invalid("missing hmac field".to_string());
+    };
+    let Some(payload) = payload.strip_suffix(':') else {
+        return invalid("does not end with ':'".to_string());
+    };
+    let expected_lengths: &[usize] = match hmac {
+        "00" => &[32, 48, 64],
+        "01" => &[32],
+        "02" => &[48],
+        "03" => &[64],
+        _ => return invalid(format!("unknown hmac '{hmac}', expected 00, 01, 02 or 03")),
+    };
+
+    let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(payload) else {
+        return invalid("payload is not valid base64".to_string());
+    };
+    let Some(secret_len) = decoded.len().checked_sub(4) else {
+        return invalid("payload is too short to contain a CRC".to_string());
+    };
+    if !expected_lengths.contains(&secret_len) {
+        return invalid(format!(
+            "secret is {secret_len} bytes, but hmac {hmac} requires {expected_lengths:?} bytes"
+        ));
+    }
+    let (secret, crc) = decoded.split_at(secret_len);
+    if crc32fast::hash(secret).to_le_bytes() != crc {
+        return invalid("CRC mismatch".to_string());
+    }
+    Ok(())
+}
+
+/// Parse a TCP or RDMA service ID, the port number, which must be 1-65535.
+pub fn parse_trsvcid(trsvcid: &str) -> Result<u16> {
+    // Only digits, `parse` would take a sign like `+4420` as well.
+    if trsvcid.is_empty() || !trsvcid.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::InvalidServiceId(trsvcid.to_string()));
+    }
+    match trsvcid.parse() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => Err(Error::InvalidServiceId(trsvcid.to_string())),
+    }
+}
+
+/// Parse an address like `192.0.2.1:4420` or `[fdff::1]:4420`.
+///
+/// The port is checked on its own first, so a mistake like `192.0.2.1:nvme` is
+/// reported as such rather than as an unparseable IP address.
+pub fn parse_socket_addr(addr: &str) -> Result<SocketAddr> {
+    if let Some((host, port)) = addr.rsplit_once(':') {
+        // Unbracketed IPv6 addresses have no port to check.
+        if host.ends_with(']') || !host.contains(':') {
+            parse_trsvcid(port)?;
+        }
+    }
+    Ok(addr.parse()?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -158,6 +348,71 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_truncate_to_len() -> Result<()> {
+        let model = "I am running out of dumb things to write!";
+        let short = truncate_to_len(model, MODEL_MAX_LEN).unwrap();
+        assert_eq!(short, "I am running out of dumb things to write");
+        assert_valid_model(short)?;
+        let serial = truncate_to_len("dumb, but long enough", SERIAL_MAX_LEN).unwrap();
+        assert_valid_serial(serial)?;
+        // Values that fit stay untouched.
+        assert_eq!(truncate_to_len("1D10T", SERIAL_MAX_LEN), None);
+        // Multi-byte characters are not split, but still rejected.
+        let short = truncate_to_len("0123456789012345678💩", SERIAL_MAX_LEN).unwrap();
+        assert_eq!(short, "0123456789012345678");
+        assert!(assert_valid_serial(&format!("{short}💩")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold_to_ascii() -> Result<()> {
+        let model = fold_to_ascii("Größe™ SSD – Séries 💩").unwrap();
+        assert_eq!(model, "Grosse(TM) SSD - Series ");
+        assert_valid_model(&model)?;
+        assert_eq!(fold_to_ascii("Dumb-O-Tron 2000"), None);
+        // Characters without a lookalike are dropped.
+        assert_eq!(fold_to_ascii("💩").unwrap(), "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_alias() -> Result<()> {
+        assert_valid_alias("backup-01")?;
+        assert_valid_alias("vm.disk_2")?;
+        // Empty
+        assert!(assert_valid_alias("").is_err());
+        // The @ is not part of the alias.
+        assert!(assert_valid_alias("@backup").is_err());
+        // Separators used by the CLI.
+        assert!(assert_valid_alias("a,b").is_err());
+        assert!(assert_valid_alias("a:b").is_err());
+        // Too long.
+        assert!(assert_valid_alias(&"a".repeat(65)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_label() -> Result<()> {
+        assert_valid_label_key("env")?;
+        assert_valid_label_key("example.com/team_name-2")?;
+        assert!(assert_valid_label_key("").is_err());
+        assert!(assert_valid_label_key("-env").is_err());
+        // Separators used by the CLI.
+        assert!(assert_valid_label_key("env=prod").is_err());
+        assert!(assert_valid_label_key("a b").is_err());
+        assert!(assert_valid_label_key(&"a".repeat(64)).is_err());
+
+        assert_valid_label_value("")?;
+        assert_valid_label_value("Storage Team, Größe")?;
+        assert!(assert_valid_label_value("two\nlines").is_err());
+        assert!(assert_valid_label_value(&"a".repeat(256)).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_valid_nsid() -> Result<()> {
         assert_valid_nsid(1)?;
@@ -169,4 +424,106 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_valid_dhchap_key() -> Result<()> {
+        // Known-good key from the nvme-cli and blktests test suites.
+        assert_valid_dhchap_key("DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:")?;
+        assert_valid_dhchap_key("DHHC-1:01:NcJ92zAcKEwrIVvaqI3/s8CL4IKox3aCA6s2IeV14WlbRimx:")?;
+        assert_valid_dhchap_key(
+            "DHHC-1:02:LtWeRcf486Hji205N7KQQ3vvElcC3/DJUiR2j0v5CnL4fP/l4MH4Tc+w7I4+ey4GyNL8eQ==:",
+        )?;
+        assert_valid_dhchap_key("DHHC-1:03:WflLiXx/RvbF6qmooDg7AdnNTzMDJG1nQyA3oE+9jdnIH3li7QDZ8R4hF27o85BXQUzvLZq+De1pswFjvVXbLjwsagM=:")?;
+
+        let reason = |key: &str| match assert_valid_dhchap_key(key).unwrap_err() {
+            Error::InvalidDhchapKey(reason) => reason,
+            other => panic!("unexpected error: {other:?}"),
+        };
+        // Bad prefix.
+        assert!(
+            reason("DHHC-2:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:")
+                .contains("DHHC-1")
+        );
+        // Missing trailing colon.
+        assert!(
+            reason("DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n")
+                .contains("end with")
+        );
+        // Unknown hmac.
+        assert!(
+            reason("DHHC-1:04:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:")
+                .contains("unknown hmac")
+        );
+        // Not base64.
+        assert!(
+            reason("DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2!:")
+                .contains("base64")
+        );
+        // 32 byte secret indicated as SHA-512.
+        assert!(
+            reason("DHHC-1:03:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:")
+                .contains("requires")
+        );
+        // Corrupted secret.
+        assert!(
+            reason("DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6bQ2n:").contains("CRC")
+        );
+        // No hmac field at all.
+        assert!(reason("DHHC-1:00").contains("hmac"));
+        // Too short to even hold the CRC.
+        assert!(reason("DHHC-1:00:AAA=:").contains("too short"));
+        // A plain secret instead of a formatted key.
+        assert!(reason("ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n").contains("DHHC-1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_trsvcid() -> Result<()> {
+        assert_eq!(parse_trsvcid("4420")?, 4420);
+        assert_eq!(parse_trsvcid("1")?, 1);
+        assert_eq!(parse_trsvcid("65535")?, 65535);
+        for invalid in [
+            "", "nvme", "44x20", "-1", "+4420", " 4420", "0", "65536", "100000",
+        ] {
+            assert!(
+                matches!(parse_trsvcid(invalid), Err(Error::InvalidServiceId(id)) if id == invalid),
+                "{invalid}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_socket_addr() -> Result<()> {
+        assert_eq!(
+            parse_socket_addr("192.0.2.1:4420")?,
+            "192.0.2.1:4420".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_socket_addr("[fdff::1]:4421")?,
+            "[fdff::1]:4421".parse::<SocketAddr>().unwrap()
+        );
+        for invalid in [
+            "192.0.2.1:notaport",
+            "192.0.2.1:0",
+            "192.0.2.1:65536",
+            "192.0.2.1:+4420",
+            "192.0.2.1:",
+            "[fdff::1]:nvme",
+        ] {
+            assert!(
+                matches!(parse_socket_addr(invalid), Err(Error::InvalidServiceId(_))),
+                "{invalid}"
+            );
+        }
+        // Without a port to blame, the address itself is wrong.
+        for invalid in ["192.0.2.1", "fdff::1", "192.0.2:4420", "host.example:4420"] {
+            assert!(
+                matches!(parse_socket_addr(invalid), Err(Error::InvalidIPAddr(_))),
+                "{invalid}"
+            );
+        }
+        Ok(())
+    }
 }
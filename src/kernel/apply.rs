@@ -0,0 +1,577 @@
+use super::hosts::{plan_host_removals, set_host_keys_in};
+use super::sysfs::NvmetRoot;
+use super::{Capabilities, FeatureUse, KernelConfig};
+use crate::errors::{Context, Error, Result};
+use crate::state::{IgnoreFields, State, StateDelta};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+/// Options controlling how `KernelConfig::apply_state` reconciles the system state.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ApplyOptions {
+    /// Only add and update, never remove anything missing from the desired state.
+    pub merge: bool,
+    /// Compute the changes, but do not apply them.
+    pub dry_run: bool,
+    /// Require subsystem NQNs to follow the formats from the NVMe specification.
+    pub strict: bool,
+    /// How often to gather the state and try again after failing to apply it, for transient
+    /// failures only, see `Error::is_transient`.
+    pub retries: u32,
+    /// How long to wait between retries.
+    pub retry_delay: Duration,
+    /// Fields whose differences do not warrant any changes.
+    pub ignore: IgnoreFields,
+    /// Gather the state again after applying and report what still differs.
+    pub verify: bool,
+    /// Keep hosts no longer allowed on any subsystem instead of removing them.
+    pub keep_hosts: bool,
+    /// Don't check that the objects touched by each change are still as gathered.
+    pub skip_preconditions: bool,
+    /// Allow changing the UUID or NGUID of a namespace without changing its device.
+    pub allow_identity_change: bool,
+    /// Leave out settings the kernel does not support instead of refusing the whole state.
+    pub skip_unsupported: bool,
+}
+
+/// The outcome of `KernelConfig::apply_state`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ApplyReport {
+    /// The changes that were planned to reach the desired state.
+    pub deltas: Vec<StateDelta>,
+    /// Whether the changes were actually applied.
+    pub applied: bool,
+    /// How many attempts it took to apply the changes.
+    pub attempts: u32,
+    /// Changes the system still needs after applying, only set when verifying.
+    pub residual: Vec<StateDelta>,
+    /// Hosts left unused by the changes, removed after applying them.
+    pub host_removals: BTreeSet<String>,
+    /// Hosts whose DH-HMAC-CHAP keys were set from `State::hosts`.
+    pub keyed_hosts: BTreeSet<String>,
+    /// Settings left out as the kernel does not support them, only set when skipping those.
+    pub skipped: Vec<FeatureUse>,
+}
+
+impl KernelConfig {
+    /// Reconcile the system state with `desired`.
+    ///
+    /// This gathers the current state, computes the necessary changes and applies them,
+    /// so everything embedding this crate shares the same semantics.
+    /// Ports defined by a network interface are resolved to its current address first, and
+    /// namespaces defined by the filesystem on their device to that device.
+    pub fn apply_state(desired: &State, opts: ApplyOptions) -> Result<ApplyReport> {
+        Self::system().reconcile(desired, opts)
+    }
+
+    pub(crate) fn apply_state_in(
+        root: &NvmetRoot,
+        desired: &State,
+        opts: &ApplyOptions,
+    ) -> Result<ApplyReport> {
+        let mut desired = desired.clone();
+        desired.resolve()?;
+        let mut report = ApplyReport::default();
+        // Only probe when the state needs any optional feature, as probing may create objects.
+        if !Capabilities::default()
+            .strip_unsupported(&mut desired.clone())
+            .is_empty()
+        {
+            let caps =
+                Self::capabilities_in(root).context("Failed to probe kernel capabilities")?;
+            let mut supported = desired.clone();
+            let unsupported = caps.strip_unsupported(&mut supported);
+            if !unsupported.is_empty() {
+                if !opts.skip_unsupported {
+                    let unsupported: Vec<String> =
+                        unsupported.iter().map(ToString::to_string).collect();
+                    return Err(Error::UnsupportedAttributes(unsupported.join(", ")));
+                }
+                desired = supported;
+                report.skipped = unsupported;
+            }
+        }
+        loop {
+            let current = Self::gather_state_in(root).context("Failed to gather current state")?;
+            let target = if opts.merge {
+                let mut merged = current.clone();
+                merged.merge(&desired);
+                merged
+            } else {
+                desired.clone()
+            };
+            target
+                .validate(opts.strict)
+                .context("Failed to validate desired state")?;
+
+            let deltas = current.get_deltas_ignoring(&target, &opts.ignore);
+            if !opts.allow_identity_change {
+                let changes = current.get_identity_changes(&deltas);
+                if !changes.is_empty() {
+                    let changes: Vec<String> = changes
+                        .iter()
+                        .map(|(nqn, nsid)| format!("namespace {nsid} of {nqn}"))
+                        .collect();
+                    return Err(Error::NamespaceIdentityChange(changes.join(", ")));
+                }
+            }
+            let mut host_removals = if opts.keep_hosts {
+                BTreeSet::new()
+            } else {
+                plan_host_removals(root, &current, &deltas)?
+            };
+            host_removals.retain(|host| !target.hosts.contains_key(host));
+            if report.attempts == 0 {
+                report.deltas.clone_from(&deltas);
+                report.host_removals.clone_from(&host_removals);
+                // Before the changes, so hosts allowed on subsystems by them have their keys.
+                if !opts.dry_run {
+                    report.keyed_hosts = set_host_keys_in(root, &target.hosts)
+                        .context("Failed to set keys of hosts")?;
+                }
+            }
+            if opts.dry_run || deltas.is_empty() {
+                return Ok(report);
+            }
+
+            report.attempts += 1;
+            let base = (!opts.skip_preconditions).then_some(&current);
+            match Self::apply_planned(root, deltas, &host_removals, base) {
+                Ok(()) => {
+                    report.applied = true;
+                    if opts.verify {
+                        let current = Self::gather_state_in(root)
+                            .context("Failed to gather state for verification")?;
+                        report.residual = opts
+                            .ignore
+                            .filter_deltas(&current, current.get_unmet_deltas(&target));
+                    }
+                    return Ok(report);
+                }
+                Err(err) if err.is_transient() && report.attempts <= opts.retries => {
+                    std::thread::sleep(opts.retry_delay);
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("Failed to apply state after {} attempts", report.attempts)
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::{FakeBackend, FakeOp};
+    use crate::kernel::tests::{example_state, HOST, SUB};
+    use crate::kernel::Feature;
+    use crate::state::{HostKeys, Subsystem, SubsystemDelta};
+    use uuid::Uuid;
+
+    const OTHER: &str = "nqn.2023-11.sh.tty:other";
+
+    fn setup() -> (std::sync::Arc<FakeBackend>, NvmetRoot) {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let mut initial = example_state();
+        initial
+            .subsystems
+            .insert(OTHER.to_string(), Subsystem::default());
+        KernelConfig::apply_state_in(&root, &initial, &ApplyOptions::default()).unwrap();
+        (fake, root)
+    }
+
+    #[test]
+    fn test_apply_state_prune() -> Result<()> {
+        let (_fake, root) = setup();
+        let report =
+            KernelConfig::apply_state_in(&root, &example_state(), &ApplyOptions::default())?;
+        assert!(report.applied);
+        assert_eq!(
+            report.deltas,
+            vec![StateDelta::RemoveSubsystem(OTHER.to_string())]
+        );
+        assert!(!KernelConfig::gather_state_in(&root)?
+            .subsystems
+            .contains_key(OTHER));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_state_merge() -> Result<()> {
+        let (_fake, root) = setup();
+        let mut desired = State::default();
+        desired.subsystems.insert(
+            SUB.to_string(),
+            Subsystem {
+                alias: None,
+                model: Some("Merged".to_string()),
+                ..Default::default()
+            },
+        );
+        let opts = ApplyOptions {
+            merge: true,
+            ..Default::default()
+        };
+        let report = KernelConfig::apply_state_in(&root, &desired, &opts)?;
+        assert_eq!(
+            report.deltas,
+            vec![StateDelta::UpdateSubsystem(
+                SUB.to_string(),
+                vec![SubsystemDelta::UpdateModel("Merged".to_string())]
+            )]
+        );
+        let state = KernelConfig::gather_state_in(&root)?;
+        assert!(state.subsystems.contains_key(OTHER));
+        assert_eq!(state.subsystems[SUB].namespaces.len(), 1);
+        assert_eq!(state.ports.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_state_inactive() -> Result<()> {
+        // The file has OTHER inactive, while the system has it configured.
+        let mut desired = example_state();
+        let mut other = Subsystem {
+            model: Some("Inactive".to_string()),
+            ..Default::default()
+        };
+        other.active = Some(false);
+        desired.subsystems.insert(OTHER.to_string(), other);
+        desired
+            .ports
+            .get_mut(&1)
+            .unwrap()
+            .subsystems
+            .insert(OTHER.to_string());
+        let mut inactive_port = desired.ports[&1].clone();
+        inactive_port.active = Some(false);
+        desired.ports.insert(2, inactive_port);
+
+        // Merging leaves the live subsystem alone, and adds nothing.
+        let (_fake, root) = setup();
+        let before = KernelConfig::gather_state_in(&root)?;
+        let merge = ApplyOptions {
+            merge: true,
+            ..Default::default()
+        };
+        let report = KernelConfig::apply_state_in(&root, &desired, &merge)?;
+        assert!(report.deltas.is_empty());
+        assert_eq!(KernelConfig::gather_state_in(&root)?, before);
+
+        // Pruning removes it, as if it was missing from the file.
+        let report = KernelConfig::apply_state_in(&root, &desired, &ApplyOptions::default())?;
+        assert_eq!(
+            report.deltas,
+            vec![StateDelta::RemoveSubsystem(OTHER.to_string())]
+        );
+        let state = KernelConfig::gather_state_in(&root)?;
+        assert!(!state.subsystems.contains_key(OTHER));
+        assert_eq!(state.ports.keys().collect::<Vec<_>>(), [&1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_state_dry_run() -> Result<()> {
+        let (fake, root) = setup();
+        fake.take_ops();
+        let opts = ApplyOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = KernelConfig::apply_state_in(&root, &State::default(), &opts)?;
+        assert!(!report.applied);
+        assert_eq!(report.deltas.len(), 3);
+        // The host left unused is reported, but stays until the changes are applied.
+        assert_eq!(report.host_removals, BTreeSet::from([HOST.to_string()]));
+        assert!(fake.contains(&format!("hosts/{HOST}")));
+        assert!(!fake
+            .take_ops()
+            .iter()
+            .any(|op| !matches!(op, FakeOp::Exists(_) | FakeOp::Read(_) | FakeOp::List(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_state_strict() -> Result<()> {
+        let (_fake, root) = setup();
+        let mut desired = example_state();
+        desired
+            .subsystems
+            .insert("nqn.not-compliant".to_string(), Subsystem::default());
+        let strict = ApplyOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(KernelConfig::apply_state_in(&root, &desired, &strict).is_err());
+        KernelConfig::apply_state_in(&root, &desired, &ApplyOptions::default())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_state_retries() -> Result<()> {
+        let (fake, root) = setup();
+        let mut desired = example_state();
+        desired.subsystems.get_mut(SUB).unwrap().model = Some("Retried".to_string());
+
+        fake.fail_writes(&format!("subsystems/{SUB}/attr_model"), 1);
+        assert!(KernelConfig::apply_state_in(&root, &desired, &ApplyOptions::default()).is_err());
+
+        fake.fail_writes(&format!("subsystems/{SUB}/attr_model"), 1);
+        let opts = ApplyOptions {
+            retries: 1,
+            ..Default::default()
+        };
+        let report = KernelConfig::apply_state_in(&root, &desired, &opts)?;
+        assert_eq!(report.attempts, 2);
+        assert_eq!(
+            KernelConfig::gather_state_in(&root)?.subsystems[SUB].model,
+            Some("Retried".to_string())
+        );
+
+        // Retrying doesn't help against lacking permissions.
+        desired.subsystems.get_mut(SUB).unwrap().model = Some("Denied".to_string());
+        fake.deny(&format!("subsystems/{SUB}/attr_model"));
+        let err = KernelConfig::apply_state_in(&root, &desired, &opts).unwrap_err();
+        assert!(!err.is_transient());
+        assert!(err.to_string().contains("after 1 attempts"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_state_ignore() -> Result<()> {
+        let (_fake, root) = setup();
+        let mut desired = example_state();
+        desired
+            .subsystems
+            .insert(OTHER.to_string(), Subsystem::default());
+        let ns = desired
+            .subsystems
+            .get_mut(SUB)
+            .unwrap()
+            .namespaces
+            .get_mut(&1)
+            .unwrap();
+        ns.device_uuid = Some(Uuid::from_u128(1234));
+        let ns = ns.clone();
+
+        let opts = ApplyOptions {
+            ignore: "uuid".parse()?,
+            verify: true,
+            ..Default::default()
+        };
+        let report = KernelConfig::apply_state_in(&root, &desired, &opts)?;
+        assert!(report.deltas.is_empty());
+        assert!(!report.applied);
+
+        let opts = ApplyOptions {
+            allow_identity_change: true,
+            ..Default::default()
+        };
+        let report = KernelConfig::apply_state_in(&root, &desired, &opts)?;
+        assert!(report.applied);
+        assert!(report.deltas.contains(&StateDelta::UpdateSubsystem(
+            SUB.to_string(),
+            vec![SubsystemDelta::UpdateNamespace(1, ns.clone())]
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_state_identity_change() -> Result<()> {
+        let (_fake, root) = setup();
+        let mut desired = KernelConfig::gather_state_in(&root)?;
+        let ns = desired
+            .subsystems
+            .get_mut(SUB)
+            .unwrap()
+            .namespaces
+            .get_mut(&1)
+            .unwrap();
+        let uuid = ns.device_uuid;
+        ns.device_uuid = Some(Uuid::from_u128(1234));
+
+        // Even a dry run is refused, and nothing is changed.
+        let dry_run = ApplyOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let err = KernelConfig::apply_state_in(&root, &desired, &dry_run).unwrap_err();
+        assert!(matches!(err.root(), Error::NamespaceIdentityChange(_)));
+        assert!(KernelConfig::apply_state_in(&root, &desired, &ApplyOptions::default()).is_err());
+        let state = KernelConfig::gather_state_in(&root)?;
+        assert_eq!(state.subsystems[SUB].namespaces[&1].device_uuid, uuid);
+
+        // Leaving the identity unset, or giving it along with a new device, is fine.
+        let mut unset = desired.clone();
+        let ns = unset
+            .subsystems
+            .get_mut(SUB)
+            .unwrap()
+            .namespaces
+            .get_mut(&1)
+            .unwrap();
+        ns.device_uuid = None;
+        ns.device_nguid = None;
+        ns.enabled = false;
+        KernelConfig::apply_state_in(&root, &unset, &ApplyOptions::default())?;
+
+        let opts = ApplyOptions {
+            allow_identity_change: true,
+            ..Default::default()
+        };
+        KernelConfig::apply_state_in(&root, &desired, &opts)?;
+        let state = KernelConfig::gather_state_in(&root)?;
+        assert_eq!(
+            state.subsystems[SUB].namespaces[&1].device_uuid,
+            Some(Uuid::from_u128(1234))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_state_unsupported() -> Result<()> {
+        let (fake, root) = setup();
+        let mut desired = example_state();
+        desired.subsystems.get_mut(SUB).unwrap().qid_max = Some(4);
+        desired
+            .subsystems
+            .insert(OTHER.to_string(), Subsystem::default());
+        desired.subsystems.get_mut(OTHER).unwrap().model = Some("Skipped".to_string());
+
+        // This kernel lacks attr_qid_max, so the state is refused before changing anything.
+        let err =
+            KernelConfig::apply_state_in(&root, &desired, &ApplyOptions::default()).unwrap_err();
+        assert!(matches!(err.root(), Error::UnsupportedAttributes(_)));
+        assert!(err.to_string().contains(SUB));
+        assert_eq!(
+            KernelConfig::gather_state_in(&root)?.subsystems[OTHER].model,
+            Some("Linux".to_string())
+        );
+
+        // Skipping it configures everything else.
+        let opts = ApplyOptions {
+            skip_unsupported: true,
+            ..Default::default()
+        };
+        let report = KernelConfig::apply_state_in(&root, &desired, &opts)?;
+        assert_eq!(
+            report.skipped,
+            vec![FeatureUse {
+                feature: Feature::QidMax,
+                object: format!("subsystem {SUB}"),
+            }]
+        );
+        let state = KernelConfig::gather_state_in(&root)?;
+        assert_eq!(state.subsystems[OTHER].model, Some("Skipped".to_string()));
+        assert_eq!(state.subsystems[SUB].qid_max, None);
+
+        // Once supported, it is applied.
+        fake.add_attr(&format!("subsystems/{SUB}/attr_qid_max"), "128");
+        let report = KernelConfig::apply_state_in(&root, &desired, &ApplyOptions::default())?;
+        assert!(report.skipped.is_empty());
+        assert_eq!(
+            report.deltas,
+            vec![StateDelta::UpdateSubsystem(
+                SUB.to_string(),
+                vec![SubsystemDelta::UpdateQidMax(4)]
+            )]
+        );
+        assert_eq!(
+            KernelConfig::gather_state_in(&root)?.subsystems[SUB].qid_max,
+            Some(4)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_state_host_keys() -> Result<()> {
+        let (fake, root) = setup();
+        let key = "DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:";
+        let mut desired = example_state();
+        desired
+            .subsystems
+            .get_mut(SUB)
+            .unwrap()
+            .allowed_hosts
+            .clear();
+        desired.hosts.insert(
+            HOST.to_string(),
+            HostKeys {
+                dhchap_key: Some(key.to_string()),
+                dhchap_ctrl_key: None,
+            },
+        );
+
+        let dry_run = ApplyOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = KernelConfig::apply_state_in(&root, &desired, &dry_run)?;
+        assert!(report.keyed_hosts.is_empty());
+        // The host keeps existing for its keys, even though no subsystem allows it anymore.
+        assert!(report.host_removals.is_empty());
+        assert_eq!(
+            fake.attr(&format!("hosts/{HOST}/dhchap_key")).as_deref(),
+            Some("")
+        );
+
+        let report = KernelConfig::apply_state_in(&root, &desired, &ApplyOptions::default())?;
+        assert_eq!(report.keyed_hosts, BTreeSet::from([HOST.to_string()]));
+        assert_eq!(
+            fake.attr(&format!("hosts/{HOST}/dhchap_key")).as_deref(),
+            Some(key)
+        );
+
+        // Malformed keys are refused before changing anything.
+        desired.hosts.get_mut(HOST).unwrap().dhchap_ctrl_key = Some("DHHC-1:00:x:".to_string());
+        desired.subsystems.insert(
+            OTHER.to_string(),
+            Subsystem {
+                model: Some("Refused".to_string()),
+                ..Default::default()
+            },
+        );
+        let err =
+            KernelConfig::apply_state_in(&root, &desired, &ApplyOptions::default()).unwrap_err();
+        assert!(matches!(err.root(), Error::InvalidDhchapKey(_)), "{err:?}");
+        assert!(!KernelConfig::gather_state_in(&root)?
+            .subsystems
+            .contains_key(OTHER));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_state_selective_clear() -> Result<()> {
+        let (fake, root) = setup();
+
+        let mut target = KernelConfig::gather_state_in(&root)?;
+        target.clear_ports();
+        KernelConfig::apply_state_in(&root, &target, &ApplyOptions::default())?;
+        let state = KernelConfig::gather_state_in(&root)?;
+        assert!(state.ports.is_empty());
+        assert_eq!(state.subsystems.len(), 2);
+
+        let (fake2, root2) = setup();
+        let mut target = KernelConfig::gather_state_in(&root2)?;
+        target.clear_subsystems();
+        let opts = ApplyOptions {
+            keep_hosts: true,
+            ..Default::default()
+        };
+        KernelConfig::apply_state_in(&root2, &target, &opts)?;
+        let state = KernelConfig::gather_state_in(&root2)?;
+        assert!(state.subsystems.is_empty());
+        assert!(state.ports[&1].subsystems.is_empty());
+        assert!(fake2.contains(&format!("hosts/{HOST}")));
+
+        // Without keep_hosts, the now unused host goes away.
+        let mut target = KernelConfig::gather_state_in(&root)?;
+        target.clear_subsystems();
+        KernelConfig::apply_state_in(&root, &target, &ApplyOptions::default())?;
+        assert!(!fake.contains(&format!("hosts/{HOST}")));
+        Ok(())
+    }
+}
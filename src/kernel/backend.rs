@@ -0,0 +1,125 @@
+use crate::errors::{Error, Result};
+use crate::helpers::{effective_uid, read_str, write_str, ResolvedDevice};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Filesystem operations needed to manipulate the nvmet configfs tree.
+///
+/// The real implementation talks to the kernel, but keeping the operations
+/// behind a trait lets the logic on top be exercised against a fake tree.
+pub(crate) trait Backend: Send + Sync {
+    fn exists(&self, path: &Path) -> Result<bool>;
+    fn read_str(&self, path: &Path) -> Result<String>;
+    fn write_str(&self, path: &Path, data: &str) -> Result<()>;
+    fn list_dir(&self, path: &Path) -> Result<Vec<String>>;
+    fn create_dir(&self, path: &Path) -> Result<()>;
+    fn remove_dir(&self, path: &Path) -> Result<()>;
+    fn symlink(&self, target: &Path, link: &Path) -> Result<()>;
+    fn remove_link(&self, path: &Path) -> Result<()>;
+    /// Where the symlink at `path` points to, as stored in the link.
+    fn read_link(&self, path: &Path) -> Result<PathBuf>;
+
+    /// Ensure the path is a block device and find its canonical path and size.
+    fn resolve_block_device(&self, path: &Path) -> Result<ResolvedDevice>;
+}
+
+pub(crate) struct SysfsBackend;
+
+impl Backend for SysfsBackend {
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(path.try_exists()?)
+    }
+    fn read_str(&self, path: &Path) -> Result<String> {
+        read_str(path)
+    }
+    fn write_str(&self, path: &Path, data: &str) -> Result<()> {
+        write_str(path, data)
+    }
+    fn list_dir(&self, path: &Path) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            names.push(entry?.file_name().to_str().unwrap().to_owned());
+        }
+        Ok(names)
+    }
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        Ok(std::fs::create_dir(path)?)
+    }
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        Ok(std::fs::remove_dir(path)?)
+    }
+    fn symlink(&self, target: &Path, link: &Path) -> Result<()> {
+        Ok(std::os::unix::fs::symlink(target, link)?)
+    }
+    fn remove_link(&self, path: &Path) -> Result<()> {
+        Ok(std::fs::remove_file(path)?)
+    }
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        Ok(std::fs::read_link(path)?)
+    }
+
+    fn resolve_block_device(&self, path: &Path) -> Result<ResolvedDevice> {
+        crate::helpers::resolve_block_device(path)
+    }
+}
+
+/// The backend for the real configfs tree.
+pub(crate) fn system_backend() -> Arc<dyn Backend> {
+    if effective_uid() == Some(0) {
+        Arc::new(SysfsBackend)
+    } else {
+        Arc::new(Unprivileged(Arc::new(SysfsBackend)))
+    }
+}
+
+/// Reports permission errors of the wrapped backend as `Error::PermissionDenied`.
+///
+/// Only used when not running as root, as the kernel also refuses some writes with EACCES,
+/// like changing the address of an enabled port, which has nothing to do with privileges.
+pub(crate) struct Unprivileged(pub(crate) Arc<dyn Backend>);
+
+impl Unprivileged {
+    fn check<T>(path: &Path, result: Result<T>) -> Result<T> {
+        match result {
+            Err(Error::Io(err)) if err.kind() == ErrorKind::PermissionDenied => {
+                Err(Error::PermissionDenied(path.display().to_string()))
+            }
+            result => result,
+        }
+    }
+}
+
+impl Backend for Unprivileged {
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Self::check(path, self.0.exists(path))
+    }
+    fn read_str(&self, path: &Path) -> Result<String> {
+        Self::check(path, self.0.read_str(path))
+    }
+    fn write_str(&self, path: &Path, data: &str) -> Result<()> {
+        Self::check(path, self.0.write_str(path, data))
+    }
+    fn list_dir(&self, path: &Path) -> Result<Vec<String>> {
+        Self::check(path, self.0.list_dir(path))
+    }
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        Self::check(path, self.0.create_dir(path))
+    }
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        Self::check(path, self.0.remove_dir(path))
+    }
+    fn symlink(&self, target: &Path, link: &Path) -> Result<()> {
+        Self::check(link, self.0.symlink(target, link))
+    }
+    fn remove_link(&self, path: &Path) -> Result<()> {
+        Self::check(path, self.0.remove_link(path))
+    }
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        Self::check(path, self.0.read_link(path))
+    }
+
+    fn resolve_block_device(&self, path: &Path) -> Result<ResolvedDevice> {
+        Self::check(path, self.0.resolve_block_device(path))
+    }
+}
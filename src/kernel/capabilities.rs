@@ -0,0 +1,243 @@
+use super::sysfs::{NvmetRoot, ObjectKind};
+use super::KernelConfig;
+use crate::errors::{Context, Result};
+use crate::state::State;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+static KERNEL_RELEASE: &str = "/proc/sys/kernel/osrelease";
+static NVMET_DEBUGFS: &str = "/sys/kernel/debug/nvmet";
+
+/// Optional nvmet features, which depend on the kernel version and configuration.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// End-to-end protection information on ports (param_pi_enable).
+    PiEnable,
+    /// Configurable maximum queue size of ports (param_max_queue_size).
+    MaxQueueSize,
+    /// Configurable inline data size of ports (param_inline_data_size).
+    InlineDataSize,
+    /// Transport specific address subtype of ports (addr_tsas).
+    Tsas,
+    /// Asymmetric namespace access groups on ports (ana_groups).
+    Ana,
+    /// Configurable controller ID range of subsystems (attr_cntlid_min/max).
+    CntlidRange,
+    /// Configurable maximum number of I/O queues of subsystems (attr_qid_max).
+    QidMax,
+    /// Configurable firmware revision of subsystems (attr_firmware).
+    Firmware,
+    /// Configurable IEEE OUI of subsystems (attr_ieee_oui).
+    IeeeOui,
+    /// NVMe passthrough subsystems (passthru).
+    Passthru,
+    /// Buffered I/O for file backed namespaces (buffered_io).
+    BufferedIo,
+    /// Revalidating the size of namespaces (revalidate_size).
+    RevalidateSize,
+    /// In-band authentication of hosts (dhchap_key).
+    Authentication,
+    /// Debugging information in /sys/kernel/debug/nvmet.
+    Debugfs,
+}
+
+impl Feature {
+    pub const ALL: [Self; 14] = [
+        Self::PiEnable,
+        Self::MaxQueueSize,
+        Self::InlineDataSize,
+        Self::Tsas,
+        Self::Ana,
+        Self::CntlidRange,
+        Self::QidMax,
+        Self::Firmware,
+        Self::IeeeOui,
+        Self::Passthru,
+        Self::BufferedIo,
+        Self::RevalidateSize,
+        Self::Authentication,
+        Self::Debugfs,
+    ];
+
+    /// Name of the feature, as used in the JSON output.
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::PiEnable => "pi_enable",
+            Self::MaxQueueSize => "max_queue_size",
+            Self::InlineDataSize => "inline_data_size",
+            Self::Tsas => "tsas",
+            Self::Ana => "ana",
+            Self::CntlidRange => "cntlid_range",
+            Self::QidMax => "qid_max",
+            Self::Firmware => "firmware",
+            Self::IeeeOui => "ieee_oui",
+            Self::Passthru => "passthru",
+            Self::BufferedIo => "buffered_io",
+            Self::RevalidateSize => "revalidate_size",
+            Self::Authentication => "authentication",
+            Self::Debugfs => "debugfs",
+        }
+    }
+
+    // The configfs entry indicating support, if the feature shows up in configfs at all.
+    const fn configfs_entry(&self) -> Option<(ObjectKind, &'static str)> {
+        match self {
+            Self::PiEnable => Some((ObjectKind::Port, "param_pi_enable")),
+            Self::MaxQueueSize => Some((ObjectKind::Port, "param_max_queue_size")),
+            Self::InlineDataSize => Some((ObjectKind::Port, "param_inline_data_size")),
+            Self::Tsas => Some((ObjectKind::Port, "addr_tsas")),
+            Self::Ana => Some((ObjectKind::Port, "ana_groups")),
+            Self::CntlidRange => Some((ObjectKind::Subsystem, "attr_cntlid_min")),
+            Self::QidMax => Some((ObjectKind::Subsystem, "attr_qid_max")),
+            Self::Firmware => Some((ObjectKind::Subsystem, "attr_firmware")),
+            Self::IeeeOui => Some((ObjectKind::Subsystem, "attr_ieee_oui")),
+            Self::Passthru => Some((ObjectKind::Subsystem, "passthru")),
+            Self::BufferedIo => Some((ObjectKind::Namespace, "buffered_io")),
+            Self::RevalidateSize => Some((ObjectKind::Namespace, "revalidate_size")),
+            Self::Authentication => Some((ObjectKind::Host, "dhchap_key")),
+            Self::Debugfs => None,
+        }
+    }
+}
+
+/// A setting of a state that needs an optional feature, see `Capabilities::strip_unsupported`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FeatureUse {
+    pub feature: Feature,
+    /// The object using it, like `subsystem nqn.2023-11.sh.tty:sub`.
+    pub object: String,
+}
+
+impl fmt::Display for FeatureUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {}", self.feature.name(), self.object)
+    }
+}
+
+/// What the running kernel supports, see `KernelConfig::capabilities`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// The kernel release, if known.
+    pub kernel_version: Option<String>,
+    /// Whether each probed feature is supported.
+    pub features: BTreeMap<Feature, bool>,
+}
+
+impl Capabilities {
+    /// Whether `feature` is supported. Features that were not probed count as unsupported.
+    #[must_use]
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.get(&feature).copied().unwrap_or(false)
+    }
+
+    /// Unset the settings of `state` which need unsupported features, returning what was unset.
+    ///
+    /// As default capabilities support nothing, they unset every setting needing any feature.
+    pub fn strip_unsupported(&self, state: &mut State) -> Vec<FeatureUse> {
+        let mut stripped = Vec::new();
+        for (nqn, sub) in &mut state.subsystems {
+            if sub.qid_max.is_some() && !self.supports(Feature::QidMax) {
+                sub.qid_max = None;
+                stripped.push(FeatureUse {
+                    feature: Feature::QidMax,
+                    object: format!("subsystem {nqn}"),
+                });
+            }
+        }
+        stripped
+    }
+}
+
+impl KernelConfig {
+    /// Probe which optional features the running kernel supports.
+    ///
+    /// Existing objects are inspected where possible. If there are none, temporary ones are
+    /// created and removed again, which are never linked to a port.
+    pub fn capabilities() -> Result<Capabilities> {
+        // Probing may create temporary objects.
+        let mut caps = Self::system().mutate(Self::capabilities_in)?;
+        caps.kernel_version = std::fs::read_to_string(KERNEL_RELEASE)
+            .ok()
+            .map(|release| release.trim().to_string());
+        caps.features
+            .insert(Feature::Debugfs, Path::new(NVMET_DEBUGFS).exists());
+        Ok(caps)
+    }
+
+    pub(crate) fn capabilities_in(root: &NvmetRoot) -> Result<Capabilities> {
+        root.check_exists()?;
+        let mut caps = Capabilities::default();
+        for kind in [
+            ObjectKind::Port,
+            ObjectKind::Subsystem,
+            ObjectKind::Namespace,
+            ObjectKind::Host,
+        ] {
+            let entries: Vec<(Feature, &str)> = Feature::ALL
+                .iter()
+                .filter_map(|feature| match feature.configfs_entry() {
+                    Some((k, entry)) if k == kind => Some((*feature, entry)),
+                    _ => None,
+                })
+                .collect();
+            let names: Vec<&str> = entries.iter().map(|(_, entry)| *entry).collect();
+            let present = root
+                .probe_attrs(kind, &names)
+                .with_context(|| format!("Failed to probe {kind:?} attributes"))?;
+            for (feature, entry) in entries {
+                caps.features.insert(feature, present.contains(entry));
+            }
+        }
+        Ok(caps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::{FakeBackend, FakeOp};
+    use crate::kernel::tests::{example_state, SUB};
+    use crate::state::State;
+
+    #[test]
+    fn test_capabilities_temporary_objects() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let caps = KernelConfig::capabilities_in(&root)?;
+        assert!(caps.supports(Feature::BufferedIo));
+        assert!(caps.supports(Feature::Authentication));
+        assert!(!caps.supports(Feature::PiEnable));
+        assert!(!caps.supports(Feature::Debugfs));
+
+        // Everything created for probing is gone again.
+        assert_eq!(KernelConfig::gather_state_in(&root)?, State::default());
+        assert!(root.list_all_hosts()?.is_empty());
+        assert!(fake
+            .take_ops()
+            .iter()
+            .any(|op| matches!(op, FakeOp::CreateDir(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_capabilities_existing_objects() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&example_state()))?;
+        fake.add_attr(&format!("subsystems/{SUB}/attr_qid_max"), "128");
+
+        fake.take_ops();
+        let caps = KernelConfig::capabilities_in(&root)?;
+        assert!(caps.supports(Feature::QidMax));
+        assert!(caps.supports(Feature::BufferedIo));
+        // Nothing is created when there are objects to inspect.
+        assert!(fake
+            .take_ops()
+            .iter()
+            .all(|op| matches!(op, FakeOp::Exists(_) | FakeOp::Read(_) | FakeOp::List(_))));
+        Ok(())
+    }
+}
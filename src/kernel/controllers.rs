@@ -0,0 +1,245 @@
+// Live controllers, as exposed by the kernel under /sys/kernel/debug/nvmet since Linux 6.10.
+// The debugfs format is not a stable interface, so files are read one by one and anything
+// missing, unreadable or unexpected is left out rather than failing the whole listing.
+
+use super::KernelConfig;
+use crate::errors::{Context, Error, Result};
+use serde::Serialize;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+
+static NVMET_DEBUGFS: &str = "/sys/kernel/debug/nvmet";
+
+/// A controller of a connected host, see `KernelConfig::list_controllers`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Controller {
+    /// NQN of the subsystem the host is connected to.
+    pub subsystem: String,
+    /// Controller ID.
+    pub id: u16,
+    /// NQN of the connected host.
+    pub host_nqn: Option<String>,
+    /// ID of the port the host connected through.
+    pub port: Option<u16>,
+    /// Address of the host, for transports which report one.
+    pub host_traddr: Option<String>,
+    /// Controller status, like `ready`.
+    pub state: Option<String>,
+    /// Keep alive timeout in seconds.
+    pub kato: Option<u32>,
+    /// Number of queues, for kernels which report them.
+    pub queues: Option<usize>,
+}
+
+impl KernelConfig {
+    /// List the controllers of connected hosts, of the subsystem `nqn` or all of them.
+    ///
+    /// Fails with `Error::NoDebugfs` if the kernel doesn't expose them.
+    pub fn list_controllers(nqn: Option<&str>) -> Result<Vec<Controller>> {
+        Self::list_controllers_in(Path::new(NVMET_DEBUGFS), nqn)
+    }
+
+    pub(crate) fn list_controllers_in(
+        debugfs: &Path,
+        nqn: Option<&str>,
+    ) -> Result<Vec<Controller>> {
+        let subsystems = match std::fs::read_dir(debugfs) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(Error::NoDebugfs(debugfs.display().to_string()));
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to list {}", debugfs.display()))
+            }
+        };
+        let mut controllers = Vec::new();
+        for entry in subsystems {
+            let entry = entry.with_context(|| format!("Failed to list {}", debugfs.display()))?;
+            let Ok(subsystem) = entry.file_name().into_string() else {
+                continue;
+            };
+            if nqn.is_some_and(|nqn| nqn != subsystem) || !entry.path().is_dir() {
+                continue;
+            }
+            controllers.extend(read_subsystem_controllers(&entry.path(), &subsystem)?);
+        }
+        controllers.sort_by(|a, b| (&a.subsystem, a.id).cmp(&(&b.subsystem, b.id)));
+        Ok(controllers)
+    }
+}
+
+fn read_subsystem_controllers(dir: &Path, subsystem: &str) -> Result<Vec<Controller>> {
+    let mut controllers = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to list {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to list {}", dir.display()))?;
+        let name = entry.file_name();
+        let Some(id) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("ctrl"))
+            .and_then(|id| id.parse().ok())
+        else {
+            continue;
+        };
+        let path = entry.path();
+        // The controller can disconnect while we are reading it.
+        if !path.is_dir() {
+            continue;
+        }
+        controllers.push(Controller {
+            subsystem: subsystem.to_string(),
+            id,
+            host_nqn: read_field(&path, "hostnqn"),
+            port: read_field(&path, "port").and_then(|port| first_number(&port)),
+            host_traddr: read_field(&path, "host_traddr"),
+            state: read_field(&path, "state"),
+            kato: read_field(&path, "kato").and_then(|kato| first_number(&kato)),
+            queues: count_queues(&path),
+        });
+    }
+    Ok(controllers)
+}
+
+/// The contents of a file of the controller, with whitespace collapsed, if readable and not empty.
+fn read_field(dir: &Path, name: &str) -> Option<String> {
+    let path = dir.join(name);
+    // Some files fail to read for transports which don't support them.
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            if err.kind() != ErrorKind::NotFound {
+                tracing::debug!("Failed to read {}: {err}", path.display());
+            }
+            return None;
+        }
+    };
+    let value = contents.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!value.is_empty()).then_some(value)
+}
+
+/// The first number in a value like `1` or `portid: 1`.
+fn first_number<T: FromStr>(value: &str) -> Option<T> {
+    value
+        .split(|c: char| c.is_whitespace() || matches!(c, ':' | '=' | ','))
+        .find_map(|token| token.parse().ok())
+}
+
+/// The number of queues, from a `queues` file with a line per queue or a directory per queue.
+fn count_queues(dir: &Path) -> Option<usize> {
+    let path = dir.join("queues");
+    if path.is_dir() {
+        return Some(std::fs::read_dir(path).ok()?.count());
+    }
+    let contents = std::fs::read_to_string(path).ok()?;
+    // Header lines, if any, don't start with the queue ID.
+    Some(
+        contents
+            .lines()
+            .filter(|line| line.trim_start().starts_with(|c: char| c.is_ascii_digit()))
+            .count(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use std::fs;
+
+    const SUB: &str = "nqn.2023-11.sh.tty:sub";
+    const OTHER: &str = "nqn.2023-11.sh.tty:other";
+    const HOST: &str = "nqn.2014-08.org.nvmexpress:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6";
+
+    fn fixture(name: &str, files: &[(&str, &str)]) -> TestDir {
+        let dir = TestDir::new(name);
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_list_controllers() -> Result<()> {
+        // Layout of Linux 6.10, and of a kernel with queues and files we don't know.
+        let dir = fixture(
+            "debugfs",
+            &[
+                (&format!("{SUB}/ctrl1/hostnqn"), &format!("{HOST}\n")),
+                (&format!("{SUB}/ctrl1/port"), "1\n"),
+                (&format!("{SUB}/ctrl1/kato"), "5\n"),
+                (&format!("{SUB}/ctrl1/state"), "ready\n"),
+                (&format!("{SUB}/ctrl1/host_traddr"), "192.0.2.7\n"),
+                (&format!("{SUB}/ctrl12/hostnqn"), HOST),
+                (&format!("{SUB}/ctrl12/port"), "portid: 2"),
+                (
+                    &format!("{SUB}/ctrl12/state"),
+                    "ready\nshutdown_in_progress\n",
+                ),
+                (
+                    &format!("{SUB}/ctrl12/queues"),
+                    "qid sqsize\n0 32\n1 128\n2 128\n",
+                ),
+                (&format!("{SUB}/ctrl12/tls_key"), "unknown\n"),
+                (&format!("{SUB}/not-a-ctrl/hostnqn"), HOST),
+                (&format!("{OTHER}/ctrl2/hostnqn"), HOST),
+                (&format!("{OTHER}/ctrl2/port"), "garbage"),
+                (&format!("{OTHER}/ctrl2/host_traddr"), ""),
+                (&format!("{OTHER}/ctrl2/queues/0/sqsize"), "32"),
+                (&format!("{OTHER}/ctrl2/queues/1/sqsize"), "128"),
+            ],
+        );
+
+        let controllers = KernelConfig::list_controllers_in(&dir, None)?;
+        assert_eq!(
+            controllers,
+            vec![
+                Controller {
+                    subsystem: OTHER.to_string(),
+                    id: 2,
+                    host_nqn: Some(HOST.to_string()),
+                    queues: Some(2),
+                    ..Default::default()
+                },
+                Controller {
+                    subsystem: SUB.to_string(),
+                    id: 1,
+                    host_nqn: Some(HOST.to_string()),
+                    port: Some(1),
+                    host_traddr: Some("192.0.2.7".to_string()),
+                    state: Some("ready".to_string()),
+                    kato: Some(5),
+                    queues: None,
+                },
+                Controller {
+                    subsystem: SUB.to_string(),
+                    id: 12,
+                    host_nqn: Some(HOST.to_string()),
+                    port: Some(2),
+                    host_traddr: None,
+                    state: Some("ready shutdown_in_progress".to_string()),
+                    kato: None,
+                    queues: Some(3),
+                },
+            ]
+        );
+
+        let controllers = KernelConfig::list_controllers_in(&dir, Some(SUB))?;
+        assert_eq!(controllers.len(), 2);
+        assert!(
+            KernelConfig::list_controllers_in(&dir, Some("nqn.2023-11.sh.tty:none"))?.is_empty()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_controllers_without_debugfs() {
+        let dir = fixture("no-debugfs", &[]);
+        let err = KernelConfig::list_controllers_in(&dir.join("nvmet"), None).unwrap_err();
+        assert!(matches!(err, Error::NoDebugfs(_)));
+        assert!(err.to_string().contains("debugfs support not available"));
+    }
+}
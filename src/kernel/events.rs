@@ -0,0 +1,719 @@
+// Watching the nvmet configfs tree for changes made by others.
+//
+// inotify reports what goes through the filesystem, which covers nvmetcli, scripts and humans
+// alike. Every directory needs a watch of its own, so watches are added for new directories as
+// they show up. Whatever was created inside them before their watch was in place is found by
+// scanning them, and the set of known objects keeps that from being reported twice.
+//
+// When nvmet is unloaded, the whole tree disappears at once. Its parent stays watched, so
+// the tree is picked up again once the module is back.
+
+use super::sysfs::NVMET_ROOT;
+use super::KernelConfig;
+use crate::errors::{Context, Error, Result};
+use inotify::{EventMask, EventOwned, Inotify, WatchDescriptor, WatchMask};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fmt::{self, Display};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// A change to the nvmet configuration, see `KernelConfig::watch_events`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    PortCreated {
+        port: u16,
+    },
+    PortRemoved {
+        port: u16,
+    },
+    PortChanged {
+        port: u16,
+        attr: String,
+    },
+    /// The subsystem was made available on the port.
+    SubsystemLinked {
+        port: u16,
+        subsystem: String,
+    },
+    SubsystemUnlinked {
+        port: u16,
+        subsystem: String,
+    },
+    SubsystemCreated {
+        subsystem: String,
+    },
+    SubsystemRemoved {
+        subsystem: String,
+    },
+    SubsystemChanged {
+        subsystem: String,
+        attr: String,
+    },
+    NamespaceCreated {
+        subsystem: String,
+        nsid: u32,
+    },
+    NamespaceRemoved {
+        subsystem: String,
+        nsid: u32,
+    },
+    NamespaceEnabled {
+        subsystem: String,
+        nsid: u32,
+    },
+    NamespaceDisabled {
+        subsystem: String,
+        nsid: u32,
+    },
+    NamespaceChanged {
+        subsystem: String,
+        nsid: u32,
+        attr: String,
+    },
+    HostAllowed {
+        subsystem: String,
+        host: String,
+    },
+    HostDisallowed {
+        subsystem: String,
+        host: String,
+    },
+    HostCreated {
+        host: String,
+    },
+    HostRemoved {
+        host: String,
+    },
+    HostChanged {
+        host: String,
+        attr: String,
+    },
+    /// The nvmet tree disappeared, usually because the module was unloaded.
+    Unloaded,
+    /// The nvmet tree appeared again.
+    Loaded,
+    /// Events were lost because too many happened at once.
+    Overflow,
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PortCreated { port } => write!(f, "Port {port} created"),
+            Self::PortRemoved { port } => write!(f, "Port {port} removed"),
+            Self::PortChanged { port, attr } => write!(f, "Port {port} {attr} changed"),
+            Self::SubsystemLinked { port, subsystem } => {
+                write!(f, "Subsystem {subsystem} added to port {port}")
+            }
+            Self::SubsystemUnlinked { port, subsystem } => {
+                write!(f, "Subsystem {subsystem} removed from port {port}")
+            }
+            Self::SubsystemCreated { subsystem } => write!(f, "Subsystem {subsystem} created"),
+            Self::SubsystemRemoved { subsystem } => write!(f, "Subsystem {subsystem} removed"),
+            Self::SubsystemChanged { subsystem, attr } => {
+                write!(f, "Subsystem {subsystem} {attr} changed")
+            }
+            Self::NamespaceCreated { subsystem, nsid } => {
+                write!(f, "Namespace {nsid} of {subsystem} created")
+            }
+            Self::NamespaceRemoved { subsystem, nsid } => {
+                write!(f, "Namespace {nsid} of {subsystem} removed")
+            }
+            Self::NamespaceEnabled { subsystem, nsid } => {
+                write!(f, "Namespace {nsid} of {subsystem} enabled")
+            }
+            Self::NamespaceDisabled { subsystem, nsid } => {
+                write!(f, "Namespace {nsid} of {subsystem} disabled")
+            }
+            Self::NamespaceChanged {
+                subsystem,
+                nsid,
+                attr,
+            } => write!(f, "Namespace {nsid} of {subsystem} {attr} changed"),
+            Self::HostAllowed { subsystem, host } => {
+                write!(f, "Host {host} allowed on {subsystem}")
+            }
+            Self::HostDisallowed { subsystem, host } => {
+                write!(f, "Host {host} no longer allowed on {subsystem}")
+            }
+            Self::HostCreated { host } => write!(f, "Host {host} created"),
+            Self::HostRemoved { host } => write!(f, "Host {host} removed"),
+            Self::HostChanged { host, attr } => write!(f, "Host {host} {attr} changed"),
+            Self::Unloaded => write!(f, "nvmet was unloaded"),
+            Self::Loaded => write!(f, "nvmet was loaded"),
+            Self::Overflow => write!(f, "Events were lost, too many happened at once"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Created,
+    Removed,
+    Written,
+}
+
+const DIR_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::CLOSE_WRITE)
+    .union(WatchMask::ONLYDIR);
+const PARENT_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::ONLYDIR);
+
+/// Reports changes to the nvmet configuration as they happen.
+pub struct EventWatcher {
+    inotify: Inotify,
+    root: PathBuf,
+    root_name: OsString,
+    parent: WatchDescriptor,
+    // Watched directories, relative to the root.
+    watches: BTreeMap<WatchDescriptor, PathBuf>,
+    // Directories and links seen so far, relative to the root.
+    known: BTreeSet<PathBuf>,
+    loaded: bool,
+    buffer: Vec<u8>,
+}
+
+impl KernelConfig {
+    /// Start watching the nvmet configuration for changes.
+    pub fn watch_events() -> Result<EventWatcher> {
+        EventWatcher::new_in(NVMET_ROOT)
+    }
+}
+
+impl EventWatcher {
+    pub(crate) fn new_in<P: Into<PathBuf>>(root: P) -> Result<Self> {
+        let root: PathBuf = root.into();
+        if !root.try_exists()? {
+            return Err(Error::NoNvmetSysfs);
+        }
+        let (Some(parent), Some(root_name)) = (root.parent(), root.file_name()) else {
+            return Err(Error::NoNvmetSysfs);
+        };
+        let inotify = Inotify::init().context("Failed to initialize inotify")?;
+        let parent = inotify
+            .watches()
+            .add(parent, PARENT_MASK)
+            .with_context(|| format!("Failed to watch {}", parent.display()))?;
+        let mut watcher = Self {
+            inotify,
+            root_name: root_name.to_owned(),
+            root,
+            parent,
+            watches: BTreeMap::new(),
+            known: BTreeSet::new(),
+            loaded: true,
+            buffer: vec![0; 4096],
+        };
+        watcher.scan(Path::new(""), None)?;
+        Ok(watcher)
+    }
+
+    /// Wait for changes and return them.
+    pub fn wait(&mut self) -> Result<Vec<Event>> {
+        loop {
+            let raw: Vec<EventOwned> = self
+                .inotify
+                .read_events_blocking(&mut self.buffer)
+                .context("Failed to read events")?
+                .map(|event| event.to_owned())
+                .collect();
+            let events = self.process(raw)?;
+            if !events.is_empty() {
+                return Ok(events);
+            }
+        }
+    }
+
+    /// Wait for changes like `wait`, but for at most `timeout`. Returns none if nothing changed.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Vec<Event>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let mut fd = libc::pollfd {
+                fd: self.inotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // Rounded up, so as not to wake up just before the deadline.
+            let millis = libc::c_int::try_from(left.as_nanos().div_ceil(1_000_000))
+                .unwrap_or(libc::c_int::MAX);
+            // SAFETY: fd is a single pollfd, for an inotify instance that lives as long as self.
+            let ready = unsafe { libc::poll(&mut fd, 1, millis) };
+            if ready < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err).context("Failed to wait for events");
+            }
+            if ready == 0 {
+                return Ok(Vec::new());
+            }
+            let events = self.poll()?;
+            if !events.is_empty() {
+                return Ok(events);
+            }
+        }
+    }
+
+    /// Return the changes that happened so far, without waiting.
+    fn poll(&mut self) -> Result<Vec<Event>> {
+        let raw: Vec<EventOwned> = match self.inotify.read_events(&mut self.buffer) {
+            Ok(events) => events.map(|event| event.to_owned()).collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        self.process(raw)
+    }
+
+    fn process(&mut self, raw: Vec<EventOwned>) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+        for event in raw {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                // Pick up directories created in the meantime.
+                events.push(Event::Overflow);
+                if self.loaded {
+                    self.scan(Path::new(""), None)?;
+                }
+                continue;
+            }
+            if event.wd == self.parent {
+                if event.name.as_ref() == Some(&self.root_name) {
+                    if event.mask.contains(EventMask::CREATE) {
+                        self.load(&mut events)?;
+                    } else if event.mask.contains(EventMask::DELETE) {
+                        self.unload(&mut events);
+                    }
+                }
+                continue;
+            }
+            let Some(dir) = self.watches.get(&event.wd).cloned() else {
+                continue;
+            };
+            if event.mask.contains(EventMask::IGNORED) {
+                self.watches.remove(&event.wd);
+                if dir.as_os_str().is_empty() {
+                    self.unload(&mut events);
+                }
+                continue;
+            }
+            let Some(name) = event.name else {
+                continue;
+            };
+            let rel = dir.join(name);
+            if event.mask.contains(EventMask::CREATE) {
+                if event.mask.contains(EventMask::ISDIR) {
+                    if self.known.insert(rel.clone()) {
+                        events.extend(self.translate(&rel, Change::Created));
+                    }
+                    self.scan(&rel, Some(&mut events))?;
+                } else if self.is_link(&rel) && self.known.insert(rel.clone()) {
+                    events.extend(self.translate(&rel, Change::Created));
+                }
+            } else if event.mask.contains(EventMask::DELETE) {
+                if self.known.remove(&rel) {
+                    events.extend(self.translate(&rel, Change::Removed));
+                    self.known.retain(|path| !path.starts_with(&rel));
+                }
+            } else if event.mask.contains(EventMask::CLOSE_WRITE) {
+                events.extend(self.translate(&rel, Change::Written));
+            }
+        }
+        Ok(events)
+    }
+
+    fn load(&mut self, events: &mut Vec<Event>) -> Result<()> {
+        if self.loaded {
+            return Ok(());
+        }
+        self.loaded = true;
+        events.push(Event::Loaded);
+        self.scan(Path::new(""), Some(events))
+    }
+
+    fn unload(&mut self, events: &mut Vec<Event>) {
+        if !self.loaded {
+            return;
+        }
+        self.loaded = false;
+        events.push(Event::Unloaded);
+        for (wd, _) in std::mem::take(&mut self.watches) {
+            // Most watches are already gone together with their directories.
+            let _ = self.inotify.watches().remove(wd);
+        }
+        self.known.clear();
+    }
+
+    fn is_link(&self, rel: &Path) -> bool {
+        self.root
+            .join(rel)
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_symlink())
+    }
+
+    // Watch the directory and everything below it. New objects are reported if `events` is given.
+    fn scan(&mut self, rel: &Path, mut events: Option<&mut Vec<Event>>) -> Result<()> {
+        let path = self.root.join(rel);
+        let wd = match self.inotify.watches().add(&path, DIR_MASK) {
+            Ok(wd) => wd,
+            // Already gone again, its removal is still to be processed.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to watch {}", path.display()))
+            }
+        };
+        self.watches.insert(wd, rel.to_path_buf());
+
+        let entries = match std::fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if !file_type.is_dir() && !file_type.is_symlink() {
+                continue;
+            }
+            let child = rel.join(entry.file_name());
+            if self.known.insert(child.clone()) {
+                if let Some(events) = events.as_deref_mut() {
+                    events.extend(self.translate(&child, Change::Created));
+                }
+            }
+            if file_type.is_dir() {
+                self.scan(&child, events.as_deref_mut())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn translate(&self, rel: &Path, change: Change) -> Option<Event> {
+        let parts: Vec<String> = rel
+            .iter()
+            .map(|part| part.to_string_lossy().into_owned())
+            .collect();
+        let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+        let event = match (parts.as_slice(), change) {
+            (["ports", port], Change::Created) => Event::PortCreated {
+                port: port.parse().ok()?,
+            },
+            (["ports", port], Change::Removed) => Event::PortRemoved {
+                port: port.parse().ok()?,
+            },
+            (["ports", port, attr], Change::Written) => Event::PortChanged {
+                port: port.parse().ok()?,
+                attr: attr.to_string(),
+            },
+            (["ports", port, "subsystems", sub], Change::Created) => Event::SubsystemLinked {
+                port: port.parse().ok()?,
+                subsystem: sub.to_string(),
+            },
+            (["ports", port, "subsystems", sub], Change::Removed) => Event::SubsystemUnlinked {
+                port: port.parse().ok()?,
+                subsystem: sub.to_string(),
+            },
+            (["subsystems", sub], Change::Created) => Event::SubsystemCreated {
+                subsystem: sub.to_string(),
+            },
+            (["subsystems", sub], Change::Removed) => Event::SubsystemRemoved {
+                subsystem: sub.to_string(),
+            },
+            (["subsystems", sub, attr], Change::Written) => Event::SubsystemChanged {
+                subsystem: sub.to_string(),
+                attr: attr.to_string(),
+            },
+            (["subsystems", sub, "namespaces", nsid], Change::Created) => Event::NamespaceCreated {
+                subsystem: sub.to_string(),
+                nsid: nsid.parse().ok()?,
+            },
+            (["subsystems", sub, "namespaces", nsid], Change::Removed) => Event::NamespaceRemoved {
+                subsystem: sub.to_string(),
+                nsid: nsid.parse().ok()?,
+            },
+            (["subsystems", sub, "namespaces", nsid, attr], Change::Written) => {
+                let subsystem = sub.to_string();
+                let nsid = nsid.parse().ok()?;
+                // The new value tells enabling and disabling apart.
+                let enabled = std::fs::read_to_string(self.root.join(rel))
+                    .ok()
+                    .map(|value| value.trim() == "1");
+                match (*attr, enabled) {
+                    ("enable", Some(true)) => Event::NamespaceEnabled { subsystem, nsid },
+                    ("enable", Some(false)) => Event::NamespaceDisabled { subsystem, nsid },
+                    _ => Event::NamespaceChanged {
+                        subsystem,
+                        nsid,
+                        attr: attr.to_string(),
+                    },
+                }
+            }
+            (["subsystems", sub, "allowed_hosts", host], Change::Created) => Event::HostAllowed {
+                subsystem: sub.to_string(),
+                host: host.to_string(),
+            },
+            (["subsystems", sub, "allowed_hosts", host], Change::Removed) => {
+                Event::HostDisallowed {
+                    subsystem: sub.to_string(),
+                    host: host.to_string(),
+                }
+            }
+            (["hosts", host], Change::Created) => Event::HostCreated {
+                host: host.to_string(),
+            },
+            (["hosts", host], Change::Removed) => Event::HostRemoved {
+                host: host.to_string(),
+            },
+            (["hosts", host, attr], Change::Written) => Event::HostChanged {
+                host: host.to_string(),
+                attr: attr.to_string(),
+            },
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    const SUB: &str = "nqn.2023-11.sh.tty:events";
+    const HOST: &str = "nqn.2023-11.sh.tty:events-host";
+
+    // A tree looking like nvmet's, below a directory of its own.
+    fn test_tree(name: &str) -> (TestDir, PathBuf) {
+        let base = TestDir::new(name);
+        let root = base.join("nvmet");
+        for dir in ["ports", "subsystems", "hosts"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        (base, root)
+    }
+
+    fn create_subsystem(root: &Path, nqn: &str) {
+        let sub = root.join("subsystems").join(nqn);
+        fs::create_dir(&sub).unwrap();
+        fs::create_dir(sub.join("namespaces")).unwrap();
+        fs::create_dir(sub.join("allowed_hosts")).unwrap();
+    }
+
+    fn ns(nsid: u32) -> (String, u32) {
+        (SUB.to_string(), nsid)
+    }
+
+    #[test]
+    fn test_watch_changes() -> Result<()> {
+        let (_base, root) = test_tree("events-changes");
+        let mut watcher = EventWatcher::new_in(&root)?;
+        let sub = root.join("subsystems").join(SUB);
+
+        create_subsystem(&root, SUB);
+        assert_eq!(
+            watcher.poll()?,
+            [Event::SubsystemCreated {
+                subsystem: SUB.to_string()
+            }]
+        );
+
+        let (subsystem, nsid) = ns(1);
+        fs::create_dir(sub.join("namespaces/1"))?;
+        assert_eq!(
+            watcher.poll()?,
+            [Event::NamespaceCreated {
+                subsystem: subsystem.clone(),
+                nsid
+            }]
+        );
+        fs::write(sub.join("namespaces/1/device_path"), "/dev/loop0")?;
+        fs::write(sub.join("namespaces/1/enable"), "1")?;
+        let mut events = watcher.poll()?;
+        // Identical events in a row are merged, so the value is read after each.
+        fs::write(sub.join("namespaces/1/enable"), "0")?;
+        events.extend(watcher.poll()?);
+        assert_eq!(
+            events,
+            [
+                Event::NamespaceChanged {
+                    subsystem: subsystem.clone(),
+                    nsid,
+                    attr: "device_path".to_string()
+                },
+                Event::NamespaceEnabled {
+                    subsystem: subsystem.clone(),
+                    nsid
+                },
+                Event::NamespaceDisabled {
+                    subsystem: subsystem.clone(),
+                    nsid
+                },
+            ]
+        );
+
+        fs::create_dir(root.join("hosts").join(HOST))?;
+        symlink(
+            root.join("hosts").join(HOST),
+            sub.join("allowed_hosts").join(HOST),
+        )?;
+        fs::create_dir(root.join("ports/2"))?;
+        fs::create_dir(root.join("ports/2/subsystems"))?;
+        symlink(&sub, root.join("ports/2/subsystems").join(SUB))?;
+        let events = watcher.poll()?;
+        assert_eq!(
+            events,
+            [
+                Event::HostCreated {
+                    host: HOST.to_string()
+                },
+                Event::HostAllowed {
+                    subsystem: SUB.to_string(),
+                    host: HOST.to_string()
+                },
+                Event::PortCreated { port: 2 },
+                Event::SubsystemLinked {
+                    port: 2,
+                    subsystem: SUB.to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            events[1].to_string(),
+            format!("Host {HOST} allowed on {SUB}")
+        );
+        assert_eq!(
+            serde_json::to_string(&events[2])?,
+            r#"{"event":"port_created","port":2}"#
+        );
+        fs::write(root.join("ports/2/addr_trtype"), "tcp")?;
+        assert_eq!(
+            watcher.poll()?,
+            [Event::PortChanged {
+                port: 2,
+                attr: "addr_trtype".to_string()
+            }]
+        );
+
+        fs::remove_file(root.join("ports/2/subsystems").join(SUB))?;
+        fs::remove_file(sub.join("allowed_hosts").join(HOST))?;
+        fs::remove_dir_all(&sub)?;
+        assert_eq!(
+            watcher.poll()?,
+            [
+                Event::SubsystemUnlinked {
+                    port: 2,
+                    subsystem: SUB.to_string()
+                },
+                Event::HostDisallowed {
+                    subsystem: SUB.to_string(),
+                    host: HOST.to_string()
+                },
+                Event::NamespaceRemoved { subsystem, nsid },
+                Event::SubsystemRemoved {
+                    subsystem: SUB.to_string()
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_new_directories() -> Result<()> {
+        let (_base, root) = test_tree("events-new-dirs");
+        let mut watcher = EventWatcher::new_in(&root)?;
+        let sub = root.join("subsystems").join(SUB);
+
+        // Everything is created before the watcher gets to add a watch for the subsystem.
+        create_subsystem(&root, SUB);
+        fs::create_dir(sub.join("namespaces/1"))?;
+        fs::create_dir(root.join("hosts").join(HOST))?;
+        symlink(
+            root.join("hosts").join(HOST),
+            sub.join("allowed_hosts").join(HOST),
+        )?;
+        let events = watcher.poll()?;
+        for event in [
+            Event::SubsystemCreated {
+                subsystem: SUB.to_string(),
+            },
+            Event::NamespaceCreated {
+                subsystem: SUB.to_string(),
+                nsid: 1,
+            },
+            Event::HostAllowed {
+                subsystem: SUB.to_string(),
+                host: HOST.to_string(),
+            },
+        ] {
+            assert_eq!(events.iter().filter(|e| **e == event).count(), 1, "{event}");
+        }
+        assert_eq!(events.len(), 4);
+
+        // The namespace found by scanning is watched as well.
+        fs::write(sub.join("namespaces/1/enable"), "1")?;
+        assert_eq!(
+            watcher.poll()?,
+            [Event::NamespaceEnabled {
+                subsystem: SUB.to_string(),
+                nsid: 1
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_unload() -> Result<()> {
+        let (_base, root) = test_tree("events-unload");
+        create_subsystem(&root, SUB);
+        let mut watcher = EventWatcher::new_in(&root)?;
+
+        fs::remove_dir_all(&root)?;
+        let events = watcher.poll()?;
+        assert_eq!(events.last(), Some(&Event::Unloaded));
+        assert_eq!(events.iter().filter(|e| **e == Event::Unloaded).count(), 1);
+
+        fs::create_dir_all(root.join("subsystems"))?;
+        assert_eq!(watcher.poll()?, [Event::Loaded]);
+        create_subsystem(&root, SUB);
+        assert_eq!(
+            watcher.poll()?,
+            [Event::SubsystemCreated {
+                subsystem: SUB.to_string()
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_timeout() -> Result<()> {
+        let (_base, root) = test_tree("events-timeout");
+        let mut watcher = EventWatcher::new_in(&root)?;
+
+        let start = Instant::now();
+        assert!(watcher.wait_timeout(Duration::from_millis(50))?.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        create_subsystem(&root, SUB);
+        assert_eq!(
+            watcher.wait_timeout(Duration::from_secs(10))?,
+            [Event::SubsystemCreated {
+                subsystem: SUB.to_string()
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_missing_tree() {
+        let err = EventWatcher::new_in("/nonexistent/nvmet").err().unwrap();
+        assert!(matches!(err.root(), Error::NoNvmetSysfs));
+    }
+}
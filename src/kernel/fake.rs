@@ -0,0 +1,619 @@
+// An in-memory imitation of the nvmet configfs tree for testing.
+// It mirrors the parts of the kernel behaviour the sysfs layer relies on:
+// default attributes on mkdir, symlink targets, locked port addresses and so on.
+
+use super::backend::{Backend, Unprivileged};
+use super::sysfs::{resolve_link_target, NvmetRoot};
+use crate::errors::{Error, Result};
+use crate::helpers::ResolvedDevice;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+static FAKE_ROOT: &str = "/fake/nvmet";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Dir(BTreeMap<String, Node>),
+    Attr(String),
+    Link(PathBuf),
+}
+
+/// A single filesystem operation, with the path relative to the fake root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FakeOp {
+    Exists(String),
+    Read(String),
+    Write(String, String),
+    List(String),
+    CreateDir(String),
+    RemoveDir(String),
+    Symlink(String),
+    RemoveLink(String),
+    ReadLink(String),
+    /// A block device path was resolved.
+    ResolveDevice(PathBuf),
+}
+
+#[derive(Default)]
+struct FakeState {
+    tree: BTreeMap<String, Node>,
+    pinned: BTreeMap<String, String>,
+    failing: BTreeMap<String, u32>,
+    denied: BTreeSet<String>,
+    devices: BTreeMap<PathBuf, PathBuf>,
+    ops: Vec<FakeOp>,
+    counter: u128,
+}
+
+pub(crate) struct FakeBackend {
+    root: PathBuf,
+    state: Mutex<FakeState>,
+}
+
+// Fail like configfs would, with the errno the kernel returns.
+fn io_err(errno: i32) -> Error {
+    std::io::Error::from_raw_os_error(errno).into()
+}
+
+fn dir(entries: &[(&str, Node)]) -> Node {
+    Node::Dir(
+        entries
+            .iter()
+            .map(|(name, node)| ((*name).to_string(), node.clone()))
+            .collect(),
+    )
+}
+
+fn attr(value: &str) -> Node {
+    Node::Attr(value.to_string())
+}
+
+fn empty_dir() -> Node {
+    Node::Dir(BTreeMap::new())
+}
+
+impl FakeBackend {
+    pub(crate) fn new() -> Self {
+        let mut state = FakeState::default();
+        for group in ["ports", "subsystems", "hosts"] {
+            state.tree.insert(group.to_string(), empty_dir());
+        }
+        Self {
+            root: PathBuf::from(FAKE_ROOT),
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Create a fake backend and an `NvmetRoot` operating on it.
+    pub(crate) fn new_root() -> (Arc<Self>, NvmetRoot) {
+        let fake = Arc::new(Self::new());
+        let root = NvmetRoot::new(FAKE_ROOT, fake.clone());
+        (fake, root)
+    }
+
+    /// A root on this backend as used when not running as root.
+    pub(crate) fn unprivileged_root(self: &Arc<Self>) -> NvmetRoot {
+        NvmetRoot::new(FAKE_ROOT, Arc::new(Unprivileged(self.clone())))
+    }
+
+    /// Register a block device which namespaces may use.
+    pub(crate) fn add_device<P: Into<PathBuf>>(&self, path: P) {
+        let path = path.into();
+        self.state
+            .lock()
+            .unwrap()
+            .devices
+            .insert(path.clone(), path);
+    }
+
+    /// Register `path` as link to the block device `canonical`, like those in /dev/disk.
+    pub(crate) fn add_device_link<P: Into<PathBuf>>(&self, path: P, canonical: P) {
+        self.state
+            .lock()
+            .unwrap()
+            .devices
+            .insert(path.into(), canonical.into());
+    }
+
+    /// Read an attribute directly, bypassing the operation log.
+    pub(crate) fn attr(&self, rel: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        match lookup(&state.tree, &split(rel)) {
+            Some(Node::Attr(value)) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Set an attribute directly, like something outside of our control would.
+    pub(crate) fn set_attr(&self, rel: &str, value: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(Node::Attr(current)) = lookup_mut(&mut state.tree, &split(rel)) {
+            *current = value.to_string();
+        } else {
+            panic!("fake attribute {rel} does not exist");
+        }
+    }
+
+    /// Add an attribute the fake doesn't have by default, like one of a newer kernel.
+    pub(crate) fn add_attr(&self, rel: &str, value: &str) {
+        let mut parts = split(rel);
+        let name = parts.pop().unwrap();
+        let mut state = self.state.lock().unwrap();
+        dir_mut(&mut state.tree, &parts)
+            .expect("parent of fake attribute must exist")
+            .insert(name, attr(value));
+    }
+
+    /// Remove an attribute, like a half-deleted directory would be missing it.
+    pub(crate) fn remove_attr(&self, rel: &str) {
+        let mut parts = split(rel);
+        let name = parts.pop().unwrap();
+        let mut state = self.state.lock().unwrap();
+        dir_mut(&mut state.tree, &parts)
+            .expect("parent of fake attribute must exist")
+            .remove(&name);
+    }
+
+    /// Add a symlink to `target` directly, without the checks the kernel makes, like a
+    /// corrupted tree would have it. Relative targets are kept as they are.
+    pub(crate) fn add_link(&self, rel: &str, target: &str) {
+        let mut parts = split(rel);
+        let name = parts.pop().unwrap();
+        let target = if target.starts_with('.') {
+            PathBuf::from(target)
+        } else {
+            self.root.join(target)
+        };
+        let mut state = self.state.lock().unwrap();
+        dir_mut(&mut state.tree, &parts)
+            .expect("parent of fake link must exist")
+            .insert(name, Node::Link(target));
+    }
+
+    /// Create a group with its default attributes directly, bypassing the name checks of
+    /// nvmetcfg, like `hosts/not-an-nqn`.
+    pub(crate) fn add_group(&self, rel: &str) {
+        let mut parts = split(rel);
+        let name = parts.pop().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let node = state
+            .default_group(&parts, &name)
+            .expect("fake group must be of a known kind");
+        dir_mut(&mut state.tree, &parts)
+            .expect("parent of fake group must exist")
+            .insert(name, node);
+    }
+
+    /// Make writes to an attribute succeed without changing what it reads back as.
+    pub(crate) fn pin_attr(&self, rel: &str, value: &str) {
+        self.set_attr(rel, value);
+        let mut state = self.state.lock().unwrap();
+        state.pinned.insert(rel.to_string(), value.to_string());
+    }
+
+    /// Make the next `count` writes to an attribute fail with an I/O error.
+    pub(crate) fn fail_writes(&self, rel: &str, count: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.failing.insert(rel.to_string(), count);
+    }
+
+    /// Make listing, writing to and creating in a path fail with a permission error, like it
+    /// would for normal users.
+    pub(crate) fn deny(&self, rel: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.denied.insert(rel.to_string());
+    }
+
+    /// Whether the relative path exists, bypassing the operation log.
+    pub(crate) fn contains(&self, rel: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        lookup(&state.tree, &split(rel)).is_some()
+    }
+
+    /// Take all operations recorded so far.
+    pub(crate) fn take_ops(&self) -> Vec<FakeOp> {
+        std::mem::take(&mut self.state.lock().unwrap().ops)
+    }
+
+    fn relative(&self, path: &Path) -> Result<String> {
+        let rel = path
+            .strip_prefix(&self.root)
+            .map_err(|_| io_err(libc::ENOENT))?;
+        let mut parts = Vec::new();
+        for comp in rel.components() {
+            match comp {
+                Component::Normal(part) => parts.push(part.to_str().unwrap().to_string()),
+                // Path traversal out of the tree is not a thing configfs allows either.
+                _ => return Err(io_err(libc::EACCES)),
+            }
+        }
+        Ok(parts.join("/"))
+    }
+}
+
+fn split(rel: &str) -> Vec<String> {
+    rel.split('/')
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn lookup<'a>(tree: &'a BTreeMap<String, Node>, parts: &[String]) -> Option<&'a Node> {
+    let (first, rest) = parts.split_first()?;
+    let node = tree.get(first)?;
+    if rest.is_empty() {
+        return Some(node);
+    }
+    match node {
+        Node::Dir(children) => lookup(children, rest),
+        _ => None,
+    }
+}
+
+fn lookup_mut<'a>(tree: &'a mut BTreeMap<String, Node>, parts: &[String]) -> Option<&'a mut Node> {
+    let (first, rest) = parts.split_first()?;
+    let node = tree.get_mut(first)?;
+    if rest.is_empty() {
+        return Some(node);
+    }
+    match node {
+        Node::Dir(children) => lookup_mut(children, rest),
+        _ => None,
+    }
+}
+
+fn dir_mut<'a>(
+    tree: &'a mut BTreeMap<String, Node>,
+    parts: &[String],
+) -> Result<&'a mut BTreeMap<String, Node>> {
+    if parts.is_empty() {
+        return Ok(tree);
+    }
+    match lookup_mut(tree, parts) {
+        Some(Node::Dir(children)) => Ok(children),
+        Some(_) => Err(io_err(libc::ENOTDIR)),
+        None => Err(io_err(libc::ENOENT)),
+    }
+}
+
+fn has_link_to(tree: &BTreeMap<String, Node>, target: &Path) -> bool {
+    tree.values().any(|node| match node {
+        Node::Link(to) => to == target,
+        Node::Dir(children) => has_link_to(children, target),
+        Node::Attr(_) => false,
+    })
+}
+
+fn attr_str(tree: &BTreeMap<String, Node>, parts: &[String]) -> Option<String> {
+    match lookup(tree, parts) {
+        Some(Node::Attr(value)) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+fn is_empty_dir(tree: &BTreeMap<String, Node>, parts: &[String]) -> bool {
+    matches!(lookup(tree, parts), Some(Node::Dir(children)) if children.is_empty())
+}
+
+impl FakeState {
+    fn record(&mut self, op: FakeOp) {
+        self.ops.push(op);
+    }
+
+    fn next_counter(&mut self) -> u128 {
+        self.counter += 1;
+        self.counter
+    }
+
+    fn default_group(&mut self, parent: &[String], name: &str) -> Result<Node> {
+        let kind: Vec<&str> = parent.iter().map(String::as_str).collect();
+        match kind.as_slice() {
+            ["ports"] => {
+                name.parse::<u16>().map_err(|_| io_err(libc::EINVAL))?;
+                Ok(dir(&[
+                    ("addr_trtype", attr("")),
+                    ("addr_adrfam", attr("")),
+                    ("addr_traddr", attr("")),
+                    ("addr_trsvcid", attr("")),
+                    ("addr_treq", attr("not specified")),
+                    ("subsystems", empty_dir()),
+                    ("referrals", empty_dir()),
+                ]))
+            }
+            ["subsystems"] => {
+                let serial = format!("{:020x}", self.next_counter());
+                Ok(dir(&[
+                    ("attr_allow_any_host", attr("0")),
+                    ("attr_model", attr("Linux")),
+                    ("attr_serial", attr(&serial)),
+                    ("attr_version", attr("1.3")),
+                    ("allowed_hosts", empty_dir()),
+                    ("namespaces", empty_dir()),
+                ]))
+            }
+            ["subsystems", _, "namespaces"] => {
+                let nsid = name.parse::<u32>().map_err(|_| io_err(libc::EINVAL))?;
+                if nsid == 0 || nsid == 0xffff_ffff {
+                    return Err(io_err(libc::EINVAL));
+                }
+                let uuid = Uuid::from_u128(self.next_counter());
+                Ok(dir(&[
+                    ("enable", attr("0")),
+                    ("device_path", attr("")),
+                    ("device_uuid", attr(&uuid.hyphenated().to_string())),
+                    ("device_nguid", attr(&Uuid::nil().hyphenated().to_string())),
+                    ("buffered_io", attr("0")),
+                ]))
+            }
+            ["hosts"] => Ok(dir(&[
+                ("dhchap_key", attr("")),
+                ("dhchap_ctrl_key", attr("")),
+                ("dhchap_hash", attr("hmac(sha256)")),
+                ("dhchap_dhgroup", attr("null")),
+            ])),
+            _ => Err(io_err(libc::EACCES)),
+        }
+    }
+
+    // Reject writes the kernel would reject.
+    fn check_write(&self, parts: &[String], data: &str) -> Result<()> {
+        let kind: Vec<&str> = parts.iter().map(String::as_str).collect();
+        match kind.as_slice() {
+            ["ports", id, attr] if attr.starts_with("addr_") => {
+                // Addresses are locked while any subsystem is enabled on the port.
+                let subs = ["ports".to_string(), (*id).to_string(), "subsystems".into()];
+                if !is_empty_dir(&self.tree, &subs) {
+                    return Err(io_err(libc::EACCES));
+                }
+                if *attr == "addr_trtype" && !["loop", "tcp", "rdma", "fc"].contains(&data) {
+                    return Err(io_err(libc::EINVAL));
+                }
+                if *attr == "addr_adrfam" && !["ipv4", "ipv6", "ib", "fc"].contains(&data) {
+                    return Err(io_err(libc::EINVAL));
+                }
+                Ok(())
+            }
+            ["subsystems", nqn, "attr_allow_any_host"] => {
+                let hosts = [
+                    "subsystems".to_string(),
+                    (*nqn).to_string(),
+                    "allowed_hosts".into(),
+                ];
+                if data == "1" && !is_empty_dir(&self.tree, &hosts) {
+                    return Err(io_err(libc::EINVAL));
+                }
+                Ok(())
+            }
+            ["subsystems", nqn, "namespaces", nsid, attr] => {
+                let ns = |a: &str| {
+                    attr_str(
+                        &self.tree,
+                        &[
+                            "subsystems".to_string(),
+                            (*nqn).to_string(),
+                            "namespaces".to_string(),
+                            (*nsid).to_string(),
+                            a.to_string(),
+                        ],
+                    )
+                    .unwrap_or_default()
+                };
+                let enabled = ns("enable") == "1";
+                match *attr {
+                    "enable" => match data {
+                        "1" if ns("device_path").is_empty() => Err(io_err(libc::EINVAL)),
+                        "0" | "1" => Ok(()),
+                        _ => Err(io_err(libc::EINVAL)),
+                    },
+                    "device_path" | "device_uuid" | "device_nguid" if enabled => {
+                        Err(io_err(libc::EBUSY))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Backend for FakeBackend {
+    fn exists(&self, path: &Path) -> Result<bool> {
+        let rel = self.relative(path)?;
+        let mut state = self.state.lock().unwrap();
+        state.record(FakeOp::Exists(rel.clone()));
+        Ok(match lookup(&state.tree, &split(&rel)) {
+            // Like try_exists, follow symlinks.
+            Some(Node::Link(target)) => match self.relative(&resolve_link_target(path, target)) {
+                Ok(target) => lookup(&state.tree, &split(&target)).is_some(),
+                Err(_) => false,
+            },
+            Some(_) => true,
+            None => rel.is_empty(),
+        })
+    }
+
+    fn read_str(&self, path: &Path) -> Result<String> {
+        let rel = self.relative(path)?;
+        let mut state = self.state.lock().unwrap();
+        state.record(FakeOp::Read(rel.clone()));
+        match lookup(&state.tree, &split(&rel)) {
+            Some(Node::Attr(value)) => Ok(value.trim().to_string()),
+            Some(_) => Err(io_err(libc::EISDIR)),
+            None => Err(io_err(libc::ENOENT)),
+        }
+    }
+
+    fn write_str(&self, path: &Path, data: &str) -> Result<()> {
+        let rel = self.relative(path)?;
+        let parts = split(&rel);
+        let mut state = self.state.lock().unwrap();
+        state.record(FakeOp::Write(rel.clone(), data.to_string()));
+        if state.denied.contains(&rel) {
+            return Err(io_err(libc::EACCES));
+        }
+        match lookup(&state.tree, &parts) {
+            Some(Node::Attr(_)) => {}
+            // configfs does not allow creating new files.
+            Some(_) => return Err(io_err(libc::EISDIR)),
+            None => return Err(io_err(libc::EACCES)),
+        }
+        state.check_write(&parts, data)?;
+        if let Some(count) = state.failing.get_mut(&rel).filter(|count| **count > 0) {
+            *count -= 1;
+            return Err(io_err(libc::EIO));
+        }
+        if state.pinned.contains_key(&rel) {
+            return Ok(());
+        }
+        if let Some(Node::Attr(value)) = lookup_mut(&mut state.tree, &parts) {
+            *value = data.to_string();
+        }
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &Path) -> Result<Vec<String>> {
+        let rel = self.relative(path)?;
+        let mut state = self.state.lock().unwrap();
+        state.record(FakeOp::List(rel.clone()));
+        if state.denied.contains(&rel) {
+            return Err(io_err(libc::EACCES));
+        }
+        let parts = split(&rel);
+        // Attributes are files, but callers only ever care about the groups and links.
+        let children = dir_mut(&mut state.tree, &parts)?;
+        Ok(children
+            .iter()
+            .filter(|(_, node)| !matches!(node, Node::Attr(_)))
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        let rel = self.relative(path)?;
+        let mut parts = split(&rel);
+        let mut state = self.state.lock().unwrap();
+        state.record(FakeOp::CreateDir(rel.clone()));
+        if state
+            .denied
+            .contains(&parts[..parts.len().saturating_sub(1)].join("/"))
+        {
+            return Err(io_err(libc::EACCES));
+        }
+        let name = parts.pop().ok_or_else(|| io_err(libc::EEXIST))?;
+        if dir_mut(&mut state.tree, &parts)?.contains_key(&name) {
+            return Err(io_err(libc::EEXIST));
+        }
+        let node = state.default_group(&parts, &name)?;
+        dir_mut(&mut state.tree, &parts)?.insert(name, node);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        let rel = self.relative(path)?;
+        let mut parts = split(&rel);
+        let mut state = self.state.lock().unwrap();
+        state.record(FakeOp::RemoveDir(rel.clone()));
+        match lookup(&state.tree, &parts) {
+            Some(Node::Dir(children)) => {
+                // Default groups need to be emptied before the item can go away.
+                let busy = children
+                    .values()
+                    .any(|child| matches!(child, Node::Dir(c) if !c.is_empty()));
+                if busy {
+                    return Err(io_err(libc::ENOTEMPTY));
+                }
+            }
+            Some(_) => return Err(io_err(libc::ENOTDIR)),
+            None => return Err(io_err(libc::ENOENT)),
+        }
+        if parts.len() < 2 {
+            // The top level groups are not removable.
+            return Err(io_err(libc::EACCES));
+        }
+        if has_link_to(&state.tree, path) {
+            return Err(io_err(libc::EBUSY));
+        }
+        let name = parts.pop().unwrap();
+        dir_mut(&mut state.tree, &parts)?.remove(&name);
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> Result<()> {
+        let rel = self.relative(link)?;
+        let target_rel = self.relative(target)?;
+        let mut parts = split(&rel);
+        let mut state = self.state.lock().unwrap();
+        state.record(FakeOp::Symlink(rel.clone()));
+        if !matches!(lookup(&state.tree, &split(&target_rel)), Some(Node::Dir(_))) {
+            return Err(io_err(libc::ENOENT));
+        }
+        let kind: Vec<&str> = parts.iter().map(String::as_str).collect();
+        let allowed = match kind.as_slice() {
+            ["ports", _, "subsystems", _] => target_rel.starts_with("subsystems/"),
+            ["subsystems", nqn, "allowed_hosts", _] => {
+                let allow_any = attr_str(
+                    &state.tree,
+                    &split(&format!("subsystems/{nqn}/attr_allow_any_host")),
+                );
+                if allow_any.as_deref() == Some("1") {
+                    return Err(io_err(libc::EINVAL));
+                }
+                target_rel.starts_with("hosts/")
+            }
+            _ => false,
+        };
+        if !allowed {
+            return Err(io_err(libc::EACCES));
+        }
+        let name = parts.pop().unwrap();
+        let children = dir_mut(&mut state.tree, &parts)?;
+        if children.contains_key(&name) {
+            return Err(io_err(libc::EEXIST));
+        }
+        children.insert(name, Node::Link(target.to_path_buf()));
+        Ok(())
+    }
+
+    fn remove_link(&self, path: &Path) -> Result<()> {
+        let rel = self.relative(path)?;
+        let mut parts = split(&rel);
+        let mut state = self.state.lock().unwrap();
+        state.record(FakeOp::RemoveLink(rel.clone()));
+        match lookup(&state.tree, &parts) {
+            Some(Node::Link(_)) => {}
+            Some(_) => return Err(io_err(libc::EACCES)),
+            None => return Err(io_err(libc::ENOENT)),
+        }
+        let name = parts.pop().unwrap();
+        dir_mut(&mut state.tree, &parts)?.remove(&name);
+        Ok(())
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        let rel = self.relative(path)?;
+        let mut state = self.state.lock().unwrap();
+        state.record(FakeOp::ReadLink(rel.clone()));
+        match lookup(&state.tree, &split(&rel)) {
+            Some(Node::Link(target)) => Ok(target.clone()),
+            Some(_) => Err(io_err(libc::EINVAL)),
+            None => Err(io_err(libc::ENOENT)),
+        }
+    }
+
+    fn resolve_block_device(&self, path: &Path) -> Result<ResolvedDevice> {
+        let mut state = self.state.lock().unwrap();
+        state.record(FakeOp::ResolveDevice(path.to_path_buf()));
+        let canonical = state
+            .devices
+            .get(path)
+            .cloned()
+            .ok_or_else(|| Error::InvalidDevice(path.display().to_string()))?;
+        Ok(ResolvedDevice {
+            canonical,
+            rdev: 0,
+            size: None,
+        })
+    }
+}
@@ -0,0 +1,280 @@
+// A handle to an nvmet configfs tree, for embedders gathering and applying from several threads
+// or tasks at once. Gathering only reads, so it runs concurrently. Everything writing to the
+// tree is serialized, within the process by a mutex shared by all clones of the handle, and
+// optionally across processes by a lock file.
+
+use super::backend::system_backend;
+use super::sysfs::NvmetRoot;
+use super::{ApplyOptions, ApplyReport, GatherWarning};
+use crate::errors::{Context, Result};
+use crate::state::{State, StateDelta};
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+
+static SYSTEM: OnceLock<KernelConfig> = OnceLock::new();
+
+/// How often `KernelConfig::reconcile` tries again after failing to apply the state, for
+/// transient failures only, see `Error::is_transient`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    /// How long to wait between retries.
+    pub delay: Duration,
+}
+
+/// Handle to the nvmet configuration of the kernel.
+///
+/// Clones share the same lock, so mutations through any of them never interleave. The
+/// associated functions, like `KernelConfig::apply_delta`, use a handle to the system's
+/// configfs tree shared by the whole process.
+#[derive(Clone)]
+pub struct KernelConfig {
+    root: NvmetRoot,
+    lock_file: Option<PathBuf>,
+    retry: RetryPolicy,
+    mutations: Arc<Mutex<()>>,
+}
+
+impl Default for KernelConfig {
+    /// A new handle to the system's configfs tree, not sharing the lock of `KernelConfig::system`.
+    fn default() -> Self {
+        Self::with_root(NvmetRoot::system())
+    }
+}
+
+impl KernelConfig {
+    /// A handle to the nvmet configfs tree at `root`, usually /sys/kernel/config/nvmet.
+    #[must_use]
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self::with_root(NvmetRoot::new(root, system_backend()))
+    }
+
+    pub(crate) fn with_root(root: NvmetRoot) -> Self {
+        Self {
+            root,
+            lock_file: None,
+            retry: RetryPolicy::default(),
+            mutations: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The handle used by the associated functions, to the system's configfs tree.
+    pub fn system() -> &'static Self {
+        SYSTEM.get_or_init(Self::default)
+    }
+
+    /// Also hold an exclusive lock on the file at `path` while mutating, which is created if
+    /// missing, to serialize with other processes doing the same.
+    #[must_use]
+    pub fn with_lock_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.lock_file = Some(path.into());
+        self
+    }
+
+    /// Retry reconciling as given by `retry`, unless the `ApplyOptions` ask for retries.
+    #[must_use]
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    #[must_use]
+    pub fn retry(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    /// Gather the state, like `KernelConfig::gather_state`.
+    pub fn gather(&self) -> Result<State> {
+        Self::gather_state_in(&self.root)
+    }
+
+    /// Gather the state skipping unreadable subsystems, like `KernelConfig::gather_state_partial`.
+    pub fn gather_partial(&self) -> Result<(State, Vec<GatherWarning>)> {
+        Self::gather_state_partial_in(&self.root)
+    }
+
+    /// Apply the changes, like `KernelConfig::apply_delta`.
+    pub fn apply(&self, changes: Vec<StateDelta>) -> Result<()> {
+        self.mutate(|root| Self::apply_delta_in(root, changes))
+    }
+
+    /// Apply changes computed against `base`, like `KernelConfig::apply_delta_checked`.
+    pub fn apply_checked(&self, base: &State, changes: Vec<StateDelta>) -> Result<()> {
+        self.mutate(|root| Self::apply_delta_checked_in(root, base, changes))
+    }
+
+    /// Reconcile the state with `desired`, like `KernelConfig::apply_state`.
+    ///
+    /// Gathering the current state is part of it, so it is serialized with other mutations too.
+    pub fn reconcile(&self, desired: &State, mut opts: ApplyOptions) -> Result<ApplyReport> {
+        if opts.retries == 0 {
+            opts.retries = self.retry.retries;
+            opts.retry_delay = self.retry.delay;
+        }
+        self.mutate(|root| Self::apply_state_in(root, desired, &opts))
+    }
+
+    /// Run `mutation` holding the mutex and the lock file, if any.
+    pub(crate) fn mutate<T>(&self, mutation: impl FnOnce(&NvmetRoot) -> Result<T>) -> Result<T> {
+        // Nothing is protected by the mutex itself, so a panic while holding it is harmless.
+        let _guard = self
+            .mutations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let _lock = self.lock_file.as_deref().map(lock_file).transpose()?;
+        mutation(&self.root)
+    }
+}
+
+/// Open the file at `path` and lock it exclusively, which lasts until it is closed.
+fn lock_file(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+    flock(&file, libc::LOCK_EX).with_context(|| format!("Failed to lock {}", path.display()))?;
+    Ok(file)
+}
+
+/// Apply or remove an advisory lock on `file`, see flock(2), retrying if interrupted.
+fn flock(file: &File, operation: libc::c_int) -> std::io::Result<()> {
+    loop {
+        // SAFETY: flock only takes the descriptor of file, which is open while borrowed.
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use crate::kernel::fake::{FakeBackend, FakeOp};
+    use crate::kernel::tests::{example_state, SUB};
+    use crate::state::Subsystem;
+
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 20;
+
+    fn nqn(thread: usize) -> String {
+        format!("{SUB}-{thread}")
+    }
+
+    fn subsystem() -> Subsystem {
+        let mut sub = example_state().subsystems[SUB].clone();
+        sub.allowed_hosts.clear();
+        for nsid in 2..=4 {
+            sub.namespaces.insert(nsid, sub.namespaces[&1].clone());
+        }
+        sub
+    }
+
+    /// Adding then removing the subsystem of `thread`, against the state before each.
+    fn rounds(thread: usize) -> Vec<(State, Vec<StateDelta>)> {
+        let empty = State::default();
+        let mut added = State::default();
+        added.subsystems.insert(nqn(thread), subsystem());
+        vec![
+            (empty.clone(), empty.get_deltas(&added)),
+            (added.clone(), added.get_deltas(&empty)),
+        ]
+    }
+
+    #[test]
+    fn test_concurrent_applies_serialized() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let config = KernelConfig::with_root(root);
+
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let config = config.clone();
+                scope.spawn(move || {
+                    for _ in 0..ROUNDS {
+                        for (base, changes) in rounds(thread) {
+                            config.apply_checked(&base, changes).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        // The operations are those of applying one after the other, in the order each apply
+        // started in. Replay that on a fresh tree to get them.
+        let ops = fake.take_ops();
+        let (replay, replay_root) = FakeBackend::new_root();
+        replay.add_device("/dev/loop0");
+        let replay_config = KernelConfig::with_root(replay_root);
+        let mut applied = [0; THREADS];
+        let mut rest = ops.as_slice();
+        // Each apply checks its subsystem first, after resolving the devices of its namespaces.
+        while let Some(FakeOp::Exists(first)) = rest
+            .iter()
+            .find(|op| !matches!(op, FakeOp::ResolveDevice(_)))
+        {
+            let thread = (0..THREADS)
+                .find(|&thread| *first == format!("subsystems/{}", nqn(thread)))
+                .unwrap_or_else(|| panic!("{first} is not the start of an apply"));
+            let (base, changes) = rounds(thread).swap_remove(applied[thread] % 2);
+            replay_config.apply_checked(&base, changes)?;
+            let expected = replay.take_ops();
+            assert_eq!(&rest[..expected.len().min(rest.len())], expected.as_slice());
+            rest = &rest[expected.len()..];
+            applied[thread] += 1;
+        }
+        assert!(rest.is_empty(), "unexpected {:?}", rest.first());
+        assert_eq!(applied, [2 * ROUNDS; THREADS]);
+        assert!(config.gather()?.subsystems.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather_during_mutation() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let config = KernelConfig::with_root(root);
+        let state = example_state();
+        config.apply(State::default().get_deltas(&state))?;
+
+        let other = config.clone();
+        config.mutate(|_| {
+            // Another thread gathers while this one is mutating.
+            let gathered = std::thread::spawn(move || other.gather()).join().unwrap();
+            assert_eq!(gathered?, state);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_lock_file() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let dir = TestDir::new("lock");
+        let path = dir.join("nvmetcfg.lock");
+        let config = KernelConfig::with_root(root).with_lock_file(&path);
+        config.mutate(|_| {
+            // Held while mutating, by anyone locking the same file.
+            let file = File::open(&path)?;
+            assert!(flock(&file, libc::LOCK_EX | libc::LOCK_NB).is_err());
+            Ok(())
+        })?;
+        assert!(flock(&File::open(&path)?, libc::LOCK_EX | libc::LOCK_NB).is_ok());
+
+        let err = KernelConfig::with_root(FakeBackend::new_root().1)
+            .with_lock_file("/nonexistent/nvmetcfg.lock")
+            .apply(State::default().get_deltas(&example_state()))
+            .unwrap_err();
+        assert!(err.full_message().contains("Failed to open lock file"));
+        Ok(())
+    }
+}
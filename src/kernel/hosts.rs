@@ -0,0 +1,505 @@
+// Lifecycle of the global host entries.
+//
+// A host entry exists because a subsystem allows the host, so it is removed again once no
+// subsystem does anymore. Hosts with DH-HMAC-CHAP keys are the exception: the keys were set up
+// on purpose and would be lost, so only an explicit prune removes those.
+//
+// Removals are planned up front from the changes, so a dry run can report them, and carried out
+// once all changes are applied.
+
+use super::sysfs::NvmetRoot;
+use super::KernelConfig;
+use crate::errors::{Context, Error, Result};
+use crate::helpers::assert_valid_dhchap_key;
+use crate::state::{HostKeys, State, StateDelta, SubsystemDelta};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Outcome of removing unused hosts, see `KernelConfig::prune_hosts`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HostPruneReport {
+    /// Unused hosts that were removed, or would be with `dry_run`.
+    pub removed: BTreeSet<String>,
+    /// Unused hosts kept because they have keys.
+    pub kept: BTreeSet<String>,
+}
+
+/// Which DH-HMAC-CHAP keys a host has, see `KernelConfig::host_auth`.
+///
+/// With only the host key, the host authenticates itself to the controller. With the controller
+/// key as well, the controller authenticates itself to the host too. The controller key alone
+/// has no effect.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HostAuth {
+    /// The key the host authenticates itself with (dhchap_key).
+    pub host_key: bool,
+    /// The key the controller authenticates itself to the host with (dhchap_ctrl_key).
+    pub ctrl_key: bool,
+}
+
+impl HostAuth {
+    /// Whether host and controller authenticate each other.
+    #[must_use]
+    pub const fn is_mutual(&self) -> bool {
+        self.host_key && self.ctrl_key
+    }
+}
+
+/// Remove those of `candidates` which are not allowed on any subsystem.
+///
+/// With `keep_keyed`, hosts with a DH-HMAC-CHAP key are kept.
+/// With `dry_run`, only report what would be removed.
+pub(super) fn remove_unused_hosts(
+    root: &NvmetRoot,
+    candidates: &BTreeSet<String>,
+    keep_keyed: bool,
+    dry_run: bool,
+) -> Result<HostPruneReport> {
+    let used_hosts = root
+        .list_used_hosts()
+        .context("Failed to list used hosts")?;
+    let unused = candidates.difference(&used_hosts).cloned().collect();
+    remove_hosts(root, &unused, keep_keyed, dry_run)
+}
+
+/// Plan which hosts to remove after applying `changes` to the `current` state.
+///
+/// These are the existing hosts without keys which the changes leave unused.
+pub(super) fn plan_host_removals(
+    root: &NvmetRoot,
+    current: &State,
+    changes: &[StateDelta],
+) -> Result<BTreeSet<String>> {
+    let unused = hosts_left_unused(current, changes);
+    let report = remove_hosts(root, &unused, true, true)
+        .context("Failed to plan removal of unused hosts")?;
+    Ok(report.removed)
+}
+
+/// Whether `changes` can leave any hosts unused, so planning is needed at all.
+pub(super) fn may_leave_hosts_unused(changes: &[StateDelta]) -> bool {
+    changes.iter().any(|change| match change {
+        StateDelta::RemoveSubsystem(_) => true,
+        StateDelta::UpdateSubsystem(_, deltas) => deltas
+            .iter()
+            .any(|delta| matches!(delta, SubsystemDelta::RemoveHost(_))),
+        _ => false,
+    })
+}
+
+// Hosts allowed on some subsystem before the changes, but on none after them.
+fn hosts_left_unused(current: &State, changes: &[StateDelta]) -> BTreeSet<String> {
+    let mut allowed: BTreeMap<&str, BTreeSet<String>> = current
+        .subsystems
+        .iter()
+        .map(|(nqn, sub)| (nqn.as_str(), sub.allowed_hosts.clone()))
+        .collect();
+    let mut candidates = BTreeSet::new();
+    for change in changes {
+        match change {
+            StateDelta::AddSubsystem(nqn, sub) => {
+                allowed.insert(nqn, sub.allowed_hosts.clone());
+            }
+            StateDelta::UpdateSubsystem(nqn, deltas) => {
+                let hosts = allowed.entry(nqn).or_default();
+                for delta in deltas {
+                    match delta {
+                        SubsystemDelta::AddHost(host) => {
+                            hosts.insert(host.clone());
+                        }
+                        SubsystemDelta::RemoveHost(host) => {
+                            hosts.remove(host);
+                            candidates.insert(host.clone());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            StateDelta::RemoveSubsystem(nqn) => {
+                if let Some(hosts) = allowed.remove(nqn.as_str()) {
+                    candidates.extend(hosts);
+                }
+            }
+            _ => {}
+        }
+    }
+    let used: BTreeSet<&String> = allowed.values().flatten().collect();
+    candidates.retain(|host| !used.contains(host));
+    candidates
+}
+
+/// Give hosts the keys of `hosts`, creating hosts which don't exist yet, see `State::hosts`.
+///
+/// All keys are checked before anything is written. Keys which are set already are left alone,
+/// the hosts whose keys were written are returned.
+pub(super) fn set_host_keys_in(
+    root: &NvmetRoot,
+    hosts: &BTreeMap<String, HostKeys>,
+) -> Result<BTreeSet<String>> {
+    for (nqn, keys) in hosts {
+        for key in [&keys.dhchap_key, &keys.dhchap_ctrl_key]
+            .into_iter()
+            .flatten()
+        {
+            assert_valid_dhchap_key(key).with_context(|| format!("Invalid key for host {nqn}"))?;
+        }
+    }
+    let mut changed = BTreeSet::new();
+    for (nqn, keys) in hosts {
+        let host = if root.has_host(nqn)? {
+            root.open_host(nqn)?
+        } else {
+            root.create_host(nqn)
+                .with_context(|| format!("Failed to create host {nqn}"))?
+        };
+        if let Some(key) = keys.dhchap_key.as_deref() {
+            if !host.key_is("dhchap_key", key)? {
+                host.set_dhchap_key(key)?;
+                changed.insert(nqn.clone());
+            }
+        }
+        if let Some(key) = keys.dhchap_ctrl_key.as_deref() {
+            if !host.key_is("dhchap_ctrl_key", key)? {
+                host.set_dhchap_ctrl_key(key)?;
+                changed.insert(nqn.clone());
+            }
+        }
+    }
+    Ok(changed)
+}
+
+fn remove_hosts(
+    root: &NvmetRoot,
+    unused: &BTreeSet<String>,
+    keep_keyed: bool,
+    dry_run: bool,
+) -> Result<HostPruneReport> {
+    let mut report = HostPruneReport::default();
+    for host in unused {
+        if !root.has_host(host)? {
+            continue;
+        }
+        if keep_keyed && root.open_host(host)?.has_keys()? {
+            report.kept.insert(host.clone());
+            continue;
+        }
+        if !dry_run {
+            root.remove_host(host)
+                .with_context(|| format!("Failed to remove unused host {host}"))?;
+        }
+        report.removed.insert(host.clone());
+    }
+    Ok(report)
+}
+
+impl KernelConfig {
+    /// List all hosts known to the kernel, including those not allowed on any subsystem.
+    pub fn list_hosts() -> Result<BTreeSet<String>> {
+        let root = NvmetRoot::system();
+        root.check_exists()?;
+        root.list_all_hosts()
+    }
+
+    /// Remove all hosts not allowed on any subsystem.
+    ///
+    /// With `keep_keys`, hosts with a DH-HMAC-CHAP key are kept so the key isn't lost.
+    /// With `dry_run`, only report what would be removed.
+    pub fn prune_hosts(keep_keys: bool, dry_run: bool) -> Result<HostPruneReport> {
+        Self::system().mutate(|root| Self::prune_hosts_in(root, keep_keys, dry_run))
+    }
+
+    /// Create a host without allowing it on any subsystem, optionally with a DH-HMAC-CHAP key.
+    ///
+    /// Allowing it on subsystems later keeps the key.
+    pub fn add_host(nqn: &str, key: Option<&str>) -> Result<()> {
+        Self::system().mutate(|root| Self::add_host_in(root, nqn, key))
+    }
+
+    /// Give hosts the DH-HMAC-CHAP keys of `hosts`, like `state restore` does for the keys in
+    /// a state file, returning the hosts whose keys changed.
+    ///
+    /// Hosts which don't exist yet are created without allowing them on any subsystem. Nothing
+    /// is written unless all keys are valid.
+    pub fn set_host_keys(hosts: &BTreeMap<String, HostKeys>) -> Result<BTreeSet<String>> {
+        Self::system().mutate(|root| {
+            root.check_exists()?;
+            set_host_keys_in(root, hosts)
+        })
+    }
+
+    /// Which DH-HMAC-CHAP keys the host has.
+    pub fn host_auth(host: &str) -> Result<HostAuth> {
+        let root = NvmetRoot::system();
+        root.check_exists()?;
+        root.open_host(host)?.get_auth()
+    }
+
+    /// Set the DH-HMAC-CHAP key the host authenticates itself with.
+    pub fn set_host_dhchap_key(host: &str, key: &str) -> Result<()> {
+        Self::system().mutate(|root| root.open_host(host)?.set_dhchap_key(key))
+    }
+
+    /// Set the DH-HMAC-CHAP key the controller authenticates itself to the host with.
+    pub fn set_host_dhchap_ctrl_key(host: &str, key: &str) -> Result<()> {
+        Self::system().mutate(|root| root.open_host(host)?.set_dhchap_ctrl_key(key))
+    }
+
+    pub(crate) fn add_host_in(root: &NvmetRoot, nqn: &str, key: Option<&str>) -> Result<()> {
+        root.check_exists()?;
+        if let Some(key) = key {
+            assert_valid_dhchap_key(key)?;
+        }
+        if root.has_host(nqn)? {
+            return Err(Error::ExistingHost(nqn.to_string()));
+        }
+        let host = root.create_host(nqn)?;
+        if let Some(key) = key {
+            // Don't leave a host without its key behind.
+            if let Err(err) = host.set_dhchap_key(key) {
+                root.remove_host(nqn)?;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn prune_hosts_in(
+        root: &NvmetRoot,
+        keep_keys: bool,
+        dry_run: bool,
+    ) -> Result<HostPruneReport> {
+        root.check_exists()?;
+        let all_hosts = root.list_all_hosts()?;
+        remove_unused_hosts(root, &all_hosts, keep_keys, dry_run)
+            .context("Failed to prune unused hosts")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::{FakeBackend, FakeOp};
+    use crate::kernel::tests::{example_state, HOST, SUB};
+    use crate::state::Subsystem;
+
+    const KEY: &str = "DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:";
+
+    #[test]
+    fn test_prune_hosts() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&example_state()))?;
+
+        let unused = "nqn.2023-11.sh.tty:unused";
+        let keyed = "nqn.2023-11.sh.tty:keyed";
+        let sub = root.open_subsystem(SUB)?;
+        for host in [unused, keyed] {
+            sub.enable_host(host)?;
+            sub.disable_host(host)?;
+        }
+        root.open_host(keyed)?.set_dhchap_key(KEY)?;
+
+        // A dry run reports without removing anything.
+        let report = KernelConfig::prune_hosts_in(&root, true, true)?;
+        assert_eq!(report.removed, BTreeSet::from([unused.to_string()]));
+        assert_eq!(report.kept, BTreeSet::from([keyed.to_string()]));
+        assert!(fake.contains(&format!("hosts/{unused}")));
+
+        let report = KernelConfig::prune_hosts_in(&root, true, false)?;
+        assert_eq!(report.removed, BTreeSet::from([unused.to_string()]));
+        assert!(!fake.contains(&format!("hosts/{unused}")));
+        assert!(fake.contains(&format!("hosts/{keyed}")));
+
+        let report = KernelConfig::prune_hosts_in(&root, false, false)?;
+        assert_eq!(report.removed, BTreeSet::from([keyed.to_string()]));
+        // The host still referenced by the subsystem stays.
+        assert_eq!(root.list_all_hosts()?, BTreeSet::from([HOST.to_string()]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hosts_left_unused() {
+        let other = "nqn.2023-11.sh.tty:other";
+        let moved = "nqn.2023-11.sh.tty:moved";
+        let mut current = example_state();
+        current
+            .subsystems
+            .get_mut(SUB)
+            .unwrap()
+            .allowed_hosts
+            .insert(moved.to_string());
+        current
+            .subsystems
+            .insert(other.to_string(), current.subsystems[SUB].clone());
+
+        // Still allowed on the other subsystem.
+        let remove_sub = vec![StateDelta::RemoveSubsystem(SUB.to_string())];
+        assert!(hosts_left_unused(&current, &remove_sub).is_empty());
+
+        // Allowed again later in the same changes.
+        let changes = vec![
+            StateDelta::RemoveSubsystem(SUB.to_string()),
+            StateDelta::UpdateSubsystem(
+                other.to_string(),
+                vec![
+                    SubsystemDelta::RemoveHost(HOST.to_string()),
+                    SubsystemDelta::RemoveHost(moved.to_string()),
+                ],
+            ),
+            StateDelta::AddSubsystem(
+                "nqn.2023-11.sh.tty:new".to_string(),
+                Subsystem {
+                    allowed_hosts: BTreeSet::from([moved.to_string()]),
+                    ..Default::default()
+                },
+            ),
+        ];
+        assert_eq!(
+            hosts_left_unused(&current, &changes),
+            BTreeSet::from([HOST.to_string()])
+        );
+        assert!(may_leave_hosts_unused(&changes));
+        assert!(!may_leave_hosts_unused(&changes[2..]));
+    }
+
+    #[test]
+    fn test_add_host_keeps_key() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        KernelConfig::add_host_in(&root, HOST, Some(KEY))?;
+        assert!(KernelConfig::add_host_in(&root, HOST, None).is_err());
+        // A host whose key the kernel rejects is not left behind.
+        let rejected = "nqn.2023-11.sh.tty:rejected";
+        fake.fail_writes(&format!("hosts/{rejected}/dhchap_key"), 1);
+        assert!(KernelConfig::add_host_in(&root, rejected, Some(KEY)).is_err());
+        assert!(!fake.contains(&format!("hosts/{rejected}")));
+
+        // Allowing the host on a subsystem reuses it, key included.
+        fake.take_ops();
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&example_state()))?;
+        assert!(!fake
+            .take_ops()
+            .contains(&FakeOp::CreateDir(format!("hosts/{HOST}"))));
+        assert_eq!(
+            fake.attr(&format!("hosts/{HOST}/dhchap_key")).as_deref(),
+            Some(KEY)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_host_keys() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let ctrl_key = "DHHC-1:01:NcJ92zAcKEwrIVvaqI3/s8CL4IKox3aCA6s2IeV14WlbRimx:";
+        let mut hosts = BTreeMap::from([(
+            HOST.to_string(),
+            HostKeys {
+                dhchap_key: Some(KEY.to_string()),
+                dhchap_ctrl_key: None,
+            },
+        )]);
+        assert_eq!(
+            set_host_keys_in(&root, &hosts)?,
+            BTreeSet::from([HOST.to_string()])
+        );
+        assert_eq!(
+            fake.attr(&format!("hosts/{HOST}/dhchap_key")).as_deref(),
+            Some(KEY)
+        );
+        // Keys already set are not written again.
+        fake.take_ops();
+        assert!(set_host_keys_in(&root, &hosts)?.is_empty());
+        assert!(!fake
+            .take_ops()
+            .iter()
+            .any(|op| matches!(op, FakeOp::Write(..))));
+
+        // A malformed key stops all of them from being written.
+        hosts.get_mut(HOST).unwrap().dhchap_ctrl_key = Some(ctrl_key.to_string());
+        let broken = "nqn.2023-11.sh.tty:broken";
+        hosts.insert(
+            broken.to_string(),
+            HostKeys {
+                dhchap_key: Some("DHHC-1:00:not-a-key:".to_string()),
+                dhchap_ctrl_key: None,
+            },
+        );
+        let err = set_host_keys_in(&root, &hosts).unwrap_err();
+        assert!(matches!(err.root(), Error::InvalidDhchapKey(_)), "{err:?}");
+        assert!(!fake.contains(&format!("hosts/{broken}")));
+        assert_eq!(
+            fake.attr(&format!("hosts/{HOST}/dhchap_ctrl_key"))
+                .as_deref(),
+            Some("")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_last_host_reference() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let mut desired = example_state();
+        let other = "nqn.2023-11.sh.tty:other";
+        desired
+            .subsystems
+            .insert(other.to_string(), desired.subsystems[SUB].clone());
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+
+        let remove_host = |nqn: &str| {
+            KernelConfig::apply_delta_in(
+                &root,
+                vec![StateDelta::UpdateSubsystem(
+                    nqn.to_string(),
+                    vec![SubsystemDelta::RemoveHost(HOST.to_string())],
+                )],
+            )
+        };
+        // Still allowed on the other subsystem.
+        remove_host(SUB)?;
+        assert!(fake.contains(&format!("hosts/{HOST}")));
+        // That was the last reference.
+        remove_host(other)?;
+        assert!(!fake.contains(&format!("hosts/{HOST}")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_subsystem_host_lifecycle() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let mut desired = example_state();
+        let keyed = "nqn.2023-11.sh.tty:keyed";
+        desired
+            .subsystems
+            .get_mut(SUB)
+            .unwrap()
+            .allowed_hosts
+            .insert(keyed.to_string());
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+        root.open_host(keyed)?.set_dhchap_key(KEY)?;
+
+        KernelConfig::apply_delta_in(&root, vec![StateDelta::RemoveSubsystem(SUB.to_string())])?;
+        assert!(!fake.contains(&format!("hosts/{HOST}")));
+        assert!(fake.contains(&format!("hosts/{keyed}")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_host_keeps_keyed() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&example_state()))?;
+        root.open_host(HOST)?.set_dhchap_key(KEY)?;
+
+        KernelConfig::apply_delta_in(
+            &root,
+            vec![StateDelta::UpdateSubsystem(
+                SUB.to_string(),
+                vec![SubsystemDelta::RemoveHost(HOST.to_string())],
+            )],
+        )?;
+        assert!(fake.contains(&format!("hosts/{HOST}")));
+        Ok(())
+    }
+}
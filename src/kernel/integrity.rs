@@ -0,0 +1,511 @@
+// Checks of the configfs tree itself, rather than of the state gathered from it.
+//
+// The kernel keeps most of the tree consistent on its own, but it is also written to by hand,
+// by other tools and by runs of nvmetcfg that were interrupted halfway. Gathering the state
+// takes the tree at its word, so links are followed by name and devices are not looked at.
+// Here every link is resolved and every enabled device checked, and problems are collected
+// rather than stopping at the first one.
+
+use super::sysfs::{LinkTarget, NvmetPort, NvmetRoot, NvmetSubsystem};
+use super::{KernelConfig, ObjectRef};
+use crate::errors::{Context, Error, Result};
+use crate::helpers::{assert_compliant_nqn, assert_valid_nqn, DeviceCache};
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// How bad a problem found by `KernelConfig::check_tree` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Nothing breaks, but something is left over or may confuse initiators.
+    Warning,
+    /// Part of the configuration doesn't work, or can't be managed by nvmetcfg.
+    Error,
+}
+
+/// A fix `KernelConfig::repair_tree` can make on its own, as nothing in use is lost by it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Repair {
+    /// Remove the link `link` from the subsystems of the port.
+    UnlinkSubsystem { port: u16, link: String },
+    /// Remove the link `link` from the allowed hosts of the subsystem.
+    UnlinkHost { subsystem: String, link: String },
+    /// Remove the host, which no subsystem allows.
+    RemoveHost { host: String },
+}
+
+/// A problem in the configfs tree, see `KernelConfig::check_tree`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The object the problem was found in.
+    pub object: ObjectRef,
+    pub problem: String,
+    /// What to do about it.
+    pub fix: String,
+    /// How `KernelConfig::repair_tree` fixes it, if it can.
+    pub repair: Option<Repair>,
+    /// Whether `KernelConfig::repair_tree` fixed it.
+    pub repaired: bool,
+}
+
+impl KernelConfig {
+    /// Check the configfs tree itself for problems gathering the state doesn't notice.
+    ///
+    /// Links to subsystems and hosts have to resolve to one named like the link, enabled
+    /// namespaces need their block device, every host has to be allowed on some subsystem
+    /// unless it has keys, and all NQNs have to be valid. Objects which can't be read are
+    /// reported as well, only failing to list the top-level groups is an error.
+    pub fn check_tree() -> Result<Vec<Finding>> {
+        Self::check_tree_in(&NvmetRoot::system())
+    }
+
+    /// Make the repairs of `findings` which have one, marking them as repaired.
+    pub fn repair_tree(findings: &mut [Finding]) -> Result<()> {
+        Self::system().mutate(|root| Self::repair_tree_in(root, findings))
+    }
+
+    pub(crate) fn check_tree_in(root: &NvmetRoot) -> Result<Vec<Finding>> {
+        root.check_exists()?;
+        let mut checker = TreeChecker::default();
+        let mut used_hosts = BTreeSet::new();
+        for sub in root.list_subsystems()? {
+            let object = ObjectRef::Subsystem(sub.nqn.clone());
+            checker.check_nqn(&object, &sub.nqn);
+            if let Err(err) = checker.check_allowed_hosts(&sub, &mut used_hosts) {
+                checker.unreadable(object.clone(), &err);
+            }
+            if let Err(err) = checker.check_namespaces(root, &sub) {
+                checker.unreadable(object, &err);
+            }
+        }
+        for port in root.list_ports()? {
+            if let Err(err) = checker.check_port_links(&port) {
+                checker.unreadable(ObjectRef::Port(port.id), &err);
+            }
+        }
+        for host in root.list_all_hosts()? {
+            let object = ObjectRef::Host(host.clone());
+            if !checker.check_nqn(&object, &host) || used_hosts.contains(&host) {
+                continue;
+            }
+            match root.open_host(&host).and_then(|entry| entry.has_keys()) {
+                // The keys were set up on purpose, like for `host add`.
+                Ok(true) => {}
+                Ok(false) => checker.add(
+                    Severity::Warning,
+                    object,
+                    "is not allowed on any subsystem".to_string(),
+                    "Remove the host".to_string(),
+                    Some(Repair::RemoveHost { host }),
+                ),
+                Err(err) => checker.unreadable(object, &err),
+            }
+        }
+        Ok(checker.findings)
+    }
+
+    pub(crate) fn repair_tree_in(root: &NvmetRoot, findings: &mut [Finding]) -> Result<()> {
+        root.check_exists()?;
+        for finding in findings.iter_mut().filter(|finding| !finding.repaired) {
+            let Some(repair) = &finding.repair else {
+                continue;
+            };
+            match repair {
+                Repair::UnlinkSubsystem { port, link } => {
+                    root.open_port(*port).disable_subsystem(link)
+                }
+                Repair::UnlinkHost { subsystem, link } => root
+                    .open_subsystem(subsystem)
+                    .and_then(|sub| sub.disable_host(link)),
+                Repair::RemoveHost { host } => root.remove_host(host),
+            }
+            .with_context(|| format!("Failed to repair {}", finding.object))?;
+            finding.repaired = true;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct TreeChecker {
+    findings: Vec<Finding>,
+    /// Namespaces sharing a device only check it once.
+    devices: DeviceCache,
+}
+
+impl TreeChecker {
+    fn add(
+        &mut self,
+        severity: Severity,
+        object: ObjectRef,
+        problem: String,
+        fix: String,
+        repair: Option<Repair>,
+    ) {
+        self.findings.push(Finding {
+            severity,
+            object,
+            problem,
+            fix,
+            repair,
+            repaired: false,
+        });
+    }
+
+    fn unreadable(&mut self, object: ObjectRef, err: &Error) {
+        self.add(
+            Severity::Error,
+            object,
+            format!("can't be read: {}", err.full_message()),
+            "Check the kernel log and the permissions of configfs".to_string(),
+            None,
+        );
+    }
+
+    // Whether the NQN is valid at all, which is needed to manage the object.
+    fn check_nqn(&mut self, object: &ObjectRef, nqn: &str) -> bool {
+        if let Err(err) = assert_valid_nqn(nqn) {
+            self.add(
+                Severity::Error,
+                object.clone(),
+                format!("has an invalid NQN: {err}"),
+                "Remove it and create it again with a valid NQN".to_string(),
+                None,
+            );
+            return false;
+        }
+        if let Err(err) = assert_compliant_nqn(nqn) {
+            self.add(
+                Severity::Warning,
+                object.clone(),
+                format!("has an NQN not following the NVMe specification: {err}"),
+                "Initiators may refuse it, consider renaming it".to_string(),
+                None,
+            );
+        }
+        true
+    }
+
+    // A link has to resolve to an object of its group named like the link, as nvmetcfg goes
+    // by the link names. Links which don't resolve are useless, so they can be removed.
+    fn check_link(
+        &mut self,
+        object: ObjectRef,
+        what: &str,
+        link: &str,
+        target: LinkTarget,
+        repair: Repair,
+    ) -> Option<String> {
+        let (problem, fix, repair) = match target {
+            LinkTarget::Object(name) if name == link => return Some(name),
+            LinkTarget::Object(name) => (
+                format!("links {what} {name} under the name {link}"),
+                format!("Link {name} again under its own name"),
+                None,
+            ),
+            LinkTarget::Missing(target) => (
+                format!(
+                    "links {what} {link} to {}, which doesn't exist",
+                    target.display()
+                ),
+                "Remove the dangling link".to_string(),
+                Some(repair),
+            ),
+            LinkTarget::Elsewhere(target) => (
+                format!(
+                    "links {what} {link} to {}, which is no {what}",
+                    target.display()
+                ),
+                "Remove the link".to_string(),
+                Some(repair),
+            ),
+        };
+        self.add(Severity::Error, object, problem, fix, repair);
+        None
+    }
+
+    fn check_allowed_hosts(
+        &mut self,
+        sub: &NvmetSubsystem,
+        used_hosts: &mut BTreeSet<String>,
+    ) -> Result<()> {
+        for link in sub.list_hosts()? {
+            let target = sub.host_link(&link)?;
+            let repair = Repair::UnlinkHost {
+                subsystem: sub.nqn.clone(),
+                link: link.clone(),
+            };
+            let object = ObjectRef::Subsystem(sub.nqn.clone());
+            if let Some(host) = self.check_link(object, "host", &link, target, repair) {
+                used_hosts.insert(host);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_namespaces(&mut self, root: &NvmetRoot, sub: &NvmetSubsystem) -> Result<()> {
+        for (nsid, ns) in sub.list_namespaces()? {
+            if !ns.is_enabled()? {
+                continue;
+            }
+            let object = ObjectRef::Namespace(sub.nqn.clone(), nsid);
+            let path = ns.get_device_path()?;
+            if path.as_os_str().is_empty() {
+                self.add(
+                    Severity::Error,
+                    object,
+                    "is enabled without a device".to_string(),
+                    "Disable the namespace and set its device".to_string(),
+                    None,
+                );
+            } else if let Err(err) = root.resolve_device(&path, &self.devices) {
+                self.add(
+                    Severity::Error,
+                    object,
+                    format!(
+                        "is enabled, but its device {} is unusable: {}",
+                        path.display(),
+                        err.full_message()
+                    ),
+                    "Restore the block device, or disable the namespace".to_string(),
+                    None,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check_port_links(&mut self, port: &NvmetPort) -> Result<()> {
+        for link in port.list_subsystems()? {
+            let target = port.subsystem_link(&link)?;
+            let repair = Repair::UnlinkSubsystem {
+                port: port.id,
+                link: link.clone(),
+            };
+            self.check_link(ObjectRef::Port(port.id), "subsystem", &link, target, repair);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::FakeBackend;
+    use crate::kernel::tests::{example_state, HOST, SUB};
+    use crate::state::State;
+
+    fn setup() -> Result<(std::sync::Arc<FakeBackend>, NvmetRoot)> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&example_state()))?;
+        Ok((fake, root))
+    }
+
+    #[test]
+    fn test_check_tree_clean() -> Result<()> {
+        let (_fake, root) = setup()?;
+        assert_eq!(KernelConfig::check_tree_in(&root)?, Vec::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tree_dangling_links() -> Result<()> {
+        let (fake, root) = setup()?;
+        let gone = "nqn.2023-11.sh.tty:gone";
+        // configfs stores targets relative to the link.
+        fake.add_link(
+            &format!("ports/1/subsystems/{gone}"),
+            &format!("../../../subsystems/{gone}"),
+        );
+        fake.add_link(
+            &format!("subsystems/{SUB}/allowed_hosts/{gone}"),
+            &format!("hosts/{gone}"),
+        );
+        fake.add_link(
+            &format!("ports/1/subsystems/{HOST}"),
+            &format!("hosts/{HOST}"),
+        );
+
+        let mut findings = KernelConfig::check_tree_in(&root)?;
+        let repairs: Vec<_> = findings.iter().map(|f| f.repair.clone()).collect();
+        assert_eq!(
+            repairs,
+            [
+                Some(Repair::UnlinkHost {
+                    subsystem: SUB.to_string(),
+                    link: gone.to_string(),
+                }),
+                Some(Repair::UnlinkSubsystem {
+                    port: 1,
+                    link: HOST.to_string(),
+                }),
+                Some(Repair::UnlinkSubsystem {
+                    port: 1,
+                    link: gone.to_string(),
+                }),
+            ]
+        );
+        assert!(findings.iter().all(|f| f.severity == Severity::Error));
+        assert!(findings[1].problem.ends_with("which is no subsystem"));
+        assert!(findings[2].problem.ends_with(&format!(
+            "to /fake/nvmet/subsystems/{gone}, which doesn't exist"
+        )));
+
+        KernelConfig::repair_tree_in(&root, &mut findings)?;
+        assert!(findings.iter().all(|f| f.repaired));
+        assert!(!fake.contains(&format!("ports/1/subsystems/{gone}")));
+        assert!(!fake.contains(&format!("subsystems/{SUB}/allowed_hosts/{gone}")));
+        assert!(fake.contains(&format!("ports/1/subsystems/{SUB}")));
+        assert_eq!(KernelConfig::check_tree_in(&root)?, Vec::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tree_misnamed_link() -> Result<()> {
+        let (fake, root) = setup()?;
+        fake.remove_attr(&format!("ports/1/subsystems/{SUB}"));
+        fake.add_link("ports/1/subsystems/other", &format!("subsystems/{SUB}"));
+
+        let findings = KernelConfig::check_tree_in(&root)?;
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].object, ObjectRef::Port(1));
+        assert_eq!(findings[0].repair, None);
+        assert_eq!(
+            findings[0].problem,
+            format!("links subsystem {SUB} under the name other")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tree_hosts() -> Result<()> {
+        let (fake, root) = setup()?;
+        let unused = "nqn.2023-11.sh.tty:unused";
+        let keyed = "nqn.2023-11.sh.tty:keyed";
+        KernelConfig::add_host_in(&root, unused, None)?;
+        KernelConfig::add_host_in(
+            &root,
+            keyed,
+            Some("DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:"),
+        )?;
+        let invalid = "nqn.2023-11.sh.tty:h\u{f6}st";
+        fake.add_group(&format!("hosts/{invalid}"));
+
+        let mut findings = KernelConfig::check_tree_in(&root)?;
+        let found: Vec<_> = findings
+            .iter()
+            .map(|f| (f.severity, f.object.to_string(), f.repair.is_some()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Severity::Error, format!("host:{invalid}"), false),
+                (Severity::Warning, format!("host:{unused}"), true),
+            ]
+        );
+
+        KernelConfig::repair_tree_in(&root, &mut findings)?;
+        assert!(!fake.contains(&format!("hosts/{unused}")));
+        assert!(fake.contains(&format!("hosts/{keyed}")));
+        assert!(fake.contains(&format!("hosts/{HOST}")));
+        // Invalid names are left alone, nvmetcfg can't tell what they are for.
+        assert!(fake.contains(&format!("hosts/{invalid}")));
+        assert!(!findings[0].repaired);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tree_namespaces() -> Result<()> {
+        let (fake, root) = setup()?;
+        let sub = root.open_subsystem(SUB)?;
+        sub.create_namespace(2)?;
+        // Disabled namespaces don't need their device.
+        fake.set_attr(
+            &format!("subsystems/{SUB}/namespaces/2/device_path"),
+            "/dev/gone",
+        );
+        let findings = KernelConfig::check_tree_in(&root)?;
+        assert_eq!(findings, Vec::new());
+
+        fake.set_attr(&format!("subsystems/{SUB}/namespaces/2/enable"), "1");
+        fake.set_attr(&format!("subsystems/{SUB}/namespaces/1/device_path"), "");
+        let findings = KernelConfig::check_tree_in(&root)?;
+        let found: Vec<_> = findings
+            .iter()
+            .map(|f| (f.object.clone(), f.problem.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (
+                    ObjectRef::Namespace(SUB.to_string(), 1),
+                    "is enabled without a device"
+                ),
+                (
+                    ObjectRef::Namespace(SUB.to_string(), 2),
+                    "is enabled, but its device /dev/gone is unusable: Invalid Device: /dev/gone"
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tree_invalid_subsystem_nqn() -> Result<()> {
+        let (fake, root) = setup()?;
+        fake.add_group("subsystems/sub");
+        fake.add_group("subsystems/nqn.2023-11.sh.tty:s\u{fc}b");
+
+        let findings = KernelConfig::check_tree_in(&root)?;
+        let found: Vec<_> = findings
+            .iter()
+            .map(|f| (f.severity, f.object.to_string()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (
+                    Severity::Error,
+                    "subsystem:nqn.2023-11.sh.tty:s\u{fc}b".to_string()
+                ),
+                (Severity::Warning, "subsystem:sub".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tree_unreadable() -> Result<()> {
+        let (fake, root) = setup()?;
+        fake.deny(&format!("subsystems/{SUB}/namespaces"));
+        let findings = KernelConfig::check_tree_in(&root)?;
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].object, ObjectRef::Subsystem(SUB.to_string()));
+        assert!(findings[0].problem.starts_with("can't be read: "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_finding_json() -> Result<()> {
+        let finding = Finding {
+            severity: Severity::Warning,
+            object: ObjectRef::Host(HOST.to_string()),
+            problem: "is not allowed on any subsystem".to_string(),
+            fix: "Remove the host".to_string(),
+            repair: Some(Repair::RemoveHost {
+                host: HOST.to_string(),
+            }),
+            repaired: false,
+        };
+        assert_eq!(
+            serde_json::to_string(&finding)?,
+            format!(
+                r#"{{"severity":"warning","object":"host:{HOST}","problem":"is not allowed on any subsystem","fix":"Remove the host","repair":{{"action":"remove_host","host":"{HOST}"}},"repaired":false}}"#
+            )
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use super::sysfs::NvmetRoot;
+use super::KernelConfig;
+use crate::errors::{Context, Result};
+use crate::helpers::CsvWriter;
+use serde::Serialize;
+
+/// Identifying attributes of a subsystem, see `KernelConfig::subsystem_inventory`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SubsystemInventory {
+    pub nqn: String,
+    pub model: String,
+    pub serial: String,
+    /// Firmware revision, if the kernel has it.
+    pub firmware: Option<String>,
+    /// IEEE OUI, if the kernel has it.
+    pub ieee_oui: Option<String>,
+}
+
+impl SubsystemInventory {
+    pub const CSV_HEADER: [&'static str; 5] = ["nqn", "model", "serial", "firmware", "ieee_oui"];
+
+    /// The inventory as CSV, with a header line. Missing attributes are left empty.
+    #[must_use]
+    pub fn to_csv(inventory: &[Self]) -> String {
+        let mut csv = CsvWriter::new(&Self::CSV_HEADER);
+        for sub in inventory {
+            csv.row(&[
+                sub.nqn.as_str(),
+                &sub.model,
+                &sub.serial,
+                sub.firmware.as_deref().unwrap_or_default(),
+                sub.ieee_oui.as_deref().unwrap_or_default(),
+            ]);
+        }
+        csv.finish()
+    }
+}
+
+impl KernelConfig {
+    /// Read the identifying attributes of all subsystems.
+    pub fn subsystem_inventory() -> Result<Vec<SubsystemInventory>> {
+        Self::subsystem_inventory_in(&NvmetRoot::system())
+    }
+
+    pub(crate) fn subsystem_inventory_in(root: &NvmetRoot) -> Result<Vec<SubsystemInventory>> {
+        root.check_exists()?;
+        let mut inventory = Vec::new();
+        for sub in root
+            .list_subsystems()
+            .context("Failed to list subsystems for inventory")?
+        {
+            inventory.push(SubsystemInventory {
+                model: sub.get_model()?,
+                serial: sub.get_serial()?,
+                firmware: sub.get_optional_attr("attr_firmware")?,
+                ieee_oui: sub.get_optional_attr("attr_ieee_oui")?,
+                nqn: sub.nqn,
+            });
+        }
+        Ok(inventory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::FakeBackend;
+    use crate::state::{State, StateDelta, Subsystem};
+
+    #[test]
+    fn test_subsystem_inventory_csv() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let old = "nqn.2023-11.sh.tty:old-kernel";
+        let new = "nqn.2023-11.sh.tty:new-kernel";
+        let mut state = State::default();
+        for nqn in [old, new] {
+            state.subsystems.insert(
+                nqn.to_string(),
+                Subsystem {
+                    model: Some("Dumb-O-Tron 2000, \"Pro\"".to_string()),
+                    serial: Some("1337".to_string()),
+                    ..Default::default()
+                },
+            );
+        }
+        let deltas: Vec<StateDelta> = State::default().get_deltas(&state);
+        KernelConfig::apply_delta_in(&root, deltas)?;
+        fake.add_attr(&format!("subsystems/{new}/attr_firmware"), "6.8.0");
+        fake.add_attr(&format!("subsystems/{new}/attr_ieee_oui"), "0x000000");
+
+        let inventory = KernelConfig::subsystem_inventory_in(&root)?;
+        assert_eq!(inventory[0].firmware.as_deref(), Some("6.8.0"));
+        assert_eq!(inventory[1].firmware, None);
+        assert_eq!(
+            SubsystemInventory::to_csv(&inventory),
+            format!(
+                "\
+nqn,model,serial,firmware,ieee_oui
+{new},\"Dumb-O-Tron 2000, \"\"Pro\"\"\",1337,6.8.0,0x000000
+{old},\"Dumb-O-Tron 2000, \"\"Pro\"\"\",1337,,
+"
+            )
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+// The NVMe controllers this host created by connecting to its own loop ports.
+// nvme-loop doesn't record which port a controller came through: connecting without a traddr
+// uses the loop port enabled first, connecting with one uses the loop port whose addr_traddr
+// matches. Only ports the subsystem is linked to accept the connection, so controllers are
+// matched to loop ports by their subsystem.
+
+use super::KernelConfig;
+use crate::errors::{Context, Result};
+use crate::state::{PortType, State};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::Path;
+
+static NVME_CLASS: &str = "/sys/class/nvme";
+
+/// An NVMe controller of this host using the loop transport, see `KernelConfig::loop_controllers`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LoopController {
+    /// Name of the controller, like `nvme0`, whose character device is `/dev/nvme0`.
+    pub name: String,
+    /// NQN of the subsystem it is connected to.
+    pub subsystem: String,
+    /// Controller ID assigned by the target.
+    pub cntlid: Option<u16>,
+    /// Controller state, like `live`.
+    pub state: Option<String>,
+    /// Block devices of its namespaces, like `nvme0n1`.
+    pub namespaces: Vec<String>,
+}
+
+impl KernelConfig {
+    /// List the NVMe controllers using the loop transport, from /sys/class/nvme.
+    ///
+    /// Without the nvme module loaded, there are none.
+    pub fn loop_controllers() -> Result<Vec<LoopController>> {
+        Self::loop_controllers_in(Path::new(NVME_CLASS))
+    }
+
+    pub(crate) fn loop_controllers_in(class: &Path) -> Result<Vec<LoopController>> {
+        let entries = match std::fs::read_dir(class) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to list {}", class.display()))
+            }
+        };
+        let mut controllers = Vec::new();
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Failed to list {}", class.display()))?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let dir = entry.path();
+            // Controllers can go away at any time, skip those which did.
+            if read_attr(&dir, "transport").as_deref() != Some("loop") {
+                continue;
+            }
+            let Some(subsystem) = read_attr(&dir, "subsysnqn") else {
+                continue;
+            };
+            controllers.push(LoopController {
+                cntlid: read_attr(&dir, "cntlid").and_then(|id| id.parse().ok()),
+                state: read_attr(&dir, "state"),
+                namespaces: namespace_devices(&dir, &name),
+                name,
+                subsystem,
+            });
+        }
+        controllers.sort_by_key(|ctrl| controller_number(&ctrl.name));
+        Ok(controllers)
+    }
+}
+
+/// The loop controllers of each loop port in `state`, by port ID.
+///
+/// A controller is counted for every loop port its subsystem is linked to, as the kernel
+/// doesn't tell which of those it came through.
+#[must_use]
+pub fn loop_port_controllers(
+    state: &State,
+    controllers: &[LoopController],
+) -> BTreeMap<u16, Vec<LoopController>> {
+    state
+        .ports
+        .iter()
+        .filter(|(_, port)| port.port_type == PortType::Loop)
+        .map(|(id, port)| {
+            let connected = controllers
+                .iter()
+                .filter(|ctrl| port.subsystems.contains(&ctrl.subsystem))
+                .cloned()
+                .collect();
+            (*id, connected)
+        })
+        .collect()
+}
+
+fn read_attr(dir: &Path, name: &str) -> Option<String> {
+    let value = std::fs::read_to_string(dir.join(name)).ok()?;
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Block devices of the namespaces of controller `name`.
+///
+/// Without multipath, they are named after the controller, like `nvme0n1`. With it, the
+/// controller has paths like `nvme1c0n1`, of the device `nvme1n1` shared by all paths.
+fn namespace_devices(dir: &Path, name: &str) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut devices: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|entry| {
+            let (head, nsid) = entry.rsplit_once('n')?;
+            if nsid.is_empty() || !nsid.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            if head == name {
+                return Some(entry);
+            }
+            let (subsys, ctrl) = head.split_once('c')?;
+            let ctrl = ctrl.parse::<u32>().ok()?;
+            (Some(ctrl) == controller_number(name) && subsys.starts_with("nvme"))
+                .then(|| format!("{subsys}n{nsid}"))
+        })
+        .collect();
+    devices.sort_by_key(|dev| (dev.len(), dev.clone()));
+    devices
+}
+
+fn controller_number(name: &str) -> Option<u32> {
+    name.strip_prefix("nvme")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use crate::state::Port;
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    const SUB: &str = "nqn.2023-11.sh.tty:loop";
+    const OTHER: &str = "nqn.2023-11.sh.tty:other";
+
+    fn controller(class: &Path, name: &str, transport: &str, subsystem: &str, files: &[&str]) {
+        let dir = class.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("transport"), format!("{transport}\n")).unwrap();
+        fs::write(dir.join("subsysnqn"), format!("{subsystem}\n")).unwrap();
+        fs::write(dir.join("state"), "live\n").unwrap();
+        fs::write(dir.join("cntlid"), "1\n").unwrap();
+        for file in files {
+            fs::create_dir_all(dir.join(file)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_loop_port_controllers() -> Result<()> {
+        let dir = TestDir::new("nvme-class");
+        let class = dir.join("nvme");
+        assert!(KernelConfig::loop_controllers_in(&class)?.is_empty());
+        controller(
+            &class,
+            "nvme10",
+            "loop",
+            SUB,
+            &["nvme10n1", "nvme10n12", "power"],
+        );
+        controller(&class, "nvme2", "loop", OTHER, &["nvme1c2n1"]);
+        controller(&class, "nvme0", "tcp", SUB, &["nvme0n1"]);
+
+        let controllers = KernelConfig::loop_controllers_in(&class)?;
+        assert_eq!(
+            controllers,
+            vec![
+                LoopController {
+                    name: "nvme2".to_string(),
+                    subsystem: OTHER.to_string(),
+                    cntlid: Some(1),
+                    state: Some("live".to_string()),
+                    namespaces: vec!["nvme1n1".to_string()],
+                },
+                LoopController {
+                    name: "nvme10".to_string(),
+                    subsystem: SUB.to_string(),
+                    cntlid: Some(1),
+                    state: Some("live".to_string()),
+                    namespaces: vec!["nvme10n1".to_string(), "nvme10n12".to_string()],
+                },
+            ]
+        );
+
+        let mut state = State::default();
+        let loop_port = |subs: &[&str]| {
+            Port::new(
+                PortType::Loop,
+                subs.iter().map(ToString::to_string).collect(),
+            )
+        };
+        state.ports.insert(1, loop_port(&[SUB]));
+        state.ports.insert(2, loop_port(&[SUB, OTHER]));
+        state.ports.insert(3, loop_port(&[]));
+        state.ports.insert(
+            4,
+            Port::new(
+                PortType::Tcp("192.0.2.1:4420".parse().unwrap()),
+                BTreeSet::from([SUB.to_string()]),
+            ),
+        );
+        let names = |id: u16| -> Vec<String> {
+            loop_port_controllers(&state, &controllers)[&id]
+                .iter()
+                .map(|ctrl| ctrl.name.clone())
+                .collect()
+        };
+        assert_eq!(names(1), ["nvme10"]);
+        assert_eq!(names(2), ["nvme2", "nvme10"]);
+        assert!(names(3).is_empty());
+        assert!(!loop_port_controllers(&state, &controllers).contains_key(&4));
+        Ok(())
+    }
+}
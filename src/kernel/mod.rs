@@ -1,70 +1,339 @@
+mod apply;
+mod backend;
+mod capabilities;
+mod controllers;
+mod events;
+#[cfg(test)]
+pub(crate) mod fake;
+mod handle;
+mod hosts;
+mod integrity;
+mod inventory;
+mod loopback;
+#[cfg(feature = "async")]
+mod nonblocking;
+mod preconditions;
+mod raw;
 pub(super) mod sysfs;
+mod visit;
+mod wait;
 
-use crate::errors::{Error, Result};
-use crate::helpers::assert_valid_nqn;
-use crate::state::{Namespace, Port, PortDelta, State, StateDelta, Subsystem, SubsystemDelta};
-use anyhow::Context;
-use std::collections::BTreeMap;
-use sysfs::NvmetRoot;
+use crate::errors::{Context, Error, Result};
+use crate::helpers::{assert_valid_nqn, assert_valid_nsid, DeviceCache};
+use crate::state::{
+    Namespace, Port, PortDelta, PortType, State, StateDelta, Subsystem, SubsystemDelta,
+};
+use hosts::{may_leave_hosts_unused, plan_host_removals, remove_unused_hosts};
+use preconditions::check_precondition;
+use std::collections::{BTreeMap, BTreeSet};
+use sysfs::{NvmetNamespace, NvmetPort, NvmetRoot, NvmetSubsystem};
+use visit::StateGatherer;
 
-pub struct KernelConfig {}
+pub use apply::*;
+pub use capabilities::*;
+pub use controllers::*;
+pub use events::*;
+pub use handle::*;
+pub use hosts::*;
+pub use integrity::*;
+pub use inventory::*;
+pub use loopback::*;
+pub use raw::*;
+pub use visit::*;
+
+/// A subsystem which could not be gathered, see `KernelConfig::gather_state_partial`.
+#[derive(Debug)]
+pub struct GatherWarning {
+    /// NQN of the subsystem.
+    pub nqn: String,
+    /// Why it could not be gathered.
+    pub error: Error,
+}
+
+impl std::fmt::Display for GatherWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Subsystem {} is unreadable: {}",
+            self.nqn,
+            self.error.full_message()
+        )
+    }
+}
 
 impl KernelConfig {
     pub fn gather_state() -> Result<State> {
-        NvmetRoot::check_exists()?;
+        Self::system().gather()
+    }
 
-        let mut state = State::default();
+    pub fn apply_delta(changes: Vec<StateDelta>) -> Result<()> {
+        Self::system().apply(changes)
+    }
 
-        // Gather ports.
-        for port in NvmetRoot::list_ports().context("Failed to gather port list")? {
-            if let Ok(port_type) = port.get_type() {
-                let subs = port.list_subsystems().with_context(|| {
-                    format!("Failed to gather subsystem state for port {}", port.id)
-                })?;
-                state.ports.insert(port.id, Port::new(port_type, subs));
-            }
+    /// Apply changes computed against the `base` state.
+    ///
+    /// Before each change, the objects it touches are checked to still match `base`, with the
+    /// earlier changes applied. If something else modified them in the meantime, this fails
+    /// with `Error::StateChanged` instead of doing the wrong thing.
+    pub fn apply_delta_checked(base: &State, changes: Vec<StateDelta>) -> Result<()> {
+        Self::system().apply_checked(base, changes)
+    }
+
+    /// Gather the state again and return the changes still needed to reach `desired`.
+    ///
+    /// Meant to be used after applying, in order to catch writes the kernel silently ignored
+    /// or normalized.
+    pub fn verify_state(desired: &State) -> Result<Vec<StateDelta>> {
+        Self::verify_state_in(&NvmetRoot::system(), desired)
+    }
+
+    pub(crate) fn verify_state_in(root: &NvmetRoot, desired: &State) -> Result<Vec<StateDelta>> {
+        let current =
+            Self::gather_state_in(root).context("Failed to gather state for verification")?;
+        Ok(current.get_unmet_deltas(desired))
+    }
+
+    /// Gather the state, skipping subsystems which can't be read.
+    ///
+    /// The state contains all healthy subsystems, the others are returned as warnings.
+    pub fn gather_state_partial() -> Result<(State, Vec<GatherWarning>)> {
+        Self::system().gather_partial()
+    }
+
+    /// Gather the subsystems one by one, as they are iterated over.
+    ///
+    /// Unlike `gather_state`, this doesn't wait for all of them before returning anything,
+    /// which is better suited to scanning many subsystems. Subsystems which can't be gathered
+    /// are yielded as errors, without ending the iteration.
+    pub fn iter_subsystems() -> impl Iterator<Item = Result<(String, Subsystem)>> {
+        Self::iter_subsystems_in(&NvmetRoot::system())
+    }
+
+    pub(crate) fn iter_subsystems_in(
+        root: &NvmetRoot,
+    ) -> impl Iterator<Item = Result<(String, Subsystem)>> {
+        let (subsystems, error) = match root
+            .check_exists()
+            .and_then(|()| root.list_subsystems())
+            .context("Failed to gather subsystem list")
+        {
+            Ok(subsystems) => (subsystems, None),
+            Err(err) => (Vec::new(), Some(Err(err))),
+        };
+        error
+            .into_iter()
+            .chain(subsystems.into_iter().map(|subsystem| {
+                let sub = Self::gather_subsystem(&subsystem)?;
+                Ok((subsystem.nqn, sub))
+            }))
+    }
+
+    pub(crate) fn gather_state_in(root: &NvmetRoot) -> Result<State> {
+        let (state, warnings) = Self::gather_state_partial_in(root)?;
+        match warnings.into_iter().next() {
+            Some(warning) => Err(warning.error),
+            None => Ok(state),
         }
+    }
 
-        // Gather subsystems.
-        for subsystem in NvmetRoot::list_subsystems().context("Failed to gather subsystem list")? {
-            // Gather namespaces of subsystem.
-            let mut namespaces = BTreeMap::<u32, Namespace>::new();
-            for (nsid, nvmetns) in subsystem.list_namespaces()? {
-                let ns = nvmetns.get_namespace().with_context(|| {
-                    format!(
-                        "Failed to get namespace {} for subsystem {}",
-                        nsid, subsystem.nqn
-                    )
-                })?;
-                namespaces.insert(nsid, ns);
-            }
+    pub(crate) fn gather_state_partial_in(root: &NvmetRoot) -> Result<(State, Vec<GatherWarning>)> {
+        let mut gatherer = StateGatherer::default();
+        Self::visit_state_in(root, &mut gatherer)?;
+        Ok((gatherer.state, gatherer.warnings))
+    }
 
-            let sub = Subsystem {
-                model: Some(subsystem.get_model().with_context(|| {
-                    format!("Failed to gather model for subsystem {}", subsystem.nqn)
-                })?),
-                serial: Some(subsystem.get_serial().with_context(|| {
-                    format!("Failed to gather serial for subsystem {}", subsystem.nqn)
-                })?),
-                allowed_hosts: subsystem.list_hosts().with_context(|| {
-                    format!(
-                        "Failed to gather allowed hosts for subsystem {}",
-                        subsystem.nqn
-                    )
-                })?,
-                namespaces,
-            };
-            state.subsystems.insert(subsystem.nqn, sub);
+    /// Read a single subsystem, without gathering the whole state.
+    ///
+    /// Returns `None` if there is no subsystem `nqn`.
+    pub fn get_subsystem(nqn: &str) -> Result<Option<Subsystem>> {
+        Self::get_subsystem_in(&NvmetRoot::system(), nqn)
+    }
+
+    /// Read a single port, without gathering the whole state.
+    ///
+    /// Returns `None` if there is no port `id`, or it's not set up yet, like `gather_state`
+    /// leaves those out.
+    pub fn get_port(id: u16) -> Result<Option<Port>> {
+        Self::get_port_in(&NvmetRoot::system(), id)
+    }
+
+    /// Read a single namespace, without gathering the rest of its subsystem.
+    ///
+    /// Returns `None` if the subsystem has no namespace `nsid`, and fails if there is no
+    /// subsystem `nqn` at all.
+    pub fn get_namespace(nqn: &str, nsid: u32) -> Result<Option<Namespace>> {
+        Self::get_namespace_in(&NvmetRoot::system(), nqn, nsid)
+    }
+
+    pub(crate) fn get_namespace_in(
+        root: &NvmetRoot,
+        nqn: &str,
+        nsid: u32,
+    ) -> Result<Option<Namespace>> {
+        assert_valid_nqn(nqn)?;
+        assert_valid_nsid(nsid)?;
+        root.check_exists()?;
+        if !root.has_subsystem(nqn)? {
+            return Err(Error::NoSuchSubsystem(nqn.to_string()));
+        }
+        let subsystem = root.open_subsystem(nqn)?;
+        if !subsystem.has_namespace(nsid)? {
+            return Ok(None);
         }
+        let ns = subsystem
+            .open_namespace(nsid)?
+            .get_namespace()
+            .with_context(|| format!("Failed to get namespace {nsid} for subsystem {nqn}"))?;
+        Ok(Some(ns))
+    }
 
-        Ok(state)
+    pub(crate) fn get_subsystem_in(root: &NvmetRoot, nqn: &str) -> Result<Option<Subsystem>> {
+        root.check_exists()?;
+        assert_valid_nqn(nqn)?;
+        if !root.has_subsystem(nqn)? {
+            return Ok(None);
+        }
+        Self::gather_subsystem(&root.open_subsystem(nqn)?).map(Some)
     }
 
-    pub fn apply_delta(changes: Vec<StateDelta>) -> Result<()> {
+    pub(crate) fn get_port_in(root: &NvmetRoot, id: u16) -> Result<Option<Port>> {
+        root.check_exists()?;
+        if !root.has_port(id)? {
+            return Ok(None);
+        }
+        Self::gather_port(&root.open_port(id))
+    }
+
+    fn gather_port(port: &NvmetPort) -> Result<Option<Port>> {
+        let Ok(port_type) = port.get_type() else {
+            return Ok(None);
+        };
+        let subs = port
+            .list_subsystems()
+            .with_context(|| format!("Failed to gather subsystem state for port {}", port.id))?;
+        let mut gathered = Port::new(port_type, subs);
+        // Loop ports have no address, whatever family they have is ignored.
+        if port_type != PortType::Loop {
+            gathered.adrfam = port
+                .get_adrfam()
+                .with_context(|| format!("Failed to gather address family of port {}", port.id))?;
+        }
+        Ok(Some(gathered))
+    }
+
+    fn gather_subsystem(subsystem: &NvmetSubsystem) -> Result<Subsystem> {
+        let mut sub = Self::gather_subsystem_attrs(subsystem)?;
+        for (nsid, nvmetns) in Self::list_subsystem_namespaces(subsystem)? {
+            sub.namespaces
+                .insert(nsid, Self::gather_namespace(subsystem, nsid, &nvmetns)?);
+        }
+        Ok(sub)
+    }
+
+    /// Read a subsystem, leaving out its namespaces.
+    fn gather_subsystem_attrs(subsystem: &NvmetSubsystem) -> Result<Subsystem> {
+        Ok(Subsystem {
+            active: None,
+            alias: None,
+            labels: BTreeMap::new(),
+            model: Some(subsystem.get_model().with_context(|| {
+                format!("Failed to gather model for subsystem {}", subsystem.nqn)
+            })?),
+            serial: Some(subsystem.get_serial().with_context(|| {
+                format!("Failed to gather serial for subsystem {}", subsystem.nqn)
+            })?),
+            qid_max: subsystem.get_qid_max().with_context(|| {
+                format!(
+                    "Failed to gather maximum number of I/O queues for subsystem {}",
+                    subsystem.nqn
+                )
+            })?,
+            allowed_hosts: subsystem.list_hosts().with_context(|| {
+                format!(
+                    "Failed to gather allowed hosts for subsystem {}",
+                    subsystem.nqn
+                )
+            })?,
+            namespaces: BTreeMap::new(),
+        })
+    }
+
+    fn list_subsystem_namespaces(
+        subsystem: &NvmetSubsystem,
+    ) -> Result<BTreeMap<u32, NvmetNamespace>> {
+        subsystem.list_namespaces().with_context(|| {
+            format!(
+                "Failed to gather namespaces for subsystem {}",
+                subsystem.nqn
+            )
+        })
+    }
+
+    fn gather_namespace(
+        subsystem: &NvmetSubsystem,
+        nsid: u32,
+        nvmetns: &NvmetNamespace,
+    ) -> Result<Namespace> {
+        nvmetns.get_namespace().with_context(|| {
+            format!(
+                "Failed to get namespace {} for subsystem {}",
+                nsid, subsystem.nqn
+            )
+        })
+    }
+
+    pub(crate) fn apply_delta_in(root: &NvmetRoot, changes: Vec<StateDelta>) -> Result<()> {
+        let host_removals = if may_leave_hosts_unused(&changes) {
+            let current = Self::gather_state_in(root)
+                .context("Failed to gather state for planning host removals")?;
+            plan_host_removals(root, &current, &changes)?
+        } else {
+            BTreeSet::new()
+        };
+        Self::apply_planned(root, changes, &host_removals, None)
+    }
+
+    pub(crate) fn apply_delta_checked_in(
+        root: &NvmetRoot,
+        base: &State,
+        changes: Vec<StateDelta>,
+    ) -> Result<()> {
+        let host_removals = plan_host_removals(root, base, &changes)?;
+        Self::apply_planned(root, changes, &host_removals, Some(base))
+    }
+
+    /// Apply the changes, then remove the hosts planned to be left unused by them.
+    /// With a `base` state, the preconditions of each change are checked against it.
+    fn apply_planned(
+        root: &NvmetRoot,
+        changes: Vec<StateDelta>,
+        host_removals: &BTreeSet<String>,
+        base: Option<&State>,
+    ) -> Result<()> {
+        Self::apply_changes(root, changes, base)?;
+        remove_unused_hosts(root, host_removals, true, false)
+            .context("Failed to remove unused hosts")?;
+        Ok(())
+    }
+
+    fn apply_changes(
+        root: &NvmetRoot,
+        changes: Vec<StateDelta>,
+        base: Option<&State>,
+    ) -> Result<()> {
+        let mut expected = base.cloned();
+        let devices = DeviceCache::default();
+        Self::check_devices(root, &changes, &devices)?;
         for change in changes {
+            if let Some(expected) = &mut expected {
+                check_precondition(root, expected, &change)?;
+                expected.apply_deltas(std::slice::from_ref(&change))?;
+            }
+            tracing::debug!(delta = ?change, "Applying change");
             match change {
                 StateDelta::AddPort(id, port) => {
-                    let p = NvmetRoot::create_port(id)
+                    let p = root
+                        .create_port(id)
                         .with_context(|| format!("Failed to add new port {id}"))?;
                     p.set_type(port.port_type)
                         .with_context(|| format!("Failed to set new port type for port {id}"))?;
@@ -78,16 +347,24 @@ impl KernelConfig {
                     })?;
                 }
                 StateDelta::UpdatePort(id, deltas) => {
-                    if !NvmetRoot::has_port(id)? {
-                        return Err(Into::<anyhow::Error>::into(Error::NoSuchPort(id)))
+                    if !root.has_port(id)? {
+                        return Err(Error::NoSuchPort(id))
                             .with_context(|| format!("Failed to update port {id}"));
                     }
-                    let p = NvmetRoot::open_port(id);
+                    let p = root.open_port(id);
+                    // Removals go first, so changing the type doesn't reattach those.
+                    let mut deltas = deltas;
+                    PortDelta::sort(&mut deltas);
                     for delta in deltas {
                         match delta {
                             PortDelta::UpdatePortType(pt) => p.set_type(pt).with_context(|| {
                                 format!("Failed to update port type of port {id}")
                             })?,
+                            PortDelta::UpdateAddress(pt) => {
+                                p.set_address(pt).with_context(|| {
+                                    format!("Failed to update address of port {id}")
+                                })?
+                            }
                             PortDelta::AddSubsystem(nqn) => {
                                 p.enable_subsystem(&nqn).with_context(|| {
                                     format!("Failed to add subsystem {nqn} to port {id}")
@@ -102,18 +379,17 @@ impl KernelConfig {
                     }
                 }
                 StateDelta::RemovePort(id) => {
-                    NvmetRoot::delete_port(id)
+                    root.delete_port(id)
                         .with_context(|| format!("Failed to remove port {id}"))?;
                 }
 
                 StateDelta::AddSubsystem(nqn, sub) => {
-                    if NvmetRoot::has_subsystem(&nqn)? {
-                        return Err(Into::<anyhow::Error>::into(Error::ExistingSubsystem(
-                            nqn.to_owned(),
-                        )))
-                        .with_context(|| format!("Failed to add new subsystem {nqn}"));
+                    if root.has_subsystem(&nqn)? {
+                        return Err(Error::ExistingSubsystem(nqn.to_owned()))
+                            .with_context(|| format!("Failed to add new subsystem {nqn}"));
                     }
-                    let nvmetsub = NvmetRoot::create_subsystem(&nqn)
+                    let nvmetsub = root
+                        .create_subsystem(&nqn)
                         .with_context(|| format!("Failed to add new subsystem {nqn}"))?;
                     if let Some(model) = sub.model {
                         nvmetsub.set_model(&model).with_context(|| {
@@ -125,21 +401,27 @@ impl KernelConfig {
                             format!("Failed to set serial for new subsystem {nqn}")
                         })?;
                     }
-                    nvmetsub.set_namespaces(&sub.namespaces).with_context(|| {
-                        format!("Failed to add namespaces for new subsystem {nqn}")
-                    })?;
+                    if let Some(qid_max) = sub.qid_max {
+                        nvmetsub.set_qid_max(qid_max).with_context(|| {
+                            format!("Failed to set maximum number of I/O queues for new subsystem {nqn}")
+                        })?;
+                    }
+                    nvmetsub
+                        .set_namespaces(&sub.namespaces, &devices)
+                        .with_context(|| {
+                            format!("Failed to add namespaces for new subsystem {nqn}")
+                        })?;
                     nvmetsub.set_hosts(&sub.allowed_hosts).with_context(|| {
                         format!("Failed to set allowed hosts for new subsystem {nqn}")
                     })?;
                 }
                 StateDelta::UpdateSubsystem(nqn, deltas) => {
-                    if !NvmetRoot::has_subsystem(&nqn)? {
-                        return Err(Into::<anyhow::Error>::into(Error::NoSuchSubsystem(
-                            nqn.to_owned(),
-                        )))
-                        .with_context(|| format!("Failed to update existing subsystem {nqn}"));
+                    if !root.has_subsystem(&nqn)? {
+                        return Err(Error::NoSuchSubsystem(nqn.to_owned()))
+                            .with_context(|| format!("Failed to update existing subsystem {nqn}"));
                     }
-                    let nvmetsub = NvmetRoot::open_subsystem(&nqn)
+                    let nvmetsub = root
+                        .open_subsystem(&nqn)
                         .with_context(|| format!("Failed to update subsystem {nqn}"))?;
                     for delta in deltas {
                         match delta {
@@ -153,6 +435,11 @@ impl KernelConfig {
                                     format!("Failed to update serial for subsystem {nqn}")
                                 })?
                             }
+                            SubsystemDelta::UpdateQidMax(qid_max) => {
+                                nvmetsub.set_qid_max(qid_max).with_context(|| {
+                                    format!("Failed to update maximum number of I/O queues for subsystem {nqn}")
+                                })?
+                            }
                             SubsystemDelta::AddHost(host) => {
                                 nvmetsub.set_allow_any(false).with_context(|| {
                                     format!("Failed to unset attr_allow_any_host before adding allowed host to subsystem {nqn}")
@@ -172,23 +459,13 @@ impl KernelConfig {
                                 if hosts.is_empty() {
                                     nvmetsub.set_allow_any(true).with_context(|| format!("Failed to set attr_allow_any_host after removing host {host} from subsystem {nqn}"))?;
                                 }
-
-                                let used_hosts = NvmetRoot::list_used_hosts()
-                                    .with_context(|| format!("Failed to list all allowed hosts before removing host {host} from subsystem {nqn}"))?;
-                                if !used_hosts.contains(&host) {
-                                    NvmetRoot::remove_host(&host).with_context(|| {
-                                        format!(
-                        "Failed to remove unused hosts after deletion of subsystem {nqn}"
-                                            )
-                                    })?;
-                                }
                             }
                             SubsystemDelta::AddNamespace(nsid, ns) => {
                                 let nvmetns =
                                     nvmetsub.create_namespace(nsid).with_context(|| {
                                         format!("Failed to add namespace for subsystem {nqn}")
                                     })?;
-                                nvmetns.set_namespace(&ns).with_context(|| {
+                                nvmetns.set_namespace(&ns, &devices).with_context(|| {
                                     format!("Failed to set new namespace for subsystem {nqn}")
                                 })?;
                             }
@@ -196,7 +473,7 @@ impl KernelConfig {
                                 let nvmetns = nvmetsub.open_namespace(nsid).with_context(|| {
                                     format!("Failed to update namespace for subsystem {nqn}")
                                 })?;
-                                nvmetns.set_namespace(&ns).with_context(|| {
+                                nvmetns.set_namespace(&ns, &devices).with_context(|| {
                                     format!("Failed to update namespace for subsystem {nqn}")
                                 })?;
                             }
@@ -205,24 +482,25 @@ impl KernelConfig {
                                     format!("Failed to remove namespace for subsystem {nqn}")
                                 })?;
                             }
+                            SubsystemDelta::SetNamespaceEnabled(nsid, enabled) => {
+                                let nvmetns = nvmetsub.open_namespace(nsid).with_context(|| {
+                                    format!("Failed to open namespace {nsid} of subsystem {nqn}")
+                                })?;
+                                nvmetns.set_enabled(enabled).with_context(|| {
+                                    format!("Failed to update namespace for subsystem {nqn}")
+                                })?;
+                            }
                         }
                     }
                 }
                 StateDelta::RemoveSubsystem(nqn) => {
-                    if !NvmetRoot::has_subsystem(&nqn)? {
-                        return Err(Into::<anyhow::Error>::into(Error::NoSuchSubsystem(
-                            nqn.to_owned(),
-                        )))
-                        .with_context(|| format!("Failed to remove existing subsystem {nqn}"));
+                    if !root.has_subsystem(&nqn)? {
+                        return Err(Error::NoSuchSubsystem(nqn.to_owned()))
+                            .with_context(|| format!("Failed to remove existing subsystem {nqn}"));
                     }
 
-                    // Fetch our hosts just before we remove the subsystem.
-                    let our_hosts = NvmetRoot::open_subsystem(&nqn)?
-                        .list_hosts()
-                        .with_context(|| format!("Failed to list subsystem hosts before removing existing subsystem {nqn}"))?;
-
                     // Before removing the subsystem, we need to remove all references to it.
-                    for port in NvmetRoot::list_ports().with_context(|| {
+                    for port in root.list_ports().with_context(|| {
                         format!("Failed to list ports before removing existing subsystem {nqn}")
                     })? {
                         if port.has_subsystem(&nqn).with_context(|| {
@@ -234,21 +512,684 @@ impl KernelConfig {
                         }
                     }
 
-                    NvmetRoot::delete_subsystem(&nqn)
+                    root.delete_subsystem(&nqn)
                         .with_context(|| format!("Failed to remove subsystem {nqn}"))?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-                    // Iterate over all remaining subsystems and find what host we're missing now.
-                    let current_hosts = NvmetRoot::list_used_hosts().with_context(|| format!("Failed to list used allowed hosts before removing existing subsystem {nqn}"))?;
-                    for unused_host in our_hosts.difference(&current_hosts) {
-                        NvmetRoot::remove_host(unused_host).with_context(|| {
-                            format!(
-                                "Failed to remove unused hosts after deletion of subsystem {nqn}"
-                            )
-                        })?;
-                    }
+    /// Check the devices of the namespaces added or updated by `changes` before changing
+    /// anything, so a missing device doesn't leave them applied halfway.
+    ///
+    /// The devices end up in `devices`, so applying the changes doesn't resolve them again.
+    fn check_devices(
+        root: &NvmetRoot,
+        changes: &[StateDelta],
+        devices: &DeviceCache,
+    ) -> Result<()> {
+        for change in changes {
+            let (nqn, namespaces): (_, Vec<_>) = match change {
+                StateDelta::AddSubsystem(nqn, sub) => (nqn, sub.namespaces.iter().collect()),
+                StateDelta::UpdateSubsystem(nqn, deltas) => (
+                    nqn,
+                    deltas
+                        .iter()
+                        .filter_map(|delta| match delta {
+                            SubsystemDelta::AddNamespace(nsid, ns)
+                            | SubsystemDelta::UpdateNamespace(nsid, ns) => Some((nsid, ns)),
+                            _ => None,
+                        })
+                        .collect(),
+                ),
+                _ => continue,
+            };
+            for (nsid, ns) in namespaces {
+                root.resolve_device(&ns.device_path, devices)
+                    .with_context(|| {
+                        format!(
+                            "Failed to resolve device {} for namespace {nsid} of subsystem {nqn}",
+                            ns.device_path.display()
+                        )
+                    })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::kernel::fake::{FakeBackend, FakeOp};
+    use crate::state::{AddrFamily, FibreChannelAddr, PortAddrFamily};
+    use std::collections::BTreeSet;
+    use uuid::Uuid;
+
+    pub(crate) const SUB: &str = "nqn.2023-11.sh.tty:fake-sub";
+    pub(crate) const HOST: &str = "nqn.2023-11.sh.tty:fake-host";
+
+    pub(crate) fn example_state() -> State {
+        let mut state = State::default();
+        state.subsystems.insert(
+            SUB.to_string(),
+            Subsystem {
+                active: None,
+                alias: None,
+                labels: BTreeMap::new(),
+                model: Some("Loop".to_string()),
+                qid_max: None,
+                serial: Some("1337".to_string()),
+                allowed_hosts: BTreeSet::from([HOST.to_string()]),
+                namespaces: BTreeMap::from([(
+                    1,
+                    Namespace {
+                        enabled: true,
+                        device_path: "/dev/loop0".into(),
+                        device_spec: None,
+                        device_uuid: Some(Uuid::from_u128(42)),
+                        device_nguid: Some(Uuid::nil()),
+                    },
+                )]),
+            },
+        );
+        state.ports.insert(
+            1,
+            Port::new(
+                PortType::Tcp("0.0.0.0:4420".parse().unwrap()),
+                BTreeSet::from([SUB.to_string()]),
+            ),
+        );
+        state
+    }
+
+    #[test]
+    fn test_apply_gather_roundtrip() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let desired = example_state();
+
+        let current = KernelConfig::gather_state_in(&root)?;
+        assert_eq!(current, State::default());
+        KernelConfig::apply_delta_in(&root, current.get_deltas(&desired))?;
+
+        fake.take_ops();
+        let current = KernelConfig::gather_state_in(&root)?;
+        assert_eq!(current, desired);
+        // Gathering must never modify anything.
+        assert!(!fake
+            .take_ops()
+            .iter()
+            .any(|op| !matches!(op, FakeOp::Exists(_) | FakeOp::Read(_) | FakeOp::List(_))));
+
+        KernelConfig::apply_delta_in(&root, current.get_deltas(&State::default()))?;
+        assert_eq!(KernelConfig::gather_state_in(&root)?, State::default());
+        assert!(!fake.contains(&format!("hosts/{HOST}")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_qid_max() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let desired = example_state();
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+        // Kernels without attr_qid_max leave it unset.
+        assert_eq!(KernelConfig::gather_state_in(&root)?, desired);
+
+        fake.add_attr(&format!("subsystems/{SUB}/attr_qid_max"), "128");
+        let mut current = KernelConfig::gather_state_in(&root)?;
+        assert_eq!(current.subsystems[SUB].qid_max, Some(128));
+        current.subsystems.get_mut(SUB).unwrap().qid_max = Some(4);
+        let deltas = KernelConfig::gather_state_in(&root)?.get_deltas(&current);
+        assert_eq!(
+            deltas,
+            vec![StateDelta::UpdateSubsystem(
+                SUB.to_string(),
+                vec![SubsystemDelta::UpdateQidMax(4)]
+            )]
+        );
+        KernelConfig::apply_delta_in(&root, deltas)?;
+        assert_eq!(
+            fake.attr(&format!("subsystems/{SUB}/attr_qid_max"))
+                .unwrap(),
+            "4"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather_state_partial() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let mut desired = example_state();
+        let broken = "nqn.2023-11.sh.tty:broken";
+        desired
+            .subsystems
+            .insert(broken.to_string(), desired.subsystems[SUB].clone());
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+
+        // A half-deleted namespace.
+        fake.remove_attr(&format!("subsystems/{broken}/namespaces/1/device_path"));
+        assert!(KernelConfig::gather_state_in(&root).is_err());
+
+        let (state, warnings) = KernelConfig::gather_state_partial_in(&root)?;
+        desired.subsystems.remove(broken);
+        assert_eq!(state, desired);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].nqn, broken);
+        assert!(warnings[0].to_string().contains("namespace 1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather_missing_groups() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        for group in ["ports", "subsystems", "hosts"] {
+            fake.remove_attr(group);
+        }
+        assert_eq!(KernelConfig::gather_state_in(&root)?, State::default());
+        assert!(root.list_all_hosts()?.is_empty());
+        assert_eq!(KernelConfig::get_port_in(&root, 1)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather_denied_group() {
+        let (fake, root) = FakeBackend::new_root();
+        fake.deny("subsystems");
+        let err = KernelConfig::gather_state_in(&root).unwrap_err();
+        assert!(err.full_message().contains("Failed to list subsystems"));
+        assert!(
+            matches!(err.root(), Error::Io(err) if err.kind() == std::io::ErrorKind::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn test_permission_denied() {
+        let setup = || {
+            let (fake, root) = FakeBackend::new_root();
+            fake.add_device("/dev/loop0");
+            fake.deny("ports/1/addr_trtype");
+            (fake, root)
+        };
+        let deltas = State::default().get_deltas(&example_state());
+        let (fake, _) = setup();
+        let err =
+            KernelConfig::apply_delta_in(&fake.unprivileged_root(), deltas.clone()).unwrap_err();
+        assert!(
+            matches!(err.root(), Error::PermissionDenied(path) if path.ends_with("ports/1/addr_trtype"))
+        );
+        assert!(err.full_message().contains("CAP_SYS_ADMIN"));
+
+        // As root, the kernel's own reasons for refusing writes are left alone.
+        let (_, root) = setup();
+        let err = KernelConfig::apply_delta_in(&root, deltas).unwrap_err();
+        assert!(matches!(err.root(), Error::Io(_)));
+    }
+
+    #[test]
+    fn test_iter_subsystems() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let mut desired = example_state();
+        for i in 0..3 {
+            desired.subsystems.insert(
+                format!("nqn.2023-11.sh.tty:sub{i}"),
+                desired.subsystems[SUB].clone(),
+            );
+        }
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+        fake.take_ops();
+
+        // Nothing is read before it's needed.
+        let mut iter = KernelConfig::iter_subsystems_in(&root);
+        let (nqn, sub) = iter.next().unwrap()?;
+        assert_eq!(sub, desired.subsystems[&nqn]);
+        let read_others = fake.take_ops().iter().any(|op| {
+            matches!(op, FakeOp::Read(path) if path.starts_with("subsystems/") && !path.contains(&nqn))
+        });
+        assert!(!read_others);
+        assert_eq!(iter.count(), 3);
+
+        let gathered =
+            KernelConfig::iter_subsystems_in(&root).collect::<Result<BTreeMap<_, _>>>()?;
+        assert_eq!(gathered, desired.subsystems);
+
+        // Unreadable subsystems don't end the iteration.
+        fake.remove_attr("subsystems/nqn.2023-11.sh.tty:sub1/attr_serial");
+        let results: Vec<_> = KernelConfig::iter_subsystems_in(&root).collect();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results.iter().filter(|res| res.is_err()).count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_single() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let mut desired = example_state();
+        let other = "nqn.2023-11.sh.tty:other";
+        desired
+            .subsystems
+            .insert(other.to_string(), desired.subsystems[SUB].clone());
+        desired.ports.insert(2, desired.ports[&1].clone());
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+        fake.take_ops();
+
+        let sub = KernelConfig::get_subsystem_in(&root, SUB)?;
+        assert_eq!(sub.as_ref(), desired.subsystems.get(SUB));
+        let port = KernelConfig::get_port_in(&root, 1)?;
+        assert_eq!(port.as_ref(), desired.ports.get(&1));
+        // Only the requested objects were looked at.
+        for op in fake.take_ops() {
+            match op {
+                FakeOp::Exists(path) | FakeOp::Read(path) | FakeOp::List(path) => {
+                    assert!(
+                        path.is_empty() || path.contains(SUB) || path.starts_with("ports/1"),
+                        "{path}"
+                    );
+                    assert!(!path.contains(other) && !path.starts_with("ports/2"));
+                }
+                op => panic!("unexpected {op:?}"),
+            }
+        }
+
+        assert_eq!(
+            KernelConfig::get_subsystem_in(&root, "nqn.2023-11.sh.tty:missing")?,
+            None
+        );
+        assert_eq!(KernelConfig::get_port_in(&root, 3)?, None);
+        assert!(KernelConfig::get_subsystem_in(&root, "../ports").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_namespace() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        fake.add_device("/dev/loop1");
+        let mut desired = example_state();
+        let sub = desired.subsystems.get_mut(SUB).unwrap();
+        for nsid in 2..=4 {
+            let mut ns = sub.namespaces[&1].clone();
+            ns.device_path = "/dev/loop1".into();
+            ns.device_uuid = Some(Uuid::from_u128(nsid.into()));
+            sub.namespaces.insert(nsid, ns);
+        }
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+        fake.take_ops();
+
+        let ns = KernelConfig::get_namespace_in(&root, SUB, 3)?;
+        assert_eq!(ns.as_ref(), desired.subsystems[SUB].namespaces.get(&3));
+        // Neither the other namespaces nor the rest of the subsystem were read.
+        for op in fake.take_ops() {
+            match op {
+                FakeOp::Exists(path) | FakeOp::Read(path) | FakeOp::List(path) => {
+                    assert!(
+                        path.is_empty()
+                            || path == format!("subsystems/{SUB}")
+                            || path.starts_with(&format!("subsystems/{SUB}/namespaces/3")),
+                        "{path}"
+                    );
                 }
+                op => panic!("unexpected {op:?}"),
             }
         }
+
+        assert_eq!(KernelConfig::get_namespace_in(&root, SUB, 5)?, None);
+        let err =
+            KernelConfig::get_namespace_in(&root, "nqn.2023-11.sh.tty:missing", 1).unwrap_err();
+        assert!(matches!(err, Error::NoSuchSubsystem(_)));
+        let err = KernelConfig::get_namespace_in(&root, SUB, 0).unwrap_err();
+        assert!(matches!(err, Error::InvalidNamespaceID(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_state_residual() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let mut desired = example_state();
+        // Leave the UUIDs to the kernel, which must not count as a difference.
+        let ns = desired
+            .subsystems
+            .get_mut(SUB)
+            .unwrap()
+            .namespaces
+            .get_mut(&1)
+            .unwrap();
+        ns.device_uuid = None;
+        ns.device_nguid = None;
+
+        let current = KernelConfig::gather_state_in(&root)?;
+        KernelConfig::apply_delta_in(&root, current.get_deltas(&desired))?;
+        assert!(KernelConfig::verify_state_in(&root, &desired)?.is_empty());
+
+        // Simulate the kernel normalizing the model.
+        fake.pin_attr(&format!("subsystems/{SUB}/attr_model"), "Linux");
+        let current = KernelConfig::gather_state_in(&root)?;
+        KernelConfig::apply_delta_in(&root, current.get_deltas(&desired))?;
+        assert_eq!(
+            KernelConfig::verify_state_in(&root, &desired)?,
+            vec![StateDelta::UpdateSubsystem(
+                SUB.to_string(),
+                vec![SubsystemDelta::UpdateModel("Loop".to_string())]
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_port_subsystems() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let other = "nqn.2023-11.sh.tty:fake-other";
+        let mut desired = example_state();
+        desired
+            .subsystems
+            .insert(other.to_string(), Subsystem::default());
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+
+        let current = KernelConfig::gather_state_in(&root)?;
+        let subs = BTreeSet::from([other.to_string()]);
+        KernelConfig::apply_delta_in(&root, current.get_port_subsystem_deltas(1, &subs)?)?;
+        assert_eq!(
+            KernelConfig::gather_state_in(&root)?.ports[&1].subsystems,
+            subs
+        );
+
+        let current = KernelConfig::gather_state_in(&root)?;
+        assert!(current.get_port_subsystem_deltas(1, &subs)?.is_empty());
+        assert!(current
+            .get_port_subsystem_deltas(
+                1,
+                &BTreeSet::from(["nqn.2023-11.sh.tty:missing".to_string()])
+            )
+            .is_err());
+        assert!(current.get_port_subsystem_deltas(2, &subs).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clear_port_subsystems() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let desired = example_state();
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+
+        let current = KernelConfig::gather_state_in(&root)?;
+        let deltas = current.get_port_subsystem_deltas(1, &BTreeSet::new())?;
+        assert_eq!(
+            deltas,
+            vec![StateDelta::UpdatePort(
+                1,
+                vec![PortDelta::RemoveSubsystem(SUB.to_string())]
+            )]
+        );
+        KernelConfig::apply_delta_in(&root, deltas)?;
+
+        let state = KernelConfig::gather_state_in(&root)?;
+        assert_eq!(state.ports[&1].port_type, desired.ports[&1].port_type);
+        assert!(state.ports[&1].subsystems.is_empty());
+        assert!(state.subsystems.contains_key(SUB));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_namespace_enabled() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let desired = example_state();
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+
+        fake.take_ops();
+        let deltas = desired.subsystems[SUB].get_enable_deltas(false, None);
+        KernelConfig::apply_delta_in(
+            &root,
+            vec![StateDelta::UpdateSubsystem(SUB.to_string(), deltas)],
+        )?;
+        // Only the enable attribute gets touched.
+        let writes: Vec<FakeOp> = fake
+            .take_ops()
+            .into_iter()
+            .filter(|op| matches!(op, FakeOp::Write(_, _)))
+            .collect();
+        assert_eq!(
+            writes,
+            vec![FakeOp::Write(
+                format!("subsystems/{SUB}/namespaces/1/enable"),
+                "0".to_string()
+            )]
+        );
+        assert!(!KernelConfig::gather_state_in(&root)?.subsystems[SUB].namespaces[&1].enabled);
+        Ok(())
+    }
+
+    #[test]
+    fn test_port_address_and_subsystems_change() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let (a, b, c) = (
+            "nqn.2023-11.sh.tty:a",
+            "nqn.2023-11.sh.tty:b",
+            "nqn.2023-11.sh.tty:c",
+        );
+        let mut base = State::default();
+        for nqn in [a, b, c] {
+            base.subsystems
+                .insert(nqn.to_string(), Subsystem::default());
+        }
+        let v4 = PortType::Tcp("192.0.2.1:4420".parse()?);
+        let v6 = PortType::Tcp("[2001:db8::1]:4420".parse()?);
+        base.ports.insert(
+            1,
+            Port::new(v4, BTreeSet::from([a.to_string(), b.to_string()])),
+        );
+        let mut desired = base.clone();
+        desired.ports.insert(
+            1,
+            Port::new(v6, BTreeSet::from([b.to_string(), c.to_string()])),
+        );
+
+        // Both the computed order and a reversed one must work the same.
+        let deltas = base.get_deltas(&desired);
+        let StateDelta::UpdatePort(_, port_deltas) = &deltas[0] else {
+            panic!("expected a port update, got {deltas:?}");
+        };
+        let reversed = vec![StateDelta::UpdatePort(
+            1,
+            port_deltas.iter().rev().cloned().collect(),
+        )];
+        for deltas in [deltas.clone(), reversed] {
+            KernelConfig::apply_delta_in(&root, State::default().get_deltas(&base))?;
+            fake.take_ops();
+            KernelConfig::apply_delta_in(&root, deltas)?;
+            // The removed subsystem is never linked again in between.
+            assert!(!fake
+                .take_ops()
+                .contains(&FakeOp::Symlink(format!("ports/1/subsystems/{a}"))));
+            assert_eq!(KernelConfig::gather_state_in(&root)?.ports, desired.ports);
+            KernelConfig::apply_delta_in(&root, desired.get_deltas(&State::default()))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather_port_adrfam() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let mut desired = State::default();
+        for (id, port_type) in [
+            (1, PortType::Tcp("192.0.2.1:4420".parse()?)),
+            (2, PortType::Rdma("[2001:db8::1]:4420".parse()?)),
+            (
+                3,
+                PortType::FibreChannel(FibreChannelAddr::new(
+                    0x1000_0000_4400_1123,
+                    0x2000_0000_5500_1123,
+                )),
+            ),
+            (4, PortType::Loop),
+        ] {
+            desired
+                .ports
+                .insert(id, Port::new(port_type, BTreeSet::new()));
+        }
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+
+        let gathered = KernelConfig::gather_state_in(&root)?;
+        for (id, port) in &gathered.ports {
+            let sysfs = fake.attr(&format!("ports/{id}/addr_adrfam")).unwrap();
+            assert_eq!(
+                port.adrfam
+                    .map(|adrfam| adrfam.to_string())
+                    .unwrap_or_default(),
+                sysfs,
+                "port {id}"
+            );
+        }
+        assert_eq!(
+            gathered.ports[&2].adrfam,
+            Some(PortAddrFamily::Ip(AddrFamily::Ipv6))
+        );
+        assert_eq!(gathered.ports[&3].adrfam, Some(PortAddrFamily::Fc));
+        assert_eq!(gathered.ports[&4].adrfam, None);
+        assert_eq!(gathered, desired);
+
+        // Left not matching the address by another tool, which restoring puts right.
+        fake.set_attr("ports/2/addr_adrfam", "ipv4");
+        let gathered = KernelConfig::gather_state_in(&root)?;
+        assert_eq!(
+            gathered.ports[&2].adrfam,
+            Some(PortAddrFamily::Ip(AddrFamily::Ipv4))
+        );
+        assert_ne!(gathered, desired);
+        assert!(gathered
+            .validate(false)
+            .unwrap_err()
+            .root()
+            .is_invalid_input());
+        KernelConfig::apply_delta_in(&root, gathered.get_deltas(&desired))?;
+        assert_eq!(fake.attr("ports/2/addr_adrfam").unwrap(), "ipv6");
+        assert_eq!(KernelConfig::gather_state_in(&root)?, desired);
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_resolution_cached_per_apply() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let mpath = "/dev/disk/by-id/dm-uuid-mpath-3600a0980";
+        fake.add_device_link(mpath, "/dev/dm-0");
+        let mut desired = example_state();
+        let sub = desired.subsystems.get_mut(SUB).unwrap();
+        for nsid in 1..=50 {
+            sub.namespaces.insert(
+                nsid,
+                Namespace {
+                    enabled: true,
+                    device_path: mpath.into(),
+                    device_spec: None,
+                    device_uuid: Some(Uuid::from_u128(nsid.into())),
+                    device_nguid: None,
+                },
+            );
+        }
+        let resolved = |ops: Vec<FakeOp>| {
+            ops.into_iter()
+                .filter(|op| matches!(op, FakeOp::ResolveDevice(_)))
+                .count()
+        };
+
+        fake.take_ops();
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+        // All namespaces share one device, which is resolved once.
+        assert_eq!(resolved(fake.take_ops()), 1);
+        let path = format!("subsystems/{SUB}/namespaces/50/device_path");
+        assert_eq!(fake.attr(&path).unwrap(), "/dev/dm-0");
+
+        // The next application must not see what the previous one resolved.
+        fake.add_device_link(mpath, "/dev/dm-1");
+        let mut moved = desired.clone();
+        for ns in moved
+            .subsystems
+            .get_mut(SUB)
+            .unwrap()
+            .namespaces
+            .values_mut()
+        {
+            ns.enabled = false;
+        }
+        let current = KernelConfig::gather_state_in(&root)?;
+        KernelConfig::apply_delta_in(&root, current.get_deltas(&moved))?;
+        assert_eq!(resolved(fake.take_ops()), 1);
+        assert_eq!(fake.attr(&path).unwrap(), "/dev/dm-1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_resolution_large_state() -> Result<()> {
+        // 2000 namespaces in 40 subsystems, spread over the paths of 4 multipath devices.
+        let (fake, root) = FakeBackend::new_root();
+        let mut desired = State::default();
+        for dev in 0..4 {
+            let mpath = format!("/dev/disk/by-id/dm-uuid-mpath-{dev}");
+            fake.add_device_link(mpath, format!("/dev/dm-{dev}"));
+        }
+        for sub in 0..40 {
+            let mut subsystem = Subsystem::default();
+            for nsid in 1..=50 {
+                let mut ns =
+                    Namespace::builder(format!("/dev/disk/by-id/dm-uuid-mpath-{}", nsid % 4))
+                        .build()?;
+                ns.device_uuid = Some(Uuid::from_u128(sub * 100 + u128::from(nsid)));
+                subsystem.namespaces.insert(nsid, ns);
+            }
+            desired
+                .subsystems
+                .insert(format!("nqn.2023-11.sh.tty:large-{sub}"), subsystem);
+        }
+
+        fake.take_ops();
+        let report = KernelConfig::apply_state_in(&root, &desired, &ApplyOptions::default())?;
+        assert!(report.applied);
+        // Checked up front and set, without the cache that would be 4000 resolutions.
+        let ops = fake.take_ops();
+        let resolved: Vec<_> = ops
+            .iter()
+            .filter(|op| matches!(op, FakeOp::ResolveDevice(_)))
+            .collect();
+        assert_eq!(resolved.len(), 4, "{resolved:?}");
+        assert!(ops
+            .iter()
+            .take_while(|op| !matches!(op, FakeOp::Write(..) | FakeOp::CreateDir(_)))
+            .any(|op| matches!(op, FakeOp::ResolveDevice(_))));
+        let path = "subsystems/nqn.2023-11.sh.tty:large-39/namespaces/50/device_path";
+        assert_eq!(fake.attr(path).unwrap(), "/dev/dm-2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_device_changes_nothing() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let mut desired = example_state();
+        let sub = desired.subsystems.get_mut(SUB).unwrap();
+        sub.namespaces
+            .insert(2, Namespace::builder("/dev/missing").build()?);
+
+        fake.take_ops();
+        let err =
+            KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired)).unwrap_err();
+        assert!(matches!(err.root(), Error::InvalidDevice(_)), "{err:?}");
+        assert!(
+            err.to_string().contains("namespace 2 of subsystem"),
+            "{err}"
+        );
+        // Found before changing anything.
+        assert!(!fake
+            .take_ops()
+            .iter()
+            .any(|op| matches!(op, FakeOp::Write(..) | FakeOp::CreateDir(_))));
         Ok(())
     }
 }
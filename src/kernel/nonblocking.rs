@@ -0,0 +1,55 @@
+// Async variants of the main entry points, for embedders running an async executor.
+// Reading and writing configfs blocks, so it is moved to a thread pool. The `blocking`
+// crate works with any executor, tokio included.
+
+use super::sysfs::NvmetRoot;
+use super::KernelConfig;
+use crate::errors::Result;
+use crate::state::{State, StateDelta};
+
+impl KernelConfig {
+    /// Like `KernelConfig::gather_state`, without blocking the executor.
+    pub async fn gather_state_async() -> Result<State> {
+        Self::gather_state_async_in(NvmetRoot::system()).await
+    }
+
+    /// Like `KernelConfig::apply_delta`, without blocking the executor.
+    pub async fn apply_delta_async(changes: Vec<StateDelta>) -> Result<()> {
+        Self::apply_delta_async_in(Self::system().clone(), changes).await
+    }
+
+    pub(crate) async fn gather_state_async_in(root: NvmetRoot) -> Result<State> {
+        blocking::unblock(move || Self::gather_state_in(&root)).await
+    }
+
+    pub(crate) async fn apply_delta_async_in(
+        config: KernelConfig,
+        changes: Vec<StateDelta>,
+    ) -> Result<()> {
+        blocking::unblock(move || config.apply(changes)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::FakeBackend;
+    use crate::kernel::tests::example_state;
+    use futures_lite::future::block_on;
+
+    #[test]
+    fn test_async_matches_sync() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let changes = State::default().get_deltas(&example_state());
+        block_on(KernelConfig::apply_delta_async_in(
+            KernelConfig::with_root(root.clone()),
+            changes,
+        ))?;
+
+        let state = block_on(KernelConfig::gather_state_async_in(root.clone()))?;
+        assert_eq!(state, KernelConfig::gather_state_in(&root)?);
+        assert_eq!(state, example_state());
+        Ok(())
+    }
+}
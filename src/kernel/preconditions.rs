@@ -0,0 +1,220 @@
+// Checks that the system still looks like the state changes were computed against.
+//
+// Someone else, like nvmetcli or a human echoing into configfs, may change things between
+// gathering the state and applying the changes. Only the objects a change touches are read,
+// so checking stays cheap.
+
+use super::sysfs::{NvmetRoot, NvmetSubsystem};
+use crate::errors::{Error, Result};
+use crate::state::{State, StateDelta, SubsystemDelta};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+fn changed(what: String) -> Error {
+    Error::StateChanged(what)
+}
+
+/// Check that the objects touched by `change` match the `expected` state.
+pub(super) fn check_precondition(
+    root: &NvmetRoot,
+    expected: &State,
+    change: &StateDelta,
+) -> Result<()> {
+    match change {
+        StateDelta::AddPort(id, _) => {
+            if root.has_port(*id)? {
+                return Err(changed(format!("port {id} was added")));
+            }
+        }
+        StateDelta::UpdatePort(id, _) | StateDelta::RemovePort(id) => {
+            let Some(port) = expected.ports.get(id) else {
+                return Ok(());
+            };
+            if !root.has_port(*id)? {
+                return Err(changed(format!("port {id} was removed")));
+            }
+            let port_type = root.open_port(*id).get_type()?;
+            if port_type != port.port_type {
+                return Err(changed(format!(
+                    "port {id} is now {port_type:?} instead of {:?}",
+                    port.port_type
+                )));
+            }
+        }
+        StateDelta::AddSubsystem(nqn, _) => {
+            if root.has_subsystem(nqn)? {
+                return Err(changed(format!("subsystem {nqn} was added")));
+            }
+        }
+        StateDelta::UpdateSubsystem(nqn, deltas) => {
+            let Some(sub) = expected.subsystems.get(nqn) else {
+                return Ok(());
+            };
+            let devices = namespace_devices(&open_subsystem(root, nqn)?)?;
+            for delta in deltas {
+                match delta {
+                    SubsystemDelta::AddNamespace(nsid, _) if devices.contains_key(nsid) => {
+                        return Err(changed(format!(
+                            "namespace {nsid} of subsystem {nqn} was added"
+                        )));
+                    }
+                    SubsystemDelta::UpdateNamespace(nsid, _)
+                    | SubsystemDelta::RemoveNamespace(nsid)
+                    | SubsystemDelta::SetNamespaceEnabled(nsid, _) => {
+                        if let Some(ns) = sub.namespaces.get(nsid) {
+                            check_namespace(
+                                nqn,
+                                *nsid,
+                                &ns.device_path,
+                                devices.get(nsid).map(PathBuf::as_path),
+                            )?;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        StateDelta::RemoveSubsystem(nqn) => {
+            let Some(sub) = expected.subsystems.get(nqn) else {
+                return Ok(());
+            };
+            // A repurposed subsystem shows by its namespaces.
+            let mut devices = namespace_devices(&open_subsystem(root, nqn)?)?;
+            for (nsid, ns) in &sub.namespaces {
+                check_namespace(nqn, *nsid, &ns.device_path, devices.remove(nsid).as_deref())?;
+            }
+            if let Some(nsid) = devices.keys().next() {
+                return Err(changed(format!(
+                    "namespace {nsid} of subsystem {nqn} was added"
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn open_subsystem(root: &NvmetRoot, nqn: &str) -> Result<NvmetSubsystem> {
+    if !root.has_subsystem(nqn)? {
+        return Err(changed(format!("subsystem {nqn} was removed")));
+    }
+    root.open_subsystem(nqn)
+}
+
+fn namespace_devices(sub: &NvmetSubsystem) -> Result<BTreeMap<u32, PathBuf>> {
+    sub.list_namespaces()?
+        .into_iter()
+        .map(|(nsid, ns)| Ok((nsid, ns.get_device_path()?)))
+        .collect()
+}
+
+fn check_namespace(nqn: &str, nsid: u32, expected: &Path, device: Option<&Path>) -> Result<()> {
+    match device {
+        None => Err(changed(format!(
+            "namespace {nsid} of subsystem {nqn} was removed"
+        ))),
+        Some(device) if device != expected => Err(changed(format!(
+            "namespace {nsid} of subsystem {nqn} now uses {} instead of {}",
+            device.display(),
+            expected.display()
+        ))),
+        Some(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::FakeBackend;
+    use crate::kernel::tests::{example_state, SUB};
+    use crate::kernel::KernelConfig;
+    use crate::state::{Namespace, Subsystem};
+
+    fn setup() -> (std::sync::Arc<FakeBackend>, NvmetRoot, State) {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        fake.add_device("/dev/loop1");
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&example_state())).unwrap();
+        let base = KernelConfig::gather_state_in(&root).unwrap();
+        (fake, root, base)
+    }
+
+    fn is_state_changed(err: &Error) -> bool {
+        matches!(err.root(), Error::StateChanged(_))
+    }
+
+    #[test]
+    fn test_repurposed_subsystem() -> Result<()> {
+        let (fake, root, base) = setup();
+        // Someone else points the namespace at another device.
+        fake.set_attr(
+            &format!("subsystems/{SUB}/namespaces/1/device_path"),
+            "/dev/loop1",
+        );
+
+        let remove = vec![StateDelta::RemoveSubsystem(SUB.to_string())];
+        let err = KernelConfig::apply_delta_checked_in(&root, &base, remove.clone()).unwrap_err();
+        assert!(is_state_changed(&err));
+        assert!(err.to_string().contains("namespace 1"));
+        assert!(fake.contains(&format!("subsystems/{SUB}")));
+
+        // Without checking, it goes through.
+        KernelConfig::apply_delta_in(&root, remove)?;
+        assert!(!fake.contains(&format!("subsystems/{SUB}")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_changed_port() -> Result<()> {
+        let (fake, root, base) = setup();
+        fake.set_attr("ports/1/addr_trsvcid", "4421");
+        let err =
+            KernelConfig::apply_delta_checked_in(&root, &base, vec![StateDelta::RemovePort(1)])
+                .unwrap_err();
+        assert!(is_state_changed(&err));
+        assert!(err.to_string().contains("port 1"));
+        assert!(fake.contains("ports/1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_follows_earlier_changes() -> Result<()> {
+        let (fake, root, base) = setup();
+        let other = "nqn.2023-11.sh.tty:other";
+        // Later changes are checked against the state after the earlier ones.
+        KernelConfig::apply_delta_checked_in(
+            &root,
+            &base,
+            vec![
+                StateDelta::AddSubsystem(other.to_string(), Subsystem::default()),
+                StateDelta::UpdateSubsystem(
+                    other.to_string(),
+                    vec![SubsystemDelta::AddNamespace(
+                        1,
+                        Namespace {
+                            enabled: true,
+                            device_path: "/dev/loop1".into(),
+                            device_spec: None,
+                            device_uuid: None,
+                            device_nguid: None,
+                        },
+                    )],
+                ),
+                StateDelta::RemoveSubsystem(other.to_string()),
+            ],
+        )?;
+        assert!(!fake.contains(&format!("subsystems/{other}")));
+
+        // Somebody else created a subsystem we planned to add.
+        let err = KernelConfig::apply_delta_checked_in(
+            &root,
+            &State::default(),
+            vec![StateDelta::AddSubsystem(
+                SUB.to_string(),
+                Subsystem::default(),
+            )],
+        )
+        .unwrap_err();
+        assert!(is_state_changed(&err));
+        Ok(())
+    }
+}
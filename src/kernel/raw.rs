@@ -0,0 +1,234 @@
+// Direct access to attributes nvmetcfg doesn't model yet, for when the kernel is ahead of us.
+// Objects are addressed by type and name instead of by path, so that nothing outside of the
+// nvmet configfs tree can be reached.
+
+use super::sysfs::NvmetRoot;
+use super::KernelConfig;
+use crate::errors::{Context, Error, Result};
+use crate::helpers::assert_valid_nqn;
+use serde::{Serialize, Serializer};
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const MAX_ATTR_LEN: usize = 64;
+
+/// An object in the nvmet configfs tree, like `subsystem:nqn.2023-11.sh.tty:sub`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ObjectRef {
+    Subsystem(String),
+    Port(u16),
+    /// A namespace, by subsystem NQN and namespace ID.
+    Namespace(String, u32),
+    Host(String),
+}
+
+impl ObjectRef {
+    /// Path of the object relative to the nvmet configfs root.
+    fn relative_path(&self) -> PathBuf {
+        match self {
+            Self::Subsystem(nqn) => PathBuf::from("subsystems").join(nqn),
+            Self::Port(id) => PathBuf::from("ports").join(id.to_string()),
+            Self::Namespace(nqn, nsid) => PathBuf::from("subsystems")
+                .join(nqn)
+                .join("namespaces")
+                .join(nsid.to_string()),
+            Self::Host(nqn) => PathBuf::from("hosts").join(nqn),
+        }
+    }
+
+    fn not_found(&self) -> Error {
+        match self {
+            Self::Subsystem(nqn) => Error::NoSuchSubsystem(nqn.clone()),
+            Self::Port(id) => Error::NoSuchPort(*id),
+            Self::Namespace(nqn, nsid) => Error::NoSuchNamespace(*nsid, nqn.clone()),
+            Self::Host(nqn) => Error::NoSuchHost(nqn.clone()),
+        }
+    }
+}
+
+// NQNs end up as a directory name, so they must not be able to leave it.
+fn parse_nqn(nqn: &str, s: &str) -> Result<String> {
+    assert_valid_nqn(nqn)?;
+    if nqn.is_empty() || nqn == "." || nqn == ".." || nqn.contains('/') {
+        return Err(Error::InvalidObjectRef(s.to_string()));
+    }
+    Ok(nqn.to_string())
+}
+
+impl FromStr for ObjectRef {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidObjectRef(s.to_string());
+        let (kind, name) = s.split_once(':').ok_or_else(invalid)?;
+        match kind {
+            "subsystem" => Ok(Self::Subsystem(parse_nqn(name, s)?)),
+            "port" => Ok(Self::Port(name.parse().map_err(|_| invalid())?)),
+            "namespace" => {
+                let (nqn, nsid) = name.rsplit_once('/').ok_or_else(invalid)?;
+                let nsid = nsid.parse().map_err(|_| invalid())?;
+                Ok(Self::Namespace(parse_nqn(nqn, s)?, nsid))
+            }
+            "host" => Ok(Self::Host(parse_nqn(name, s)?)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Display for ObjectRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Subsystem(nqn) => write!(f, "subsystem:{nqn}"),
+            Self::Port(id) => write!(f, "port:{id}"),
+            Self::Namespace(nqn, nsid) => write!(f, "namespace:{nqn}/{nsid}"),
+            Self::Host(nqn) => write!(f, "host:{nqn}"),
+        }
+    }
+}
+
+impl Serialize for ObjectRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Check that `attr` looks like a configfs attribute name, like `attr_qid_max`.
+pub fn assert_valid_attr_name(attr: &str) -> Result<()> {
+    let mut chars = attr.chars();
+    let valid = attr.len() <= MAX_ATTR_LEN
+        && chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidAttributeName(attr.to_string()))
+    }
+}
+
+impl KernelConfig {
+    /// Read the attribute `attr` of `object` as it is, without interpreting it.
+    pub fn get_raw_attr(object: &ObjectRef, attr: &str) -> Result<String> {
+        Self::get_raw_attr_in(&NvmetRoot::system(), object, attr)
+    }
+
+    /// Write `value` to the attribute `attr` of `object` as it is, returning what the kernel
+    /// reads back afterwards.
+    ///
+    /// Nothing is checked about the value, this is for attributes nvmetcfg doesn't know about.
+    pub fn set_raw_attr(object: &ObjectRef, attr: &str, value: &str) -> Result<String> {
+        Self::system().mutate(|root| Self::set_raw_attr_in(root, object, attr, value))
+    }
+
+    pub(crate) fn get_raw_attr_in(
+        root: &NvmetRoot,
+        object: &ObjectRef,
+        attr: &str,
+    ) -> Result<String> {
+        let path = raw_attr_path(root, object, attr)?;
+        root.read_relative(&path)
+            .with_context(|| format!("Failed to read {attr} of {object}"))
+    }
+
+    pub(crate) fn set_raw_attr_in(
+        root: &NvmetRoot,
+        object: &ObjectRef,
+        attr: &str,
+        value: &str,
+    ) -> Result<String> {
+        let path = raw_attr_path(root, object, attr)?;
+        root.write_relative(&path, value)
+            .with_context(|| format!("Failed to write {attr} of {object}"))?;
+        root.read_relative(&path)
+            .with_context(|| format!("Failed to read back {attr} of {object}"))
+    }
+}
+
+// Path of an attribute relative to the root, after checking that it exists.
+fn raw_attr_path(root: &NvmetRoot, object: &ObjectRef, attr: &str) -> Result<PathBuf> {
+    assert_valid_attr_name(attr)?;
+    root.check_exists()?;
+    let object_path = object.relative_path();
+    if !root.exists_relative(&object_path)? {
+        return Err(object.not_found());
+    }
+    let path = object_path.join(attr);
+    if !root.exists_relative(&path)? {
+        return Err(Error::NoSuchAttribute(object.to_string(), attr.to_string()));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::FakeBackend;
+    use crate::kernel::tests::{example_state, SUB};
+    use crate::state::State;
+
+    #[test]
+    fn test_object_ref() -> Result<()> {
+        for s in [
+            format!("subsystem:{SUB}"),
+            "port:3".to_string(),
+            format!("namespace:{SUB}/2"),
+            format!("host:{SUB}"),
+        ] {
+            assert_eq!(s.parse::<ObjectRef>()?.to_string(), s);
+        }
+        assert_eq!(
+            format!("namespace:{SUB}/2").parse::<ObjectRef>()?,
+            ObjectRef::Namespace(SUB.to_string(), 2)
+        );
+        for invalid in [
+            "port:abc",
+            "port",
+            "controller:1",
+            "subsystem:..",
+            "subsystem:../ports",
+            "namespace:../2",
+            "namespace:nqn.2023-11.sh.tty:sub",
+        ] {
+            assert!(invalid.parse::<ObjectRef>().is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_attr() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&example_state()))?;
+        let sub = ObjectRef::Subsystem(SUB.to_string());
+        fake.add_attr(&format!("subsystems/{SUB}/attr_future"), "0");
+
+        assert_eq!(
+            KernelConfig::get_raw_attr_in(&root, &sub, "attr_future")?,
+            "0"
+        );
+        assert_eq!(
+            KernelConfig::set_raw_attr_in(&root, &sub, "attr_future", "1")?,
+            "1"
+        );
+        assert_eq!(
+            fake.attr(&format!("subsystems/{SUB}/attr_future")).unwrap(),
+            "1"
+        );
+        let ns = ObjectRef::Namespace(SUB.to_string(), 1);
+        assert_eq!(
+            KernelConfig::get_raw_attr_in(&root, &ns, "device_path")?,
+            "/dev/loop0"
+        );
+
+        let err = KernelConfig::get_raw_attr_in(&root, &sub, "attr_missing").unwrap_err();
+        assert!(matches!(err, Error::NoSuchAttribute(..)));
+        let err =
+            KernelConfig::get_raw_attr_in(&root, &ObjectRef::Port(2), "addr_trtype").unwrap_err();
+        assert!(matches!(err, Error::NoSuchPort(2)));
+        for attr in ["../attr_model", "Attr", "", "namespaces/1/device_path"] {
+            let err = KernelConfig::get_raw_attr_in(&root, &sub, attr).unwrap_err();
+            assert!(matches!(err, Error::InvalidAttributeName(_)), "{attr}");
+        }
+        Ok(())
+    }
+}
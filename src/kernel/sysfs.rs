@@ -1,33 +1,141 @@
-use crate::errors::{Error, Result};
+use super::backend::{system_backend, Backend};
+use super::hosts::HostAuth;
+use crate::errors::{Context, Error, Result};
 use crate::helpers::{
-    assert_valid_model, assert_valid_nqn, assert_valid_nsid, assert_valid_serial,
-    get_btreemap_differences, read_str, write_str,
+    assert_valid_dhchap_key, assert_valid_model, assert_valid_nqn, assert_valid_nsid,
+    assert_valid_qid_max, assert_valid_serial, get_btreemap_differences, DeviceCache,
+    ResolvedDevice,
 };
-use crate::state::{Namespace, PortType};
-use anyhow::Context;
+use crate::state::{AddrFamily, FibreChannelAddr, Namespace, PortAddrFamily, PortType};
 use std::collections::{BTreeMap, BTreeSet};
-use std::os::unix::fs::FileTypeExt;
-use std::path::{Path, PathBuf};
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
-static NVMET_ROOT: &str = "/sys/kernel/config/nvmet/";
+pub(super) static NVMET_ROOT: &str = "/sys/kernel/config/nvmet/";
 
-pub(super) struct NvmetRoot {}
+// Keys must not end up in logs.
+fn is_key_attr(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name == "dhchap_key" || name == "dhchap_ctrl_key")
+}
+
+/// Where a symlink in the tree points to, see `NvmetRoot::link_target`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum LinkTarget {
+    /// An object of the expected group, by name.
+    Object(String),
+    /// Nothing, the link dangles.
+    Missing(PathBuf),
+    /// Something outside of the expected group.
+    Elsewhere(PathBuf),
+}
+
+/// Resolve the `target` of the symlink at `link` without following any further links.
+///
+/// configfs stores targets relative to the link, like `../../../subsystems/<nqn>`.
+pub(super) fn resolve_link_target(link: &Path, target: &Path) -> PathBuf {
+    let mut resolved = link.parent().map(Path::to_path_buf).unwrap_or_default();
+    for comp in target.components() {
+        match comp {
+            Component::RootDir => resolved = PathBuf::from("/"),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    resolved
+}
+
+#[derive(Clone)]
+pub(crate) struct NvmetRoot {
+    path: PathBuf,
+    backend: Arc<dyn Backend>,
+}
 
 impl NvmetRoot {
-    pub(super) fn check_exists() -> Result<()> {
-        let exists = Path::new(NVMET_ROOT).try_exists()?;
+    pub(crate) fn new<P: Into<PathBuf>>(path: P, backend: Arc<dyn Backend>) -> Self {
+        Self {
+            path: path.into(),
+            backend,
+        }
+    }
+    pub(crate) fn system() -> Self {
+        Self::new(NVMET_ROOT, system_backend())
+    }
+
+    fn read<P: AsRef<Path>>(&self, path: P) -> Result<String> {
+        self.backend.read_str(path.as_ref())
+    }
+    fn write<P: AsRef<Path>, D: Display>(&self, path: P, data: D) -> Result<()> {
+        let path = path.as_ref();
+        let data = Zeroizing::new(format!("{data}"));
+        if is_key_attr(path) {
+            tracing::debug!(path = %path.display(), "Writing key");
+        } else {
+            tracing::debug!(path = %path.display(), value = %data.as_str(), "Writing attribute");
+        }
+        self.backend.write_str(path, &data)
+    }
+
+    pub(super) fn exists_relative(&self, rel: &Path) -> Result<bool> {
+        self.backend.exists(&self.path.join(rel))
+    }
+    pub(super) fn read_relative(&self, rel: &Path) -> Result<String> {
+        self.read(self.path.join(rel))
+    }
+    pub(super) fn write_relative(&self, rel: &Path, value: &str) -> Result<()> {
+        self.write(self.path.join(rel), value)
+    }
+
+    /// Where the symlink at `link` points to, expecting an object of the top-level `group`.
+    fn link_target(&self, link: &Path, group: &str) -> Result<LinkTarget> {
+        let target = resolve_link_target(link, &self.backend.read_link(link)?);
+        if !self.backend.exists(link)? {
+            return Ok(LinkTarget::Missing(target));
+        }
+        let name = target
+            .strip_prefix(self.path.join(group))
+            .ok()
+            .and_then(|rest| {
+                let mut parts = rest.components();
+                match (parts.next(), parts.next()) {
+                    (Some(Component::Normal(name)), None) => name.to_str().map(str::to_string),
+                    _ => None,
+                }
+            });
+        Ok(name.map_or(LinkTarget::Elsewhere(target), LinkTarget::Object))
+    }
+
+    /// Ensure `path` is a block device, like namespaces need, unless `devices` already did.
+    pub(super) fn resolve_device(
+        &self,
+        path: &Path,
+        devices: &DeviceCache,
+    ) -> Result<ResolvedDevice> {
+        devices.resolve_with(path, |path| self.backend.resolve_block_device(path))
+    }
+
+    pub(super) fn check_exists(&self) -> Result<()> {
+        let exists = self.backend.exists(&self.path)?;
         if exists {
             Ok(())
         } else {
-            Err(Error::NoNvmetSysfs.into())
+            Err(Error::NoNvmetSysfs)
         }
     }
 
-    pub(super) fn list_used_hosts() -> Result<BTreeSet<String>> {
+    pub(super) fn list_used_hosts(&self) -> Result<BTreeSet<String>> {
         let mut hosts = BTreeSet::new();
-        let subsystems = Self::list_subsystems()
-            .with_context(|| format!("Failed listing subsystems to list used hosts"))?;
+        let subsystems = self
+            .list_subsystems()
+            .context("Failed listing subsystems to list used hosts")?;
         for sub in subsystems {
             hosts.append(&mut sub.list_hosts().with_context(|| {
                 format!(
@@ -39,53 +147,100 @@ impl NvmetRoot {
         Ok(hosts)
     }
 
-    pub(super) fn remove_host(nqn: &str) -> Result<()> {
-        let path = Path::new(NVMET_ROOT).join("hosts").join(nqn);
-        std::fs::remove_dir(path)
+    /// List the top-level group `group`, like `ports`.
+    ///
+    /// The kernel creates them along with the root, but a tree still being set up may lack
+    /// them. That means nothing is configured, so they are treated as empty. Any other error,
+    /// like lacking permission, is still returned.
+    fn list_group(&self, group: &str) -> Result<Vec<String>> {
+        match self.backend.list_dir(&self.path.join(group)) {
+            Err(Error::Io(err)) if err.kind() == ErrorKind::NotFound => {
+                tracing::debug!("{group} missing from {}", self.path.display());
+                Ok(Vec::new())
+            }
+            result => result,
+        }
+    }
+
+    /// List all hosts, including those not allowed on any subsystem.
+    pub(super) fn list_all_hosts(&self) -> Result<BTreeSet<String>> {
+        let names = self.list_group("hosts").context("Failed listing hosts")?;
+        Ok(names.into_iter().collect())
+    }
+
+    pub(super) fn remove_host(&self, nqn: &str) -> Result<()> {
+        let path = self.path.join("hosts").join(nqn);
+        self.backend
+            .remove_dir(&path)
             .with_context(|| format!("Failed to remove directory of host {nqn}"))?;
         Ok(())
     }
 
-    pub(super) fn list_ports() -> Result<Vec<NvmetPort>> {
-        let path = Path::new(NVMET_ROOT).join("ports");
-        let paths = std::fs::read_dir(path).context("Failed to list ports")?;
+    pub(super) fn create_host(&self, nqn: &str) -> Result<NvmetHost> {
+        assert_valid_nqn(nqn)?;
+        let path = self.path.join("hosts").join(nqn);
+        self.backend
+            .create_dir(&path)
+            .with_context(|| format!("Failed to create new host {nqn}"))?;
+        self.open_host(nqn)
+    }
+
+    pub(super) fn has_host(&self, nqn: &str) -> Result<bool> {
+        self.backend.exists(&self.path.join("hosts").join(nqn))
+    }
+    pub(super) fn open_host(&self, nqn: &str) -> Result<NvmetHost> {
+        assert_valid_nqn(nqn)?;
+        if !self.has_host(nqn)? {
+            return Err(Error::NoSuchHost(nqn.to_string()));
+        }
+        Ok(NvmetHost {
+            nqn: nqn.to_string(),
+            path: self.path.join("hosts").join(nqn),
+            root: self.clone(),
+        })
+    }
+
+    pub(super) fn list_ports(&self) -> Result<Vec<NvmetPort>> {
+        let path = self.path.join("ports");
+        let names = self.list_group("ports").context("Failed to list ports")?;
 
         let mut ports = Vec::new();
-        for wpath in paths {
-            let path = wpath?;
-            if let Ok(id) = path.file_name().to_str().unwrap().parse() {
+        for name in names {
+            if let Ok(id) = name.parse() {
                 ports.push(NvmetPort {
                     id,
-                    path: path.path(),
+                    path: path.join(name),
+                    root: self.clone(),
                 });
             }
         }
         Ok(ports)
     }
-    pub(super) fn has_port(id: u16) -> Result<bool> {
-        let path = Path::new(NVMET_ROOT).join("ports").join(format!("{id}"));
-        Ok(path.try_exists()?)
+    pub(super) fn has_port(&self, id: u16) -> Result<bool> {
+        let path = self.path.join("ports").join(format!("{id}"));
+        self.backend.exists(&path)
     }
-    pub(super) fn open_port(id: u16) -> NvmetPort {
-        let path = Path::new(NVMET_ROOT).join("ports").join(format!("{id}"));
-        NvmetPort { id, path }
+    pub(super) fn open_port(&self, id: u16) -> NvmetPort {
+        let path = self.path.join("ports").join(format!("{id}"));
+        NvmetPort {
+            id,
+            path,
+            root: self.clone(),
+        }
     }
-    pub(super) fn create_port(id: u16) -> Result<NvmetPort> {
-        let port = Self::open_port(id);
-        std::fs::create_dir(port.path.clone())
+    pub(super) fn create_port(&self, id: u16) -> Result<NvmetPort> {
+        let port = self.open_port(id);
+        self.backend
+            .create_dir(&port.path)
             .with_context(|| format!("Failed to create directory of port {id}"))?;
         Ok(port)
     }
-    pub(super) fn delete_port(id: u16) -> Result<()> {
-        let path = Path::new(NVMET_ROOT).join("ports").join(format!("{id}"));
-        if !path.try_exists()? {
-            return Err(Error::NoSuchPort(id).into());
+    pub(super) fn delete_port(&self, id: u16) -> Result<()> {
+        if !self.has_port(id)? {
+            return Err(Error::NoSuchPort(id));
         }
 
-        let port = NvmetPort {
-            id,
-            path: path.clone(),
-        };
+        let port = self.open_port(id);
 
         for sub in port.list_subsystems()? {
             port.disable_subsystem(&sub).with_context(|| {
@@ -93,56 +248,54 @@ impl NvmetRoot {
             })?;
         }
 
-        std::fs::remove_dir(path)
+        self.backend
+            .remove_dir(&port.path)
             .with_context(|| format!("Failed to remove directory of port {id}"))?;
         Ok(())
     }
 
-    pub(super) fn list_subsystems() -> Result<Vec<NvmetSubsystem>> {
-        let path = Path::new(NVMET_ROOT).join("subsystems");
-        let paths = std::fs::read_dir(path).context("Failed to list subsystems")?;
+    pub(super) fn list_subsystems(&self) -> Result<Vec<NvmetSubsystem>> {
+        let path = self.path.join("subsystems");
+        let names = self
+            .list_group("subsystems")
+            .context("Failed to list subsystems")?;
 
-        let mut ports = Vec::new();
-        for wpath in paths {
-            let path = wpath?;
-            let nqn = path.file_name().to_str().unwrap().to_string();
-            ports.push(NvmetSubsystem {
+        let mut subsystems = Vec::new();
+        for nqn in names {
+            subsystems.push(NvmetSubsystem {
+                path: path.join(&nqn),
                 nqn,
-                path: path.path(),
+                root: self.clone(),
             });
         }
-        Ok(ports)
+        Ok(subsystems)
     }
-    pub(super) fn has_subsystem(nqn: &str) -> Result<bool> {
-        let path = Path::new(NVMET_ROOT).join("subsystems").join(nqn);
-        Ok(path.try_exists()?)
+    pub(super) fn has_subsystem(&self, nqn: &str) -> Result<bool> {
+        let path = self.path.join("subsystems").join(nqn);
+        self.backend.exists(&path)
     }
-    pub(super) fn open_subsystem(nqn: &str) -> Result<NvmetSubsystem> {
+    pub(super) fn open_subsystem(&self, nqn: &str) -> Result<NvmetSubsystem> {
         assert_valid_nqn(nqn)?;
-        let path = Path::new(NVMET_ROOT).join("subsystems").join(nqn);
+        let path = self.path.join("subsystems").join(nqn);
         Ok(NvmetSubsystem {
             nqn: nqn.to_string(),
             path,
+            root: self.clone(),
         })
     }
-    pub(super) fn create_subsystem(nqn: &str) -> Result<NvmetSubsystem> {
-        let sub = Self::open_subsystem(nqn)?;
-        std::fs::create_dir(sub.path.clone())
+    pub(super) fn create_subsystem(&self, nqn: &str) -> Result<NvmetSubsystem> {
+        let sub = self.open_subsystem(nqn)?;
+        self.backend
+            .create_dir(&sub.path)
             .with_context(|| format!("Failed to create directory of subsystem {nqn}"))?;
         Ok(sub)
     }
-    pub(super) fn delete_subsystem(nqn: &str) -> Result<()> {
-        assert_valid_nqn(nqn)?;
-        let path = Path::new(NVMET_ROOT).join("subsystems").join(nqn);
-        if !path.try_exists()? {
-            return Err(Error::NoSuchSubsystem(nqn.to_string()).into());
+    pub(super) fn delete_subsystem(&self, nqn: &str) -> Result<()> {
+        let sub = self.open_subsystem(nqn)?;
+        if !self.backend.exists(&sub.path)? {
+            return Err(Error::NoSuchSubsystem(nqn.to_string()));
         }
 
-        let sub = NvmetSubsystem {
-            nqn: nqn.to_string(),
-            path: path.clone(),
-        };
-
         for host in sub.list_hosts()? {
             sub.disable_host(&host).with_context(|| {
                 format!("Failed to disable hosts for subsystem {nqn} before deletion")
@@ -155,108 +308,297 @@ impl NvmetRoot {
             })?;
         }
 
-        std::fs::remove_dir(path)
+        self.backend
+            .remove_dir(&sub.path)
             .with_context(|| format!("Failed to remove directory of subsystem {nqn}"))?;
         Ok(())
     }
+
+    /// Check which of `attrs` objects of `kind` have.
+    ///
+    /// An existing object is inspected if there is one. Otherwise a temporary one is created
+    /// and removed right after. These are never linked to a port, so hosts can't see them.
+    pub(super) fn probe_attrs(&self, kind: ObjectKind, attrs: &[&str]) -> Result<BTreeSet<String>> {
+        let check = |dir: &Path| -> Result<BTreeSet<String>> {
+            let mut present = BTreeSet::new();
+            for attr in attrs {
+                if self.backend.exists(&dir.join(attr))? {
+                    present.insert((*attr).to_string());
+                }
+            }
+            Ok(present)
+        };
+
+        match kind {
+            ObjectKind::Port => {
+                if let Some(port) = self.list_ports()?.first() {
+                    return check(&port.path);
+                }
+                let port = self
+                    .create_port(PROBE_PORT)
+                    .context("Failed to create temporary port for probing")?;
+                let present = check(&port.path);
+                self.remove_probe(&port.path)?;
+                present
+            }
+            ObjectKind::Subsystem => {
+                if let Some(sub) = self.list_subsystems()?.first() {
+                    return check(&sub.path);
+                }
+                let sub = self
+                    .create_subsystem(PROBE_NQN)
+                    .context("Failed to create temporary subsystem for probing")?;
+                let present = check(&sub.path);
+                self.remove_probe(&sub.path)?;
+                present
+            }
+            ObjectKind::Namespace => {
+                for sub in self.list_subsystems()? {
+                    if let Some(ns) = sub.list_namespaces()?.values().next() {
+                        return check(&ns.path);
+                    }
+                }
+                // Never add namespaces to existing subsystems, even disabled ones.
+                let sub = self
+                    .create_subsystem(PROBE_NQN)
+                    .context("Failed to create temporary subsystem for probing")?;
+                let present = sub
+                    .create_namespace(1)
+                    .context("Failed to create temporary namespace for probing")
+                    .and_then(|ns| {
+                        let present = check(&ns.path);
+                        self.remove_probe(&ns.path)?;
+                        present
+                    });
+                self.remove_probe(&sub.path)?;
+                present
+            }
+            ObjectKind::Host => {
+                let hosts = self.path.join("hosts");
+                if let Some(host) = self.list_all_hosts()?.first() {
+                    return check(&hosts.join(host));
+                }
+                let path = hosts.join(PROBE_NQN);
+                self.backend
+                    .create_dir(&path)
+                    .context("Failed to create temporary host for probing")?;
+                let present = check(&path);
+                self.remove_probe(&path)?;
+                present
+            }
+        }
+    }
+    fn remove_probe(&self, path: &Path) -> Result<()> {
+        self.backend.remove_dir(path).with_context(|| {
+            format!(
+                "Failed to remove temporary probe object {}, please remove it manually",
+                path.display()
+            )
+        })
+    }
 }
 
-pub(super) struct NvmetPort {
+/// Kinds of objects in the nvmet configfs tree.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum ObjectKind {
+    Port,
+    Subsystem,
+    Namespace,
+    Host,
+}
+
+// Names of the temporary objects used for probing.
+const PROBE_PORT: u16 = u16::MAX;
+static PROBE_NQN: &str = "nqn.2023-11.sh.tty.nvmetcfg:probe";
+
+pub(crate) struct NvmetPort {
     pub id: u16,
     path: PathBuf,
+    root: NvmetRoot,
+}
+
+// The attributes describing a port type, in the order they need to be written.
+fn port_type_attrs(port_type: PortType) -> Vec<(&'static str, String)> {
+    match port_type {
+        PortType::Loop => vec![("addr_trtype", "loop".to_string())],
+        PortType::Tcp(saddr) | PortType::Rdma(saddr) => {
+            let adrfam = PortAddrFamily::Ip(AddrFamily::of(saddr.ip()));
+            vec![
+                ("addr_trtype", port_type.trtype().to_string()),
+                ("addr_adrfam", adrfam.to_string()),
+                ("addr_traddr", saddr.ip().to_string()),
+                ("addr_trsvcid", saddr.port().to_string()),
+            ]
+        }
+        PortType::FibreChannel(fcaddr) => vec![
+            ("addr_trtype", "fc".to_string()),
+            ("addr_adrfam", PortAddrFamily::Fc.to_string()),
+            ("addr_traddr", fcaddr.to_traddr()),
+            ("addr_trsvcid", "none".to_string()),
+        ],
+    }
+}
+
+// Fibre Channel addresses written by other tools may differ in case or 0x prefixes,
+// rewriting them anyway would detach the port's subsystems for nothing.
+fn same_attr(port_type: PortType, attr: &str, current: &str, value: &str) -> bool {
+    match port_type {
+        PortType::FibreChannel(fcaddr) if attr == "addr_traddr" => {
+            current.parse::<FibreChannelAddr>().ok() == Some(fcaddr)
+        }
+        _ => current == value,
+    }
 }
 
 impl NvmetPort {
     pub(super) fn get_type(&self) -> Result<PortType> {
-        let trtype = read_str(self.path.join("addr_trtype"))?;
-        let traddr = read_str(self.path.join("addr_traddr"))?;
-        let trsvcid = read_str(self.path.join("addr_trsvcid"))?;
+        let trtype = self.root.read(self.path.join("addr_trtype"))?;
+        let traddr = self.root.read(self.path.join("addr_traddr"))?;
+        let trsvcid = self.root.read(self.path.join("addr_trsvcid"))?;
+        // IPv6 addresses are stored without brackets, so they can't be parsed as "addr:port".
+        let saddr = || -> Result<SocketAddr> {
+            Ok(SocketAddr::new(
+                traddr.parse::<IpAddr>()?,
+                trsvcid
+                    .parse()
+                    .with_context(|| format!("Invalid addr_trsvcid: {trsvcid}"))?,
+            ))
+        };
         match trtype.as_str() {
             "loop" => Ok(PortType::Loop),
-            "tcp" => Ok(PortType::Tcp(format!("{traddr}:{trsvcid}").parse()?)),
-            "rdma" => Ok(PortType::Rdma(format!("{traddr}:{trsvcid}").parse()?)),
+            "tcp" => Ok(PortType::Tcp(saddr()?)),
+            "rdma" => Ok(PortType::Rdma(saddr()?)),
             "fc" => Ok(PortType::FibreChannel(traddr.parse()?)),
-            _ => Err(Error::UnsupportedTrType(trtype).into()),
+            _ => Err(Error::UnsupportedTrType(trtype)),
         }
     }
+    /// The address family as the kernel has it, `None` if unset, like for loop ports.
+    pub(super) fn get_adrfam(&self) -> Result<Option<PortAddrFamily>> {
+        let adrfam = self.root.read(self.path.join("addr_adrfam"))?;
+        if adrfam.is_empty() {
+            return Ok(None);
+        }
+        adrfam.parse().map(Some)
+    }
     pub(super) fn set_type(&self, port_type: PortType) -> Result<()> {
+        // Only rewrite what differs, e.g. just addr_traddr for an address change.
+        let attrs: Vec<(&str, String)> = port_type_attrs(port_type)
+            .into_iter()
+            .filter(|(attr, value)| {
+                self.root
+                    .read(self.path.join(attr))
+                    .map_or(true, |current| !same_attr(port_type, attr, &current, value))
+            })
+            .collect();
+        // Detaching subsystems disconnects initiators, so don't bother if nothing changes.
+        if attrs.is_empty() {
+            return Ok(());
+        }
+
         // Remove all subsystems in order to unlock.
+        // The kernel refuses writes to every addr_* attribute of a port with subsystems,
+        // not just addr_trtype, so this is needed for address-only changes as well.
         let subs = self.list_subsystems()?;
         self.set_subsystems(&BTreeSet::new())?;
 
-        match port_type {
-            PortType::Loop => {
-                write_str(self.path.join("addr_trtype"), "loop")?;
-            }
-            PortType::Tcp(saddr) => {
-                write_str(self.path.join("addr_trtype"), "tcp")?;
-                if saddr.is_ipv6() {
-                    write_str(self.path.join("addr_adrfam"), "ipv6")?;
-                } else {
-                    write_str(self.path.join("addr_adrfam"), "ipv4")?;
-                }
-                write_str(self.path.join("addr_traddr"), saddr.ip())?;
-                write_str(self.path.join("addr_trsvcid"), saddr.port())?;
-            }
-            PortType::Rdma(saddr) => {
-                write_str(self.path.join("addr_trtype"), "rdma")?;
-                if saddr.is_ipv6() {
-                    write_str(self.path.join("addr_adrfam"), "ipv6")?;
-                } else {
-                    write_str(self.path.join("addr_adrfam"), "ipv4")?;
-                }
-                write_str(self.path.join("addr_traddr"), saddr.ip())?;
-                write_str(self.path.join("addr_trsvcid"), saddr.port())?;
-            }
-            PortType::FibreChannel(fcaddr) => {
-                write_str(self.path.join("addr_trtype"), "fc")?;
-                write_str(self.path.join("addr_adrfam"), "fc")?;
-                write_str(self.path.join("addr_traddr"), fcaddr.to_traddr())?;
-                write_str(self.path.join("addr_trsvcid"), "none")?;
+        for (attr, value) in &attrs {
+            self.root
+                .write(self.path.join(attr), value)
+                .with_context(|| format!("Failed to write {attr} for port {}", self.id))?;
+        }
+        // A kernel ignoring the write keeps the old family, which may not match the address.
+        // Loop ports have no address, whatever family they have is ignored.
+        let adrfam = match port_type {
+            PortType::Loop => None,
+            _ => self.get_adrfam()?,
+        };
+        if let Some(adrfam) = adrfam {
+            port_type
+                .check_adrfam(adrfam)
+                .with_context(|| format!("Failed to set address family for port {}", self.id))?;
+        }
+        // The kernel may silently ignore or reject some combinations,
+        // so make sure what we wrote is what it now reports.
+        for (attr, value) in &attrs {
+            let actual = self
+                .root
+                .read(self.path.join(attr))
+                .with_context(|| format!("Failed to read back {attr} for port {}", self.id))?;
+            if actual != *value {
+                return Err(Error::PortAttributeMismatch(
+                    self.id,
+                    (*attr).to_string(),
+                    value.clone(),
+                    actual,
+                ));
             }
         }
+
         // Re-add all the previously enabled subsystems.
         self.set_subsystems(&subs)?;
         Ok(())
     }
 
+    /// Like `set_type`, but refuses to change the transport type.
+    pub(super) fn set_address(&self, port_type: PortType) -> Result<()> {
+        let trtype = self
+            .root
+            .read(self.path.join("addr_trtype"))
+            .with_context(|| format!("Failed to get addr_trtype for port {}", self.id))?;
+        if trtype != port_type.trtype() {
+            return Err(Error::PortTransportChanged(
+                self.id,
+                trtype,
+                port_type.trtype().to_string(),
+            ));
+        }
+        self.set_type(port_type)
+    }
+
     pub(super) fn list_subsystems(&self) -> Result<BTreeSet<String>> {
         let path = self.path.join("subsystems");
-        let paths = std::fs::read_dir(path)
-            .with_context(|| format!("Failed to list enabled subsystems for pot {}", self.id))?;
-
-        let mut subsystems = BTreeSet::new();
-        for wpath in paths {
-            let path = wpath?;
-            subsystems.insert(path.file_name().to_str().unwrap().to_owned());
-        }
-        Ok(subsystems)
+        let names =
+            self.root.backend.list_dir(&path).with_context(|| {
+                format!("Failed to list enabled subsystems for pot {}", self.id)
+            })?;
+        Ok(names.into_iter().collect())
     }
 
     pub(super) fn has_subsystem(&self, nqn: &str) -> Result<bool> {
         let path = self.path.join("subsystems").join(nqn);
-        Ok(path.try_exists()?)
+        self.root.backend.exists(&path)
+    }
+    /// Where the link `name` among the subsystems of the port points to.
+    pub(super) fn subsystem_link(&self, name: &str) -> Result<LinkTarget> {
+        let path = self.path.join("subsystems").join(name);
+        self.root
+            .link_target(&path, "subsystems")
+            .with_context(|| format!("Failed to read subsystem link {name} of port {}", self.id))
     }
     pub(super) fn disable_subsystem(&self, nqn: &str) -> Result<()> {
         let path = self.path.join("subsystems").join(nqn);
-        std::fs::remove_file(path)
+        self.root
+            .backend
+            .remove_link(&path)
             .with_context(|| format!("Failed to disable subsystem {} for port {}", nqn, self.id))?;
         Ok(())
     }
     pub(super) fn enable_subsystem(&self, nqn: &str) -> Result<()> {
         assert_valid_nqn(nqn)?;
         let path = self.path.join("subsystems").join(nqn);
-        let sub = Path::new(NVMET_ROOT).join("subsystems").join(nqn);
-        if !sub.try_exists()? {
-            return Err(Error::NoSuchSubsystem(nqn.to_string()).into());
+        if !self.root.has_subsystem(nqn)? {
+            return Err(Error::NoSuchSubsystem(nqn.to_string()));
         }
-        std::os::unix::fs::symlink(sub, path)
+        let sub = self.root.path.join("subsystems").join(nqn);
+        self.root
+            .backend
+            .symlink(&sub, &path)
             .with_context(|| format!("Failed to enable subsystem {} for port {}", nqn, self.id))?;
         Ok(())
     }
 
     pub(super) fn set_subsystems(&self, desired: &BTreeSet<String>) -> Result<()> {
-        let actual = BTreeSet::from_iter(self.list_subsystems()?);
+        let actual = self.list_subsystems()?;
         let added = desired.difference(&actual);
         let removed = actual.difference(desired);
 
@@ -275,17 +617,18 @@ impl NvmetPort {
     }
 }
 
-pub(super) struct NvmetSubsystem {
+pub(crate) struct NvmetSubsystem {
     pub(super) nqn: String,
     path: PathBuf,
+    root: NvmetRoot,
 }
 
 impl NvmetSubsystem {
     pub(super) fn set_allow_any(&self, enabled: bool) -> Result<()> {
         if enabled {
-            write_str(self.path.join("attr_allow_any_host"), "1")
+            self.root.write(self.path.join("attr_allow_any_host"), "1")
         } else {
-            write_str(self.path.join("attr_allow_any_host"), "0")
+            self.root.write(self.path.join("attr_allow_any_host"), "0")
         }
         .with_context(|| {
             format!(
@@ -297,31 +640,41 @@ impl NvmetSubsystem {
 
     pub(super) fn list_hosts(&self) -> Result<BTreeSet<String>> {
         let path = self.path.join("allowed_hosts");
-        let paths = std::fs::read_dir(path)
-            .with_context(|| format!("Failed to list allowed_hosts for subsystem {}", self.nqn))?;
-
-        let mut hosts = BTreeSet::new();
-        for wpath in paths {
-            let path = wpath?;
-            hosts.insert(path.file_name().to_str().unwrap().to_owned());
-        }
-        Ok(hosts)
+        let names =
+            self.root.backend.list_dir(&path).with_context(|| {
+                format!("Failed to list allowed_hosts for subsystem {}", self.nqn)
+            })?;
+        Ok(names.into_iter().collect())
+    }
+    /// Where the link `name` among the allowed hosts of the subsystem points to.
+    pub(super) fn host_link(&self, name: &str) -> Result<LinkTarget> {
+        let path = self.path.join("allowed_hosts").join(name);
+        self.root.link_target(&path, "hosts").with_context(|| {
+            format!(
+                "Failed to read allowed host link {name} of subsystem {}",
+                self.nqn
+            )
+        })
     }
     pub(super) fn enable_host(&self, nqn: &str) -> Result<()> {
         assert_valid_nqn(nqn)?;
         let path = self.path.join("allowed_hosts").join(nqn);
-        let host = Path::new(NVMET_ROOT).join("hosts").join(nqn);
-        if !host.try_exists()? {
-            std::fs::create_dir(host.clone())
-                .with_context(|| format!("Failed to create new host {nqn}"))?;
+        let host = self.root.path.join("hosts").join(nqn);
+        // Hosts created beforehand are reused, keeping their keys.
+        if !self.root.has_host(nqn)? {
+            self.root.create_host(nqn)?;
         }
-        std::os::unix::fs::symlink(host, path)
+        self.root
+            .backend
+            .symlink(&host, &path)
             .with_context(|| format!("Failed to enable host {} in subsystem {}", nqn, self.nqn))?;
         Ok(())
     }
     pub(super) fn disable_host(&self, nqn: &str) -> Result<()> {
         let path = self.path.join("allowed_hosts").join(nqn);
-        std::fs::remove_file(path)
+        self.root
+            .backend
+            .remove_link(&path)
             .with_context(|| format!("Failed to disable host {} in subsystem {}", nqn, self.nqn))?;
         Ok(())
     }
@@ -346,34 +699,45 @@ impl NvmetSubsystem {
 
     pub(super) fn list_namespaces(&self) -> Result<BTreeMap<u32, NvmetNamespace>> {
         let path = self.path.join("namespaces");
-        let paths = std::fs::read_dir(path)
+        let names = self
+            .root
+            .backend
+            .list_dir(&path)
             .with_context(|| format!("Failed to list namespaces of subsystem {}", self.nqn))?;
 
         let mut nses = BTreeMap::new();
-        for wpath in paths {
-            let path = wpath?;
-            let nsid = path.file_name().to_str().unwrap().parse()?;
+        for name in names {
+            let nsid = name.parse()?;
             nses.insert(
                 nsid,
                 NvmetNamespace {
-                    path: path.path(),
+                    path: path.join(name),
                     nsid,
+                    root: self.root.clone(),
                 },
             );
         }
         Ok(nses)
     }
+    pub(super) fn has_namespace(&self, nsid: u32) -> Result<bool> {
+        let path = self.path.join("namespaces").join(format!("{nsid}"));
+        self.root.backend.exists(&path)
+    }
     pub(super) fn open_namespace(&self, nsid: u32) -> Result<NvmetNamespace> {
         assert_valid_nsid(nsid)?;
         let path = self.path.join("namespaces").join(format!("{nsid}"));
-        Ok(NvmetNamespace { nsid, path })
+        Ok(NvmetNamespace {
+            nsid,
+            path,
+            root: self.root.clone(),
+        })
     }
     pub(super) fn create_namespace(&self, nsid: u32) -> Result<NvmetNamespace> {
         let ns = self.open_namespace(nsid)?;
-        if ns.path.try_exists()? {
-            return Err(Error::ExistingNamespace(nsid, self.nqn.clone()).into());
+        if self.root.backend.exists(&ns.path)? {
+            return Err(Error::ExistingNamespace(nsid, self.nqn.clone()));
         }
-        std::fs::create_dir(ns.path.clone()).with_context(|| {
+        self.root.backend.create_dir(&ns.path).with_context(|| {
             format!(
                 "Failed to create directory of namespace {} in subsystem {}",
                 nsid, self.nqn
@@ -383,12 +747,13 @@ impl NvmetSubsystem {
     }
     pub(super) fn delete_namespace(&self, nsid: u32) -> Result<()> {
         let path = self.path.join("namespaces").join(format!("{nsid}"));
-        if !path.try_exists()? {
-            return Err(Error::NoSuchNamespace(nsid, self.nqn.clone()).into());
+        if !self.root.backend.exists(&path)? {
+            return Err(Error::NoSuchNamespace(nsid, self.nqn.clone()));
         }
         let ns = NvmetNamespace {
             path: path.clone(),
             nsid,
+            root: self.root.clone(),
         };
         // Disable first
         ns.set_enabled(false).with_context(|| {
@@ -398,7 +763,7 @@ impl NvmetSubsystem {
             )
         })?;
         // Delete directory.
-        std::fs::remove_dir(path).with_context(|| {
+        self.root.backend.remove_dir(&path).with_context(|| {
             format!(
                 "Failed to remove directory of namespace {} in subsystem {}",
                 nsid, self.nqn
@@ -406,7 +771,15 @@ impl NvmetSubsystem {
         })?;
         Ok(())
     }
-    pub(super) fn set_namespaces(&self, nses: &BTreeMap<u32, Namespace>) -> Result<()> {
+    /// Make the namespaces of the subsystem match `nses`.
+    ///
+    /// Namespaces are removed first, then updated, then added, each in ascending nsid order.
+    /// When stacked devices depend on each other, number the namespaces accordingly.
+    pub(super) fn set_namespaces(
+        &self,
+        nses: &BTreeMap<u32, Namespace>,
+        devices: &DeviceCache,
+    ) -> Result<()> {
         // TODO: slightly inefficient as it fetches data for to-be-removed namespaces too
         // Utterly irrelevant though.
         let mut current = BTreeMap::new();
@@ -425,7 +798,7 @@ impl NvmetSubsystem {
         }
         for nsid in delta.changed {
             let ns = self.open_namespace(nsid)?;
-            ns.set_namespace(nses.get(&nsid).unwrap())
+            ns.set_namespace(nses.get(&nsid).unwrap(), devices)
                 .with_context(|| {
                     format!(
                         "Failed to update existing namespaces for subsystem {}",
@@ -440,7 +813,7 @@ impl NvmetSubsystem {
                     self.nqn
                 )
             })?;
-            ns.set_namespace(nses.get(&nsid).unwrap())
+            ns.set_namespace(nses.get(&nsid).unwrap(), devices)
                 .with_context(|| {
                     format!("Failed to set added namespaces for subsystem {}", self.nqn)
                 })?;
@@ -449,36 +822,133 @@ impl NvmetSubsystem {
     }
 
     pub(super) fn get_model(&self) -> Result<String> {
-        read_str(self.path.join("attr_model"))
+        self.root
+            .read(self.path.join("attr_model"))
             .with_context(|| format!("Failed to get attr_model for subsystem {}", self.nqn))
     }
     pub(super) fn set_model(&self, model: &str) -> Result<()> {
         assert_valid_model(model)?;
-        write_str(self.path.join("attr_model"), model)
+        self.root
+            .write(self.path.join("attr_model"), model)
             .with_context(|| format!("Failed to set attr_model for subsystem {}", self.nqn))?;
         Ok(())
     }
+    /// Read an attribute which older kernels lack, or `None` if this one does.
+    pub(super) fn get_optional_attr(&self, attr: &str) -> Result<Option<String>> {
+        let path = self.path.join(attr);
+        if !self.root.backend.exists(&path)? {
+            return Ok(None);
+        }
+        self.root
+            .read(&path)
+            .map(Some)
+            .with_context(|| format!("Failed to read {attr} for subsystem {}", self.nqn))
+    }
     pub(super) fn get_serial(&self) -> Result<String> {
-        read_str(self.path.join("attr_serial"))
+        self.root
+            .read(self.path.join("attr_serial"))
             .with_context(|| format!("Failed to read attr_serial for subsystem {}", self.nqn))
     }
     pub(super) fn set_serial(&self, serial: &str) -> Result<()> {
         assert_valid_serial(serial)?;
-        write_str(self.path.join("attr_serial"), serial)
+        self.root
+            .write(self.path.join("attr_serial"), serial)
             .with_context(|| format!("Failed to set attr_serial for subsystem {}", self.nqn))?;
         Ok(())
     }
+    /// The maximum number of I/O queues, or `None` on kernels without attr_qid_max.
+    pub(super) fn get_qid_max(&self) -> Result<Option<u32>> {
+        self.get_optional_attr("attr_qid_max")?
+            .map(|qid_max| {
+                qid_max.parse().with_context(|| {
+                    format!("Failed to parse attr_qid_max for subsystem {}", self.nqn)
+                })
+            })
+            .transpose()
+    }
+    pub(super) fn set_qid_max(&self, qid_max: u32) -> Result<()> {
+        assert_valid_qid_max(qid_max)?;
+        self.root
+            .write(self.path.join("attr_qid_max"), qid_max)
+            .with_context(|| format!("Failed to set attr_qid_max for subsystem {}", self.nqn))?;
+        Ok(())
+    }
+}
+
+pub(crate) struct NvmetHost {
+    nqn: String,
+    path: PathBuf,
+    root: NvmetRoot,
 }
 
-pub(super) struct NvmetNamespace {
+impl NvmetHost {
+    // Keys are written through the backend directly, so no formatted copies are left around.
+    pub(super) fn set_dhchap_key(&self, key: &str) -> Result<()> {
+        assert_valid_dhchap_key(key)?;
+        self.root
+            .backend
+            .write_str(&self.path.join("dhchap_key"), key)
+            .with_context(|| format!("Failed to set dhchap_key for host {}", self.nqn))
+    }
+    pub(super) fn set_dhchap_ctrl_key(&self, key: &str) -> Result<()> {
+        assert_valid_dhchap_key(key)?;
+        self.root
+            .backend
+            .write_str(&self.path.join("dhchap_ctrl_key"), key)
+            .with_context(|| format!("Failed to set dhchap_ctrl_key for host {}", self.nqn))
+    }
+    /// Whether either DH-HMAC-CHAP key is set.
+    pub(super) fn has_keys(&self) -> Result<bool> {
+        let auth = self.get_auth()?;
+        Ok(auth.host_key || auth.ctrl_key)
+    }
+    /// Which DH-HMAC-CHAP keys are set, without keeping the keys around.
+    /// Kernels built without authentication support have no keys at all.
+    pub(super) fn get_auth(&self) -> Result<HostAuth> {
+        Ok(HostAuth {
+            host_key: self.has_key("dhchap_key")?,
+            ctrl_key: self.has_key("dhchap_ctrl_key")?,
+        })
+    }
+    /// Whether the DH-HMAC-CHAP key `name` is `key` already.
+    pub(super) fn key_is(&self, name: &str, key: &str) -> Result<bool> {
+        let path = self.path.join(name);
+        if !self.root.backend.exists(&path)? {
+            return Ok(false);
+        }
+        let current = Zeroizing::new(
+            self.root
+                .read(&path)
+                .with_context(|| format!("Failed to read {name} for host {}", self.nqn))?,
+        );
+        Ok(*current == key)
+    }
+    fn has_key(&self, name: &str) -> Result<bool> {
+        let path = self.path.join(name);
+        if !self.root.backend.exists(&path)? {
+            return Ok(false);
+        }
+        let key = Zeroizing::new(
+            self.root
+                .read(&path)
+                .with_context(|| format!("Failed to read {name} for host {}", self.nqn))?,
+        );
+        Ok(!key.is_empty())
+    }
+}
+
+pub(crate) struct NvmetNamespace {
     nsid: u32,
     path: PathBuf,
+    root: NvmetRoot,
 }
 
 impl NvmetNamespace {
     pub(super) fn is_enabled(&self) -> Result<bool> {
         Ok(
-            match read_str(self.path.join("enable"))
+            match self
+                .root
+                .read(self.path.join("enable"))
                 .with_context(|| {
                     format!("Failed to get enabled state for namespace {}", self.nsid)
                 })?
@@ -494,58 +964,53 @@ impl NvmetNamespace {
     }
     pub(super) fn set_enabled(&self, enabled: bool) -> Result<()> {
         if enabled {
-            write_str(self.path.join("enable"), "1")
+            self.root.write(self.path.join("enable"), "1")
         } else {
-            write_str(self.path.join("enable"), "0")
+            self.root.write(self.path.join("enable"), "0")
         }
         .with_context(|| format!("Failed to set enabled state for namespace {}", self.nsid))
     }
 
     pub(super) fn get_device_path(&self) -> Result<PathBuf> {
-        Ok(read_str(self.path.join("device_path"))?.into())
+        Ok(self.root.read(self.path.join("device_path"))?.into())
     }
-    pub(super) fn set_device_path(&self, dev: &PathBuf) -> Result<()> {
-        let path = Path::new(dev);
-        // TODO: is it possible to mount a file instead? there is a mysterious "buffered_io" file..
-        let metadata = std::fs::metadata(path)
-            .with_context(|| {
-                format!(
-                    "Failed to get metadata for device {} in namespace {}",
-                    dev.display(),
-                    self.nsid
-                )
-            })?
-            .file_type();
-        if !metadata.is_block_device() {
-            return Err(Error::InvalidDevice(dev.display().to_string()).into());
-        }
-        write_str(
-            self.path.join("device_path"),
-            path.canonicalize()?.to_str().unwrap(),
-        )
-        .with_context(|| format!("Failed to set device_path for namespace {}", self.nsid))
+    pub(super) fn set_device_path(&self, dev: &Path, devices: &DeviceCache) -> Result<()> {
+        let device = self.root.resolve_device(dev, devices).with_context(|| {
+            format!(
+                "Failed to resolve device {} for namespace {}",
+                dev.display(),
+                self.nsid
+            )
+        })?;
+        self.root
+            .write(self.path.join("device_path"), device.canonical.display())
+            .with_context(|| format!("Failed to set device_path for namespace {}", self.nsid))
     }
 
     pub(super) fn get_device_uuid(&self) -> Result<Uuid> {
         Ok(Uuid::parse_str(
-            read_str(self.path.join("device_uuid"))
+            self.root
+                .read(self.path.join("device_uuid"))
                 .with_context(|| format!("Failed to read device_uuid for namespace {}", self.nsid))?
                 .as_str(),
         )?)
     }
     pub(super) fn set_device_uuid(&self, uuid: &Uuid) -> Result<()> {
-        write_str(self.path.join("device_uuid"), uuid.hyphenated()).with_context(|| {
-            format!(
-                "Failed to set device_uuid {} for namespace {}",
-                uuid, self.nsid
-            )
-        })?;
+        self.root
+            .write(self.path.join("device_uuid"), uuid.hyphenated())
+            .with_context(|| {
+                format!(
+                    "Failed to set device_uuid {} for namespace {}",
+                    uuid, self.nsid
+                )
+            })?;
         Ok(())
     }
 
     pub(super) fn get_device_nguid(&self) -> Result<Uuid> {
         Ok(Uuid::parse_str(
-            read_str(self.path.join("device_nguid"))
+            self.root
+                .read(self.path.join("device_nguid"))
                 .with_context(|| {
                     format!("Failed to read device_nguid for namespace {}", self.nsid)
                 })?
@@ -553,12 +1018,14 @@ impl NvmetNamespace {
         )?)
     }
     pub(super) fn set_device_nguid(&self, uuid: &Uuid) -> Result<()> {
-        write_str(self.path.join("device_nguid"), uuid.hyphenated()).with_context(|| {
-            format!(
-                "Failed to set device_nguid {} for namespace {}",
-                uuid, self.nsid
-            )
-        })?;
+        self.root
+            .write(self.path.join("device_nguid"), uuid.hyphenated())
+            .with_context(|| {
+                format!(
+                    "Failed to set device_nguid {} for namespace {}",
+                    uuid, self.nsid
+                )
+            })?;
         Ok(())
     }
 
@@ -566,11 +1033,12 @@ impl NvmetNamespace {
         Ok(Namespace {
             enabled: self.is_enabled()?,
             device_path: self.get_device_path()?,
+            device_spec: None,
             device_uuid: Some(self.get_device_uuid()?),
             device_nguid: Some(self.get_device_nguid()?),
         })
     }
-    pub(super) fn set_namespace(&self, ns: &Namespace) -> Result<()> {
+    pub(super) fn set_namespace(&self, ns: &Namespace, devices: &DeviceCache) -> Result<()> {
         // Always need to disable before applying changes.
         self.set_enabled(false).with_context(|| {
             format!(
@@ -579,7 +1047,7 @@ impl NvmetNamespace {
             )
         })?;
 
-        self.set_device_path(&ns.device_path)?;
+        self.set_device_path(&ns.device_path, devices)?;
         if let Some(uuid) = ns.device_uuid {
             self.set_device_uuid(&uuid)?;
         }
@@ -597,3 +1065,276 @@ impl NvmetNamespace {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::{FakeBackend, FakeOp};
+
+    #[test]
+    fn test_set_type_read_back() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let port = root.create_port(1)?;
+
+        let tcp = PortType::Tcp("192.0.2.1:4420".parse()?);
+        port.set_type(tcp)?;
+        assert_eq!(port.get_type()?, tcp);
+        assert_eq!(fake.attr("ports/1/addr_adrfam").unwrap(), "ipv4");
+
+        // Simulate a kernel that accepts the write but keeps the old value.
+        fake.pin_attr("ports/1/addr_traddr", "192.0.2.1");
+        let err = port
+            .set_type(PortType::Tcp("192.0.2.2:4420".parse()?))
+            .unwrap_err();
+        match err.root() {
+            Error::PortAttributeMismatch(1, attr, expected, actual) => {
+                assert_eq!(attr, "addr_traddr");
+                assert_eq!(expected, "192.0.2.2");
+                assert_eq!(actual, "192.0.2.1");
+            }
+            _ => panic!("unexpected error: {err:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_type_adrfam_mismatch() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let port = root.create_port(1)?;
+        port.set_type(PortType::Tcp("192.0.2.1:4420".parse()?))?;
+
+        // A family written by hand is replaced along with the address.
+        fake.set_attr("ports/1/addr_adrfam", "ipv6");
+        port.set_type(PortType::Tcp("192.0.2.1:4421".parse()?))?;
+        assert_eq!(fake.attr("ports/1/addr_adrfam").unwrap(), "ipv4");
+
+        // Unless the kernel keeps it.
+        fake.pin_attr("ports/1/addr_adrfam", "ipv4");
+        let err = port
+            .set_type(PortType::Tcp("[fdff::1]:4420".parse()?))
+            .unwrap_err();
+        assert!(
+            matches!(err.root(), Error::AddrFamilyMismatch(addr, family) if addr == "tcp://[fdff::1]:4420" && family == "ipv4"),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_namespaces_nsid_order() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let sub = root.create_subsystem("nqn.2023-11.sh.tty:order")?;
+        let mut nses = BTreeMap::new();
+        for nsid in [7, 2, 30, 1] {
+            let path = format!("/dev/loop{nsid}");
+            fake.add_device(&path);
+            nses.insert(
+                nsid,
+                Namespace {
+                    enabled: true,
+                    device_path: path.into(),
+                    device_spec: None,
+                    device_uuid: None,
+                    device_nguid: None,
+                },
+            );
+        }
+        fake.take_ops();
+        sub.set_namespaces(&nses, &DeviceCache::default())?;
+
+        let enabled: Vec<String> = fake
+            .take_ops()
+            .into_iter()
+            .filter_map(|op| match op {
+                FakeOp::Write(rel, val) if rel.ends_with("/enable") && val == "1" => Some(rel),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            enabled,
+            [1, 2, 7, 30]
+                .iter()
+                .map(|nsid| format!("subsystems/nqn.2023-11.sh.tty:order/namespaces/{nsid}/enable"))
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_dhchap_keys() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let host = "nqn.2023-11.sh.tty:keyed-host";
+        assert!(root.open_host(host).is_err());
+
+        let sub = root.create_subsystem("nqn.2023-11.sh.tty:keyed-sub")?;
+        sub.enable_host(host)?;
+        let nvmethost = root.open_host(host)?;
+        let key = "DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:";
+        nvmethost.set_dhchap_key(key)?;
+        assert_eq!(fake.attr(&format!("hosts/{host}/dhchap_key")).unwrap(), key);
+        assert_eq!(
+            fake.attr(&format!("hosts/{host}/dhchap_ctrl_key")).unwrap(),
+            ""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_auth_one_way_and_mutual() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let host = root.create_host("nqn.2023-11.sh.tty:auth-host")?;
+        assert_eq!(host.get_auth()?, HostAuth::default());
+
+        let key = "DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:";
+        let ctrl_key = "DHHC-1:01:NcJ92zAcKEwrIVvaqI3/s8CL4IKox3aCA6s2IeV14WlbRimx:";
+        host.set_dhchap_key(key)?;
+        let auth = host.get_auth()?;
+        assert!(auth.host_key && !auth.ctrl_key && !auth.is_mutual());
+
+        host.set_dhchap_ctrl_key(ctrl_key)?;
+        assert!(host.get_auth()?.is_mutual());
+        // Both keys are kept independently.
+        assert_eq!(
+            fake.attr("hosts/nqn.2023-11.sh.tty:auth-host/dhchap_key")
+                .unwrap(),
+            key
+        );
+        assert_eq!(
+            fake.attr("hosts/nqn.2023-11.sh.tty:auth-host/dhchap_ctrl_key")
+                .unwrap(),
+            ctrl_key
+        );
+
+        // Invalid keys are rejected before reaching the kernel.
+        fake.take_ops();
+        assert!(host.set_dhchap_ctrl_key("DHHC-1:01:not-a-key:").is_err());
+        assert!(host
+            .set_dhchap_key("ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n")
+            .is_err());
+        assert!(fake.take_ops().is_empty());
+        assert!(host.get_auth()?.is_mutual());
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_same_type_no_churn() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        root.create_subsystem("nqn.2023-11.sh.tty:churn")?;
+        let port = root.create_port(1)?;
+        let tcp = PortType::Tcp("192.0.2.1:4420".parse()?);
+        port.set_type(tcp)?;
+        port.enable_subsystem("nqn.2023-11.sh.tty:churn")?;
+
+        fake.take_ops();
+        port.set_type(tcp)?;
+        assert!(fake
+            .take_ops()
+            .iter()
+            .all(|op| matches!(op, FakeOp::Read(_))));
+
+        // A different type still goes through the whole dance.
+        port.set_type(PortType::Tcp("192.0.2.1:4421".parse()?))?;
+        let ops = fake.take_ops();
+        assert!(ops.iter().any(|op| matches!(op, FakeOp::RemoveLink(_))));
+        assert!(ops.iter().any(|op| matches!(op, FakeOp::Symlink(_))));
+        assert!(port.has_subsystem("nqn.2023-11.sh.tty:churn")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_same_fc_type_no_churn() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let port = root.create_port(1)?;
+        let fc = PortType::FibreChannel(FibreChannelAddr::new(
+            0x2000_0090_fa94_2779,
+            0x1000_0090_fa94_2779,
+        ));
+        port.set_type(fc)?;
+        // Written by another tool, short and in uppercase.
+        root.write(
+            port.path.join("addr_traddr"),
+            "nn-20000090FA942779:pn-10000090FA942779",
+        )?;
+        assert_eq!(port.get_type()?, fc);
+
+        fake.take_ops();
+        port.set_type(fc)?;
+        assert!(fake
+            .take_ops()
+            .iter()
+            .all(|op| matches!(op, FakeOp::Read(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_type_address_only() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        let port = root.create_port(1)?;
+        port.set_type(PortType::Tcp("192.0.2.1:4420".parse()?))?;
+
+        let written = |ops: Vec<FakeOp>| -> Vec<String> {
+            ops.into_iter()
+                .filter_map(|op| match op {
+                    FakeOp::Write(rel, _) if rel.starts_with("ports/1/addr_") => Some(rel),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        fake.take_ops();
+        port.set_type(PortType::Tcp("192.0.2.2:4420".parse()?))?;
+        assert_eq!(written(fake.take_ops()), ["ports/1/addr_traddr"]);
+
+        port.set_type(PortType::Rdma("192.0.2.2:4420".parse()?))?;
+        assert_eq!(written(fake.take_ops()), ["ports/1/addr_trtype"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_address_keeps_transport() -> Result<()> {
+        let (_fake, root) = FakeBackend::new_root();
+        let port = root.create_port(1)?;
+        port.set_type(PortType::Tcp("192.0.2.1:4420".parse()?))?;
+
+        let moved = PortType::Tcp("192.0.2.2:4420".parse()?);
+        port.set_address(moved)?;
+        assert_eq!(port.get_type()?, moved);
+
+        let err = port
+            .set_address(PortType::Rdma("192.0.2.2:4420".parse()?))
+            .unwrap_err();
+        assert!(matches!(err.root(), Error::PortTransportChanged(1, _, _)));
+        assert_eq!(port.get_type()?, moved);
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_attrs() {
+        assert!(is_key_attr(Path::new("hosts/host/dhchap_key")));
+        assert!(is_key_attr(Path::new("hosts/host/dhchap_ctrl_key")));
+        assert!(!is_key_attr(Path::new("hosts/host/dhchap_hash")));
+        assert!(!is_key_attr(Path::new("subsystems/sub/attr_model")));
+    }
+
+    #[test]
+    fn test_list_all_hosts() -> Result<()> {
+        let (_fake, root) = FakeBackend::new_root();
+        let sub = root.create_subsystem("nqn.2023-11.sh.tty:hosts")?;
+        sub.enable_host("nqn.2023-11.sh.tty:used")?;
+        sub.enable_host("nqn.2023-11.sh.tty:unused")?;
+        sub.disable_host("nqn.2023-11.sh.tty:unused")?;
+
+        assert_eq!(
+            root.list_used_hosts()?,
+            BTreeSet::from(["nqn.2023-11.sh.tty:used".to_string()])
+        );
+        assert_eq!(
+            root.list_all_hosts()?,
+            BTreeSet::from([
+                "nqn.2023-11.sh.tty:unused".to_string(),
+                "nqn.2023-11.sh.tty:used".to_string()
+            ])
+        );
+        Ok(())
+    }
+}
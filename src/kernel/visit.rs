@@ -0,0 +1,248 @@
+// Gathering the state object by object, for consumers which don't need all of it at once,
+// like exporters streaming the namespaces of very large targets elsewhere.
+
+use super::sysfs::{NvmetRoot, NvmetSubsystem};
+use super::{GatherWarning, KernelConfig, ObjectRef};
+use crate::errors::{Context, Error, Result};
+use crate::state::{Namespace, Port, State, Subsystem};
+use std::collections::BTreeSet;
+
+/// Callbacks of `KernelConfig::visit_state`, called for each object as soon as it is read.
+///
+/// Returning an error from any of them stops visiting, and `visit_state` returns it.
+pub trait StateVisitor {
+    fn port(&mut self, _id: u16, _port: Port) -> Result<()> {
+        Ok(())
+    }
+
+    /// A subsystem without its namespaces, which are visited right after it.
+    fn subsystem(&mut self, _nqn: &str, _subsystem: Subsystem) -> Result<()> {
+        Ok(())
+    }
+
+    fn namespace(&mut self, _nqn: &str, _nsid: u32, _namespace: Namespace) -> Result<()> {
+        Ok(())
+    }
+
+    /// An object which could not be read.
+    ///
+    /// Returning `Ok` continues with the next object. For a subsystem, that is the next
+    /// subsystem, as none of its namespaces can be read either. By default, the error is
+    /// returned, which stops visiting.
+    fn error(&mut self, _object: ObjectRef, error: Error) -> Result<()> {
+        Err(error)
+    }
+}
+
+impl KernelConfig {
+    /// Read the state, passing each port, subsystem and namespace to `visitor` as it is read,
+    /// instead of keeping all of them.
+    ///
+    /// Ports are visited first, then the subsystems, each followed by its namespaces.
+    pub fn visit_state(visitor: &mut impl StateVisitor) -> Result<()> {
+        Self::visit_state_in(&NvmetRoot::system(), visitor)
+    }
+
+    pub(crate) fn visit_state_in(root: &NvmetRoot, visitor: &mut impl StateVisitor) -> Result<()> {
+        root.check_exists()?;
+
+        for port in root.list_ports().context("Failed to gather port list")? {
+            match Self::gather_port(&port) {
+                Ok(Some(gathered)) => visitor.port(port.id, gathered)?,
+                Ok(None) => {}
+                Err(err) => visitor.error(ObjectRef::Port(port.id), err)?,
+            }
+        }
+
+        for subsystem in root
+            .list_subsystems()
+            .context("Failed to gather subsystem list")?
+        {
+            Self::visit_subsystem(&subsystem, visitor)?;
+        }
+        Ok(())
+    }
+
+    fn visit_subsystem(subsystem: &NvmetSubsystem, visitor: &mut impl StateVisitor) -> Result<()> {
+        let nqn = &subsystem.nqn;
+        let sub = match Self::gather_subsystem_attrs(subsystem) {
+            Ok(sub) => sub,
+            Err(err) => return visitor.error(ObjectRef::Subsystem(nqn.clone()), err),
+        };
+        visitor.subsystem(nqn, sub)?;
+        let namespaces = match Self::list_subsystem_namespaces(subsystem) {
+            Ok(namespaces) => namespaces,
+            Err(err) => return visitor.error(ObjectRef::Subsystem(nqn.clone()), err),
+        };
+        for (nsid, nvmetns) in namespaces {
+            match Self::gather_namespace(subsystem, nsid, &nvmetns) {
+                Ok(ns) => visitor.namespace(nqn, nsid, ns)?,
+                Err(err) => visitor.error(ObjectRef::Namespace(nqn.clone(), nsid), err)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Collects the visited objects into a `State`, see `KernelConfig::gather_state_partial`.
+///
+/// Subsystems with any unreadable part are left out and warned about instead.
+/// Unreadable ports stop gathering.
+#[derive(Default)]
+pub(super) struct StateGatherer {
+    pub(super) state: State,
+    pub(super) warnings: Vec<GatherWarning>,
+    failed: BTreeSet<String>,
+}
+
+impl StateVisitor for StateGatherer {
+    fn port(&mut self, id: u16, port: Port) -> Result<()> {
+        self.state.ports.insert(id, port);
+        Ok(())
+    }
+
+    fn subsystem(&mut self, nqn: &str, subsystem: Subsystem) -> Result<()> {
+        self.state.subsystems.insert(nqn.to_string(), subsystem);
+        Ok(())
+    }
+
+    fn namespace(&mut self, nqn: &str, nsid: u32, namespace: Namespace) -> Result<()> {
+        if let Some(sub) = self.state.subsystems.get_mut(nqn) {
+            sub.namespaces.insert(nsid, namespace);
+        }
+        Ok(())
+    }
+
+    fn error(&mut self, object: ObjectRef, error: Error) -> Result<()> {
+        let nqn = match object {
+            ObjectRef::Subsystem(nqn) | ObjectRef::Namespace(nqn, _) => nqn,
+            ObjectRef::Port(_) | ObjectRef::Host(_) => return Err(error),
+        };
+        self.state.subsystems.remove(&nqn);
+        // Only the first error of a subsystem is reported.
+        if self.failed.insert(nqn.clone()) {
+            self.warnings.push(GatherWarning { nqn, error });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::FakeBackend;
+    use crate::kernel::tests::{example_state, SUB};
+
+    const BROKEN: &str = "nqn.2023-11.sh.tty:broken";
+    const LAST: &str = "nqn.2023-11.sh.tty:last";
+
+    /// Records what it visits, continuing after errors unless told to abort.
+    #[derive(Default)]
+    struct Recorder {
+        abort: bool,
+        visited: Vec<String>,
+    }
+
+    impl StateVisitor for Recorder {
+        fn port(&mut self, id: u16, _port: Port) -> Result<()> {
+            self.visited.push(format!("port:{id}"));
+            Ok(())
+        }
+
+        fn subsystem(&mut self, nqn: &str, _subsystem: Subsystem) -> Result<()> {
+            self.visited.push(format!("subsystem:{nqn}"));
+            Ok(())
+        }
+
+        fn namespace(&mut self, nqn: &str, nsid: u32, _namespace: Namespace) -> Result<()> {
+            self.visited.push(format!("namespace:{nqn}/{nsid}"));
+            Ok(())
+        }
+
+        fn error(&mut self, object: ObjectRef, error: Error) -> Result<()> {
+            self.visited.push(format!("error:{object}"));
+            if self.abort {
+                Err(error)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn setup() -> Result<NvmetRoot> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let mut desired = example_state();
+        let mut sub = desired.subsystems[SUB].clone();
+        sub.namespaces.insert(2, sub.namespaces[&1].clone());
+        sub.namespaces.insert(3, sub.namespaces[&1].clone());
+        desired.subsystems.insert(BROKEN.to_string(), sub.clone());
+        desired.subsystems.insert(LAST.to_string(), sub);
+        KernelConfig::apply_delta_in(&root, State::default().get_deltas(&desired))?;
+        fake.remove_attr(&format!("subsystems/{BROKEN}/namespaces/2/device_path"));
+        Ok(root)
+    }
+
+    #[test]
+    fn test_visit_continue() -> Result<()> {
+        let root = setup()?;
+        let mut recorder = Recorder::default();
+        KernelConfig::visit_state_in(&root, &mut recorder)?;
+        assert_eq!(
+            recorder.visited,
+            [
+                "port:1".to_string(),
+                format!("subsystem:{BROKEN}"),
+                format!("namespace:{BROKEN}/1"),
+                format!("error:namespace:{BROKEN}/2"),
+                format!("namespace:{BROKEN}/3"),
+                format!("subsystem:{SUB}"),
+                format!("namespace:{SUB}/1"),
+                format!("subsystem:{LAST}"),
+                format!("namespace:{LAST}/1"),
+                format!("namespace:{LAST}/2"),
+                format!("namespace:{LAST}/3"),
+            ]
+        );
+
+        // Gathering leaves out the whole subsystem.
+        let (state, warnings) = KernelConfig::gather_state_partial_in(&root)?;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].nqn, BROKEN);
+        assert!(!state.subsystems.contains_key(BROKEN));
+        assert_eq!(state.subsystems[LAST].namespaces.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_visit_abort() -> Result<()> {
+        let root = setup()?;
+        let mut recorder = Recorder {
+            abort: true,
+            ..Default::default()
+        };
+        let err = KernelConfig::visit_state_in(&root, &mut recorder).unwrap_err();
+        assert!(err
+            .full_message()
+            .contains(&format!("Failed to get namespace 2 for subsystem {BROKEN}")));
+        // Nothing is visited after the error.
+        assert_eq!(
+            recorder.visited.last(),
+            Some(&format!("error:namespace:{BROKEN}/2"))
+        );
+        assert!(KernelConfig::gather_state_in(&root).is_err());
+
+        // Visitors which only count namespaces get the default error handling.
+        struct Counter(usize);
+        impl StateVisitor for Counter {
+            fn namespace(&mut self, _nqn: &str, _nsid: u32, _ns: Namespace) -> Result<()> {
+                self.0 += 1;
+                Ok(())
+            }
+        }
+        let mut counter = Counter(0);
+        assert!(KernelConfig::visit_state_in(&root, &mut counter).is_err());
+        assert_eq!(counter.0, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+// Waiting for objects created by another process, like a provisioning step running in parallel.
+// configfs has no change notification of its own, so the tree is polled.
+
+use super::sysfs::NvmetRoot;
+use super::KernelConfig;
+use crate::errors::{Error, Result};
+use crate::helpers::assert_valid_nqn;
+use std::time::{Duration, Instant};
+
+/// How often to look for the awaited object.
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+
+impl KernelConfig {
+    /// Wait until there is a subsystem `nqn`, failing with `Error::Timeout` after `timeout`.
+    pub fn wait_for_subsystem(nqn: &str, timeout: Duration) -> Result<()> {
+        Self::wait_for_subsystem_in(&NvmetRoot::system(), nqn, timeout, WAIT_INTERVAL)
+    }
+
+    /// Wait until there is a port `id` with its address set, failing with `Error::Timeout`
+    /// after `timeout`.
+    ///
+    /// Like `get_port`, a port whose address isn't set up yet doesn't count.
+    pub fn wait_for_port(id: u16, timeout: Duration) -> Result<()> {
+        Self::wait_for_port_in(&NvmetRoot::system(), id, timeout, WAIT_INTERVAL)
+    }
+
+    pub(crate) fn wait_for_subsystem_in(
+        root: &NvmetRoot,
+        nqn: &str,
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<()> {
+        assert_valid_nqn(nqn)?;
+        poll(timeout, interval, &format!("subsystem {nqn}"), || {
+            root.check_exists()?;
+            root.has_subsystem(nqn)
+        })
+    }
+
+    pub(crate) fn wait_for_port_in(
+        root: &NvmetRoot,
+        id: u16,
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<()> {
+        poll(timeout, interval, &format!("port {id}"), || {
+            root.check_exists()?;
+            Ok(root.has_port(id)? && root.open_port(id).get_type().is_ok())
+        })
+    }
+}
+
+/// Call `present` every `interval` until it returns true, for at most `timeout`.
+fn poll<F: FnMut() -> Result<bool>>(
+    timeout: Duration,
+    interval: Duration,
+    what: &str,
+    mut present: F,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if present()? {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Timeout(what.to_string(), timeout));
+        }
+        std::thread::sleep(interval.min(deadline - now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::FakeBackend;
+    use crate::state::{Port, PortType, State, Subsystem};
+    use std::collections::BTreeSet;
+
+    const SUB: &str = "nqn.2023-11.sh.tty:wait";
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const INTERVAL: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn test_wait_appearing() -> Result<()> {
+        let (_fake, root) = FakeBackend::new_root();
+        let creator = root.clone();
+        let created = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            let mut state = State::default();
+            state
+                .subsystems
+                .insert(SUB.to_string(), Subsystem::default());
+            state
+                .ports
+                .insert(1, Port::new(PortType::Loop, BTreeSet::new()));
+            KernelConfig::apply_delta_in(&creator, State::default().get_deltas(&state))
+        });
+
+        let start = Instant::now();
+        KernelConfig::wait_for_subsystem_in(&root, SUB, TIMEOUT, INTERVAL)?;
+        KernelConfig::wait_for_port_in(&root, 1, TIMEOUT, INTERVAL)?;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        created.join().unwrap()?;
+
+        // Present ones return right away.
+        KernelConfig::wait_for_subsystem_in(&root, SUB, Duration::ZERO, INTERVAL)?;
+        KernelConfig::wait_for_port_in(&root, 1, Duration::ZERO, INTERVAL)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_timeout() -> Result<()> {
+        let (_fake, root) = FakeBackend::new_root();
+        let timeout = Duration::from_millis(50);
+        let err = KernelConfig::wait_for_subsystem_in(&root, SUB, timeout, INTERVAL).unwrap_err();
+        assert!(
+            matches!(err, Error::Timeout(ref what, t) if *what == format!("subsystem {SUB}") && t == timeout)
+        );
+        assert!(err.to_string().ends_with("after 50ms"), "{err}");
+
+        // A port without its address set up yet isn't there yet.
+        root.create_port(2)?;
+        let err = KernelConfig::wait_for_port_in(&root, 2, timeout, INTERVAL).unwrap_err();
+        assert!(matches!(err, Error::Timeout(what, _) if what == "port 2"));
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+// Subsystem aliases, kept next to the kernel state.
+// The kernel has nowhere to store them, so they live in a file of their own.
+
+use super::types::State;
+use crate::errors::{Context, Error, Result};
+use crate::helpers::{assert_valid_alias, assert_valid_nqn, write_file_atomic};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs::File, path::Path};
+
+/// Default location of the alias file.
+pub const ALIAS_FILE: &str = "/var/lib/nvmetcfg/aliases.yaml";
+
+/// Aliases of subsystems, keyed by subsystem NQN.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Aliases(BTreeMap<String, String>);
+
+impl Aliases {
+    /// Load the aliases from `path`. A missing file means there are no aliases.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let f = File::open(path)
+            .with_context(|| format!("Failed to open alias file {}", path.display()))?;
+        let aliases = serde_yaml::from_reader(f)
+            .with_context(|| format!("Failed to read alias file {}", path.display()))?;
+        Ok(aliases)
+    }
+
+    /// Save the aliases to `path`, creating its parent directory if needed.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+        let yaml = serde_yaml::to_string(self).context("Failed to serialize aliases")?;
+        write_file_atomic(path, 0o644, yaml.as_bytes())
+            .with_context(|| format!("Failed to write alias file {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Collect the aliases set in a state, checking that they are valid and unique.
+    pub fn from_state(state: &State) -> Result<Self> {
+        let mut aliases = Self::default();
+        for (nqn, sub) in &state.subsystems {
+            if let Some(alias) = &sub.alias {
+                aliases
+                    .set(alias, nqn)
+                    .with_context(|| format!("Invalid alias for subsystem {nqn}"))?;
+            }
+        }
+        Ok(aliases)
+    }
+
+    /// Take over the aliases set in a state.
+    ///
+    /// These take precedence: an alias already used by another subsystem is moved over.
+    pub fn extend_from_state(&mut self, state: &State) -> Result<()> {
+        for (nqn, alias) in Self::from_state(state)?.0 {
+            self.0.retain(|_, a| *a != alias);
+            self.0.insert(nqn, alias);
+        }
+        Ok(())
+    }
+
+    /// Fill in the alias of every subsystem in `state` that has one.
+    pub fn apply_to_state(&self, state: &mut State) {
+        for (nqn, sub) in &mut state.subsystems {
+            if let Some(alias) = self.0.get(nqn) {
+                sub.alias = Some(alias.clone());
+            }
+        }
+    }
+
+    /// Set the alias of subsystem `nqn`, replacing its previous alias.
+    pub fn set(&mut self, alias: &str, nqn: &str) -> Result<()> {
+        assert_valid_alias(alias)?;
+        assert_valid_nqn(nqn)?;
+        if let Some(other) = self.nqns_of(alias).into_iter().find(|other| *other != nqn) {
+            return Err(Error::DuplicateAlias(
+                alias.to_string(),
+                other.to_string(),
+                nqn.to_string(),
+            ));
+        }
+        self.0.insert(nqn.to_string(), alias.to_string());
+        Ok(())
+    }
+
+    /// Remove an alias, returning the NQN of the subsystem it referred to.
+    pub fn remove(&mut self, alias: &str) -> Result<String> {
+        let nqn = self.resolve_alias(alias)?;
+        self.0.remove(&nqn);
+        Ok(nqn)
+    }
+
+    /// Resolve a subsystem name given on the command line.
+    ///
+    /// Names starting with `@` are looked up as aliases, anything else is returned as is.
+    pub fn resolve(&self, name: &str) -> Result<String> {
+        match name.strip_prefix('@') {
+            Some(alias) => self.resolve_alias(alias),
+            None => Ok(name.to_string()),
+        }
+    }
+
+    /// Look up the NQN of the subsystem with the given alias.
+    pub fn resolve_alias(&self, alias: &str) -> Result<String> {
+        match self.nqns_of(alias).as_slice() {
+            [] => Err(Error::UnknownAlias(alias.to_string())),
+            [nqn] => Ok((*nqn).to_string()),
+            nqns => Err(Error::AmbiguousAlias(alias.to_string(), nqns.join(", "))),
+        }
+    }
+
+    /// Iterate over all (NQN, alias) pairs, sorted by NQN.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn nqns_of(&self, alias: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(_, a)| *a == alias)
+            .map(|(nqn, _)| nqn.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use crate::state::Subsystem;
+
+    const SUB1: &str = "nqn.2023-11.sh.tty:sub1";
+    const SUB2: &str = "nqn.2023-11.sh.tty:sub2";
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let mut aliases = Aliases::default();
+        aliases.set("backup", SUB1)?;
+        assert_eq!(aliases.resolve("@backup")?, SUB1);
+        // Plain NQNs are passed through.
+        assert_eq!(aliases.resolve(SUB2)?, SUB2);
+
+        let err = aliases.resolve("@missing").unwrap_err();
+        assert!(matches!(err.root(), Error::UnknownAlias(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_replace_and_remove() -> Result<()> {
+        let mut aliases = Aliases::default();
+        aliases.set("backup", SUB1)?;
+        // Another subsystem can't take the same alias.
+        let err = aliases.set("backup", SUB2).unwrap_err();
+        assert!(matches!(err.root(), Error::DuplicateAlias(..)));
+        // Setting it again for the same subsystem is fine.
+        aliases.set("backup", SUB1)?;
+        // Renaming replaces the old alias.
+        aliases.set("archive", SUB1)?;
+        assert!(aliases.resolve("@backup").is_err());
+
+        assert_eq!(aliases.remove("archive")?, SUB1);
+        assert!(aliases.is_empty());
+        assert!(aliases.remove("archive").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ambiguous() {
+        // Only possible with a hand-edited file.
+        let aliases: Aliases =
+            serde_yaml::from_str(&format!("{SUB1}: backup\n{SUB2}: backup\n")).unwrap();
+        let err = aliases.resolve("@backup").unwrap_err();
+        assert!(matches!(err.root(), Error::AmbiguousAlias(..)));
+    }
+
+    #[test]
+    fn test_state_roundtrip() -> Result<()> {
+        let mut state = State::default();
+        state.subsystems.insert(
+            SUB1.to_string(),
+            Subsystem {
+                alias: Some("backup".to_string()),
+                ..Default::default()
+            },
+        );
+        state
+            .subsystems
+            .insert(SUB2.to_string(), Subsystem::default());
+        let aliases = Aliases::from_state(&state)?;
+
+        let dir = TestDir::new("aliases");
+        let path = dir.join("aliases.yaml");
+        assert!(Aliases::load(&path)?.is_empty());
+        aliases.save(&path)?;
+        let loaded = Aliases::load(&path)?;
+        assert_eq!(loaded, aliases);
+
+        let mut gathered = state.clone();
+        for sub in gathered.subsystems.values_mut() {
+            sub.alias = None;
+        }
+        loaded.apply_to_state(&mut gathered);
+        assert_eq!(gathered, state);
+
+        // Aliases from a state replace conflicting ones.
+        let mut existing = Aliases::default();
+        existing.set("backup", SUB2)?;
+        existing.extend_from_state(&state)?;
+        assert_eq!(existing, aliases);
+
+        // Aliases have to be unique.
+        state.subsystems.get_mut(SUB2).unwrap().alias = Some("backup".to_string());
+        assert!(Aliases::from_state(&state).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+// An append-only record of the changes applied to the kernel, for showing who changed what when.
+// Every application is a JSON object on a line of its own, so the log can be tailed and grepped.
+
+use super::delta::StateDelta;
+use crate::errors::{Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Permissions of newly created audit logs.
+pub const AUDIT_LOG_MODE: u32 = 0o600;
+
+/// Whether the changes of an `AuditRecord` were applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditResult {
+    Ok,
+    /// Applying failed, possibly after some of the changes were made.
+    Error,
+}
+
+/// A line of the audit log, describing one application of changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// When applying finished, in RFC 3339 format.
+    pub timestamp: String,
+    /// The command that applied the changes.
+    pub command: String,
+    pub deltas: Vec<StateDelta>,
+    /// Changes outside of the state, like to hosts and their keys, as lines like
+    /// `remove host nqn.2023-11.sh.tty:client`. Never carries keys themselves.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<String>,
+    pub result: AuditResult,
+    /// Why applying failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// A record of applying `deltas` now, which failed if `error` is given.
+    #[must_use]
+    pub fn new(command: &str, deltas: Vec<StateDelta>, error: Option<String>) -> Self {
+        Self {
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            command: command.to_string(),
+            deltas,
+            actions: Vec::new(),
+            result: if error.is_some() {
+                AuditResult::Error
+            } else {
+                AuditResult::Ok
+            },
+            error,
+        }
+    }
+
+    /// Also record `actions`, see `AuditRecord::actions`.
+    #[must_use]
+    pub fn with_actions(mut self, actions: Vec<String>) -> Self {
+        self.actions = actions;
+        self
+    }
+}
+
+/// An audit log file, appended to by every application of changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    #[must_use]
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Apply `deltas` using `apply` and append a record of the outcome, whether it failed or not.
+    ///
+    /// Nothing is recorded if there are no changes.
+    pub fn apply<E, F>(&self, command: &str, deltas: Vec<StateDelta>, apply: F) -> Result<(), E>
+    where
+        E: Display + From<Error>,
+        F: FnOnce(Vec<StateDelta>) -> Result<(), E>,
+    {
+        if deltas.is_empty() {
+            return apply(deltas);
+        }
+        let result = apply(deltas.clone());
+        let error = result.as_ref().err().map(|err| format!("{err:#}"));
+        self.append(&AuditRecord::new(command, deltas, error))?;
+        result
+    }
+
+    /// Append `record` as a line of its own, creating the log and its directory if needed.
+    pub fn append(&self, record: &AuditRecord) -> Result<()> {
+        let path = &self.path;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+        let mut line = serde_json::to_string(record).context("Failed to serialize audit record")?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(AUDIT_LOG_MODE)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        // A single write, so that records of concurrent runs don't interleave.
+        file.write_all(line.as_bytes())
+            .and_then(|()| file.sync_data())
+            .with_context(|| format!("Failed to append to audit log {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Read all records of the log. A missing file means there are none.
+    pub fn read(&self) -> Result<Vec<AuditRecord>> {
+        let path = &self.path;
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        let mut records = Vec::new();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line =
+                line.with_context(|| format!("Failed to read audit log {}", path.display()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line).with_context(|| {
+                format!(
+                    "Failed to parse line {} of audit log {}",
+                    n + 1,
+                    path.display()
+                )
+            })?);
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use crate::kernel::fake::FakeBackend;
+    use crate::kernel::tests::{example_state, SUB};
+    use crate::kernel::KernelConfig;
+    use crate::state::State;
+
+    #[test]
+    fn test_apply_appends_record() -> Result<()> {
+        let (fake, root) = FakeBackend::new_root();
+        fake.add_device("/dev/loop0");
+        let dir = TestDir::new("audit");
+        let log = AuditLog::new(dir.join("audit.jsonl"));
+        assert!(log.read()?.is_empty());
+
+        let deltas = State::default().get_deltas(&example_state());
+        log.apply("state restore state.yaml", deltas.clone(), |deltas| {
+            KernelConfig::apply_delta_in(&root, deltas)
+        })?;
+        // No changes, no record.
+        log.apply("state restore state.yaml", Vec::new(), |deltas| {
+            KernelConfig::apply_delta_in(&root, deltas)
+        })?;
+        let removal = vec![StateDelta::RemoveSubsystem(SUB.to_string())];
+        log.apply("subsystem remove", removal.clone(), |deltas| {
+            KernelConfig::apply_delta_in(&root, deltas)
+        })?;
+        let missing = vec![StateDelta::RemoveSubsystem(SUB.to_string())];
+        let err = log
+            .apply("subsystem remove", missing.clone(), |deltas| {
+                KernelConfig::apply_delta_in(&root, deltas)
+            })
+            .unwrap_err();
+
+        let records = log.read()?;
+        let text = std::fs::read_to_string(log.path())?;
+        assert_eq!(text.lines().count(), 3);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].command, "state restore state.yaml");
+        assert_eq!(records[0].deltas, deltas);
+        assert_eq!(records[0].result, AuditResult::Ok);
+        assert_eq!(records[0].error, None);
+        assert!(humantime::parse_rfc3339(&records[0].timestamp).is_ok());
+        assert_eq!(records[1].deltas, removal);
+        assert_eq!(records[2].deltas, missing);
+        assert_eq!(records[2].result, AuditResult::Error);
+        assert_eq!(records[2].error, Some(format!("{err:#}")));
+        assert!(text.contains("\"remove_subsystem\":\"nqn.2023-11.sh.tty:fake-sub\""));
+        assert!(!text.contains("actions"));
+
+        let record = AuditRecord::new("host add", Vec::new(), None)
+            .with_actions(vec!["add host nqn.2023-11.sh.tty:client".to_string()]);
+        let line = serde_json::to_string(&record)?;
+        assert!(line.contains(r#""actions":["add host nqn.2023-11.sh.tty:client"]"#));
+        assert_eq!(serde_json::from_str::<AuditRecord>(&line)?, record);
+        Ok(())
+    }
+}
@@ -0,0 +1,553 @@
+// Assembling a state in code, without spelling out the nested maps.
+
+use super::labels::assert_valid_labels;
+use super::types::{Namespace, Port, PortInterface, PortType, State, Subsystem};
+use crate::errors::{Context, Error, Result};
+use crate::helpers::{
+    assert_valid_alias, assert_valid_model, assert_valid_nqn, assert_valid_nsid,
+    assert_valid_serial,
+};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// What the methods for subsystems or ports apply to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Current {
+    None,
+    Subsystem(String),
+    Port(u16),
+}
+
+/// Builds a `State` step by step.
+///
+/// Methods like `namespace` and `host` apply to the subsystem last given by `subsystem`,
+/// `with_subsystem` to the port last given by `port`. Mistakes are reported by `build`,
+/// which also validates the whole state.
+///
+/// ```
+/// use nvmetcfg::state::{PortType, StateBuilder};
+///
+/// let state = StateBuilder::new()
+///     .subsystem("nqn.2023-11.sh.tty:test")
+///     .namespace(1, "/dev/loop0")
+///     .host("nqn.2023-11.sh.tty:client")
+///     .port(1, PortType::Loop)
+///     .with_subsystem("nqn.2023-11.sh.tty:test")
+///     .build()
+///     .unwrap();
+/// assert_eq!(state.ports[&1].subsystems.len(), 1);
+/// ```
+#[derive(Debug)]
+#[must_use]
+pub struct StateBuilder {
+    state: State,
+    current: Current,
+    error: Option<Error>,
+}
+
+impl Default for StateBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateBuilder {
+    pub fn new() -> Self {
+        Self {
+            state: State::default(),
+            current: Current::None,
+            error: None,
+        }
+    }
+
+    /// Add a subsystem, or select it again if it was added before.
+    pub fn subsystem(mut self, nqn: &str) -> Self {
+        self.state.subsystems.entry(nqn.to_string()).or_default();
+        self.current = Current::Subsystem(nqn.to_string());
+        self
+    }
+
+    /// Set the model of the current subsystem.
+    pub fn model(self, model: &str) -> Self {
+        self.with_current_subsystem("model", |sub| {
+            sub.model = Some(model.to_string());
+            Ok(())
+        })
+    }
+
+    /// Set the serial of the current subsystem.
+    pub fn serial(self, serial: &str) -> Self {
+        self.with_current_subsystem("serial", |sub| {
+            sub.serial = Some(serial.to_string());
+            Ok(())
+        })
+    }
+
+    /// Set the alias of the current subsystem.
+    pub fn alias(self, alias: &str) -> Self {
+        self.with_current_subsystem("alias", |sub| {
+            sub.alias = Some(alias.to_string());
+            Ok(())
+        })
+    }
+
+    /// Allow a host on the current subsystem.
+    pub fn host(self, nqn: &str) -> Self {
+        self.with_current_subsystem("host", |sub| {
+            sub.allowed_hosts.insert(nqn.to_string());
+            Ok(())
+        })
+    }
+
+    /// Add an enabled namespace backed by `path` to the current subsystem.
+    /// The kernel generates its UUID and NGUID.
+    pub fn namespace(self, nsid: u32, path: impl Into<PathBuf>) -> Self {
+        match Namespace::builder(path).build() {
+            Ok(namespace) => self.namespace_with(nsid, namespace),
+            Err(err) => self.fail(err),
+        }
+    }
+
+    /// Add a namespace to the current subsystem.
+    pub fn namespace_with(self, nsid: u32, namespace: Namespace) -> Self {
+        let Current::Subsystem(nqn) = self.current.clone() else {
+            return self.fail(Error::BuilderOrder("namespace", "subsystem"));
+        };
+        self.with_current_subsystem("namespace", |sub| {
+            if sub.namespaces.contains_key(&nsid) {
+                return Err(Error::ExistingNamespace(nsid, nqn));
+            }
+            sub.namespaces.insert(nsid, namespace);
+            Ok(())
+        })
+    }
+
+    /// Add a port.
+    pub fn port(mut self, id: u16, port_type: PortType) -> Self {
+        if self.state.ports.contains_key(&id) {
+            return self.fail(Error::ExistingPort(id));
+        }
+        self.state
+            .ports
+            .insert(id, Port::new(port_type, BTreeSet::new()));
+        self.current = Current::Port(id);
+        self
+    }
+
+    /// Provide a subsystem on the current port. The subsystem has to be added as well.
+    pub fn with_subsystem(mut self, nqn: &str) -> Self {
+        let Current::Port(id) = self.current else {
+            return self.fail(Error::BuilderOrder("with_subsystem", "port"));
+        };
+        if let Some(port) = self.state.ports.get_mut(&id) {
+            port.subsystems.insert(nqn.to_string());
+        }
+        self
+    }
+
+    /// Set a label on the current subsystem or port.
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        let labels = match &self.current {
+            Current::Subsystem(nqn) => self.state.subsystems.get_mut(nqn).map(|s| &mut s.labels),
+            Current::Port(id) => self.state.ports.get_mut(id).map(|p| &mut p.labels),
+            Current::None => None,
+        };
+        match labels {
+            Some(labels) => {
+                labels.insert(key.to_string(), value.to_string());
+                self
+            }
+            None => self.fail(Error::BuilderOrder("label", "subsystem or port")),
+        }
+    }
+
+    /// Validate and return the state.
+    pub fn build(self) -> Result<State> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        self.state.validate(false)?;
+        Ok(self.state)
+    }
+
+    fn with_current_subsystem(
+        mut self,
+        what: &'static str,
+        f: impl FnOnce(&mut Subsystem) -> Result<()>,
+    ) -> Self {
+        let sub = match &self.current {
+            Current::Subsystem(nqn) => self.state.subsystems.get_mut(nqn),
+            _ => None,
+        };
+        let result = match sub {
+            Some(sub) => f(sub),
+            None => Err(Error::BuilderOrder(what, "subsystem")),
+        };
+        match result {
+            Ok(()) => self,
+            Err(err) => self.fail(err),
+        }
+    }
+
+    /// Remember the first error, for `build` to return.
+    fn fail(mut self, err: Error) -> Self {
+        self.error.get_or_insert(err);
+        self
+    }
+}
+
+impl Subsystem {
+    /// Start building a subsystem, see `SubsystemBuilder`.
+    pub fn builder() -> SubsystemBuilder {
+        SubsystemBuilder::default()
+    }
+}
+
+/// Builds a `Subsystem`, checking its values in `build`.
+#[derive(Debug, Default, Clone)]
+#[must_use]
+pub struct SubsystemBuilder {
+    subsystem: Subsystem,
+}
+
+impl SubsystemBuilder {
+    pub fn model(mut self, model: &str) -> Self {
+        self.subsystem.model = Some(model.to_string());
+        self
+    }
+
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.subsystem.serial = Some(serial.to_string());
+        self
+    }
+
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.subsystem.alias = Some(alias.to_string());
+        self
+    }
+
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.subsystem
+            .labels
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Allow a host on the subsystem.
+    pub fn host(mut self, nqn: &str) -> Self {
+        self.subsystem.allowed_hosts.insert(nqn.to_string());
+        self
+    }
+
+    /// Add a namespace, replacing any with the same ID.
+    pub fn namespace(mut self, nsid: u32, namespace: Namespace) -> Self {
+        self.subsystem.namespaces.insert(nsid, namespace);
+        self
+    }
+
+    /// Check the values and return the subsystem.
+    pub fn build(self) -> Result<Subsystem> {
+        let sub = self.subsystem;
+        if let Some(model) = &sub.model {
+            assert_valid_model(model)?;
+        }
+        if let Some(serial) = &sub.serial {
+            assert_valid_serial(serial)?;
+        }
+        if let Some(alias) = &sub.alias {
+            assert_valid_alias(alias)?;
+        }
+        assert_valid_labels(&sub.labels)?;
+        for host in &sub.allowed_hosts {
+            assert_valid_nqn(host).context("Invalid allowed host")?;
+        }
+        for nsid in sub.namespaces.keys() {
+            assert_valid_nsid(*nsid)?;
+        }
+        Ok(sub)
+    }
+}
+
+impl Port {
+    /// Start building a port of `port_type`, see `PortBuilder`.
+    pub fn builder(port_type: PortType) -> PortBuilder {
+        PortBuilder {
+            port: Self::new(port_type, BTreeSet::new()),
+        }
+    }
+}
+
+/// Builds a `Port`, checking its values in `build`.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct PortBuilder {
+    port: Port,
+}
+
+impl PortBuilder {
+    /// Provide a subsystem on the port.
+    pub fn subsystem(mut self, nqn: &str) -> Self {
+        self.port.subsystems.insert(nqn.to_string());
+        self
+    }
+
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.port.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Take the address from a network interface, see `Port::interface`.
+    pub fn interface(mut self, interface: PortInterface) -> Self {
+        self.port.interface = Some(interface);
+        self
+    }
+
+    /// Check the values and return the port.
+    pub fn build(self) -> Result<Port> {
+        let port = self.port;
+        for nqn in &port.subsystems {
+            assert_valid_nqn(nqn)?;
+        }
+        assert_valid_labels(&port.labels)?;
+        if port.interface.is_some() {
+            // Only these can be given by an interface.
+            if !matches!(port.port_type, PortType::Tcp(_) | PortType::Rdma(_)) {
+                return Err(Error::UnsupportedInterfaceTransport(
+                    port.port_type.trtype().to_string(),
+                ));
+            }
+        }
+        Ok(port)
+    }
+}
+
+impl Namespace {
+    /// Start building an enabled namespace backed by `device_path`, see `NamespaceBuilder`.
+    pub fn builder(device_path: impl Into<PathBuf>) -> NamespaceBuilder {
+        NamespaceBuilder {
+            namespace: Self {
+                enabled: true,
+                device_path: device_path.into(),
+                device_spec: None,
+                device_uuid: None,
+                device_nguid: None,
+            },
+        }
+    }
+}
+
+/// Builds a `Namespace`, checking its values in `build`.
+///
+/// Without a UUID or NGUID, the kernel generates one.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct NamespaceBuilder {
+    namespace: Namespace,
+}
+
+impl NamespaceBuilder {
+    pub const fn enabled(mut self, enabled: bool) -> Self {
+        self.namespace.enabled = enabled;
+        self
+    }
+
+    pub const fn uuid(mut self, uuid: Uuid) -> Self {
+        self.namespace.device_uuid = Some(uuid);
+        self
+    }
+
+    pub const fn nguid(mut self, nguid: Uuid) -> Self {
+        self.namespace.device_nguid = Some(nguid);
+        self
+    }
+
+    /// Check the values and return the namespace.
+    ///
+    /// The device has to be given by absolute path, whether it exists is only checked when
+    /// applying the namespace.
+    pub fn build(self) -> Result<Namespace> {
+        let ns = self.namespace;
+        if !ns.device_path.is_absolute() {
+            return Err(Error::InvalidDevice(ns.device_path.display().to_string()));
+        }
+        Ok(ns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::AddrFamily;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    const SUB1: &str = "nqn.2023-11.sh.tty:sub1";
+    const SUB2: &str = "nqn.2023-11.sh.tty:sub2";
+    const HOST: &str = "nqn.2023-11.sh.tty:client";
+
+    #[test]
+    fn test_builder() -> Result<()> {
+        let state = StateBuilder::new()
+            .subsystem(SUB1)
+            .model("Builder")
+            .serial("1234")
+            .alias("one")
+            .label("env", "prod")
+            .namespace(1, "/dev/loop0")
+            .namespace(2, "/dev/loop1")
+            .host(HOST)
+            .subsystem(SUB2)
+            .host(HOST)
+            .port(1, PortType::Tcp("127.0.0.1:4420".parse()?))
+            .with_subsystem(SUB1)
+            .with_subsystem(SUB2)
+            .label("rack", "a1")
+            .port(2, PortType::Loop)
+            .with_subsystem(SUB2)
+            // Selecting a subsystem again adds to it.
+            .subsystem(SUB1)
+            .namespace(3, "/dev/loop2")
+            .build()?;
+
+        assert_eq!(state.subsystems.len(), 2);
+        let sub1 = &state.subsystems[SUB1];
+        assert_eq!(sub1.model.as_deref(), Some("Builder"));
+        assert_eq!(sub1.serial.as_deref(), Some("1234"));
+        assert_eq!(sub1.alias.as_deref(), Some("one"));
+        assert_eq!(
+            sub1.labels,
+            BTreeMap::from([("env".to_string(), "prod".to_string())])
+        );
+        assert_eq!(
+            sub1.namespaces.keys().copied().collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert!(sub1.namespaces[&2].enabled);
+        assert_eq!(sub1.namespaces[&2].device_path, Path::new("/dev/loop1"));
+        assert_eq!(sub1.allowed_hosts, BTreeSet::from([HOST.to_string()]));
+        assert!(state.subsystems[SUB2].namespaces.is_empty());
+
+        assert_eq!(state.ports.len(), 2);
+        assert_eq!(
+            state.ports[&1].subsystems,
+            BTreeSet::from([SUB1.to_string(), SUB2.to_string()])
+        );
+        assert_eq!(state.ports[&1].labels["rack"], "a1");
+        assert_eq!(state.ports[&2].port_type, PortType::Loop);
+        assert_eq!(
+            state.ports[&2].subsystems,
+            BTreeSet::from([SUB2.to_string()])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_builders() -> Result<()> {
+        let uuid = Uuid::from_u128(0x1234);
+        let ns = Namespace::builder("/dev/loop0")
+            .enabled(false)
+            .uuid(uuid)
+            .nguid(uuid)
+            .build()?;
+        assert!(!ns.enabled);
+        assert_eq!(ns.device_path, Path::new("/dev/loop0"));
+        assert_eq!((ns.device_uuid, ns.device_nguid), (Some(uuid), Some(uuid)));
+        assert!(Namespace::builder("/dev/loop0").build()?.enabled);
+
+        let sub = Subsystem::builder()
+            .model("Builder")
+            .serial("1234")
+            .alias("one")
+            .label("env", "prod")
+            .host(HOST)
+            .namespace(1, ns.clone())
+            .build()?;
+        assert_eq!(sub.model.as_deref(), Some("Builder"));
+        assert_eq!(sub.serial.as_deref(), Some("1234"));
+        assert_eq!(sub.alias.as_deref(), Some("one"));
+        assert_eq!(sub.labels["env"], "prod");
+        assert_eq!(sub.allowed_hosts, BTreeSet::from([HOST.to_string()]));
+        assert_eq!(sub.namespaces[&1], ns);
+        assert_eq!(Subsystem::builder().build()?, Subsystem::default());
+
+        let port = Port::builder(PortType::Tcp("0.0.0.0:4420".parse()?))
+            .subsystem(SUB1)
+            .label("rack", "a1")
+            .interface(PortInterface::new("eth1", AddrFamily::Ipv4))
+            .build()?;
+        assert_eq!(port.subsystems, BTreeSet::from([SUB1.to_string()]));
+        assert_eq!(port.labels["rack"], "a1");
+        assert_eq!(port.interface.unwrap().name, "eth1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_builders_invalid() {
+        assert!(Namespace::builder("loop0").build().is_err());
+        assert!(Namespace::builder("").build().is_err());
+        assert!(Subsystem::builder().model(&"M".repeat(41)).build().is_err());
+        assert!(Subsystem::builder().serial("").build().is_err());
+        assert!(Subsystem::builder().alias("@bad").build().is_err());
+        assert!(Subsystem::builder().label("bad key", "x").build().is_err());
+        assert!(Subsystem::builder().host("nqn.ünicode").build().is_err());
+        assert!(Subsystem::builder()
+            .namespace(0, Namespace::builder("/dev/loop0").build().unwrap())
+            .build()
+            .is_err());
+        assert!(Port::builder(PortType::Loop)
+            .subsystem(&"n".repeat(224))
+            .build()
+            .is_err());
+        assert!(Port::builder(PortType::Loop)
+            .interface(PortInterface::new("eth1", AddrFamily::Ipv4))
+            .build()
+            .is_err());
+        // The state builder checks namespaces the same way.
+        assert!(StateBuilder::new()
+            .subsystem(SUB1)
+            .namespace(1, "loop0")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let err = |builder: StateBuilder| builder.build().unwrap_err().to_string();
+
+        assert_eq!(
+            err(StateBuilder::new().namespace(1, "/dev/loop0")),
+            "namespace given before any subsystem"
+        );
+        assert_eq!(
+            err(StateBuilder::new().port(1, PortType::Loop).host(HOST)),
+            "host given before any subsystem"
+        );
+        assert_eq!(
+            err(StateBuilder::new().subsystem(SUB1).with_subsystem(SUB1)),
+            "with_subsystem given before any port"
+        );
+        // The first mistake is reported.
+        assert!(err(StateBuilder::new()
+            .subsystem(SUB1)
+            .namespace(1, "/dev/loop0")
+            .namespace(1, "/dev/loop1")
+            .port(1, PortType::Loop)
+            .port(1, PortType::Loop))
+        .contains("Namespace 1"));
+        assert!(err(StateBuilder::new()
+            .port(1, PortType::Loop)
+            .port(1, PortType::Loop))
+        .contains("Port 1"));
+        // Validation catches the rest, like ports providing unknown subsystems.
+        assert!(StateBuilder::new()
+            .port(1, PortType::Loop)
+            .with_subsystem(SUB1)
+            .build()
+            .is_err());
+        assert!(StateBuilder::new()
+            .subsystem("nqn.ünicode")
+            .build()
+            .is_err());
+    }
+}
@@ -0,0 +1,136 @@
+// Initiator side configuration for reaching the target, in the syntax of nvme-cli.
+
+use super::{PortType, State};
+
+impl PortType {
+    /// The nvme-cli arguments addressing the port, or `None` for loop ports, which are local.
+    #[must_use]
+    pub fn connect_args(&self) -> Option<String> {
+        match self {
+            Self::Loop => None,
+            // IPv6 addresses are given without brackets.
+            Self::Tcp(addr) | Self::Rdma(addr) => Some(format!(
+                "--transport={} --traddr={} --trsvcid={}",
+                self.trtype(),
+                addr.ip(),
+                addr.port()
+            )),
+            Self::FibreChannel(addr) => Some(format!(
+                "--transport={} --traddr={}",
+                self.trtype(),
+                addr.to_traddr()
+            )),
+        }
+    }
+}
+
+fn with_host_traddr(args: String, host_traddr: Option<&str>) -> String {
+    match host_traddr {
+        Some(host_traddr) => format!("{args} --host-traddr={host_traddr}"),
+        None => args,
+    }
+}
+
+impl State {
+    /// `nvme connect` commands for every subsystem on every port an initiator can reach.
+    #[must_use]
+    pub fn connect_commands(&self, host_traddr: Option<&str>) -> Vec<String> {
+        let mut commands = Vec::new();
+        for port in self.ports.values() {
+            let Some(args) = port.port_type.connect_args() else {
+                continue;
+            };
+            for nqn in &port.subsystems {
+                commands.push(with_host_traddr(
+                    format!("nvme connect {args} --nqn={nqn}"),
+                    host_traddr,
+                ));
+            }
+        }
+        commands
+    }
+
+    /// Lines for an initiator's /etc/nvme/discovery.conf, one for each distinct port address
+    /// an initiator can reach.
+    #[must_use]
+    pub fn discovery_conf(&self, host_traddr: Option<&str>) -> Vec<String> {
+        let mut lines = Vec::new();
+        for port in self.ports.values() {
+            let Some(args) = port.port_type.connect_args() else {
+                continue;
+            };
+            let line = with_host_traddr(args, host_traddr);
+            if !lines.contains(&line) {
+                lines.push(line);
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::{FibreChannelAddr, Port, PortType, State};
+    use std::collections::BTreeSet;
+
+    const SUB1: &str = "nqn.2023-11.sh.tty:one";
+    const SUB2: &str = "nqn.2023-11.sh.tty:two";
+
+    fn ports_state() -> State {
+        let mut state = State::default();
+        let subs = BTreeSet::from([SUB1.to_string(), SUB2.to_string()]);
+        for (id, port_type) in [
+            (1, PortType::Tcp("192.0.2.1:4420".parse().unwrap())),
+            (2, PortType::Tcp("[2001:db8::1]:4420".parse().unwrap())),
+            (3, PortType::Rdma("192.0.2.2:4420".parse().unwrap())),
+            (
+                4,
+                PortType::FibreChannel(FibreChannelAddr::new(
+                    0x1000_0000_4400_1123,
+                    0x2000_0000_5500_1123,
+                )),
+            ),
+            (5, PortType::Loop),
+            // Same address as port 1.
+            (6, PortType::Tcp("192.0.2.1:4420".parse().unwrap())),
+        ] {
+            let subs = if id == 1 {
+                subs.clone()
+            } else {
+                BTreeSet::new()
+            };
+            state.ports.insert(id, Port::new(port_type, subs));
+        }
+        state
+    }
+
+    #[test]
+    fn test_discovery_conf() {
+        let state = ports_state();
+        assert_eq!(
+            state.discovery_conf(None).join("\n"),
+            "\
+--transport=tcp --traddr=192.0.2.1 --trsvcid=4420
+--transport=tcp --traddr=2001:db8::1 --trsvcid=4420
+--transport=rdma --traddr=192.0.2.2 --trsvcid=4420
+--transport=fc --traddr=nn-0x1000000044001123:pn-0x2000000055001123"
+        );
+        assert_eq!(
+            state.discovery_conf(Some("192.0.2.100"))[0],
+            "--transport=tcp --traddr=192.0.2.1 --trsvcid=4420 --host-traddr=192.0.2.100"
+        );
+        assert!(State::default().discovery_conf(None).is_empty());
+    }
+
+    #[test]
+    fn test_connect_commands() {
+        assert_eq!(
+            ports_state().connect_commands(None).join("\n"),
+            format!(
+                "\
+nvme connect --transport=tcp --traddr=192.0.2.1 --trsvcid=4420 --nqn={SUB1}
+nvme connect --transport=tcp --traddr=192.0.2.1 --trsvcid=4420 --nqn={SUB2}"
+            )
+        );
+    }
+}
@@ -1,8 +1,14 @@
 use super::types::{Namespace, Port, PortType, State, Subsystem};
-use crate::helpers::get_btreemap_differences;
+use crate::errors::{Error, Result};
+use crate::helpers::{assert_valid_nqn, get_btreemap_differences};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use uuid::Uuid;
 
 // Define the representation of differences to the state.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum StateDelta {
     AddPort(u16, Port),
     UpdatePort(u16, Vec<PortDelta>),
@@ -13,6 +19,53 @@ pub enum StateDelta {
     RemoveSubsystem(String),
 }
 
+/// A line for people, like `update port 1: add subsystem nqn.2023-11.sh.tty:sub`.
+impl fmt::Display for StateDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AddPort(id, port) => {
+                write!(f, "add port {id}: {}", port.port_type)?;
+                if !port.subsystems.is_empty() {
+                    let subsystems: Vec<&str> =
+                        port.subsystems.iter().map(String::as_str).collect();
+                    write!(f, " with subsystems {}", subsystems.join(", "))?;
+                }
+                Ok(())
+            }
+            Self::UpdatePort(id, deltas) => {
+                write!(f, "update port {id}: ")?;
+                write_list(f, deltas)
+            }
+            Self::RemovePort(id) => write!(f, "remove port {id}"),
+            Self::AddSubsystem(nqn, sub) => {
+                write!(f, "add subsystem {nqn}")?;
+                // What it gets set up with, as if it was added empty.
+                let deltas = Subsystem::default().get_deltas(sub);
+                if !deltas.is_empty() {
+                    write!(f, ": ")?;
+                    write_list(f, &deltas)?;
+                }
+                Ok(())
+            }
+            Self::UpdateSubsystem(nqn, deltas) => {
+                write!(f, "update subsystem {nqn}: ")?;
+                write_list(f, deltas)
+            }
+            Self::RemoveSubsystem(nqn) => write!(f, "remove subsystem {nqn}"),
+        }
+    }
+}
+
+fn write_list<T: fmt::Display>(f: &mut fmt::Formatter<'_>, items: &[T]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
 impl State {
     #[must_use]
     pub fn get_deltas(&self, other: &Self) -> Vec<StateDelta> {
@@ -33,13 +86,15 @@ impl State {
 
         // Update Subsystems
         for updated in &subsystem_changes.changed {
-            deltas.push(StateDelta::UpdateSubsystem(
-                updated.to_string(),
-                self.subsystems
-                    .get(updated)
-                    .unwrap()
-                    .get_deltas(other.subsystems.get(updated).unwrap()),
-            ));
+            // Differences the kernel does not know about, like the alias, need no changes.
+            let sub_deltas = self
+                .subsystems
+                .get(updated)
+                .unwrap()
+                .get_deltas(other.subsystems.get(updated).unwrap());
+            if !sub_deltas.is_empty() {
+                deltas.push(StateDelta::UpdateSubsystem(updated.to_string(), sub_deltas));
+            }
         }
 
         // Add Subsystems not in base.
@@ -52,13 +107,18 @@ impl State {
 
         // Update Ports.
         for updated in &port_changes.changed {
-            deltas.push(StateDelta::UpdatePort(
-                *updated,
-                self.ports
-                    .get(updated)
-                    .unwrap()
-                    .get_deltas(other.ports.get(updated).unwrap()),
-            ));
+            // Removing a subsystem already removes it from all ports.
+            let port_deltas: Vec<PortDelta> = self
+                .ports
+                .get(updated)
+                .unwrap()
+                .get_deltas(other.ports.get(updated).unwrap())
+                .into_iter()
+                .filter(|delta| !matches!(delta, PortDelta::RemoveSubsystem(nqn) if subsystem_changes.removed.contains(nqn)))
+                .collect();
+            if !port_deltas.is_empty() {
+                deltas.push(StateDelta::UpdatePort(*updated, port_deltas));
+            }
         }
 
         // Add Ports not in base.
@@ -71,16 +131,352 @@ impl State {
 
         deltas
     }
+
+    /// Compute the changes replacing all subsystems of port `id` with `subsystems`.
+    ///
+    /// The port and all subsystems must exist in `self`.
+    pub fn get_port_subsystem_deltas(
+        &self,
+        id: u16,
+        subsystems: &BTreeSet<String>,
+    ) -> Result<Vec<StateDelta>> {
+        let port = self.ports.get(&id).ok_or(Error::NoSuchPort(id))?;
+        for nqn in subsystems {
+            assert_valid_nqn(nqn)?;
+            if !self.subsystems.contains_key(nqn) {
+                return Err(Error::NoSuchSubsystem(nqn.to_string()));
+            }
+        }
+
+        let desired = Port::new(port.port_type, subsystems.clone());
+        let deltas = port.get_deltas(&desired);
+        if deltas.is_empty() {
+            Ok(Vec::new())
+        } else {
+            Ok(vec![StateDelta::UpdatePort(id, deltas)])
+        }
+    }
+
+    /// Like `get_deltas`, but without changes that would only set fields left unset in `other`.
+    /// This is what remains when comparing a live state against the state it was configured from.
+    #[must_use]
+    pub fn get_unmet_deltas(&self, other: &Self) -> Vec<StateDelta> {
+        let mut deltas = Vec::new();
+        for delta in self.get_deltas(other) {
+            match delta {
+                StateDelta::UpdateSubsystem(nqn, _) => {
+                    let sub_deltas =
+                        self.subsystems[&nqn].get_unmet_deltas(&other.subsystems[&nqn]);
+                    if !sub_deltas.is_empty() {
+                        deltas.push(StateDelta::UpdateSubsystem(nqn, sub_deltas));
+                    }
+                }
+                other => deltas.push(other),
+            }
+        }
+        deltas
+    }
+
+    /// The part of `changes` which would change `self`, dropping those already in effect.
+    ///
+    /// Adding an object already present counts as in effect if it matches the added one, with
+    /// unset identifiers of namespaces matching any value. Removals are always kept, so that
+    /// removing a missing object still fails. Each change is checked against `self` with the
+    /// changes kept before it applied, so one undoing an earlier one is kept as well.
+    #[must_use]
+    pub fn get_pending_deltas(&self, changes: &[StateDelta]) -> Vec<StateDelta> {
+        let mut working = self.clone();
+        // A change which can't be applied fails when applying it for real, which reports it.
+        let keep = |working: &mut Self, delta: StateDelta| {
+            let _ = working.apply_delta(&delta);
+            delta
+        };
+        let mut pending = Vec::new();
+        for delta in changes {
+            match delta {
+                StateDelta::AddPort(id, port) => {
+                    if !working
+                        .ports
+                        .get(id)
+                        .is_some_and(|current| current.get_deltas(port).is_empty())
+                    {
+                        pending.push(keep(&mut working, delta.clone()));
+                    }
+                }
+                StateDelta::UpdatePort(id, port_deltas) => {
+                    if !working.ports.contains_key(id) {
+                        pending.push(keep(&mut working, delta.clone()));
+                        continue;
+                    }
+                    let mut kept = Vec::new();
+                    for pd in port_deltas {
+                        let port = &working.ports[id];
+                        let in_effect = match pd {
+                            PortDelta::UpdatePortType(pt) | PortDelta::UpdateAddress(pt) => {
+                                port.port_type == *pt && port.adrfam() == pt.adrfam()
+                            }
+                            PortDelta::AddSubsystem(nqn) => port.subsystems.contains(nqn),
+                            PortDelta::RemoveSubsystem(_) => false,
+                        };
+                        if !in_effect {
+                            let update = StateDelta::UpdatePort(*id, vec![pd.clone()]);
+                            keep(&mut working, update);
+                            kept.push(pd.clone());
+                        }
+                    }
+                    if !kept.is_empty() {
+                        pending.push(StateDelta::UpdatePort(*id, kept));
+                    }
+                }
+                StateDelta::AddSubsystem(nqn, sub) => {
+                    if !working
+                        .subsystems
+                        .get(nqn)
+                        .is_some_and(|current| current.get_unmet_deltas(sub).is_empty())
+                    {
+                        pending.push(keep(&mut working, delta.clone()));
+                    }
+                }
+                StateDelta::UpdateSubsystem(nqn, sub_deltas) => {
+                    if !working.subsystems.contains_key(nqn) {
+                        pending.push(keep(&mut working, delta.clone()));
+                        continue;
+                    }
+                    let mut kept = Vec::new();
+                    for sd in sub_deltas {
+                        if !working.subsystems[nqn].is_in_effect(sd) {
+                            let update = StateDelta::UpdateSubsystem(nqn.clone(), vec![sd.clone()]);
+                            keep(&mut working, update);
+                            kept.push(sd.clone());
+                        }
+                    }
+                    if !kept.is_empty() {
+                        pending.push(StateDelta::UpdateSubsystem(nqn.clone(), kept));
+                    }
+                }
+                StateDelta::RemovePort(_) | StateDelta::RemoveSubsystem(_) => {
+                    pending.push(keep(&mut working, delta.clone()));
+                }
+            }
+        }
+        pending
+    }
+
+    /// Namespaces, by subsystem NQN and nsid, whose UUID or NGUID `deltas` would change while
+    /// keeping their device. Initiators would take such a namespace for a new disk.
+    ///
+    /// Identifiers left unset in the updated namespace don't count, as they keep their value.
+    #[must_use]
+    pub fn get_identity_changes(&self, deltas: &[StateDelta]) -> Vec<(String, u32)> {
+        let mut changes = Vec::new();
+        for delta in deltas {
+            let StateDelta::UpdateSubsystem(nqn, sub_deltas) = delta else {
+                continue;
+            };
+            let Some(sub) = self.subsystems.get(nqn) else {
+                continue;
+            };
+            for sub_delta in sub_deltas {
+                let SubsystemDelta::UpdateNamespace(nsid, ns) = sub_delta else {
+                    continue;
+                };
+                let Some(current) = sub.namespaces.get(nsid) else {
+                    continue;
+                };
+                let changed = |new: Option<Uuid>, old: Option<Uuid>| new.is_some() && new != old;
+                if ns.device_path == current.device_path
+                    && (changed(ns.device_uuid, current.device_uuid)
+                        || changed(ns.device_nguid, current.device_nguid))
+                {
+                    changes.push((nqn.clone(), *nsid));
+                }
+            }
+        }
+        changes
+    }
+}
+impl State {
+    /// Apply changes to this in-memory state, failing like the kernel would.
+    ///
+    /// This allows checking a sequence of changes, each building on the previous ones,
+    /// before anything touches the system.
+    pub fn apply_deltas(&mut self, deltas: &[StateDelta]) -> Result<()> {
+        for delta in deltas {
+            self.apply_delta(delta)?;
+        }
+        Ok(())
+    }
+
+    fn apply_delta(&mut self, delta: &StateDelta) -> Result<()> {
+        match delta {
+            StateDelta::AddPort(id, port) => {
+                if self.ports.contains_key(id) {
+                    return Err(Error::ExistingPort(*id));
+                }
+                for nqn in &port.subsystems {
+                    self.check_subsystem(nqn)?;
+                }
+                self.ports.insert(*id, port.clone());
+            }
+            StateDelta::UpdatePort(id, port_deltas) => {
+                let mut port = self.ports.get(id).ok_or(Error::NoSuchPort(*id))?.clone();
+                let mut port_deltas = port_deltas.clone();
+                PortDelta::sort(&mut port_deltas);
+                for port_delta in &port_deltas {
+                    match port_delta {
+                        // The kernel then has the address family of the new address.
+                        PortDelta::UpdatePortType(pt) => {
+                            port.port_type = *pt;
+                            port.adrfam = None;
+                        }
+                        PortDelta::UpdateAddress(pt) => {
+                            if port.port_type.trtype() != pt.trtype() {
+                                return Err(Error::PortTransportChanged(
+                                    *id,
+                                    port.port_type.trtype().to_string(),
+                                    pt.trtype().to_string(),
+                                ));
+                            }
+                            port.port_type = *pt;
+                            port.adrfam = None;
+                        }
+                        PortDelta::AddSubsystem(nqn) => {
+                            self.check_subsystem(nqn)?;
+                            port.subsystems.insert(nqn.clone());
+                        }
+                        PortDelta::RemoveSubsystem(nqn) => {
+                            if !port.subsystems.remove(nqn) {
+                                return Err(Error::NoSuchSubsystem(nqn.clone()));
+                            }
+                        }
+                    }
+                }
+                self.ports.insert(*id, port);
+            }
+            StateDelta::RemovePort(id) => {
+                self.ports.remove(id).ok_or(Error::NoSuchPort(*id))?;
+            }
+            StateDelta::AddSubsystem(nqn, sub) => {
+                if self.subsystems.contains_key(nqn) {
+                    return Err(Error::ExistingSubsystem(nqn.clone()));
+                }
+                self.subsystems.insert(nqn.clone(), sub.clone());
+            }
+            StateDelta::UpdateSubsystem(nqn, sub_deltas) => {
+                let sub = self
+                    .subsystems
+                    .get_mut(nqn)
+                    .ok_or_else(|| Error::NoSuchSubsystem(nqn.clone()))?;
+                for sub_delta in sub_deltas {
+                    sub.apply_delta(nqn, sub_delta)?;
+                }
+            }
+            StateDelta::RemoveSubsystem(nqn) => {
+                if self.subsystems.remove(nqn).is_none() {
+                    return Err(Error::NoSuchSubsystem(nqn.clone()));
+                }
+                // Removing a subsystem unlinks it from all ports.
+                for port in self.ports.values_mut() {
+                    port.subsystems.remove(nqn);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_subsystem(&self, nqn: &str) -> Result<()> {
+        if self.subsystems.contains_key(nqn) {
+            Ok(())
+        } else {
+            Err(Error::NoSuchSubsystem(nqn.to_string()))
+        }
+    }
+}
+
+impl Subsystem {
+    fn apply_delta(&mut self, nqn: &str, delta: &SubsystemDelta) -> Result<()> {
+        let no_namespace = |nsid: &u32| Error::NoSuchNamespace(*nsid, nqn.to_string());
+        match delta {
+            SubsystemDelta::UpdateModel(model) => self.model = Some(model.clone()),
+            SubsystemDelta::UpdateSerial(serial) => self.serial = Some(serial.clone()),
+            SubsystemDelta::UpdateQidMax(qid_max) => self.qid_max = Some(*qid_max),
+            SubsystemDelta::AddHost(host) => {
+                self.allowed_hosts.insert(host.clone());
+            }
+            SubsystemDelta::RemoveHost(host) => {
+                if !self.allowed_hosts.remove(host) {
+                    return Err(Error::NoSuchHost(host.clone()));
+                }
+            }
+            SubsystemDelta::AddNamespace(nsid, ns) => {
+                if self.namespaces.contains_key(nsid) {
+                    return Err(Error::ExistingNamespace(*nsid, nqn.to_string()));
+                }
+                self.namespaces.insert(*nsid, ns.clone());
+            }
+            SubsystemDelta::UpdateNamespace(nsid, ns) => {
+                let current = self
+                    .namespaces
+                    .get_mut(nsid)
+                    .ok_or_else(|| no_namespace(nsid))?;
+                *current = ns.clone();
+            }
+            SubsystemDelta::RemoveNamespace(nsid) => {
+                self.namespaces
+                    .remove(nsid)
+                    .ok_or_else(|| no_namespace(nsid))?;
+            }
+            SubsystemDelta::SetNamespaceEnabled(nsid, enabled) => {
+                self.namespaces
+                    .get_mut(nsid)
+                    .ok_or_else(|| no_namespace(nsid))?
+                    .enabled = *enabled;
+            }
+        }
+        Ok(())
+    }
 }
-#[derive(Debug, Clone, PartialEq, Eq)]
+
+/// A change to a port.
+///
+/// Changes to a single port are applied in a fixed order: subsystem removals first, then the
+/// type or address change, then subsystem additions. Changing the type or address needs all
+/// subsystems detached and reattached, so this way only those staying are reattached.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PortDelta {
     UpdatePortType(PortType),
+    /// Change only the address, the transport type stays the same.
+    UpdateAddress(PortType),
 
     AddSubsystem(String),
     RemoveSubsystem(String),
 }
 
+impl PortDelta {
+    /// Bring changes to a single port into the order they have to be applied in.
+    pub fn sort(deltas: &mut [Self]) {
+        deltas.sort_by_key(|delta| match delta {
+            Self::RemoveSubsystem(_) => 0,
+            Self::UpdatePortType(_) | Self::UpdateAddress(_) => 1,
+            Self::AddSubsystem(_) => 2,
+        });
+    }
+}
+
+impl fmt::Display for PortDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UpdatePortType(port_type) => write!(f, "change type to {port_type}"),
+            Self::UpdateAddress(port_type) => write!(f, "change address to {port_type}"),
+            Self::AddSubsystem(nqn) => write!(f, "add subsystem {nqn}"),
+            Self::RemoveSubsystem(nqn) => write!(f, "remove subsystem {nqn}"),
+        }
+    }
+}
+
 impl Port {
+    /// Compute the changes from this port to `other`, in the order they have to be applied in.
     #[must_use]
     pub fn get_deltas(&self, other: &Self) -> Vec<PortDelta> {
         let mut deltas = Vec::new();
@@ -90,9 +486,13 @@ impl Port {
             deltas.push(PortDelta::RemoveSubsystem(removed_sub.clone()));
         }
 
-        // Updated Port Type.
-        if self.port_type != other.port_type {
-            deltas.push(PortDelta::UpdatePortType(other.port_type));
+        // Updated Port Type. Rewriting it also puts right an address family not matching it.
+        if self.port_type != other.port_type || self.adrfam() != other.adrfam() {
+            if self.port_type.trtype() == other.port_type.trtype() {
+                deltas.push(PortDelta::UpdateAddress(other.port_type));
+            } else {
+                deltas.push(PortDelta::UpdatePortType(other.port_type));
+            }
         }
 
         // Add subsystems not in self.
@@ -104,10 +504,12 @@ impl Port {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SubsystemDelta {
     UpdateModel(String),
     UpdateSerial(String),
+    UpdateQidMax(u32),
 
     AddHost(String),
     RemoveHost(String),
@@ -115,9 +517,42 @@ pub enum SubsystemDelta {
     AddNamespace(u32, Namespace),
     UpdateNamespace(u32, Namespace),
     RemoveNamespace(u32),
+    /// Only enable or disable a namespace, without rewriting anything else.
+    SetNamespaceEnabled(u32, bool),
+}
+
+impl fmt::Display for SubsystemDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let disabled = |ns: &Namespace| if ns.enabled { "" } else { " (disabled)" };
+        match self {
+            Self::UpdateModel(model) => write!(f, "set model to '{model}'"),
+            Self::UpdateSerial(serial) => write!(f, "set serial to '{serial}'"),
+            Self::UpdateQidMax(qid_max) => write!(f, "set maximum I/O queues to {qid_max}"),
+            Self::AddHost(host) => write!(f, "allow host {host}"),
+            Self::RemoveHost(host) => write!(f, "disallow host {host}"),
+            Self::AddNamespace(nsid, ns) => write!(
+                f,
+                "add namespace {nsid} on {}{}",
+                ns.device_path.display(),
+                disabled(ns)
+            ),
+            Self::UpdateNamespace(nsid, ns) => write!(
+                f,
+                "update namespace {nsid} on {}{}",
+                ns.device_path.display(),
+                disabled(ns)
+            ),
+            Self::RemoveNamespace(nsid) => write!(f, "remove namespace {nsid}"),
+            Self::SetNamespaceEnabled(nsid, true) => write!(f, "enable namespace {nsid}"),
+            Self::SetNamespaceEnabled(nsid, false) => write!(f, "disable namespace {nsid}"),
+        }
+    }
 }
 
 impl Subsystem {
+    /// Compute the changes needed to turn `self` into `other`.
+    ///
+    /// Namespace changes are ordered by ascending nsid, so applying them is deterministic.
     #[must_use]
     pub fn get_deltas(&self, other: &Self) -> Vec<SubsystemDelta> {
         let mut deltas = Vec::new();
@@ -138,6 +573,13 @@ impl Subsystem {
             }
         }
 
+        // Updated maximum number of I/O queues
+        if self.qid_max != other.qid_max {
+            if let Some(qid_max) = other.qid_max {
+                deltas.push(SubsystemDelta::UpdateQidMax(qid_max));
+            }
+        }
+
         // Add hosts not in self.
         for new_host in other.allowed_hosts.difference(&self.allowed_hosts) {
             deltas.push(SubsystemDelta::AddHost(new_host.clone()));
@@ -171,12 +613,146 @@ impl Subsystem {
 
         deltas
     }
+
+    /// Like `get_deltas`, but without namespace updates this subsystem already satisfies.
+    #[must_use]
+    pub fn get_unmet_deltas(&self, other: &Self) -> Vec<SubsystemDelta> {
+        self.get_deltas(other)
+            .into_iter()
+            .filter(|sd| match sd {
+                SubsystemDelta::UpdateNamespace(nsid, ns) => !self
+                    .namespaces
+                    .get(nsid)
+                    .is_some_and(|current| current.satisfies(ns)),
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Whether applying `delta` would leave this subsystem as it is.
+    ///
+    /// Removals never count, as they either change something or fail.
+    fn is_in_effect(&self, delta: &SubsystemDelta) -> bool {
+        match delta {
+            SubsystemDelta::UpdateModel(model) => self.model.as_ref() == Some(model),
+            SubsystemDelta::UpdateSerial(serial) => self.serial.as_ref() == Some(serial),
+            SubsystemDelta::UpdateQidMax(qid_max) => self.qid_max == Some(*qid_max),
+            SubsystemDelta::AddHost(host) => self.allowed_hosts.contains(host),
+            SubsystemDelta::AddNamespace(nsid, ns) | SubsystemDelta::UpdateNamespace(nsid, ns) => {
+                self.namespaces
+                    .get(nsid)
+                    .is_some_and(|current| current.satisfies(ns))
+            }
+            SubsystemDelta::SetNamespaceEnabled(nsid, enabled) => self
+                .namespaces
+                .get(nsid)
+                .is_some_and(|current| current.enabled == *enabled),
+            SubsystemDelta::RemoveHost(_) | SubsystemDelta::RemoveNamespace(_) => false,
+        }
+    }
+
+    /// Compute the changes giving this subsystem the allowed hosts of `src`.
+    ///
+    /// With `merge`, hosts not allowed on `src` are kept, unless `src` allows any host, which
+    /// this subsystem then does as well. New hosts are added before any are removed, so the
+    /// list never runs empty in between and allows any host.
+    #[must_use]
+    pub fn get_host_copy_deltas(&self, src: &Self, merge: bool) -> Vec<SubsystemDelta> {
+        let added = src
+            .allowed_hosts
+            .difference(&self.allowed_hosts)
+            .map(|host| SubsystemDelta::AddHost(host.clone()));
+        let removed = self
+            .allowed_hosts
+            .difference(&src.allowed_hosts)
+            .filter(|_| !merge || src.allowed_hosts.is_empty())
+            .map(|host| SubsystemDelta::RemoveHost(host.clone()));
+        added.chain(removed).collect()
+    }
+
+    /// Compute the changes setting the enabled flag of all namespaces to `enabled`.
+    ///
+    /// If `only` is set, only namespaces currently in that enabled state are considered.
+    #[must_use]
+    pub fn get_enable_deltas(&self, enabled: bool, only: Option<bool>) -> Vec<SubsystemDelta> {
+        self.namespaces
+            .iter()
+            .filter(|(_, ns)| only.map_or(true, |only| ns.enabled == only))
+            .filter(|(_, ns)| ns.enabled != enabled)
+            .map(|(nsid, _)| SubsystemDelta::SetNamespaceEnabled(*nsid, enabled))
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::BTreeSet;
+    use crate::state::{AddrFamily, PortAddrFamily};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_delta_display() -> Result<()> {
+        let sub = "nqn.2023-11.sh.tty:sub";
+        let mut port = Port::new(
+            PortType::Tcp("192.0.2.1:4420".parse()?),
+            BTreeSet::from([sub.to_string()]),
+        );
+        assert_eq!(
+            StateDelta::AddPort(1, port.clone()).to_string(),
+            "add port 1: tcp://192.0.2.1:4420 with subsystems nqn.2023-11.sh.tty:sub"
+        );
+        port.subsystems.clear();
+        assert_eq!(
+            StateDelta::AddPort(2, port).to_string(),
+            "add port 2: tcp://192.0.2.1:4420"
+        );
+        assert_eq!(
+            StateDelta::UpdatePort(
+                1,
+                vec![
+                    PortDelta::RemoveSubsystem(sub.to_string()),
+                    PortDelta::UpdateAddress(PortType::Tcp("192.0.2.2:4420".parse()?)),
+                ]
+            )
+            .to_string(),
+            "update port 1: remove subsystem nqn.2023-11.sh.tty:sub, change address to tcp://192.0.2.2:4420"
+        );
+        assert_eq!(StateDelta::RemovePort(1).to_string(), "remove port 1");
+
+        let mut subsystem = Subsystem {
+            model: Some("Linux".to_string()),
+            ..Default::default()
+        };
+        let mut ns = Namespace::builder("/dev/loop0").build()?;
+        ns.enabled = false;
+        subsystem.namespaces.insert(1, ns.clone());
+        assert_eq!(
+            StateDelta::AddSubsystem(sub.to_string(), subsystem).to_string(),
+            "add subsystem nqn.2023-11.sh.tty:sub: set model to 'Linux', add namespace 1 on /dev/loop0 (disabled)"
+        );
+        assert_eq!(
+            StateDelta::AddSubsystem(sub.to_string(), Subsystem::default()).to_string(),
+            "add subsystem nqn.2023-11.sh.tty:sub"
+        );
+        ns.enabled = true;
+        assert_eq!(
+            StateDelta::UpdateSubsystem(
+                sub.to_string(),
+                vec![
+                    SubsystemDelta::AddHost("nqn.2023-11.sh.tty:host".to_string()),
+                    SubsystemDelta::UpdateNamespace(1, ns),
+                    SubsystemDelta::SetNamespaceEnabled(2, false),
+                ]
+            )
+            .to_string(),
+            "update subsystem nqn.2023-11.sh.tty:sub: allow host nqn.2023-11.sh.tty:host, update namespace 1 on /dev/loop0, disable namespace 2"
+        );
+        assert_eq!(
+            StateDelta::RemoveSubsystem(sub.to_string()).to_string(),
+            "remove subsystem nqn.2023-11.sh.tty:sub"
+        );
+        Ok(())
+    }
 
     #[test]
     fn test_state_get_deltas_port() {
@@ -383,4 +959,342 @@ mod tests {
         deltas = base_state.get_deltas(&new_state);
         assert_eq!(deltas.len(), 0);
     }
+
+    #[test]
+    fn test_subsystem_get_enable_deltas() {
+        let ns = |enabled| Namespace {
+            enabled,
+            device_path: "/dev/loop0".into(),
+            device_spec: None,
+            device_uuid: None,
+            device_nguid: None,
+        };
+        let sub = Subsystem {
+            namespaces: BTreeMap::from([(1, ns(true)), (2, ns(false)), (3, ns(true))]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            sub.get_enable_deltas(false, None),
+            vec![
+                SubsystemDelta::SetNamespaceEnabled(1, false),
+                SubsystemDelta::SetNamespaceEnabled(3, false),
+            ]
+        );
+        assert_eq!(
+            sub.get_enable_deltas(true, None),
+            vec![SubsystemDelta::SetNamespaceEnabled(2, true)]
+        );
+        assert!(sub.get_enable_deltas(true, Some(true)).is_empty());
+        assert!(sub.get_enable_deltas(false, Some(false)).is_empty());
+    }
+
+    #[test]
+    fn test_subsystem_get_host_copy_deltas() {
+        let hosts = |hosts: &[&str]| Subsystem {
+            allowed_hosts: hosts.iter().map(ToString::to_string).collect(),
+            ..Default::default()
+        };
+        let dst = hosts(&["host-a", "host-b"]);
+        let src = hosts(&["host-b", "host-c"]);
+
+        assert_eq!(
+            dst.get_host_copy_deltas(&src, false),
+            vec![
+                SubsystemDelta::AddHost("host-c".to_string()),
+                SubsystemDelta::RemoveHost("host-a".to_string()),
+            ]
+        );
+        assert_eq!(
+            dst.get_host_copy_deltas(&src, true),
+            vec![SubsystemDelta::AddHost("host-c".to_string())]
+        );
+        assert!(dst.get_host_copy_deltas(&dst, false).is_empty());
+
+        // Copying from a subsystem allowing any host opens up the other one, even when merging.
+        for merge in [false, true] {
+            assert_eq!(
+                dst.get_host_copy_deltas(&hosts(&[]), merge),
+                vec![
+                    SubsystemDelta::RemoveHost("host-a".to_string()),
+                    SubsystemDelta::RemoveHost("host-b".to_string()),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_state_get_deltas_alias_only() {
+        let mut base = State::default();
+        base.subsystems
+            .insert("nqn.subsystem".to_string(), Subsystem::default());
+        let mut new = base.clone();
+        new.subsystems.get_mut("nqn.subsystem").unwrap().alias = Some("test".to_string());
+
+        // The kernel doesn't know about aliases, so there is nothing to change.
+        assert!(base.get_deltas(&new).is_empty());
+    }
+
+    #[test]
+    fn test_state_get_deltas_removed_subsystem_on_port() {
+        let mut base = State::default();
+        base.subsystems
+            .insert("nqn.subsystem".to_string(), Subsystem::default());
+        base.ports.insert(
+            1,
+            Port::new(
+                PortType::Loop,
+                BTreeSet::from(["nqn.subsystem".to_string()]),
+            ),
+        );
+        let mut new = base.clone();
+        new.clear_subsystems();
+
+        // The port is left alone, as removing the subsystem unlinks it anyway.
+        assert_eq!(
+            base.get_deltas(&new),
+            vec![StateDelta::RemoveSubsystem("nqn.subsystem".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_port_get_deltas_address() {
+        let tcp = |addr: &str| PortType::Tcp(addr.parse().unwrap());
+        let base = Port::new(tcp("192.0.2.1:4420"), BTreeSet::new());
+
+        // Same transport, different address.
+        let new = Port::new(tcp("192.0.2.2:4421"), BTreeSet::new());
+        assert_eq!(
+            base.get_deltas(&new),
+            vec![PortDelta::UpdateAddress(tcp("192.0.2.2:4421"))]
+        );
+
+        // Different transport, same address.
+        let rdma = PortType::Rdma("192.0.2.1:4420".parse().unwrap());
+        let new = Port::new(rdma, BTreeSet::new());
+        assert_eq!(base.get_deltas(&new), vec![PortDelta::UpdatePortType(rdma)]);
+    }
+
+    #[test]
+    fn test_port_get_deltas_adrfam() -> Result<()> {
+        let v6 = PortType::Tcp("[fdff::1]:4420".parse().unwrap());
+        let desired = Port::new(v6, BTreeSet::new());
+
+        // As gathered, with the family matching the address, there is nothing to do.
+        let mut gathered = desired.clone();
+        gathered.adrfam = Some(PortAddrFamily::Ip(AddrFamily::Ipv6));
+        assert!(gathered.get_deltas(&desired).is_empty());
+
+        // A family not matching the address is put right by writing the address again.
+        gathered.adrfam = Some(PortAddrFamily::Ip(AddrFamily::Ipv4));
+        assert_eq!(
+            gathered.get_deltas(&desired),
+            vec![PortDelta::UpdateAddress(v6)]
+        );
+
+        let mut state = State::default();
+        state.ports.insert(1, gathered);
+        let mut target = State::default();
+        target.ports.insert(1, desired);
+        let deltas = state.get_deltas(&target);
+        assert_eq!(state.get_unmet_deltas(&target), deltas);
+        state.apply_deltas(&deltas)?;
+        assert_eq!(
+            state.ports[&1].adrfam(),
+            Some(PortAddrFamily::Ip(AddrFamily::Ipv6))
+        );
+        assert!(state.get_unmet_deltas(&target).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_apply_deltas() -> Result<()> {
+        let mut base = State::default();
+        let mut new = State::default();
+        new.subsystems.insert(
+            "nqn.subsystem".to_string(),
+            Subsystem {
+                allowed_hosts: BTreeSet::from(["nqn.initiator".to_string()]),
+                ..Default::default()
+            },
+        );
+        new.ports.insert(
+            1,
+            Port::new(
+                PortType::Loop,
+                BTreeSet::from(["nqn.subsystem".to_string()]),
+            ),
+        );
+
+        // Applying the differences of two states results in the second one.
+        let deltas = base.get_deltas(&new);
+        base.apply_deltas(&deltas)?;
+        assert_eq!(base, new);
+
+        // Later changes see the earlier ones.
+        let mut state = State::default();
+        state.apply_deltas(&[
+            StateDelta::AddSubsystem("nqn.subsystem".to_string(), Subsystem::default()),
+            StateDelta::AddPort(1, Port::new(PortType::Loop, BTreeSet::new())),
+            StateDelta::UpdatePort(
+                1,
+                vec![PortDelta::AddSubsystem("nqn.subsystem".to_string())],
+            ),
+            StateDelta::RemoveSubsystem("nqn.subsystem".to_string()),
+        ])?;
+        assert!(state.ports[&1].subsystems.is_empty());
+
+        // Changes the kernel would reject.
+        for delta in [
+            StateDelta::AddPort(1, Port::new(PortType::Loop, BTreeSet::new())),
+            StateDelta::RemovePort(2),
+            StateDelta::RemoveSubsystem("nqn.missing".to_string()),
+            StateDelta::UpdatePort(1, vec![PortDelta::AddSubsystem("nqn.missing".to_string())]),
+            StateDelta::UpdateSubsystem(
+                "nqn.missing".to_string(),
+                vec![SubsystemDelta::UpdateModel("Loop".to_string())],
+            ),
+        ] {
+            assert!(state.clone().apply_deltas(&[delta]).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_port_get_deltas_combined_change() {
+        let v4 = PortType::Tcp("192.0.2.1:4420".parse().unwrap());
+        let v6 = PortType::Tcp("[2001:db8::1]:4420".parse().unwrap());
+        let base = Port::new(
+            v4,
+            BTreeSet::from(["nqn.a".to_string(), "nqn.b".to_string()]),
+        );
+        let new = Port::new(
+            v6,
+            BTreeSet::from(["nqn.b".to_string(), "nqn.c".to_string()]),
+        );
+        let expected = vec![
+            PortDelta::RemoveSubsystem("nqn.a".to_string()),
+            PortDelta::UpdateAddress(v6),
+            PortDelta::AddSubsystem("nqn.c".to_string()),
+        ];
+        assert_eq!(base.get_deltas(&new), expected);
+
+        let mut reversed: Vec<PortDelta> = expected.iter().rev().cloned().collect();
+        PortDelta::sort(&mut reversed);
+        assert_eq!(reversed, expected);
+    }
+
+    #[test]
+    fn test_state_get_pending_deltas() {
+        let ns = Namespace {
+            enabled: true,
+            device_path: "/dev/loop0".into(),
+            device_spec: None,
+            device_uuid: Some(Uuid::from_u128(1)),
+            device_nguid: None,
+        };
+        let mut state = State::default();
+        state.subsystems.insert(
+            "nqn.subsystem".to_string(),
+            Subsystem {
+                model: Some("Loop".to_string()),
+                namespaces: BTreeMap::from([(1, ns.clone())]),
+                ..Default::default()
+            },
+        );
+        state.ports.insert(
+            1,
+            Port::new(
+                PortType::Loop,
+                BTreeSet::from(["nqn.subsystem".to_string()]),
+            ),
+        );
+        let add_namespace = |ns: Namespace| {
+            StateDelta::UpdateSubsystem(
+                "nqn.subsystem".to_string(),
+                vec![SubsystemDelta::AddNamespace(1, ns)],
+            )
+        };
+
+        // Re-adding an identical namespace changes nothing, even without giving its UUID.
+        assert!(state
+            .get_pending_deltas(&[add_namespace(ns.clone())])
+            .is_empty());
+        let unset = Namespace {
+            device_uuid: None,
+            ..ns.clone()
+        };
+        assert!(state.get_pending_deltas(&[add_namespace(unset)]).is_empty());
+        let redundant = [
+            StateDelta::AddPort(1, state.ports[&1].clone()),
+            StateDelta::UpdatePort(
+                1,
+                vec![
+                    PortDelta::UpdatePortType(PortType::Loop),
+                    PortDelta::AddSubsystem("nqn.subsystem".to_string()),
+                ],
+            ),
+            StateDelta::AddSubsystem(
+                "nqn.subsystem".to_string(),
+                state.subsystems["nqn.subsystem"].clone(),
+            ),
+            StateDelta::UpdateSubsystem(
+                "nqn.subsystem".to_string(),
+                vec![
+                    SubsystemDelta::UpdateModel("Loop".to_string()),
+                    SubsystemDelta::SetNamespaceEnabled(1, true),
+                ],
+            ),
+        ];
+        assert!(state.get_pending_deltas(&redundant).is_empty());
+
+        // Only what differs is kept.
+        let other = Namespace {
+            device_path: "/dev/loop1".into(),
+            ..ns
+        };
+        assert_eq!(
+            state.get_pending_deltas(&[
+                add_namespace(other.clone()),
+                StateDelta::UpdateSubsystem(
+                    "nqn.subsystem".to_string(),
+                    vec![
+                        SubsystemDelta::UpdateModel("Loop".to_string()),
+                        SubsystemDelta::UpdateSerial("1234".to_string()),
+                    ],
+                ),
+            ]),
+            [
+                add_namespace(other),
+                StateDelta::UpdateSubsystem(
+                    "nqn.subsystem".to_string(),
+                    vec![SubsystemDelta::UpdateSerial("1234".to_string())],
+                ),
+            ]
+        );
+
+        // Removals are kept, even of missing objects, so they still fail.
+        let removals = [
+            StateDelta::RemovePort(2),
+            StateDelta::RemoveSubsystem("nqn.missing".to_string()),
+        ];
+        assert_eq!(state.get_pending_deltas(&removals), removals);
+
+        // Re-adding what was removed before is no longer in effect.
+        let readd = [
+            StateDelta::RemoveSubsystem("nqn.subsystem".to_string()),
+            StateDelta::AddSubsystem(
+                "nqn.subsystem".to_string(),
+                state.subsystems["nqn.subsystem"].clone(),
+            ),
+            StateDelta::UpdatePort(
+                1,
+                vec![
+                    PortDelta::RemoveSubsystem("nqn.subsystem".to_string()),
+                    PortDelta::AddSubsystem("nqn.subsystem".to_string()),
+                ],
+            ),
+        ];
+        assert_eq!(state.get_pending_deltas(&readd), readd);
+    }
 }
@@ -0,0 +1,252 @@
+// Filesystems the backing devices of namespaces were given by, kept next to the kernel state.
+// The kernel only gets the device path, so like labels the UUID=/LABEL= specs live in a file of
+// their own, to be put back when saving or showing the state.
+
+use super::delta::{StateDelta, SubsystemDelta};
+use super::types::State;
+use crate::errors::{Context, Result};
+use crate::helpers::{write_file_atomic, DeviceSpec};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Default location of the device spec file.
+pub const DEVICE_SPEC_FILE: &str = "/var/lib/nvmetcfg/device-specs.yaml";
+
+/// Device specs of namespaces, keyed by subsystem NQN and namespace ID.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceSpecs {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub subsystems: BTreeMap<String, BTreeMap<u32, DeviceSpec>>,
+}
+
+impl DeviceSpecs {
+    /// Load the device specs from `path`. A missing file means there are none.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let f = File::open(path)
+            .with_context(|| format!("Failed to open device spec file {}", path.display()))?;
+        let specs = serde_yaml::from_reader(f)
+            .with_context(|| format!("Failed to read device spec file {}", path.display()))?;
+        Ok(specs)
+    }
+
+    /// Save the device specs to `path`, creating its parent directory if needed.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+        let yaml = serde_yaml::to_string(self).context("Failed to serialize device specs")?;
+        write_file_atomic(path, 0o644, yaml.as_bytes())
+            .with_context(|| format!("Failed to write device spec file {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Take over the device specs of the namespaces in a state, before resolving it.
+    ///
+    /// These take precedence: namespaces given by path in the state lose any spec they had.
+    pub fn extend_from_state(&mut self, state: &State) {
+        for (nqn, sub) in &state.subsystems {
+            for (nsid, ns) in &sub.namespaces {
+                self.set(nqn, *nsid, ns.device_spec.clone());
+            }
+        }
+    }
+
+    /// Record the device specs of namespaces added or updated by `deltas`, and drop those of
+    /// namespaces given by path or removed.
+    ///
+    /// Returns whether any specs changed.
+    pub fn record(&mut self, deltas: &[StateDelta]) -> bool {
+        let before = self.clone();
+        for delta in deltas {
+            match delta {
+                StateDelta::RemoveSubsystem(nqn) => {
+                    self.subsystems.remove(nqn);
+                }
+                StateDelta::AddSubsystem(nqn, sub) => {
+                    for (nsid, ns) in &sub.namespaces {
+                        self.set(nqn, *nsid, ns.device_spec.clone());
+                    }
+                }
+                StateDelta::UpdateSubsystem(nqn, sub_deltas) => {
+                    for sub_delta in sub_deltas {
+                        match sub_delta {
+                            SubsystemDelta::AddNamespace(nsid, ns)
+                            | SubsystemDelta::UpdateNamespace(nsid, ns) => {
+                                self.set(nqn, *nsid, ns.device_spec.clone());
+                            }
+                            SubsystemDelta::RemoveNamespace(nsid) => self.set(nqn, *nsid, None),
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        *self != before
+    }
+
+    /// Put back the device specs of the namespaces in `state`, like a gathered one.
+    ///
+    /// Specs no longer resolving to the device of their namespace, like after it was changed
+    /// by other means, are left out.
+    pub fn apply_to_state(&self, state: &mut State) {
+        self.apply_to_state_with(state, DeviceSpec::resolve);
+    }
+
+    fn apply_to_state_with(
+        &self,
+        state: &mut State,
+        resolve: impl Fn(&DeviceSpec) -> Result<PathBuf>,
+    ) {
+        let mut resolved = BTreeMap::new();
+        for (nqn, specs) in &self.subsystems {
+            let Some(sub) = state.subsystems.get_mut(nqn) else {
+                continue;
+            };
+            for (nsid, spec) in specs {
+                let Some(ns) = sub.namespaces.get_mut(nsid) else {
+                    continue;
+                };
+                let path = resolved.entry(spec).or_insert_with(|| resolve(spec).ok());
+                if path.as_ref() == Some(&ns.device_path) {
+                    ns.device_spec = Some(spec.clone());
+                } else {
+                    tracing::debug!(
+                        "{spec} no longer is the device of namespace {nsid} of {nqn}, leaving it out"
+                    );
+                }
+            }
+        }
+    }
+
+    fn set(&mut self, nqn: &str, nsid: u32, spec: Option<DeviceSpec>) {
+        match spec {
+            Some(spec) => {
+                self.subsystems
+                    .entry(nqn.to_string())
+                    .or_default()
+                    .insert(nsid, spec);
+            }
+            None => {
+                if let Some(specs) = self.subsystems.get_mut(nqn) {
+                    specs.remove(&nsid);
+                    if specs.is_empty() {
+                        self.subsystems.remove(nqn);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Error;
+    use crate::helpers::TestDir;
+    use crate::state::{Namespace, Subsystem};
+
+    const SUB: &str = "nqn.2023-11.sh.tty:sub";
+
+    fn label(label: &str) -> DeviceSpec {
+        DeviceSpec::Label(label.to_string())
+    }
+
+    fn resolve(spec: &DeviceSpec) -> Result<PathBuf> {
+        match spec {
+            DeviceSpec::Label(label) if label == "tenant-a" => Ok("/dev/sdb1".into()),
+            _ => Err(Error::NoSuchDevice(spec.to_string())),
+        }
+    }
+
+    #[test]
+    fn test_state_roundtrip() -> Result<()> {
+        let mut sub = Subsystem::default();
+        let mut ns = Namespace::builder("/dev/sdb1").build()?;
+        ns.device_spec = Some(label("tenant-a"));
+        sub.namespaces.insert(1, ns.clone());
+        ns.device_spec = Some(label("gone"));
+        sub.namespaces.insert(2, ns);
+        sub.namespaces
+            .insert(3, Namespace::builder("/dev/loop0").build()?);
+        let mut state = State::default();
+        state.subsystems.insert(SUB.to_string(), sub);
+
+        let mut specs = DeviceSpecs::default();
+        specs.extend_from_state(&state);
+        assert_eq!(
+            specs.subsystems[SUB],
+            BTreeMap::from([(1, label("tenant-a")), (2, label("gone"))])
+        );
+        let dir = TestDir::new("devspecs");
+        let path = dir.join("device-specs.yaml");
+        assert_eq!(DeviceSpecs::load(&path)?, DeviceSpecs::default());
+        specs.save(&path)?;
+        let loaded = DeviceSpecs::load(&path)?;
+        assert_eq!(loaded, specs);
+
+        // Only specs still resolving to the device of their namespace are put back.
+        let mut gathered = state.clone();
+        for ns in gathered
+            .subsystems
+            .get_mut(SUB)
+            .unwrap()
+            .namespaces
+            .values_mut()
+        {
+            ns.device_spec = None;
+        }
+        loaded.apply_to_state_with(&mut gathered, resolve);
+        let namespaces = &gathered.subsystems[SUB].namespaces;
+        assert_eq!(namespaces[&1].device_spec, Some(label("tenant-a")));
+        assert_eq!(namespaces[&2].device_spec, None);
+        assert_eq!(namespaces[&3].device_spec, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record() -> Result<()> {
+        let mut ns = Namespace::builder("/dev/sdb1").build()?;
+        ns.device_spec = Some(label("tenant-a"));
+        let add = |nsid, ns: &Namespace| {
+            StateDelta::UpdateSubsystem(
+                SUB.to_string(),
+                vec![SubsystemDelta::AddNamespace(nsid, ns.clone())],
+            )
+        };
+
+        let mut specs = DeviceSpecs::default();
+        assert!(specs.record(&[add(1, &ns), add(2, &ns)]));
+        assert!(!specs.record(&[add(1, &ns)]));
+        assert_eq!(specs.subsystems[SUB].len(), 2);
+
+        // Given by path now.
+        ns.device_spec = None;
+        assert!(specs.record(&[StateDelta::UpdateSubsystem(
+            SUB.to_string(),
+            vec![SubsystemDelta::UpdateNamespace(1, ns)],
+        )]));
+        assert!(specs.record(&[StateDelta::UpdateSubsystem(
+            SUB.to_string(),
+            vec![SubsystemDelta::RemoveNamespace(2)],
+        )]));
+        assert!(specs.subsystems.is_empty());
+
+        let mut sub = Subsystem::default();
+        let mut ns = Namespace::builder("/dev/sdb1").build()?;
+        ns.device_spec = Some(label("tenant-a"));
+        sub.namespaces.insert(1, ns);
+        assert!(specs.record(&[StateDelta::AddSubsystem(SUB.to_string(), sub)]));
+        assert!(specs.record(&[StateDelta::RemoveSubsystem(SUB.to_string())]));
+        assert!(specs.subsystems.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+// Reporting that the configuration in the kernel no longer matches a state file.
+// The report is JSON, for monitoring to pick up from a command or webhook, and carries the
+// changes restoring the file would make, serialized as in the audit log.
+
+use super::delta::StateDelta;
+use crate::errors::{Context, Error, Result};
+use crate::helpers::{NotifyTarget, RateLimit};
+use serde::Serialize;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Numbers of changes by object and what they do, see `DriftReport`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct DriftCounts {
+    pub total: usize,
+    pub ports_added: usize,
+    pub ports_updated: usize,
+    pub ports_removed: usize,
+    pub subsystems_added: usize,
+    pub subsystems_updated: usize,
+    pub subsystems_removed: usize,
+}
+
+/// How the configuration in the kernel differs from a state file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DriftReport {
+    /// When the drift was found, in RFC 3339 format.
+    pub timestamp: String,
+    pub hostname: String,
+    /// The state file compared with.
+    pub source: String,
+    /// A line per change, like `add subsystem nqn.2023-11.sh.tty:sub`.
+    pub summary: Vec<String>,
+    pub counts: DriftCounts,
+    /// The changes restoring the state file would make.
+    pub changes: Vec<StateDelta>,
+}
+
+impl DriftReport {
+    /// A report of drift from the state file `source` found now, `changes` being what
+    /// restoring it would do.
+    #[must_use]
+    pub fn new(source: &str, changes: Vec<StateDelta>) -> Self {
+        let mut counts = DriftCounts {
+            total: changes.len(),
+            ..Default::default()
+        };
+        let mut summary = Vec::with_capacity(changes.len());
+        for change in &changes {
+            let (count, line) = match change {
+                StateDelta::AddPort(id, _) => (&mut counts.ports_added, format!("add port {id}")),
+                StateDelta::UpdatePort(id, _) => {
+                    (&mut counts.ports_updated, format!("update port {id}"))
+                }
+                StateDelta::RemovePort(id) => {
+                    (&mut counts.ports_removed, format!("remove port {id}"))
+                }
+                StateDelta::AddSubsystem(nqn, _) => {
+                    (&mut counts.subsystems_added, format!("add subsystem {nqn}"))
+                }
+                StateDelta::UpdateSubsystem(nqn, _) => (
+                    &mut counts.subsystems_updated,
+                    format!("update subsystem {nqn}"),
+                ),
+                StateDelta::RemoveSubsystem(nqn) => (
+                    &mut counts.subsystems_removed,
+                    format!("remove subsystem {nqn}"),
+                ),
+            };
+            *count += 1;
+            summary.push(line);
+        }
+        Self {
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            hostname: hostname(),
+            source: source.to_string(),
+            summary,
+            counts,
+            changes,
+        }
+    }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Sends drift reports to a set of targets, skipping repeats and keeping to a rate limit.
+#[derive(Debug, Clone)]
+pub struct DriftNotifier {
+    targets: Vec<NotifyTarget>,
+    limit: RateLimit,
+    attempts: u32,
+    delay: Duration,
+    // The changes of the last report sent, until the drift is gone.
+    sent: Option<Vec<StateDelta>>,
+}
+
+impl DriftNotifier {
+    /// Send reports to `targets`, at most one per `interval`.
+    ///
+    /// Delivery is tried three times per target, waiting a second and then two in between.
+    #[must_use]
+    pub const fn new(targets: Vec<NotifyTarget>, interval: Duration) -> Self {
+        Self {
+            targets,
+            limit: RateLimit::new(interval),
+            attempts: 3,
+            delay: Duration::from_secs(1),
+            sent: None,
+        }
+    }
+
+    /// Change how often and how long apart delivery is tried.
+    #[must_use]
+    pub const fn with_retry(mut self, attempts: u32, delay: Duration) -> Self {
+        self.attempts = attempts;
+        self.delay = delay;
+        self
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Note that there is no drift anymore, so the next one is reported even if it has the same
+    /// changes as the last.
+    pub fn clear(&mut self) {
+        self.sent = None;
+    }
+
+    /// Send `report` to every target at `now`, returning whether it was sent.
+    ///
+    /// Reports with the same changes as the last one sent, and those coming too soon after it,
+    /// are left out. A report which couldn't be delivered to some target still counts as sent,
+    /// the first failure is returned after trying all targets.
+    pub fn notify(&mut self, report: &DriftReport, now: Instant) -> Result<bool> {
+        if self.targets.is_empty() || self.sent.as_ref() == Some(&report.changes) {
+            return Ok(false);
+        }
+        if !self.limit.allow(now) {
+            return Ok(false);
+        }
+        self.sent = Some(report.changes.clone());
+        let payload = serde_json::to_vec(report).context("Failed to serialize drift report")?;
+        let mut failure: Option<Error> = None;
+        for target in &self.targets {
+            if let Err(err) = target
+                .deliver_with_retry(&payload, self.attempts, self.delay)
+                .with_context(|| format!("Failed to notify {target}"))
+            {
+                failure.get_or_insert(err);
+            }
+        }
+        failure.map_or(Ok(true), Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use crate::state::{Port, PortDelta, PortType, SubsystemDelta};
+    use std::collections::BTreeSet;
+
+    const SUB: &str = "nqn.2023-11.sh.tty:drift";
+
+    fn changes() -> Vec<StateDelta> {
+        vec![
+            StateDelta::RemovePort(2),
+            StateDelta::UpdateSubsystem(
+                SUB.to_string(),
+                vec![SubsystemDelta::AddHost(
+                    "nqn.2023-11.sh.tty:host".to_string(),
+                )],
+            ),
+            StateDelta::AddPort(1, Port::new(PortType::Loop, BTreeSet::new())),
+            StateDelta::UpdatePort(3, vec![PortDelta::AddSubsystem(SUB.to_string())]),
+        ]
+    }
+
+    fn report(changes: Vec<StateDelta>) -> DriftReport {
+        let mut report = DriftReport::new("/etc/nvmetcfg/state.yaml", changes);
+        report.timestamp = "2024-05-01T12:00:00Z".to_string();
+        report.hostname = "target1".to_string();
+        report
+    }
+
+    // Records the payloads it gets to `out` in its directory, one per line, failing if `fail`
+    // exists there.
+    fn recorder(name: &str) -> (NotifyTarget, TestDir) {
+        let dir = TestDir::new(&format!("drift-{name}"));
+        let (out, fail) = (dir.join("out"), dir.join("fail"));
+        let command = format!(
+            "cat >> {0}; echo >> {0}; test ! -e {1}",
+            out.display(),
+            fail.display()
+        );
+        (NotifyTarget::Command(command), dir)
+    }
+
+    #[test]
+    fn test_drift_report_json() -> Result<()> {
+        let report = report(changes());
+        assert!(humantime::parse_rfc3339(&DriftReport::new("-", Vec::new()).timestamp).is_ok());
+        assert_eq!(
+            serde_json::to_string_pretty(&report)?,
+            r#"{
+  "timestamp": "2024-05-01T12:00:00Z",
+  "hostname": "target1",
+  "source": "/etc/nvmetcfg/state.yaml",
+  "summary": [
+    "remove port 2",
+    "update subsystem nqn.2023-11.sh.tty:drift",
+    "add port 1",
+    "update port 3"
+  ],
+  "counts": {
+    "total": 4,
+    "ports_added": 1,
+    "ports_updated": 1,
+    "ports_removed": 1,
+    "subsystems_added": 0,
+    "subsystems_updated": 1,
+    "subsystems_removed": 0
+  },
+  "changes": [
+    {
+      "remove_port": 2
+    },
+    {
+      "update_subsystem": [
+        "nqn.2023-11.sh.tty:drift",
+        [
+          {
+            "add_host": "nqn.2023-11.sh.tty:host"
+          }
+        ]
+      ]
+    },
+    {
+      "add_port": [
+        1,
+        {
+          "port_type": "Loop",
+          "subsystems": []
+        }
+      ]
+    },
+    {
+      "update_port": [
+        3,
+        [
+          {
+            "add_subsystem": "nqn.2023-11.sh.tty:drift"
+          }
+        ]
+      ]
+    }
+  ]
+}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_drift_notifier() -> Result<()> {
+        let (target, dir) = recorder("notify");
+        let mut notifier =
+            DriftNotifier::new(vec![target], Duration::from_secs(60)).with_retry(1, Duration::ZERO);
+        let start = Instant::now();
+        let first = report(changes());
+        assert!(notifier.notify(&first, start)?);
+        // The same drift again is no news.
+        assert!(!notifier.notify(&first, start + Duration::from_secs(120))?);
+        // Other drift waits for the rate limit.
+        let second = report(changes()[..1].to_vec());
+        assert!(!notifier.notify(&second, start + Duration::from_secs(30))?);
+        assert!(notifier.notify(&second, start + Duration::from_secs(60))?);
+        // Back in sync, then the first drift again.
+        notifier.clear();
+        assert!(notifier.notify(&first, start + Duration::from_secs(180))?);
+
+        let sent: Vec<serde_json::Value> = std::fs::read_to_string(dir.join("out"))?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0]["counts"]["total"], 4);
+        assert_eq!(sent[1]["summary"][0], "remove port 2");
+        assert_eq!(sent[2], sent[0]);
+
+        // Failing delivery is returned, but the report counts as sent.
+        std::fs::write(dir.join("fail"), "")?;
+        notifier.clear();
+        let err = notifier
+            .notify(&second, start + Duration::from_secs(240))
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("Failed to notify command"),
+            "{err}"
+        );
+        assert!(!notifier.notify(&second, start + Duration::from_secs(300))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drift_notifier_without_targets() -> Result<()> {
+        let mut notifier = DriftNotifier::new(Vec::new(), Duration::ZERO);
+        assert!(notifier.is_empty());
+        assert!(!notifier.notify(&report(changes()), Instant::now())?);
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+// Comparison helpers that ignore fields the kernel fills in on its own.
+
+use super::delta::{StateDelta, SubsystemDelta};
+use super::types::{Namespace, State};
+use crate::errors::Error;
+use std::str::FromStr;
+
+/// Fields to be disregarded when comparing states.
+///
+/// Things like the namespace UUID/NGUID or the subsystem serial get generated
+/// by the kernel if they are not set explicitly, so comparing a saved state
+/// against a live system flags them even though nobody configured them.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct IgnoreFields {
+    pub uuid: bool,
+    pub nguid: bool,
+    pub serial: bool,
+    pub model: bool,
+    pub enabled: bool,
+}
+
+impl IgnoreFields {
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        !(self.uuid || self.nguid || self.serial || self.model || self.enabled)
+    }
+
+    #[must_use]
+    pub fn namespace_equivalent(&self, a: &Namespace, b: &Namespace) -> bool {
+        a.device_path == b.device_path
+            && (self.enabled || a.enabled == b.enabled)
+            && (self.uuid || a.device_uuid == b.device_uuid)
+            && (self.nguid || a.device_nguid == b.device_nguid)
+    }
+
+    /// Drop deltas whose only effect would be changing ignored fields.
+    ///
+    /// `base` has to be the state the deltas were computed against.
+    #[must_use]
+    pub fn filter_deltas(&self, base: &State, deltas: Vec<StateDelta>) -> Vec<StateDelta> {
+        if self.is_empty() {
+            return deltas;
+        }
+
+        let mut filtered = Vec::with_capacity(deltas.len());
+        for delta in deltas {
+            match delta {
+                StateDelta::UpdateSubsystem(nqn, sub_deltas) => {
+                    let base_sub = base.subsystems.get(&nqn);
+                    let sub_deltas: Vec<SubsystemDelta> = sub_deltas
+                        .into_iter()
+                        .filter(|sd| match sd {
+                            SubsystemDelta::UpdateModel(_) => !self.model,
+                            SubsystemDelta::UpdateSerial(_) => !self.serial,
+                            SubsystemDelta::UpdateNamespace(nsid, ns) => !base_sub
+                                .and_then(|sub| sub.namespaces.get(nsid))
+                                .is_some_and(|base_ns| self.namespace_equivalent(base_ns, ns)),
+                            SubsystemDelta::SetNamespaceEnabled(_, _) => !self.enabled,
+                            _ => true,
+                        })
+                        .collect();
+                    if !sub_deltas.is_empty() {
+                        filtered.push(StateDelta::UpdateSubsystem(nqn, sub_deltas));
+                    }
+                }
+                other => filtered.push(other),
+            }
+        }
+        filtered
+    }
+}
+
+impl FromStr for IgnoreFields {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ignore = Self::default();
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match field {
+                "uuid" | "uuids" => ignore.uuid = true,
+                "nguid" | "nguids" => ignore.nguid = true,
+                "serial" | "serials" => ignore.serial = true,
+                "model" | "models" => ignore.model = true,
+                "enabled" => ignore.enabled = true,
+                _ => return Err(Error::InvalidIgnoreField(field.to_string())),
+            }
+        }
+        Ok(ignore)
+    }
+}
+
+impl State {
+    #[must_use]
+    pub fn get_deltas_ignoring(&self, other: &Self, ignore: &IgnoreFields) -> Vec<StateDelta> {
+        ignore.filter_deltas(self, self.get_deltas(other))
+    }
+
+    #[must_use]
+    pub fn equivalent(&self, other: &Self, ignore: &IgnoreFields) -> bool {
+        self.get_deltas_ignoring(other, ignore).is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Subsystem;
+    use std::collections::BTreeMap;
+    use uuid::Uuid;
+
+    fn state_with_ns(serial: &str, ns: Namespace) -> State {
+        let mut state = State::default();
+        state.subsystems.insert(
+            "nqn.test".to_string(),
+            Subsystem {
+                alias: None,
+                model: Some("Linux".to_string()),
+                serial: Some(serial.to_string()),
+                namespaces: BTreeMap::from([(1, ns)]),
+                ..Default::default()
+            },
+        );
+        state
+    }
+
+    fn ns(enabled: bool, path: &str, uuid: u128) -> Namespace {
+        Namespace {
+            enabled,
+            device_path: path.into(),
+            device_spec: None,
+            device_uuid: Some(Uuid::from_u128(uuid)),
+            device_nguid: Some(Uuid::nil()),
+        }
+    }
+
+    #[test]
+    fn test_ignore_fields_parse() {
+        let ignore: IgnoreFields = "uuids,serials".parse().unwrap();
+        assert!(ignore.uuid && ignore.serial);
+        assert!(!ignore.nguid && !ignore.model && !ignore.enabled);
+        assert!("".parse::<IgnoreFields>().unwrap().is_empty());
+        assert!("uuids,colour".parse::<IgnoreFields>().is_err());
+    }
+
+    #[test]
+    fn test_equivalent_ignoring_uuid_and_serial() {
+        let base = state_with_ns("1234", ns(true, "/dev/loop0", 1));
+        let new = state_with_ns("5678", ns(true, "/dev/loop0", 2));
+
+        assert!(!base.equivalent(&new, &IgnoreFields::default()));
+        let ignore: IgnoreFields = "uuid,serial".parse().unwrap();
+        assert!(base.equivalent(&new, &ignore));
+        // Only ignoring one of the two still reports the other.
+        assert!(!base.equivalent(&new, &"uuid".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_filter_deltas_partial_difference() {
+        let base = state_with_ns("1234", ns(true, "/dev/loop0", 1));
+        let new = state_with_ns("1234", ns(false, "/dev/loop1", 2));
+        let ignore: IgnoreFields = "uuid".parse().unwrap();
+
+        // The device path changed as well, so the namespace update must remain.
+        let deltas = base.get_deltas_ignoring(&new, &ignore);
+        assert_eq!(
+            deltas,
+            vec![StateDelta::UpdateSubsystem(
+                "nqn.test".to_string(),
+                vec![SubsystemDelta::UpdateNamespace(
+                    1,
+                    ns(false, "/dev/loop1", 2)
+                )]
+            )]
+        );
+
+        // Same path, only ignored fields differ: dropped entirely.
+        let new = state_with_ns("1234", ns(false, "/dev/loop0", 2));
+        let ignore: IgnoreFields = "uuid,enabled".parse().unwrap();
+        assert!(base.get_deltas_ignoring(&new, &ignore).is_empty());
+    }
+}
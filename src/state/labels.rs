@@ -0,0 +1,264 @@
+// User-defined labels of subsystems and ports, kept next to the kernel state.
+// Like aliases, the kernel has nowhere to store them, so they live in a file of their own.
+
+use super::delta::StateDelta;
+use super::types::State;
+use crate::errors::{Context, Error, Result};
+use crate::helpers::{assert_valid_label_key, assert_valid_label_value, write_file_atomic};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs::File, path::Path};
+
+/// Default location of the label file.
+pub const LABEL_FILE: &str = "/var/lib/nvmetcfg/labels.yaml";
+
+/// Labels of a single subsystem or port.
+pub type Labels = BTreeMap<String, String>;
+
+/// Split a label given as `key=value`, checking both parts.
+pub fn parse_label(label: &str) -> Result<(String, String)> {
+    let (key, value) = label
+        .split_once('=')
+        .ok_or_else(|| Error::InvalidLabel(label.to_string()))?;
+    assert_valid_label_key(key)?;
+    assert_valid_label_value(value)?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Whether `labels` has all of the labels in `selector`.
+#[must_use]
+pub fn labels_match(labels: &Labels, selector: &Labels) -> bool {
+    selector
+        .iter()
+        .all(|(key, value)| labels.get(key) == Some(value))
+}
+
+/// Check all keys and values of `labels`.
+pub fn assert_valid_labels(labels: &Labels) -> Result<()> {
+    for (key, value) in labels {
+        assert_valid_label_key(key)?;
+        assert_valid_label_value(value)?;
+    }
+    Ok(())
+}
+
+/// Labels of subsystems, keyed by NQN, and of ports, keyed by ID.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabelStore {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub subsystems: BTreeMap<String, Labels>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ports: BTreeMap<u16, Labels>,
+}
+
+impl LabelStore {
+    /// Load the labels from `path`. A missing file means there are no labels.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let f = File::open(path)
+            .with_context(|| format!("Failed to open label file {}", path.display()))?;
+        let labels = serde_yaml::from_reader(f)
+            .with_context(|| format!("Failed to read label file {}", path.display()))?;
+        Ok(labels)
+    }
+
+    /// Save the labels to `path`, creating its parent directory if needed.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+        let yaml = serde_yaml::to_string(self).context("Failed to serialize labels")?;
+        write_file_atomic(path, 0o644, yaml.as_bytes())
+            .with_context(|| format!("Failed to write label file {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Take over the labels set in a state.
+    ///
+    /// These take precedence: objects labelled in the state get exactly the labels from it.
+    pub fn extend_from_state(&mut self, state: &State) {
+        for (nqn, sub) in &state.subsystems {
+            if !sub.labels.is_empty() {
+                self.subsystems.insert(nqn.clone(), sub.labels.clone());
+            }
+        }
+        for (id, port) in &state.ports {
+            if !port.labels.is_empty() {
+                self.ports.insert(*id, port.labels.clone());
+            }
+        }
+    }
+
+    /// Fill in the labels of every subsystem and port in `state` that has any.
+    pub fn apply_to_state(&self, state: &mut State) {
+        for (nqn, sub) in &mut state.subsystems {
+            if let Some(labels) = self.subsystems.get(nqn) {
+                sub.labels.clone_from(labels);
+            }
+        }
+        for (id, port) in &mut state.ports {
+            if let Some(labels) = self.ports.get(id) {
+                port.labels.clone_from(labels);
+            }
+        }
+    }
+
+    /// Drop the labels of subsystems and ports removed by `deltas`.
+    ///
+    /// Returns whether any labels were dropped.
+    pub fn forget_removed(&mut self, deltas: &[StateDelta]) -> bool {
+        let mut changed = false;
+        for delta in deltas {
+            changed |= match delta {
+                StateDelta::RemoveSubsystem(nqn) => self.subsystems.remove(nqn).is_some(),
+                StateDelta::RemovePort(id) => self.ports.remove(id).is_some(),
+                _ => false,
+            };
+        }
+        changed
+    }
+
+    /// Drop the labels of subsystems and ports which are not in `state`.
+    ///
+    /// Returns whether any labels were dropped.
+    pub fn retain_existing(&mut self, state: &State) -> bool {
+        let before = (self.subsystems.len(), self.ports.len());
+        self.subsystems
+            .retain(|nqn, _| state.subsystems.contains_key(nqn));
+        self.ports.retain(|id, _| state.ports.contains_key(id));
+        before != (self.subsystems.len(), self.ports.len())
+    }
+
+    /// Set and remove labels of one object, dropping it from the store once it has none left.
+    pub fn update<K: Ord>(
+        store: &mut BTreeMap<K, Labels>,
+        id: K,
+        set: Labels,
+        remove: &[String],
+    ) -> Result<()> {
+        assert_valid_labels(&set)?;
+        let labels = store.entry(id).or_default();
+        labels.extend(set);
+        for key in remove {
+            labels.remove(key);
+        }
+        store.retain(|_, labels| !labels.is_empty());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use crate::state::{Port, PortType, Subsystem};
+    use std::collections::BTreeSet;
+
+    const SUB1: &str = "nqn.2023-11.sh.tty:sub1";
+    const SUB2: &str = "nqn.2023-11.sh.tty:sub2";
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_and_match() -> Result<()> {
+        assert_eq!(
+            parse_label("env=prod")?,
+            ("env".to_string(), "prod".to_string())
+        );
+        // The value may contain further '='.
+        assert_eq!(parse_label("query=a=b")?.1, "a=b");
+        assert!(parse_label("env").is_err());
+        assert!(parse_label("=prod").is_err());
+
+        let sub = labels(&[("env", "prod"), ("team", "storage")]);
+        assert!(labels_match(&sub, &labels(&[("env", "prod")])));
+        assert!(labels_match(&sub, &Labels::new()));
+        assert!(!labels_match(&sub, &labels(&[("env", "dev")])));
+        assert!(!labels_match(
+            &sub,
+            &labels(&[("env", "prod"), ("owner", "bob")])
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_roundtrip_and_cleanup() -> Result<()> {
+        let mut state = State::default();
+        state.subsystems.insert(
+            SUB1.to_string(),
+            Subsystem {
+                labels: labels(&[("env", "prod")]),
+                ..Default::default()
+            },
+        );
+        state
+            .subsystems
+            .insert(SUB2.to_string(), Subsystem::default());
+        let mut port = Port::new(PortType::Loop, BTreeSet::new());
+        port.labels = labels(&[("rack", "a1")]);
+        state.ports.insert(1, port);
+
+        let mut store = LabelStore::default();
+        store.extend_from_state(&state);
+        let dir = TestDir::new("labels");
+        let path = dir.join("labels.yaml");
+        assert_eq!(LabelStore::load(&path)?, LabelStore::default());
+        store.save(&path)?;
+        let loaded = LabelStore::load(&path)?;
+        assert_eq!(loaded, store);
+
+        let mut gathered = state.clone();
+        gathered.subsystems.get_mut(SUB1).unwrap().labels.clear();
+        gathered.ports.get_mut(&1).unwrap().labels.clear();
+        loaded.apply_to_state(&mut gathered);
+        assert_eq!(gathered, state);
+
+        // Removing objects drops their labels.
+        let mut store = loaded.clone();
+        assert!(!store.forget_removed(&[StateDelta::RemoveSubsystem(SUB2.to_string())]));
+        assert!(store.forget_removed(&[StateDelta::RemoveSubsystem(SUB1.to_string())]));
+        assert!(store.subsystems.is_empty());
+        let mut store = loaded;
+        state.ports.clear();
+        assert!(store.retain_existing(&state));
+        assert!(store.ports.is_empty() && store.subsystems.contains_key(SUB1));
+        assert!(!store.retain_existing(&state));
+        Ok(())
+    }
+
+    #[test]
+    fn test_update() -> Result<()> {
+        let mut store = LabelStore::default();
+        LabelStore::update(
+            &mut store.subsystems,
+            SUB1.to_string(),
+            labels(&[("env", "prod"), ("team", "storage")]),
+            &[],
+        )?;
+        LabelStore::update(
+            &mut store.subsystems,
+            SUB1.to_string(),
+            labels(&[("env", "dev")]),
+            &["team".to_string()],
+        )?;
+        assert_eq!(store.subsystems[SUB1], labels(&[("env", "dev")]));
+        LabelStore::update(
+            &mut store.subsystems,
+            SUB1.to_string(),
+            Labels::new(),
+            &["env".to_string()],
+        )?;
+        assert!(store.subsystems.is_empty());
+        assert!(LabelStore::update(&mut store.ports, 1, labels(&[("bad key", "x")]), &[]).is_err());
+        Ok(())
+    }
+}
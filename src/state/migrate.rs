@@ -0,0 +1,127 @@
+// Moving a port to a new address without disconnecting its initiators.
+// Updating the address of a port takes all its subsystems off it while it changes, so instead
+// a second port with the new address is added next to it, and the old one is only removed once
+// the initiators had the chance to connect through the new one.
+
+use super::delta::StateDelta;
+use super::types::{Port, PortType, State};
+use crate::errors::{Error, Result};
+
+/// The changes moving a port to a new address, see `State::plan_port_migration`.
+///
+/// Apply `attach`, wait for the initiators to move to the new port, then apply `detach`.
+/// If anything fails before `detach` has been applied, applying `rollback` removes the new
+/// port again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMigration {
+    /// ID of the port being replaced.
+    pub old: u16,
+    /// ID of the port replacing it.
+    pub new: u16,
+    /// Adds the new port, providing the same subsystems as the old one.
+    pub attach: Vec<StateDelta>,
+    /// Removes the old port.
+    pub detach: Vec<StateDelta>,
+    /// Removes the new port, undoing `attach`.
+    pub rollback: Vec<StateDelta>,
+}
+
+impl State {
+    /// Plan moving port `old` to the address `port_type`, on the new port `new`.
+    ///
+    /// Without `new`, the lowest unused port ID is taken. The new port gets the labels of the
+    /// old one. Fails if the new port would use the address of any other port.
+    pub fn plan_port_migration(
+        &self,
+        old: u16,
+        port_type: PortType,
+        new: Option<u16>,
+    ) -> Result<PortMigration> {
+        let port = self.ports.get(&old).ok_or(Error::NoSuchPort(old))?;
+        let new = match new {
+            Some(new) if self.ports.contains_key(&new) => return Err(Error::ExistingPort(new)),
+            Some(new) => new,
+            None => (1..=u16::MAX)
+                .find(|id| !self.ports.contains_key(id))
+                .ok_or(Error::ExistingPort(u16::MAX))?,
+        };
+        let mut replacement = Port::new(port_type, port.subsystems.clone());
+        replacement.labels.clone_from(&port.labels);
+        let migration = PortMigration {
+            old,
+            new,
+            attach: vec![StateDelta::AddPort(new, replacement)],
+            detach: vec![StateDelta::RemovePort(old)],
+            rollback: vec![StateDelta::RemovePort(new)],
+        };
+
+        // Both ports exist at the same time, so they cannot share an address.
+        let mut attached = self.clone();
+        attached.apply_deltas(&migration.attach)?;
+        attached.validate(false)?;
+        Ok(migration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    const SUB: &str = "nqn.2023-11.sh.tty:sub";
+
+    fn state() -> State {
+        let mut state = State::default();
+        let mut port = Port::new(
+            PortType::Tcp("192.0.2.1:4420".parse().unwrap()),
+            BTreeSet::from([SUB.to_string()]),
+        );
+        port.labels = BTreeMap::from([("env".to_string(), "prod".to_string())]);
+        state.ports.insert(1, port);
+        state.subsystems.insert(SUB.to_string(), Default::default());
+        state
+    }
+
+    #[test]
+    fn test_plan_port_migration() -> Result<()> {
+        let state = state();
+        let address = PortType::Tcp("192.0.2.2:4420".parse().unwrap());
+        let migration = state.plan_port_migration(1, address, None)?;
+        assert_eq!(migration.new, 2);
+
+        let mut migrated = state.clone();
+        migrated.apply_deltas(&migration.attach)?;
+        assert_eq!(migrated.ports[&2].subsystems, state.ports[&1].subsystems);
+        assert_eq!(migrated.ports[&2].labels, state.ports[&1].labels);
+        let attached = migrated.clone();
+        migrated.apply_deltas(&migration.detach)?;
+        assert_eq!(migrated.ports.keys().collect::<Vec<_>>(), [&2]);
+        assert_eq!(migrated.ports[&2].port_type, address);
+
+        let mut rolled_back = attached;
+        rolled_back.apply_deltas(&migration.rollback)?;
+        assert_eq!(rolled_back, state);
+
+        assert_eq!(state.plan_port_migration(1, address, Some(7))?.new, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_port_migration_invalid() {
+        let state = state();
+        let address = PortType::Tcp("192.0.2.2:4420".parse().unwrap());
+        assert!(matches!(
+            state.plan_port_migration(2, address, None),
+            Err(Error::NoSuchPort(2))
+        ));
+        assert!(matches!(
+            state.plan_port_migration(1, address, Some(1)),
+            Err(Error::ExistingPort(1))
+        ));
+        let same = state.ports[&1].port_type;
+        assert!(matches!(
+            state.plan_port_migration(1, same, None),
+            Err(Error::DuplicatePortAddress(_, 1, 2))
+        ));
+    }
+}
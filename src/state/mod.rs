@@ -1,5 +1,35 @@
+mod alias;
+mod audit;
+mod builder;
+mod connect;
 mod delta;
+mod devices;
+mod drift;
+mod ignore;
+mod labels;
+mod migrate;
+mod offline;
+mod records;
+mod redact;
+mod renumber;
+mod summary;
+mod tree;
 mod types;
+mod validate;
 
+pub use alias::*;
+pub use audit::*;
+pub use builder::*;
 pub use delta::*;
+pub use devices::*;
+pub use drift::*;
+pub use ignore::*;
+pub use labels::*;
+pub use migrate::*;
+pub use offline::*;
+pub use records::*;
+pub use redact::*;
+pub use renumber::*;
+pub use summary::*;
+pub use tree::*;
 pub use types::*;
@@ -0,0 +1,225 @@
+// Ports taken offline for maintenance, with the subsystems they provided before.
+// The kernel has no notion of an offline port, so the subsystems are stashed in a file of our own
+// until the port is brought online again.
+
+use super::delta::{PortDelta, StateDelta};
+use super::types::State;
+use crate::errors::{Context, Error, Result};
+use crate::helpers::write_file_atomic;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::path::Path;
+
+/// Default location of the offline port file.
+pub const OFFLINE_FILE: &str = "/var/lib/nvmetcfg/offline-ports.yaml";
+
+/// The subsystems of offline ports, keyed by port ID.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfflinePorts {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ports: BTreeMap<u16, BTreeSet<String>>,
+}
+
+impl OfflinePorts {
+    /// Load the offline ports from `path`. A missing file means there are none.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let f = File::open(path)
+            .with_context(|| format!("Failed to open offline port file {}", path.display()))?;
+        let offline = serde_yaml::from_reader(f)
+            .with_context(|| format!("Failed to read offline port file {}", path.display()))?;
+        Ok(offline)
+    }
+
+    /// Save the offline ports to `path`, creating its parent directory if needed.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+        let yaml = serde_yaml::to_string(self).context("Failed to serialize offline ports")?;
+        write_file_atomic(path, 0o644, yaml.as_bytes())
+            .with_context(|| format!("Failed to write offline port file {}", path.display()))?;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn is_offline(&self, id: u16) -> bool {
+        self.ports.contains_key(&id)
+    }
+
+    /// Take port `id` of `state` offline, stashing its subsystems.
+    ///
+    /// Returns the changes removing all subsystems from the port.
+    pub fn take_offline(&mut self, state: &State, id: u16) -> Result<Vec<StateDelta>> {
+        let port = state.ports.get(&id).ok_or(Error::NoSuchPort(id))?;
+        if self.is_offline(id) {
+            return Err(Error::PortOffline(id));
+        }
+        let deltas = state.get_port_subsystem_deltas(id, &BTreeSet::new())?;
+        self.ports.insert(id, port.subsystems.clone());
+        Ok(deltas)
+    }
+
+    /// Bring port `id` of `state` online again, forgetting its stashed subsystems.
+    ///
+    /// Returns the changes adding them back to the port. Fails if any of them no longer exists,
+    /// rather than bringing the port online with only some of them.
+    pub fn bring_online(&mut self, state: &State, id: u16) -> Result<Vec<StateDelta>> {
+        let port = state.ports.get(&id).ok_or(Error::NoSuchPort(id))?;
+        let stashed = self.ports.get(&id).ok_or(Error::PortNotOffline(id))?;
+        let missing: Vec<&str> = stashed
+            .iter()
+            .filter(|nqn| !state.subsystems.contains_key(*nqn))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::OfflineSubsystemsGone(id, missing.join(", ")));
+        }
+        let added: Vec<PortDelta> = stashed
+            .difference(&port.subsystems)
+            .cloned()
+            .map(PortDelta::AddSubsystem)
+            .collect();
+        self.ports.remove(&id);
+        if added.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(vec![StateDelta::UpdatePort(id, added)])
+    }
+
+    /// Keep `desired` from adding subsystems back to offline ports, stashing them instead.
+    ///
+    /// The stash of each offline port in `desired` is replaced by the subsystems it has there,
+    /// so that bringing it online later provides those. Returns the ports which had any.
+    pub fn hold_back(&mut self, desired: &mut State) -> Vec<u16> {
+        let mut held = Vec::new();
+        for (id, port) in &mut desired.ports {
+            let Some(stashed) = self.ports.get_mut(id) else {
+                continue;
+            };
+            if !port.subsystems.is_empty() {
+                held.push(*id);
+            }
+            *stashed = std::mem::take(&mut port.subsystems);
+        }
+        held
+    }
+
+    /// Forget offline ports removed by `deltas`.
+    ///
+    /// Returns whether any were forgotten.
+    pub fn forget_removed(&mut self, deltas: &[StateDelta]) -> bool {
+        let mut changed = false;
+        for delta in deltas {
+            if let StateDelta::RemovePort(id) = delta {
+                changed |= self.ports.remove(id).is_some();
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestDir;
+    use crate::state::{Port, PortType, Subsystem};
+
+    const SUB1: &str = "nqn.2023-11.sh.tty:sub1";
+    const SUB2: &str = "nqn.2023-11.sh.tty:sub2";
+
+    fn state() -> State {
+        let mut state = State::default();
+        for nqn in [SUB1, SUB2] {
+            state
+                .subsystems
+                .insert(nqn.to_string(), Subsystem::default());
+        }
+        state.ports.insert(
+            1,
+            Port::new(
+                PortType::Loop,
+                BTreeSet::from([SUB1.to_string(), SUB2.to_string()]),
+            ),
+        );
+        state
+    }
+
+    #[test]
+    fn test_offline_online() -> Result<()> {
+        let mut state = state();
+        let original = state.clone();
+        let mut offline = OfflinePorts::default();
+        assert!(matches!(
+            offline.take_offline(&state, 2),
+            Err(Error::NoSuchPort(2))
+        ));
+        assert!(matches!(
+            offline.bring_online(&state, 1),
+            Err(Error::PortNotOffline(1))
+        ));
+
+        let deltas = offline.take_offline(&state, 1)?;
+        state.apply_deltas(&deltas)?;
+        assert!(state.ports[&1].subsystems.is_empty());
+        assert!(offline.is_offline(1));
+        assert!(matches!(
+            offline.take_offline(&state, 1),
+            Err(Error::PortOffline(1))
+        ));
+
+        // The stash survives saving and loading.
+        let dir = TestDir::new("offline");
+        let path = dir.join("offline-ports.yaml");
+        offline.save(&path)?;
+        let mut offline = OfflinePorts::load(&path)?;
+        std::fs::remove_file(&path)?;
+        assert!(OfflinePorts::load(&path)?.ports.is_empty());
+
+        let deltas = offline.bring_online(&state, 1)?;
+        state.apply_deltas(&deltas)?;
+        assert_eq!(state, original);
+        assert!(!offline.is_offline(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_online_missing_subsystem() -> Result<()> {
+        let mut state = state();
+        let mut offline = OfflinePorts::default();
+        let deltas = offline.take_offline(&state, 1)?;
+        state.apply_deltas(&deltas)?;
+        state.apply_deltas(&[StateDelta::RemoveSubsystem(SUB2.to_string())])?;
+
+        let err = offline.bring_online(&state, 1).unwrap_err();
+        assert!(err.to_string().contains(SUB2));
+        // Nothing is forgotten, so the port can be brought online once the subsystem is back.
+        assert!(offline.is_offline(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hold_back() -> Result<()> {
+        let mut offline = OfflinePorts::default();
+        let mut current = state();
+        let deltas = offline.take_offline(&current, 1)?;
+        current.apply_deltas(&deltas)?;
+
+        // Restoring a state saved before the port went offline keeps it offline.
+        let mut desired = state();
+        desired.ports.get_mut(&1).unwrap().subsystems.remove(SUB2);
+        assert_eq!(offline.hold_back(&mut desired), [1]);
+        assert!(current.get_deltas(&desired).is_empty());
+        assert_eq!(offline.ports[&1], BTreeSet::from([SUB1.to_string()]));
+
+        assert!(offline.forget_removed(&[StateDelta::RemovePort(1)]));
+        assert!(!offline.forget_removed(&[StateDelta::RemovePort(1)]));
+        Ok(())
+    }
+}
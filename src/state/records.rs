@@ -0,0 +1,261 @@
+// Flat views of the objects in a state, for printing them one by one.
+
+use super::types::{Port, State, Subsystem};
+use crate::errors::{Error, Result};
+use serde::Serialize;
+use std::fmt;
+
+/// A subsystem together with its NQN.
+#[derive(Debug, Clone, Serialize)]
+pub struct SubsystemRecord<'a> {
+    pub nqn: &'a str,
+    #[serde(flatten)]
+    pub subsystem: &'a Subsystem,
+}
+
+/// A port together with its ID.
+#[derive(Debug, Clone, Serialize)]
+pub struct PortRecord<'a> {
+    pub id: u16,
+    #[serde(flatten)]
+    pub port: &'a Port,
+}
+
+/// A subsystem provided by a port, one per link between them.
+#[derive(Debug, Clone, Serialize)]
+pub struct PortSubsystemRecord<'a> {
+    pub port: u16,
+    /// The address of the port as URI, like `tcp://192.0.2.1:4420`.
+    pub address: String,
+    pub nqn: &'a str,
+}
+
+/// Prints which ports provide which subsystems as table with aligned columns.
+///
+/// Ports without subsystems are listed with a `-` in place of the NQN.
+#[derive(Debug, Clone, Copy)]
+pub struct PortSubsystemTable<'a>(pub &'a State);
+
+impl State {
+    /// The subsystems, ordered by NQN.
+    pub fn subsystem_records(&self) -> impl Iterator<Item = SubsystemRecord<'_>> {
+        self.subsystems
+            .iter()
+            .map(|(nqn, subsystem)| SubsystemRecord { nqn, subsystem })
+    }
+
+    /// The ports, ordered by ID.
+    pub fn port_records(&self) -> impl Iterator<Item = PortRecord<'_>> {
+        self.ports.iter().map(|(&id, port)| PortRecord { id, port })
+    }
+
+    /// The subsystems provided by each port, ordered by port ID and then NQN.
+    pub fn port_subsystem_records(&self) -> impl Iterator<Item = PortSubsystemRecord<'_>> {
+        self.ports.iter().flat_map(|(&port, p)| {
+            p.subsystems.iter().map(move |nqn| PortSubsystemRecord {
+                port,
+                address: p.port_type.to_string(),
+                nqn,
+            })
+        })
+    }
+
+    /// The IDs of the ports providing subsystem `nqn`, in ascending order.
+    pub fn subsystem_ports(&self, nqn: &str) -> Result<Vec<u16>> {
+        if !self.subsystems.contains_key(nqn) {
+            return Err(Error::NoSuchSubsystem(nqn.to_string()));
+        }
+        Ok(self
+            .ports
+            .iter()
+            .filter(|(_, port)| port.subsystems.contains(nqn))
+            .map(|(&id, _)| id)
+            .collect())
+    }
+
+    /// The port to subsystem mappings as table, see `PortSubsystemTable`.
+    #[must_use]
+    pub const fn port_subsystem_table(&self) -> PortSubsystemTable<'_> {
+        PortSubsystemTable(self)
+    }
+}
+
+impl fmt::Display for PortSubsystemTable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rows = vec![[
+            "PORT".to_string(),
+            "ADDRESS".to_string(),
+            "SUBSYSTEM".to_string(),
+        ]];
+        for (id, port) in &self.0.ports {
+            let address = port.port_type.to_string();
+            if port.subsystems.is_empty() {
+                rows.push([id.to_string(), address, "-".to_string()]);
+            } else {
+                for nqn in &port.subsystems {
+                    rows.push([id.to_string(), address.clone(), nqn.clone()]);
+                }
+            }
+        }
+        let port_width = rows.iter().map(|row| row[0].len()).max().unwrap_or(0);
+        let address_width = rows.iter().map(|row| row[1].len()).max().unwrap_or(0);
+        for [port, address, nqn] in rows {
+            writeln!(f, "{port:<port_width$}  {address:<address_width$}  {nqn}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::{Error, Result};
+    use crate::helpers::write_ndjson;
+    use crate::state::{Namespace, Port, PortType, State, Subsystem};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn test_records_ndjson() -> Result<()> {
+        let mut state = State::default();
+        for (i, nqn) in ["nqn.2023-11.sh.tty:a", "nqn.2023-11.sh.tty:b"]
+            .into_iter()
+            .enumerate()
+        {
+            state.subsystems.insert(
+                nqn.to_string(),
+                Subsystem {
+                    model: Some("Linux".to_string()),
+                    // A newline within a value must not split the line.
+                    serial: Some(format!("serial\n{i}")),
+                    namespaces: BTreeMap::from([(
+                        1,
+                        Namespace {
+                            enabled: true,
+                            device_path: "/dev/loop0".into(),
+                            device_spec: None,
+                            device_uuid: None,
+                            device_nguid: None,
+                        },
+                    )]),
+                    ..Default::default()
+                },
+            );
+        }
+        state.ports.insert(
+            1,
+            Port::new(
+                PortType::Tcp("127.0.0.1:4420".parse()?),
+                BTreeSet::from(["nqn.2023-11.sh.tty:a".to_string()]),
+            ),
+        );
+        state
+            .ports
+            .insert(2, Port::new(PortType::Loop, BTreeSet::new()));
+
+        let mut out = Vec::new();
+        write_ndjson(&mut out, state.subsystem_records())?;
+        write_ndjson(&mut out, state.port_records())?;
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 4);
+        assert!(lines.iter().all(serde_json::Value::is_object));
+        assert_eq!(lines[0]["nqn"], "nqn.2023-11.sh.tty:a");
+        assert_eq!(lines[1]["serial"], "serial\n1");
+        assert_eq!(lines[1]["namespaces"]["1"]["device_path"], "/dev/loop0");
+        assert_eq!(lines[2]["id"], 1);
+        assert_eq!(lines[2]["port_addr"], "127.0.0.1:4420");
+        assert_eq!(lines[3]["port_type"], "Loop");
+        Ok(())
+    }
+
+    // Subsystem c isn't provided by any port, port 2 provides nothing.
+    fn multi_port_state() -> Result<State> {
+        let mut state = State::default();
+        for nqn in [
+            "nqn.2023-11.sh.tty:a",
+            "nqn.2023-11.sh.tty:b",
+            "nqn.2023-11.sh.tty:c",
+        ] {
+            state
+                .subsystems
+                .insert(nqn.to_string(), Subsystem::default());
+        }
+        state.ports.insert(
+            1,
+            Port::new(
+                PortType::Tcp("192.0.2.1:4420".parse()?),
+                BTreeSet::from([
+                    "nqn.2023-11.sh.tty:b".to_string(),
+                    "nqn.2023-11.sh.tty:a".to_string(),
+                ]),
+            ),
+        );
+        state
+            .ports
+            .insert(2, Port::new(PortType::Loop, BTreeSet::new()));
+        state.ports.insert(
+            10,
+            Port::new(
+                PortType::Rdma("[fdff::1]:4420".parse()?),
+                BTreeSet::from(["nqn.2023-11.sh.tty:a".to_string()]),
+            ),
+        );
+        Ok(state)
+    }
+
+    #[test]
+    fn test_port_subsystem_table() -> Result<()> {
+        let state = multi_port_state()?;
+        assert_eq!(
+            state.port_subsystem_table().to_string(),
+            "\
+PORT  ADDRESS                SUBSYSTEM
+1     tcp://192.0.2.1:4420   nqn.2023-11.sh.tty:a
+1     tcp://192.0.2.1:4420   nqn.2023-11.sh.tty:b
+2     loop://                -
+10    rdma://[fdff::1]:4420  nqn.2023-11.sh.tty:a
+"
+        );
+
+        let records: Vec<_> = state
+            .port_subsystem_records()
+            .map(|record| (record.port, record.address, record.nqn))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (
+                    1,
+                    "tcp://192.0.2.1:4420".to_string(),
+                    "nqn.2023-11.sh.tty:a"
+                ),
+                (
+                    1,
+                    "tcp://192.0.2.1:4420".to_string(),
+                    "nqn.2023-11.sh.tty:b"
+                ),
+                (
+                    10,
+                    "rdma://[fdff::1]:4420".to_string(),
+                    "nqn.2023-11.sh.tty:a"
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_subsystem_ports() -> Result<()> {
+        let state = multi_port_state()?;
+        assert_eq!(state.subsystem_ports("nqn.2023-11.sh.tty:a")?, [1, 10]);
+        assert_eq!(state.subsystem_ports("nqn.2023-11.sh.tty:b")?, [1]);
+        assert!(state.subsystem_ports("nqn.2023-11.sh.tty:c")?.is_empty());
+        assert!(matches!(
+            state.subsystem_ports("nqn.2023-11.sh.tty:gone"),
+            Err(Error::NoSuchSubsystem(nqn)) if nqn == "nqn.2023-11.sh.tty:gone"
+        ));
+        Ok(())
+    }
+}
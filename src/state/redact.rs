@@ -0,0 +1,202 @@
+// Scrubbing identifying values from a state, for sharing it with others.
+
+use super::types::State;
+use crate::errors::Error;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// Categories of values to replace with placeholders.
+///
+/// `keys` drops the DH-HMAC-CHAP keys of hosts, see `State::hosts`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RedactFields {
+    pub serials: bool,
+    pub hosts: bool,
+    pub keys: bool,
+}
+
+impl RedactFields {
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        !(self.serials || self.hosts || self.keys)
+    }
+
+    /// Names of the selected categories, as accepted by `from_str`.
+    #[must_use]
+    pub fn names(&self) -> Vec<String> {
+        [
+            (self.serials, "serials"),
+            (self.hosts, "hosts"),
+            (self.keys, "keys"),
+        ]
+        .into_iter()
+        .filter(|(selected, _)| *selected)
+        .map(|(_, name)| name.to_string())
+        .collect()
+    }
+
+    /// Select the categories of both.
+    #[must_use]
+    pub const fn union(&self, other: &Self) -> Self {
+        Self {
+            serials: self.serials || other.serials,
+            hosts: self.hosts || other.hosts,
+            keys: self.keys || other.keys,
+        }
+    }
+}
+
+impl FromStr for RedactFields {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut redact = Self::default();
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match field {
+                "serial" | "serials" => redact.serials = true,
+                "host" | "hosts" => redact.hosts = true,
+                "key" | "keys" => redact.keys = true,
+                _ => return Err(Error::InvalidRedactField(field.to_string())),
+            }
+        }
+        Ok(redact)
+    }
+}
+
+/// Hands out numbered placeholders, the same one every time for the same value.
+struct Placeholders {
+    format: fn(usize) -> String,
+    assigned: BTreeMap<String, String>,
+}
+
+impl Placeholders {
+    fn new(format: fn(usize) -> String) -> Self {
+        Self {
+            format,
+            assigned: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, value: &str) -> String {
+        let next = self.assigned.len() + 1;
+        self.assigned
+            .entry(value.to_string())
+            .or_insert_with(|| (self.format)(next))
+            .clone()
+    }
+}
+
+impl State {
+    /// Replace the values of the selected categories with placeholders.
+    ///
+    /// Equal values get equal placeholders, so it can still be told which subsystems share a
+    /// serial or an allowed host. The placeholders are valid values, so the state stays valid.
+    /// Keys have no placeholder that would pass validation, so they are left out instead.
+    pub fn redact(&mut self, fields: &RedactFields) {
+        let mut serials = Placeholders::new(|n| format!("redacted-{n}"));
+        let mut hosts = Placeholders::new(|n| format!("nqn.2000-01.invalid.redacted:host-{n}"));
+        for sub in self.subsystems.values_mut() {
+            if fields.serials {
+                if let Some(serial) = &sub.serial {
+                    sub.serial = Some(serials.get(serial));
+                }
+            }
+            if fields.hosts {
+                sub.allowed_hosts = sub.allowed_hosts.iter().map(|h| hosts.get(h)).collect();
+            }
+        }
+        if fields.hosts {
+            self.hosts = std::mem::take(&mut self.hosts)
+                .into_iter()
+                .map(|(nqn, keys)| (hosts.get(&nqn), keys))
+                .collect();
+        }
+        if fields.keys {
+            self.hosts.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Result;
+    use crate::state::{HostKeys, Subsystem};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_redact() -> Result<()> {
+        let host1 = "nqn.2024-02.sh.tty:client1".to_string();
+        let host2 = "nqn.2024-02.sh.tty:client2".to_string();
+        let mut state = State::default();
+        for (nqn, hosts) in [
+            ("nqn.2023-11.sh.tty:a", vec![host1.clone(), host2.clone()]),
+            ("nqn.2023-11.sh.tty:b", vec![host2.clone()]),
+        ] {
+            state.subsystems.insert(
+                nqn.to_string(),
+                Subsystem {
+                    model: Some("Linux".to_string()),
+                    serial: Some("1337".to_string()),
+                    allowed_hosts: hosts.into_iter().collect(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let mut redacted = state.clone();
+        redacted.redact(&"serials,hosts".parse()?);
+        redacted.validate(true)?;
+        let a = &redacted.subsystems["nqn.2023-11.sh.tty:a"];
+        let b = &redacted.subsystems["nqn.2023-11.sh.tty:b"];
+        assert_eq!(a.model.as_deref(), Some("Linux"));
+        assert_eq!(a.serial.as_deref(), Some("redacted-1"));
+        assert_eq!(b.serial, a.serial);
+        assert!(!a.allowed_hosts.contains(&host1) && !a.allowed_hosts.contains(&host2));
+        // client2 is allowed on both, and still is.
+        assert_eq!(a.allowed_hosts.len(), 2);
+        assert_eq!(
+            b.allowed_hosts,
+            BTreeSet::from(["nqn.2000-01.invalid.redacted:host-2".to_string()])
+        );
+        assert!(a.allowed_hosts.is_superset(&b.allowed_hosts));
+
+        // Keys are renamed along with their host, or dropped.
+        let key = "DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:".to_string();
+        state.hosts.insert(
+            host2.clone(),
+            HostKeys {
+                dhchap_key: Some(key.clone()),
+                ..Default::default()
+            },
+        );
+        let mut renamed = state.clone();
+        renamed.redact(&"hosts".parse()?);
+        renamed.validate(true)?;
+        assert_eq!(
+            renamed.hosts["nqn.2000-01.invalid.redacted:host-2"].dhchap_key,
+            Some(key)
+        );
+        let mut keyless = state.clone();
+        keyless.redact(&"keys".parse()?);
+        assert!(keyless.hosts.is_empty());
+        assert_eq!(keyless.subsystems, state.subsystems);
+
+        // Only the selected categories are replaced.
+        let mut serials_only = state.clone();
+        serials_only.redact(&"serials".parse()?);
+        assert!(serials_only.subsystems["nqn.2023-11.sh.tty:b"]
+            .allowed_hosts
+            .contains(&host2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_redact_fields() -> Result<()> {
+        let fields: RedactFields = "serials, keys".parse()?;
+        assert_eq!(fields.names(), vec!["serials", "keys"]);
+        assert!(RedactFields::default().is_empty());
+        assert!("passwords".parse::<RedactFields>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+// Giving the namespaces of a subsystem sequential NSIDs again, after years of adds and removes.
+// The kernel can't change the NSID of a namespace, so each one is removed and added back under
+// its new NSID with the same device and identity. Initiators see it go away and come back.
+
+use super::delta::{StateDelta, SubsystemDelta};
+use super::types::State;
+use crate::errors::{Error, Result};
+use crate::helpers::assert_valid_nsid;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The changes renumbering the namespaces of a subsystem, see
+/// `State::plan_namespace_renumbering`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceRenumbering {
+    /// New NSID by old NSID, for every namespace of the subsystem.
+    pub mapping: BTreeMap<u32, u32>,
+    /// The moves from one NSID to another in the order they are made, including those
+    /// through temporary NSIDs.
+    pub moves: Vec<(u32, u32)>,
+    /// Moves each namespace, one change per move.
+    pub deltas: Vec<StateDelta>,
+}
+
+impl NamespaceRenumbering {
+    /// Whether every namespace keeps its NSID.
+    #[must_use]
+    pub fn is_noop(&self) -> bool {
+        self.moves.is_empty()
+    }
+}
+
+impl State {
+    /// Plan giving the namespaces of subsystem `nqn` sequential NSIDs from `start`, in the
+    /// order of their current NSIDs.
+    ///
+    /// Each move is a change of its own, removing the namespace and adding it back unchanged,
+    /// so the NSID it moves to is free by the time it is applied.
+    pub fn plan_namespace_renumbering(
+        &self,
+        nqn: &str,
+        start: u32,
+    ) -> Result<NamespaceRenumbering> {
+        let sub = self
+            .subsystems
+            .get(nqn)
+            .ok_or_else(|| Error::NoSuchSubsystem(nqn.to_string()))?;
+        let nsids: BTreeSet<u32> = sub.namespaces.keys().copied().collect();
+        let mapping = sequential_nsids(&nsids, start)?;
+        let moves = nsid_moves(&mapping)?;
+
+        let mut namespaces = sub.namespaces.clone();
+        let mut deltas = Vec::new();
+        for &(old, new) in &moves {
+            let ns = namespaces.remove(&old).unwrap();
+            namespaces.insert(new, ns.clone());
+            deltas.push(StateDelta::UpdateSubsystem(
+                nqn.to_string(),
+                vec![
+                    SubsystemDelta::RemoveNamespace(old),
+                    SubsystemDelta::AddNamespace(new, ns),
+                ],
+            ));
+        }
+        Ok(NamespaceRenumbering {
+            mapping,
+            moves,
+            deltas,
+        })
+    }
+}
+
+/// Map `nsids` to sequential NSIDs from `start`, keeping their order.
+pub fn sequential_nsids(nsids: &BTreeSet<u32>, start: u32) -> Result<BTreeMap<u32, u32>> {
+    assert_valid_nsid(start)?;
+    if let Some(count) = nsids.len().checked_sub(1) {
+        let count = u32::try_from(count).unwrap_or(u32::MAX);
+        assert_valid_nsid(start.saturating_add(count))?;
+    }
+    Ok(nsids.iter().copied().zip(start..).collect())
+}
+
+// Order the moves of `mapping`, which must not map two NSIDs to the same one, so no NSID is
+// ever taken twice. A move waits until the namespace on its new NSID has moved on. When all
+// that are left wait on each other, one of them goes through an NSID unused on either side.
+fn nsid_moves(mapping: &BTreeMap<u32, u32>) -> Result<Vec<(u32, u32)>> {
+    let mut pending: BTreeMap<u32, u32> = mapping
+        .iter()
+        .filter(|(old, new)| old != new)
+        .map(|(&old, &new)| (old, new))
+        .collect();
+    let mut moves = Vec::new();
+    while let Some((&first, &first_new)) = pending.first_key_value() {
+        if let Some((&old, &new)) = pending.iter().find(|(_, new)| !pending.contains_key(new)) {
+            moves.push((old, new));
+            pending.remove(&old);
+            continue;
+        }
+
+        let temporary = (1..u32::MAX)
+            .find(|nsid| !mapping.contains_key(nsid) && !mapping.values().any(|new| new == nsid))
+            .ok_or(Error::InvalidNamespaceID(u32::MAX))?;
+        moves.push((first, temporary));
+        pending.remove(&first);
+        pending.insert(temporary, first_new);
+    }
+    Ok(moves)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorKind;
+    use crate::state::{Namespace, Subsystem};
+    use uuid::Uuid;
+
+    const SUB: &str = "nqn.2023-11.sh.tty:sub";
+
+    fn mapping(pairs: &[(u32, u32)]) -> BTreeMap<u32, u32> {
+        pairs.iter().copied().collect()
+    }
+
+    // Applies the moves one by one, checking no NSID is ever taken twice.
+    fn apply_moves(mapping: &BTreeMap<u32, u32>, moves: &[(u32, u32)]) -> BTreeMap<u32, u32> {
+        let mut taken: BTreeMap<u32, u32> = mapping.keys().map(|&nsid| (nsid, nsid)).collect();
+        for &(old, new) in moves {
+            let origin = taken.remove(&old).expect("moved from a free NSID");
+            assert!(taken.insert(new, origin).is_none(), "moved onto {new}");
+        }
+        taken.into_iter().map(|(new, old)| (old, new)).collect()
+    }
+
+    #[test]
+    fn test_sequential_nsids() -> Result<()> {
+        let nsids = BTreeSet::from([3, 17, 58]);
+        assert_eq!(
+            sequential_nsids(&nsids, 1)?,
+            mapping(&[(3, 1), (17, 2), (58, 3)])
+        );
+        assert_eq!(
+            sequential_nsids(&nsids, 10)?,
+            mapping(&[(3, 10), (17, 11), (58, 12)])
+        );
+        assert_eq!(sequential_nsids(&BTreeSet::new(), 1)?, BTreeMap::new());
+
+        for start in [0, u32::MAX, u32::MAX - 2] {
+            let err = sequential_nsids(&nsids, start).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{start}: {err:?}");
+        }
+        assert!(sequential_nsids(&nsids, u32::MAX - 3).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nsid_moves_overlapping() -> Result<()> {
+        // Moving down, each move frees the NSID the next one moves to.
+        let down = mapping(&[(2, 1), (3, 2), (4, 3)]);
+        assert_eq!(nsid_moves(&down)?, [(2, 1), (3, 2), (4, 3)]);
+
+        // Moving up has to start at the top.
+        let up = mapping(&[(1, 2), (2, 3), (3, 4)]);
+        assert_eq!(nsid_moves(&up)?, [(3, 4), (2, 3), (1, 2)]);
+
+        // Namespaces already in place stay, the others move around them.
+        let mixed = mapping(&[(1, 1), (5, 2), (6, 3), (17, 4)]);
+        assert_eq!(nsid_moves(&mixed)?, [(5, 2), (6, 3), (17, 4)]);
+
+        let interleaved = mapping(&[(2, 3), (3, 5), (5, 1), (7, 2)]);
+        for map in [down, up, mixed, interleaved] {
+            let moves = nsid_moves(&map)?;
+            assert_eq!(apply_moves(&map, &moves), map);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_nsid_moves_cycles() -> Result<()> {
+        // A swap needs a temporary NSID, the lowest unused by either side.
+        let swap = mapping(&[(1, 2), (2, 1)]);
+        assert_eq!(nsid_moves(&swap)?, [(1, 3), (2, 1), (3, 2)]);
+
+        let rotation = mapping(&[(1, 3), (3, 4), (4, 1), (2, 5), (5, 2)]);
+        let moves = nsid_moves(&rotation)?;
+        assert_eq!(apply_moves(&rotation, &moves), rotation);
+        // One temporary NSID per cycle.
+        assert_eq!(moves.len(), 7);
+        assert!(moves.iter().all(|&(_, new)| new <= 6));
+
+        assert_eq!(nsid_moves(&mapping(&[(1, 1), (2, 2)]))?, []);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_namespace_renumbering() -> Result<()> {
+        let namespace = |i: u32| Namespace {
+            enabled: i % 2 == 1,
+            device_path: format!("/dev/loop{i}").into(),
+            device_spec: None,
+            device_uuid: Some(Uuid::from_u128(u128::from(i))),
+            device_nguid: (i == 17).then(|| Uuid::from_u128(0xbeef)),
+        };
+        let mut state = State::default();
+        state.subsystems.insert(
+            SUB.to_string(),
+            Subsystem {
+                namespaces: [3, 17, 58].into_iter().map(|i| (i, namespace(i))).collect(),
+                ..Default::default()
+            },
+        );
+
+        let renumbering = state.plan_namespace_renumbering(SUB, 1)?;
+        assert_eq!(renumbering.mapping, mapping(&[(3, 1), (17, 2), (58, 3)]));
+        assert_eq!(renumbering.deltas.len(), 3);
+        assert_eq!(
+            renumbering.deltas[0],
+            StateDelta::UpdateSubsystem(
+                SUB.to_string(),
+                vec![
+                    SubsystemDelta::RemoveNamespace(3),
+                    SubsystemDelta::AddNamespace(1, namespace(3)),
+                ]
+            )
+        );
+
+        // Devices and identities move along unchanged.
+        let mut renumbered = state.clone();
+        renumbered.apply_deltas(&renumbering.deltas)?;
+        assert_eq!(
+            renumbered.subsystems[SUB].namespaces,
+            BTreeMap::from([(1, namespace(3)), (2, namespace(17)), (3, namespace(58))])
+        );
+
+        // Already sequential.
+        let again = renumbered.plan_namespace_renumbering(SUB, 1)?;
+        assert!(again.is_noop());
+        assert!(again.deltas.is_empty());
+
+        // Overlapping the current NSIDs.
+        let renumbering = state.plan_namespace_renumbering(SUB, 17)?;
+        let mut shifted = state.clone();
+        shifted.apply_deltas(&renumbering.deltas)?;
+        assert_eq!(
+            shifted.subsystems[SUB]
+                .namespaces
+                .keys()
+                .collect::<Vec<_>>(),
+            [&17, &18, &19]
+        );
+
+        assert!(matches!(
+            state.plan_namespace_renumbering("nqn.2023-11.sh.tty:gone", 1),
+            Err(Error::NoSuchSubsystem(_))
+        ));
+        Ok(())
+    }
+}
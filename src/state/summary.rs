@@ -0,0 +1,366 @@
+use super::types::{State, Subsystem};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Namespace counts and exported capacity of a subsystem, see `Subsystem::namespace_summary`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct NamespaceSummary {
+    pub enabled: usize,
+    pub disabled: usize,
+    /// Sum of the sizes of the backing devices in bytes, each counted once even if several
+    /// namespaces use it, without those of `missing`.
+    pub capacity: u64,
+    /// NSIDs of the namespaces whose backing device could not be found.
+    pub missing: Vec<u32>,
+}
+
+impl Subsystem {
+    /// Count the enabled and disabled namespaces and add up the sizes of their backing devices.
+    ///
+    /// `device_size` gives the size of a device in bytes, or `None` if it doesn't exist, like
+    /// `helpers::device_size`. It is called once per device, even if several namespaces use it.
+    pub fn namespace_summary<F>(&self, mut device_size: F) -> NamespaceSummary
+    where
+        F: FnMut(&Path) -> Option<u64>,
+    {
+        let mut summary = NamespaceSummary::default();
+        let mut sizes = BTreeMap::new();
+        for (&nsid, ns) in &self.namespaces {
+            if ns.enabled {
+                summary.enabled += 1;
+            } else {
+                summary.disabled += 1;
+            }
+            let first = !sizes.contains_key(ns.device_path.as_path());
+            let size = *sizes
+                .entry(ns.device_path.as_path())
+                .or_insert_with(|| device_size(&ns.device_path));
+            match size {
+                Some(size) if first => summary.capacity = summary.capacity.saturating_add(size),
+                Some(_) => {}
+                None => summary.missing.push(nsid),
+            }
+        }
+        summary
+    }
+}
+
+/// Counts and exported capacity of a whole state, see `State::stats`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct StateStats {
+    /// Sum of the sizes of all backing devices, each counted once even if several subsystems
+    /// export it.
+    pub capacity: u64,
+    pub enabled_namespaces: usize,
+    pub disabled_namespaces: usize,
+    /// Namespaces whose backing device could not be found, left out of the capacity.
+    pub missing_namespaces: usize,
+    pub subsystems: BTreeMap<String, NamespaceSummary>,
+    /// Number of ports by transport, like `tcp`.
+    pub ports: BTreeMap<String, usize>,
+    /// Number of different hosts allowed on any subsystem.
+    pub hosts: usize,
+    /// Number of subsystems allowing any host.
+    pub open_subsystems: usize,
+}
+
+/// What a host can reach, see `State::capacity_by_host`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct HostReach {
+    pub subsystems: BTreeSet<String>,
+    /// Number of enabled namespaces on `subsystems`.
+    pub namespaces: usize,
+    /// Sum of the sizes of the backing devices of those namespaces, each counted once.
+    pub capacity: u64,
+    /// Namespaces whose backing device could not be found, left out of the capacity.
+    pub missing_namespaces: usize,
+}
+
+/// The capacity each host can reach, see `State::capacity_by_host`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct HostCapacity {
+    /// By host NQN, for every host allowed on any subsystem.
+    pub hosts: BTreeMap<String, HostReach>,
+    /// What any host can reach, through the subsystems allowing any host.
+    pub any_host: HostReach,
+}
+
+impl State {
+    /// Count the namespaces, ports and hosts, and add up the capacity of all subsystems.
+    ///
+    /// `device_size` is used like with `Subsystem::namespace_summary`, and called once per
+    /// device even if several subsystems use it.
+    pub fn stats<F>(&self, device_size: F) -> StateStats
+    where
+        F: FnMut(&Path) -> Option<u64>,
+    {
+        let mut device_size = cached(device_size);
+        let mut stats = StateStats::default();
+        let mut hosts = BTreeSet::new();
+        let mut devices = BTreeSet::new();
+        for (nqn, sub) in &self.subsystems {
+            let summary = sub.namespace_summary(&mut device_size);
+            for ns in sub.namespaces.values() {
+                if devices.insert(ns.device_path.as_path()) {
+                    let size = device_size(&ns.device_path).unwrap_or_default();
+                    stats.capacity = stats.capacity.saturating_add(size);
+                }
+            }
+            stats.enabled_namespaces += summary.enabled;
+            stats.disabled_namespaces += summary.disabled;
+            stats.missing_namespaces += summary.missing.len();
+            stats.subsystems.insert(nqn.clone(), summary);
+            if sub.allowed_hosts.is_empty() {
+                stats.open_subsystems += 1;
+            }
+            hosts.extend(&sub.allowed_hosts);
+        }
+        stats.hosts = hosts.len();
+        for port in self.ports.values() {
+            *stats
+                .ports
+                .entry(port.port_type.trtype().to_string())
+                .or_default() += 1;
+        }
+        stats
+    }
+
+    /// The subsystems, enabled namespaces and capacity each allowed host can reach.
+    ///
+    /// Hosts reach the subsystems they are allowed on and those allowing any host, as long as
+    /// the subsystem is on a port. Disabled namespaces are left out, as no host can use them.
+    pub fn capacity_by_host<F>(&self, device_size: F) -> HostCapacity
+    where
+        F: FnMut(&Path) -> Option<u64>,
+    {
+        let mut device_size = cached(device_size);
+        let provided: BTreeSet<&String> = self
+            .ports
+            .values()
+            .flat_map(|port| &port.subsystems)
+            .collect();
+        // The subsystems each host reaches, then what is on them.
+        let mut any_host = BTreeSet::new();
+        let mut hosts: BTreeMap<&String, BTreeSet<&String>> = BTreeMap::new();
+        for (nqn, sub) in &self.subsystems {
+            // Known hosts are listed even if they can't reach anything.
+            for host in &sub.allowed_hosts {
+                let reached = hosts.entry(host).or_default();
+                if provided.contains(nqn) {
+                    reached.insert(nqn);
+                }
+            }
+            if sub.allowed_hosts.is_empty() && provided.contains(nqn) {
+                any_host.insert(nqn);
+            }
+        }
+        let mut reach = |subsystems: BTreeSet<&String>| {
+            let mut reach = HostReach::default();
+            let mut devices = BTreeSet::new();
+            for nqn in subsystems {
+                let namespaces = self.subsystems[nqn].namespaces.values();
+                for ns in namespaces.filter(|ns| ns.enabled) {
+                    reach.namespaces += 1;
+                    match device_size(&ns.device_path) {
+                        Some(size) if devices.insert(&ns.device_path) => {
+                            reach.capacity = reach.capacity.saturating_add(size);
+                        }
+                        Some(_) => {}
+                        None => reach.missing_namespaces += 1,
+                    }
+                }
+                reach.subsystems.insert(nqn.clone());
+            }
+            reach
+        };
+        HostCapacity {
+            hosts: hosts
+                .into_iter()
+                .map(|(host, reached)| (host.clone(), reach(&reached | &any_host)))
+                .collect(),
+            any_host: reach(any_host),
+        }
+    }
+}
+
+/// `device_size`, called only once per device.
+fn cached<F>(mut device_size: F) -> impl FnMut(&Path) -> Option<u64>
+where
+    F: FnMut(&Path) -> Option<u64>,
+{
+    let mut sizes: BTreeMap<PathBuf, Option<u64>> = BTreeMap::new();
+    move |path| {
+        *sizes
+            .entry(path.to_path_buf())
+            .or_insert_with(|| device_size(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Namespace, Port, PortType};
+
+    fn namespace(enabled: bool, device: &str) -> Namespace {
+        Namespace {
+            enabled,
+            device_path: device.into(),
+            device_spec: None,
+            device_uuid: None,
+            device_nguid: None,
+        }
+    }
+
+    #[test]
+    fn test_namespace_summary() {
+        let sub = Subsystem {
+            namespaces: BTreeMap::from([
+                (1, namespace(true, "/dev/loop0")),
+                (2, namespace(false, "/dev/loop1")),
+                (3, namespace(true, "/dev/missing")),
+                (4, namespace(true, "/dev/loop0")),
+            ]),
+            ..Default::default()
+        };
+        let mut resolved = Vec::new();
+        let summary = sub.namespace_summary(|path| {
+            resolved.push(path.to_path_buf());
+            match path.to_str()? {
+                "/dev/loop0" => Some(1 << 30),
+                "/dev/loop1" => Some(512 << 20),
+                _ => None,
+            }
+        });
+        assert_eq!(
+            summary,
+            NamespaceSummary {
+                enabled: 3,
+                disabled: 1,
+                // loop0 only counts once.
+                capacity: (1 << 30) + (512 << 20),
+                missing: vec![3],
+            }
+        );
+        // Each device is resolved only once.
+        assert_eq!(
+            resolved,
+            ["/dev/loop0", "/dev/loop1", "/dev/missing"].map(PathBuf::from)
+        );
+
+        assert_eq!(
+            Subsystem::default().namespace_summary(|_| unreachable!()),
+            NamespaceSummary::default()
+        );
+    }
+
+    fn state() -> State {
+        let mut state = State::default();
+        state.subsystems.insert(
+            "nqn.2023-11.sh.tty:a".to_string(),
+            Subsystem {
+                allowed_hosts: BTreeSet::from(["nqn.host1".to_string()]),
+                namespaces: BTreeMap::from([
+                    (1, namespace(true, "/dev/loop0")),
+                    (2, namespace(false, "/dev/loop1")),
+                ]),
+                ..Default::default()
+            },
+        );
+        state.subsystems.insert(
+            "nqn.2023-11.sh.tty:b".to_string(),
+            Subsystem {
+                namespaces: BTreeMap::from([
+                    (1, namespace(true, "/dev/loop0")),
+                    (2, namespace(true, "/dev/missing")),
+                ]),
+                ..Default::default()
+            },
+        );
+        state.subsystems.insert(
+            "nqn.2023-11.sh.tty:unprovided".to_string(),
+            Subsystem {
+                allowed_hosts: BTreeSet::from(["nqn.host1".to_string(), "nqn.host2".to_string()]),
+                namespaces: BTreeMap::from([(1, namespace(true, "/dev/loop1"))]),
+                ..Default::default()
+            },
+        );
+        let subsystems = |nqns: &[&str]| nqns.iter().map(ToString::to_string).collect();
+        state.ports.insert(
+            1,
+            Port::new(
+                PortType::Tcp("192.0.2.1:4420".parse().unwrap()),
+                subsystems(&["nqn.2023-11.sh.tty:a", "nqn.2023-11.sh.tty:b"]),
+            ),
+        );
+        state.ports.insert(
+            2,
+            Port::new(PortType::Loop, subsystems(&["nqn.2023-11.sh.tty:b"])),
+        );
+        state
+            .ports
+            .insert(3, Port::new(PortType::Loop, BTreeSet::new()));
+        state
+    }
+
+    fn device_size(path: &Path) -> Option<u64> {
+        match path.to_str()? {
+            "/dev/loop0" => Some(1 << 30),
+            "/dev/loop1" => Some(512 << 20),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_state_stats() {
+        let mut resolved = Vec::new();
+        let stats = state().stats(|path| {
+            resolved.push(path.to_path_buf());
+            device_size(path)
+        });
+        // loop0 is on both subsystems, but only counts once.
+        assert_eq!(stats.capacity, (1 << 30) + (512 << 20));
+        assert_eq!(stats.enabled_namespaces, 4);
+        assert_eq!(stats.disabled_namespaces, 1);
+        assert_eq!(stats.missing_namespaces, 1);
+        assert_eq!(stats.subsystems["nqn.2023-11.sh.tty:b"].missing, [2]);
+        assert_eq!(
+            stats.ports,
+            BTreeMap::from([("loop".to_string(), 2), ("tcp".to_string(), 1)])
+        );
+        assert_eq!(stats.hosts, 2);
+        assert_eq!(stats.open_subsystems, 1);
+        // Each device is resolved only once, across subsystems.
+        assert_eq!(resolved.len(), 3);
+    }
+
+    #[test]
+    fn test_capacity_by_host() {
+        let capacity = state().capacity_by_host(device_size);
+        // Only the subsystem allowing any host is on a port, and has a missing device.
+        assert_eq!(
+            capacity.any_host,
+            HostReach {
+                subsystems: BTreeSet::from(["nqn.2023-11.sh.tty:b".to_string()]),
+                namespaces: 2,
+                capacity: 1 << 30,
+                missing_namespaces: 1,
+            }
+        );
+        // Without the disabled namespace, and the subsystem which isn't on any port.
+        assert_eq!(
+            capacity.hosts["nqn.host1"],
+            HostReach {
+                subsystems: BTreeSet::from([
+                    "nqn.2023-11.sh.tty:a".to_string(),
+                    "nqn.2023-11.sh.tty:b".to_string()
+                ]),
+                namespaces: 3,
+                // Both subsystems export loop0.
+                capacity: 1 << 30,
+                missing_namespaces: 1,
+            }
+        );
+        assert_eq!(capacity.hosts["nqn.host2"], capacity.any_host);
+    }
+}
@@ -0,0 +1,202 @@
+// The whole state as an indented tree, for reading it at once.
+
+use super::types::{Namespace, Port, State, Subsystem};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Prints a state as tree of its ports, subsystems with their namespaces, and hosts.
+///
+/// Each level is indented by a tab more. Inactive objects are marked as such.
+#[derive(Debug, Clone, Copy)]
+pub struct StateTree<'a>(pub &'a State);
+
+impl State {
+    /// The state as indented tree, see `StateTree`.
+    #[must_use]
+    pub const fn tree(&self) -> StateTree<'_> {
+        StateTree(self)
+    }
+}
+
+impl fmt::Display for StateTree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.0;
+        writeln!(f, "Ports: {}", state.ports.len())?;
+        for (id, port) in &state.ports {
+            write_port(f, *id, port)?;
+        }
+        writeln!(f, "Subsystems: {}", state.subsystems.len())?;
+        for (nqn, sub) in &state.subsystems {
+            write_subsystem(f, nqn, sub)?;
+        }
+
+        let mut hosts: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        for (nqn, sub) in &state.subsystems {
+            for host in &sub.allowed_hosts {
+                hosts.entry(host).or_default().insert(nqn);
+            }
+        }
+        writeln!(f, "Hosts: {}", hosts.len())?;
+        for (host, subsystems) in hosts {
+            writeln!(f, "\t{host}")?;
+            for nqn in subsystems {
+                writeln!(f, "\t\t{nqn}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_port(f: &mut fmt::Formatter<'_>, id: u16, port: &Port) -> fmt::Result {
+    writeln!(
+        f,
+        "\t{id}: {}{}",
+        port.port_type,
+        inactive(port.is_active())
+    )?;
+    if let Some(interface) = &port.interface {
+        writeln!(f, "\t\tInterface: {}", interface.name)?;
+    }
+    write_labels(f, &port.labels)?;
+    if !port.subsystems.is_empty() {
+        writeln!(f, "\t\tSubsystems:")?;
+        for nqn in &port.subsystems {
+            writeln!(f, "\t\t\t{nqn}")?;
+        }
+    }
+    Ok(())
+}
+
+fn write_subsystem(f: &mut fmt::Formatter<'_>, nqn: &str, sub: &Subsystem) -> fmt::Result {
+    write!(f, "\t{nqn}")?;
+    if let Some(alias) = &sub.alias {
+        write!(f, " (@{alias})")?;
+    }
+    writeln!(f, "{}", inactive(sub.is_active()))?;
+    if let Some(model) = &sub.model {
+        writeln!(f, "\t\tModel: {model}")?;
+    }
+    if let Some(serial) = &sub.serial {
+        writeln!(f, "\t\tSerial: {serial}")?;
+    }
+    if let Some(qid_max) = sub.qid_max {
+        writeln!(f, "\t\tMaximum I/O Queues: {qid_max}")?;
+    }
+    write_labels(f, &sub.labels)?;
+    if sub.allowed_hosts.is_empty() {
+        writeln!(f, "\t\tAllowed Hosts: any")?;
+    } else {
+        writeln!(f, "\t\tAllowed Hosts:")?;
+        for host in &sub.allowed_hosts {
+            writeln!(f, "\t\t\t{host}")?;
+        }
+    }
+    if !sub.namespaces.is_empty() {
+        writeln!(f, "\t\tNamespaces:")?;
+        for (nsid, ns) in &sub.namespaces {
+            write_namespace(f, *nsid, ns)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_namespace(f: &mut fmt::Formatter<'_>, nsid: u32, ns: &Namespace) -> fmt::Result {
+    write!(f, "\t\t\t{nsid}: ")?;
+    match &ns.device_spec {
+        Some(spec) => write!(f, "{spec}")?,
+        None => write!(f, "{}", ns.device_path.display())?,
+    }
+    writeln!(f, "{}", if ns.enabled { "" } else { " (disabled)" })?;
+    if let Some(uuid) = ns.device_uuid {
+        writeln!(f, "\t\t\t\tUUID: {uuid}")?;
+    }
+    if let Some(nguid) = ns.device_nguid {
+        writeln!(f, "\t\t\t\tNGUID: {nguid}")?;
+    }
+    Ok(())
+}
+
+fn write_labels(f: &mut fmt::Formatter<'_>, labels: &BTreeMap<String, String>) -> fmt::Result {
+    if labels.is_empty() {
+        return Ok(());
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+    writeln!(f, "\t\tLabels: {}", labels.join(" "))
+}
+
+const fn inactive(active: bool) -> &'static str {
+    if active {
+        ""
+    } else {
+        " (inactive)"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{PortType, StateBuilder};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_state_tree() {
+        let mut state = StateBuilder::new()
+            .subsystem("nqn.2023-11.sh.tty:a")
+            .alias("a")
+            .model("Model")
+            .label("env", "prod")
+            .host("nqn.2023-11.sh.tty:client")
+            .namespace(1, "/dev/loop0")
+            .subsystem("nqn.2023-11.sh.tty:b")
+            .host("nqn.2023-11.sh.tty:client")
+            .port(1, PortType::Tcp("192.0.2.1:4420".parse().unwrap()))
+            .with_subsystem("nqn.2023-11.sh.tty:a")
+            .port(2, PortType::Loop)
+            .build()
+            .unwrap();
+        let ns = state
+            .subsystems
+            .get_mut("nqn.2023-11.sh.tty:a")
+            .unwrap()
+            .namespaces
+            .get_mut(&1)
+            .unwrap();
+        ns.device_uuid = Some(Uuid::from_u128(1));
+        ns.enabled = false;
+        state.ports.get_mut(&2).unwrap().active = Some(false);
+
+        assert_eq!(
+            state.tree().to_string(),
+            "\
+Ports: 2
+\t1: tcp://192.0.2.1:4420
+\t\tSubsystems:
+\t\t\tnqn.2023-11.sh.tty:a
+\t2: loop:// (inactive)
+Subsystems: 2
+\tnqn.2023-11.sh.tty:a (@a)
+\t\tModel: Model
+\t\tLabels: env=prod
+\t\tAllowed Hosts:
+\t\t\tnqn.2023-11.sh.tty:client
+\t\tNamespaces:
+\t\t\t1: /dev/loop0 (disabled)
+\t\t\t\tUUID: 00000000-0000-0000-0000-000000000001
+\tnqn.2023-11.sh.tty:b
+\t\tAllowed Hosts:
+\t\t\tnqn.2023-11.sh.tty:client
+Hosts: 1
+\tnqn.2023-11.sh.tty:client
+\t\tnqn.2023-11.sh.tty:a
+\t\tnqn.2023-11.sh.tty:b
+"
+        );
+        assert_eq!(
+            State::default().tree().to_string(),
+            "Ports: 0\nSubsystems: 0\nHosts: 0\n"
+        );
+    }
+}
@@ -1,12 +1,16 @@
 // Define the high level datastructures.
 // This is *purely* for representing the state.
 
-use crate::errors::Error;
-use anyhow::Context;
-use serde::{Deserialize, Serialize};
+use crate::errors::{Context, Error, Result};
+use crate::helpers::{
+    interface_addresses, parse_socket_addr, parse_trsvcid, resolve_host, select_global_address,
+    DeviceSpec,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     collections::{BTreeMap, BTreeSet},
-    net::SocketAddr,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::PathBuf,
     str::FromStr,
 };
@@ -16,28 +20,331 @@ use uuid::Uuid;
 pub struct State {
     pub subsystems: BTreeMap<String, Subsystem>,
     pub ports: BTreeMap<u16, Port>,
+    /// DH-HMAC-CHAP keys to give hosts, by host NQN. Only ever read from state files, gathered
+    /// states leave keys out, and changes to hosts' keys are no state changes.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, HostKeys>,
+}
+
+impl State {
+    /// Overlay `other` on top of this state.
+    ///
+    /// Objects only present in either are kept, objects present in both are combined
+    /// with the settings of `other` taking precedence. Nothing is ever removed.
+    pub fn merge(&mut self, other: &Self) {
+        for (nqn, sub) in &other.subsystems {
+            match self.subsystems.get_mut(nqn) {
+                Some(existing) => existing.merge(sub),
+                None => {
+                    self.subsystems.insert(nqn.clone(), sub.clone());
+                }
+            }
+        }
+        for (id, port) in &other.ports {
+            match self.ports.get_mut(id) {
+                Some(existing) => {
+                    if port.active.is_some() {
+                        existing.active = port.active;
+                    }
+                    existing.port_type = port.port_type;
+                    existing.adrfam = port.adrfam;
+                    existing.interface.clone_from(&port.interface);
+                    existing
+                        .labels
+                        .extend(port.labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+                    existing.subsystems.extend(port.subsystems.iter().cloned());
+                }
+                None => {
+                    self.ports.insert(*id, port.clone());
+                }
+            }
+        }
+        for (nqn, keys) in &other.hosts {
+            let existing = self.hosts.entry(nqn.clone()).or_default();
+            if keys.dhchap_key.is_some() {
+                existing.dhchap_key.clone_from(&keys.dhchap_key);
+            }
+            if keys.dhchap_ctrl_key.is_some() {
+                existing.dhchap_ctrl_key.clone_from(&keys.dhchap_ctrl_key);
+            }
+        }
+    }
+
+    /// Resolve the addresses of ports defined by a network interface.
+    ///
+    /// Afterwards, these ports have the interface's current global address, as if it had been
+    /// given literally. Gathered states never have any such ports.
+    pub fn resolve_interfaces(&mut self) -> Result<()> {
+        for (id, port) in &mut self.ports {
+            if let Some(interface) = port.interface.take() {
+                port.port_type = interface
+                    .resolve(port.port_type)
+                    .with_context(|| format!("Failed to resolve address of port {id}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve the backing devices of namespaces defined by the filesystem on them.
+    ///
+    /// Afterwards, these namespaces have the path of the device, as if it had been given
+    /// literally. Gathered states never have any such namespaces.
+    pub fn resolve_devices(&mut self) -> Result<()> {
+        let mut resolved = BTreeMap::new();
+        for (nqn, sub) in &mut self.subsystems {
+            for (nsid, ns) in &mut sub.namespaces {
+                let Some(spec) = ns.device_spec.take() else {
+                    continue;
+                };
+                // Several namespaces may use the same device, like the paths of a multipath one.
+                if !resolved.contains_key(&spec) {
+                    let path = spec.resolve().with_context(|| {
+                        format!("Failed to resolve device of namespace {nsid} of {nqn}")
+                    })?;
+                    resolved.insert(spec.clone(), path);
+                }
+                ns.device_path.clone_from(&resolved[&spec]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve everything only given indirectly, see `resolve_interfaces` and `resolve_devices`.
+    ///
+    /// Inactive subsystems and ports are removed first, so nothing is resolved for them.
+    pub fn resolve(&mut self) -> Result<()> {
+        self.remove_inactive();
+        self.resolve_interfaces()?;
+        self.resolve_devices()
+    }
+
+    /// Remove all ports, keeping the subsystems.
+    pub fn clear_ports(&mut self) {
+        self.ports.clear();
+    }
+
+    /// Remove inactive subsystems and ports, as if they weren't there.
+    ///
+    /// Removed subsystems are also removed from the ports they were on. The others are left
+    /// as if never marked active, like gathered ones.
+    pub fn remove_inactive(&mut self) {
+        self.subsystems.retain(|_, sub| sub.is_active());
+        self.ports.retain(|_, port| port.is_active());
+        for sub in self.subsystems.values_mut() {
+            sub.active = None;
+        }
+        for port in self.ports.values_mut() {
+            port.active = None;
+            port.subsystems
+                .retain(|nqn| self.subsystems.contains_key(nqn));
+        }
+    }
+
+    /// Remove all subsystems, keeping the ports without any subsystems.
+    pub fn clear_subsystems(&mut self) {
+        self.subsystems.clear();
+        for port in self.ports.values_mut() {
+            port.subsystems.clear();
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Subsystem {
+    /// Whether the subsystem is configured at all, active unless set. Inactive subsystems stay
+    /// in state files, but are left out when restoring them, see `State::remove_inactive`.
+    /// Only stored by nvmetcfg, gathered subsystems never have it set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    /// Short name to refer to the subsystem by, as `@alias`.
+    /// Only stored by nvmetcfg, the kernel knows nothing about it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// User-defined metadata, like the owner. Only stored by nvmetcfg, like the alias.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
     pub model: Option<String>,
     pub serial: Option<String>,
+    /// Maximum number of I/O queues per controller (attr_qid_max), which older kernels lack.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qid_max: Option<u32>,
     pub allowed_hosts: BTreeSet<String>,
     pub namespaces: BTreeMap<u32, Namespace>,
 }
 
+/// The DH-HMAC-CHAP keys of a host, see `State::hosts`.
+#[derive(Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostKeys {
+    /// The key the host authenticates itself with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dhchap_key: Option<String>,
+    /// The key the controller authenticates itself to the host with, for mutual authentication.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dhchap_ctrl_key: Option<String>,
+}
+
+impl fmt::Debug for HostKeys {
+    // Keys must not end up in logs.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = |key: &Option<String>| key.as_ref().map(|_| "<redacted>");
+        f.debug_struct("HostKeys")
+            .field("dhchap_key", &redacted(&self.dhchap_key))
+            .field("dhchap_ctrl_key", &redacted(&self.dhchap_ctrl_key))
+            .finish()
+    }
+}
+
+impl Subsystem {
+    /// Whether the subsystem is configured at all, see `Subsystem::active`.
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.active != Some(false)
+    }
+
+    /// Overlay `other` on top of this subsystem, see `State::merge`.
+    pub fn merge(&mut self, other: &Self) {
+        if other.active.is_some() {
+            self.active = other.active;
+        }
+        if other.alias.is_some() {
+            self.alias.clone_from(&other.alias);
+        }
+        self.labels
+            .extend(other.labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if other.model.is_some() {
+            self.model.clone_from(&other.model);
+        }
+        if other.serial.is_some() {
+            self.serial.clone_from(&other.serial);
+        }
+        if other.qid_max.is_some() {
+            self.qid_max = other.qid_max;
+        }
+        self.allowed_hosts
+            .extend(other.allowed_hosts.iter().cloned());
+        for (nsid, ns) in &other.namespaces {
+            self.namespaces.insert(*nsid, ns.clone());
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "NamespaceRepr", into = "NamespaceRepr")]
 pub struct Namespace {
     pub enabled: bool,
+    /// Path of the backing device. For namespaces defined by the filesystem on their device,
+    /// this is empty until resolved, see `State::resolve_devices`.
     pub device_path: PathBuf,
+    /// Filesystem to find the backing device by when applying.
+    /// Only stored by nvmetcfg, the kernel knows nothing about it.
+    pub device_spec: Option<DeviceSpec>,
     pub device_uuid: Option<Uuid>,
     pub device_nguid: Option<Uuid>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// How namespaces are written in state files: with the path of their device, or the
+/// filesystem on it as `device: UUID=<uuid>` or `device: LABEL=<label>`.
+#[derive(Serialize, Deserialize)]
+#[serde(
+    untagged,
+    expecting = "namespace with enabled and either device_path or device"
+)]
+enum NamespaceRepr {
+    Spec {
+        enabled: bool,
+        device: String,
+        device_uuid: Option<Uuid>,
+        device_nguid: Option<Uuid>,
+    },
+    Path {
+        enabled: bool,
+        device_path: PathBuf,
+        device_uuid: Option<Uuid>,
+        device_nguid: Option<Uuid>,
+    },
+}
+
+impl TryFrom<NamespaceRepr> for Namespace {
+    type Error = Error;
+
+    fn try_from(repr: NamespaceRepr) -> Result<Self> {
+        Ok(match repr {
+            NamespaceRepr::Spec {
+                enabled,
+                device,
+                device_uuid,
+                device_nguid,
+            } => Self {
+                enabled,
+                device_path: PathBuf::new(),
+                device_spec: Some(device.parse()?),
+                device_uuid,
+                device_nguid,
+            },
+            NamespaceRepr::Path {
+                enabled,
+                device_path,
+                device_uuid,
+                device_nguid,
+            } => Self {
+                enabled,
+                device_path,
+                device_spec: None,
+                device_uuid,
+                device_nguid,
+            },
+        })
+    }
+}
+
+impl From<Namespace> for NamespaceRepr {
+    fn from(ns: Namespace) -> Self {
+        match ns.device_spec {
+            Some(spec) => Self::Spec {
+                enabled: ns.enabled,
+                device: spec.to_string(),
+                device_uuid: ns.device_uuid,
+                device_nguid: ns.device_nguid,
+            },
+            None => Self::Path {
+                enabled: ns.enabled,
+                device_path: ns.device_path,
+                device_uuid: ns.device_uuid,
+                device_nguid: ns.device_nguid,
+            },
+        }
+    }
+}
+
+impl Namespace {
+    /// Whether this namespace fulfills `desired`.
+    /// Identifiers left unset in `desired` are satisfied by any value, as the kernel generates them.
+    #[must_use]
+    pub fn satisfies(&self, desired: &Self) -> bool {
+        self.enabled == desired.enabled
+            && self.device_path == desired.device_path
+            && (desired.device_uuid.is_none() || self.device_uuid == desired.device_uuid)
+            && (desired.device_nguid.is_none() || self.device_nguid == desired.device_nguid)
+    }
+}
+
+#[derive(Debug, Clone, Eq, Serialize, Deserialize)]
+#[serde(from = "PortRepr", into = "PortRepr")]
 pub struct Port {
-    #[serde(flatten)]
+    /// Whether the port is configured at all, like `Subsystem::active`.
+    pub active: Option<bool>,
+    /// Transport and address. For ports defined by an interface, this has the unspecified
+    /// address until resolved, see `State::resolve_interfaces`.
     pub port_type: PortType,
+    /// Network interface to take the address from when applying.
+    /// Only stored by nvmetcfg, the kernel knows nothing about it.
+    pub interface: Option<PortInterface>,
+    /// Address family as read from addr_adrfam.
+    /// `None` if not known, then it is the one of the address, see `PortType::adrfam`.
+    /// Only gathered, never written to state files.
+    pub adrfam: Option<PortAddrFamily>,
+    /// User-defined metadata, like the owner. Only stored by nvmetcfg.
+    pub labels: BTreeMap<String, String>,
     pub subsystems: BTreeSet<String>,
 }
 
@@ -45,10 +352,308 @@ impl Port {
     #[must_use]
     pub const fn new(port_type: PortType, subsystems: BTreeSet<String>) -> Self {
         Self {
+            active: None,
             port_type,
+            interface: None,
+            adrfam: None,
+            labels: BTreeMap::new(),
             subsystems,
         }
     }
+
+    /// The address family of the port: the one read from the kernel, if known, or else
+    /// the one of its address.
+    #[must_use]
+    pub fn adrfam(&self) -> Option<PortAddrFamily> {
+        self.adrfam.or_else(|| self.port_type.adrfam())
+    }
+
+    /// Whether the port is configured at all, see `Port::active`.
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.active != Some(false)
+    }
+}
+
+/// Ports are equal with the same address family, whether it is known or from the address.
+impl PartialEq for Port {
+    fn eq(&self, other: &Self) -> bool {
+        self.active == other.active
+            && self.port_type == other.port_type
+            && self.adrfam() == other.adrfam()
+            && self.interface == other.interface
+            && self.labels == other.labels
+            && self.subsystems == other.subsystems
+    }
+}
+
+/// A network interface whose current global address a port listens on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortInterface {
+    pub name: String,
+    /// Address family to use if the interface has global addresses of both.
+    pub prefer: AddrFamily,
+}
+
+impl PortInterface {
+    #[must_use]
+    pub fn new(name: &str, prefer: AddrFamily) -> Self {
+        Self {
+            name: name.to_string(),
+            prefer,
+        }
+    }
+
+    /// The unresolved port type listening on `port`, see `Port::port_type`.
+    pub fn unresolved(&self, trtype: &str, port: u16) -> Result<PortType> {
+        let addr = SocketAddr::new(self.prefer.unspecified(), port);
+        match trtype {
+            "tcp" => Ok(PortType::Tcp(addr)),
+            "rdma" => Ok(PortType::Rdma(addr)),
+            other => Err(Error::UnsupportedInterfaceTransport(other.to_string())),
+        }
+    }
+
+    /// Replace the address of `port_type` with the current global address of the interface.
+    pub fn resolve(&self, port_type: PortType) -> Result<PortType> {
+        let addrs = interface_addresses(&self.name)?;
+        let ip = select_global_address(&addrs, self.prefer == AddrFamily::Ipv6)
+            .ok_or_else(|| Error::NoInterfaceAddress(self.name.clone()))?;
+        match port_type {
+            PortType::Tcp(addr) => Ok(PortType::Tcp(SocketAddr::new(ip, addr.port()))),
+            PortType::Rdma(addr) => Ok(PortType::Rdma(SocketAddr::new(ip, addr.port()))),
+            other => Err(Error::UnsupportedInterfaceTransport(
+                other.trtype().to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddrFamily {
+    #[default]
+    Ipv4,
+    Ipv6,
+}
+
+impl AddrFamily {
+    #[must_use]
+    pub const fn unspecified(self) -> IpAddr {
+        match self {
+            Self::Ipv4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            Self::Ipv6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        }
+    }
+
+    /// The family of `ip`.
+    #[must_use]
+    pub const fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Self::Ipv4,
+            IpAddr::V6(_) => Self::Ipv6,
+        }
+    }
+
+    /// The first of `addrs` of this family, like out of those of a dual-stack host name.
+    #[must_use]
+    pub fn select(self, addrs: &[SocketAddr]) -> Option<SocketAddr> {
+        addrs
+            .iter()
+            .find(|addr| Self::of(addr.ip()) == self)
+            .copied()
+    }
+
+    const fn is_default(&self) -> bool {
+        matches!(self, Self::Ipv4)
+    }
+}
+
+/// The family as written to addr_adrfam.
+impl fmt::Display for AddrFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ipv4 => write!(f, "ipv4"),
+            Self::Ipv6 => write!(f, "ipv6"),
+        }
+    }
+}
+
+/// The family of a port address as in addr_adrfam: an IP one, or one of the other transports.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PortAddrFamily {
+    Ip(AddrFamily),
+    Fc,
+    Ib,
+}
+
+impl From<AddrFamily> for PortAddrFamily {
+    fn from(family: AddrFamily) -> Self {
+        Self::Ip(family)
+    }
+}
+
+impl fmt::Display for PortAddrFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(family) => family.fmt(f),
+            Self::Fc => write!(f, "fc"),
+            Self::Ib => write!(f, "ib"),
+        }
+    }
+}
+
+impl FromStr for PortAddrFamily {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ipv4" => Ok(Self::Ip(AddrFamily::Ipv4)),
+            "ipv6" => Ok(Self::Ip(AddrFamily::Ipv6)),
+            "fc" => Ok(Self::Fc),
+            "ib" => Ok(Self::Ib),
+            _ => Err(Error::UnsupportedAdrFam(s.to_string())),
+        }
+    }
+}
+
+/// Transports a port defined by an interface can use.
+#[derive(Serialize, Deserialize)]
+enum InterfaceTransport {
+    Tcp,
+    Rdma,
+}
+
+/// How ports are written in state files: either with an address, or with an interface.
+/// The address can also be given as URI, which is only read, never written.
+#[derive(Serialize, Deserialize)]
+#[serde(
+    untagged,
+    expecting = "port with port_type and port_addr, port_type, interface and port, or address"
+)]
+enum PortRepr {
+    Interface {
+        port_type: InterfaceTransport,
+        interface: String,
+        port: u16,
+        #[serde(default, skip_serializing_if = "AddrFamily::is_default")]
+        prefer: AddrFamily,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        labels: BTreeMap<String, String>,
+        subsystems: BTreeSet<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        active: Option<bool>,
+    },
+    Address {
+        #[serde(flatten)]
+        port_type: PortType,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        labels: BTreeMap<String, String>,
+        subsystems: BTreeSet<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        active: Option<bool>,
+    },
+    Uri {
+        #[serde(serialize_with = "serialize_uri", deserialize_with = "deserialize_uri")]
+        address: PortType,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        labels: BTreeMap<String, String>,
+        subsystems: BTreeSet<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        active: Option<bool>,
+    },
+}
+
+fn serialize_uri<S: Serializer>(port_type: &PortType, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(port_type)
+}
+
+fn deserialize_uri<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PortType, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+impl From<PortRepr> for Port {
+    fn from(repr: PortRepr) -> Self {
+        match repr {
+            PortRepr::Interface {
+                port_type,
+                interface,
+                port,
+                prefer,
+                labels,
+                subsystems,
+                active,
+            } => {
+                let addr = SocketAddr::new(prefer.unspecified(), port);
+                Self {
+                    active,
+                    port_type: match port_type {
+                        InterfaceTransport::Tcp => PortType::Tcp(addr),
+                        InterfaceTransport::Rdma => PortType::Rdma(addr),
+                    },
+                    interface: Some(PortInterface::new(&interface, prefer)),
+                    adrfam: None,
+                    labels,
+                    subsystems,
+                }
+            }
+            PortRepr::Address {
+                port_type,
+                labels,
+                subsystems,
+                active,
+            }
+            | PortRepr::Uri {
+                address: port_type,
+                labels,
+                subsystems,
+                active,
+            } => Self {
+                active,
+                port_type,
+                interface: None,
+                adrfam: None,
+                labels,
+                subsystems,
+            },
+        }
+    }
+}
+
+impl From<Port> for PortRepr {
+    fn from(port: Port) -> Self {
+        let (transport, addr) = match port.port_type {
+            PortType::Tcp(addr) => (InterfaceTransport::Tcp, addr),
+            PortType::Rdma(addr) => (InterfaceTransport::Rdma, addr),
+            _ => {
+                return Self::Address {
+                    port_type: port.port_type,
+                    labels: port.labels,
+                    subsystems: port.subsystems,
+                    active: port.active,
+                }
+            }
+        };
+        match port.interface {
+            Some(interface) => Self::Interface {
+                port_type: transport,
+                interface: interface.name,
+                port: addr.port(),
+                prefer: interface.prefer,
+                labels: port.labels,
+                subsystems: port.subsystems,
+                active: port.active,
+            },
+            None => Self::Address {
+                port_type: port.port_type,
+                labels: port.labels,
+                subsystems: port.subsystems,
+                active: port.active,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -60,7 +665,137 @@ pub enum PortType {
     FibreChannel(FibreChannelAddr),
 }
 
+/// Port used by address URIs without one, as assigned to NVMe over Fabrics.
+pub const DEFAULT_NVMF_PORT: u16 = 4420;
+
+impl PortType {
+    /// The address family of the address, as written to addr_adrfam. Loop ports have none.
+    #[must_use]
+    pub const fn adrfam(&self) -> Option<PortAddrFamily> {
+        match self {
+            Self::Loop => None,
+            Self::Tcp(addr) | Self::Rdma(addr) => {
+                Some(PortAddrFamily::Ip(AddrFamily::of(addr.ip())))
+            }
+            Self::FibreChannel(_) => Some(PortAddrFamily::Fc),
+        }
+    }
+
+    /// Check that `adrfam`, as in addr_adrfam, is the family of the address.
+    ///
+    /// Loop ports have no address, so the kernel ignores whatever family they have.
+    pub fn check_adrfam(&self, adrfam: PortAddrFamily) -> Result<()> {
+        match self.adrfam() {
+            Some(family) if family != adrfam => Err(Error::AddrFamilyMismatch(
+                self.to_string(),
+                adrfam.to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The transport type, as written to addr_trtype.
+    #[must_use]
+    pub const fn trtype(&self) -> &'static str {
+        match self {
+            Self::Loop => "loop",
+            Self::Tcp(_) => "tcp",
+            Self::Rdma(_) => "rdma",
+            Self::FibreChannel(_) => "fc",
+        }
+    }
+}
+
+/// Parse an address URI like `tcp://192.0.2.1:4420`, `rdma://[fdff::1]:4420`,
+/// `fc://nn-0x1000000044001123:pn-0x2000000055001123` or `loop://`.
+///
+/// TCP and RDMA addresses without a port use `DEFAULT_NVMF_PORT`.
+impl FromStr for PortType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidPortUri(s.to_string());
+        let (scheme, addr) = s.split_once("://").ok_or_else(invalid)?;
+        match scheme.to_ascii_lowercase().as_str() {
+            "loop" if addr.is_empty() => Ok(Self::Loop),
+            "tcp" => Ok(Self::Tcp(parse_uri_addr(s, addr)?)),
+            "rdma" => Ok(Self::Rdma(parse_uri_addr(s, addr)?)),
+            "fc" => Ok(Self::FibreChannel(addr.parse()?)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// IPv6 addresses need brackets, as in URLs, so the port can be told apart.
+fn parse_uri_addr(uri: &str, addr: &str) -> Result<SocketAddr, Error> {
+    let invalid = || Error::InvalidPortUri(uri.to_string());
+    match parse_socket_addr(addr) {
+        Ok(addr) => return Ok(addr),
+        Err(err @ Error::InvalidServiceId(_)) => return Err(err),
+        Err(_) => {}
+    }
+    let ip = match addr.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')) {
+        Some(ip) => IpAddr::V6(ip.parse().map_err(|_| invalid())?),
+        None => IpAddr::V4(addr.parse().map_err(|_| invalid())?),
+    };
+    Ok(SocketAddr::new(ip, DEFAULT_NVMF_PORT))
+}
+
+/// Parse a TCP or RDMA address like `parse_socket_addr`, or resolve a host name with port
+/// like `storage.example:4420` right away.
+///
+/// With `family`, the address must be of that family, or the host name have one of it.
+/// Otherwise the first address the resolver gives is used.
+pub fn resolve_socket_addr(addr: &str, family: Option<AddrFamily>) -> Result<SocketAddr> {
+    let addrs = match parse_socket_addr(addr) {
+        Ok(addr) => vec![addr],
+        Err(Error::InvalidIPAddr(err)) => match addr.rsplit_once(':') {
+            Some((host, port)) if is_host_name(host) => resolve_host(host, parse_trsvcid(port)?)?,
+            _ => return Err(Error::InvalidIPAddr(err)),
+        },
+        Err(err) => return Err(err),
+    };
+    select_addr(addr, &addrs, family)
+}
+
+// Anything looking like a partial IPv4 address, like 192.0.2, is a typo rather than a name.
+fn is_host_name(host: &str) -> bool {
+    host.bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+        && host.bytes().any(|b| b.is_ascii_alphabetic())
+}
+
+fn select_addr(addr: &str, addrs: &[SocketAddr], family: Option<AddrFamily>) -> Result<SocketAddr> {
+    match family {
+        Some(family) => family
+            .select(addrs)
+            .ok_or_else(|| Error::AddrFamilyMismatch(addr.to_string(), family.to_string())),
+        None => addrs
+            .first()
+            .copied()
+            .ok_or_else(|| Error::UnresolvableHost(addr.to_string())),
+    }
+}
+
+/// The address as URI, see `PortType::from_str`.
+impl fmt::Display for PortType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Loop => write!(f, "loop://"),
+            Self::Tcp(addr) => write!(f, "tcp://{addr}"),
+            Self::Rdma(addr) => write!(f, "rdma://{addr}"),
+            Self::FibreChannel(addr) => write!(f, "fc://{}", addr.to_traddr()),
+        }
+    }
+}
+
+/// A Fibre Channel address, by World Wide Node and Port Name.
+///
+/// Equal addresses compare equal however they were written, see `from_str`. State files
+/// store the address as `to_traddr` gives it, but still accept the `wwnn` and `wwpn`
+/// numbers written by older versions.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "FibreChannelAddrRepr", into = "String")]
 pub struct FibreChannelAddr {
     pub wwnn: u64,
     pub wwpn: u64,
@@ -72,37 +807,61 @@ impl FibreChannelAddr {
         Self { wwnn, wwpn }
     }
 
+    /// The address as the kernel reports it in addr_traddr: lowercase, with 0x prefixes.
     #[must_use]
     pub fn to_traddr(&self) -> String {
         format!("nn-{:#018x}:pn-{:#018x}", self.wwnn, self.wwpn)
     }
 }
 
+/// Parse a traddr like `nn-0x1000000044001123:pn-0x2000000055001123` or
+/// `nn-1000000044001123:pn-2000000055001123`, in any case.
 impl FromStr for FibreChannelAddr {
-    type Err = anyhow::Error;
+    type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // The traddr looks like this:
-        // nn-0x1000000044001123:pn-0x2000000055001123
-        // OR
-        // nn-1000000044001123:pn-2000000055001123
-
-        if s.len() == 7 + 4 + 32 {
-            Ok(Self {
-                wwnn: u64::from_str_radix(&s[5..21], 16)
-                    .with_context(|| Error::InvalidFCWWNN(s[5..21].to_string()))?,
-                wwpn: u64::from_str_radix(&s[27..43], 16)
-                    .with_context(|| Error::InvalidFCWWPN(s[27..43].to_string()))?,
-            })
-        } else if s.len() == 7 + 32 {
-            Ok(Self {
-                wwnn: u64::from_str_radix(&s[3..19], 16)
-                    .with_context(|| Error::InvalidFCWWNN(s[3..19].to_string()))?,
-                wwpn: u64::from_str_radix(&s[23..39], 16)
-                    .with_context(|| Error::InvalidFCWWPN(s[23..39].to_string()))?,
-            })
-        } else {
-            Err(Error::InvalidFCAddr(s.to_string()).into())
+        let invalid = || Error::InvalidFCAddr(s.to_string());
+        let lower = s.to_ascii_lowercase();
+        let (nn, pn) = lower
+            .strip_prefix("nn-")
+            .and_then(|rest| rest.split_once(":pn-"))
+            .ok_or_else(invalid)?;
+        Ok(Self {
+            wwnn: parse_wwn(nn).ok_or_else(|| Error::InvalidFCWWNN(nn.to_string()))?,
+            wwpn: parse_wwn(pn).ok_or_else(|| Error::InvalidFCWWPN(pn.to_string()))?,
+        })
+    }
+}
+
+// Exactly 16 hex digits, so a missing or doubled digit isn't taken for a different name.
+fn parse_wwn(wwn: &str) -> Option<u64> {
+    let hex = wwn.strip_prefix("0x").unwrap_or(wwn);
+    if hex.len() != 16 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(hex, 16).ok()
+}
+
+impl From<FibreChannelAddr> for String {
+    fn from(addr: FibreChannelAddr) -> Self {
+        addr.to_traddr()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "Fibre Channel traddr or wwnn and wwpn")]
+enum FibreChannelAddrRepr {
+    Traddr(String),
+    Names { wwnn: u64, wwpn: u64 },
+}
+
+impl TryFrom<FibreChannelAddrRepr> for FibreChannelAddr {
+    type Error = Error;
+
+    fn try_from(repr: FibreChannelAddrRepr) -> Result<Self, Self::Error> {
+        match repr {
+            FibreChannelAddrRepr::Traddr(traddr) => traddr.parse(),
+            FibreChannelAddrRepr::Names { wwnn, wwpn } => Ok(Self::new(wwnn, wwpn)),
         }
     }
 }
@@ -111,6 +870,54 @@ impl FromStr for FibreChannelAddr {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_select_addr_family() -> Result<()> {
+        // A dual-stack host name, as resolved.
+        let addrs: Vec<SocketAddr> = vec!["[2001:db8::1]:4420".parse()?, "192.0.2.1:4420".parse()?];
+        let host = "storage.example:4420";
+        assert_eq!(select_addr(host, &addrs, None)?, addrs[0]);
+        assert_eq!(select_addr(host, &addrs, Some(AddrFamily::Ipv6))?, addrs[0]);
+        assert_eq!(select_addr(host, &addrs, Some(AddrFamily::Ipv4))?, addrs[1]);
+
+        let err = select_addr(host, &addrs[..1], Some(AddrFamily::Ipv4)).unwrap_err();
+        assert!(
+            matches!(err, Error::AddrFamilyMismatch(addr, family) if addr == host && family == "ipv4")
+        );
+        assert!(matches!(
+            select_addr(host, &[], None).unwrap_err(),
+            Error::UnresolvableHost(_)
+        ));
+
+        // Literal addresses must be of the family asked for.
+        assert_eq!(
+            resolve_socket_addr("[fdff::1]:4420", Some(AddrFamily::Ipv6))?,
+            "[fdff::1]:4420".parse()?
+        );
+        assert!(matches!(
+            resolve_socket_addr("192.0.2.1:4420", Some(AddrFamily::Ipv6)).unwrap_err(),
+            Error::AddrFamilyMismatch(..)
+        ));
+        for invalid in ["192.0.2:4420", "storage_1:4420", "fdff::1"] {
+            assert!(
+                matches!(
+                    resolve_socket_addr(invalid, None),
+                    Err(Error::InvalidIPAddr(_))
+                ),
+                "{invalid}"
+            );
+        }
+        for invalid in ["storage.example:nvme", "storage.example:+4420"] {
+            assert!(
+                matches!(
+                    resolve_socket_addr(invalid, None).unwrap_err(),
+                    Error::InvalidServiceId(_)
+                ),
+                "{invalid}"
+            );
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_fcaddr_valid() {
         let addr = FibreChannelAddr::new(0x1000_0000_4400_1123, 0x2000_0000_5500_1123);
@@ -123,11 +930,359 @@ mod tests {
         assert_eq!(addr.to_traddr(), traddr_long);
     }
 
+    #[test]
+    fn test_fcaddr_normalized() {
+        // As read from addr_traddr of an lpfc port.
+        let kernel = "nn-0x20000090fa942779:pn-0x10000090fa942779";
+        let addr = FibreChannelAddr::new(0x2000_0090_fa94_2779, 0x1000_0090_fa94_2779);
+        for traddr in [
+            kernel,
+            "nn-0x20000090FA942779:pn-0x10000090FA942779",
+            "NN-0X20000090FA942779:PN-0X10000090FA942779",
+            "nn-20000090fa942779:pn-10000090FA942779",
+            "nn-0x20000090fa942779:pn-10000090fa942779",
+        ] {
+            let parsed: FibreChannelAddr = traddr.parse().unwrap();
+            assert_eq!(parsed, addr, "{traddr}");
+            assert_eq!(parsed.to_traddr(), kernel, "{traddr}");
+        }
+    }
+
     #[test]
     fn test_fcaddr_invalid() {
         let traddr_too_short = "nn-10000000440011:pn-20000000550011";
         assert!(traddr_too_short.parse::<FibreChannelAddr>().is_err());
         let traddr_invalid_hex = "nn-10MEH00044001123:pn-2000000055001123";
         assert!(traddr_invalid_hex.parse::<FibreChannelAddr>().is_err());
+        for traddr in [
+            "xx-0x1000000044001123:pn-0x2000000055001123",
+            "nn-0x1000000044001123-pn-0x2000000055001123",
+            "nn-0x1000000044001123:pn-0x20000000550011234",
+            "nn-+000000044001123:pn-2000000055001123",
+            "nn-0x1000000044001123:pn-0x2000000055001123:pn-0x2000000055001123",
+        ] {
+            assert!(traddr.parse::<FibreChannelAddr>().is_err(), "{traddr}");
+        }
+    }
+
+    #[test]
+    fn test_fcaddr_repr() {
+        let addr = PortType::FibreChannel(FibreChannelAddr::new(
+            0x1000_0000_4400_1123,
+            0x2000_0000_5500_1123,
+        ));
+        let canonical =
+            "port_type: FibreChannel\nport_addr: nn-0x1000000044001123:pn-0x2000000055001123\n";
+        assert_eq!(serde_yaml::to_string(&addr).unwrap(), canonical);
+        for yaml in [
+            canonical,
+            "port_type: FibreChannel\nport_addr: NN-1000000044001123:PN-2000000055001123\n",
+            // As written by older versions.
+            "port_type: FibreChannel\nport_addr:\n  wwnn: 1152921505747702051\n  wwpn: 2305843010639761699\n",
+        ] {
+            assert_eq!(serde_yaml::from_str::<PortType>(yaml).unwrap(), addr, "{yaml}");
+        }
+        assert!(serde_yaml::from_str::<PortType>(
+            "port_type: FibreChannel\nport_addr: nn-0x10:pn-0x20\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_port_uri() -> Result<()> {
+        let parse = |uri: &str| uri.parse::<PortType>();
+        assert_eq!(parse("loop://")?, PortType::Loop);
+        assert_eq!(
+            parse("tcp://192.0.2.1:4421")?,
+            PortType::Tcp("192.0.2.1:4421".parse()?)
+        );
+        assert_eq!(
+            parse("rdma://[fdff::1]:4421")?,
+            PortType::Rdma("[fdff::1]:4421".parse()?)
+        );
+        assert_eq!(
+            parse("fc://nn-1000000044001123:pn-2000000055001123")?,
+            PortType::FibreChannel(FibreChannelAddr::new(
+                0x1000_0000_4400_1123,
+                0x2000_0000_5500_1123
+            ))
+        );
+        // Without a port, the default one is used.
+        assert_eq!(
+            parse("tcp://192.0.2.1")?,
+            PortType::Tcp("192.0.2.1:4420".parse()?)
+        );
+        assert_eq!(
+            parse("TCP://[fdff::1]")?,
+            PortType::Tcp("[fdff::1]:4420".parse()?)
+        );
+
+        for uri in [
+            "192.0.2.1:4420",
+            "tcp:/192.0.2.1",
+            "http://192.0.2.1:4420",
+            "tcp://fdff::1",
+            "tcp://fdff::1:4420",
+            "tcp://[fdff::1",
+            "tcp://192.0.2.1:http",
+            "tcp://",
+            "loop://localhost",
+            "fc://192.0.2.1",
+        ] {
+            assert!(parse(uri).is_err(), "{uri} should be rejected");
+        }
+        assert!(matches!(
+            parse("http://192.0.2.1").unwrap_err(),
+            Error::InvalidPortUri(_)
+        ));
+        for uri in [
+            "tcp://192.0.2.1:http",
+            "rdma://[fdff::1]:0",
+            "tcp://192.0.2.1:",
+        ] {
+            assert!(
+                matches!(parse(uri).unwrap_err(), Error::InvalidServiceId(_)),
+                "{uri} should be rejected for its port"
+            );
+        }
+
+        // Displaying gives back the same URI, with the port always included.
+        for uri in [
+            "loop://",
+            "tcp://192.0.2.1:4420",
+            "rdma://[fdff::1]:4420",
+            "fc://nn-0x1000000044001123:pn-0x2000000055001123",
+        ] {
+            assert_eq!(parse(uri)?.to_string(), uri);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_port_uri_repr() {
+        let yaml = "address: rdma://[fdff::1]\nlabels:\n  rack: a1\nsubsystems: []\n";
+        let port: Port = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            port.port_type,
+            PortType::Rdma("[fdff::1]:4420".parse().unwrap())
+        );
+        assert_eq!(port.labels["rack"], "a1");
+        // Written out as usual.
+        assert_eq!(
+            serde_yaml::to_string(&port).unwrap(),
+            "port_type: Rdma\nport_addr: '[fdff::1]:4420'\nlabels:\n  rack: a1\nsubsystems: []\n"
+        );
+        assert!(serde_yaml::from_str::<Port>("address: tcp://fdff::1\nsubsystems: []\n").is_err());
+    }
+
+    #[test]
+    fn test_port_adrfam_repr() {
+        let mut port = Port::new(
+            PortType::Tcp("[fdff::1]:4420".parse().unwrap()),
+            BTreeSet::new(),
+        );
+        let yaml = "port_type: Tcp\nport_addr: '[fdff::1]:4420'\nsubsystems: []\n";
+
+        // The gathered family is never written, not even one not matching the address.
+        for family in [AddrFamily::Ipv6, AddrFamily::Ipv4] {
+            port.adrfam = Some(family.into());
+            assert_eq!(serde_yaml::to_string(&port).unwrap(), yaml);
+        }
+        let read: Port = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(read.adrfam, None);
+        assert_eq!(read.adrfam(), Some(PortAddrFamily::Ip(AddrFamily::Ipv6)));
+
+        for family in ["ipv4", "ipv6", "fc", "ib"] {
+            assert_eq!(
+                family.parse::<PortAddrFamily>().unwrap().to_string(),
+                family
+            );
+        }
+        assert!(matches!(
+            "pcie".parse::<PortAddrFamily>(),
+            Err(Error::UnsupportedAdrFam(family)) if family == "pcie"
+        ));
+    }
+
+    #[test]
+    fn test_port_interface_repr() {
+        let yaml = "port_type: Tcp\ninterface: eth1\nport: 4420\nsubsystems: []\n";
+        let port: Port = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            port.interface,
+            Some(PortInterface::new("eth1", AddrFamily::Ipv4))
+        );
+        assert_eq!(
+            port.port_type,
+            PortType::Tcp("0.0.0.0:4420".parse().unwrap())
+        );
+        assert_eq!(serde_yaml::to_string(&port).unwrap(), yaml);
+
+        let yaml = "port_type: Rdma\ninterface: eth1\nport: 4420\nprefer: ipv6\nsubsystems: []\n";
+        let port: Port = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(port.port_type, PortType::Rdma("[::]:4420".parse().unwrap()));
+        assert_eq!(serde_yaml::to_string(&port).unwrap(), yaml);
+
+        // Ports with an address are written as before.
+        let yaml = "port_type: Tcp\nport_addr: 192.0.2.1:4420\nsubsystems: []\n";
+        let port: Port = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(port.interface, None);
+        assert_eq!(serde_yaml::to_string(&port).unwrap(), yaml);
+
+        // Only IP based transports can use an interface.
+        let yaml = "port_type: FibreChannel\ninterface: eth1\nport: 4420\nsubsystems: []\n";
+        assert!(serde_yaml::from_str::<Port>(yaml).is_err());
+        assert!(PortInterface::new("eth1", AddrFamily::Ipv4)
+            .unresolved("fc", 4420)
+            .is_err());
+    }
+
+    #[test]
+    fn test_namespace_device_repr() {
+        let yaml = "enabled: true\ndevice: LABEL=tenant-a\ndevice_uuid: null\ndevice_nguid: null\n";
+        let ns: Namespace = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            ns.device_spec,
+            Some(DeviceSpec::Label("tenant-a".to_string()))
+        );
+        assert_eq!(ns.device_path, PathBuf::new());
+        // The spec is kept, not the device it resolves to.
+        assert_eq!(serde_yaml::to_string(&ns).unwrap(), yaml);
+
+        // Namespaces with a path are written as before.
+        let yaml =
+            "enabled: true\ndevice_path: /dev/loop0\ndevice_uuid: null\ndevice_nguid: null\n";
+        let ns: Namespace = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(ns.device_spec, None);
+        assert_eq!(serde_yaml::to_string(&ns).unwrap(), yaml);
+
+        let err =
+            serde_yaml::from_str::<Namespace>("enabled: true\ndevice: /dev/loop0\n").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("expected a path, UUID=<uuid> or LABEL=<label>"));
+    }
+
+    #[test]
+    fn test_inactive() {
+        let yaml = "subsystems:
+  nqn.2023-11.sh.tty:on:
+    model: null
+    serial: null
+    allowed_hosts: []
+    namespaces: {}
+  nqn.2023-11.sh.tty:off:
+    active: false
+    model: null
+    serial: null
+    allowed_hosts: []
+    namespaces: {}
+ports:
+  1:
+    port_type: Loop
+    subsystems:
+    - nqn.2023-11.sh.tty:off
+    - nqn.2023-11.sh.tty:on
+  2:
+    port_type: Loop
+    subsystems:
+    - nqn.2023-11.sh.tty:on
+    active: false
+";
+        let mut state: State = serde_yaml::from_str(yaml).unwrap();
+        assert!(state.subsystems["nqn.2023-11.sh.tty:on"].is_active());
+        assert!(!state.subsystems["nqn.2023-11.sh.tty:off"].is_active());
+        assert!(state.ports[&1].is_active());
+        assert!(!state.ports[&2].is_active());
+        // Only inactive objects are marked when written.
+        let written = serde_yaml::to_string(&state).unwrap();
+        assert_eq!(written.matches("active: false").count(), 2);
+        assert!(!written.contains("active: true"));
+
+        // Inactive objects are still validated.
+        let mut invalid = state.clone();
+        invalid.subsystems.insert(
+            "nqn.2023-11.sh.tty:invalid".to_string(),
+            Subsystem {
+                active: Some(false),
+                model: Some("x".repeat(100)),
+                ..Default::default()
+            },
+        );
+        assert!(invalid.validate(false).is_err());
+
+        state.remove_inactive();
+        assert_eq!(
+            state.subsystems.keys().collect::<Vec<_>>(),
+            ["nqn.2023-11.sh.tty:on"]
+        );
+        assert_eq!(state.ports.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(
+            state.ports[&1].subsystems,
+            BTreeSet::from(["nqn.2023-11.sh.tty:on".to_string()])
+        );
+        assert!(!serde_yaml::to_string(&state).unwrap().contains("active"));
+    }
+
+    #[test]
+    fn test_merge_inactive() {
+        const SUB: &str = "nqn.2023-11.sh.tty:sub";
+        let mut base = State::default();
+        base.subsystems
+            .insert(SUB.to_string(), Subsystem::default());
+        base.ports
+            .insert(1, Port::new(PortType::Loop, BTreeSet::new()));
+        let mut inactive = base.clone();
+        inactive.subsystems.get_mut(SUB).unwrap().active = Some(false);
+        inactive.ports.get_mut(&1).unwrap().active = Some(false);
+
+        // Marking objects inactive in an overlay makes them inactive.
+        let mut merged = base.clone();
+        merged.merge(&inactive);
+        assert!(!merged.subsystems[SUB].is_active());
+        assert!(!merged.ports[&1].is_active());
+        // Overlays not saying anything about it keep them that way.
+        merged.merge(&base);
+        assert!(!merged.subsystems[SUB].is_active());
+        assert!(!merged.ports[&1].is_active());
+
+        // An overlay can activate them again.
+        let mut active = base.clone();
+        active.subsystems.get_mut(SUB).unwrap().active = Some(true);
+        active.ports.get_mut(&1).unwrap().active = Some(true);
+        merged.merge(&active);
+        assert!(merged.subsystems[SUB].is_active());
+        assert!(merged.ports[&1].is_active());
+        let written = serde_yaml::to_string(&active).unwrap();
+        assert_eq!(written.matches("active: true").count(), 2);
+        assert_eq!(serde_yaml::from_str::<State>(&written).unwrap(), active);
+    }
+
+    #[test]
+    fn test_resolve_without_specs() -> Result<()> {
+        let mut state = State::default();
+        let mut sub = Subsystem::default();
+        sub.namespaces
+            .insert(1, Namespace::builder("/dev/loop0").build()?);
+        state
+            .subsystems
+            .insert("nqn.2023-11.sh.tty:sub".to_string(), sub);
+        let before = state.clone();
+        state.resolve_devices()?;
+        assert_eq!(state, before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_interfaces_without_interfaces() -> Result<()> {
+        let mut state = State::default();
+        state.ports.insert(
+            1,
+            Port::new(PortType::Tcp("192.0.2.1:4420".parse()?), BTreeSet::new()),
+        );
+        let before = state.clone();
+        state.resolve_interfaces()?;
+        assert_eq!(state, before);
+        Ok(())
     }
 }
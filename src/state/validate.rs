@@ -0,0 +1,221 @@
+// Sanity checks on a whole state before anything gets applied.
+
+use super::alias::Aliases;
+use super::labels::assert_valid_labels;
+use super::types::{PortType, State};
+use crate::errors::{Context, Error, Result};
+use crate::helpers::{
+    assert_compliant_nqn, assert_valid_dhchap_key, assert_valid_model, assert_valid_nqn,
+    assert_valid_nsid, assert_valid_qid_max, assert_valid_serial,
+};
+use std::collections::BTreeMap;
+
+impl State {
+    /// Check the whole state for values the kernel would reject.
+    ///
+    /// With `strict`, subsystem NQNs also have to follow the formats from the NVMe specification.
+    pub fn validate(&self, strict: bool) -> Result<()> {
+        for (nqn, sub) in &self.subsystems {
+            if strict {
+                assert_compliant_nqn(nqn)
+            } else {
+                assert_valid_nqn(nqn)
+            }
+            .with_context(|| format!("Invalid NQN for subsystem {nqn}"))?;
+            if let Some(model) = &sub.model {
+                assert_valid_model(model)
+                    .with_context(|| format!("Invalid model for subsystem {nqn}"))?;
+            }
+            if let Some(serial) = &sub.serial {
+                assert_valid_serial(serial)
+                    .with_context(|| format!("Invalid serial for subsystem {nqn}"))?;
+            }
+            if let Some(qid_max) = sub.qid_max {
+                assert_valid_qid_max(qid_max).with_context(|| {
+                    format!("Invalid maximum number of I/O queues for subsystem {nqn}")
+                })?;
+            }
+            for host in &sub.allowed_hosts {
+                assert_valid_nqn(host)
+                    .with_context(|| format!("Invalid allowed host for subsystem {nqn}"))?;
+            }
+            assert_valid_labels(&sub.labels)
+                .with_context(|| format!("Invalid label for subsystem {nqn}"))?;
+            for nsid in sub.namespaces.keys() {
+                assert_valid_nsid(*nsid)
+                    .with_context(|| format!("Invalid namespace for subsystem {nqn}"))?;
+            }
+        }
+
+        for (nqn, keys) in &self.hosts {
+            assert_valid_nqn(nqn).with_context(|| format!("Invalid NQN for host {nqn}"))?;
+            if let Some(key) = &keys.dhchap_key {
+                assert_valid_dhchap_key(key)
+                    .with_context(|| format!("Invalid dhchap_key for host {nqn}"))?;
+            }
+            if let Some(key) = &keys.dhchap_ctrl_key {
+                assert_valid_dhchap_key(key)
+                    .with_context(|| format!("Invalid dhchap_ctrl_key for host {nqn}"))?;
+            }
+        }
+
+        Aliases::from_state(self)?;
+
+        let mut addresses = BTreeMap::new();
+        for (id, port) in &self.ports {
+            // Interface ports have no address until resolved, and loop ports none at all.
+            let unresolved = port.interface.is_some()
+                && matches!(port.port_type, PortType::Tcp(addr) | PortType::Rdma(addr) if addr.ip().is_unspecified());
+            if let Some(adrfam) = port.adrfam.filter(|_| !unresolved) {
+                port.port_type
+                    .check_adrfam(adrfam)
+                    .with_context(|| format!("Invalid address family for port {id}"))?;
+            }
+            if port.port_type != PortType::Loop && !unresolved {
+                let address = port.port_type.to_string();
+                if let Some(other) = addresses.insert(address.clone(), *id) {
+                    return Err(Error::DuplicatePortAddress(address, other, *id));
+                }
+            }
+            assert_valid_labels(&port.labels)
+                .with_context(|| format!("Invalid label for port {id}"))?;
+            for nqn in &port.subsystems {
+                if !self.subsystems.contains_key(nqn) {
+                    return Err(Error::NoSuchSubsystem(nqn.clone()))
+                        .with_context(|| format!("Invalid subsystem for port {id}"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{
+        AddrFamily, HostKeys, Port, PortAddrFamily, PortInterface, PortType, Subsystem,
+    };
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let mut state = State::default();
+        state
+            .subsystems
+            .insert("nqn.2023-11.sh.tty:valid".to_string(), Subsystem::default());
+        state.validate(true)?;
+
+        // Valid, but not compliant.
+        state
+            .subsystems
+            .insert("nqn.test".to_string(), Subsystem::default());
+        state.validate(false)?;
+        assert!(state.validate(true).is_err());
+
+        // Port referencing a subsystem that does not exist.
+        state.ports.insert(
+            1,
+            Port::new(
+                PortType::Loop,
+                BTreeSet::from(["nqn.2023-11.sh.tty:missing".to_string()]),
+            ),
+        );
+        assert!(state.validate(false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_host_keys() -> Result<()> {
+        let host = "nqn.2023-11.sh.tty:host";
+        let mut state = State::default();
+        let keys = HostKeys {
+            dhchap_key: Some(
+                "DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6aQ2n:".to_string(),
+            ),
+            dhchap_ctrl_key: None,
+        };
+        state.hosts.insert(host.to_string(), keys.clone());
+        state.validate(true)?;
+
+        // Corrupted CRC in the controller key.
+        state.hosts.get_mut(host).unwrap().dhchap_ctrl_key =
+            Some("DHHC-1:00:ia6zGodOr4SEG0Zzaw398rpY0wqipUWj4jWjUh4HWUz6bQ2n:".to_string());
+        let err = state.validate(false).unwrap_err();
+        assert!(matches!(err.root(), Error::InvalidDhchapKey(_)), "{err:?}");
+        assert!(err.to_string().contains("dhchap_ctrl_key"), "{err}");
+
+        state.hosts.clear();
+        state
+            .hosts
+            .insert("nqn.2023-11.sh.tty:hö".to_string(), keys);
+        assert!(state.validate(false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_duplicate_port_address() -> Result<()> {
+        let nqn = "nqn.2023-11.sh.tty:valid";
+        let mut state = State::default();
+        state
+            .subsystems
+            .insert(nqn.to_string(), Subsystem::default());
+        let port = |uri: &str| -> Result<Port> {
+            Ok(Port::new(uri.parse()?, BTreeSet::from([nqn.to_string()])))
+        };
+        state.ports.insert(1, port("tcp://192.0.2.1:4420")?);
+        state.ports.insert(2, port("tcp://192.0.2.1:4421")?);
+        state.ports.insert(3, port("rdma://192.0.2.1:4420")?);
+        state.ports.insert(4, port("loop://")?);
+        state.ports.insert(5, port("loop://")?);
+        // Interface ports only get their address when resolved.
+        for (id, name) in [(7, "eth0"), (8, "eth1")] {
+            let mut port = port("tcp://0.0.0.0:4420")?;
+            port.interface = Some(PortInterface::new(name, AddrFamily::default()));
+            state.ports.insert(id, port);
+        }
+        state.validate(false)?;
+
+        state.ports.insert(6, port("tcp://192.0.2.1:4420")?);
+        let err = state.validate(false).unwrap_err();
+        assert!(
+            matches!(err, Error::DuplicatePortAddress(ref addr, 1, 6) if addr == "tcp://192.0.2.1:4420")
+        );
+        assert_eq!(
+            err.to_string(),
+            "Ports 1 and 6 both use address tcp://192.0.2.1:4420"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_port_adrfam() -> Result<()> {
+        let mut state = State::default();
+        let mut port = Port::new("tcp://[fdff::1]:4420".parse()?, BTreeSet::new());
+        port.adrfam = Some(PortAddrFamily::Ip(AddrFamily::Ipv6));
+        state.ports.insert(1, port.clone());
+        state.validate(false)?;
+
+        for (uri, adrfam) in [
+            ("tcp://[fdff::1]:4420", "ipv4"),
+            ("rdma://192.0.2.1:4420", "ipv6"),
+            ("fc://nn-0x1000000044001123:pn-0x2000000055001123", "ipv4"),
+        ] {
+            port.port_type = uri.parse()?;
+            port.adrfam = Some(adrfam.parse()?);
+            state.ports.insert(1, port.clone());
+            let err = state.validate(false).unwrap_err();
+            assert!(
+                matches!(err.root(), Error::AddrFamilyMismatch(addr, family) if addr == uri && family == adrfam),
+                "{err:?}"
+            );
+        }
+
+        // Loop ports have no address for the family to match.
+        port.port_type = PortType::Loop;
+        port.adrfam = Some(PortAddrFamily::Ip(AddrFamily::Ipv4));
+        state.ports.insert(1, port);
+        state.validate(false)?;
+        Ok(())
+    }
+}
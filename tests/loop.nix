@@ -9,6 +9,7 @@
     environment.systemPackages = with pkgs; [
       self.packages.${system}.nvmetcfg-coverage
       nvme-cli
+      e2fsprogs
       llvmPackages_17.bintools
     ];
     boot.kernelModules = ["nvmet"];
@@ -23,55 +24,258 @@
 
     # Set up the loop device.
     node.succeed("fallocate -l 1G /root/test.img")
+    node.succeed("mkfs.ext4 -L nvmetdata /root/test.img")
     node.succeed("losetup /dev/loop0 /root/test.img")
 
+    # Probing must not leave anything behind.
+    assert "buffered_io" in node.succeed("nvmet capabilities")
+    node.succeed("nvmet capabilities --output json | grep -q kernel_version")
+    assert "No changes" in node.succeed("nvmet state clear 2>&1")
+
     # Create our subsystems.
     node.succeed("nvmet subsystem add ${subnqn}")
+    # Invalid models and serials are rejected, unless asked to fix them up.
+    node.fail("nvmet subsystem update ${subnqn} --serial 012345678901234567890")
+    node.succeed("nvmet subsystem update ${subnqn} --serial 012345678901234567890 --truncate")
+    assert "01234567890123456789" in node.succeed("cat /sys/kernel/config/nvmet/subsystems/${subnqn}/attr_serial")
+    node.fail("nvmet subsystem update ${subnqn} --model 'Größe'")
+    node.succeed("nvmet subsystem update ${subnqn} --model 'Größe' --ascii-fold")
+    assert "Grosse" in node.succeed("cat /sys/kernel/config/nvmet/subsystems/${subnqn}/attr_model")
     node.succeed("nvmet subsystem update ${subnqn} --model Loop --serial 1337")
+    # Status messages and configfs writes can be logged as JSON.
+    log = node.succeed("nvmet -v --log-format json subsystem update ${subnqn} --model Loop 2>&1")
+    assert '"path":"/sys/kernel/config/nvmet/subsystems/${subnqn}/attr_model"' in log
+    assert "${subnqn},Loop,1337," in node.succeed("nvmet subsystem inventory --output csv")
+    assert '"level":"INFO"' in node.succeed("nvmet --log-format json state clear --only-ports 2>&1")
     assert "${subnqn}" in node.succeed("nvmet subsystem list")
     node.succeed("test -d /sys/kernel/config/nvmet/subsystems/${subnqn}")
     node.succeed("nvmet subsystem show")
 
     node.succeed("nvmet namespace add ${subnqn} 1 /dev/loop0")
+    node.succeed("nvmet namespace update ${subnqn} 1 LABEL=nvmetdata")
+    assert "/dev/loop0" in node.succeed("cat /sys/kernel/config/nvmet/subsystems/${subnqn}/namespaces/1/device_path")
+    assert node.execute("nvmet namespace update ${subnqn} 1 LABEL=missing")[0] == 2
     node.succeed("nvmet namespace update ${subnqn} 1 /dev/loop0")
+    assert node.execute("nvmet namespace add ${subnqn} 1 /dev/loop0")[0] == 4
+    assert "No changes" in node.succeed("nvmet --no-op-if-unchanged namespace add ${subnqn} 1 /dev/loop0 2>&1")
+    assert node.succeed("nvmet subsystem exists ${subnqn}") == ""
+    assert node.succeed("nvmet namespace exists ${subnqn} 1") == ""
+    assert "exists" in node.succeed("nvmet -v namespace exists ${subnqn} 1 2>&1")
+    assert node.execute("nvmet subsystem exists nqn.2023-11.sh.tty:missing")[0] == 10
+    assert node.execute("nvmet namespace exists ${subnqn} 2")[0] == 10
+    assert node.execute("nvmet namespace exists nqn.2023-11.sh.tty:missing 1")[0] == 10
+    assert node.execute("nvmet port exists 1337")[0] == 10
+    assert "does not exist" in node.execute("nvmet -v port exists 1337 2>&1")[1]
+    node.succeed("nvmet subsystem wait ${subnqn} --timeout 0")
+    assert node.execute("echo | nvmet subsystem wizard")[0] == 3
+    assert "Timed out" in node.fail("nvmet subsystem wait nqn.2023-11.sh.tty:missing --timeout 1 2>&1")
+    node.succeed("(sleep 2; nvmet port add 1 loop) >&2 &")
+    node.succeed("nvmet port wait 1 --timeout 30")
+    node.succeed("nvmet port remove 1")
     assert "1" in node.succeed("nvmet namespace list ${subnqn}")
+    csv = node.succeed("nvmet namespace list ${subnqn} --output csv")
+    assert csv.startswith("nsid,enabled,device_path,device_uuid,device_nguid\n1,true,/dev/loop0,")
+    assert "${subnqn},Loop,1337,,1" in node.succeed("nvmet subsystem list --output csv")
+    show = node.succeed("nvmet subsystem show ${subnqn}")
+    assert "Enabled Namespaces: 1, disabled: 0" in show
+    assert "Capacity: 1.0 GiB" in show
+    assert "Capacity: 1.0 GiB" in node.succeed("nvmet stats")
+    assert '"capacity":1073741824' in node.succeed("nvmet stats --output json --json-compact")
     node.succeed("test -d /sys/kernel/config/nvmet/subsystems/${subnqn}/namespaces/1")
     assert "/dev/loop0" in node.succeed("cat /sys/kernel/config/nvmet/subsystems/${subnqn}/namespaces/1/device_path")
-    node.succeed("nvmet namespace show ${subnqn}")
+    show = node.succeed("nvmet namespace show ${subnqn}")
+    assert "Filesystem: ext4" in show
+    assert "Filesystem Label: nvmetdata" in show
+    # JSON is compact when piped, unless asked to be pretty.
+    assert '"fs_label":"nvmetdata"' in node.succeed("nvmet namespace show ${subnqn} --output json")
+    assert '"fs_label": "nvmetdata"' in node.succeed("nvmet namespace show ${subnqn} --output json --json-pretty")
+    assert "Filesystem" not in node.succeed("nvmet namespace show ${subnqn} --no-probe")
+    # NDJSON prints one object per line.
+    lines = node.succeed("nvmet subsystem list --output ndjson").splitlines()
+    assert len(lines) == 1 and lines[0].startswith('{"nqn":"${subnqn}"')
+    # The namespace UUID is checked against the identity of its device.
+    node.fail("nvmet namespace verify ${subnqn} 1")
+    fsuuid = node.succeed("blkid -s UUID -o value /dev/loop0").strip()
+    node.succeed(f"nvmet namespace update ${subnqn} 1 /dev/loop0 --uuid {fsuuid}")
+    assert "filesystem UUID" in node.succeed("nvmet namespace verify ${subnqn} 1")
+    assert node.execute("nvmet namespace verify ${subnqn} 2")[0] == 2
+    node.succeed("nvmet namespace disable ${subnqn} 1")
+    assert node.succeed("cat /sys/kernel/config/nvmet/subsystems/${subnqn}/namespaces/1/enable").strip() == "0"
+    node.succeed("nvmet namespace enable ${subnqn} 1")
+    assert node.succeed("cat /sys/kernel/config/nvmet/subsystems/${subnqn}/namespaces/1/enable").strip() == "1"
+
+    node.succeed("nvmet alias set test ${subnqn}")
+    assert "@test" in node.succeed("nvmet alias list")
+    assert "1" in node.succeed("nvmet namespace list @test")
+    node.fail("nvmet namespace list @missing")
+
+    # Labels are kept by nvmetcfg and filter lists.
+    node.succeed("nvmet subsystem label @test env=prod team=storage")
+    node.succeed("nvmet subsystem label @test --remove team")
+    assert "Labels: env=prod" in node.succeed("nvmet subsystem show")
+    assert "Labels: env=prod" in node.succeed("nvmet subsystem show @test")
+    node.fail("nvmet subsystem show nqn.2023-11.sh.tty:missing")
+    assert "${subnqn}" in node.succeed("nvmet subsystem list --label env=prod")
+    assert "${subnqn}" not in node.succeed("nvmet subsystem list --label env=dev")
+    node.fail("nvmet subsystem label @test 'bad key=x'")
+    node.fail("nvmet port label 69 env=prod")
 
     # Create the loopback port.
+    # There is no Fibre Channel HBA, so no WWN is local.
+    assert node.execute("nvmet port add 5 fc nn-0x1000000044001123:pn-0x2000000055001123 --require-local-wwn")[0] == 2
     node.succeed("nvmet port add 1 loop")
     node.succeed("nvmet port update 1 loop")
     assert "1" in node.succeed("nvmet port list")
+    assert node.succeed("nvmet port exists 1") == ""
     node.succeed("test -d /sys/kernel/config/nvmet/ports/1")
     assert "loop" in node.succeed("cat /sys/kernel/config/nvmet/ports/1/addr_trtype")
 
     node.succeed("nvmet port add-subsystem 1 ${subnqn}")
     assert "${subnqn}" in node.succeed("nvmet port list-subsystems 1")
+    assert "*,1,1,1073741824,0" in node.succeed("nvmet stats --by-host --output csv")
     node.succeed("test -h /sys/kernel/config/nvmet/ports/1/subsystems/${subnqn}")
     node.fail("nvmet port list-subsystems 69")
+    assert "1     loop://  ${subnqn}" in node.succeed("nvmet port subsystems")
+    assert "1,loop://,${subnqn}" in node.succeed("nvmet port subsystems --output csv")
+    assert node.succeed("nvmet subsystem ports ${subnqn}") == "1\n"
     node.succeed("nvmet port show")
+    assert "${subnqn}" in node.succeed("nvmet port show 1")
+    node.fail("nvmet port show 69")
+
+    # Attributes can be accessed directly, but only within nvmet's configfs tree.
+    assert node.succeed("nvmet raw get namespace:${subnqn}/1 device_path").strip() == "/dev/loop0"
+    assert node.succeed("nvmet raw get port:1 addr_trtype").strip() == "loop"
+    node.fail("nvmet raw get port:1 ../../../../etc/shadow")
+    node.fail("nvmet raw get subsystem:.. attr_model")
+    assert node.execute("nvmet raw get port:69 addr_trtype")[0] == 2
 
     assert "${subnqn}" in machine.succeed("nvme discover -t loop")
 
+    # Loop controllers are shown with the port of their subsystem.
+    machine.succeed("nvme connect -t loop -n ${subnqn}")
+    shown = node.succeed("nvmet port show 1")
+    assert "Loop Controllers: 1" in shown and "/dev/nvme" in shown, shown
+    machine.succeed("nvme disconnect -n ${subnqn}")
+    assert "Loop Controllers" not in node.succeed("nvmet port show 1")
+
+    # The whole state at once.
+    tree = node.succeed("nvmet state show")
+    assert "\t1: loop://\n\t\tSubsystems:\n\t\t\t${subnqn}" in tree
+    assert "\t\t\t1: /dev/loop0" in tree
+    assert "- ${subnqn}" in node.succeed("nvmet state show --output yaml")
+
     # State save/restore test.
     node.succeed("nvmet state save /root/state.yml")
     node.succeed("test -f /root/state.yml")
+    assert node.succeed("stat -c %a /root/state.yml").strip() == "600"
 
-    node.succeed("nvmet state clear")
+    node.succeed("NVMET_AUDIT_LOG=/root/audit.jsonl nvmet state clear")
     node.fail("test -e /sys/kernel/config/nvmet/subsystems/${subnqn}")
+    assert node.succeed("stat -c %a /root/audit.jsonl").strip() == "600"
+    audit = node.succeed("cat /root/audit.jsonl")
+    assert '"command":"state clear"' in audit and '"result":"ok"' in audit
     node.fail("test -e /sys/kernel/config/nvmet/ports/1")
-    assert "no config" in node.succeed("nvmet state clear")
+    assert "no config" in node.succeed("nvmet --audit-log /root/audit.jsonl state clear 2>&1")
+    assert node.succeed("wc -l < /root/audit.jsonl").strip() == "1"
+    # Changes to hosts are recorded too, as actions.
+    node.succeed("nvmet --audit-log /root/audit.jsonl host add nqn.2023-11.sh.tty:audited")
+    node.succeed("nvmet --audit-log /root/audit.jsonl host prune")
+    audit = node.succeed("cat /root/audit.jsonl")
+    assert '"actions":["add host nqn.2023-11.sh.tty:audited"]' in audit
+    assert '"actions":["remove host nqn.2023-11.sh.tty:audited"]' in audit
+
+    node.fail("grep -q env /var/lib/nvmetcfg/labels.yaml")
 
     node.succeed("nvmet state restore /root/state.yml")
     node.succeed("test -d /sys/kernel/config/nvmet/subsystems/${subnqn}/namespaces/1")
     node.succeed("test -d /sys/kernel/config/nvmet/ports/1")
-    assert "no changes" in node.succeed("nvmet state restore /root/state.yml")
+    assert "${subnqn}" in node.succeed("nvmet subsystem list --label env=prod")
+    assert "no changes" in node.succeed("nvmet state restore /root/state.yml 2>&1")
+
+    # Offline ports keep their subsystems stashed, even when restoring.
+    node.succeed("nvmet port offline 1")
+    node.fail("test -e /sys/kernel/config/nvmet/ports/1/subsystems/${subnqn}")
+    assert "[OFFLINE]" in node.succeed("nvmet port show 1")
+    node.fail("nvmet port offline 1")
+    assert "is offline" in node.succeed("nvmet state restore /root/state.yml 2>&1")
+    node.fail("test -e /sys/kernel/config/nvmet/ports/1/subsystems/${subnqn}")
+    node.succeed("nvmet port online 1")
+    node.succeed("test -h /sys/kernel/config/nvmet/ports/1/subsystems/${subnqn}")
+    node.fail("nvmet port online 1")
 
-    node.succeed("nvmet state save /root/state-after.yml")
-    node.succeed("test -f /root/state-after.yml")
+    node.succeed("touch /root/state-after.yml && chmod 644 /root/state-after.yml")
+    node.succeed("nvmet state save --mode 640 /root/state-after.yml")
+    assert node.succeed("stat -c %a /root/state-after.yml").strip() == "640"
     assert node.succeed("cat /root/state.yml") == node.succeed("cat /root/state-after.yml")
 
+    # Redacted state files keep their structure, but are not restored unless forced.
+    node.succeed("nvmet state save --redact serials,hosts /root/state-redacted.yml")
+    redacted = node.succeed("cat /root/state-redacted.yml")
+    assert "redacted-1" in redacted and "1337" not in redacted
+    node.succeed("nvmet state redact /root/state.yml /root/state-redacted2.yml")
+    assert "- keys" in node.succeed("cat /root/state-redacted2.yml")
+    node.fail("nvmet state restore /root/state-redacted.yml")
+    node.succeed("nvmet state diff /root/state-redacted.yml")
+
+    # A namespace keeping its device doesn't get a new UUID unless allowed.
+    node.succeed("sed 's/device_uuid: .*/device_uuid: 00000000-0000-0000-0000-000000001234/' /root/state.yml > /root/state-uuid.yml")
+    assert "allow it explicitly" in node.fail("nvmet state restore --dry-run /root/state-uuid.yml 2>&1")
+    node.succeed("nvmet state restore --dry-run --allow-identity-change /root/state-uuid.yml")
+
+    # Profiles are saved into a file without touching its other contents.
+    node.succeed("nvmet state save --profile current --into /root/state.yml")
+    node.succeed("nvmet state save --profile empty --into /root/profiles.yml")
+    node.succeed("nvmet state save --profile current --into /root/profiles.yml")
+    # The profile saved has real values, so the file no longer counts as redacted.
+    node.succeed("cp /root/state-redacted.yml /root/profiles-redacted.yml")
+    node.succeed("nvmet state save --profile current --into /root/profiles-redacted.yml")
+    assert "meta:" not in node.succeed("cat /root/profiles-redacted.yml")
+    node.succeed("nvmet state validate /root/profiles.yml")
+    assert "No differences" in node.succeed("nvmet state diff --profile current /root/profiles.yml")
+    assert "No differences" in node.succeed("nvmet state diff /root/state.yml")
+    node.succeed("nvmet state verify --profile current /root/state.yml")
+    node.fail("nvmet state diff --profile missing /root/state.yml")
+
+    # Included files are merged before the including one.
+    node.succeed("mkdir -p /root/node && echo 'include: [../state.yml]' > /root/node/state.yml")
+    assert "No differences" in node.succeed("nvmet state diff /root/node/state.yml")
+    node.succeed("echo 'include: [node/state.yml]' > /root/cycle.yml && echo 'include: [../cycle.yml]' > /root/node/state.yml")
+    node.fail("nvmet state validate /root/cycle.yml")
+
+    # Compressed state files, by extension or forced, and through stdin and stdout.
+    node.succeed("nvmet state save /root/state.yml.gz && gzip -t /root/state.yml.gz")
+    node.succeed("nvmet state save --compress=zstd /root/state-zstd.yml")
+    assert node.succeed("head -c 4 /root/state-zstd.yml | od -An -tx1").split() == ["28", "b5", "2f", "fd"]
+    assert "no changes" in node.succeed("nvmet state restore /root/state.yml.gz 2>&1")
+    assert "No differences" in node.succeed("nvmet state diff /root/state-zstd.yml")
+    node.succeed("nvmet state save --compress - | nvmet state validate -")
+    assert "no changes" in node.succeed("nvmet state save - | nvmet state restore - 2>&1")
+    assert "subsystems:" in node.succeed("nvmet state save --compress - | gzip -dc")
+
+    # Attributes of newer kernels are saved and restored where the kernel has them.
+    assert "qid_max:" in node.succeed("cat /root/state.yml")
+    node.succeed("sed 's/qid_max: .*/qid_max: 4/' /root/state.yml > /root/state-qid.yml")
+    node.succeed("nvmet state restore --skip-unsupported /root/state-qid.yml")
+    assert node.succeed("cat /sys/kernel/config/nvmet/subsystems/${subnqn}/attr_qid_max").strip() == "4"
+
+    # Namespaces can be given by the filesystem on their device, which diff resolves too.
+    node.succeed("sed 's|device_path: /dev/loop0|device: LABEL=nvmetdata|' /root/state.yml > /root/state-label.yml")
+    assert "device: LABEL=nvmetdata" in node.succeed("cat /root/state-label.yml")
+    assert "No differences" in node.succeed("nvmet state diff /root/state-label.yml")
+    node.succeed("nvmet state restore /root/state-label.yml")
+    # The label is kept, and saved again in place of the path.
+    node.succeed("nvmet state save /root/state-label-saved.yml")
+    assert "device: LABEL=nvmetdata" in node.succeed("cat /root/state-label-saved.yml")
+    assert "LABEL=nvmetdata" in node.succeed("nvmet state show")
+    node.succeed("nvmet state restore /root/state.yml")
+    assert "device_path: /dev/loop0" in node.succeed("nvmet state show --output yaml")
+    node.fail("sed 's|device_path: /dev/loop0|device: /dev/loop0|' /root/state.yml | nvmet state validate -")
+
+    # Inactive subsystems are marked when showing the state file, the kernel has none.
+    node.succeed("sed '/^  ${subnqn}:$/a\\    active: false' /root/state.yml > /root/state-inactive.yml")
+    assert "${subnqn} (inactive)" in node.succeed("nvmet state show /root/state-inactive.yml")
+    assert "(inactive)" not in node.succeed("nvmet state show")
+
     # Cleanup.
     node.succeed("nvmet namespace remove ${subnqn} 1")
     node.fail("test -e /sys/kernel/config/nvmet/subsystems/${subnqn}/namespaces/1")
@@ -83,12 +287,64 @@
 
     node.succeed("nvmet subsystem remove ${subnqn}")
     node.fail("test -e /sys/kernel/config/nvmet/subsystems/${subnqn}")
+    node.fail("grep -q ${subnqn} /var/lib/nvmetcfg/labels.yaml")
     node.fail("nvmet subsystem remove ${subnqn}")
 
+    node.succeed("nvmet alias remove test")
+    node.fail("nvmet alias remove test")
+
     node.succeed("nvmet port remove 1")
     node.fail("test -e /sys/kernel/config/nvmet/ports/1")
     node.fail("nvmet port remove 1")
 
+    node.succeed("nvmet port add 1 loop")
+    node.succeed("nvmet port add 2 loop://")
+    assert "Address: loop://" in node.succeed("nvmet port show")
+    assert "Aborted" in node.succeed("echo n | nvmet port remove --all 2>&1")
+    node.succeed("test -d /sys/kernel/config/nvmet/ports/2")
+    node.succeed("nvmet port remove --all --type tcp --yes")
+    node.succeed("test -d /sys/kernel/config/nvmet/ports/2")
+    node.succeed("nvmet port remove --all --yes")
+    node.fail("test -e /sys/kernel/config/nvmet/ports/1")
+    node.fail("test -e /sys/kernel/config/nvmet/ports/2")
+    assert "No ports" in node.succeed("nvmet port remove --all --yes 2>&1")
+
+    # Host names resolve to the address of the family asked for.
+    node.succeed("nvmet port add 3 tcp localhost:4420 --adrfam ipv6")
+    node.succeed("grep -x ipv6 /sys/kernel/config/nvmet/ports/3/addr_adrfam")
+    node.succeed("grep -x ::1 /sys/kernel/config/nvmet/ports/3/addr_traddr")
+    node.succeed("nvmet port update 3 tcp localhost:4420 --adrfam ipv4")
+    node.succeed("grep -x 127.0.0.1 /sys/kernel/config/nvmet/ports/3/addr_traddr")
+    assert node.execute("nvmet port update 3 tcp 127.0.0.1:4420 --adrfam ipv6")[0] == 3
+    node.succeed("nvmet port remove 3")
+
+    # verify --deep checks the configfs tree itself, --repair removes what is left over.
+    node.succeed("nvmet verify")
+    node.succeed("mkdir /sys/kernel/config/nvmet/hosts/nqn.2023-11.sh.tty:leftover")
+    assert "not allowed on any subsystem" in node.succeed("nvmet verify --deep")
+    assert '"action":"remove_host"' in node.succeed("nvmet verify --deep --output json --json-compact")
+    node.succeed("nvmet verify --deep --repair")
+    node.fail("test -e /sys/kernel/config/nvmet/hosts/nqn.2023-11.sh.tty:leftover")
+
+    # Batches are applied as a whole, or not at all.
+    node.succeed("printf '%s\\n' '# Setup' 'subsystem add ${subnqn}' 'namespace add ${subnqn} 1 /dev/loop0' 'port add 1 loop' 'port add-subsystem 1 ${subnqn}' | nvmet batch")
+    node.succeed("test -h /sys/kernel/config/nvmet/ports/1/subsystems/${subnqn}")
+    node.succeed("nvmet state clear")
+    node.fail("printf '%s\\n' 'port add 1 loop' 'port add-subsystem 1 ${subnqn}' | nvmet batch")
+    node.fail("test -e /sys/kernel/config/nvmet/ports/1")
+
+    # Failures are told apart by their exit code.
+    node.succeed("nvmet subsystem add ${subnqn}")
+    assert node.execute("nvmet subsystem remove nqn.2023-11.sh.tty:missing")[0] == 2
+    assert node.execute("nvmet subsystem add not-an-nqn")[0] == 3
+    assert node.execute("nvmet port add")[0] == 3
+    assert "trsvcid" in node.fail("nvmet port add 9 tcp 127.0.0.1:notaport 2>&1")
+    assert node.execute("nvmet subsystem add ${subnqn}")[0] == 4
+    assert '"code":4' in node.fail("nvmet --output json subsystem add ${subnqn} 2>&1")
+    node.succeed("nvmet state clear")
+    node.succeed("rmmod nvme_loop || true; rmmod nvmet")
+    assert node.execute("nvmet subsystem list")[0] == 5
+
     # Export coverage.
     node.succeed("llvm-profdata merge --sparse -o /tmp/nvmetcfg.profdata /tmp/nvmetcfg-*.profraw")
     node.succeed("llvm-cov export -format=lcov -instr-profile=/tmp/nvmetcfg.profdata " +
@@ -62,6 +62,17 @@
     target.fail("test -e /sys/kernel/config/nvmet/subsystems/${subnqn}/allowed_hosts/${initiator1}")
     assert "${initiator1}" not in target.succeed("nvmet subsystem list-hosts ${subnqn}")
 
+    # Copy the allowed hosts to another subsystem, which needs a flag while they allow any.
+    target.succeed("nvmet subsystem add nqn.2023-11.sh.tty:copy")
+    assert "allows any host" in target.fail("nvmet subsystem copy-hosts nqn.2023-11.sh.tty:copy ${subnqn} 2>&1")
+    target.succeed("nvmet subsystem add-host nqn.2023-11.sh.tty:copy ${initiator1}")
+    assert "Added Host: ${initiator2}" in target.succeed("nvmet subsystem copy-hosts --merge ${subnqn} nqn.2023-11.sh.tty:copy")
+    hosts = target.succeed("nvmet subsystem list-hosts nqn.2023-11.sh.tty:copy")
+    assert "${initiator1}" in hosts and "${initiator2}" in hosts
+    assert "Removed Host: ${initiator1}" in target.succeed("nvmet subsystem copy-hosts ${subnqn} nqn.2023-11.sh.tty:copy")
+    assert "${initiator1}" not in target.succeed("nvmet subsystem list-hosts nqn.2023-11.sh.tty:copy")
+    target.succeed("nvmet subsystem remove nqn.2023-11.sh.tty:copy")
+
     target.succeed("nvmet subsystem show")
 
     target.succeed("nvmet namespace add ${subnqn} 1 /dev/loop0")
@@ -95,12 +106,12 @@
     target.succeed("nvmet state clear")
     target.fail("test -e /sys/kernel/config/nvmet/subsystems/${subnqn}")
     target.fail("test -e /sys/kernel/config/nvmet/ports/1")
-    assert "no config" in target.succeed("nvmet state clear")
+    assert "no config" in target.succeed("nvmet state clear 2>&1")
 
     target.succeed("nvmet state restore /root/state.yml")
     target.succeed("test -d /sys/kernel/config/nvmet/subsystems/${subnqn}/namespaces/1")
     target.succeed("test -d /sys/kernel/config/nvmet/ports/1")
-    assert "no changes" in target.succeed("nvmet state restore /root/state.yml")
+    assert "no changes" in target.succeed("nvmet state restore /root/state.yml 2>&1")
 
     target.succeed("nvmet state save /root/state-after.yml")
     target.succeed("test -f /root/state-after.yml")
@@ -44,12 +44,17 @@
     assert "${subnqn}" in target.succeed("nvmet subsystem list")
     target.succeed("test -d /sys/kernel/config/nvmet/subsystems/${subnqn}")
 
+    # Hosts created beforehand are reused.
+    target.succeed("nvmet host add ${initiator1}")
+    target.succeed("test -d /sys/kernel/config/nvmet/hosts/${initiator1}")
+    target.fail("nvmet host add ${initiator1}")
     target.succeed("nvmet subsystem add-host ${subnqn} ${initiator1}")
     target.succeed("test -d /sys/kernel/config/nvmet/hosts/${initiator1}")
     target.succeed("test -d /sys/kernel/config/nvmet/subsystems/${subnqn}/allowed_hosts/${initiator1}")
     assert "${initiator1}" in target.succeed("nvmet subsystem list-hosts ${subnqn}")
     target.succeed("nvmet subsystem add-host ${subnqn} ${initiator2}")
     target.succeed("test -d /sys/kernel/config/nvmet/hosts/${initiator2}")
+    assert "${initiator1},false,false" in target.succeed("nvmet host list --output csv")
     target.succeed("test -d /sys/kernel/config/nvmet/subsystems/${subnqn}/allowed_hosts/${initiator2}")
     assert "${initiator2}" in target.succeed("nvmet subsystem list-hosts ${subnqn}")
     target.succeed("nvmet subsystem remove-host ${subnqn} ${initiator1}")
@@ -73,6 +78,12 @@
     assert "1" in target.succeed("nvmet port list")
     target.succeed("test -d /sys/kernel/config/nvmet/ports/1")
     assert "tcp" in target.succeed("cat /sys/kernel/config/nvmet/ports/1/addr_trtype")
+
+    # Ports can take their address from a network interface.
+    target.succeed("nvmet port add 2 tcp --interface eth1:4421")
+    assert "192.168.1." in target.succeed("cat /sys/kernel/config/nvmet/ports/2/addr_traddr")
+    target.fail("nvmet port add 3 tcp --interface nonexistent0:4421")
+    target.succeed("nvmet port remove 2")
     assert "ipv4" in target.succeed("cat /sys/kernel/config/nvmet/ports/1/addr_adrfam")
     assert "0.0.0.0" in target.succeed("cat /sys/kernel/config/nvmet/ports/1/addr_traddr")
     assert "4420" in target.succeed("cat /sys/kernel/config/nvmet/ports/1/addr_trsvcid")
@@ -82,6 +93,11 @@
     target.succeed("test -h /sys/kernel/config/nvmet/ports/1/subsystems/${subnqn}")
     target.fail("nvmet port list-subsystems 69")
     target.succeed("nvmet port show")
+    assert "Status: LISTENING" in target.succeed("nvmet port show --check")
+    assert "Port 1 (tcp://0.0.0.0:4420): LISTENING" in target.succeed("nvmet doctor")
+    assert "1,tcp,0.0.0.0,4420,${subnqn}" in target.succeed("nvmet port list --output csv")
+    assert "--transport=tcp --traddr=0.0.0.0 --trsvcid=4420 --nqn=${subnqn}" in target.succeed("nvmet connect-info")
+    assert target.succeed("nvmet connect-info --discovery-conf").strip() == "--transport=tcp --traddr=0.0.0.0 --trsvcid=4420"
 
     # State save/restore test.
     target.succeed("nvmet state save /root/state.yml")
@@ -90,12 +106,12 @@
     target.succeed("nvmet state clear")
     target.fail("test -e /sys/kernel/config/nvmet/subsystems/${subnqn}")
     target.fail("test -e /sys/kernel/config/nvmet/ports/1")
-    assert "no config" in target.succeed("nvmet state clear")
+    assert "no config" in target.succeed("nvmet state clear 2>&1")
 
     target.succeed("nvmet state restore /root/state.yml")
     target.succeed("test -d /sys/kernel/config/nvmet/subsystems/${subnqn}/namespaces/1")
     target.succeed("test -d /sys/kernel/config/nvmet/ports/1")
-    assert "no changes" in target.succeed("nvmet state restore /root/state.yml")
+    assert "no changes" in target.succeed("nvmet state restore /root/state.yml 2>&1")
 
     target.succeed("nvmet state save /root/state-after.yml")
     target.succeed("test -f /root/state-after.yml")
@@ -107,6 +123,24 @@
     target.succeed("nvmet subsystem add-host ${subnqn} " + clientnqn)
     assert "${subnqn}" in initiator.succeed("nvme discover -t tcp -a target -s 4420 -q " + clientnqn)
 
+    # Connected controllers are only shown by kernels with the nvmet debugfs.
+    initiator.succeed("nvme connect -t tcp -a target -s 4420 -n ${subnqn} -q " + clientnqn)
+    connections = target.succeed("nvmet subsystem connections ${subnqn} 2>&1 || true")
+    assert clientnqn.strip() in connections or "debugfs support not available" in connections
+    initiator.succeed("nvme disconnect -n ${subnqn}")
+
+    # Move the port to another address, then back.
+    assert "remove port 1" in target.succeed("nvmet port migrate 1 tcp://0.0.0.0:4421 --new-pid 2 --dry-run")
+    target.fail("test -e /sys/kernel/config/nvmet/ports/2")
+    target.fail("nvmet port migrate 1 tcp://0.0.0.0:4420 --new-pid 2 --delay 0")
+    target.fail("test -e /sys/kernel/config/nvmet/ports/2")
+    target.succeed("nvmet port migrate 1 tcp://0.0.0.0:4421 --new-pid 2 --delay 0")
+    target.fail("test -e /sys/kernel/config/nvmet/ports/1")
+    target.succeed("test -h /sys/kernel/config/nvmet/ports/2/subsystems/${subnqn}")
+    target.succeed("nvmet port migrate 2 tcp://0.0.0.0:4420 --new-pid 1 --wait-for-drain --drain-timeout 10")
+    target.fail("test -e /sys/kernel/config/nvmet/ports/2")
+    assert "4420" in target.succeed("cat /sys/kernel/config/nvmet/ports/1/addr_trsvcid")
+
     # Cleanup.
     target.succeed("nvmet namespace remove ${subnqn} 1")
     target.fail("test -e /sys/kernel/config/nvmet/subsystems/${subnqn}/namespaces/1")